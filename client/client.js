@@ -10,9 +10,19 @@ function log(msg, level = 'info') {
     console.log(msg);
 }
 
+// Served by the proxy itself (--serve-demo): point at the same host
+if (location.protocol.startsWith('http')) {
+    document.getElementById('url').value = `https://${location.hostname}:4433/`;
+}
+
 document.getElementById('connect').onclick = () => {
     const url = document.getElementById('url').value;
     const rtspUrl = document.getElementById('rtspUrl').value;
+    const subUrl = document.getElementById('subUrl').value;
+    const rendition = document.getElementById('rendition').value;
+    const token = document.getElementById('token').value;
+    const compress = document.getElementById('compress').checked;
+    const latency = document.getElementById('latency').value;
     const canvas = document.getElementById('canvas');
 
     // Create worker
@@ -25,13 +35,46 @@ document.getElementById('connect').onclick = () => {
         type: 'init',
         url: url,
         rtspUrl: rtspUrl,
+        subUrl: subUrl,
+        rendition: rendition,
+        token: token,
+        compress: compress,
+        latency: latency,
         canvas: offscreen
     }, [offscreen]);
 
     worker.onmessage = (e) => {
-        const { type, msg, level, data } = e.data;
+        const { type, msg, level, data, capabilities, event } = e.data;
         if (type === 'log') {
             log(msg, level);
+        } else if (type === 'event' && event.event === 'audio') {
+            const indicator = document.getElementById('audio');
+            indicator.textContent = `Audio ${event.level_dbfs.toFixed(1)} dBFS`;
+            indicator.classList.toggle('active', event.voice);
+        } else if (type === 'event' && event.event === 'motion') {
+            const indicator = document.getElementById('motion');
+            indicator.textContent = event.active ? 'Motion' : '';
+            indicator.classList.toggle('active', event.active);
+        } else if (type === 'event' && event.event === 'timeshift') {
+            const indicator = document.getElementById('timeshift');
+            indicator.textContent = event.state === 'live' ? '' : `${event.state} (-${(event.behind_ms / 1000).toFixed(0)} s)`;
+            indicator.classList.toggle('active', event.state !== 'live');
+        } else if (type === 'event' && event.event === 'objects') {
+            const indicator = document.getElementById('objects');
+            const classes = event.objects.map(object => object.class || 'object');
+            indicator.textContent = classes.length ? `Objects: ${classes.join(', ')}` : '';
+            indicator.classList.toggle('active', classes.length > 0);
+        } else if (type === 'capabilities') {
+            log(`Camera supports: ${capabilities.methods.join(', ') || 'unknown'}`);
+            for (const stream of capabilities.streams) {
+                const details = [
+                    stream.width && `${stream.width}x${stream.height}`,
+                    stream.fps && `${stream.fps} fps`,
+                    stream.bitrate_kbps && `${stream.bitrate_kbps} kbit/s`,
+                    stream.profile && `${stream.profile}${stream.level ? ` ${stream.level}` : ''}`,
+                ].filter(Boolean);
+                log(`Camera ${stream.kind}: ${stream.codec || 'unknown codec'}${details.length ? ` (${details.join(', ')})` : ''}`);
+            }
         } else if (type === 'download') {
             const blob = new Blob([data], { type: 'video/h264' });
             const url = URL.createObjectURL(blob);
@@ -58,5 +101,12 @@ document.getElementById('connect').onclick = () => {
         log('Stopped recording. Preparing download...');
     };
 
+    // Time-shift: the proxy keeps recent media when [timeshift] is configured
+    const command = (command, params) => worker.postMessage({ type: 'command', command, params });
+    document.getElementById('pauseLive').onclick = () => command('pause_live');
+    document.getElementById('rewind').onclick = () => command('rewind', { seconds: 10 });
+    document.getElementById('resume').onclick = () => command('resume');
+    document.getElementById('live').onclick = () => command('live');
+
     log('Initialized Web Worker and transferred canvas control');
 };
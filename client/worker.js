@@ -14,17 +14,91 @@ function mergeBuffers(bufs) {
     return merged;
 }
 
+// Control message compression formats the browser can decode ('zstd' is not
+// available everywhere, 'deflate-raw' is)
+function supportsDecompression(format) {
+    try {
+        new DecompressionStream(format);
+        return true;
+    } catch (e) {
+        return false;
+    }
+}
+
+async function decompress(format, data) {
+    const stream = new Blob([data]).stream().pipeThrough(new DecompressionStream(format));
+    return new Uint8Array(await new Response(stream).arrayBuffer());
+}
+
+// Proxy compression name -> DecompressionStream format
+const DECOMPRESSION_FORMATS = { deflate: 'deflate-raw', zstd: 'zstd' };
+
+// Split the WebTransport control stream into [u32 length][compressed message]
+// frames and decompress each one
+function decompressFrames(readable, compression) {
+    const format = DECOMPRESSION_FORMATS[compression];
+    let pending = new Uint8Array(0);
+    return readable.pipeThrough(new TransformStream({
+        async transform(chunk, controller) {
+            pending = mergeBuffers([pending, chunk]);
+            while (pending.length >= 4) {
+                const len = new DataView(pending.buffer, pending.byteOffset, 4).getUint32(0);
+                if (pending.length < 4 + len) break;
+                controller.enqueue(await decompress(format, pending.subarray(4, 4 + len)));
+                pending = pending.slice(4 + len);
+            }
+        }
+    }));
+}
+
+// Hash of the bundled DO_NOT_USE_CERT.pem
+const BUNDLED_CERT_HASH = new Uint8Array([100, 233, 92, 176, 89, 220, 118, 78, 143, 217, 157, 67, 70, 118, 9, 150, 159, 234, 192, 32, 47, 142, 83, 198, 41, 23, 11, 252, 150, 115, 233, 137]);
+
+// Hashes of a proxy running with a self-signed certificate, published on its
+// HTTP port (4433 -> 8080); the bundled certificate's hash otherwise
+async function certificateHashes(url) {
+    try {
+        const u = new URL(url);
+        if (u.port === '4433') {
+            u.port = '8080';
+        }
+        u.protocol = 'http:';
+        u.pathname = '/cert-hash';
+        u.search = '';
+        const response = await fetch(u);
+        if (response.ok) {
+            const hashes = await response.json();
+            return hashes.map(h => ({ algorithm: h.algorithm, value: new Uint8Array(h.value) }));
+        }
+    } catch (e) {
+        log(`No certificate hashes from the proxy: ${e}`, 'warn');
+    }
+    return [{ algorithm: "sha-256", value: BUNDLED_CERT_HASH }];
+}
+
 class WebTransportAdapter {
-    constructor(url, hash) {
-        this.transport = new WebTransport(url, {
-            serverCertificateHashes: hash ? [{ algorithm: "sha-256", value: hash.buffer }] : undefined
-        });
+    constructor(url, hashes, compression) {
+        this.compression = compression;
+        this.transport = new WebTransport(url, { serverCertificateHashes: hashes });
         this.ready = this.transport.ready;
         this.datagrams = this.transport.datagrams;
+        // Used by the proxy when datagrams are unsupported (x-wt-delivery=stream)
+        this.incomingUnidirectionalStreams = this.transport.incomingUnidirectionalStreams;
     }
 
     async createBidirectionalStream() {
-        return this.transport.createBidirectionalStream();
+        const stream = await this.transport.createBidirectionalStream();
+        if (!this.compression) {
+            return stream;
+        }
+        return { readable: decompressFrames(stream.readable, this.compression), writable: stream.writable };
+    }
+
+    async sendDatagram(data) {
+        if (!this.datagramWriter) {
+            this.datagramWriter = this.transport.datagrams.writable.getWriter();
+        }
+        await this.datagramWriter.write(data);
     }
 
     close() {
@@ -55,6 +129,7 @@ class WebSocketAdapter {
         log(`Connecting WS Data: ${dataUrl}`);
 
         this.wsControl = new WebSocket(controlUrl);
+        this.wsControl.binaryType = 'arraybuffer';
         this.wsData = new WebSocket(dataUrl);
         this.wsData.binaryType = 'arraybuffer';
 
@@ -83,7 +158,8 @@ class WebSocketAdapter {
             })
         };
 
-        // Handle Control Messages (Text)
+        // Handle Control Messages (Text). With `compress`, the proxy accepts the
+        // browser's permessage-deflate offer and the browser inflates them itself.
         this.wsControl.onmessage = (event) => {
             if (typeof event.data === 'string') {
                 if (controlController) {
@@ -124,18 +200,23 @@ class WebSocketAdapter {
         };
     }
 
+    async sendDatagram(data) {
+        if (this.wsData.readyState === WebSocket.OPEN) {
+            this.wsData.send(data);
+        }
+    }
+
     close() {
         this.wsControl.close();
         this.wsData.close();
     }
 }
 
-class H264Depacketizer {
+// RTP header parsing and loss accounting shared by the codec depacketizers;
+// subclasses implement processPayload(payload, timestamp, marker)
+class RtpDepacketizer {
     constructor(onFrame) {
         this.onFrame = onFrame;
-        this.fragmentBuffer = null;
-        this.fragmentType = null;
-        this.fragmentTimestamp = null;
         this.lastSequenceNumber = null;
         this.packetStats = { total: 0, lost: 0, outOfOrder: 0 };
     }
@@ -148,6 +229,7 @@ class H264Depacketizer {
 
         // Parse RTP Header
         const v_p_x_cc = packet[0];
+        const marker = (packet[1] & 0x80) !== 0;
         const x_bit = (v_p_x_cc & 0x10) >> 4;
         const cc = (v_p_x_cc & 0x0F);
 
@@ -197,10 +279,21 @@ class H264Depacketizer {
             return;
         }
 
-        let payload = packet.subarray(payloadOffset);
-
+        const payload = packet.subarray(payloadOffset);
         if (payload.length === 0) return;
+        this.processPayload(payload, timestamp, marker);
+    }
+}
 
+class H264Depacketizer extends RtpDepacketizer {
+    constructor(onFrame) {
+        super(onFrame);
+        this.fragmentBuffer = null;
+        this.fragmentType = null;
+        this.fragmentTimestamp = null;
+    }
+
+    processPayload(payload, timestamp) {
         // NAL Unit Header
         const nalHeader = payload[0];
         const forbidden_zero_bit = (nalHeader & 0x80) >> 7;
@@ -266,10 +359,229 @@ class H264Depacketizer {
     }
 }
 
+// VP9 payload (RFC 9628): frames reassembled from the B (start) to the E (end)
+// bit of the payload descriptor
+class Vp9Depacketizer extends RtpDepacketizer {
+    constructor(onFrame) {
+        super(onFrame);
+        this.frameBuffer = null;
+        this.keyFrame = false;
+    }
+
+    processPayload(payload, timestamp) {
+        const descriptor = payload[0];
+        const I = descriptor & 0x80, P = descriptor & 0x40, L = descriptor & 0x20, F = descriptor & 0x10;
+        const B = descriptor & 0x08, E = descriptor & 0x04, V = descriptor & 0x02;
+        let offset = 1;
+        if (I) offset += (payload[offset] & 0x80) ? 2 : 1; // 7- or 15-bit picture ID
+        if (L) offset += F ? 1 : 2; // Layer indices, plus TL0PICIDX in non-flexible mode
+        if (P && F) {
+            // Reference indices, each with an N bit when another follows
+            while (offset < payload.length && (payload[offset++] & 0x01)) { }
+        }
+        if (V) {
+            // Scalability structure
+            const ss = payload[offset++];
+            const spatialLayers = (ss >> 5) + 1;
+            if (ss & 0x10) offset += spatialLayers * 4; // Resolutions
+            if (ss & 0x08) {
+                const groups = payload[offset++];
+                for (let i = 0; i < groups; i++) {
+                    offset += 1 + ((payload[offset] >> 2) & 0x03);
+                }
+            }
+        }
+        if (offset > payload.length) {
+            log('Truncated VP9 payload descriptor', 'warn');
+            return;
+        }
+
+        if (B) {
+            this.frameBuffer = [];
+            this.keyFrame = !P;
+        } else if (!this.frameBuffer) {
+            return; // Lost the start of this frame
+        }
+        this.frameBuffer.push(payload.subarray(offset));
+        if (E) {
+            this.onFrame(mergeBuffers(this.frameBuffer), timestamp, this.keyFrame);
+            this.frameBuffer = null;
+        }
+    }
+}
+
+// AV1 payload (AOM RTP specification): OBU elements are reassembled into a
+// temporal unit, which ends with the marker bit. Elements arrive without
+// obu_size fields, which WebCodecs expects, so they are added back.
+class Av1Depacketizer extends RtpDepacketizer {
+    constructor(onFrame) {
+        super(onFrame);
+        this.obus = [];
+        this.fragment = null;
+        this.sequenceHeader = false;
+    }
+
+    processPayload(payload, timestamp, marker) {
+        const aggregation = payload[0];
+        const Z = aggregation & 0x80, Y = aggregation & 0x40, W = (aggregation >> 4) & 0x03;
+        let offset = 1;
+        for (let i = 0; offset < payload.length; i++) {
+            // The last of W elements has no length field
+            let length = payload.length - offset;
+            if (W === 0 || i < W - 1) {
+                [length, offset] = readLeb128(payload, offset);
+            }
+            let element = payload.subarray(offset, offset + length);
+            offset += length;
+
+            if (i === 0 && Z) {
+                if (!this.fragment) continue; // Lost the start of this OBU
+                element = mergeBuffers([this.fragment, element]);
+                this.fragment = null;
+            }
+            if (offset >= payload.length && Y) {
+                this.fragment = element;
+                break;
+            }
+            this.pushObu(element);
+        }
+        if (marker) {
+            // Encoders send a sequence header with every keyframe
+            if (this.obus.length) {
+                this.onFrame(mergeBuffers(this.obus), timestamp, this.sequenceHeader);
+            }
+            this.obus = [];
+            this.fragment = null;
+            this.sequenceHeader = false;
+        }
+    }
+
+    pushObu(obu) {
+        if (obu.length === 0) return;
+        const type = (obu[0] >> 3) & 0x0F;
+        if (type === OBU_TEMPORAL_DELIMITER || type === OBU_TILE_LIST) return;
+        if (type === OBU_SEQUENCE_HEADER) this.sequenceHeader = true;
+        const headerLength = (obu[0] & 0x04) ? 2 : 1;
+        const header = obu.slice(0, headerLength);
+        header[0] |= 0x02; // obu_has_size_field
+        this.obus.push(header, writeLeb128(obu.length - headerLength), obu.subarray(headerLength));
+    }
+}
+
+const OBU_SEQUENCE_HEADER = 1;
+const OBU_TEMPORAL_DELIMITER = 2;
+const OBU_TILE_LIST = 8;
+
+// [value, offset after it]
+function readLeb128(data, offset) {
+    let value = 0;
+    for (let i = 0; i < 8 && offset < data.length; i++) {
+        const byte = data[offset++];
+        value += (byte & 0x7F) * 2 ** (7 * i);
+        if (!(byte & 0x80)) break;
+    }
+    return [value, offset];
+}
+
+function writeLeb128(value) {
+    const bytes = [];
+    do {
+        let byte = value & 0x7F;
+        value = Math.floor(value / 128);
+        if (value) byte |= 0x80;
+        bytes.push(byte);
+    } while (value);
+    return new Uint8Array(bytes);
+}
+
+// Holds one track's RTP packets for up to delayMs to put them back in
+// sequence order and give lost ones time to be resent (latency=balanced).
+// onLoss gets the sequence numbers missing from a gap, once each.
+class JitterBuffer {
+    constructor(delayMs, onPacket, onLoss) {
+        this.delayMs = delayMs;
+        this.onPacket = onPacket;
+        this.onLoss = onLoss;
+        this.expected = null;
+        this.pending = new Map(); // sequence number -> { packet, arrived }
+        this.timer = null;
+    }
+
+    push(packet) {
+        if (packet.length < 12) return;
+        const seq = (packet[2] << 8) | packet[3];
+        if (this.expected === null) this.expected = seq;
+        const ahead = (seq - this.expected) & 0xFFFF;
+        // Already played or given up on
+        if (ahead >= 0x8000) return;
+        if (ahead > 0 && !this.pending.has(seq)) {
+            const missing = [];
+            for (let i = 0; i < ahead; i++) {
+                const s = (this.expected + i) & 0xFFFF;
+                if (!this.pending.has(s)) missing.push(s);
+            }
+            // Only the newest gap is new; earlier ones were reported already
+            const last = Math.max(...[...this.pending.keys()].map(s => (s - this.expected) & 0xFFFF), -1);
+            const fresh = missing.filter(s => ((s - this.expected) & 0xFFFF) > last);
+            if (fresh.length) this.onLoss(fresh);
+        }
+        this.pending.set(seq, { packet, arrived: performance.now() });
+        this.drain();
+    }
+
+    drain() {
+        clearTimeout(this.timer);
+        this.timer = null;
+        while (this.pending.has(this.expected)) {
+            const { packet } = this.pending.get(this.expected);
+            this.pending.delete(this.expected);
+            this.expected = (this.expected + 1) & 0xFFFF;
+            this.onPacket(packet);
+        }
+        if (this.pending.size === 0) return;
+        // Waited long enough for the gap: skip to the next packet held
+        const oldest = Math.min(...[...this.pending.values()].map(p => p.arrived));
+        const wait = this.delayMs - (performance.now() - oldest);
+        if (wait <= 0) {
+            const next = [...this.pending.keys()].reduce((a, b) => ((a - this.expected) & 0xFFFF) <= ((b - this.expected) & 0xFFFF) ? a : b);
+            this.expected = next;
+            this.drain();
+            return;
+        }
+        this.timer = setTimeout(() => this.drain(), wait);
+    }
+
+    // Forget held packets and the expected sequence number, as after a seek
+    reset() {
+        clearTimeout(this.timer);
+        this.timer = null;
+        this.pending.clear();
+        this.expected = null;
+    }
+}
+
+// First byte of client -> proxy feedback datagrams (see proxy-server/src/feedback.rs)
+// Control stream format requested from the proxy: newline-delimited JSON
+// envelopes, so proxy events are never mistaken for RTSP responses
+const CONTROL_PROTOCOL = 'json';
+
+const FEEDBACK_MARKER = 0xFF;
+const FEEDBACK_INTERVAL_MS = 1000;
+// First byte of a NACK: [0xFE][channel][pid u16][blp u16], as RFC 4585
+const NACK_MARKER = 0xFE;
+// First byte of an ack: [0xFD][media bytes received, u32 BE], for bandwidth estimation
+const ACK_MARKER = 0xFD;
+const ACK_INTERVAL_MS = 250;
+
 class RTSPClient {
-    constructor(url, rtspUrl, canvas) {
+    constructor(url, rtspUrl, canvas, subUrl, rendition, token, compress, latency) {
         this.url = url;
         this.rtspUrl = rtspUrl;
+        this.subUrl = subUrl;
+        this.rendition = rendition;
+        this.token = token;
+        this.compress = compress;
+        this.latency = latency; // ultra-low, balanced, reliable, or '' for the proxy's defaults
         this.canvas = canvas;
         // this.ctx = this.canvas.getContext('2d');
         this.gl = this.canvas.getContext('webgl2') || this.canvas.getContext('webgl');
@@ -291,13 +603,28 @@ class RTSPClient {
 
         this.hasSeenKeyFrame = false; // Track if we've seen a keyframe
         this.videoChannelId = null; // Dynamically assigned by server
+        this.announcedChannels = null; // From a channels event, when the proxy sends no Transport parameter
+        this.jitterBuffer = null; // Reorders video packets when the proxy asks for x-wt-jitter-ms
+        this.framing = 'prefix'; // Media payload framing, advertised by server
         this.profileLevelId = '42001E'; // Default fallback
+        this.videoCodec = 'H264'; // From the SDP rtpmap: H264, VP9 or AV1
+        this.codecString = null; // WebCodecs codec string for VP9 and AV1
 
         this.isRecording = false;
         this.recordedChunks = [];
+
+        this.bytesReceived = 0;
+        this.lastReport = { total: 0, lost: 0, bytes: 0 };
+
+        // Per-channel RTP -> wall clock mappings pushed by the proxy
+        this.clockSync = new Map();
     }
 
     startRecording() {
+        if (this.videoCodec !== 'H264') {
+            log(`Recording is only supported for H.264, not ${this.videoCodec}`, 'warn');
+            return;
+        }
         this.isRecording = true;
         this.recordedChunks = [];
     }
@@ -312,14 +639,40 @@ class RTSPClient {
     async connect() {
         log(`Connecting to ${this.url}...`);
 
-        // Append RTSP URL as query param
-        const connectionUrl = `${this.url}?rtsp=${encodeURIComponent(this.rtspUrl)}`;
+        // Append RTSP URL (and optional lower-bitrate profile, transcode
+        // rendition and tenant token) as query params
+        let connectionUrl = `${this.url}?rtsp=${encodeURIComponent(this.rtspUrl)}`;
+        if (this.subUrl) {
+            connectionUrl += `&sub=${encodeURIComponent(this.subUrl)}`;
+        }
+        if (this.rendition) {
+            connectionUrl += `&rendition=${encodeURIComponent(this.rendition)}`;
+        }
+        if (this.token) {
+            connectionUrl += `&token=${encodeURIComponent(this.token)}`;
+        }
+        if (this.latency) {
+            connectionUrl += `&latency=${this.latency}`;
+        }
+        connectionUrl += `&protocol=${CONTROL_PROTOCOL}`;
+
+        // Optional control message compression: zstd on WebTransport where the
+        // browser can decode it, deflate otherwise. On WebSocket it is
+        // permessage-deflate, which the browser handles.
+        const compression = (transport) => {
+            if (!this.compress) return null;
+            if (transport === 'ws') return 'deflate';
+            if (!supportsDecompression('deflate-raw')) return null;
+            return supportsDecompression('zstd') ? 'zstd' : 'deflate';
+        };
 
         try {
             if (typeof WebTransport !== 'undefined') {
-                log(`Attempting WebTransport connection to ${connectionUrl}...`);
-                const HASH = new Uint8Array([100, 233, 92, 176, 89, 220, 118, 78, 143, 217, 157, 67, 70, 118, 9, 150, 159, 234, 192, 32, 47, 142, 83, 198, 41, 23, 11, 252, 150, 115, 233, 137]);
-                this.transport = new WebTransportAdapter(connectionUrl, HASH);
+                const wtCompression = compression('wt');
+                const wtUrl = wtCompression ? `${connectionUrl}&compress=${wtCompression}` : connectionUrl;
+                log(`Attempting WebTransport connection to ${wtUrl}...`);
+                const hashes = await certificateHashes(this.url);
+                this.transport = new WebTransportAdapter(wtUrl, hashes, wtCompression);
                 await this.transport.ready;
                 log('WebTransport connected');
             } else {
@@ -336,6 +689,10 @@ class RTSPClient {
                     u.port = '8080';
                 }
                 u.protocol = 'ws:';
+                const wsCompression = compression('ws');
+                if (wsCompression) {
+                    u.searchParams.set('compress', wsCompression);
+                }
 
                 const wsUrl = u.toString();
 
@@ -371,6 +728,16 @@ class RTSPClient {
         // Start reading datagrams
         this.readDatagrams();
 
+        // Start reading stream-delivered RTP (datagram fallback)
+        if (this.transport.incomingUnidirectionalStreams) {
+            this.readMediaStreams();
+        }
+
+        // Report reception quality so the proxy can adapt the upstream profile
+        setInterval(() => this.sendFeedback(), FEEDBACK_INTERVAL_MS);
+        // Acknowledge media so the proxy can estimate our bandwidth
+        setInterval(() => this.sendAck(), ACK_INTERVAL_MS);
+
         // Start RTSP Handshake
         await this.sendRTSP('OPTIONS', this.rtspUrl);
         await this.sendRTSP('DESCRIBE', this.rtspUrl);
@@ -506,71 +873,43 @@ class RTSPClient {
         msg += `\r\n`;
 
         log(`Sending ${method}`);
-        await this.writer.write(new TextEncoder().encode(msg));
+        await this.sendControl({ type: 'rtsp', data: msg });
+    }
+
+    // Envelope for the proxy; sent as raw RTSP text unless JSON was negotiated
+    async sendControl(envelope) {
+        const text = CONTROL_PROTOCOL === 'json' ? JSON.stringify(envelope) + '\n' : envelope.data;
+        await this.writer.write(new TextEncoder().encode(text));
+    }
+
+    // Request to the proxy itself; only understood on the JSON protocol
+    async sendCommand(command, params = {}) {
+        if (CONTROL_PROTOCOL !== 'json') {
+            log(`Cannot send ${command}: commands need the JSON control protocol`, 'warn');
+            return;
+        }
+        await this.sendControl({ type: 'command', command, ...params });
     }
 
     async readControl() {
         const decoder = new TextDecoder();
+        let pending = '';
         try {
             while (true) {
                 const { value, done } = await this.reader.read();
                 if (done) break;
-                const text = decoder.decode(value);
-                log(`RTSP Response: ${text}`);
-
-                // Simple state machine
-                if (text.includes('RTSP/1.0 200 OK')) {
-                    if (text.includes('Public:')) {
-                        // Response to OPTIONS
-                        // Next: DESCRIBE
-                    } else if (text.includes('Content-Type: application/sdp')) {
-                        // Response to DESCRIBE
-                        // Parse SDP (for potential future use)
-                        this.parseSDP(text);
-
-                        // Configure decoder for Annex B format
-                        // Since we're sending NAL units with start codes (Annex B),
-                        this.decoder.configure({
-                            codec: `avc1.${this.profileLevelId}`,
-                            hardwareAcceleration: 'prefer-software',
-                            optimizeForLatency: false,
-                            colorSpace: {
-                                matrix: 'smpte170m',
-                                primaries: 'smpte170m',
-                                transfer: 'smpte170m'
-                            }
-                        });
-                        log('VideoDecoder configured for Annex B format');
-
-                        // Next: SETUP
-                        await this.sendRTSP('SETUP', this.rtspUrl + '/stream=0', {
-                            'Transport': 'RTP/AVP;unicast;client_port=0-0'
-                        });
-                    } else if (text.includes('Transport:')) {
-                        // Response to SETUP
-                        // Check for Session ID
-                        const match = text.match(/Session:\s*(\S+)/);
-                        if (match) {
-                            this.sessionId = match[1].split(';')[0];
-                        }
-
-                        // Check for Channel ID injection
-                        // Format: Transport: ...;x-wt-channel-id=0-1
-                        const channelMatch = text.match(/x-wt-channel-id=(\d+)-(\d+)/);
-                        if (channelMatch) {
-                            this.videoChannelId = parseInt(channelMatch[1], 10);
-                            const rtcpChannelId = parseInt(channelMatch[2], 10);
-                            log(`Assigned Channel IDs: Video=${this.videoChannelId}, RTCP=${rtcpChannelId}`);
-                        } else {
-                            log('WARNING: No x-wt-channel-id found in Transport header. Defaulting to 0.', 'warn');
-                            this.videoChannelId = 0;
-                        }
-
-                        // Next: PLAY
-                        if (this.sessionId) {
-                            await this.sendRTSP('PLAY', this.rtspUrl, { Session: this.sessionId });
-                        }
-                    }
+                const text = decoder.decode(value, { stream: true });
+
+                // A proxy without JSON support answers in raw RTSP
+                if (CONTROL_PROTOCOL !== 'json' || (!pending && !text.startsWith('{'))) {
+                    await this.handleControlText(text);
+                    continue;
+                }
+                pending += text;
+                const lines = pending.split('\n');
+                pending = lines.pop();
+                for (const line of lines) {
+                    if (line.trim()) await this.handleEnvelope(JSON.parse(line));
                 }
             }
         } catch (e) {
@@ -578,6 +917,342 @@ class RTSPClient {
         }
     }
 
+    async handleEnvelope(envelope) {
+        switch (envelope.type) {
+            case 'rtsp':
+                await this.handleControlText(envelope.data);
+                break;
+            case 'event':
+                this.handleEvent(envelope);
+                break;
+            case 'stats':
+                postMessage({ type: 'stats', stats: envelope });
+                break;
+            default:
+                log(`Unknown control envelope: ${envelope.type}`, 'warn');
+        }
+    }
+
+    async handleControlText(text) {
+        // Proxy-originated notification; never answered
+        if (text.startsWith('SET_PARAMETER * RTSP/1.0')) {
+            this.handleNotification(text);
+            return;
+        }
+        log(`RTSP Response: ${text}`);
+
+        // Simple state machine
+        if (text.includes('RTSP/1.0 200 OK')) {
+            if (text.includes('Public:')) {
+                // Response to OPTIONS
+                // Next: DESCRIBE
+            } else if (text.includes('Content-Type: application/sdp')) {
+                // Response to DESCRIBE
+                // Parse SDP (for potential future use)
+                this.parseSDP(text);
+                this.configureDecoder();
+
+                // Next: SETUP
+                await this.sendRTSP('SETUP', this.rtspUrl + '/stream=0', {
+                    'Transport': 'RTP/AVP;unicast;client_port=0-0'
+                });
+            } else if (text.includes('Transport:')) {
+                // Response to SETUP
+                // Check for Session ID
+                const match = text.match(/Session:\s*(\S+)/);
+                if (match) {
+                    this.sessionId = match[1].split(';')[0];
+                }
+
+                // Check for Channel ID injection
+                // Format: Transport: ...;x-wt-channel-id=0-1 (or interleaved=0-1), or a channels event before this response
+                const channelMatch = text.match(/(?:x-wt-channel-id|interleaved)=(\d+)-(\d+)/);
+                if (channelMatch) {
+                    this.videoChannelId = parseInt(channelMatch[1], 10);
+                    const rtcpChannelId = parseInt(channelMatch[2], 10);
+                    log(`Assigned Channel IDs: Video=${this.videoChannelId}, RTCP=${rtcpChannelId}`);
+                } else if (this.announcedChannels) {
+                    this.videoChannelId = this.announcedChannels.rtp;
+                    log(`Assigned Channel IDs: Video=${this.videoChannelId}, RTCP=${this.announcedChannels.rtcp}`);
+                } else {
+                    log('WARNING: No x-wt-channel-id found in Transport header. Defaulting to 0.', 'warn');
+                    this.videoChannelId = 0;
+                }
+
+                const framingMatch = text.match(/x-wt-framing=(\w+)/);
+                if (framingMatch) {
+                    this.framing = framingMatch[1];
+                }
+
+                // Largest media datagram the proxy can send us; absent when unlimited
+                const maxDatagramMatch = text.match(/x-wt-max-datagram=(\d+)/);
+                if (maxDatagramMatch) {
+                    this.maxDatagramSize = parseInt(maxDatagramMatch[1], 10);
+                    log(`Max datagram size: ${this.maxDatagramSize} bytes`);
+                }
+
+                // Latency mode: how long to hold packets, and whether to ask for lost ones
+                const jitterMatch = text.match(/x-wt-jitter-ms=(\d+)/);
+                const jitterMs = jitterMatch ? parseInt(jitterMatch[1], 10) : 0;
+                if (jitterMs > 0) {
+                    const nack = /x-wt-nack=yes/.test(text);
+                    const channelId = this.videoChannelId;
+                    this.jitterBuffer = new JitterBuffer(
+                        jitterMs,
+                        packet => this.depacketizer.process(packet),
+                        seqs => nack && this.sendNack(channelId, seqs),
+                    );
+                    log(`Jitter buffer: ${jitterMs} ms${nack ? ', lost packets are requested again' : ''}`);
+                }
+
+                // Next: PLAY
+                if (this.sessionId) {
+                    await this.sendRTSP('PLAY', this.rtspUrl, { Session: this.sessionId });
+                }
+            }
+        }
+    }
+
+    handleNotification(text) {
+        const match = text.match(/(x-wt-[a-z-]+):\s*(\S+)/);
+        if (!match) return;
+        // Values may contain '=' (e.g. URLs with a query); only the first one splits
+        const params = Object.fromEntries(match[2].split(';').map(p => {
+            const eq = p.indexOf('=');
+            return eq < 0 ? [p] : [p.slice(0, eq), p.slice(eq + 1)];
+        }));
+        if (match[1] === 'x-wt-sync') {
+            // x-wt-sync: channel=0;wallclock=<unix ms>;rtp=<ts>;clock-rate=90000[;measured-rate=<Hz>]
+            this.setSync(parseInt(params.channel, 10), {
+                wallclock: parseFloat(params.wallclock),
+                rtp: parseInt(params.rtp, 10),
+                rate: parseFloat(params['measured-rate'] || params['clock-rate']),
+            });
+        } else if (match[1] === 'x-wt-capabilities') {
+            // pause=yes;get-parameter=no;set-parameter=no;methods=OPTIONS,DESCRIBE,...
+            //   [;streams=video:H264:1920x1080:25:4000:high:4.0/audio:MPEG4-GENERIC:::64::]
+            const number = value => value ? parseFloat(value) : null;
+            const streams = params.streams ? params.streams.split('/').map(stream => {
+                const [kind, codec, size, fps, kbps, profile, level] = stream.split(':');
+                const [width, height] = size ? size.split('x').map(Number) : [null, null];
+                return {
+                    kind, codec: codec || null, width, height,
+                    fps: number(fps), bitrate_kbps: number(kbps),
+                    profile: profile || null, level: level || null,
+                };
+            }) : [];
+            this.setCapabilities({
+                pause: params.pause === 'yes',
+                getParameter: params['get-parameter'] === 'yes',
+                setParameter: params['set-parameter'] === 'yes',
+                methods: params.methods ? params.methods.split(',') : [],
+                streams,
+            });
+        } else if (match[1] === 'x-wt-event') {
+            // x-wt-event: stall;idle-ms=5000 | resume | profile;profile=sub | rendition;name=480p
+            //             | expired;reason=max-duration | audio;channel=2;level=-32.5;voice=yes
+            //             | motion;channel=0;state=start;cue=bitrate
+            //             | onvif;kind=motion;topic=tns1:RuleEngine/CellMotionDetector/Motion;data=IsMotion:true
+            //             | timeshift;state=playing;behind-ms=10000
+            //             | draining;alternate=wss://proxy-b.example.com:8080
+            //             | skip;channel=0;behind-ms=3120
+            //             | channels;cseq=3;rtp=0;rtcp=1
+            //             | codec-config-changed;channel=0;cause=in-band;width=1280;height=720;profile=main;level=3.1;profile-level-id=4D401F
+            //             | objects;channel=4;time=2024-01-01T00:00:00Z;objects=3:Human:-0.5,0.4,-0.2,-0.3/7::
+            //             | end-of-stream;cause=bye
+            //             | discontinuity;npt=30.500
+            //             | health;score=35;action=reconnect
+            this.handleEvent({
+                event: match[2].split(';')[0],
+                idle_ms: parseInt(params['idle-ms'], 10),
+                profile: params.profile,
+                name: params.name,
+                channel: parseInt(params.channel, 10),
+                level_dbfs: parseFloat(params.level),
+                voice: params.voice === 'yes',
+                active: params.state === 'start',
+                cue: params.cue,
+                kind: params.kind,
+                topic: params.topic,
+                data: params.data ? Object.fromEntries(params.data.split(',').map(item => item.split(':'))) : undefined,
+                reason: params.reason,
+                state: params.state,
+                behind_ms: parseInt(params['behind-ms'], 10),
+                time: params.time,
+                alternate: params.alternate,
+                cause: params.cause,
+                npt: parseFloat(params.npt),
+                score: parseInt(params.score, 10),
+                action: params.action,
+                profile_level_id: params['profile-level-id'],
+                cseq: params.cseq,
+                rtp_channel: parseInt(params.rtp, 10),
+                rtcp_channel: parseInt(params.rtcp, 10),
+                stream: match[2].startsWith('codec-config-changed') ? {
+                    codec: params.codec,
+                    width: params.width ? parseInt(params.width, 10) : null,
+                    height: params.height ? parseInt(params.height, 10) : null,
+                    fps: params.fps ? parseFloat(params.fps) : null,
+                    profile: params.profile || null,
+                    level: params.level || null,
+                } : undefined,
+                objects: params.objects === undefined ? undefined : params.objects.split('/').filter(Boolean).map(item => {
+                    const [id, cls, box] = item.split(':');
+                    const [left, top, right, bottom] = (box || '').split(',').map(parseFloat);
+                    return { id, class: cls || null, box: box ? { left, top, right, bottom } : null };
+                }),
+            });
+        }
+    }
+
+    // Proxy event, from a JSON envelope or a raw x-wt-event notification
+    handleEvent(event) {
+        switch (event.event) {
+            case 'sync':
+                this.setSync(event.channel, {
+                    wallclock: event.wallclock,
+                    rtp: event.rtp,
+                    rate: event.measured_rate || event.clock_rate,
+                });
+                return;
+            case 'capabilities':
+                this.setCapabilities({
+                    pause: event.pause,
+                    getParameter: event.get_parameter,
+                    setParameter: event.set_parameter,
+                    methods: event.methods,
+                    streams: event.streams || [],
+                });
+                return;
+            case 'codec-config-changed':
+            case 'codec_config_changed': {
+                // Start decoding again from the next keyframe, with the new profile
+                const stream = event.stream || {};
+                log(`Camera changed video to ${stream.width || '?'}x${stream.height || '?'} (${event.cause})`);
+                if (event.profile_level_id) this.profileLevelId = event.profile_level_id;
+                if (this.decoder && this.decoder.state !== 'closed') {
+                    this.decoder.reset();
+                    this.configureDecoder();
+                }
+                this.hasSeenKeyFrame = false;
+                break;
+            }
+            case 'channels':
+                // Channel IDs of the SETUP response that follows
+                this.announcedChannels = { rtp: event.rtp_channel, rtcp: event.rtcp_channel };
+                return;
+            case 'stall':
+                log(`Camera stalled: no media for ${event.idle_ms} ms`, 'warn');
+                break;
+            case 'skip':
+                log(`Fell ${event.behind_ms} ms behind live on channel ${event.channel}; the proxy is skipping ahead`, 'warn');
+                break;
+            case 'resume':
+                log('Camera media resumed');
+                break;
+            case 'profile':
+                log(`Proxy switched video to the ${event.profile} profile`);
+                break;
+            case 'rendition':
+                log(`Proxy switched video to the ${event.name} rendition`);
+                break;
+            case 'expired':
+                log(`Session expired (${event.reason}); the proxy is closing it`, 'warn');
+                break;
+            case 'end-of-stream':
+            case 'end_of_stream':
+                log(`Camera ended the stream (${event.cause})`);
+                break;
+            case 'health':
+                // A reconnect or fallback ends the session; simple mode sets it up again
+                log(`Stream health stayed low (${event.score}/100): ${event.action}`, 'warn');
+                break;
+            case 'discontinuity':
+                // Media from before the seek is stale: start over from the next keyframe
+                log(`Seeked to ${event.npt.toFixed(3)} s`);
+                if (this.jitterBuffer) this.jitterBuffer.reset();
+                this.depacketizer.fragmentBuffer = null;
+                this.NALUnitBuffer = [];
+                if (this.decoder && this.decoder.state !== 'closed') {
+                    this.decoder.reset();
+                    this.configureDecoder();
+                }
+                this.hasSeenKeyFrame = false;
+                break;
+            case 'motion':
+                log(event.active ? `Motion detected (${event.cue})` : 'Motion ended');
+                break;
+            case 'onvif': {
+                const state = Object.entries(event.data || {}).map(([name, value]) => `${name}=${value}`).join(', ');
+                log(`Camera ${event.kind} event ${event.topic}${state ? ` (${state})` : ''}`);
+                break;
+            }
+            case 'timeshift':
+                log(event.state === 'live' ? 'Back to live' : `Time-shift ${event.state}, ${(event.behind_ms / 1000).toFixed(1)} s behind live`);
+                break;
+            case 'draining':
+                log(`Proxy is draining; reconnect to ${event.alternate || 'another instance'}`, 'warn');
+                break;
+            case 'objects':
+                // Overlay data for the page; too frequent to log
+                break;
+            case 'audio':
+                // Sent several times a second; the page shows it, the log only notes changes
+                if (event.voice !== this.voiceActive) {
+                    this.voiceActive = event.voice;
+                    log(event.voice ? `Audio detected (${event.level_dbfs} dBFS)` : 'Audio quiet');
+                }
+                break;
+            default:
+                return;
+        }
+        postMessage({ type: 'event', event });
+    }
+
+    configureDecoder() {
+        if (this.codecString) {
+            // VP9 and AV1 frames carry their own color information
+            this.decoder.configure({
+                codec: this.codecString,
+                hardwareAcceleration: 'prefer-software',
+                optimizeForLatency: this.latency === 'ultra-low',
+            });
+            log(`VideoDecoder configured for ${this.codecString}`);
+        } else {
+            // Configure decoder for Annex B format
+            // Since we're sending NAL units with start codes (Annex B),
+            this.decoder.configure({
+                codec: `avc1.${this.profileLevelId}`,
+                hardwareAcceleration: 'prefer-software',
+                optimizeForLatency: this.latency === 'ultra-low',
+                colorSpace: {
+                    matrix: 'smpte170m',
+                    primaries: 'smpte170m',
+                    transfer: 'smpte170m'
+                }
+            });
+            log('VideoDecoder configured for Annex B format');
+        }
+    }
+
+    setSync(channel, sync) {
+        this.clockSync.set(channel, sync);
+    }
+
+    setCapabilities(capabilities) {
+        this.capabilities = capabilities;
+        postMessage({ type: 'capabilities', capabilities });
+    }
+
+    // Wall-clock time (unix ms) of an RTP timestamp, once the proxy has sent a sync point
+    wallclockFor(channelId, rtpTimestamp) {
+        const sync = this.clockSync.get(channelId);
+        if (!sync) return null;
+        // Signed 32-bit difference handles timestamp wrap-around
+        const ticks = (rtpTimestamp - sync.rtp) | 0;
+        return sync.wallclock + (ticks / sync.rate) * 1000;
+    }
+
     async readDatagrams() {
         const reader = this.transport.datagrams.readable.getReader();
         try {
@@ -586,20 +1261,169 @@ class RTSPClient {
                 if (done) break;
 
                 // value is Uint8Array
-                // First byte is Channel ID
-                const channelId = value[0];
-                const payload = value.subarray(1);
+                this.handleMediaPacket(value);
+            }
+        } catch (e) {
+            log(`Datagram error: ${e}`, 'error');
+        }
+    }
+
+    async readMediaStreams() {
+        const streams = this.transport.incomingUnidirectionalStreams.getReader();
+        try {
+            while (true) {
+                const { value: stream, done } = await streams.read();
+                if (done) break;
+                // The datagram fallback, or a time-shift replay
+                log('Receiving RTP over a unidirectional stream');
+                this.readFramedStream(stream);
+            }
+        } catch (e) {
+            log(`Incoming stream error: ${e}`, 'error');
+        }
+    }
+
+    async readFramedStream(stream) {
+        // Each packet is prefixed with a 16-bit big-endian length
+        const reader = stream.getReader();
+        let pending = new Uint8Array(0);
+        try {
+            while (true) {
+                const { value, done } = await reader.read();
+                if (done) break;
+                pending = mergeBuffers([pending, value]);
 
-                if (this.videoChannelId !== null && channelId === this.videoChannelId) {
-                    this.depacketizer.process(payload);
+                while (pending.length >= 2) {
+                    const len = (pending[0] << 8) | pending[1];
+                    if (pending.length < 2 + len) break;
+                    this.handleMediaPacket(pending.slice(2, 2 + len));
+                    pending = pending.subarray(2 + len);
                 }
             }
         } catch (e) {
-            log(`Datagram error: ${e}`, 'error');
+            log(`Media stream error: ${e}`, 'error');
+        }
+    }
+
+    async sendFeedback() {
+        const stats = this.depacketizer.packetStats;
+        const total = stats.total - this.lastReport.total;
+        const lost = stats.lost - this.lastReport.lost;
+        const bytes = this.bytesReceived - this.lastReport.bytes;
+        this.lastReport = { total: stats.total, lost: stats.lost, bytes: this.bytesReceived };
+
+        // [marker][fraction lost in 1/256ths][received kbps, u32 BE]
+        const expected = total + lost;
+        const fractionLost = expected > 0 ? Math.min(255, Math.floor((lost / expected) * 256)) : 0;
+        const kbps = Math.floor((bytes * 8) / FEEDBACK_INTERVAL_MS);
+        const msg = new Uint8Array(6);
+        msg[0] = FEEDBACK_MARKER;
+        msg[1] = fractionLost;
+        new DataView(msg.buffer).setUint32(2, kbps);
+
+        try {
+            await this.transport.sendDatagram(msg);
+        } catch (e) {
+            log(`Failed to send feedback: ${e}`, 'warn');
+        }
+    }
+
+    async sendAck() {
+        const msg = new Uint8Array(5);
+        msg[0] = ACK_MARKER;
+        new DataView(msg.buffer).setUint32(1, this.bytesReceived % 2 ** 32);
+        try {
+            await this.transport.sendDatagram(msg);
+        } catch (e) {
+            log(`Failed to send ack: ${e}`, 'warn');
+        }
+    }
+
+    // Ask the proxy to resend lost packets, up to 17 per NACK
+    async sendNack(channelId, seqs) {
+        while (seqs.length) {
+            const pid = seqs[0];
+            let blp = 0;
+            let i = 1;
+            for (; i < seqs.length; i++) {
+                const bit = ((seqs[i] - pid) & 0xFFFF) - 1;
+                if (bit >= 16) break;
+                blp |= 1 << bit;
+            }
+            seqs = seqs.slice(i);
+            const msg = new Uint8Array(6);
+            msg[0] = NACK_MARKER;
+            msg[1] = channelId;
+            const view = new DataView(msg.buffer);
+            view.setUint16(2, pid);
+            view.setUint16(4, blp);
+            try {
+                await this.transport.sendDatagram(msg);
+            } catch (e) {
+                log(`Failed to send NACK: ${e}`, 'warn');
+                return;
+            }
+        }
+    }
+
+    handleMediaPacket(value) {
+        this.bytesReceived += value.length;
+
+        const [channelId, payload] = this.unframe(value);
+
+        if (this.videoChannelId !== null && channelId === this.videoChannelId) {
+            if (this.jitterBuffer) {
+                this.jitterBuffer.push(payload);
+            } else {
+                this.depacketizer.process(payload);
+            }
+        }
+    }
+
+    // Split a media payload into [channel ID, RTP packet] per the negotiated framing
+    unframe(value) {
+        switch (this.framing) {
+            case 'rfc4571': {
+                // [channel][u16 length][packet]
+                const len = (value[1] << 8) | value[2];
+                return [value[0], value.subarray(3, 3 + len)];
+            }
+            case 'context':
+                // [QUIC varint channel][packet]; channels >= 64 take two bytes
+                if (value[0] & 0x40) {
+                    return [((value[0] & 0x3F) << 8) | value[1], value.subarray(2)];
+                }
+                return [value[0], value.subarray(1)];
+            default:
+                // [channel][packet]
+                return [value[0], value.subarray(1)];
         }
     }
 
     parseSDP(sdpText) {
+        // Video codec of the first video track; anything but VP9 and AV1 is treated as H.264
+        const video = sdpText.split(/\r?\nm=/).find(section => section.startsWith('video')) || '';
+        const rtpmap = video.match(/a=rtpmap:\d+\s+([\w-]+)\//);
+        const fmtp = (name, fallback) => {
+            const match = video.match(new RegExp(`[;\\s]${name}=(\\d+)`));
+            return match ? parseInt(match[1], 10) : fallback;
+        };
+        const encoding = rtpmap ? rtpmap[1].toUpperCase() : 'H264';
+        if (encoding === 'VP9') {
+            this.videoCodec = 'VP9';
+            this.codecString = `vp09.${String(fmtp('profile-id', 0)).padStart(2, '0')}.10.08`;
+            this.depacketizer = new Vp9Depacketizer(this.onFrame.bind(this));
+        } else if (encoding === 'AV1') {
+            this.videoCodec = 'AV1';
+            const level = String(fmtp('level-idx', 8)).padStart(2, '0');
+            this.codecString = `av01.${fmtp('profile', 0)}.${level}${fmtp('tier', 0) ? 'H' : 'M'}.08`;
+            this.depacketizer = new Av1Depacketizer(this.onFrame.bind(this));
+        }
+        if (this.codecString) {
+            log(`Video codec: ${this.videoCodec} (${this.codecString})`);
+            return;
+        }
+
         // Extract profile-level-id
         const profileMatch = sdpText.match(/profile-level-id=([0-9a-fA-F]+)/);
         if (profileMatch) {
@@ -640,6 +1464,29 @@ class RTSPClient {
         }
     }
 
+    // Whole frame from the VP9 or AV1 depacketizer
+    onFrame(data, timestamp, isKey) {
+        if (!this.decoder || this.decoder.state !== 'configured') {
+            return;
+        }
+        if (!this.hasSeenKeyFrame) {
+            if (!isKey) return;
+            this.hasSeenKeyFrame = true;
+        }
+
+        const chunk = new EncodedVideoChunk({
+            type: isKey ? 'key' : 'delta',
+            timestamp: (timestamp / 90000) * 1_000_000,
+            data,
+            transfer: [data.buffer]
+        });
+        try {
+            this.decoder.decode(chunk);
+        } catch (e) {
+            log(`Decode error: ${e}`, 'error');
+        }
+    }
+
     onNalUnit(data, timestamp) {
         // data is Annex B NAL Unit (00 00 00 01 <NAL>)
         // timestamp is RTP timestamp
@@ -709,13 +1556,15 @@ class RTSPClient {
 }
 
 self.onmessage = (e) => {
-    const { type, url, rtspUrl, canvas } = e.data;
+    const { type, url, rtspUrl, subUrl, rendition, token, compress, latency, canvas } = e.data;
     if (type === 'init') {
-        self.client = new RTSPClient(url, rtspUrl, canvas);
+        self.client = new RTSPClient(url, rtspUrl, canvas, subUrl, rendition, token, compress, latency);
         self.client.connect();
     } else if (type === 'startRecording') {
         if (self.client) self.client.startRecording();
     } else if (type === 'stopRecording') {
         if (self.client) self.client.stopRecording();
+    } else if (type === 'command') {
+        if (self.client) self.client.sendCommand(e.data.command, e.data.params);
     }
 };
@@ -0,0 +1,150 @@
+//! The per-message and per-packet work of a session: RTSP parsing, media
+//! framing and the depacketizers. Run with `cargo bench`.
+//!
+//! The crate has no library target, so the modules under test are built
+//! from their sources here and may only depend on each other.
+
+#![allow(dead_code)]
+
+#[path = "../src/aac.rs"]
+mod aac;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/h264.rs"]
+mod h264;
+#[path = "../src/protocol.rs"]
+mod protocol;
+#[path = "../src/rtp_header.rs"]
+mod rtp_header;
+#[path = "../src/rtsp.rs"]
+mod rtsp;
+#[path = "../src/sdp.rs"]
+mod sdp;
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::hint::black_box;
+use protocol::Framing;
+use rtsp::{RtspRequest, RtspResponse};
+use sdp::SessionDescription;
+
+const SETUP: &[u8] = b"SETUP rtsp://camera.example.com/stream/trackID=1 RTSP/1.0\r\n\
+CSeq: 3\r\n\
+Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\
+User-Agent: rtsp2browser\r\n\
+\r\n";
+
+const DESCRIBE_RESPONSE: &[u8] = b"RTSP/1.0 200 OK\r\n\
+CSeq: 2\r\n\
+Content-Base: rtsp://camera.example.com/stream/\r\n\
+Content-Type: application/sdp\r\n\
+Content-Length: 311\r\n\
+\r\n\
+v=0\r\n\
+o=- 0 0 IN IP4 0.0.0.0\r\n\
+s=Stream\r\n\
+t=0 0\r\n\
+m=video 0 RTP/AVP 96\r\n\
+a=rtpmap:96 H264/90000\r\n\
+a=fmtp:96 packetization-mode=1;profile-level-id=640028\r\n\
+a=control:trackID=1\r\n\
+m=audio 0 RTP/AVP 97\r\n\
+a=rtpmap:97 MPEG4-GENERIC/48000/2\r\n\
+a=fmtp:97 mode=AAC-hbr;sizelength=13;indexlength=3;config=1190\r\n\
+a=control:trackID=2\r\n";
+
+/// A typical video packet, close to a 1500-byte MTU
+const PACKET_LEN: usize = 1400;
+
+fn rtp_packet(seq: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x80, if marker { 0xE0 } else { 0x60 }];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// One IDR picture split into FU-A packets (RFC 6184)
+fn fu_a_picture(size: usize) -> Vec<Vec<u8>> {
+    let chunk = PACKET_LEN - 12 - 2;
+    let count = size.div_ceil(chunk);
+    (0..count)
+        .map(|i| {
+            let start = if i == 0 { 0x80 } else { 0 };
+            let end = if i + 1 == count { 0x40 } else { 0 };
+            let mut payload = vec![0x7C, start | end | 5];
+            payload.resize(2 + chunk.min(size - i * chunk), 0xAB);
+            rtp_packet(i as u16, 90_000, i + 1 == count, &payload)
+        })
+        .collect()
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rtsp");
+    group.bench_function("parse_request", |b| b.iter(|| RtspRequest::parse(black_box(SETUP)).unwrap()));
+    group.bench_function("parse_response", |b| b.iter(|| RtspResponse::parse(black_box(DESCRIBE_RESPONSE)).unwrap()));
+    let (response, _) = RtspResponse::parse(DESCRIBE_RESPONSE).unwrap().unwrap();
+    let body = String::from_utf8(response.body).unwrap();
+    group.bench_function("parse_sdp", |b| b.iter(|| SessionDescription::parse(black_box(&body))));
+    group.finish();
+}
+
+fn framing(c: &mut Criterion) {
+    let packet = rtp_packet(1, 0, false, &[0xAB; PACKET_LEN - 12]);
+    let mut group = c.benchmark_group("framing");
+    group.throughput(Throughput::Bytes(packet.len() as u64));
+    for framing in [Framing::Prefix, Framing::Rfc4571, Framing::Context] {
+        group.bench_function(format!("frame/{}", framing.as_str()), |b| {
+            b.iter_batched_ref(
+                || BytesMut::with_capacity(PACKET_LEN + 3),
+                |out| {
+                    framing.begin(black_box(2), out);
+                    out.extend_from_slice(&packet);
+                    framing.finish(out);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        let mut framed = BytesMut::new();
+        framing.begin(2, &mut framed);
+        framed.extend_from_slice(&packet);
+        framing.finish(&mut framed);
+        group.bench_function(format!("split/{}", framing.as_str()), |b| b.iter(|| framing.split(black_box(&framed))));
+    }
+    group.finish();
+}
+
+fn depacketizers(c: &mut Criterion) {
+    let picture = fu_a_picture(64 * 1024);
+    let mut group = c.benchmark_group("depacketize");
+    group.throughput(Throughput::Bytes(picture.iter().map(|p| p.len() as u64).sum()));
+    group.bench_function("h264_fu_a", |b| {
+        b.iter_batched_ref(
+            h264::Depacketizer::default,
+            |depacketizer| {
+                for packet in &picture {
+                    black_box(depacketizer.push(packet));
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let (response, _) = RtspResponse::parse(DESCRIBE_RESPONSE).unwrap().unwrap();
+    let sdp = SessionDescription::parse(&String::from_utf8(response.body).unwrap());
+    let depacketizer = aac::AacDepacketizer::new(&sdp.media[1]);
+    // Four 256-byte frames behind one AU header each (13-bit size, 3-bit index)
+    let mut payload = vec![0x00, 0x40];
+    for _ in 0..4 {
+        payload.extend_from_slice(&((256u16 << 3).to_be_bytes()));
+    }
+    payload.extend_from_slice(&[0x21; 4 * 256]);
+    let packet = rtp_packet(1, 0, true, &payload);
+    group.throughput(Throughput::Bytes(packet.len() as u64));
+    group.bench_function("aac_hbr", |b| b.iter(|| depacketizer.push(black_box(&packet))));
+    group.finish();
+}
+
+criterion_group!(benches, parsing, framing, depacketizers);
+criterion_main!(benches);
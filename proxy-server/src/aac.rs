@@ -0,0 +1,112 @@
+//! AAC over RTP (RFC 3640 `mpeg4-generic`, AAC-hbr mode), for outputs that
+//! carry audio as whole frames rather than RTP
+
+use crate::sdp::MediaDescription;
+
+/// Samples per AAC-LC frame
+pub const FRAME_SAMPLES: u32 = 1024;
+
+/// The parts of the AudioSpecificConfig an ADTS header repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AacConfig {
+    /// MPEG-4 audio object type; 2 is AAC-LC
+    pub object_type: u8,
+    pub frequency_index: u8,
+    pub channels: u8,
+}
+
+impl AacConfig {
+    /// The config of an AAC-hbr track, from the `config` fmtp parameter
+    pub fn for_media(media: &MediaDescription) -> Option<Self> {
+        if !media.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("MPEG4-GENERIC")) {
+            return None;
+        }
+        if !media.fmtp_param("mode").is_some_and(|mode| mode.eq_ignore_ascii_case("AAC-hbr")) {
+            return None;
+        }
+        let hex = media.fmtp_param("config")?;
+        let bytes: Vec<u8> = (0..hex.len() / 2).map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()).collect::<Option<_>>()?;
+        let bits = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+        let config = Self { object_type: (bits >> 11) as u8, frequency_index: (bits >> 7) as u8 & 0x0F, channels: (bits >> 3) as u8 & 0x0F };
+        // ADTS only has room for the first four object types and table frequencies
+        ((1..=4).contains(&config.object_type) && config.frequency_index < 13).then_some(config)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        const RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+        RATES[self.frequency_index as usize]
+    }
+
+    /// The two-byte AudioSpecificConfig, as FLV's sequence header carries it
+    pub fn audio_specific_config(&self) -> [u8; 2] {
+        let bits = (self.object_type as u16) << 11 | (self.frequency_index as u16) << 7 | (self.channels as u16) << 3;
+        bits.to_be_bytes()
+    }
+
+    /// ADTS header for a raw frame of `len` bytes
+    pub fn adts_header(&self, len: usize) -> [u8; 7] {
+        let len = len + 7;
+        [
+            0xFF,
+            0xF1,
+            ((self.object_type - 1) << 6) | (self.frequency_index << 2) | (self.channels >> 2),
+            ((self.channels & 3) << 6) | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len & 7) << 5) as u8 | 0x1F,
+            0xFC,
+        ]
+    }
+}
+
+/// Splits RTP packets of AAC-hbr into raw frames. Frames fragmented over
+/// several packets are dropped; cameras send them whole.
+#[derive(Debug)]
+pub struct AacDepacketizer {
+    size_length: u32,
+    index_length: u32,
+}
+
+impl AacDepacketizer {
+    pub fn new(media: &MediaDescription) -> Self {
+        let param = |name, default| media.fmtp_param(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        Self { size_length: param("sizelength", 13), index_length: param("indexlength", 3) }
+    }
+
+    /// The packet's frames, each with its RTP timestamp
+    pub fn push(&self, packet: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return frames;
+        };
+        if payload.len() < 2 || packet.len() < 8 {
+            return frames;
+        }
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let header_bits = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let header_len = header_bits.div_ceil(8);
+        let Some(headers) = payload.get(2..2 + header_len) else {
+            return frames;
+        };
+        let header_size = (self.size_length + self.index_length) as usize;
+        if header_size == 0 {
+            return frames;
+        }
+        let mut data = &payload[2 + header_len..];
+        for n in 0..header_bits / header_size {
+            let size = read_bits(headers, n * header_size, self.size_length as usize);
+            let Some(frame) = data.get(..size) else {
+                break;
+            };
+            frames.push((timestamp.wrapping_add(n as u32 * FRAME_SAMPLES), frame.to_vec()));
+            data = &data[size..];
+        }
+        frames
+    }
+}
+
+fn read_bits(data: &[u8], start: usize, len: usize) -> usize {
+    (start..start + len).fold(0, |acc, bit| {
+        let set = data.get(bit / 8).is_some_and(|byte| byte >> (7 - bit % 8) & 1 == 1);
+        (acc << 1) | set as usize
+    })
+}
@@ -0,0 +1,318 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use crate::config::{Credentials, ParameterSetsConfig, QuirkRule, RtpConfig, UpstreamConfig};
+use crate::control::Event;
+use crate::feedback::Feedback;
+use crate::filter::{PayloadFilter, SourceFilter};
+use crate::memory::SessionMemory;
+use crate::paramsets::ParameterSetInjector;
+use crate::proxy::PacketPipeline;
+use crate::retransmit::Retransmitter;
+use crate::rtp::{Continuity, RtpRewriter};
+use crate::rtsp_client::RtspClient;
+use crate::sdp::{self, SessionDescription};
+use crate::sync::ClockSync;
+use crate::transport::DatagramSender;
+use crate::workers::Shard;
+
+/// Loss fraction above which a report counts as congested
+pub const CONGESTED_LOSS: f32 = 0.05;
+/// Loss fraction below which a report counts as clean
+pub const CLEAN_LOSS: f32 = 0.01;
+/// Consecutive congested reports (~1/s) before switching to the substream
+pub const DOWNGRADE_AFTER: u32 = 5;
+/// Consecutive clean reports before switching back to the main stream
+pub const UPGRADE_AFTER: u32 = 30;
+/// Keep-alive period for the proxy-driven substream session
+const SUBSTREAM_KEEPALIVE: Duration = Duration::from_secs(30);
+/// How often the substream checks whether the main profile has taken over again
+const SETTLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Upstream camera profile feeding the browser's video channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Profile {
+    Main = 0,
+    Sub = 1,
+}
+
+impl Profile {
+    fn from_u8(v: u8) -> Self {
+        if v == Profile::Sub as u8 { Profile::Sub } else { Profile::Main }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Main => "main",
+            Profile::Sub => "sub",
+        }
+    }
+}
+
+/// Decides which profile's packets reach the browser.
+///
+/// A switch is requested by setting the desired profile; the old profile keeps
+/// flowing until the new one delivers a keyframe, so the decoder never sees a
+/// stream it cannot start from.
+#[derive(Debug)]
+pub struct ProfileGate {
+    desired: AtomicU8,
+    active: AtomicU8,
+    /// Tells the browser when a switch takes effect
+    notify: mpsc::Sender<Event>,
+}
+
+impl ProfileGate {
+    pub fn new(notify: mpsc::Sender<Event>) -> Self {
+        Self {
+            desired: AtomicU8::new(Profile::Main as u8),
+            active: AtomicU8::new(Profile::Main as u8),
+            notify,
+        }
+    }
+
+    /// Profile currently reaching the browser
+    pub fn active(&self) -> Profile {
+        Profile::from_u8(self.active.load(Ordering::Relaxed))
+    }
+
+    pub fn request(&self, profile: Profile) {
+        self.desired.store(profile as u8, Ordering::Relaxed);
+    }
+
+    /// True once the browser is back on the main profile and nothing else is pending
+    fn settled_on_main(&self) -> bool {
+        self.desired.load(Ordering::Relaxed) == Profile::Main as u8
+            && self.active.load(Ordering::Relaxed) == Profile::Main as u8
+    }
+
+    fn admit(&self, profile: Profile, rtcp: bool, packet: &[u8]) -> bool {
+        let active = self.active();
+        if profile == active {
+            return true;
+        }
+        if rtcp || profile != Profile::from_u8(self.desired.load(Ordering::Relaxed)) {
+            return false;
+        }
+        if crate::rtp::is_keyframe_start(packet) {
+            info!("Switching video to {:?} profile at keyframe", profile);
+            self.active.store(profile as u8, Ordering::Relaxed);
+            let _ = self.notify.try_send(Event::Profile(profile));
+            return true;
+        }
+        false
+    }
+}
+
+/// Per-forwarder view of a [`ProfileGate`]
+#[derive(Debug, Clone)]
+pub struct GateHandle {
+    gate: Arc<ProfileGate>,
+    profile: Profile,
+    rtcp: bool,
+}
+
+impl GateHandle {
+    pub fn new(gate: Arc<ProfileGate>, profile: Profile, rtcp: bool) -> Self {
+        Self { gate, profile, rtcp }
+    }
+
+    pub fn admit(&self, packet: &[u8]) -> bool {
+        self.gate.admit(self.profile, self.rtcp, packet)
+    }
+}
+
+/// Turns a series of client feedback reports into switch decisions
+#[derive(Debug)]
+pub struct CongestionMonitor {
+    current: Profile,
+    congested_streak: u32,
+    clean_streak: u32,
+    /// Set when something other than feedback fixed the profile
+    held: bool,
+}
+
+impl CongestionMonitor {
+    pub fn new() -> Self {
+        Self {
+            current: Profile::Main,
+            congested_streak: 0,
+            clean_streak: 0,
+            held: false,
+        }
+    }
+
+    /// Stay on `profile` whatever the feedback says
+    pub fn hold(&mut self, profile: Profile) {
+        self.current = profile;
+        self.held = true;
+    }
+
+    /// Returns the profile to switch to when conditions have been sustained long enough
+    pub fn on_feedback(&mut self, fb: &Feedback) -> Option<Profile> {
+        if self.held {
+            return None;
+        }
+        if fb.loss_fraction >= CONGESTED_LOSS {
+            self.congested_streak += 1;
+            self.clean_streak = 0;
+        } else if fb.loss_fraction <= CLEAN_LOSS {
+            self.clean_streak += 1;
+            self.congested_streak = 0;
+        } else {
+            self.congested_streak = 0;
+            self.clean_streak = 0;
+        }
+
+        let next = match self.current {
+            Profile::Main if self.congested_streak >= DOWNGRADE_AFTER => Profile::Sub,
+            Profile::Sub if self.clean_streak >= UPGRADE_AFTER => Profile::Main,
+            _ => return None,
+        };
+        self.current = next;
+        self.congested_streak = 0;
+        self.clean_streak = 0;
+        Some(next)
+    }
+}
+
+/// Browser-side channels and RTP identity the substream must feed
+#[derive(Debug, Clone)]
+pub struct SubstreamTarget {
+    pub rtp_channel_id: u8,
+    pub rtcp_channel_id: u8,
+    /// SSRC the browser already sees for the video track
+    pub ssrc: u32,
+    pub clock_rate: u32,
+    pub rtp: RtpConfig,
+    /// Quirk rule configured for the camera
+    pub quirks: Option<QuirkRule>,
+    pub upstream: UpstreamConfig,
+    /// Output timeline shared with the main profile's forwarders
+    pub continuity: Arc<Continuity>,
+    /// Notifications for the browser's control channel
+    pub notify: mpsc::Sender<Event>,
+    /// Session the substream's buffers are accounted to
+    pub memory: Arc<SessionMemory>,
+    /// Sent packets kept for the browser's NACKs, in balanced mode
+    pub retransmit: Option<Retransmitter>,
+    /// The sub-stream's IDRs get its SPS and PPS as the main profile's do
+    pub parameter_sets: ParameterSetsConfig,
+}
+
+/// Run a proxy-driven session on the lower-bitrate profile, feeding its video
+/// track into the browser's existing video channel IDs.
+///
+/// The session ends once the gate has switched back to the main profile, or
+/// when cancelled.
+pub async fn run_substream(
+    url: String,
+    credentials: Option<Credentials>,
+    target: SubstreamTarget,
+    sender: DatagramSender,
+    gate: Arc<ProfileGate>,
+    token: CancellationToken,
+) -> Result<()> {
+    let parsed = url::Url::parse(&url)?;
+    let mut client = RtspClient::connect(&parsed, &target.upstream).await?.with_credentials(credentials).with_quirks(target.quirks.clone());
+
+    let mut describe = client.request_ok("DESCRIBE", &url, &[("Accept", "application/sdp".to_string())]).await?;
+    if target.upstream.rewrite_advertised_addresses {
+        crate::nat::rewrite_describe(&mut describe, &parsed);
+    }
+    let sdp = SessionDescription::parse(&String::from_utf8_lossy(&describe.body));
+    let base = sdp::content_base(&describe.headers, &url);
+    let video = sdp.first_video().ok_or_else(|| anyhow!("Substream has no video track"))?;
+    let track_url = sdp::resolve_control(&base, video.control.as_deref());
+
+    let shard = Shard::next();
+    let rtp_socket = Arc::new(shard.bind_udp().await?);
+    let rtcp_socket = Arc::new(shard.bind_udp().await?);
+    let ports = (rtp_socket.local_addr()?.port(), rtcp_socket.local_addr()?.port());
+    let (rtp_port, rtcp_port) = crate::mux::client_ports().unwrap_or(ports);
+    let transport = format!("RTP/AVP;unicast;client_port={}-{}", rtp_port, rtcp_port);
+    let setup = client.request_ok("SETUP", &track_url, &[("Transport", transport)]).await?;
+    let camera_transport = setup.headers.get("Transport").cloned().unwrap_or_default();
+    let server_ports = crate::nat::server_ports(&camera_transport);
+    let source = crate::nat::source_address(&camera_transport).unwrap_or(client.camera_ip());
+    // Held until the substream is torn down
+    let mux_route = crate::mux::MuxRoute::new(&camera_transport, source, target.upstream.validate_source, ports.0, ports.1);
+    if target.upstream.hole_punch && let Some(server_ports) = server_ports {
+        if mux_route.is_some() {
+            crate::mux::punch(source, server_ports).await;
+        } else {
+            crate::nat::punch(&rtp_socket, &rtcp_socket, source, server_ports).await;
+        }
+    }
+
+    let aggregate_url = sdp.aggregate_url(&base);
+    client.request_ok("PLAY", &aggregate_url, &[("Range", "npt=0.000-".to_string())]).await?;
+    info!("Substream {} playing into channels {}-{}", url, target.rtp_channel_id, target.rtcp_channel_id);
+
+    let forwarders = token.child_token();
+    for (socket, channel_id, rtcp) in [(rtp_socket, target.rtp_channel_id, false), (rtcp_socket, target.rtcp_channel_id, true)] {
+        let sender = sender.clone();
+        let port = server_ports.map(|(rtp_port, rtcp_port)| if rtcp { rtcp_port } else { rtp_port });
+        let pipeline = PacketPipeline {
+            source: SourceFilter::new(target.upstream.validate_source, source, port).filter(|_| mux_route.is_none()),
+            payload: PayloadFilter::new(&target.rtp, Some(&video.formats), rtcp),
+            seek: None,
+            // The browser's channel keeps the main profile's meter
+            receive: None,
+            motion: None,
+            cadence: None,
+            bye: None,
+            gate: Some(GateHandle::new(gate.clone(), Profile::Sub, rtcp)),
+            demux: None,
+            audio: None,
+            metadata: None,
+            remux: None,
+            flow: None,
+            parameter_sets: if rtcp { None } else { ParameterSetInjector::new(video, &target.parameter_sets) },
+            reconfig: None,
+            retransmit: if rtcp { None } else { target.retransmit.clone() },
+            rewriter: Some(RtpRewriter::continuous(&target.rtp, target.ssrc, rtcp, target.continuity.clone(), Profile::Sub as u8)),
+            sync: rtcp.then(|| ClockSync::new(target.rtp_channel_id, target.clock_rate, target.notify.clone())),
+            media: None,
+            startup: None,
+            memory: Some(target.memory.clone()),
+        };
+        let token = forwarders.clone();
+        shard.spawn(async move {
+            if let Err(e) = crate::proxy::forward_udp(socket, sender, channel_id, pipeline, token).await {
+                error!("Substream forwarder error: {}", e);
+            }
+        });
+    }
+
+    let mut keepalive = tokio::time::interval(SUBSTREAM_KEEPALIVE);
+    keepalive.tick().await;
+    let mut settle = tokio::time::interval(SETTLE_CHECK);
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = settle.tick() => {
+                if gate.settled_on_main() {
+                    break;
+                }
+            }
+            _ = keepalive.tick() => {
+                let method = client.keepalive_method();
+                if let Err(e) = client.request(method, &aggregate_url, &[]).await {
+                    warn!("Substream keep-alive failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    forwarders.cancel();
+    info!("Tearing down substream {}", url);
+    let _ = client.request("TEARDOWN", &aggregate_url, &[]).await;
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Audio level and voice activity for forwarded audio tracks, so a player can
+//! show "audio detected" without decoding the audio itself. Only sample
+//! formats the proxy can read directly (G.711, L16) are measured.
+
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use crate::config::AudioLevelConfig;
+use crate::control::Event;
+use crate::sdp::MediaDescription;
+
+/// Level reported for digital silence, as in RFC 6464
+const SILENCE_DBFS: f32 = -127.0;
+/// Voice stays active this long after the last window over the threshold,
+/// so pauses between words do not flicker the indicator
+const VOICE_HANGOVER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SampleFormat {
+    /// G.711 µ-law
+    Pcmu,
+    /// G.711 A-law
+    Pcma,
+    /// 16-bit big-endian linear PCM
+    L16,
+}
+
+impl SampleFormat {
+    fn for_media(media: &MediaDescription) -> Option<Self> {
+        match media.encoding.as_deref() {
+            Some(encoding) if encoding.eq_ignore_ascii_case("PCMU") => Some(Self::Pcmu),
+            Some(encoding) if encoding.eq_ignore_ascii_case("PCMA") => Some(Self::Pcma),
+            Some(encoding) if encoding.eq_ignore_ascii_case("L16") => Some(Self::L16),
+            Some(_) => None,
+            // Static payload types need no rtpmap
+            None => match media.payload_type {
+                Some(0) => Some(Self::Pcmu),
+                Some(8) => Some(Self::Pcma),
+                Some(10 | 11) => Some(Self::L16),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Measures one audio track's RTP and reports its level once per interval
+#[derive(Debug)]
+pub struct AudioMeter {
+    channel_id: u8,
+    format: SampleFormat,
+    interval: Duration,
+    threshold_dbfs: f32,
+    tx: mpsc::Sender<Event>,
+    sum_squares: f64,
+    samples: u64,
+    window_start: Instant,
+    voice_until: Option<Instant>,
+}
+
+impl AudioMeter {
+    /// None when the track's encoding cannot be measured
+    pub fn new(media: &MediaDescription, channel_id: u8, config: &AudioLevelConfig, tx: mpsc::Sender<Event>) -> Option<Self> {
+        let format = SampleFormat::for_media(media)?;
+        Some(Self {
+            channel_id,
+            format,
+            interval: Duration::from_millis(config.interval_ms.max(100)),
+            threshold_dbfs: config.vad_threshold_dbfs,
+            tx,
+            sum_squares: 0.0,
+            samples: 0,
+            window_start: Instant::now(),
+            voice_until: None,
+        })
+    }
+
+    /// Inspect one RTP packet as received from the camera
+    pub fn on_rtp(&mut self, packet: &[u8]) {
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return;
+        };
+        let mut add = |sample: i16| {
+            self.sum_squares += (sample as f64) * (sample as f64);
+            self.samples += 1;
+        };
+        match self.format {
+            SampleFormat::Pcmu => payload.iter().for_each(|&b| add(ulaw_to_linear(b))),
+            SampleFormat::Pcma => payload.iter().for_each(|&b| add(alaw_to_linear(b))),
+            SampleFormat::L16 => payload.chunks_exact(2).for_each(|s| add(i16::from_be_bytes([s[0], s[1]]))),
+        }
+
+        if self.window_start.elapsed() < self.interval {
+            return;
+        }
+        let level_dbfs = self.level_dbfs();
+        let now = Instant::now();
+        if level_dbfs >= self.threshold_dbfs {
+            self.voice_until = Some(now + VOICE_HANGOVER);
+        }
+        let voice = self.voice_until.is_some_and(|until| now < until);
+        self.sum_squares = 0.0;
+        self.samples = 0;
+        self.window_start = now;
+        // A level that arrives late is already stale, so drop it rather than wait
+        let _ = self.tx.try_send(Event::Audio { channel_id: self.channel_id, level_dbfs, voice });
+    }
+
+    /// RMS level of the current window relative to full scale, to 0.1 dB
+    fn level_dbfs(&self) -> f32 {
+        if self.samples == 0 || self.sum_squares == 0.0 {
+            return SILENCE_DBFS;
+        }
+        let rms = (self.sum_squares / self.samples as f64).sqrt() / 32768.0;
+        let dbfs = (20.0 * rms.log10()).max(SILENCE_DBFS as f64);
+        (dbfs * 10.0).round() as f32 / 10.0
+    }
+}
+
+/// G.711 µ-law expansion (ITU-T G.711)
+fn ulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i16;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 != 0 { -magnitude } else { magnitude }
+}
+
+/// G.711 A-law expansion (ITU-T G.711)
+fn alaw_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i16;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    if byte & 0x80 != 0 { magnitude } else { -magnitude }
+}
@@ -0,0 +1,102 @@
+//! External authorization: before a session starts, the proxy POSTs who is
+//! asking for which camera to `[authorizer] url` and honours the answer
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+use crate::config::AuthorizerConfig;
+use crate::limits::SessionLimits;
+
+/// Largest authorizer response read
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// Body POSTed to the authorizer
+#[derive(Debug, Serialize)]
+pub struct AuthRequest<'a> {
+    /// Address the browser connected from
+    pub client_address: Option<SocketAddr>,
+    pub origin: Option<&'a str>,
+    /// `token` query parameter, as sent
+    pub token: Option<&'a str>,
+    /// Subject CN of the client's TLS certificate, or its first other name
+    pub client_cert: Option<&'a str>,
+    pub tenant: Option<&'a str>,
+    pub rtsp_url: &'a str,
+    pub substream_url: Option<&'a str>,
+    /// "webtransport", "websocket" or "tcp"
+    pub transport: &'static str,
+}
+
+/// Authorizer's JSON answer to a 200 response
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Answer {
+    allow: bool,
+    reason: Option<String>,
+    max_duration_s: Option<u64>,
+    max_bitrate_kbps: Option<u64>,
+}
+
+/// Ask the authorizer; Ok with the session's limits, or Err with why it was denied
+pub async fn authorize(config: &AuthorizerConfig, request: &AuthRequest<'_>) -> Result<SessionLimits> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let outcome = match tokio::time::timeout(timeout, ask(config, request)).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(anyhow!("No answer within {:?}", timeout)),
+    };
+    let answer = match outcome {
+        Ok(answer) => answer,
+        Err(e) if config.fail_open => {
+            warn!("Authorizer failed, allowing the session: {:#}", e);
+            return Ok(SessionLimits::default());
+        }
+        Err(e) => return Err(e.context("Authorizer unavailable")),
+    };
+    if !answer.allow {
+        bail!("{}", answer.reason.unwrap_or_else(|| "Denied by the authorizer".to_string()));
+    }
+    Ok(SessionLimits {
+        max_duration: answer.max_duration_s.map(Duration::from_secs),
+        max_bitrate_kbps: answer.max_bitrate_kbps,
+        ..Default::default()
+    })
+}
+
+/// One POST; 401 and 403 are denials, other non-200 statuses are failures
+async fn ask(config: &AuthorizerConfig, request: &AuthRequest<'_>) -> Result<Answer> {
+    let url = url::Url::parse(&config.url).context("Invalid authorizer URL")?;
+    if url.scheme() != "http" {
+        bail!("Authorizer URL must be http://, not {}", url.scheme());
+    }
+    let host = url.host_str().context("Authorizer URL has no host")?;
+    let port = url.port().unwrap_or(80);
+    let body = serde_json::to_vec(request)?;
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rtsp2browser\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        &url[url::Position::BeforePath..],
+        host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").context("Incomplete authorizer response")?;
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(serde_json::from_str(body).context("Invalid authorizer answer")?),
+        Some("401") | Some("403") => {
+            let reason = serde_json::from_str::<Answer>(body).ok().and_then(|a| a.reason);
+            Ok(Answer { allow: false, reason: reason.or_else(|| Some(status_line.to_string())), ..Default::default() })
+        }
+        _ => bail!("Authorizer answered {}", status_line),
+    }
+}
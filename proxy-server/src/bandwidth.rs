@@ -0,0 +1,71 @@
+//! Receiver-driven bandwidth estimation: the browser acknowledges the media
+//! it has received, and the proxy compares that with what it sent to learn
+//! how much the client can take. Estimates reach the sub-stream switch and
+//! the transcode ladder as [`Feedback`] reports.
+
+use std::time::{Duration, Instant};
+use crate::adaptive::{CLEAN_LOSS, CONGESTED_LOSS};
+use crate::feedback::{Ack, Feedback};
+
+/// Shortest span an estimate is taken over; acks closer together than this
+/// are folded into the next one
+const MIN_INTERVAL: Duration = Duration::from_millis(900);
+/// Growth per clean interval, so the estimate probes above the current rate
+const INCREASE: f64 = 1.05;
+/// How far above what was delivered the estimate may grow
+const MAX_HEADROOM: f64 = 1.5;
+
+/// Media counters at one acknowledgment
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    sent: u64,
+    received: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct BandwidthEstimator {
+    last: Option<Sample>,
+    estimate_kbps: Option<f64>,
+}
+
+impl BandwidthEstimator {
+    /// Take in an acknowledgment, `sent` being the proxy's byte count when it
+    /// arrived. Returns a report once enough time has passed since the last.
+    ///
+    /// Both counts lag by the bytes in flight, which cancels out between two
+    /// acks at a steady rate. Loss above [`CONGESTED_LOSS`] lowers the
+    /// estimate in proportion, as in loss-based congestion control; a clean
+    /// interval raises it a step, up to [`MAX_HEADROOM`] over the delivered
+    /// rate.
+    pub fn on_ack(&mut self, ack: Ack, sent: u64, now: Instant) -> Option<Feedback> {
+        let sample = Sample { at: now, sent, received: ack.bytes_received };
+        let Some(last) = self.last else {
+            self.last = Some(sample);
+            return None;
+        };
+        let elapsed = now.duration_since(last.at);
+        if elapsed < MIN_INTERVAL {
+            return None;
+        }
+        self.last = Some(sample);
+
+        // Nothing sent says nothing about the client
+        let sent_bytes = sent.saturating_sub(last.sent);
+        if sent_bytes == 0 {
+            return None;
+        }
+        let received_bytes = u64::from(ack.bytes_received.wrapping_sub(last.received));
+        let delivered_kbps = received_bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1000.0;
+        let loss_fraction = 1.0 - received_bytes.min(sent_bytes) as f64 / sent_bytes as f64;
+
+        let estimate = match self.estimate_kbps {
+            _ if loss_fraction as f32 >= CONGESTED_LOSS => delivered_kbps * (1.0 - loss_fraction / 2.0),
+            Some(estimate) if loss_fraction as f32 <= CLEAN_LOSS => (estimate * INCREASE).max(delivered_kbps).min(delivered_kbps * MAX_HEADROOM),
+            Some(estimate) => estimate,
+            None => delivered_kbps,
+        };
+        self.estimate_kbps = Some(estimate);
+        Some(Feedback { loss_fraction: loss_fraction as f32, received_kbps: delivered_kbps as u32, estimate_kbps: Some(estimate as u32) })
+    }
+}
@@ -0,0 +1,51 @@
+//! Cap on the sessions the proxy opens to one camera at a time. Many
+//! embedded cameras fall over beyond a few RTSP clients, so viewers over the
+//! cap wait for a slot or are refused.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A camera's slots, with the limit they were made for
+type Slots = (usize, Arc<Semaphore>);
+
+/// Slots of each camera by `host:port`
+static CAMERAS: LazyLock<Mutex<HashMap<String, Slots>>> = LazyLock::new(Default::default);
+
+/// One of a camera's slots, held for as long as the session runs
+pub struct CameraSlot {
+    _permit: OwnedSemaphorePermit,
+    _gauge: crate::stats::GaugeGuard,
+}
+
+fn slots(camera: &str, limit: usize) -> Arc<Semaphore> {
+    let mut cameras = CAMERAS.lock().unwrap();
+    // Held and awaited slots keep a reference; forget idle cameras
+    cameras.retain(|_, (_, slots)| Arc::strong_count(slots) > 1);
+    match cameras.get(camera) {
+        Some((made_for, slots)) if *made_for == limit => slots.clone(),
+        // New camera, or a reloaded config changed its limit; sessions
+        // holding the old slots keep them until they end
+        _ => {
+            let slots = Arc::new(Semaphore::new(limit.max(1)));
+            cameras.insert(camera.to_string(), (limit, slots.clone()));
+            slots
+        }
+    }
+}
+
+fn slot(camera: &str, permit: OwnedSemaphorePermit) -> CameraSlot {
+    let gauge = crate::stats::global().track(format!("camera_sessions{{camera=\"{}\"}}", camera));
+    CameraSlot { _permit: permit, _gauge: gauge }
+}
+
+/// A slot at the camera if one is free now
+pub fn try_acquire(camera: &str, limit: usize) -> Option<CameraSlot> {
+    slots(camera, limit).try_acquire_owned().ok().map(|permit| slot(camera, permit))
+}
+
+/// Wait for a slot at the camera
+pub async fn acquire(camera: &str, limit: usize) -> CameraSlot {
+    let permit = slots(camera, limit).acquire_owned().await.expect("camera slots are never closed");
+    slot(camera, permit)
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use crate::config::{Credentials, QuirkRule, UpstreamConfig};
+use crate::rtsp::RtspRequest;
+use crate::rtsp_client::RtspClient;
+use crate::sdp::{SessionDescription, StreamInfo};
+
+/// Methods a camera advertises in the `Public` header of its OPTIONS
+/// response, and the streams its DESCRIBE response offers
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub methods: Vec<String>,
+    pub streams: Vec<StreamInfo>,
+}
+
+impl Capabilities {
+    pub fn from_public(public: &str) -> Self {
+        let methods = public
+            .split(',')
+            .map(|m| m.trim().to_ascii_uppercase())
+            .filter(|m| !m.is_empty())
+            .collect();
+        Self { methods, streams: Vec::new() }
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// Notification telling the player which controls it can offer
+    pub fn notification(&self) -> RtspRequest {
+        let flag = |method| if self.supports(method) { "yes" } else { "no" };
+        let mut params = format!(
+            "x-wt-capabilities: pause={};get-parameter={};set-parameter={};methods={}",
+            flag("PAUSE"),
+            flag("GET_PARAMETER"),
+            flag("SET_PARAMETER"),
+            self.methods.join(",")
+        );
+        // kind:codec:<width>x<height>:fps:kbps:profile:level per stream; empty fields when unknown
+        if !self.streams.is_empty() {
+            let field = |value: Option<String>| value.unwrap_or_default();
+            let streams: Vec<String> = self
+                .streams
+                .iter()
+                .map(|s| {
+                    let size = s.width.zip(s.height).map(|(w, h)| format!("{}x{}", w, h));
+                    format!(
+                        "{}:{}:{}:{}:{}:{}:{}",
+                        s.kind,
+                        field(s.codec.clone()),
+                        field(size),
+                        field(s.fps.map(|fps| fps.to_string())),
+                        field(s.bitrate_kbps.map(|kbps| kbps.to_string())),
+                        field(s.profile.clone()),
+                        field(s.level.clone())
+                    )
+                })
+                .collect();
+            params.push_str(&format!(";streams={}", streams.join("/")));
+        }
+        params.push_str("\r\n");
+        RtspRequest::notification(&params)
+    }
+}
+
+/// Ask the camera for its supported methods and streams on a separate
+/// connection, so the browser's own request sequence is left untouched. A
+/// refused DESCRIBE leaves the streams empty.
+pub async fn discover(
+    url: &str,
+    credentials: Option<Credentials>,
+    quirks: Option<QuirkRule>,
+    upstream: &UpstreamConfig,
+) -> Result<Capabilities> {
+    let parsed = url::Url::parse(url)?;
+    let mut client = RtspClient::connect(&parsed, upstream).await?.with_credentials(credentials).with_quirks(quirks);
+    let resp = client.request_ok("OPTIONS", url, &[]).await?;
+    let mut capabilities = resp.headers.get("Public").map(|p| Capabilities::from_public(p)).unwrap_or_default();
+    let describe = client.request("DESCRIBE", url, &[("Accept", "application/sdp".to_string())]).await?;
+    if (200..300).contains(&describe.status_code) {
+        let sdp = SessionDescription::parse(&String::from_utf8_lossy(&describe.body));
+        capabilities.streams = sdp.media.iter().map(|media| media.info()).collect();
+    }
+    Ok(capabilities)
+}
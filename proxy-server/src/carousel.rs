@@ -0,0 +1,62 @@
+//! Carousel sessions (`playlist`): one connection shows several cameras in
+//! turn, for lobby displays. Each camera gets a simple-mode session of its
+//! own on the shared connection; when its dwell time is up the proxy tears
+//! it down, announces the switch and sets up the next.
+
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use crate::control::{ControlCodec, Event};
+use crate::error::{Error, Result};
+use crate::limits::SessionLimits;
+use crate::proxy::{Ended, RTSPProxy};
+use crate::rtp::SessionTimeline;
+use crate::transport::Transport;
+
+/// Dwell time when the client does not pass `dwell`
+pub const DEFAULT_DWELL: Duration = Duration::from_secs(30);
+/// Most cameras one playlist may name
+pub const MAX_CAMERAS: usize = 32;
+
+/// Cycle through `playlist` until the browser leaves or the session fails.
+/// `proxy` makes the session for one camera, with the limits left.
+pub async fn run(
+    playlist: &[String],
+    dwell: Duration,
+    mut transport: Transport,
+    mut control: ControlCodec,
+    limits: SessionLimits,
+    proxy: impl Fn(&str, SessionLimits) -> RTSPProxy,
+) -> Result<()> {
+    let closer = transport.closer();
+    let started = Instant::now();
+    let count = playlist.len();
+    // Cameras that failed in a row; the carousel gives up once all have
+    let mut failed = 0;
+    for index in (0..count).cycle() {
+        let url = &playlist[index];
+        info!("Carousel showing camera {} of {}", index + 1, count);
+        if let Err(e) = transport.write_control(&control.event(&Event::Carousel { index, count })).await {
+            closer.close(&e).await;
+            return Err(e);
+        }
+        let session = proxy(url, limits.remaining(started.elapsed())).with_dwell(Some(dwell));
+        // Each camera is a stream of its own
+        match session.run(&mut transport, &SessionTimeline::default()).await {
+            // A recording that ended is done for this round, and so is a
+            // camera whose health needs a new session
+            Ok(Ended::Dwell | Ended::EndOfStream | Ended::Unhealthy(_)) => failed = 0,
+            Ok(Ended::Closed) => return Ok(()),
+            // A camera that is down is skipped, not the end of the display
+            Err(e @ (Error::UpstreamConnect(_) | Error::UpstreamClosed(_) | Error::RtspProtocol(_))) if failed + 1 < count => {
+                warn!("Carousel skipping camera {}: {}", index + 1, e);
+                crate::stats::global().add(&format!("carousel_skips{{kind=\"{}\"}}", e.kind()), 1);
+                failed += 1;
+            }
+            Err(e) => {
+                closer.close(&e).await;
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
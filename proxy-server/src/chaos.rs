@@ -0,0 +1,95 @@
+//! Fault injection for resilience testing of browser clients, built only
+//! with the `chaos` feature and set through `POST /chaos` with the admin token
+
+use serde_json::json;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::config::Config;
+use crate::http::{Request, Response};
+
+/// Faults applied to every session; 0 disables each one
+struct Faults {
+    /// Delay before each camera response is forwarded to the browser
+    response_delay_ms: AtomicU64,
+    /// Share of media datagrams dropped instead of sent, 0-100
+    drop_percent: AtomicU32,
+    /// Close the camera connection this long after the session started
+    kill_upstream_after_s: AtomicU64,
+}
+
+static FAULTS: Faults = Faults {
+    response_delay_ms: AtomicU64::new(0),
+    drop_percent: AtomicU32::new(0),
+    kill_upstream_after_s: AtomicU64::new(0),
+};
+
+/// How often a session re-checks the upstream kill setting
+const KILL_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Wait out the configured response delay
+pub async fn delay_response() {
+    let delay = FAULTS.response_delay_ms.load(Ordering::Relaxed);
+    if delay > 0 {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+}
+
+/// Whether to drop this datagram
+pub fn drop_datagram() -> bool {
+    let percent = FAULTS.drop_percent.load(Ordering::Relaxed);
+    percent > 0 && rand::random::<u32>() % 100 < percent
+}
+
+/// Resolves once a session started at `started` should lose its camera
+/// connection. Follows changes made while the session runs.
+pub async fn upstream_killed(started: Instant) {
+    loop {
+        let after = FAULTS.kill_upstream_after_s.load(Ordering::Relaxed);
+        if after > 0 && started.elapsed() >= Duration::from_secs(after) {
+            return;
+        }
+        tokio::time::sleep(KILL_CHECK_INTERVAL).await;
+    }
+}
+
+/// `POST /chaos`: set any of `response_delay_ms`, `drop_percent` and
+/// `kill_upstream_after_s` from the query, then report all three
+pub fn handle(request: &Request, config: &Config) -> Response {
+    if request.method != "POST" {
+        return Response::text("405 Method Not Allowed", "Method not allowed");
+    }
+    if let Err(refused) = crate::http::require_admin(request, config) {
+        return refused;
+    }
+    let params = request.query();
+    let parse = |name: &str| params.get(name).map(|v| v.parse::<u64>().map_err(|_| name.to_string())).transpose();
+    let (delay, drop, kill) = match (parse("response_delay_ms"), parse("drop_percent"), parse("kill_upstream_after_s")) {
+        (Ok(delay), Ok(drop), Ok(kill)) => (delay, drop, kill),
+        (Err(name), _, _) | (_, Err(name), _) | (_, _, Err(name)) => {
+            return Response::json("400 Bad Request", &json!({ "error": format!("'{}' must be a whole number", name) }));
+        }
+    };
+    if drop.is_some_and(|d| d > 100) {
+        return Response::json("400 Bad Request", &json!({ "error": "'drop_percent' must be at most 100" }));
+    }
+
+    if let Some(delay) = delay {
+        FAULTS.response_delay_ms.store(delay, Ordering::Relaxed);
+    }
+    if let Some(drop) = drop {
+        FAULTS.drop_percent.store(drop as u32, Ordering::Relaxed);
+    }
+    if let Some(kill) = kill {
+        FAULTS.kill_upstream_after_s.store(kill, Ordering::Relaxed);
+    }
+    let faults = json!({
+        "response_delay_ms": FAULTS.response_delay_ms.load(Ordering::Relaxed),
+        "drop_percent": FAULTS.drop_percent.load(Ordering::Relaxed),
+        "kill_upstream_after_s": FAULTS.kill_upstream_after_s.load(Ordering::Relaxed),
+    });
+    if delay.is_some() || drop.is_some() || kill.is_some() {
+        warn!("Chaos faults set: {}", faults);
+    }
+    Response::json("200 OK", &faults)
+}
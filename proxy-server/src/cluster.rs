@@ -0,0 +1,210 @@
+//! Cluster mode: several proxy instances behind one load balancer, sharing
+//! a registry of WebSocket sessions waiting to be paired.
+//!
+//! A session's control and data connections may land on different
+//! instances. Each instance answers `GET /cluster/pending` for control
+//! connections waiting on it; a data connection with no local partner asks
+//! the configured peers for one and, once found, is relayed to that peer.
+//! Peers talk over TLS on their `[wss]` ports, since every request carries
+//! the cluster secret.
+
+use anyhow::{bail, ensure, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::Connector;
+use tracing::{info, warn};
+use crate::config::{ClusterConfig, Config};
+use crate::http::{Request, Response};
+
+/// Header marking a connection relayed by a peer; its value is the cluster secret
+pub const RELAY_HEADER: &str = "X-Rtsp2browser-Cluster";
+/// How long a data connection looks for its control connection on peers
+const PAIR_WINDOW: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RESPONSE: u64 = 4096;
+
+/// Sessions whose control connection waits on this instance
+fn waiting() -> &'static Mutex<HashSet<String>> {
+    static WAITING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    WAITING.get_or_init(Default::default)
+}
+
+/// A control connection for `session_id` now waits here for its data connection
+pub fn control_waiting(session_id: &str) {
+    waiting().lock().unwrap().insert(session_id.to_string());
+}
+
+/// The control connection for `session_id` was paired
+pub fn control_paired(session_id: &str) {
+    waiting().lock().unwrap().remove(session_id);
+}
+
+/// Whether a handshake carrying `value` in [`RELAY_HEADER`] came from a peer
+pub fn is_relayed(config: &Config, value: Option<&str>) -> bool {
+    config.cluster.as_ref().is_some_and(|cluster| crate::http::token_matches(value, &cluster.secret))
+}
+
+/// Answer `GET /cluster/pending?session=<id>` from a peer: 200 if that
+/// session's control connection waits here, else 404
+pub fn handle(request: &Request, config: &Config) -> Response {
+    let Some(cluster) = &config.cluster else {
+        return Response::text("404 Not Found", "Not found");
+    };
+    if !crate::http::token_matches(crate::http::bearer_token(request).as_deref(), &cluster.secret) {
+        warn!("Refusing cluster request without the cluster secret");
+        return Response::json("401 Unauthorized", &json!({ "error": "Missing or wrong cluster secret" }));
+    }
+    let Some(session_id) = request.query().remove("session") else {
+        return Response::json("400 Bad Request", &json!({ "error": "Missing 'session' query parameter" }));
+    };
+    if waiting().lock().unwrap().contains(&session_id) {
+        Response::json("200 OK", &json!({ "waiting": true }))
+    } else {
+        Response::json("404 Not Found", &json!({ "waiting": false }))
+    }
+}
+
+/// The peer holding the control connection for `session_id`, polling for up
+/// to [`PAIR_WINDOW`] while `unpaired` says the data connection still waits
+pub async fn find_control(cluster: &ClusterConfig, session_id: &str, unpaired: impl Fn() -> bool) -> Option<String> {
+    let started = Instant::now();
+    while started.elapsed() < PAIR_WINDOW && unpaired() {
+        for peer in &cluster.peers {
+            match tokio::time::timeout(LOOKUP_TIMEOUT, lookup(cluster, peer, session_id)).await {
+                Ok(Ok(true)) => return Some(peer.clone()),
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => warn!("Cluster peer {} lookup failed: {:#}", peer, e),
+                Err(_) => warn!("Cluster peer {} did not answer in time", peer),
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    None
+}
+
+/// A peer's URL, refused unless it is https://
+fn peer_url(peer: &str) -> Result<url::Url> {
+    let url = url::Url::parse(peer).with_context(|| format!("Invalid cluster peer URL {}", peer))?;
+    ensure!(
+        url.scheme() == "https",
+        "Cluster peer URL must be https://, not {}; the cluster secret is only sent over TLS",
+        url.scheme()
+    );
+    Ok(url)
+}
+
+/// TLS config for connections to peers, trusting `ca` or else the system's
+/// roots; built again only when `ca` changes
+fn tls_config(cluster: &ClusterConfig) -> Result<Arc<ClientConfig>> {
+    static CACHED: Mutex<Option<(Option<PathBuf>, Arc<ClientConfig>)>> = Mutex::new(None);
+    let mut cached = CACHED.lock().unwrap();
+    if let Some((_, tls)) = cached.as_ref().filter(|(ca, _)| *ca == cluster.ca) {
+        return Ok(tls.clone());
+    }
+    let mut roots = RootCertStore::empty();
+    match &cluster.ca {
+        Some(ca) => {
+            let certs = CertificateDer::pem_file_iter(ca)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Failed to read cluster CA {}", ca.display()))?;
+            for cert in certs {
+                roots.add(cert).with_context(|| format!("Unusable certificate in cluster CA {}", ca.display()))?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                warn!("Skipping system root certificates: {}", e);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    ensure!(!roots.is_empty(), "No root certificates to check cluster peers against");
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let tls = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tls = Arc::new(tls);
+    *cached = Some((cluster.ca.clone(), tls.clone()));
+    Ok(tls)
+}
+
+/// Whether `peer` has a control connection waiting for `session_id`
+async fn lookup(cluster: &ClusterConfig, peer: &str, session_id: &str) -> Result<bool> {
+    let url = peer_url(peer)?;
+    let host = url.host_str().context("Cluster peer URL has no host")?;
+    let port = url.port().unwrap_or(443);
+    let session: String = url::form_urlencoded::byte_serialize(session_id.as_bytes()).collect();
+
+    let connector = TlsConnector::from(tls_config(cluster)?);
+    let server_name = ServerName::try_from(host.to_string()).with_context(|| format!("Invalid cluster peer host {}", host))?;
+    let stream = TcpStream::connect((host, port)).await?;
+    let mut stream = connector.connect(server_name, stream).await?;
+    let head = format!(
+        "GET /cluster/pending?session={} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rtsp2browser\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        session, host, cluster.secret
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(true),
+        Some("404") => Ok(false),
+        _ => bail!("Peer answered {}", status_line),
+    }
+}
+
+/// Relay a data connection to the peer holding its control connection,
+/// opening it there with the same path and query
+pub async fn relay<S>(browser: tokio_tungstenite::WebSocketStream<S>, cluster: &ClusterConfig, peer: &str, target: &str, origin: Option<&str>) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut url = peer_url(peer)?;
+    let _ = url.set_scheme("wss");
+    let mut request = format!("{}{}", url.as_str().trim_end_matches('/'), target).into_client_request()?;
+    request.headers_mut().insert(RELAY_HEADER, cluster.secret.parse()?);
+    if let Some(origin) = origin {
+        request.headers_mut().insert("Origin", origin.parse()?);
+    }
+    let connector = Connector::Rustls(tls_config(cluster)?);
+    let (upstream, _) = tokio_tungstenite::connect_async_tls_with_config(request, None, false, Some(connector))
+        .await
+        .with_context(|| format!("Failed to relay to {}", peer))?;
+    info!("Relaying data connection to cluster peer {}", peer);
+
+    let (mut browser_tx, mut browser_rx) = browser.split();
+    let (mut peer_tx, mut peer_rx) = upstream.split();
+    let to_peer = async {
+        while let Some(message) = browser_rx.next().await {
+            peer_tx.send(message?).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    let to_browser = async {
+        while let Some(message) = peer_rx.next().await {
+            browser_tx.send(message?).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::select! {
+        res = to_peer => res,
+        res = to_browser => res,
+    }
+}
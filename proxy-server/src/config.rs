@@ -0,0 +1,1400 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+use crate::filter::SourceValidation;
+use crate::limits::{SessionLimits, ViewingHours};
+use crate::onvif::OnvifKind;
+use crate::quirks::{KeepAlive, Vendor};
+
+/// Proxy configuration, loaded from a TOML file passed with `--config`.
+///
+/// Every section is optional; an empty file gives the built-in defaults.
+/// The file is re-read on SIGHUP; sessions already running keep the settings
+/// they started with.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Log filter in `RUST_LOG` syntax, e.g. "info" or "rtsp2browser=debug"
+    pub log_level: Option<String>,
+    /// Per-stream re-encoding rules (requires the `transcode` feature)
+    pub transcode: Vec<TranscodeRule>,
+    /// Rewriting applied to RTP before it is forwarded to the browser
+    pub rtp: RtpConfig,
+    /// Independent applications sharing this proxy. When any are configured,
+    /// every client must belong to one.
+    pub tenant: Vec<Tenant>,
+    /// On-disk session journal; disabled when unset
+    pub journal: Option<JournalConfig>,
+    /// On-disk sample of dropped media packets; disabled when unset
+    pub drop_log: Option<DropLogConfig>,
+    /// Memory budget for session buffers; unlimited when unset
+    pub memory: Option<MemoryConfig>,
+    /// Egress and CPU budgets that lower-priority sessions give way under;
+    /// off when unset
+    pub qos: Option<QosConfig>,
+    /// Debug transcripts of each session's RTSP exchange with the camera
+    pub transcript: Option<TranscriptConfig>,
+    /// The main tokio runtime that runs the listeners and control loops
+    pub runtime: RuntimeConfig,
+    /// Dedicated threads for UDP media forwarders; they share the main
+    /// runtime when unset
+    pub forwarders: Option<ForwarderConfig>,
+    /// External service asked to allow each session before it starts
+    pub authorizer: Option<AuthorizerConfig>,
+    /// Time limits for every session; tenants may override them
+    pub session: SessionConfig,
+    /// Audio level and voice activity events for audio tracks; off when unset
+    pub audio_levels: Option<AudioLevelConfig>,
+    /// Motion hint events for video tracks; off when unset
+    pub motion: Option<MotionConfig>,
+    /// A health score per session, and what a low one triggers; off when unset
+    pub health: Option<HealthConfig>,
+    /// Handling of ONVIF and KLV metadata tracks
+    pub metadata: MetadataConfig,
+    /// Buffer of recent media so the browser can pause live and rewind; off when unset
+    pub timeshift: Option<TimeShiftConfig>,
+    /// Skipping ahead for viewers that fall behind on reliable media paths
+    pub flow_control: FlowControlConfig,
+    /// H.264 SPS and PPS sent ahead of IDRs that arrive without them
+    pub parameter_sets: ParameterSetsConfig,
+    /// How SETUP responses tell the browser a track's channel IDs
+    pub channel_ids: ChannelIdConfig,
+    /// Token for the admin endpoints: `/export`, `/metrics`, `/sessions`,
+    /// `/reload`, `/chaos`, changes through `/drain` and `/probe` without
+    /// tenants; they are off when unset
+    pub admin: Option<AdminConfig>,
+    /// `/drain` for rolling deployments; off when unset
+    pub drain: Option<DrainConfig>,
+    /// Peer instances that share WebSocket session pairing; off when unset
+    pub cluster: Option<ClusterConfig>,
+    /// QUIC tuning for WebTransport connections
+    pub quic: QuicConfig,
+    /// TLS session resumption and 0-RTT for WebTransport connections
+    pub resumption: ResumptionConfig,
+    /// Client certificates for WebTransport connections; off when unset
+    pub mtls: Option<MtlsConfig>,
+    /// Rotation of the certificate made when none is installed
+    pub self_signed: SelfSignedConfig,
+    /// Certificates served by SNI hostname on WebTransport and `[wss]`;
+    /// other names get the default certificate
+    pub certificate: Vec<CertificateConfig>,
+    /// Plain TCP listener for native clients; off when unset
+    pub tcp: Option<TcpConfig>,
+    /// WebSocket over TLS listener, for pages served over https://; off when unset
+    pub wss: Option<WssConfig>,
+    /// RTSP listener re-exporting cameras to RTSP clients; off when unset
+    pub rtsp_server: Option<RtspServerConfig>,
+    /// One fixed UDP port pair for all camera media; two ephemeral ports
+    /// per track when unset
+    pub udp_mux: Option<UdpMuxConfig>,
+    /// Edge instances may register at `/relay` as upstreams; off when unset
+    pub relay: Option<RelayConfig>,
+    /// Register with a public instance as an edge and reach cameras for it;
+    /// off when unset
+    pub edge: Option<EdgeConfig>,
+    /// Built-in RTSP middleware applied to every session
+    pub middleware: MiddlewareConfig,
+    /// Checks on the browser's RTSP before it is forwarded
+    pub validation: ValidationConfig,
+    /// Camera quirk settings; cameras without a rule are auto-detected
+    pub quirks: Vec<QuirkRule>,
+    /// Cameras whose ONVIF events are forwarded to the browser
+    pub onvif: Vec<OnvifRule>,
+    /// Longest keyframe interval viewers of a camera should wait to join
+    pub keyframes: Vec<KeyframeRule>,
+    /// Cameras kept connected with DESCRIBE done, so viewers start faster
+    pub warm: Vec<WarmCamera>,
+    /// Cameras browsers may open by name (`stream`) instead of by URL
+    pub stream: Vec<NamedStream>,
+    /// Refuse camera URLs from browsers, so every session names a `[[stream]]`
+    pub streams_only: bool,
+    /// How the proxy reaches cameras
+    pub upstream: UpstreamConfig,
+    /// Web origins whose pages may open sessions, e.g. "https://app.example.com".
+    /// Empty allows any origin, with a warning at startup; "*" does the same
+    /// explicitly.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Replace camera-advertised addresses in DESCRIBE responses with the
+    /// host the proxy connected to (for cameras behind NAT)
+    pub rewrite_advertised_addresses: bool,
+    /// Send a few empty packets to the camera's RTP/RTCP ports after SETUP,
+    /// so replies get through NAT or a stateful firewall in front of the proxy
+    pub hole_punch: bool,
+    /// Which senders RTP/RTCP is accepted from: "off", "strict" or "learn"
+    pub validate_source: SourceValidation,
+    /// Proxy for the RTSP connection to every camera, e.g.
+    /// "socks5://user:pass@gw:1080" or "http://gw:3128"
+    pub proxy: Option<String>,
+    /// Per-camera proxy overrides; the first matching prefix wins
+    pub route: Vec<UpstreamRoute>,
+    /// Cameras reached with RTSP tunnelled over HTTP, like `rtsp+http://` URLs
+    pub tunnel: Vec<TunnelRule>,
+    /// Offer RTSP/2.0 to each camera first, falling back to 1.0
+    pub rtsp2: bool,
+    /// URL prefixes of cameras whose requests are relayed byte-for-byte,
+    /// except for the Transport header
+    pub passthrough: Vec<String>,
+    /// Cameras reached through a registered edge instance
+    pub relay: Vec<RelayRule>,
+    /// Sessions the proxy opens to any one camera (host and port) at a
+    /// time; unlimited when unset
+    pub max_sessions_per_camera: Option<usize>,
+    /// How long a viewer over a camera's limit waits for a slot before it
+    /// is refused; 0 refuses at once
+    pub camera_queue_s: u64,
+    /// Per-camera overrides of `max_sessions_per_camera`; the first
+    /// matching prefix wins
+    pub camera_limit: Vec<CameraLimit>,
+}
+
+/// A camera kept on warm standby
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmCamera {
+    /// RTSP URL exactly as viewers ask for it
+    pub url: String,
+    /// How often the description is fetched again, which also keeps the
+    /// connection alive
+    #[serde(default = "default_warm_refresh_s")]
+    pub refresh_s: u64,
+}
+
+fn default_warm_refresh_s() -> u64 {
+    30
+}
+
+/// A camera browsers open by name, keeping its address and credentials on
+/// the server. `${VAR}` in any field is replaced from the environment when
+/// the config is loaded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamedStream {
+    /// What browsers pass as `stream`, e.g. "front-door"
+    pub name: String,
+    pub url: String,
+    /// Lower-bitrate profile of the same camera, used as `sub`
+    pub sub: Option<String>,
+    /// Camera credentials the proxy adds to upstream requests
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl NamedStream {
+    /// Prefix of the URLs a browser uses for the stream in its RTSP requests
+    pub fn request_url(&self) -> String {
+        format!("stream://{}", self.name)
+    }
+
+    /// The stream's credentials, for its URL or sub-stream
+    pub fn credentials_for(&self, rtsp_url: &str) -> Option<Credentials> {
+        let ours = rtsp_url == self.url || self.sub.as_deref() == Some(rtsp_url);
+        let username = self.username.clone().filter(|_| ours)?;
+        Some(Credentials { username, password: self.password.clone().unwrap_or_default() })
+    }
+
+    fn expand(&mut self) -> Result<()> {
+        self.url = expand_env(&self.url)?;
+        for field in [&mut self.sub, &mut self.username, &mut self.password].into_iter().flatten() {
+            *field = expand_env(field)?;
+        }
+        Ok(())
+    }
+}
+
+/// `value` with each `${VAR}` replaced by the environment variable
+fn expand_env(value: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').ok_or_else(|| anyhow!("Unclosed ${{ in '{}'", value))? + start;
+        let name = &rest[start + 2..end];
+        let var = std::env::var(name).with_context(|| format!("Environment variable {} is not set", name))?;
+        out.push_str(&rest[..start]);
+        out.push_str(&var);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Session limit for cameras matching a URL prefix
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraLimit {
+    /// RTSP URL prefix this limit applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    pub max_sessions: usize,
+}
+
+/// Proxy override for cameras matching a URL prefix
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamRoute {
+    /// RTSP URL prefix this route applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    /// Proxy URL, or "direct" to bypass the global proxy
+    pub proxy: String,
+}
+
+/// Cameras matching a URL prefix that only accept RTSP over HTTP
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    /// The camera's HTTP port
+    #[serde(default = "default_tunnel_port")]
+    pub port: u16,
+}
+
+/// Cameras matching a URL prefix that sit behind an edge instance
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    /// Name the edge registered with
+    pub edge: String,
+}
+
+fn default_tunnel_port() -> u16 {
+    80
+}
+
+impl UpstreamConfig {
+    /// Proxy to dial the camera at `rtsp_url` through, None for a direct connection
+    pub fn proxy_for(&self, rtsp_url: &str) -> Option<&str> {
+        match self.route.iter().find(|route| rtsp_url.starts_with(&route.url_prefix)) {
+            Some(route) => (route.proxy != "direct").then_some(route.proxy.as_str()),
+            None => self.proxy.as_deref(),
+        }
+    }
+
+    /// Whether requests for `rtsp_url` are relayed without being rebuilt
+    pub fn passthrough_for(&self, rtsp_url: &str) -> bool {
+        self.passthrough.iter().any(|prefix| rtsp_url.starts_with(prefix))
+    }
+
+    /// Sessions the camera at `rtsp_url` may have at a time, None for no limit
+    pub fn camera_limit_for(&self, rtsp_url: &str) -> Option<usize> {
+        self.camera_limit
+            .iter()
+            .find(|rule| rtsp_url.starts_with(&rule.url_prefix))
+            .map(|rule| rule.max_sessions)
+            .or(self.max_sessions_per_camera)
+    }
+
+    /// Edge instance to reach the camera at `rtsp_url` through, None to dial it
+    pub fn relay_for(&self, rtsp_url: &str) -> Option<&str> {
+        self.relay.iter().find(|rule| rtsp_url.starts_with(&rule.url_prefix)).map(|rule| rule.edge.as_str())
+    }
+
+    /// HTTP port to tunnel RTSP through for `url`, None for plain RTSP
+    pub fn tunnel_port(&self, url: &url::Url) -> Option<u16> {
+        if url.scheme() == "rtsp+http" {
+            return Some(url.port().unwrap_or(80));
+        }
+        self.tunnel.iter().find(|rule| url.as_str().starts_with(&rule.url_prefix)).map(|rule| rule.port)
+    }
+}
+
+/// ONVIF event subscription for cameras matching a URL prefix
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnvifRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    /// Device service URL; `http://<camera host>/onvif/device_service` when unset
+    pub device_url: Option<String>,
+    /// Event kinds forwarded to the browser
+    #[serde(default = "default_onvif_kinds")]
+    pub kinds: Vec<OnvifKind>,
+}
+
+fn default_onvif_kinds() -> Vec<OnvifKind> {
+    vec![OnvifKind::Motion, OnvifKind::Tamper, OnvifKind::Io]
+}
+
+/// Keyframe cadence for cameras matching a URL prefix, so a viewer joining
+/// mid-GOP waits at most about `interval_ms` for the first picture
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyframeRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    /// Longest gap between keyframes, in milliseconds
+    pub interval_ms: u64,
+    /// How the camera is made to keep it
+    #[serde(default)]
+    pub method: KeyframeMethod,
+}
+
+impl KeyframeRule {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyframeMethod {
+    /// Send the camera an RTCP FIR whenever a keyframe is overdue
+    #[default]
+    Fir,
+    /// Shorten the camera's GOP over ONVIF when a session starts
+    Onvif,
+    /// Leave the camera alone; only a `[[transcode]]` rule's encoder keeps it
+    Transcode,
+}
+
+impl KeyframeMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KeyframeMethod::Fir => "fir",
+            KeyframeMethod::Onvif => "onvif",
+            KeyframeMethod::Transcode => "transcode",
+        }
+    }
+}
+
+/// Quirks for cameras matching a URL prefix. Unset fields come from the
+/// vendor profile, configured or detected from the camera's `Server` header.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuirkRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    pub vendor: Option<Vendor>,
+    pub keepalive: Option<KeepAlive>,
+    pub normalize_transport: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Reject requests for other URLs, other methods or with ambiguous
+    /// headers instead of forwarding them
+    pub enabled: bool,
+    /// Methods the browser may send
+    pub allow_methods: Vec<String>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        let methods = ["OPTIONS", "DESCRIBE", "SETUP", "PLAY", "PAUSE", "TEARDOWN", "GET_PARAMETER", "SET_PARAMETER"];
+        Self { enabled: true, allow_methods: methods.iter().map(|m| m.to_string()).collect() }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiddlewareConfig {
+    /// Log every request and response passing between browser and camera
+    pub log: bool,
+    /// Methods the browser may not send, answered with 405 by the proxy
+    pub deny_methods: Vec<String>,
+    /// Header rewrites, applied in order
+    pub header: Vec<HeaderRule>,
+}
+
+/// Headers to set or remove on messages going one way through the proxy
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    #[serde(default)]
+    pub direction: Direction,
+    /// Only rewrite requests with this method (responses are always rewritten)
+    pub method: Option<String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Browser → camera
+    #[default]
+    Request,
+    /// Camera → browser
+    Response,
+    Both,
+}
+
+impl Direction {
+    pub fn requests(&self) -> bool {
+        matches!(self, Direction::Request | Direction::Both)
+    }
+
+    pub fn responses(&self) -> bool {
+        matches!(self, Direction::Response | Direction::Both)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    /// Size at which the journal is rotated to `<path>.1`
+    #[serde(default = "default_journal_max_bytes")]
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DropLogConfig {
+    pub path: PathBuf,
+    /// Size at which the log is rotated to `<path>.1`
+    #[serde(default = "default_drop_log_max_bytes")]
+    pub max_bytes: u64,
+    /// Log one drop in this many of each reason
+    #[serde(default = "default_drop_log_sample_every")]
+    pub sample_every: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Total across all sessions; the largest session is closed while over it
+    pub budget_mb: u64,
+    /// Limit for any one session, which is closed when it goes over
+    pub session_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QosConfig {
+    /// Media sent to all clients together, in Mbit/s
+    pub bandwidth_mbps: Option<u64>,
+    /// Process CPU time as a percentage of one core, e.g. 300 for three
+    /// cores; Linux only
+    pub cpu_percent: Option<u64>,
+}
+
+/// How a tenant's sessions rank when the proxy is congested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Degraded and then closed first, e.g. background recorders
+    Low,
+    #[default]
+    Normal,
+    /// Degraded last and never closed for congestion, e.g. an operator wall
+    High,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranscriptConfig {
+    /// Directory for the transcript files, one per session
+    pub dir: PathBuf,
+    /// RTSP URL prefixes to record; every session when empty
+    #[serde(rename = "match", default)]
+    pub url_prefixes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthorizerConfig {
+    /// Plain `http://` endpoint the session details are POSTed to
+    pub url: String,
+    #[serde(default = "default_authorizer_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Allow sessions when the authorizer cannot be reached or answers badly
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_authorizer_timeout_ms() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Sessions are closed after this long; unlimited when unset
+    pub max_duration_s: Option<u64>,
+    /// Daily windows in which sessions may run, e.g. "08:00-18:00"; any time when empty
+    pub viewing_hours: Vec<ViewingHours>,
+    /// Offset of the viewing hours' time zone from UTC, e.g. 60 for UTC+1
+    pub utc_offset_minutes: i32,
+    /// RTSP requests per second a browser may send; more are answered with
+    /// 503 by the proxy. 0 for no limit.
+    pub max_requests_per_s: u32,
+    /// Largest RTSP request a browser may send, in KiB; larger ones are
+    /// answered with 413. 0 for no limit.
+    pub max_request_kb: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            max_duration_s: None,
+            viewing_hours: Vec::new(),
+            utc_offset_minutes: 0,
+            max_requests_per_s: 20,
+            max_request_kb: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioLevelConfig {
+    /// Time between level events for each track
+    #[serde(default = "default_audio_interval_ms")]
+    pub interval_ms: u64,
+    /// RMS level, in dBFS, at or above which a track counts as having voice
+    #[serde(default = "default_vad_threshold_dbfs")]
+    pub vad_threshold_dbfs: f32,
+}
+
+fn default_audio_interval_ms() -> u64 {
+    500
+}
+
+fn default_vad_threshold_dbfs() -> f32 {
+    -45.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotionConfig {
+    /// Inter-frame bitrate, as a multiple of its running average, that counts as motion
+    #[serde(default = "default_motion_bitrate_ratio")]
+    pub bitrate_ratio: f64,
+    /// Motion ends this long after the last cue
+    #[serde(default = "default_motion_hold_s")]
+    pub hold_s: u64,
+}
+
+fn default_motion_bitrate_ratio() -> f64 {
+    2.0
+}
+
+fn default_motion_hold_s() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Scores from 0 to 100 under which each action is taken; an action
+    /// without one is never taken. Only simple-mode sessions, which set the
+    /// camera up by themselves, transcode or reconnect; others only alert.
+    pub alert_below: Option<u8>,
+    pub transcode_below: Option<u8>,
+    pub reconnect_below: Option<u8>,
+    /// How long the score must stay under a threshold before its action
+    #[serde(default = "default_health_hold_ms")]
+    pub hold_ms: u64,
+    /// Plain `http://` endpoint alerts are POSTed to; alerts are only logged when unset
+    pub webhook: Option<String>,
+    #[serde(default = "default_authorizer_timeout_ms")]
+    pub webhook_timeout_ms: u64,
+    /// Times a simple-mode session reconnects or falls back to transcoding
+    /// by itself before it closes instead
+    #[serde(default = "default_health_max_restarts")]
+    pub max_restarts: u32,
+    /// How long later sessions for a camera that fell back are transcoded too
+    #[serde(default = "default_health_fallback_s")]
+    pub fallback_s: u64,
+}
+
+impl HealthConfig {
+    pub fn hold(&self) -> Duration {
+        Duration::from_millis(self.hold_ms)
+    }
+
+    pub fn fallback(&self) -> Duration {
+        Duration::from_secs(self.fallback_s)
+    }
+}
+
+fn default_health_hold_ms() -> u64 {
+    10_000
+}
+
+fn default_health_max_restarts() -> u32 {
+    3
+}
+
+fn default_health_fallback_s() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    /// Parse ONVIF analytics into object events with bounding boxes, for overlays
+    pub objects: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeShiftConfig {
+    /// How far back the browser can rewind
+    #[serde(default = "default_timeshift_seconds")]
+    pub seconds: u64,
+    /// Cap on each session's buffer; the oldest media goes first
+    #[serde(default = "default_timeshift_max_mb")]
+    pub max_mb: u64,
+    /// Rate, relative to real time, at which buffered media is replayed until
+    /// it catches up with live
+    #[serde(default = "default_timeshift_catchup_speed")]
+    pub catchup_speed: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowControlConfig {
+    /// How far behind live a track on a reliable path (WebSocket, TCP or a
+    /// WebTransport stream) may fall before it skips ahead to its next
+    /// keyframe. 0 lets it fall behind without limit.
+    pub max_lag_ms: u64,
+}
+
+impl Default for FlowControlConfig {
+    fn default() -> Self {
+        Self { max_lag_ms: 3000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParameterSetsConfig {
+    /// Send the latest SPS and PPS (from the SDP, or the camera's last in-band
+    /// ones) ahead of IDR pictures the camera sends without them
+    pub inject: bool,
+    /// After the first IDR, send them again only once this long has passed
+    /// since the browser last got them; 0 sends them ahead of the first IDR only
+    pub repeat_ms: u64,
+}
+
+impl Default for ParameterSetsConfig {
+    fn default() -> Self {
+        Self { inject: true, repeat_ms: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelIdConfig {
+    /// Transport parameter carrying them, with the "range" format
+    pub parameter: String,
+    pub format: ChannelIdFormat,
+}
+
+impl Default for ChannelIdConfig {
+    fn default() -> Self {
+        Self { parameter: "x-wt-channel-id".to_string(), format: ChannelIdFormat::Range }
+    }
+}
+
+impl ChannelIdConfig {
+    /// Transport header parameter for a track's channels; None with the
+    /// "event" format
+    pub fn transport_param(&self, rtp_channel_id: u8, rtcp_channel_id: u8) -> Option<String> {
+        match self.format {
+            ChannelIdFormat::Range => Some(format!("{}={}-{}", self.parameter, rtp_channel_id, rtcp_channel_id)),
+            ChannelIdFormat::Interleaved => Some(format!("interleaved={}-{}", rtp_channel_id, rtcp_channel_id)),
+            ChannelIdFormat::Event => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelIdFormat {
+    /// `<parameter>=<rtp>-<rtcp>`
+    #[default]
+    Range,
+    /// RFC 2326's own `interleaved=<rtp>-<rtcp>`, which RTSP clients already parse
+    Interleaved,
+    /// No Transport parameter; a `channels` event ahead of the SETUP response
+    Event,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+pub struct ClusterConfig {
+    /// Other instances' `[wss]` ports, e.g. "https://10.0.0.2:8443"; only
+    /// https:// is accepted, so the secret never crosses the network in clear
+    pub peers: Vec<String>,
+    /// Shared secret peers authenticate each other with
+    pub secret: String,
+    /// PEM file of the CA(s) peers' certificates are checked against; the
+    /// system's roots when unset
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuicConfig {
+    /// Time between keep-alive packets; 0 disables them
+    pub keep_alive_ms: u64,
+    /// Connections silent this long are closed; 30 s when unset, 0 never
+    pub max_idle_timeout_ms: Option<u64>,
+    /// Datagrams received from the browser and not yet read, in KiB, before
+    /// new ones are dropped
+    pub datagram_receive_buffer_kb: Option<usize>,
+    /// Datagrams queued for the browser, in KiB, before the oldest are dropped
+    pub datagram_send_buffer_kb: Option<usize>,
+    /// Congestion controller: "cubic" (default), "new_reno" or "bbr"
+    pub congestion: CongestionController,
+    /// Data the browser may send on one stream before it is read, in KiB
+    pub stream_receive_window_kb: Option<u64>,
+    /// Data the browser may send on all streams before it is read, in KiB
+    pub receive_window_kb: Option<u64>,
+    /// Data sent to the browser and not yet acknowledged, in KiB
+    pub send_window_kb: Option<u64>,
+    /// Round-trip time assumed before the first measurement; raise it for
+    /// high-latency viewers
+    pub initial_rtt_ms: Option<u64>,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive_ms: 3000,
+            max_idle_timeout_ms: None,
+            datagram_receive_buffer_kb: None,
+            datagram_send_buffer_kb: None,
+            congestion: CongestionController::default(),
+            stream_receive_window_kb: None,
+            receive_window_kb: None,
+            send_window_kb: None,
+            initial_rtt_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResumptionConfig {
+    /// Let returning browsers send their session request in 0-RTT
+    pub early_data: bool,
+    /// How long a session ticket can be used to resume, at most 7 days; 0
+    /// disables resumption
+    pub ticket_lifetime_s: u32,
+    /// File holding the key tickets are encrypted with, created if missing,
+    /// so tickets survive restarts; a new key each start when unset
+    pub ticket_key_file: Option<PathBuf>,
+}
+
+impl Default for ResumptionConfig {
+    fn default() -> Self {
+        Self { early_data: true, ticket_lifetime_s: 24 * 3600, ticket_key_file: None }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfSignedConfig {
+    /// Days each certificate answers handshakes for
+    pub rotate_after_days: u64,
+    /// Hours before a rotation the next certificate's hash is published
+    /// alongside the current one
+    pub overlap_h: u64,
+    /// File the published hashes are written to, as served at `/cert-hash`
+    pub hash_file: Option<PathBuf>,
+}
+
+impl Default for SelfSignedConfig {
+    fn default() -> Self {
+        Self { rotate_after_days: 10, overlap_h: 24, hash_file: None }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "webtransport"), allow(dead_code))]
+pub struct MtlsConfig {
+    /// PEM bundle of the CAs client certificates must chain to
+    pub ca_file: PathBuf,
+    /// Refuse the handshake without a client certificate; otherwise only
+    /// tenants with `client_cert` need one
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// A certificate served to clients that ask for `hostname`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// Name clients send as SNI, e.g. "cams.customer-a.com", or
+    /// "*.customer-a.com" for any one label under it
+    pub hostname: String,
+    /// PEM certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionController {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    pub port: u16,
+    /// Address the listener is bound on
+    #[serde(default = "default_bind_address")]
+    pub address: IpAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WssConfig {
+    pub port: u16,
+    /// Address the listener is bound on
+    #[serde(default = "default_bind_address")]
+    pub address: IpAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RtspServerConfig {
+    pub port: u16,
+    /// Address the listener is bound on
+    #[serde(default = "default_bind_address")]
+    pub address: IpAddr,
+    /// Cameras served, each at `rtsp://<proxy>:<port>/<name>`
+    pub stream: Vec<RtspStream>,
+}
+
+/// A camera re-exported by the RTSP listener
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RtspStream {
+    /// First path segment of the proxy URL
+    pub name: String,
+    /// The camera's RTSP URL
+    pub url: String,
+    /// Tenant token the sessions use, when tenants are configured
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpMuxConfig {
+    /// Port cameras send RTP to; RTCP goes to the next one
+    pub port: u16,
+    /// Address the ports are bound on
+    #[serde(default = "default_bind_address")]
+    pub address: IpAddr,
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "webtransport"), allow(dead_code))]
+pub struct RelayConfig {
+    /// Shared secret edge instances register with
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "webtransport"), allow(dead_code))]
+pub struct EdgeConfig {
+    /// The public instance's relay endpoint, e.g. "https://proxy.example.com:4433/relay"
+    pub url: String,
+    /// Name `[[upstream.relay]]` rules on the public instance refer to
+    pub name: String,
+    /// The public instance's `[relay]` secret
+    pub secret: String,
+    /// SHA-256 of the public instance's certificate, hex, for self-signed
+    /// certificates; the system's roots are used when unset
+    pub cert_hash: Option<String>,
+    /// Camera hosts the public instance may reach; empty allows any
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Token admin requests must carry, as `token` in the query or in an
+    /// `Authorization: Bearer` header
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DrainConfig {
+    /// Endpoint new sessions are pointed to while draining, e.g.
+    /// "wss://proxy-b.example.com:8080"; a `POST /drain` can name another
+    pub alternate: Option<String>,
+}
+
+fn default_timeshift_seconds() -> u64 {
+    30
+}
+
+fn default_timeshift_max_mb() -> u64 {
+    64
+}
+
+fn default_timeshift_catchup_speed() -> f64 {
+    1.5
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// "multi_thread" (default) or "current_thread"
+    pub flavor: RuntimeFlavor,
+    /// Worker threads of a multi-threaded runtime; one per CPU core when unset
+    pub worker_threads: Option<usize>,
+    /// Name of the runtime's threads, as shown by `top -H` and debuggers
+    pub thread_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    /// Everything on the main thread, for small embedded devices
+    CurrentThread,
+    /// A work-stealing pool of worker threads
+    #[default]
+    MultiThread,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwarderConfig {
+    pub threads: usize,
+    #[serde(default)]
+    pub mode: ForwarderMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwarderMode {
+    /// A multi-threaded runtime shared by all forwarders
+    #[default]
+    Pool,
+    /// A single-threaded runtime per thread, with tracks assigned round-robin
+    Sharded,
+}
+
+/// Namespace for one application, selected by the client's `token` query
+/// parameter or by the URL path it connects on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    /// Used in logs and as the `tenant` metrics label
+    pub name: String,
+    pub token: Option<String>,
+    pub path_prefix: Option<String>,
+    /// Client certificate identity selecting the tenant: its subject CN or a
+    /// DNS, email or URI name. Needs `[mtls]`.
+    pub client_cert: Option<String>,
+    /// RTSP URLs the tenant may open, matching scheme, host and port exactly
+    /// and the path as a prefix; any URL when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Concurrent sessions; unlimited when unset
+    pub max_sessions: Option<u32>,
+    /// Camera credentials the proxy adds to upstream requests
+    #[serde(default)]
+    pub credentials: Vec<CredentialRule>,
+    /// Overrides `[session] max_duration_s` for this tenant
+    pub max_duration_s: Option<u64>,
+    /// Overrides `[session] viewing_hours` for this tenant when not empty
+    #[serde(default)]
+    pub viewing_hours: Vec<ViewingHours>,
+    /// Priority class of the tenant's sessions under `[qos]` and `[memory]`
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialRule {
+    /// RTSP URL these credentials apply to, matched like `Tenant::allow`
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).finish_non_exhaustive()
+    }
+}
+
+impl Tenant {
+    pub fn allows(&self, rtsp_url: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|prefix| url_under(rtsp_url, prefix))
+    }
+
+    /// Credentials of the first rule whose prefix matches the RTSP URL
+    pub fn credentials_for(&self, rtsp_url: &str) -> Option<Credentials> {
+        self.credentials
+            .iter()
+            .find(|rule| url_under(rtsp_url, &rule.url_prefix))
+            .map(|rule| Credentials { username: rule.username.clone(), password: rule.password.clone() })
+    }
+
+    /// `allow` and `credentials` entries that are not URLs with a whole host,
+    /// such as the old text prefix `rtsp://10.0.1.`
+    fn invalid_prefixes(&self) -> impl Iterator<Item = &str> {
+        let credentials = self.credentials.iter().map(|rule| rule.url_prefix.as_str());
+        let whole_host = |url: url::Url| url.host_str().is_some_and(|host| !host.is_empty() && !host.ends_with('.'));
+        self.allow
+            .iter()
+            .map(String::as_str)
+            .chain(credentials)
+            .filter(move |prefix| !url::Url::parse(prefix).is_ok_and(whole_host))
+    }
+}
+
+/// Whether `rtsp_url` is on the same scheme, host and port as `prefix`,
+/// with a path under the prefix's. Matching the text instead would let
+/// `rtsp://10.0.1.5@attacker.example/` through for `rtsp://10.0.1.`.
+fn url_under(rtsp_url: &str, prefix: &str) -> bool {
+    let (Ok(url), Ok(prefix)) = (url::Url::parse(rtsp_url), url::Url::parse(prefix)) else {
+        return false;
+    };
+    let path = |url: &url::Url| if url.path().is_empty() { "/".to_string() } else { url.path().to_string() };
+    url.scheme() == prefix.scheme()
+        && url.host_str().zip(prefix.host_str()).is_some_and(|(host, allowed)| host.eq_ignore_ascii_case(allowed))
+        && url.port_or_known_default() == prefix.port_or_known_default()
+        && path(&url).starts_with(&path(&prefix))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtpConfig {
+    /// Remove RTP header extensions (the browser never negotiates any)
+    pub strip_header_extensions: bool,
+    /// Replace camera SSRCs with proxy-chosen values, one per track
+    pub rewrite_ssrc: bool,
+    /// Hold timestamps that jump backwards at the last good value
+    pub fix_timestamps: bool,
+    /// Remove RTP padding (and the P bit) before forwarding
+    pub strip_padding: bool,
+    /// Drop packets that are not valid RTP/RTCP version 2
+    pub drop_malformed: bool,
+    /// Drop RTP whose payload type is not listed for the track in the SDP
+    pub filter_payload_types: bool,
+}
+
+/// Re-encode the video track of matching streams to H.264 constrained baseline
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct TranscodeRule {
+    /// RTSP URL prefix this rule applies to
+    #[serde(rename = "match")]
+    pub url_prefix: String,
+    #[serde(default = "default_bitrate_kbps")]
+    pub bitrate_kbps: u32,
+    /// Output size; the source size is kept when unset
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// "auto" prefers hardware encoders (NVENC, VA-API, V4L2) and falls back to
+    /// x264; "software" forces x264; any other value names a GStreamer element,
+    /// optionally followed by its properties
+    #[serde(default = "default_encoder")]
+    pub encoder: String,
+    /// Outputs encoded side by side, largest first; each session watches one
+    /// and switches between them. Replaces `width`, `height` and `bitrate_kbps`.
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+    /// Only used once `[health]` falls back to transcoding for the camera
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+/// One output size of a multi-rendition transcode
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct Rendition {
+    /// Name clients pick it by (`rendition=`), e.g. "480p"
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_bitrate_kbps")]
+    pub bitrate_kbps: u32,
+}
+
+fn default_bitrate_kbps() -> u32 {
+    1500
+}
+
+fn default_journal_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_drop_log_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_drop_log_sample_every() -> u64 {
+    100
+}
+
+fn default_encoder() -> String {
+    "auto".to_string()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: Self = toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+        for stream in &mut config.stream {
+            stream.expand().with_context(|| format!("Invalid stream '{}'", stream.name))?;
+        }
+        Ok(config)
+    }
+
+    /// The `[[stream]]` called `name`
+    pub fn stream_named(&self, name: &str) -> Option<&NamedStream> {
+        self.stream.iter().find(|stream| stream.name == name)
+    }
+
+    /// The `[[stream]]` whose camera or sub-stream is at `rtsp_url`
+    pub fn stream_for(&self, rtsp_url: &str) -> Option<&NamedStream> {
+        self.stream.iter().find(|stream| stream.url == rtsp_url || stream.sub.as_deref() == Some(rtsp_url))
+    }
+
+    /// Log problems with settings this build cannot honour
+    pub fn warn_unsupported(&self) {
+        for problem in self.unsupported() {
+            warn!("{}", problem);
+        }
+    }
+
+    /// Settings this build or platform ignores
+    pub fn unsupported(&self) -> Vec<&'static str> {
+        let mut problems = Vec::new();
+        if !self.transcode.is_empty() && !cfg!(feature = "transcode") {
+            problems.push("Config has transcode rules but the proxy was built without the `transcode` feature; ignoring them");
+        }
+        if !cfg!(feature = "webtransport") {
+            if self.mtls.is_some() {
+                problems.push("Config has [mtls] but the proxy was built without the `webtransport` feature, the only transport it applies to; ignoring it");
+            }
+            if self.relay.is_some() || self.edge.is_some() || !self.upstream.relay.is_empty() {
+                problems.push("Edges register over WebTransport, but the proxy was built without the `webtransport` feature; ignoring [relay], [edge] and [[upstream.relay]]");
+            }
+        }
+        if !cfg!(feature = "websocket") {
+            if self.wss.is_some() {
+                problems.push("Config has [wss] but the proxy was built without the `websocket` feature; ignoring it");
+            }
+            if self.cluster.is_some() {
+                problems.push("[cluster] pairs WebSocket connections, but the proxy was built without the `websocket` feature; ignoring it");
+            }
+        }
+        if self.tenant.iter().any(|t| t.invalid_prefixes().next().is_some()) {
+            problems.push("Tenant allow and credentials entries must be URLs with a whole host, such as rtsp://10.0.1.20/; others match nothing");
+        }
+        if self.mtls.is_none() && self.tenant.iter().any(|t| t.client_cert.is_some()) {
+            problems.push("Tenants with client_cert need [mtls]; no client can select them");
+        }
+        if self.qos.as_ref().is_some_and(|qos| qos.cpu_percent.is_some()) && !cfg!(target_os = "linux") {
+            problems.push("qos.cpu_percent is only measured on Linux; ignoring it");
+        }
+        if self.runtime.flavor == RuntimeFlavor::CurrentThread && self.runtime.worker_threads.is_some() {
+            problems.push("runtime.worker_threads only applies to the multi_thread flavor; ignoring it");
+        }
+        problems
+    }
+
+    /// Log that any web page may open sessions, unless `allowed_origins` says
+    /// which may
+    pub fn warn_any_origin(&self) {
+        if self.allowed_origins.is_empty() {
+            warn!("allowed_origins is empty, so any web page can open sessions; list your pages' origins to refuse the rest");
+        }
+    }
+
+    /// Whether a browser `Origin` may use the HTTP endpoints. Requests without
+    /// one come from non-browser clients, which a web page cannot impersonate.
+    pub fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return true;
+        };
+        let origin = origin.trim().trim_end_matches('/');
+        self.allowed_origins.is_empty()
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+
+    /// Whether a WebSocket handshake or WebTransport session from `origin` may
+    /// open a session. Unlike plain HTTP, one without an `Origin` is refused
+    /// once `allowed_origins` lists origins, unless the list has "*".
+    pub fn session_origin_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(_) => self.origin_allowed(origin),
+            None => self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|allowed| allowed == "*"),
+        }
+    }
+
+    /// Tenant a client belongs to: by token first, then by client
+    /// certificate, then by path prefix. Ok(None) when no tenants are configured.
+    pub fn tenant_for(&self, path: &str, token: Option<&str>, client_identities: &[String]) -> Result<Option<&Tenant>> {
+        if self.tenant.is_empty() {
+            return Ok(None);
+        }
+        let has_cert = |t: &Tenant| t.client_cert.as_ref().is_none_or(|cert| client_identities.contains(cert));
+        let by_token = token.and_then(|_| self.tenant.iter().find(|t| t.token.as_deref().is_some_and(|expected| crate::http::token_matches(token, expected)) && has_cert(t)));
+        let by_cert = || self.tenant.iter().find(|t| t.token.is_none() && t.client_cert.is_some() && has_cert(t));
+        let by_path = || {
+            self.tenant.iter().find(|t| {
+                t.token.is_none() && t.client_cert.is_none() && t.path_prefix.as_deref().is_some_and(|prefix| path.starts_with(prefix))
+            })
+        };
+        by_token.or_else(by_cert).or_else(by_path).map(Some).ok_or_else(|| anyhow!("Client does not belong to any tenant"))
+    }
+
+    /// Time limits for a new session of `tenant`; Err outside its viewing hours
+    pub fn session_limits(&self, tenant: Option<&Tenant>) -> Result<SessionLimits> {
+        let max_duration_s = tenant.and_then(|t| t.max_duration_s).or(self.session.max_duration_s);
+        let viewing_hours = match tenant {
+            Some(tenant) if !tenant.viewing_hours.is_empty() => &tenant.viewing_hours,
+            _ => &self.session.viewing_hours,
+        };
+        let window_left = if viewing_hours.is_empty() {
+            None
+        } else {
+            Some(ViewingHours::left_now(viewing_hours, self.session.utc_offset_minutes)?)
+        };
+        Ok(SessionLimits { max_duration: max_duration_s.map(Duration::from_secs), window_left, ..Default::default() })
+    }
+
+    /// First quirk rule whose prefix matches the requested RTSP URL
+    pub fn quirks_for(&self, rtsp_url: &str) -> Option<&QuirkRule> {
+        self.quirks.iter().find(|rule| rtsp_url.starts_with(&rule.url_prefix))
+    }
+
+    /// First ONVIF rule whose prefix matches the requested RTSP URL
+    pub fn onvif_for(&self, rtsp_url: &str) -> Option<&OnvifRule> {
+        self.onvif.iter().find(|rule| rtsp_url.starts_with(&rule.url_prefix))
+    }
+
+    /// First keyframe rule whose prefix matches the requested RTSP URL
+    pub fn keyframes_for(&self, rtsp_url: &str) -> Option<&KeyframeRule> {
+        self.keyframes.iter().find(|rule| rtsp_url.starts_with(&rule.url_prefix))
+    }
+
+    /// First transcode rule whose prefix matches the requested RTSP URL
+    pub fn transcode_for(&self, rtsp_url: &str) -> Option<&TranscodeRule> {
+        self.transcode.iter().find(|rule| !rule.fallback && rtsp_url.starts_with(&rule.url_prefix))
+    }
+
+    /// First fallback transcode rule matching the URL, for `[health]`
+    pub fn transcode_fallback_for(&self, rtsp_url: &str) -> Option<&TranscodeRule> {
+        self.transcode.iter().find(|rule| rule.fallback && rtsp_url.starts_with(&rule.url_prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(toml: &str) -> Tenant {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn allow_matches_scheme_host_and_port_exactly() {
+        let tenant = tenant(r#"name = "a"
+allow = ["rtsp://10.0.1.5/", "rtsp://cams.example:8554/lobby"]"#);
+        assert!(tenant.allows("rtsp://10.0.1.5/stream1"));
+        assert!(tenant.allows("rtsp://10.0.1.5"));
+        assert!(tenant.allows("rtsp://user:pw@10.0.1.5/stream1"));
+        assert!(tenant.allows("rtsp://CAMS.example:8554/lobby/main"));
+        assert!(!tenant.allows("rtsp://10.0.1.5:554/stream1"));
+        assert!(!tenant.allows("rtsps://10.0.1.5/stream1"));
+        assert!(!tenant.allows("rtsp://cams.example/lobby"));
+        assert!(!tenant.allows("rtsp://cams.example:8554/garage"));
+        assert!(!tenant.allows("not a url"));
+    }
+
+    #[test]
+    fn allow_rejects_userinfo_and_hostname_suffix_tricks() {
+        let tenant = tenant(r#"name = "a"
+allow = ["rtsp://10.0.1.5/"]"#);
+        assert!(!tenant.allows("rtsp://10.0.1.5@attacker.example/"));
+        assert!(!tenant.allows("rtsp://10.0.1.5.attacker.example/"));
+        assert!(!tenant.allows("rtsp://10.0.1.50/"));
+        assert!(tenant.invalid_prefixes().next().is_none());
+        let partial = self::tenant(r#"name = "a"
+allow = ["rtsp://10.0.1."]"#);
+        assert!(!partial.allows("rtsp://10.0.1.evil.example/"));
+        assert!(!partial.allows("rtsp://10.0.1.5/"));
+    }
+
+    #[test]
+    fn credentials_stay_on_their_host() {
+        let tenant = tenant(r#"name = "a"
+[[credentials]]
+match = "rtsp://10.0.1.5/"
+username = "admin"
+password = "secret""#);
+        assert_eq!(tenant.credentials_for("rtsp://10.0.1.5/stream1").unwrap().username, "admin");
+        assert!(tenant.credentials_for("rtsp://10.0.1.5@attacker.example/").is_none());
+        assert!(tenant.credentials_for("rtsp://10.0.1.5.attacker.example/").is_none());
+    }
+
+    #[test]
+    fn non_url_prefixes_are_reported() {
+        let tenant = tenant(r#"name = "a"
+allow = ["rtsp://10.0.1.", "10.0.1.5", "rtsp://10.0.1.5/"]"#);
+        assert_eq!(tenant.invalid_prefixes().collect::<Vec<_>>(), ["rtsp://10.0.1.", "10.0.1.5"]);
+    }
+}
@@ -0,0 +1,549 @@
+//! Control stream protocol between proxy and browser: raw RTSP text, or RTSP
+//! and proxy messages wrapped in newline-delimited JSON envelopes
+//! (`protocol=json`)
+
+use bytes::{Buf, BytesMut};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tracing::warn;
+use crate::adaptive::Profile;
+use crate::capabilities::Capabilities;
+use crate::health::HealthAction;
+use crate::limits::Expiry;
+use crate::metadata::ObjectFrame;
+use crate::motion::MotionCue;
+use crate::onvif::OnvifEvent;
+use crate::config::Priority;
+use crate::protocol::{ClientMessage, EventMessage, ServerMessage};
+use crate::qos::{Congestion, QosAction};
+use crate::receive::ChannelReport;
+use crate::reconfig::ConfigChange;
+use crate::rtsp::RtspRequest;
+use crate::sdp::StreamInfo;
+use crate::startup::{Phase, StartupReport};
+use crate::sync::SyncPoint;
+use crate::timeshift::PlaybackState;
+
+pub use crate::protocol::Command;
+
+/// No media for this long after it started flowing counts as a stall
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Control stream format, requested by the client with the `protocol` query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControlProtocol {
+    /// RTSP messages as-is; proxy events are `SET_PARAMETER *` notifications
+    #[default]
+    Raw,
+    /// One JSON object per line, with a `type` of "rtsp", "event", "stats" or "command"
+    Json,
+}
+
+impl ControlProtocol {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "raw" => Some(ControlProtocol::Raw),
+            "json" => Some(ControlProtocol::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlProtocol::Raw => "raw",
+            ControlProtocol::Json => "json",
+        }
+    }
+}
+
+/// Asynchronous proxy-originated message for the browser
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Wall-clock mapping for a track
+    Sync(SyncPoint),
+    /// Methods the camera supports
+    Capabilities(Capabilities),
+    /// Media from the camera stopped arriving
+    Stall { idle: Duration },
+    /// Media is flowing again after a stall
+    Resume,
+    /// The video switched between main and sub-stream
+    Profile(Profile),
+    /// The video switched to another transcoded rendition
+    #[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+    Rendition(String),
+    /// The session ran out of time and is closing
+    Expired(Expiry),
+    /// Periodic level of an audio track
+    Audio { channel_id: u8, level_dbfs: f32, voice: bool },
+    /// Motion started or ended on a video track, with the cue that started it
+    Motion { channel_id: u8, active: bool, cue: MotionCue },
+    /// Notification from the camera's ONVIF event service
+    Onvif(OnvifEvent),
+    /// Playback moved between live and the time-shift buffer
+    TimeShift { state: PlaybackState, behind: Duration },
+    /// Objects detected by the camera's analytics, from an ONVIF metadata track
+    Objects(ObjectFrame),
+    /// The proxy is draining and will not run the session; retry against
+    /// `alternate`, or another instance behind the same address
+    Draining { alternate: Option<String> },
+    /// The camera refused a DESCRIBE, SETUP or PLAY; the response itself
+    /// is forwarded too
+    UpstreamError { method: String, code: u16, reason: String },
+    /// A carousel switched to the camera at `index` of its playlist
+    Carousel { index: usize, count: usize },
+    /// A track on a reliable path fell `behind` live and is skipping ahead
+    /// to its next keyframe
+    Skip { channel_id: u8, behind: Duration },
+    /// The camera changed a video track's encoding parameters mid-session
+    CodecConfig { channel_id: u8, stream: StreamInfo, profile_level_id: Option<String>, cause: ConfigChange },
+    /// Channel IDs of the track whose SETUP response, with this CSeq, follows
+    Channels { cseq: String, rtp_channel_id: u8, rtcp_channel_id: u8 },
+    /// The proxy is over a budget and this session, of `priority`, gives way
+    Qos { action: QosAction, reason: Congestion, priority: Priority },
+    /// The camera has its `limit` of sessions; the session waits up to
+    /// `wait` for one to end
+    CameraBusy { limit: usize, wait: Duration },
+    /// The camera ended the stream, as at the end of a recording, and the
+    /// session is closing
+    EndOfStream { cause: EndCause },
+    /// Managed mode: a seek went through and media resumes at `position`
+    /// seconds; what the browser buffered from before is stale
+    Discontinuity { position: f64 },
+    /// The session's health `score` stayed low and the proxy took `action`
+    Health { score: u8, action: HealthAction },
+}
+
+impl Event {
+    /// `x-wt-*` notification carrying the event in raw mode
+    fn notification(&self) -> RtspRequest {
+        match self {
+            Event::Sync(point) => point.notification(),
+            Event::Capabilities(capabilities) => capabilities.notification(),
+            Event::Stall { idle } => {
+                RtspRequest::notification(&format!("x-wt-event: stall;idle-ms={}\r\n", idle.as_millis()))
+            }
+            Event::Resume => RtspRequest::notification("x-wt-event: resume\r\n"),
+            Event::Profile(profile) => {
+                RtspRequest::notification(&format!("x-wt-event: profile;profile={}\r\n", profile.as_str()))
+            }
+            Event::Rendition(name) => {
+                RtspRequest::notification(&format!("x-wt-event: rendition;name={}\r\n", name))
+            }
+            Event::Expired(expiry) => {
+                RtspRequest::notification(&format!("x-wt-event: expired;reason={}\r\n", expiry.as_str()))
+            }
+            Event::Audio { channel_id, level_dbfs, voice } => RtspRequest::notification(&format!(
+                "x-wt-event: audio;channel={};level={:.1};voice={}\r\n",
+                channel_id,
+                level_dbfs,
+                if *voice { "yes" } else { "no" }
+            )),
+            Event::Motion { channel_id, active, cue } => RtspRequest::notification(&format!(
+                "x-wt-event: motion;channel={};state={};cue={}\r\n",
+                channel_id,
+                if *active { "start" } else { "end" },
+                cue.as_str()
+            )),
+            Event::Onvif(event) => {
+                // Raw mode carries the state only; JSON mode has the full event
+                let data: Vec<String> = event.data.iter().map(|(name, value)| format!("{}:{}", name, value)).collect();
+                RtspRequest::notification(&format!(
+                    "x-wt-event: onvif;kind={};topic={};data={}\r\n",
+                    event.kind.as_str(),
+                    event.topic,
+                    data.join(",")
+                ))
+            }
+            Event::TimeShift { state, behind } => RtspRequest::notification(&format!(
+                "x-wt-event: timeshift;state={};behind-ms={}\r\n",
+                state.as_str(),
+                behind.as_millis()
+            )),
+            Event::Objects(frame) => {
+                // id:class:left,top,right,bottom per object; empty fields when unknown
+                let objects: Vec<String> = frame
+                    .objects
+                    .iter()
+                    .map(|object| {
+                        let bbox = object
+                            .bbox
+                            .map(|b| format!("{},{},{},{}", b.left, b.top, b.right, b.bottom))
+                            .unwrap_or_default();
+                        format!("{}:{}:{}", object.id, object.class.as_deref().unwrap_or(""), bbox)
+                    })
+                    .collect();
+                RtspRequest::notification(&format!(
+                    "x-wt-event: objects;channel={};time={};objects={}\r\n",
+                    frame.channel_id,
+                    frame.time.as_deref().unwrap_or(""),
+                    objects.join("/")
+                ))
+            }
+            Event::Draining { alternate } => match alternate {
+                Some(alternate) => RtspRequest::notification(&format!("x-wt-event: draining;alternate={}\r\n", alternate)),
+                None => RtspRequest::notification("x-wt-event: draining\r\n"),
+            },
+            Event::UpstreamError { method, code, reason } => RtspRequest::notification(&format!(
+                "x-wt-event: upstream-error;method={};code={};reason={}\r\n",
+                method, code, reason
+            )),
+            Event::Carousel { index, count } => {
+                RtspRequest::notification(&format!("x-wt-event: carousel;index={};count={}\r\n", index, count))
+            }
+            Event::Skip { channel_id, behind } => RtspRequest::notification(&format!(
+                "x-wt-event: skip;channel={};behind-ms={}\r\n",
+                channel_id,
+                behind.as_millis()
+            )),
+            Event::CodecConfig { channel_id, stream, profile_level_id, cause } => {
+                // Only what is known
+                let mut params = format!("x-wt-event: codec-config-changed;channel={};cause={}", channel_id, cause.as_str());
+                let fields = [
+                    ("codec", stream.codec.clone()),
+                    ("width", stream.width.map(|w| w.to_string())),
+                    ("height", stream.height.map(|h| h.to_string())),
+                    ("fps", stream.fps.map(|fps| fps.to_string())),
+                    ("profile", stream.profile.clone()),
+                    ("level", stream.level.clone()),
+                    ("profile-level-id", profile_level_id.clone()),
+                ];
+                for (name, value) in fields {
+                    if let Some(value) = value {
+                        params.push_str(&format!(";{}={}", name, value));
+                    }
+                }
+                params.push_str("\r\n");
+                RtspRequest::notification(&params)
+            }
+            Event::Channels { cseq, rtp_channel_id, rtcp_channel_id } => RtspRequest::notification(&format!(
+                "x-wt-event: channels;cseq={};rtp={};rtcp={}\r\n",
+                cseq, rtp_channel_id, rtcp_channel_id
+            )),
+            Event::Qos { action, reason, priority } => RtspRequest::notification(&format!(
+                "x-wt-event: qos;action={};reason={};priority={}\r\n",
+                action.as_str(),
+                reason.as_str(),
+                priority.as_str()
+            )),
+            Event::CameraBusy { limit, wait } => RtspRequest::notification(&format!(
+                "x-wt-event: camera-busy;limit={};wait-ms={}\r\n",
+                limit,
+                wait.as_millis()
+            )),
+            Event::EndOfStream { cause } => {
+                RtspRequest::notification(&format!("x-wt-event: end-of-stream;cause={}\r\n", cause.as_str()))
+            }
+            Event::Discontinuity { position } => {
+                RtspRequest::notification(&format!("x-wt-event: discontinuity;npt={:.3}\r\n", position))
+            }
+            Event::Health { score, action } => {
+                RtspRequest::notification(&format!("x-wt-event: health;score={};action={}\r\n", score, action.as_str()))
+            }
+        }
+    }
+
+    fn message(&self) -> EventMessage {
+        match self {
+            Event::Sync(point) => EventMessage::Sync {
+                channel: point.channel_id,
+                wallclock: point.unix_ms,
+                rtp: point.rtp_timestamp,
+                clock_rate: point.clock_rate,
+                measured_rate: point.measured_rate,
+            },
+            Event::Capabilities(capabilities) => EventMessage::Capabilities {
+                pause: capabilities.supports("PAUSE"),
+                get_parameter: capabilities.supports("GET_PARAMETER"),
+                set_parameter: capabilities.supports("SET_PARAMETER"),
+                methods: capabilities.methods.clone(),
+                streams: capabilities.streams.clone(),
+            },
+            Event::Stall { idle } => EventMessage::Stall { idle_ms: idle.as_millis() as u64 },
+            Event::Resume => EventMessage::Resume,
+            Event::Profile(profile) => EventMessage::Profile { profile: profile.as_str() },
+            Event::Rendition(name) => EventMessage::Rendition { name: name.clone() },
+            Event::Expired(expiry) => EventMessage::Expired { reason: expiry.as_str() },
+            Event::Audio { channel_id, level_dbfs, voice } => {
+                EventMessage::Audio { channel: *channel_id, level_dbfs: *level_dbfs, voice: *voice }
+            }
+            Event::Motion { channel_id, active, cue } => {
+                EventMessage::Motion { channel: *channel_id, active: *active, cue: cue.as_str() }
+            }
+            Event::Onvif(event) => EventMessage::Onvif {
+                kind: event.kind.as_str(),
+                topic: event.topic.clone(),
+                time: event.time.clone(),
+                operation: event.operation.clone(),
+                source: event.source.iter().cloned().collect(),
+                data: event.data.iter().cloned().collect(),
+            },
+            Event::TimeShift { state, behind } => {
+                EventMessage::Timeshift { state: state.as_str(), behind_ms: behind.as_millis() as u64 }
+            }
+            Event::Objects(frame) => EventMessage::Objects {
+                channel: frame.channel_id,
+                time: frame.time.clone(),
+                objects: frame.objects.clone(),
+            },
+            Event::Draining { alternate } => EventMessage::Draining { alternate: alternate.clone() },
+            Event::UpstreamError { method, code, reason } => EventMessage::UpstreamError {
+                method: method.clone(),
+                code: *code,
+                class: status_class(*code),
+                reason: reason.clone(),
+            },
+            Event::Carousel { index, count } => EventMessage::Carousel { index: *index, count: *count },
+            Event::Skip { channel_id, behind } => {
+                EventMessage::Skip { channel: *channel_id, behind_ms: behind.as_millis() as u64 }
+            }
+            Event::CodecConfig { channel_id, stream, profile_level_id, cause } => EventMessage::CodecConfigChanged {
+                channel: *channel_id,
+                cause: cause.as_str(),
+                profile_level_id: profile_level_id.clone(),
+                stream: stream.clone(),
+            },
+            Event::Channels { cseq, rtp_channel_id, rtcp_channel_id } => EventMessage::Channels {
+                cseq: cseq.clone(),
+                rtp_channel: *rtp_channel_id,
+                rtcp_channel: *rtcp_channel_id,
+            },
+            Event::Qos { action, reason, priority } => {
+                EventMessage::Qos { action: action.as_str(), reason: reason.as_str(), priority: priority.as_str() }
+            }
+            Event::CameraBusy { limit, wait } => EventMessage::CameraBusy { limit: *limit, wait_ms: wait.as_millis() as u64 },
+            Event::EndOfStream { cause } => EventMessage::EndOfStream { cause: cause.as_str() },
+            Event::Discontinuity { position } => EventMessage::Discontinuity { npt: *position },
+            Event::Health { score, action } => EventMessage::Health { score: *score, action: action.as_str() },
+        }
+    }
+}
+
+/// Per-session statistics returned for [`Command::Stats`]
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub uptime: Duration,
+    pub bytes_sent: u64,
+    pub profile: Profile,
+    pub stalled: bool,
+    pub startup: StartupReport,
+    /// Receive-side metrics of the camera's RTP channels
+    pub channels: Vec<ChannelReport>,
+    /// What the client can receive, once it has been estimated
+    pub estimate_kbps: Option<u32>,
+}
+
+/// Encodes and decodes one session's control stream
+#[derive(Debug)]
+pub struct ControlCodec {
+    protocol: ControlProtocol,
+    /// CSeq of the last raw-mode notification
+    notify_cseq: u32,
+}
+
+impl ControlCodec {
+    pub fn new(protocol: ControlProtocol) -> Self {
+        Self { protocol, notify_cseq: 0 }
+    }
+
+    /// An RTSP message for the browser
+    pub fn rtsp(&self, message: &[u8]) -> Vec<u8> {
+        match self.protocol {
+            ControlProtocol::Raw => message.to_vec(),
+            ControlProtocol::Json => line(&ServerMessage::Rtsp { data: String::from_utf8_lossy(message).into_owned() }),
+        }
+    }
+
+    pub fn event(&mut self, event: &Event) -> Vec<u8> {
+        match self.protocol {
+            ControlProtocol::Raw => {
+                let mut notification = event.notification();
+                self.notify_cseq += 1;
+                notification.headers.insert("CSeq".to_string(), self.notify_cseq.to_string());
+                notification.to_bytes()
+            }
+            ControlProtocol::Json => line(&ServerMessage::Event(event.message())),
+        }
+    }
+
+    /// A proxy message that only exists in JSON mode, such as simple mode's track list
+    pub fn message(&self, message: &ServerMessage) -> Vec<u8> {
+        line(message)
+    }
+
+    pub fn stats(&self, stats: &SessionStats) -> Vec<u8> {
+        // Phases not reached yet are null
+        let mut startup: serde_json::Map<String, Value> = Phase::ALL
+            .iter()
+            .map(|&phase| {
+                let ms = stats.startup.phase(phase).map(|d| d.as_millis() as u64);
+                (format!("{}_ms", phase.as_str()), json!(ms))
+            })
+            .collect();
+        startup.insert("total_ms".to_string(), json!(stats.startup.total.map(|d| d.as_millis() as u64)));
+        line(&ServerMessage::Stats {
+            uptime_ms: stats.uptime.as_millis() as u64,
+            bytes_sent: stats.bytes_sent,
+            profile: stats.profile.as_str(),
+            stalled: stats.stalled,
+            startup,
+            channels: json!(stats.channels),
+            estimate_kbps: stats.estimate_kbps,
+        })
+    }
+
+    /// Move the RTSP text received from the browser into `rtsp`, returning any
+    /// commands. In JSON mode an incomplete last line is left in `input`.
+    pub fn decode(&self, input: &mut BytesMut, rtsp: &mut BytesMut) -> Vec<Command> {
+        if self.protocol == ControlProtocol::Raw {
+            rtsp.extend_from_slice(&input.split());
+            return Vec::new();
+        }
+
+        let mut commands = Vec::new();
+        while let Some(end) = input.iter().position(|&b| b == b'\n') {
+            let envelope = input.split_to(end);
+            input.advance(1);
+            if envelope.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match serde_json::from_slice::<ClientMessage>(&envelope) {
+                Ok(ClientMessage::Rtsp { data }) => rtsp.extend_from_slice(data.as_bytes()),
+                Ok(ClientMessage::Command { command }) => commands.push(command),
+                Err(e) => warn!("Ignoring invalid control envelope: {}", e),
+            }
+        }
+        commands
+    }
+}
+
+/// One JSON envelope and its newline; a message that cannot be serialized is
+/// logged and left out rather than taking the session down
+fn line(message: &ServerMessage) -> Vec<u8> {
+    match serde_json::to_vec(message) {
+        Ok(mut bytes) => {
+            bytes.push(b'\n');
+            bytes
+        }
+        Err(e) => {
+            warn!("Dropping control message that failed to serialize: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Tracks when the camera last delivered media, to report stalls
+#[derive(Debug)]
+pub struct MediaWatch {
+    started: Instant,
+    /// Milliseconds after `started` of the last packet; 0 before the first
+    last_ms: AtomicU64,
+    paused: AtomicBool,
+    stalled: AtomicBool,
+}
+
+impl MediaWatch {
+    pub fn new() -> Self {
+        Self { started: Instant::now(), last_ms: AtomicU64::new(0), paused: AtomicBool::new(false), stalled: AtomicBool::new(false) }
+    }
+
+    /// Record a packet from the camera
+    pub fn touch(&self) {
+        self.last_ms.store(self.started.elapsed().as_millis().max(1) as u64, Ordering::Relaxed);
+    }
+
+    /// Media is expected to stop while the browser has the stream paused
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        // Give a resumed stream the full timeout to start flowing again
+        if !paused && self.last_ms.load(Ordering::Relaxed) != 0 {
+            self.touch();
+        }
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    /// Stall or resume event, if the state changed since the last check
+    pub fn check(&self) -> Option<Event> {
+        let last = self.last_ms.load(Ordering::Relaxed);
+        if last == 0 {
+            return None;
+        }
+        let idle = self.started.elapsed().saturating_sub(Duration::from_millis(last));
+        let stalled = idle >= STALL_TIMEOUT && !self.paused.load(Ordering::Relaxed);
+        if stalled == self.stalled.swap(stalled, Ordering::Relaxed) {
+            return None;
+        }
+        Some(if stalled { Event::Stall { idle } } else { Event::Resume })
+    }
+}
+
+/// How the camera said its stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndCause {
+    /// RTCP BYE on every track
+    Bye,
+    /// An ANNOUNCE or PLAY_NOTIFY saying so
+    Announce,
+}
+
+impl EndCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndCause::Bye => "bye",
+            EndCause::Announce => "announce",
+        }
+    }
+}
+
+/// Notices the camera ending the stream: once every watched track has sent
+/// RTCP BYE, or when the camera announces it
+#[derive(Debug, Default)]
+pub struct EndOfStream {
+    /// RTCP channels watched for BYE, and whether each has sent one
+    tracks: Mutex<HashMap<u8, bool>>,
+    cause: Mutex<Option<EndCause>>,
+    ended: CancellationToken,
+}
+
+impl EndOfStream {
+    /// Wait for BYE on this RTCP channel too
+    pub fn watch(&self, channel_id: u8) {
+        self.tracks.lock().unwrap().insert(channel_id, false);
+    }
+
+    pub fn on_bye(&self, channel_id: u8) {
+        let mut tracks = self.tracks.lock().unwrap();
+        if let Some(said) = tracks.get_mut(&channel_id) {
+            *said = true;
+        }
+        if tracks.values().all(|&said| said) {
+            self.finish(EndCause::Bye);
+        }
+    }
+
+    pub fn finish(&self, cause: EndCause) {
+        self.cause.lock().unwrap().get_or_insert(cause);
+        self.ended.cancel();
+    }
+
+    /// Resolves once the stream has ended
+    pub fn ended(&self) -> WaitForCancellationFuture<'_> {
+        self.ended.cancelled()
+    }
+
+    pub fn cause(&self) -> EndCause {
+        self.cause.lock().unwrap().unwrap_or(EndCause::Bye)
+    }
+}
+
+/// `4xx` or `5xx`, for metrics and dashboards
+pub fn status_class(code: u16) -> String {
+    format!("{}xx", code / 100)
+}
@@ -0,0 +1,72 @@
+//! The browser player files, served by `--serve-demo` on the WebSocket port
+//! so the page and the proxy share an origin
+
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+use crate::http::{Request, Response};
+
+/// Response with a file from `root` for a GET or HEAD request
+pub async fn file(root: &Path, request: &Request) -> Response {
+    let Some(path) = resolve(root, &request.target) else {
+        warn!("Refusing demo request for {}", request.target);
+        return Response::text("404 Not Found", "Not found");
+    };
+    match tokio::fs::read(&path).await {
+        Ok(body) => {
+            info!("Serving demo file {}", path.display());
+            Response::new("200 OK", content_type(&path), body)
+        }
+        Err(_) => Response::text("404 Not Found", "Not found"),
+    }
+}
+
+/// Map a request target to a file under `root`; None for anything that
+/// would escape it
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let url = url::Url::parse(&format!("http://localhost{}", target)).ok()?;
+    let mut path = root.to_path_buf();
+    for segment in url.path_segments()? {
+        let segment = percent_decode(segment)?;
+        let mut components = Path::new(&segment).components();
+        match (components.next(), components.next()) {
+            (None, _) => {}
+            (Some(Component::Normal(name)), None) => path.push(name),
+            _ => return None,
+        }
+    }
+    if path.is_dir() {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,68 @@
+//! Draining for rolling deployments: sessions already running carry on, new
+//! ones get a `draining` event naming another instance and are closed.
+//!
+//! `POST /drain` starts draining, `DELETE /drain` stops it and `GET /drain`
+//! reports the state, answering 503 while draining so load balancer health
+//! checks take the instance out of rotation.
+
+use serde_json::json;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use crate::config::Config;
+use crate::http::{Request, Response};
+
+#[derive(Debug, Clone)]
+pub struct Drain {
+    /// Unix time draining started, in ms
+    pub since_ms: u64,
+    /// Endpoint new sessions should use instead, if known
+    pub alternate: Option<String>,
+}
+
+fn state() -> &'static Mutex<Option<Drain>> {
+    static DRAIN: OnceLock<Mutex<Option<Drain>>> = OnceLock::new();
+    DRAIN.get_or_init(Default::default)
+}
+
+/// The drain in progress, if any
+pub fn current() -> Option<Drain> {
+    state().lock().unwrap().clone()
+}
+
+/// Answer a `/drain` request. Changes need the admin token; the state is
+/// public.
+pub fn handle(request: &Request, config: &Config) -> Response {
+    let Some(drain) = &config.drain else {
+        return Response::text("404 Not Found", "Not found");
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        if let Err(refused) = crate::http::require_admin(request, config) {
+            return refused;
+        }
+        let params = request.query();
+        let mut state = state().lock().unwrap();
+        if request.method == "POST" {
+            let alternate = params.get("alternate").cloned().or_else(|| drain.alternate.clone());
+            let since_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            info!("Draining: new sessions are sent to {}", alternate.as_deref().unwrap_or("another instance"));
+            *state = Some(Drain { since_ms: state.as_ref().map_or(since_ms, |d| d.since_ms), alternate });
+        } else if state.take().is_some() {
+            info!("Draining stopped: accepting new sessions again");
+        }
+    }
+
+    let sessions: i64 = crate::stats::global()
+        .snapshot()
+        .into_iter()
+        .filter(|(key, _)| key.starts_with("sessions_active"))
+        .map(|(_, value)| value)
+        .sum();
+    match current() {
+        Some(drain) => Response::json(
+            "503 Service Unavailable",
+            &json!({ "draining": true, "since_ms": drain.since_ms, "alternate": drain.alternate, "sessions": sessions }),
+        ),
+        None => Response::json("200 OK", &json!({ "draining": false, "sessions": sessions })),
+    }
+}
@@ -0,0 +1,126 @@
+//! Media the proxy gave up on before it reached the browser. Every drop is
+//! counted as `packets_dropped{reason="..."}`; with `[drop_log]`, a sample of
+//! them is also written to disk, one JSON line each with the packet's
+//! channel and size, so throughput problems can be diagnosed afterwards.
+//! The log is rotated to `<path>.1` at its size limit, so it never takes
+//! more than twice that.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::config::DropLogConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// A queue between the proxy's tasks was full
+    #[cfg_attr(not(any(feature = "srt", feature = "transcode")), allow(dead_code))]
+    QueueFull,
+    /// Larger than a WebTransport datagram can carry
+    #[cfg_attr(not(feature = "webtransport"), allow(dead_code))]
+    TooLarge,
+    /// The browser's connection refused it
+    #[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+    SendFailed,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::QueueFull => "queue-full",
+            DropReason::TooLarge => "too-large",
+            DropReason::SendFailed => "send-failed",
+        }
+    }
+}
+
+/// One sampled drop
+#[derive(Debug, Serialize)]
+struct Record {
+    t: u64,
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+    size: usize,
+    /// Drops for the reason so far, this one included
+    n: u64,
+}
+
+struct DropLog {
+    path: PathBuf,
+    max_bytes: u64,
+    sample_every: u64,
+    file: Mutex<File>,
+}
+
+static LOG: OnceLock<DropLog> = OnceLock::new();
+/// Drops so far, by reason, for sampling
+static COUNTS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Open the configured drop log
+pub fn init(config: &DropLogConfig) -> Result<()> {
+    let log = DropLog {
+        path: config.path.clone(),
+        max_bytes: config.max_bytes,
+        sample_every: config.sample_every.max(1),
+        file: Mutex::new(open(&config.path)?),
+    };
+    let _ = LOG.set(log);
+    Ok(())
+}
+
+/// Count a dropped packet, and log it if it is sampled
+pub fn record(reason: DropReason, channel: Option<u8>, size: usize) {
+    crate::stats::global().add(&format!("packets_dropped{{reason=\"{}\"}}", reason.as_str()), 1);
+    let n = COUNTS[reason as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    // The first drop of each kind is always logged
+    if let Some(log) = LOG.get()
+        && (n - 1).is_multiple_of(log.sample_every)
+    {
+        log.append(&Record { t: now_ms(), reason: reason.as_str(), channel, size, n });
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open drop log {}", path.display()))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl DropLog {
+    /// Drop log failures are logged, never fatal to the session
+    fn append(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).expect("drop records serialize");
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = self.rotate_if_full(&mut file) {
+            warn!("Drop log rotation failed: {:#}", e);
+        }
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write drop log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn rotate_if_full(&self, file: &mut File) -> Result<()> {
+        if file.metadata()?.len() < self.max_bytes {
+            return Ok(());
+        }
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(".1");
+        std::fs::rename(&self.path, rotated)?;
+        *file = open(&self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Why a session failed. Sessions end with one of these kinds, so callers can
+//! tell a camera that is down from a browser that left or a policy that
+//! refused, and the browser is told the kind when the proxy closes on it.
+
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The camera, or the relay or proxy in front of it, could not be reached
+    UpstreamConnect(String),
+    /// The camera's connection failed or was closed during the session
+    UpstreamClosed(String),
+    /// The camera answered in a way the session cannot go on from
+    RtspProtocol(String),
+    /// The browser's connection failed or was closed
+    TransportClosed(String),
+    /// A limit or policy ended or refused the session
+    PolicyDenied(String),
+    /// A URL or message that could not be parsed
+    Parse(String),
+    /// Anything else, such as a local socket that could not be bound
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// Failure of the browser's connection
+    pub fn transport(e: impl fmt::Display) -> Self {
+        Error::TransportClosed(e.to_string())
+    }
+
+    /// The first of these in `error`'s chain, if any
+    pub fn find(error: &anyhow::Error) -> Option<&Error> {
+        error.chain().find_map(|cause| cause.downcast_ref::<Error>())
+    }
+
+    /// Stable name of the kind, for close reasons and logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::UpstreamConnect(_) => "upstream-connect",
+            Error::UpstreamClosed(_) => "upstream-closed",
+            Error::RtspProtocol(_) => "rtsp-protocol",
+            Error::TransportClosed(_) => "transport-closed",
+            Error::PolicyDenied(_) => "policy-denied",
+            Error::Parse(_) => "parse-error",
+            Error::Other(_) => "internal",
+        }
+    }
+
+    /// Code the browser's connection is closed with: the WebTransport
+    /// session error code, or the WebSocket close code. 4000-4999 is the
+    /// range WebSocket leaves to applications.
+    pub fn close_code(&self) -> u32 {
+        match self {
+            Error::Other(_) => 4000,
+            Error::UpstreamConnect(_) => 4001,
+            Error::UpstreamClosed(_) => 4002,
+            Error::RtspProtocol(_) => 4003,
+            Error::TransportClosed(_) => 4004,
+            Error::PolicyDenied(_) => 4005,
+            Error::Parse(_) => 4006,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UpstreamConnect(reason) => write!(f, "Failed to connect to the camera: {}", reason),
+            Error::UpstreamClosed(reason) => write!(f, "Camera connection lost: {}", reason),
+            Error::RtspProtocol(reason) => write!(f, "RTSP error from the camera: {}", reason),
+            Error::TransportClosed(reason) => write!(f, "Client connection lost: {}", reason),
+            Error::PolicyDenied(reason) => write!(f, "Session refused: {}", reason),
+            Error::Parse(reason) => write!(f, "Parse error: {}", reason),
+            Error::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    /// Recovers the kind of an `Error` that was passed through `anyhow`
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast::<Error>().unwrap_or_else(Error::Other)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Other(e.into())
+    }
+}
+
+/// Failures of the browser's connection
+#[cfg_attr(not(any(feature = "webtransport", feature = "websocket")), allow(unused_macros))]
+macro_rules! transport_errors {
+    ($($error:ty),*) => {
+        $(impl From<$error> for Error {
+            fn from(e: $error) -> Self {
+                Error::transport(e)
+            }
+        })*
+    };
+}
+
+#[cfg(feature = "webtransport")]
+transport_errors!(
+    wtransport::error::ConnectionError,
+    wtransport::error::StreamOpeningError,
+    wtransport::error::StreamWriteError,
+    wtransport::error::SendDatagramError
+);
+
+#[cfg(feature = "websocket")]
+transport_errors!(tokio_tungstenite::tungstenite::Error);
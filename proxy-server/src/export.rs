@@ -0,0 +1,84 @@
+//! `GET /export?session=<id>&seconds=<n>`: the last seconds of a running
+//! session's video as an MP4 clip, cut from its time-shift buffer, for
+//! capturing incidents while they are still on screen
+
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+use tracing::info;
+use crate::config::Config;
+use crate::h264::Depacketizer;
+use crate::http::{Request, Response};
+use crate::timeshift::TimeShift;
+
+/// Time-shift buffers of running sessions, by session ID
+fn sessions() -> &'static Mutex<HashMap<String, Weak<TimeShift>>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, Weak<TimeShift>>>> = OnceLock::new();
+    SESSIONS.get_or_init(Default::default)
+}
+
+/// Keeps a session exportable until dropped
+pub struct Registration {
+    session_id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        sessions().lock().unwrap().remove(&self.session_id);
+    }
+}
+
+pub fn register(session_id: &str, timeshift: &Arc<TimeShift>) -> Registration {
+    sessions().lock().unwrap().insert(session_id.to_string(), Arc::downgrade(timeshift));
+    Registration { session_id: session_id.to_string() }
+}
+
+/// Answer an export request, which needs the admin token
+pub fn handle(request: &Request, config: &Config) -> Response {
+    if let Err(refused) = crate::http::require_admin(request, config) {
+        return refused;
+    }
+    let params = request.query();
+    let Some(session_id) = params.get("session") else {
+        return Response::json("400 Bad Request", &json!({ "error": "Missing 'session' query parameter" }));
+    };
+    // The whole buffer unless a span is given
+    let span = match params.get("seconds").map(|s| s.parse::<u64>()) {
+        Some(Ok(seconds)) => Duration::from_secs(seconds),
+        Some(Err(_)) => return Response::json("400 Bad Request", &json!({ "error": "Invalid 'seconds'" })),
+        None => Duration::MAX,
+    };
+    let timeshift = sessions().lock().unwrap().get(session_id).and_then(Weak::upgrade);
+    let Some(timeshift) = timeshift else {
+        return Response::json("404 Not Found", &json!({ "error": "No running session with a time-shift buffer has this ID" }));
+    };
+    match clip(&timeshift, span) {
+        Ok(mp4) => {
+            info!("Exported a {} KiB clip of session {}", mp4.len() / 1024, session_id);
+            Response::new("200 OK", "video/mp4", mp4)
+        }
+        Err(e) => Response::json("409 Conflict", &json!({ "error": format!("{:#}", e) })),
+    }
+}
+
+/// MP4 of the session's video over the last `span`, from its first keyframe
+fn clip(timeshift: &TimeShift, span: Duration) -> Result<Vec<u8>> {
+    let (video, payloads) = timeshift.snapshot(span);
+    let Some(video) = video else {
+        bail!("The session has no H.264 video to export");
+    };
+    let framing = timeshift.framing();
+    let mut depacketizer = Depacketizer::default();
+    let mut units = Vec::new();
+    for payload in &payloads {
+        if let Some((channel_id, packet)) = framing.split(payload)
+            && channel_id == video.channel_id
+        {
+            units.extend(depacketizer.push(packet));
+        }
+    }
+    units.extend(depacketizer.finish());
+    crate::mp4::h264_clip(&units, &video.parameter_sets)
+}
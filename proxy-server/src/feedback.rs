@@ -0,0 +1,86 @@
+/// First byte of a client → proxy feedback message on the datagram path.
+///
+/// Media datagrams towards the browser start with a channel ID allocated
+/// upwards from 0, so the top values are reserved for feedback.
+pub const FEEDBACK_MARKER: u8 = 0xFF;
+/// First byte of a [`Nack`]
+pub const NACK_MARKER: u8 = 0xFE;
+/// First byte of an [`Ack`]
+pub const ACK_MARKER: u8 = 0xFD;
+
+/// Periodic reception report sent by the browser.
+///
+/// Wire format: `[0xFF][fraction lost: u8][received kbps: u32 BE]`, where the
+/// fraction lost is expressed in 1/256ths like an RTCP receiver report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Feedback {
+    pub loss_fraction: f32,
+    pub received_kbps: u32,
+    /// What the client can receive, from its acknowledgments or the QUIC
+    /// connection; None when neither says
+    pub estimate_kbps: Option<u32>,
+}
+
+impl Feedback {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 6 || data[0] != FEEDBACK_MARKER {
+            return None;
+        }
+        Some(Self {
+            loss_fraction: data[1] as f32 / 256.0,
+            received_kbps: u32::from_be_bytes([data[2], data[3], data[4], data[5]]),
+            estimate_kbps: None,
+        })
+    }
+}
+
+/// Acknowledgment of the media received so far, sent by the browser a few
+/// times a second so the proxy can estimate its bandwidth.
+///
+/// Wire format: `[0xFD][bytes received: u32 BE]`, counting every media
+/// payload since the session started, framing included, modulo 2^32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub bytes_received: u32,
+}
+
+impl Ack {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 5 || data[0] != ACK_MARKER {
+            return None;
+        }
+        Some(Self { bytes_received: u32::from_be_bytes([data[1], data[2], data[3], data[4]]) })
+    }
+}
+
+/// Request to resend lost packets of one track, sent by the browser in
+/// `latency=balanced` sessions.
+///
+/// Wire format: `[0xFE][channel][pid: u16 BE][blp: u16 BE]`, the generic
+/// NACK of RFC 4585: `pid` is a lost sequence number, and bit `i` of `blp`
+/// marks `pid + i + 1` lost as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nack {
+    pub channel_id: u8,
+    pub pid: u16,
+    pub blp: u16,
+}
+
+impl Nack {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 6 || data[0] != NACK_MARKER {
+            return None;
+        }
+        Some(Self {
+            channel_id: data[1],
+            pid: u16::from_be_bytes([data[2], data[3]]),
+            blp: u16::from_be_bytes([data[4], data[5]]),
+        })
+    }
+
+    /// Every sequence number the NACK reports lost
+    pub fn sequence_numbers(self) -> impl Iterator<Item = u16> {
+        let following = (0..16).filter(move |bit| self.blp & (1 << bit) != 0).map(move |bit| self.pid.wrapping_add(bit + 1));
+        std::iter::once(self.pid).chain(following)
+    }
+}
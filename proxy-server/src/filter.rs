@@ -0,0 +1,97 @@
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+use crate::config::RtpConfig;
+use crate::rtp_header::payload_offset;
+
+/// Which senders a UDP forwarder accepts media from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceValidation {
+    /// Accept packets from anyone who finds the port
+    #[default]
+    Off,
+    /// Only the camera's address and `server_port` from the SETUP response
+    Strict,
+    /// Lock onto whoever sends the first packet (cameras behind NAT whose
+    /// media comes from a different address or port than advertised)
+    Learn,
+}
+
+/// Drops packets that did not come from the camera
+#[derive(Debug, Clone)]
+pub struct SourceFilter {
+    mode: SourceValidation,
+    camera: IpAddr,
+    /// Expected port; any port of the camera's address when the SETUP
+    /// response did not say
+    port: Option<u16>,
+    learned: Option<SocketAddr>,
+    warned: bool,
+}
+
+impl SourceFilter {
+    /// None when validation is off
+    pub fn new(mode: SourceValidation, camera: IpAddr, port: Option<u16>) -> Option<Self> {
+        (mode != SourceValidation::Off).then_some(Self { mode, camera, port, learned: None, warned: false })
+    }
+
+    pub fn admit(&mut self, from: SocketAddr) -> bool {
+        let ok = match self.mode {
+            SourceValidation::Off => true,
+            SourceValidation::Strict => from.ip() == self.camera && self.port.is_none_or(|port| port == from.port()),
+            SourceValidation::Learn => *self.learned.get_or_insert(from) == from,
+        };
+        if !ok {
+            crate::stats::global().add("rtp_packets_rejected", 1);
+            if !self.warned {
+                warn!("Dropping media from unexpected sender {} (further drops are only counted)", from);
+                self.warned = true;
+            }
+        }
+        ok
+    }
+}
+
+/// Drops packets a browser decoder should never see: anything that is not
+/// RTP/RTCP version 2, and RTP with a payload type the SDP did not announce
+#[derive(Debug, Clone)]
+pub struct PayloadFilter {
+    drop_malformed: bool,
+    /// Payload types allowed on an RTP channel; None for RTCP or when unfiltered
+    formats: Option<Vec<u8>>,
+    rtcp: bool,
+}
+
+impl PayloadFilter {
+    /// None when the config filters nothing
+    pub fn new(config: &RtpConfig, formats: Option<&[u8]>, rtcp: bool) -> Option<Self> {
+        let formats = formats.filter(|f| config.filter_payload_types && !rtcp && !f.is_empty()).map(<[u8]>::to_vec);
+        (config.drop_malformed || formats.is_some()).then_some(Self { drop_malformed: config.drop_malformed, formats, rtcp })
+    }
+
+    pub fn admit(&self, packet: &[u8]) -> bool {
+        let ok = (!self.drop_malformed || self.well_formed(packet))
+            && self.formats.as_ref().is_none_or(|formats| packet.get(1).is_some_and(|b| formats.contains(&(b & 0x7F))));
+        if !ok {
+            crate::stats::global().add("rtp_packets_filtered", 1);
+        }
+        ok
+    }
+
+    fn well_formed(&self, packet: &[u8]) -> bool {
+        let min_len = if self.rtcp { 8 } else { 12 };
+        if packet.len() < min_len || packet[0] >> 6 != 2 {
+            return false;
+        }
+        if self.rtcp {
+            return true;
+        }
+        // The header, and any padding it announces, must fit in the packet
+        let Some(offset) = payload_offset(packet) else {
+            return false;
+        };
+        let padding = if packet[0] & 0x20 != 0 { packet[packet.len() - 1] as usize } else { 0 };
+        packet.len() >= offset + padding
+    }
+}
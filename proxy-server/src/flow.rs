@@ -0,0 +1,110 @@
+//! Flow control for reliable media paths (WebSocket, TCP and WebTransport
+//! streams). Nothing is lost on them, so a viewer on a slow link makes every
+//! send wait and falls further and further behind live. Once a track is too
+//! far behind, it drops packets until it has caught up and reaches a
+//! keyframe, where the browser's decoder can start again.
+//!
+//! How far behind is measured per track, as the wall-clock time taken to
+//! forward its media against the media time its RTP timestamps cover.
+
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::config::FlowControlConfig;
+use crate::control::Event;
+
+/// A gap this long between packets means the camera paused or stalled
+/// rather than the viewer lagging; the timeline starts again after it
+const IDLE_GAP: Duration = Duration::from_secs(1);
+
+/// Watches one track's lag and decides which of its packets to forward
+#[derive(Debug)]
+pub struct FlowControl {
+    channel_id: u8,
+    clock_rate: u32,
+    max_lag: Duration,
+    /// Skipping only ends on a keyframe (H.264 and H.265 video)
+    keyframes: bool,
+    tx: mpsc::Sender<Event>,
+    /// When the timeline started
+    origin: Option<Instant>,
+    last_timestamp: u32,
+    /// RTP ticks since the origin, unwrapped
+    ticks: i64,
+    /// When the previous packet was sent or dropped
+    last_done: Option<Instant>,
+    skipping: bool,
+}
+
+impl FlowControl {
+    /// None when the config lets tracks fall behind without limit
+    pub fn new(channel_id: u8, clock_rate: u32, keyframes: bool, config: &FlowControlConfig, tx: mpsc::Sender<Event>) -> Option<Self> {
+        (config.max_lag_ms > 0).then(|| Self {
+            channel_id,
+            clock_rate: clock_rate.max(1),
+            max_lag: Duration::from_millis(config.max_lag_ms),
+            keyframes,
+            tx,
+            origin: None,
+            last_timestamp: 0,
+            ticks: 0,
+            last_done: None,
+            skipping: false,
+        })
+    }
+
+    /// Whether to forward an RTP packet; call [`FlowControl::sent`] once it is
+    pub fn admit(&mut self, packet: &[u8]) -> bool {
+        if packet.len() < 12 {
+            return true;
+        }
+        let now = Instant::now();
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let lag = self.lag(now, timestamp);
+
+        if !self.skipping && lag > self.max_lag {
+            info!("Channel {} is {:?} behind live, skipping ahead", self.channel_id, lag);
+            crate::stats::global().add("flow_skips", 1);
+            let _ = self.tx.try_send(Event::Skip { channel_id: self.channel_id, behind: lag });
+            self.skipping = true;
+        }
+        if self.skipping {
+            if lag > self.max_lag / 2 || (self.keyframes && !crate::rtp::is_keyframe_start(packet)) {
+                crate::stats::global().add("flow_skipped_packets", 1);
+                self.last_done = Some(now);
+                return false;
+            }
+            self.skipping = false;
+        }
+        true
+    }
+
+    /// The admitted packet has been handed to the client's connection
+    pub fn sent(&mut self) {
+        self.last_done = Some(Instant::now());
+    }
+
+    /// How far the track is behind its timeline at `now`
+    fn lag(&mut self, now: Instant, timestamp: u32) -> Duration {
+        let idle = self.last_done.is_some_and(|done| now.duration_since(done) >= IDLE_GAP);
+        let step = timestamp.wrapping_sub(self.last_timestamp) as i32 as i64;
+        self.last_timestamp = timestamp;
+        // Seeks and camera restarts jump back; B-frames only a little
+        let Some(started) = self.origin.filter(|_| !idle && step > -(self.clock_rate as i64)) else {
+            self.origin = Some(now);
+            self.ticks = 0;
+            return Duration::ZERO;
+        };
+        self.ticks += step;
+        let media = Duration::from_secs_f64(self.ticks.max(0) as f64 / self.clock_rate as f64);
+        match now.duration_since(started).checked_sub(media) {
+            Some(lag) => lag,
+            // Media ahead of the wall clock: a burst, or a jump forward
+            None => {
+                self.origin = Some(now);
+                self.ticks = 0;
+                Duration::ZERO
+            }
+        }
+    }
+}
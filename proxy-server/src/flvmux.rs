@@ -0,0 +1,113 @@
+//! FLV writer for `output=flv`: H.264 video and AAC audio tags, as flv.js
+//! plays them. Sequence headers go out before the first frame of each
+//! track, and again for video whenever the camera's SPS or PPS change.
+
+const TAG_AUDIO: u8 = 8;
+const TAG_VIDEO: u8 = 9;
+const TAG_HEADER: usize = 11;
+const CODEC_AVC: u8 = 7;
+/// AAC; FLV requires the rate, size and channel bits set like this for it
+const AUDIO_AAC: u8 = 0xAF;
+const FRAME_KEY: u8 = 1;
+const FRAME_INTER: u8 = 2;
+const PACKET_SEQUENCE_HEADER: u8 = 0;
+const PACKET_DATA: u8 = 1;
+
+#[derive(Debug, Default)]
+pub struct FlvMuxer {
+    video: bool,
+    audio: bool,
+    header_sent: bool,
+    /// SPS and PPS of the last AVC sequence header
+    parameter_sets: Option<(Vec<u8>, Vec<u8>)>,
+    audio_config_sent: bool,
+}
+
+impl FlvMuxer {
+    /// Streams must be added before the first frame is written
+    pub fn add_video(&mut self) {
+        self.video = true;
+    }
+
+    pub fn add_audio(&mut self) {
+        self.audio = true;
+    }
+
+    /// Tags for one picture's NAL units, stamped `pts` (90 kHz). Pictures
+    /// before the first SPS and PPS cannot be described and are dropped.
+    pub fn video(&mut self, pts: u64, keyframe: bool, nals: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.header(&mut out);
+        let sps = nals.iter().copied().find(|nal| nal[0] & 0x1F == 7);
+        let pps = nals.iter().copied().find(|nal| nal[0] & 0x1F == 8);
+        if let (Some(sps), Some(pps)) = (sps, pps)
+            && self.parameter_sets.as_ref().is_none_or(|(s, p)| s != sps || p != pps)
+            && sps.len() >= 4
+        {
+            let mut record = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+            record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            record.extend_from_slice(sps);
+            record.push(1);
+            record.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            record.extend_from_slice(pps);
+            write_tag(TAG_VIDEO, pts, &[FRAME_KEY << 4 | CODEC_AVC, PACKET_SEQUENCE_HEADER, 0, 0, 0], &record, &mut out);
+            self.parameter_sets = Some((sps.to_vec(), pps.to_vec()));
+        }
+        if self.parameter_sets.is_none() {
+            return out;
+        }
+        // Parameter sets travel in the sequence header, the rest length-prefixed
+        let mut data = Vec::new();
+        for nal in nals.iter().filter(|nal| !matches!(nal[0] & 0x1F, 7 | 8)) {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        let frame = if keyframe { FRAME_KEY } else { FRAME_INTER };
+        write_tag(TAG_VIDEO, pts, &[frame << 4 | CODEC_AVC, PACKET_DATA, 0, 0, 0], &data, &mut out);
+        out
+    }
+
+    /// Tags for raw AAC frames, each with its `pts` (90 kHz).
+    /// `config` is the track's AudioSpecificConfig.
+    pub fn audio(&mut self, config: &[u8], frames: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.header(&mut out);
+        let Some(&(first, _)) = frames.first() else {
+            return out;
+        };
+        if !self.audio_config_sent {
+            write_tag(TAG_AUDIO, first, &[AUDIO_AAC, PACKET_SEQUENCE_HEADER], config, &mut out);
+            self.audio_config_sent = true;
+        }
+        for (pts, frame) in frames {
+            write_tag(TAG_AUDIO, *pts, &[AUDIO_AAC, PACKET_DATA], frame, &mut out);
+        }
+        out
+    }
+
+    /// The file header, before the first tag
+    fn header(&mut self, out: &mut Vec<u8>) {
+        if std::mem::replace(&mut self.header_sent, true) {
+            return;
+        }
+        let flags = if self.audio { 0x04 } else { 0x00 } | if self.video { 0x01 } else { 0x00 };
+        out.extend_from_slice(&[b'F', b'L', b'V', 1, flags, 0, 0, 0, 9]);
+        // PreviousTagSize0
+        out.extend_from_slice(&[0, 0, 0, 0]);
+    }
+}
+
+/// One tag: `prefix` is the codec's part of the body, before `data`
+fn write_tag(tag_type: u8, pts: u64, prefix: &[u8], data: &[u8], out: &mut Vec<u8>) {
+    let size = prefix.len() + data.len();
+    let ms = (pts / 90) as u32;
+    out.push(tag_type);
+    out.extend_from_slice(&(size as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&ms.to_be_bytes()[1..]);
+    out.push((ms >> 24) as u8);
+    // Stream ID
+    out.extend_from_slice(&[0, 0, 0]);
+    out.extend_from_slice(prefix);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&((TAG_HEADER + size) as u32).to_be_bytes());
+}
@@ -0,0 +1,428 @@
+//! H.264 bitstream helpers
+
+/// Reads Exp-Golomb coded fields from an RBSP
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some((acc << 1) | self.bit()?))
+    }
+
+    /// ue(v)
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    /// se(v)
+    fn se(&mut self) -> Option<i32> {
+        let k = self.ue()?;
+        Some(if k % 2 == 1 { k.div_ceil(2) as i32 } else { -((k / 2) as i32) })
+    }
+}
+
+/// Remove emulation prevention bytes (`00 00 03` -> `00 00`)
+fn rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+/// What a sequence parameter set says about the stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0..5 flags, as in the byte after profile_idc
+    pub constraints: u8,
+    pub level_idc: u8,
+    /// Display size, after cropping
+    pub width: u32,
+    pub height: u32,
+    /// Frame rate from the VUI timing info, when the camera includes it
+    pub fps: Option<f64>,
+}
+
+/// Display size of a sequence parameter set NAL unit (header byte included),
+/// after cropping
+pub fn sps_resolution(sps: &[u8]) -> Option<(u32, u32)> {
+    parse_sps(sps).map(|sps| (sps.width, sps.height))
+}
+
+/// Parse a sequence parameter set NAL unit (header byte included)
+pub fn parse_sps(sps: &[u8]) -> Option<Sps> {
+    if sps.first()? & 0x1F != 7 {
+        return None;
+    }
+    let data = rbsp(&sps[1..]);
+    let mut r = BitReader::new(&data);
+    let profile_idc = r.bits(8)?;
+    let constraints = r.bits(8)?;
+    let level_idc = r.bits(8)?;
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = r.bit()? == 1;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            // seq_scaling_matrix_present_flag
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_mbs = r.ue()? + 1;
+    let height_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+
+    let mut width = width_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_map_units * 16;
+    if r.bit()? == 1 {
+        // frame_cropping_flag
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (crop_x, crop_y) = match (chroma_format_idc, separate_colour_plane) {
+            (1, false) => (2, 2 * (2 - frame_mbs_only)),
+            (2, false) => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        width = width.checked_sub(crop_x * (left + right))?;
+        height = height.checked_sub(crop_y * (top + bottom))?;
+    }
+    Some(Sps {
+        profile_idc: profile_idc as u8,
+        constraints: constraints as u8,
+        level_idc: level_idc as u8,
+        width,
+        height,
+        // A missing or truncated VUI still leaves the size known
+        fps: vui_frame_rate(&mut r),
+    })
+}
+
+/// Frame rate from the VUI's timing info, read up to it
+fn vui_frame_rate(r: &mut BitReader) -> Option<f64> {
+    if r.bit()? == 0 {
+        // vui_parameters_present_flag
+        return None;
+    }
+    if r.bit()? == 1 && r.bits(8)? == 255 {
+        // aspect_ratio_info_present_flag, Extended_SAR
+        r.bits(32)?; // sar_width, sar_height
+    }
+    if r.bit()? == 1 {
+        r.bit()?; // overscan_appropriate_flag
+    }
+    if r.bit()? == 1 {
+        // video_signal_type_present_flag
+        r.bits(4)?; // video_format, video_full_range_flag
+        if r.bit()? == 1 {
+            r.bits(24)?; // colour_primaries, transfer_characteristics, matrix_coefficients
+        }
+    }
+    if r.bit()? == 1 {
+        r.ue()?; // chroma_sample_loc_type_top_field
+        r.ue()?; // chroma_sample_loc_type_bottom_field
+    }
+    if r.bit()? == 0 {
+        // timing_info_present_flag
+        return None;
+    }
+    let num_units_in_tick = r.bits(32)?;
+    let time_scale = r.bits(32)?;
+    // One frame is two ticks (a field each)
+    (num_units_in_tick > 0 && time_scale > 0).then(|| time_scale as f64 / (2.0 * num_units_in_tick as f64))
+}
+
+/// Profile name for a `profile_idc` and its constraint flags, as used in
+/// stream reports
+pub fn profile_name(profile_idc: u8, constraints: u8) -> Option<&'static str> {
+    Some(match profile_idc {
+        66 if constraints & 0x40 != 0 => "constrained-baseline",
+        66 => "baseline",
+        77 => "main",
+        88 => "extended",
+        100 => "high",
+        110 => "high-10",
+        122 => "high-422",
+        244 => "high-444",
+        _ => return None,
+    })
+}
+
+/// Level as written in the spec ("3.1"), from `level_idc`
+pub fn level_name(profile_idc: u8, constraints: u8, level_idc: u8) -> String {
+    // Level 1b is level_idc 11 with constraint_set3 in Baseline and Main, 9 elsewhere
+    if level_idc == 9 || (level_idc == 11 && matches!(profile_idc, 66 | 77 | 88) && constraints & 0x10 != 0) {
+        return "1b".to_string();
+    }
+    format!("{}.{}", level_idc / 10, level_idc % 10)
+}
+
+
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last, mut next) = (8i32, 8i32);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + r.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+/// Whole NAL units in an RTP payload: a single NAL unit or a STAP-A's.
+/// Fragments are not reassembled.
+pub fn rtp_nal_units(payload: &[u8]) -> Vec<&[u8]> {
+    match payload.first().map(|header| header & 0x1F) {
+        Some(1..=23) => vec![payload],
+        Some(24) => {
+            let mut nals = Vec::new();
+            let mut rest = &payload[1..];
+            while rest.len() >= 2 {
+                let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                let Some(nal) = rest.get(2..2 + size) else {
+                    break;
+                };
+                if !nal.is_empty() {
+                    nals.push(nal);
+                }
+                rest = &rest[2 + size..];
+            }
+            nals
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// One picture: its RTP timestamp and NAL units (header byte included)
+#[derive(Debug)]
+pub struct AccessUnit {
+    pub timestamp: u32,
+    pub nals: Vec<Vec<u8>>,
+}
+
+impl AccessUnit {
+    /// Whether the picture is an IDR, which decoding can start from
+    pub fn is_keyframe(&self) -> bool {
+        self.nals.iter().any(|nal| nal[0] & 0x1F == 5)
+    }
+}
+
+/// Reassembles RFC 6184 RTP (single NAL unit, STAP-A and FU-A packets) into
+/// access units. A fragmented NAL unit that lost a packet is dropped.
+#[derive(Debug, Default)]
+pub struct Depacketizer {
+    timestamp: Option<u32>,
+    last_seq: Option<u16>,
+    nals: Vec<Vec<u8>>,
+    /// FU-A NAL unit being reassembled
+    fragment: Option<Vec<u8>>,
+}
+
+impl Depacketizer {
+    /// Access units completed by one RTP packet: the previous one when the
+    /// timestamp changes, the current one on the marker bit
+    pub fn push(&mut self, packet: &[u8]) -> Vec<AccessUnit> {
+        let mut done = Vec::new();
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return done;
+        };
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        if self.last_seq.replace(seq).is_some_and(|last| seq != last.wrapping_add(1)) {
+            self.fragment = None;
+        }
+        if self.timestamp.is_some_and(|t| t != timestamp) {
+            done.extend(self.finish());
+        }
+        self.timestamp = Some(timestamp);
+
+        let Some(&header) = payload.first() else {
+            return done;
+        };
+        match header & 0x1F {
+            1..=23 => self.nals.push(payload.to_vec()),
+            24 => {
+                let mut rest = &payload[1..];
+                while rest.len() >= 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let Some(nal) = rest.get(2..2 + size) else {
+                        break;
+                    };
+                    if !nal.is_empty() {
+                        self.nals.push(nal.to_vec());
+                    }
+                    rest = &rest[2 + size..];
+                }
+            }
+            28 if payload.len() >= 2 => {
+                let fu = payload[1];
+                if fu & 0x80 != 0 {
+                    let mut nal = vec![(header & 0xE0) | (fu & 0x1F)];
+                    nal.extend_from_slice(&payload[2..]);
+                    self.fragment = Some(nal);
+                } else if let Some(nal) = &mut self.fragment {
+                    nal.extend_from_slice(&payload[2..]);
+                }
+                if fu & 0x40 != 0
+                    && let Some(nal) = self.fragment.take()
+                {
+                    self.nals.push(nal);
+                }
+            }
+            _ => {}
+        }
+        if packet[1] & 0x80 != 0 {
+            done.extend(self.finish());
+        }
+        done
+    }
+
+    /// The access unit in progress, if any
+    pub fn finish(&mut self) -> Option<AccessUnit> {
+        self.fragment = None;
+        let nals = std::mem::take(&mut self.nals);
+        Some(AccessUnit { timestamp: self.timestamp?, nals }).filter(|unit| !unit.nals.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Baseline 3.1, 1280x720, no cropping or VUI
+    const SPS_720P: &[u8] = &[0x67, 0x42, 0xC0, 0x1F, 0xDA, 0x01, 0x40, 0x16, 0xE4];
+    /// High 4.0, 1920x1088 cropped to 1080, VUI timing at 25 fps; has an
+    /// emulation prevention byte
+    const SPS_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xC0, 0x5A, 0x80, 0x80, 0x80, 0xA0, 0x00, 0x00,
+        0x03, 0x00, 0x20, 0x00, 0x00, 0x06, 0x58,
+    ];
+    /// Main 3.0, 720x576 in fields, pic_order_cnt_type 1 with two reference offsets
+    const SPS_576I: &[u8] = &[0x67, 0x4D, 0x40, 0x1E, 0xD1, 0x91, 0x98, 0x4A, 0x02, 0xD0, 0x93, 0x20];
+    /// The replay server's `sprop-parameter-sets` SPS
+    const SPS_REPLAY: &[u8] = &[0x67, 0x42, 0x00, 0x1F, 0x95, 0xA8, 0x14, 0x01, 0x6E, 0x40];
+
+    fn sps(profile_idc: u8, constraints: u8, level_idc: u8, width: u32, height: u32, fps: Option<f64>) -> Sps {
+        Sps { profile_idc, constraints, level_idc, width, height, fps }
+    }
+
+    #[test]
+    fn exp_golomb_codes() {
+        // ue(v) 0, 1, 2, 3, 6, 7: 1 010 011 00100 00111 0001000
+        let mut r = BitReader::new(&[0xA6, 0x43, 0x88]);
+        let ue: Vec<_> = (0..6).map(|_| r.ue()).collect();
+        assert_eq!(ue, [Some(0), Some(1), Some(2), Some(3), Some(6), Some(7)]);
+        assert_eq!(r.ue(), None);
+
+        // se(v) 1, -1, 2, -2: 010 011 00100 00101
+        let mut r = BitReader::new(&[0x4C, 0x85]);
+        let se: Vec<_> = (0..4).map(|_| r.se()).collect();
+        assert_eq!(se, [Some(1), Some(-1), Some(2), Some(-2)]);
+
+        // More than 31 leading zeros can't be a 32-bit code
+        assert_eq!(BitReader::new(&[0, 0, 0, 0, 0x80]).ue(), None);
+        assert_eq!(BitReader::new(&[0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFE]).ue(), Some(u32::MAX - 1));
+    }
+
+    #[test]
+    fn emulation_prevention_is_removed() {
+        assert_eq!(rbsp(&[0x00, 0x00, 0x03, 0x01]), [0x00, 0x00, 0x01]);
+        assert_eq!(rbsp(&[0x00, 0x00, 0x03, 0x00, 0x00, 0x03]), [0x00; 4]);
+        assert_eq!(rbsp(&[0x00, 0x03, 0x00, 0x00, 0x02]), [0x00, 0x03, 0x00, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn sps_fields() {
+        assert_eq!(parse_sps(SPS_720P), Some(sps(66, 0xC0, 31, 1280, 720, None)));
+        assert_eq!(parse_sps(SPS_1080P), Some(sps(100, 0x00, 40, 1920, 1080, Some(25.0))));
+        assert_eq!(parse_sps(SPS_576I), Some(sps(77, 0x40, 30, 720, 576, None)));
+        assert_eq!(parse_sps(SPS_REPLAY), Some(sps(66, 0x00, 31, 1280, 720, None)));
+        assert_eq!(sps_resolution(SPS_1080P), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn broken_sps_is_refused() {
+        // A PPS, and an SPS cut off before its size
+        assert_eq!(parse_sps(&[0x68, 0xCE, 0x3C, 0x80]), None);
+        assert_eq!(parse_sps(&SPS_720P[..5]), None);
+        assert_eq!(parse_sps(&[]), None);
+        // Cut off in the VUI, the size is still known
+        let truncated = parse_sps(&SPS_1080P[..14]).unwrap();
+        assert_eq!((truncated.width, truncated.height, truncated.fps), (1920, 1080, None));
+    }
+
+    #[test]
+    fn profile_and_level_names() {
+        assert_eq!(profile_name(66, 0xC0), Some("constrained-baseline"));
+        assert_eq!(profile_name(66, 0x00), Some("baseline"));
+        assert_eq!(profile_name(100, 0x00), Some("high"));
+        assert_eq!(profile_name(83, 0x00), None);
+        assert_eq!(level_name(100, 0x00, 31), "3.1");
+        assert_eq!(level_name(66, 0x10, 11), "1b");
+        assert_eq!(level_name(100, 0x10, 11), "1.1");
+        assert_eq!(level_name(100, 0x00, 9), "1b");
+    }
+}
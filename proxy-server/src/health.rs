@@ -0,0 +1,224 @@
+//! Stream health: once a second each session's camera media and the
+//! browser's reception reports are folded into a score from 0 to 100. A
+//! score that stays under one of `[health]`'s thresholds triggers its
+//! action, so a stream that gets bad is alerted on, transcoded or
+//! reconnected instead of staying bad.
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+use crate::config::HealthConfig;
+use crate::feedback::Feedback;
+use crate::receive::ChannelReport;
+
+/// Points lost per unit of the camera's worst loss fraction, up to [`MAX_CAMERA_LOSS`]
+const CAMERA_LOSS_WEIGHT: f64 = 250.0;
+const MAX_CAMERA_LOSS: f64 = 50.0;
+/// Jitter the score tolerates, and points lost per millisecond beyond it
+const JITTER_ALLOWANCE_MS: f64 = 30.0;
+const JITTER_WEIGHT: f64 = 0.2;
+const MAX_JITTER: f64 = 20.0;
+/// Points lost per unit of the loss fraction the browser reports
+const CLIENT_LOSS_WEIGHT: f64 = 200.0;
+const MAX_CLIENT_LOSS: f64 = 40.0;
+/// Largest webhook response read
+const MAX_RESPONSE: u64 = 4096;
+
+/// What a low score triggers, mildest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthAction {
+    /// Tell the operator's webhook
+    Alert,
+    /// Restart the session with the camera's fallback transcode rule
+    Transcode,
+    /// Restart the session on a new connection to the camera
+    Reconnect,
+}
+
+impl HealthAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthAction::Alert => "alert",
+            HealthAction::Transcode => "transcode",
+            HealthAction::Reconnect => "reconnect",
+        }
+    }
+}
+
+/// What a score was made of
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct HealthInputs {
+    pub stalled: bool,
+    /// Worst recent loss fraction of the camera's RTP channels
+    pub camera_loss_fraction: f32,
+    /// Worst interarrival jitter of the camera's RTP channels
+    pub jitter_ms: f64,
+    /// Latest loss fraction the browser reported, if it reports
+    pub client_loss_fraction: Option<f32>,
+}
+
+impl HealthInputs {
+    /// 100 for clean media; 0 for a stalled camera
+    pub fn score(&self) -> u8 {
+        if self.stalled {
+            return 0;
+        }
+        let penalty = (self.camera_loss_fraction as f64 * CAMERA_LOSS_WEIGHT).min(MAX_CAMERA_LOSS)
+            + ((self.jitter_ms - JITTER_ALLOWANCE_MS).max(0.0) * JITTER_WEIGHT).min(MAX_JITTER)
+            + (self.client_loss_fraction.unwrap_or(0.0) as f64 * CLIENT_LOSS_WEIGHT).min(MAX_CLIENT_LOSS);
+        (100.0 - penalty).clamp(0.0, 100.0).round() as u8
+    }
+}
+
+/// One action's threshold and how long the score has been under it
+#[derive(Debug)]
+struct Threshold {
+    action: HealthAction,
+    below: u8,
+    since: Option<Instant>,
+    /// Taken during this spell under the threshold
+    taken: bool,
+}
+
+/// Scores one session and decides when an action is due
+#[derive(Debug)]
+pub struct HealthMonitor {
+    hold: Duration,
+    /// Most severe first
+    thresholds: Vec<Threshold>,
+    client_loss_fraction: Option<f32>,
+    inputs: HealthInputs,
+}
+
+impl HealthMonitor {
+    /// `restart` says whether the session can be set up again, and
+    /// `transcode` whether a fallback is available to it
+    pub fn new(config: &HealthConfig, restart: bool, transcode: bool) -> Self {
+        let thresholds = [
+            (HealthAction::Reconnect, config.reconnect_below.filter(|_| restart)),
+            (HealthAction::Transcode, config.transcode_below.filter(|_| restart && transcode)),
+            (HealthAction::Alert, config.alert_below),
+        ]
+        .into_iter()
+        .filter_map(|(action, below)| Some(Threshold { action, below: below?, since: None, taken: false }))
+        .collect();
+        Self { hold: config.hold(), thresholds, client_loss_fraction: None, inputs: HealthInputs::default() }
+    }
+
+    pub fn on_feedback(&mut self, feedback: &Feedback) {
+        self.client_loss_fraction = Some(feedback.loss_fraction);
+    }
+
+    /// Score the session now; the most severe action whose threshold the
+    /// score has been under for the hold time, once per spell under it
+    pub fn check(&mut self, stalled: bool, channels: &[ChannelReport], now: Instant) -> Option<HealthAction> {
+        self.inputs = HealthInputs {
+            stalled,
+            camera_loss_fraction: channels.iter().map(|c| c.loss_fraction).fold(0.0, f32::max),
+            jitter_ms: channels.iter().map(|c| c.jitter_ms).fold(0.0, f64::max),
+            client_loss_fraction: self.client_loss_fraction,
+        };
+        let score = self.inputs.score();
+        let mut due = None;
+        for threshold in &mut self.thresholds {
+            if score >= threshold.below {
+                threshold.since = None;
+                threshold.taken = false;
+                continue;
+            }
+            let since = *threshold.since.get_or_insert(now);
+            if !threshold.taken && now.duration_since(since) >= self.hold {
+                threshold.taken = true;
+                due = due.or(Some(threshold.action));
+            }
+        }
+        due
+    }
+
+    pub fn score(&self) -> u8 {
+        self.inputs.score()
+    }
+
+    pub fn inputs(&self) -> HealthInputs {
+        self.inputs
+    }
+}
+
+/// Body POSTed to the webhook
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    pub session_id: String,
+    pub rtsp_url: String,
+    pub score: u8,
+    pub action: &'static str,
+    #[serde(flatten)]
+    pub inputs: HealthInputs,
+}
+
+/// POST an alert to the webhook, logging failures
+pub async fn notify(config: &HealthConfig, alert: &Alert) {
+    let Some(webhook) = &config.webhook else {
+        return;
+    };
+    let timeout = Duration::from_millis(config.webhook_timeout_ms);
+    let outcome = match tokio::time::timeout(timeout, post(webhook, alert)).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(anyhow::anyhow!("No answer within {:?}", timeout)),
+    };
+    if let Err(e) = outcome {
+        warn!("Health webhook failed: {:#}", e);
+    }
+}
+
+async fn post(webhook: &str, alert: &Alert) -> Result<()> {
+    let url = url::Url::parse(webhook).context("Invalid webhook URL")?;
+    if url.scheme() != "http" {
+        bail!("Webhook URL must be http://, not {}", url.scheme());
+    }
+    let host = url.host_str().context("Webhook URL has no host")?;
+    let port = url.port().unwrap_or(80);
+    let body = serde_json::to_vec(alert)?;
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rtsp2browser\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        &url[url::Position::BeforePath..],
+        host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => bail!("Webhook answered {}", status_line),
+    }
+}
+
+/// Cameras that fell back to transcoding, until when
+fn fallbacks() -> &'static Mutex<HashMap<String, Instant>> {
+    static FALLBACKS: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+    FALLBACKS.get_or_init(Default::default)
+}
+
+/// Transcode sessions for the camera for a while
+pub fn fall_back(rtsp_url: &str, duration: Duration) {
+    fallbacks().lock().unwrap().insert(rtsp_url.to_string(), Instant::now() + duration);
+}
+
+/// Whether new sessions for the camera should use its fallback transcode rule
+pub fn falling_back(rtsp_url: &str) -> bool {
+    let mut fallbacks = fallbacks().lock().unwrap();
+    let now = Instant::now();
+    fallbacks.retain(|_, until| *until > now);
+    fallbacks.contains_key(rtsp_url)
+}
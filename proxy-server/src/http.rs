@@ -0,0 +1,266 @@
+//! Plain HTTP requests on the WebSocket port: the `/probe`, `/export`,
+//! `/drain` and `/reload` endpoints and the demo player files
+//! (`--serve-demo`). `[wss]` answers only peers' `/cluster/pending`.
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(feature = "websocket")]
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+use crate::config::Config;
+
+/// Largest request head accepted
+const MAX_HEAD: usize = 8192;
+/// How long to wait for a complete request head when classifying a connection
+#[cfg(feature = "websocket")]
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a new connection is a plain HTTP request rather than a WebSocket
+/// handshake. Only peeks, so the handshake can still be read afterwards.
+#[cfg(feature = "websocket")]
+pub async fn is_plain_http(stream: &TcpStream) -> Result<bool> {
+    let mut buf = vec![0u8; MAX_HEAD];
+    let head = tokio::time::timeout(HEAD_TIMEOUT, async {
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            if n == 0 {
+                bail!("Connection closed before the request");
+            }
+            if let Some(end) = buf[..n].windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(end);
+            }
+            if n == buf.len() {
+                bail!("Request head too long");
+            }
+            // More of the head is on its way
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    let request = Request::parse(&String::from_utf8_lossy(&buf[..head]));
+    Ok(!request.is_websocket_handshake())
+}
+
+/// Request line and headers of a plain HTTP request
+pub struct Request {
+    pub method: String,
+    /// Path and query, e.g. `/probe?rtsp=...`
+    pub target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Self {
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or("/").to_string();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Self { method, target, headers }
+    }
+
+    async fn read(stream: &mut TcpStream) -> Result<Self> {
+        Ok(Self::read_raw(stream).await?.0)
+    }
+
+    /// Read a request head, returning it with every byte read, which may run
+    /// past the head
+    pub async fn read_raw<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Self, Vec<u8>)> {
+        let mut buf = vec![0u8; MAX_HEAD];
+        let mut len = 0;
+        loop {
+            if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+                let request = Self::parse(&String::from_utf8_lossy(&buf[..end]));
+                buf.truncate(len);
+                return Ok((request, buf));
+            }
+            if len == buf.len() {
+                bail!("Request head too long");
+            }
+            let n = stream.read(&mut buf[len..]).await?;
+            if n == 0 {
+                bail!("Connection closed mid-request");
+            }
+            len += n;
+        }
+    }
+
+    /// Whether this is a WebSocket handshake rather than a plain request
+    #[cfg(feature = "websocket")]
+    pub fn is_websocket_handshake(&self) -> bool {
+        self.header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Path without the query
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("/")
+    }
+
+    pub fn query(&self) -> HashMap<String, String> {
+        match url::Url::parse(&format!("http://localhost{}", self.target)) {
+            Ok(url) => url.query_pairs().into_owned().collect(),
+            Err(_) => HashMap::new(),
+        }
+    }
+}
+
+/// Token a request carries, from `token` in the query or an
+/// `Authorization: Bearer` header
+pub fn bearer_token(request: &Request) -> Option<String> {
+    request
+        .query()
+        .remove("token")
+        .or_else(|| request.header("Authorization").and_then(bearer).map(str::to_string))
+}
+
+/// The token of an `Authorization` header value, if it is a bearer token
+pub fn bearer(authorization: &str) -> Option<&str> {
+    authorization.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Whether `token` is `expected`, in a time that does not depend on how
+/// much of it matches
+pub fn token_matches(token: Option<&str>, expected: &str) -> bool {
+    token.is_some_and(|token| {
+        token.len() == expected.len() && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    })
+}
+
+/// Refusal for a request without the `[admin]` token. The admin endpoints
+/// are not found at all when no token is configured.
+pub fn require_admin(request: &Request, config: &Config) -> Result<(), Response> {
+    let Some(admin) = &config.admin else {
+        return Err(Response::text("404 Not Found", "Not found"));
+    };
+    if !token_matches(bearer_token(request).as_deref(), &admin.token) {
+        warn!("Refusing {} {} without the admin token", request.method, request.path());
+        return Err(Response::json("401 Unauthorized", &serde_json::json!({ "error": "Missing or wrong admin token" })));
+    }
+    Ok(())
+}
+
+/// Response to a plain HTTP request
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self { status, content_type, body: body.into() }
+    }
+
+    pub fn text(status: &'static str, body: &str) -> Self {
+        Self::new(status, "text/plain", format!("{}\n", body))
+    }
+
+    pub fn json(status: &'static str, body: &serde_json::Value) -> Self {
+        Self::new(status, "application/json", body.to_string())
+    }
+}
+
+/// Only draining, faults and the config are changed through HTTP
+const CHANGES: &[(&str, &[&str])] = &[("/drain", &["POST", "DELETE"]), ("/chaos", &["POST"]), ("/reload", &["POST"])];
+
+/// Methods `path` answers: GET and HEAD, and any in [`CHANGES`]
+fn allowed_methods(path: &str) -> Vec<&'static str> {
+    let changes = CHANGES.iter().filter(|(changed, _)| *changed == path).flat_map(|(_, methods)| methods.iter().copied());
+    ["GET", "HEAD"].into_iter().chain(changes).collect()
+}
+
+/// Answer one plain HTTP request, then close the connection
+pub async fn handle(mut stream: TcpStream, config: &Config, demo_dir: Option<&Path>) -> Result<()> {
+    let request = Request::read(&mut stream).await?;
+    let with_body = request.method != "HEAD";
+
+    // Pages from other origins may only use these endpoints if allowed
+    let origin = request.header("Origin");
+    if !config.origin_allowed(origin) {
+        warn!("Refusing HTTP request from origin {:?}", origin);
+        return respond(&mut stream, Response::text("403 Forbidden", "Origin not allowed"), "", with_body).await;
+    }
+    let cors = match origin {
+        Some(origin) => format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin),
+        None => String::new(),
+    };
+    let methods = allowed_methods(request.path());
+    if request.method == "OPTIONS" {
+        let preflight = format!(
+            "{}Access-Control-Allow-Methods: {}\r\nAccess-Control-Allow-Headers: Authorization\r\n",
+            cors,
+            methods.join(", ")
+        );
+        return respond(&mut stream, Response::new("204 No Content", "text/plain", ""), &preflight, false).await;
+    }
+    if !methods.contains(&request.method.as_str()) {
+        return respond(&mut stream, Response::text("405 Method Not Allowed", "Method not allowed"), &cors, true).await;
+    }
+
+    let response = match (request.path(), demo_dir) {
+        ("/probe", _) => crate::probe::handle(&request, config).await,
+        ("/export", _) => crate::export::handle(&request, config),
+        ("/drain", _) => crate::drain::handle(&request, config),
+        ("/metrics" | "/sessions", _) => crate::metrics::handle(&request, config),
+        ("/reload", _) => reload(&request, config).await,
+        #[cfg(feature = "webtransport")]
+        ("/cert-hash", _) => crate::selfsigned::handle(),
+        #[cfg(feature = "chaos")]
+        ("/chaos", _) => crate::chaos::handle(&request, config),
+        (_, Some(root)) => crate::demo::file(root, &request).await,
+        _ => Response::text("404 Not Found", "Not found"),
+    };
+    respond(&mut stream, response, &cors, with_body).await
+}
+
+/// Answer a plain request on the `[wss]` port, where peers look up sessions;
+/// the other endpoints stay on the plain port
+#[cfg(feature = "websocket")]
+pub async fn handle_tls<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &Request, config: &Config) -> Result<()> {
+    let response = match (request.method.as_str(), request.path()) {
+        ("GET", "/cluster/pending") => crate::cluster::handle(request, config),
+        _ => Response::text("404 Not Found", "Not found"),
+    };
+    respond(&mut stream, response, "", true).await
+}
+
+/// `POST /reload`: re-read the config file as on SIGHUP; needs the admin token
+async fn reload(request: &Request, config: &Config) -> Response {
+    if request.method != "POST" {
+        return Response::text("405 Method Not Allowed", "Method not allowed");
+    }
+    if let Err(refused) = require_admin(request, config) {
+        return refused;
+    }
+    match crate::request_reload().await {
+        Ok(()) => Response::json("200 OK", &serde_json::json!({ "reloaded": true })),
+        Err(e) => Response::json("409 Conflict", &serde_json::json!({ "reloaded": false, "error": format!("{:#}", e) })),
+    }
+}
+
+/// `extra_headers` are complete header lines, each ending in CRLF
+async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, response: Response, extra_headers: &str, with_body: bool) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        extra_headers
+    );
+    stream.write_all(head.as_bytes()).await?;
+    if with_body {
+        stream.write_all(&response.body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::config::JournalConfig;
+
+/// One line of the journal (JSON Lines, short keys to keep it compact)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "ev", rename_all = "lowercase")]
+enum Record {
+    /// Proxy process started; sessions still open at this point were cut short
+    Boot { t: u64, pid: u32 },
+    Start {
+        t: u64,
+        id: String,
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+    Stop {
+        t: u64,
+        id: String,
+        bytes: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// Append-only record of session starts and stops, kept on disk so operators
+/// can see what was running when the proxy crashed
+pub struct Journal {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Mutex<File>,
+}
+
+static JOURNAL: OnceLock<Journal> = OnceLock::new();
+
+/// The process journal, if one is configured
+pub fn global() -> Option<&'static Journal> {
+    JOURNAL.get()
+}
+
+/// Open the configured journal and record this process start
+pub fn init(config: &JournalConfig) -> Result<()> {
+    let journal = Journal {
+        path: config.path.clone(),
+        max_bytes: config.max_bytes,
+        file: Mutex::new(open(&config.path)?),
+    };
+    journal.append(&Record::Boot { t: now_ms(), pid: std::process::id() });
+    let _ = JOURNAL.set(journal);
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open journal {}", path.display()))
+}
+
+/// Previous generation of the journal: `<path>.1`
+fn rotated(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl Journal {
+    pub fn session_start(&self, id: &str, url: &str, tenant: Option<&str>) {
+        self.append(&Record::Start {
+            t: now_ms(),
+            id: id.to_string(),
+            url: url.to_string(),
+            tenant: tenant.map(str::to_string),
+        });
+    }
+
+    pub fn session_stop(&self, id: &str, bytes: u64, error: Option<String>) {
+        self.append(&Record::Stop { t: now_ms(), id: id.to_string(), bytes, error });
+    }
+
+    /// Write one line; journal failures are logged, never fatal to the session
+    fn append(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).expect("journal records serialize");
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = self.rotate_if_full(&mut file) {
+            warn!("Journal rotation failed: {:#}", e);
+        }
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write journal {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Keep one previous generation as `<path>.1` once the journal reaches its size limit
+    fn rotate_if_full(&self, file: &mut File) -> Result<()> {
+        if file.metadata()?.len() < self.max_bytes {
+            return Ok(());
+        }
+        std::fs::rename(&self.path, rotated(&self.path))?;
+        *file = open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// A session reconstructed from the journal, as printed by `--dump-journal`
+#[derive(Debug, Serialize)]
+struct SessionEntry {
+    id: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    started_ms: u64,
+    stopped_ms: Option<u64>,
+    bytes: Option<u64>,
+    error: Option<String>,
+    /// "ended", "interrupted" (the proxy restarted first) or "open" (no stop
+    /// record yet; still running, or cut short by the latest crash)
+    state: &'static str,
+}
+
+/// Print the journal (and its rotated generation) as a JSON array of sessions
+pub fn dump(path: &Path) -> Result<()> {
+    let mut sessions: Vec<SessionEntry> = Vec::new();
+    for file in [rotated(path), path.to_path_buf()] {
+        let Ok(file) = File::open(&file) else {
+            continue;
+        };
+        for line in BufReader::new(file).lines() {
+            // A crash can leave a torn last line; skip anything unparseable
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                continue;
+            };
+            match record {
+                Record::Boot { .. } => {
+                    for session in sessions.iter_mut().filter(|s| s.state == "open") {
+                        session.state = "interrupted";
+                    }
+                }
+                Record::Start { t, id, url, tenant } => sessions.push(SessionEntry {
+                    id,
+                    url,
+                    tenant,
+                    started_ms: t,
+                    stopped_ms: None,
+                    bytes: None,
+                    error: None,
+                    state: "open",
+                }),
+                Record::Stop { t, id, bytes, error } => {
+                    if let Some(session) = sessions.iter_mut().rev().find(|s| s.id == id && s.state == "open") {
+                        session.stopped_ms = Some(t);
+                        session.bytes = Some(bytes);
+                        session.error = error;
+                        session.state = "ended";
+                    }
+                }
+            }
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&sessions)?);
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Keyframe cadence: a viewer joining mid-GOP sees nothing until the next
+//! keyframe, so cameras with long GOPs are asked for keyframes more often.
+//! The video track is watched, and when a keyframe is overdue the camera is
+//! sent an RTCP Full Intra Request (RFC 5104) over its RTCP port or channel.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// RTCP payload-specific feedback (RFC 4585)
+const RTCP_PSFB: u8 = 206;
+/// Feedback message type of a FIR
+const FMT_FIR: u8 = 4;
+
+/// A keyframe the camera should send
+#[derive(Debug, Clone, Copy)]
+pub struct KeyframeRequest {
+    /// RTP channel of the track
+    pub channel_id: u8,
+    /// The camera's SSRC for the track
+    pub media_ssrc: u32,
+}
+
+/// Watches a video track's RTP and asks for a keyframe each time the
+/// interval passes without one
+pub struct KeyframeWatch {
+    channel_id: u8,
+    interval: Duration,
+    /// Last keyframe, or last request while none came
+    last: Instant,
+    requests: mpsc::Sender<KeyframeRequest>,
+}
+
+impl KeyframeWatch {
+    pub fn new(channel_id: u8, interval: Duration, requests: mpsc::Sender<KeyframeRequest>) -> Self {
+        Self { channel_id, interval, last: Instant::now(), requests }
+    }
+
+    pub fn on_rtp(&mut self, packet: &[u8]) {
+        let now = Instant::now();
+        if crate::rtp::is_keyframe_start(packet) {
+            self.last = now;
+            return;
+        }
+        if now.duration_since(self.last) < self.interval {
+            return;
+        }
+        let Some(ssrc) = packet.get(8..12) else {
+            return;
+        };
+        // The next request waits another interval, so a camera that ignores
+        // them is not flooded
+        self.last = now;
+        let media_ssrc = u32::from_be_bytes([ssrc[0], ssrc[1], ssrc[2], ssrc[3]]);
+        let _ = self.requests.try_send(KeyframeRequest { channel_id: self.channel_id, media_ssrc });
+    }
+}
+
+/// Where a track's RTCP to the camera goes
+pub enum FirRoute {
+    /// The camera's RTCP port
+    Udp(Arc<UdpSocket>, SocketAddr),
+    /// The camera's RTCP channel on the RTSP connection
+    Interleaved(u8),
+}
+
+/// A session's FIR routes and sequence numbers, by track
+pub struct FirSender {
+    /// SSRC the proxy sends its feedback as
+    ssrc: u32,
+    routes: HashMap<u8, (FirRoute, u8)>,
+}
+
+impl Default for FirSender {
+    fn default() -> Self {
+        Self { ssrc: rand::random(), routes: HashMap::new() }
+    }
+}
+
+impl FirSender {
+    pub fn add(&mut self, channel_id: u8, route: FirRoute) {
+        self.routes.insert(channel_id, (route, 0));
+    }
+
+    /// The FIR for a request and where it goes; interleaved FIRs come
+    /// framed for the RTSP connection
+    pub fn request(&mut self, request: KeyframeRequest) -> Option<(&FirRoute, Vec<u8>)> {
+        let (route, sequence) = self.routes.get_mut(&request.channel_id)?;
+        // A new sequence number asks for a new keyframe; a repeat is a retransmission
+        *sequence = sequence.wrapping_add(1);
+        let packet = fir(self.ssrc, request.media_ssrc, *sequence);
+        let packet = match route {
+            FirRoute::Udp(..) => packet,
+            FirRoute::Interleaved(channel) => {
+                let mut framed = vec![b'$', *channel];
+                framed.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                framed.extend_from_slice(&packet);
+                framed
+            }
+        };
+        Some((&*route, packet))
+    }
+}
+
+/// RTCP FIR from `sender_ssrc` asking `media_ssrc` for a keyframe
+fn fir(sender_ssrc: u32, media_ssrc: u32, sequence: u8) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(20);
+    packet.push(0x80 | FMT_FIR);
+    packet.push(RTCP_PSFB);
+    // Length in 32-bit words minus one
+    packet.extend_from_slice(&4u16.to_be_bytes());
+    packet.extend_from_slice(&sender_ssrc.to_be_bytes());
+    // Media source SSRC is unused in a FIR; the FCI names the stream
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&media_ssrc.to_be_bytes());
+    packet.extend_from_slice(&[sequence, 0, 0, 0]);
+    packet
+}
@@ -0,0 +1,69 @@
+//! Latency modes (`latency`): one per-session choice that sets how media
+//! reaches the browser, instead of tuning delivery, retransmission and flow
+//! control one by one.
+
+use std::time::Duration;
+use crate::config::FlowControlConfig;
+
+/// Playout delay `balanced` asks the browser for: time to put packets back
+/// in order and for resent ones to arrive
+const BALANCED_JITTER: Duration = Duration::from_millis(150);
+/// How far behind live `ultra-low` lets a reliable path fall
+const ULTRA_LOW_MAX_LAG_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyMode {
+    /// Datagrams, played as they arrive; losses show as glitches
+    UltraLow,
+    /// Datagrams behind a small jitter buffer, with lost packets resent on NACK
+    Balanced,
+    /// Media on a stream, so nothing is lost but a slow link adds delay
+    Reliable,
+}
+
+impl LatencyMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ultra-low" => Some(LatencyMode::UltraLow),
+            "balanced" => Some(LatencyMode::Balanced),
+            "reliable" => Some(LatencyMode::Reliable),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LatencyMode::UltraLow => "ultra-low",
+            LatencyMode::Balanced => "balanced",
+            LatencyMode::Reliable => "reliable",
+        }
+    }
+
+    /// Whether WebTransport media goes on a stream rather than datagrams
+    pub fn stream_delivery(&self) -> bool {
+        *self == LatencyMode::Reliable
+    }
+
+    /// Whether lost datagrams are resent when the browser reports them
+    pub fn retransmit(&self) -> bool {
+        *self == LatencyMode::Balanced
+    }
+
+    /// How long the browser should hold datagrams before playing them
+    pub fn jitter_buffer(&self) -> Duration {
+        match self {
+            LatencyMode::Balanced => BALANCED_JITTER,
+            LatencyMode::UltraLow | LatencyMode::Reliable => Duration::ZERO,
+        }
+    }
+
+    /// Flow control for the session's reliable paths, from the configured one
+    pub fn flow_control(&self, config: &FlowControlConfig) -> FlowControlConfig {
+        match self {
+            LatencyMode::UltraLow if config.max_lag_ms == 0 || config.max_lag_ms > ULTRA_LOW_MAX_LAG_MS => {
+                FlowControlConfig { max_lag_ms: ULTRA_LOW_MAX_LAG_MS }
+            }
+            _ => config.clone(),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! Limits on one session: how long it may run, how fast it may send and how
+//! much RTSP the browser may push at the camera
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::config::SessionConfig;
+use crate::rtsp::{RtspRequest, RtspResponse};
+
+/// Consecutive checks over the bitrate limit before the session is closed,
+/// so a burst (e.g. a keyframe) does not end it
+const BITRATE_GRACE: u32 = 5;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionLimits {
+    pub max_duration: Option<Duration>,
+    /// Average rate to the browser, in kbit/s
+    pub max_bitrate_kbps: Option<u64>,
+    /// Time left in the viewing-hours window the session started in
+    pub window_left: Option<Duration>,
+}
+
+/// Why a session ran out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    MaxDuration,
+    ViewingHours,
+}
+
+impl Expiry {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Expiry::MaxDuration => "max-duration",
+            Expiry::ViewingHours => "viewing-hours",
+        }
+    }
+}
+
+impl SessionLimits {
+    /// The stricter of two sets of limits
+    pub fn min(self, other: SessionLimits) -> SessionLimits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        SessionLimits {
+            max_duration: min(self.max_duration, other.max_duration),
+            max_bitrate_kbps: min(self.max_bitrate_kbps, other.max_bitrate_kbps),
+            window_left: min(self.window_left, other.window_left),
+        }
+    }
+
+    /// What is left of the limits `elapsed` into the session, for a
+    /// carousel's next camera
+    pub fn remaining(self, elapsed: Duration) -> SessionLimits {
+        SessionLimits {
+            max_duration: self.max_duration.map(|d| d.saturating_sub(elapsed)),
+            window_left: self.window_left.map(|d| d.saturating_sub(elapsed)),
+            ..self
+        }
+    }
+
+    /// Resolves when the session runs out of time, with the limit it hit;
+    /// never without a time limit
+    pub async fn expired(&self, started: Instant) -> Expiry {
+        let deadline = match (self.max_duration, self.window_left) {
+            (Some(max), Some(left)) if left < max => Some((left, Expiry::ViewingHours)),
+            (Some(max), _) => Some((max, Expiry::MaxDuration)),
+            (None, Some(left)) => Some((left, Expiry::ViewingHours)),
+            (None, None) => None,
+        };
+        match deadline {
+            Some((after, expiry)) => {
+                tokio::time::sleep_until((started + after).into()).await;
+                expiry
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Daily window in which sessions may run, written "08:00-18:00". Windows
+/// ending before they start run past midnight.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ViewingHours {
+    /// Seconds after midnight
+    start: u64,
+    end: u64,
+}
+
+impl TryFrom<String> for ViewingHours {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        fn time_of_day(text: &str) -> Result<u64> {
+            let (h, m) = text.trim().split_once(':').ok_or_else(|| anyhow!("Expected HH:MM, got '{}'", text))?;
+            let (h, m): (u64, u64) = (h.parse()?, m.parse()?);
+            if m >= 60 || h > 24 || (h == 24 && m > 0) {
+                bail!("Invalid time of day '{}'", text);
+            }
+            Ok(h * 3600 + m * 60)
+        }
+        let (start, end) = value.split_once('-').ok_or_else(|| anyhow!("Expected HH:MM-HH:MM, got '{}'", value))?;
+        let (start, end) = (time_of_day(start)?, time_of_day(end)?);
+        if start == end {
+            bail!("Viewing hours '{}' are empty", value);
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl ViewingHours {
+    /// Time left in this window at `now` seconds after midnight, None outside it
+    fn left_at(&self, now: u64) -> Option<Duration> {
+        let left = if self.start < self.end {
+            (self.start..self.end).contains(&now).then(|| self.end - now)
+        } else if now >= self.start {
+            Some(DAY_SECS - now + self.end)
+        } else {
+            (now < self.end).then(|| self.end - now)
+        };
+        left.map(Duration::from_secs)
+    }
+
+    /// Time left in the longest of `windows` open now, at `utc_offset_minutes`
+    /// from UTC; Err when none is open
+    pub fn left_now(windows: &[ViewingHours], utc_offset_minutes: i32) -> Result<Duration> {
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let now = (unix + utc_offset_minutes as i64 * 60).rem_euclid(DAY_SECS as i64) as u64;
+        windows
+            .iter()
+            .filter_map(|window| window.left_at(now))
+            .max()
+            .ok_or_else(|| anyhow!("Outside viewing hours"))
+    }
+}
+
+/// Compares the bytes sent to the browser against `max_bitrate_kbps`
+#[derive(Debug)]
+pub struct BitrateCheck {
+    limit_kbps: Option<u64>,
+    last_bytes: u64,
+    last_at: Instant,
+    over: u32,
+}
+
+impl BitrateCheck {
+    pub fn new(limits: &SessionLimits) -> Self {
+        Self { limit_kbps: limits.max_bitrate_kbps, last_bytes: 0, last_at: Instant::now(), over: 0 }
+    }
+
+    /// Called about once a second with the session's total bytes sent; true
+    /// once the rate has been over the limit for several checks in a row
+    pub fn exceeded(&mut self, bytes_sent: u64) -> bool {
+        let Some(limit) = self.limit_kbps else {
+            return false;
+        };
+        let elapsed = self.last_at.elapsed().as_secs_f64().max(0.001);
+        let kbps = (bytes_sent.saturating_sub(self.last_bytes) * 8) as f64 / 1000.0 / elapsed;
+        self.last_bytes = bytes_sent;
+        self.last_at = Instant::now();
+        self.over = if kbps > limit as f64 { self.over + 1 } else { 0 };
+        self.over >= BITRATE_GRACE
+    }
+}
+
+/// Rate and size limits on the RTSP requests a browser sends. Many embedded
+/// RTSP servers crash when flooded, so excess requests are answered by the
+/// proxy and never reach the camera.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestLimits {
+    /// Requests per second, in bursts of up to as many; unlimited when 0
+    pub per_second: u32,
+    /// Largest request, headers and body; unlimited when 0
+    pub max_bytes: usize,
+}
+
+impl RequestLimits {
+    pub fn from_config(config: &SessionConfig) -> Self {
+        Self { per_second: config.max_requests_per_s, max_bytes: config.max_request_kb * 1024 }
+    }
+
+    /// Whether `buffered` bytes of an unfinished request are already too many
+    pub fn overflowed(&self, buffered: usize) -> bool {
+        self.max_bytes > 0 && buffered > self.max_bytes
+    }
+}
+
+/// Token bucket over one connection's requests
+#[derive(Debug)]
+pub struct RequestLimiter {
+    limits: RequestLimits,
+    tokens: f64,
+    last: Instant,
+    /// Requests rejected since the last one let through, to log once per flood
+    rejected: u64,
+}
+
+impl RequestLimiter {
+    pub fn new(limits: RequestLimits) -> Self {
+        Self { limits, tokens: limits.per_second as f64, last: Instant::now(), rejected: 0 }
+    }
+
+    /// The local answer to a request of `size` bytes that must not be
+    /// forwarded, or None to let it through
+    pub fn check(&mut self, req: &RtspRequest, size: usize) -> Option<RtspResponse> {
+        let mut resp = if self.limits.overflowed(size) {
+            warn!("Rejecting a {} byte {} request (limit {} bytes)", size, req.method, self.limits.max_bytes);
+            RtspResponse::new(413, "Request Entity Too Large")
+        } else if self.limits.per_second > 0 && !self.take() {
+            if self.rejected == 0 {
+                warn!("Browser exceeds {} RTSP requests/s; answering locally", self.limits.per_second);
+            }
+            self.rejected += 1;
+            let mut resp = RtspResponse::new(503, "Service Unavailable");
+            resp.headers.insert("Retry-After".to_string(), "1".to_string());
+            resp
+        } else {
+            if self.rejected > 0 {
+                warn!("Rejected {} RTSP requests over the rate limit", self.rejected);
+                self.rejected = 0;
+            }
+            return None;
+        };
+        if let Some(cseq) = req.headers.get("CSeq") {
+            resp.headers.insert("CSeq".to_string(), cseq.clone());
+        }
+        Some(resp)
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let rate = self.limits.per_second as f64;
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cseq: u32) -> RtspRequest {
+        let mut req = RtspRequest::new("GET_PARAMETER", "rtsp://cam.local/stream");
+        req.headers.insert("CSeq".to_string(), cseq.to_string());
+        req
+    }
+
+    /// Status codes of `count` requests of `size` bytes sent at once
+    fn burst(limiter: &mut RequestLimiter, count: u32, size: usize) -> Vec<Option<u16>> {
+        (0..count).map(|cseq| limiter.check(&request(cseq), size).map(|resp| resp.status_code)).collect()
+    }
+
+    /// Pretend the last request came `elapsed` ago
+    fn wait(limiter: &mut RequestLimiter, elapsed: Duration) {
+        limiter.last -= elapsed;
+    }
+
+    #[test]
+    fn bucket_starts_full_and_refills_at_the_rate() {
+        let mut limiter = RequestLimiter::new(RequestLimits { per_second: 4, max_bytes: 0 });
+        assert_eq!(burst(&mut limiter, 5, 100), [None, None, None, None, Some(503)]);
+
+        wait(&mut limiter, Duration::from_millis(500));
+        assert_eq!(burst(&mut limiter, 3, 100), [None, None, Some(503)]);
+
+        // Idle time only refills up to one second's worth
+        wait(&mut limiter, Duration::from_secs(10));
+        assert_eq!(burst(&mut limiter, 5, 100), [None, None, None, None, Some(503)]);
+    }
+
+    #[test]
+    fn refusals_carry_retry_after_and_cseq() {
+        let mut limiter = RequestLimiter::new(RequestLimits { per_second: 1, max_bytes: 0 });
+        assert!(limiter.check(&request(1), 100).is_none());
+        let resp = limiter.check(&request(2), 100).unwrap();
+        assert_eq!(resp.status_code, 503);
+        assert_eq!(resp.headers["Retry-After"], "1");
+        assert_eq!(resp.headers["CSeq"], "2");
+        assert_eq!(limiter.rejected, 1);
+
+        wait(&mut limiter, Duration::from_secs(1));
+        assert!(limiter.check(&request(3), 100).is_none());
+        assert_eq!(limiter.rejected, 0);
+    }
+
+    #[test]
+    fn size_limit_applies_before_the_rate() {
+        let limits = RequestLimits { per_second: 2, max_bytes: 1024 };
+        assert!(!limits.overflowed(1024));
+        assert!(limits.overflowed(1025));
+        let mut limiter = RequestLimiter::new(limits);
+        // An oversized request costs no token
+        assert_eq!(burst(&mut limiter, 2, 4096), [Some(413), Some(413)]);
+        assert_eq!(burst(&mut limiter, 3, 1024), [None, None, Some(503)]);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let mut limiter = RequestLimiter::new(RequestLimits::default());
+        assert!(burst(&mut limiter, 1000, 1 << 20).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn bitrate_is_exceeded_after_the_grace_checks() {
+        let limits = SessionLimits { max_bitrate_kbps: Some(1000), ..Default::default() };
+        let mut check = BitrateCheck::new(&limits);
+        let mut sent = 0;
+        // 250 kB a second is 2000 kbit/s
+        let mut second = |check: &mut BitrateCheck, bytes: u64| {
+            sent += bytes;
+            check.last_at -= Duration::from_secs(1);
+            check.exceeded(sent)
+        };
+        let over: Vec<bool> = (0..BITRATE_GRACE).map(|_| second(&mut check, 250_000)).collect();
+        assert_eq!(over, [false, false, false, false, true]);
+
+        // One second under the limit starts the count again
+        assert!(!second(&mut check, 100_000));
+        assert!(!second(&mut check, 250_000));
+        assert!(!BitrateCheck::new(&SessionLimits::default()).exceeded(u64::MAX / 8));
+    }
+
+    #[test]
+    fn viewing_hours() {
+        let hours = |text: &str| ViewingHours::try_from(text.to_string());
+        let office = hours("08:00-18:00").unwrap();
+        assert_eq!(office.left_at(9 * 3600), Some(Duration::from_secs(9 * 3600)));
+        assert_eq!(office.left_at(8 * 3600), Some(Duration::from_secs(10 * 3600)));
+        assert_eq!(office.left_at(18 * 3600), None);
+        assert_eq!(office.left_at(7 * 3600 + 59 * 60), None);
+
+        let night = hours("22:00-06:30").unwrap();
+        assert_eq!(night.left_at(23 * 3600), Some(Duration::from_secs(7 * 3600 + 30 * 60)));
+        assert_eq!(night.left_at(6 * 3600), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(night.left_at(12 * 3600), None);
+
+        let until_midnight = hours("20:00-24:00").unwrap();
+        assert_eq!(until_midnight.left_at(23 * 3600), Some(Duration::from_secs(3600)));
+
+        for bad in ["08:00", "8-18", "08:60-09:00", "24:01-06:00", "09:00-09:00", "xx:00-09:00"] {
+            assert!(hours(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn stricter_limits_win() {
+        let minutes = |m: u64| Some(Duration::from_secs(m * 60));
+        let session = SessionLimits { max_duration: minutes(60), max_bitrate_kbps: None, window_left: minutes(10) };
+        let tenant = SessionLimits { max_duration: minutes(30), max_bitrate_kbps: Some(4000), window_left: None };
+        let limits = session.min(tenant);
+        assert_eq!((limits.max_duration, limits.max_bitrate_kbps, limits.window_left), (minutes(30), Some(4000), minutes(10)));
+
+        let left = limits.remaining(Duration::from_secs(15 * 60));
+        assert_eq!((left.max_duration, left.max_bitrate_kbps, left.window_left), (minutes(15), Some(4000), minutes(0)));
+    }
+}
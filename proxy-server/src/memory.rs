@@ -0,0 +1,168 @@
+//! Per-session memory accounting against a process-wide budget, so one
+//! runaway stream cannot take the whole proxy down
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tracing::{info, warn};
+use crate::config::{MemoryConfig, Priority};
+
+/// How often usage is checked against the budget
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What session memory is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    /// RTSP and control stream buffers
+    Control,
+    /// UDP receive buffers of the media forwarders
+    Receive,
+    /// Media queued for the browser (transcoder output)
+    SendQueue,
+    /// Recent media kept for pausing and rewinding
+    TimeShift,
+}
+
+impl Usage {
+    const ALL: [Usage; 4] = [Usage::Control, Usage::Receive, Usage::SendQueue, Usage::TimeShift];
+}
+
+/// Memory held by one session
+#[derive(Debug)]
+pub struct SessionMemory {
+    url: String,
+    priority: Priority,
+    used: [AtomicUsize; 4],
+    /// Bumped each time the budget asks sessions to drop what they can
+    shed: AtomicU64,
+    evict: CancellationToken,
+}
+
+impl SessionMemory {
+    fn new(url: &str, priority: Priority) -> Self {
+        Self { url: url.to_string(), priority, used: Default::default(), shed: AtomicU64::new(0), evict: CancellationToken::new() }
+    }
+
+    pub fn set(&self, usage: Usage, bytes: usize) {
+        self.used[usage as usize].store(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` for as long as the returned guard is alive
+    pub fn hold(self: &Arc<Self>, usage: Usage, bytes: usize) -> MemoryHold {
+        self.used[usage as usize].fetch_add(bytes, Ordering::Relaxed);
+        MemoryHold { session: self.clone(), usage, bytes }
+    }
+
+    pub fn total(&self) -> usize {
+        Usage::ALL.iter().map(|&usage| self.used[usage as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    /// Whether the budget asked to drop queued data since `seen` was last updated
+    pub fn shed_requested(&self, seen: &mut u64) -> bool {
+        let shed = self.shed.load(Ordering::Relaxed);
+        std::mem::replace(seen, shed) != shed
+    }
+
+    /// Resolves when the budget closes this session
+    pub fn evicted(&self) -> WaitForCancellationFuture<'_> {
+        self.evict.cancelled()
+    }
+}
+
+pub struct MemoryHold {
+    session: Arc<SessionMemory>,
+    usage: Usage,
+    bytes: usize,
+}
+
+impl Drop for MemoryHold {
+    fn drop(&mut self) {
+        self.session.used[self.usage as usize].fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Limits from `[memory]`, and the sessions counted against them
+pub struct MemoryBudget {
+    budget: usize,
+    session_limit: Option<usize>,
+    sessions: Mutex<Vec<Weak<SessionMemory>>>,
+}
+
+static BUDGET: OnceLock<MemoryBudget> = OnceLock::new();
+
+/// Enforce the configured budget for the rest of the process
+pub fn init(config: &MemoryConfig) {
+    let budget = MemoryBudget {
+        budget: (config.budget_mb * 1024 * 1024) as usize,
+        session_limit: config.session_mb.map(|mb| (mb * 1024 * 1024) as usize),
+        sessions: Mutex::new(Vec::new()),
+    };
+    if BUDGET.set(budget).is_ok() {
+        info!("Memory budget {} MiB, {} MiB per session", config.budget_mb, config.session_mb.map_or("unlimited".to_string(), |mb| mb.to_string()));
+        tokio::spawn(enforce());
+    }
+}
+
+/// Accounting for a new session; only checked when a budget is configured
+pub fn session(url: &str, priority: Priority) -> Arc<SessionMemory> {
+    let session = Arc::new(SessionMemory::new(url, priority));
+    if let Some(budget) = BUDGET.get() {
+        budget.sessions.lock().unwrap().push(Arc::downgrade(&session));
+    }
+    session
+}
+
+async fn enforce() {
+    let Some(budget) = BUDGET.get() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    // Whether sessions were already asked to shed for the current overrun
+    let mut shedding = false;
+    loop {
+        interval.tick().await;
+        let sessions: Vec<Arc<SessionMemory>> = {
+            let mut sessions = budget.sessions.lock().unwrap();
+            sessions.retain(|s| s.strong_count() > 0);
+            sessions.iter().filter_map(Weak::upgrade).filter(|s| !s.evict.is_cancelled()).collect()
+        };
+
+        if let Some(limit) = budget.session_limit {
+            for session in sessions.iter().filter(|s| s.total() > limit) {
+                warn!("Session for {} uses {} KiB, over its {} KiB limit; closing it", session.url, session.total() / 1024, limit / 1024);
+                crate::stats::global().add("sessions_evicted", 1);
+                session.evict.cancel();
+            }
+        }
+
+        let total: usize = sessions.iter().filter(|s| !s.evict.is_cancelled()).map(|s| s.total()).sum();
+        crate::stats::global().set("session_memory_bytes", total as i64);
+        if total <= budget.budget {
+            shedding = false;
+            continue;
+        }
+
+        // First drop queued media everywhere; if that was not enough, close
+        // the largest session of the lowest priority class
+        if !shedding {
+            warn!("Session memory {} KiB is over the {} KiB budget; dropping queued media", total / 1024, budget.budget / 1024);
+            for session in &sessions {
+                session.shed.fetch_add(1, Ordering::Relaxed);
+            }
+            shedding = true;
+            continue;
+        }
+        let worst = sessions.iter().filter(|s| !s.evict.is_cancelled()).max_by_key(|s| (std::cmp::Reverse(s.priority), s.total()));
+        if let Some(worst) = worst {
+            warn!(
+                "Session memory still over budget; closing the {} priority session for {} ({} KiB)",
+                worst.priority.as_str(),
+                worst.url,
+                worst.total() / 1024
+            );
+            crate::stats::global().add("sessions_evicted", 1);
+            worst.evict.cancel();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Metadata tracks: ONVIF analytics XML (`vnd.onvif.metadata`) and KLV
+//! (RFC 6597, `smpte336m`). They are forwarded like any other track; ONVIF
+//! object detections can also be turned into events for overlays.
+
+use tokio::sync::mpsc;
+use crate::control::Event;
+use crate::onvif::{attribute, elements, tags, unescape};
+use crate::protocol::{BoundingBox, DetectedObject};
+use crate::sdp::MediaDescription;
+
+/// Largest metadata document reassembled from RTP; larger ones are dropped
+const MAX_DOCUMENT: usize = 256 * 1024;
+
+/// Format of a metadata track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataFormat {
+    Onvif,
+    Klv,
+}
+
+impl MetadataFormat {
+    pub fn for_media(media: &MediaDescription) -> Option<Self> {
+        let encoding = media.encoding.as_deref()?;
+        if encoding.eq_ignore_ascii_case("vnd.onvif.metadata") {
+            Some(Self::Onvif)
+        } else if encoding.eq_ignore_ascii_case("smpte336m") {
+            Some(Self::Klv)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetadataFormat::Onvif => "onvif",
+            MetadataFormat::Klv => "klv",
+        }
+    }
+}
+
+/// Objects the camera's analytics saw in one frame
+#[derive(Debug, Clone)]
+pub struct ObjectFrame {
+    pub channel_id: u8,
+    /// The frame's `UtcTime`
+    pub time: Option<String>,
+    pub objects: Vec<DetectedObject>,
+}
+
+/// Reassembles ONVIF metadata documents from RTP and reports their objects
+#[derive(Debug)]
+pub struct ObjectParser {
+    channel_id: u8,
+    tx: mpsc::Sender<Event>,
+    document: Vec<u8>,
+    /// Whether the last frame reported had objects, so an empty one is sent
+    /// once to clear the overlay
+    had_objects: bool,
+}
+
+impl ObjectParser {
+    /// None unless the track carries ONVIF metadata
+    pub fn new(media: &MediaDescription, channel_id: u8, tx: mpsc::Sender<Event>) -> Option<Self> {
+        (MetadataFormat::for_media(media)? == MetadataFormat::Onvif).then(|| Self { channel_id, tx, document: Vec::new(), had_objects: false })
+    }
+
+    /// Inspect one RTP packet as received from the camera
+    pub fn on_rtp(&mut self, packet: &[u8]) {
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return;
+        };
+        if self.document.len() + payload.len() > MAX_DOCUMENT {
+            self.document.clear();
+            return;
+        }
+        self.document.extend_from_slice(payload);
+        // The marker bit ends a document
+        if packet[1] & 0x80 == 0 {
+            return;
+        }
+        let document = std::mem::take(&mut self.document);
+        for frame in frames(&String::from_utf8_lossy(&document), self.channel_id) {
+            let has_objects = !frame.objects.is_empty();
+            if has_objects || self.had_objects {
+                // Stale detections are worthless; drop them rather than wait
+                let _ = self.tx.try_send(Event::Objects(frame));
+            }
+            self.had_objects = has_objects;
+        }
+    }
+}
+
+/// Analytics frames of a `tt:MetadataStream` document
+fn frames(xml: &str, channel_id: u8) -> Vec<ObjectFrame> {
+    let frame_tags = tags(xml).filter(|tag| tag.name == "Frame" && !tag.closing);
+    frame_tags
+        .zip(elements(xml, "Frame"))
+        .map(|(tag, body)| ObjectFrame {
+            channel_id,
+            time: attribute(tag.text, "UtcTime").map(unescape),
+            objects: objects(body),
+        })
+        .collect()
+}
+
+fn objects(frame: &str) -> Vec<DetectedObject> {
+    let object_tags = tags(frame).filter(|tag| tag.name == "Object" && !tag.closing);
+    object_tags
+        .zip(elements(frame, "Object"))
+        .filter_map(|(tag, body)| {
+            let id = unescape(attribute(tag.text, "ObjectId")?);
+            let bbox = tags(body).find(|tag| tag.name == "BoundingBox").and_then(|tag| {
+                let edge = |name| attribute(tag.text, name)?.parse().ok();
+                Some(BoundingBox { left: edge("left")?, top: edge("top")?, right: edge("right")?, bottom: edge("bottom")? })
+            });
+            // ONVIF 1.x: <Type Likelihood="0.9">Human</Type>; 2.x adds a <Likelihood> element
+            let class_tag = tags(body).find(|tag| tag.name == "Type" && !tag.closing);
+            let class = elements(body, "Type").into_iter().next().map(|text| unescape(text.trim())).filter(|c| !c.is_empty());
+            let likelihood = class_tag
+                .and_then(|tag| attribute(tag.text, "Likelihood"))
+                .or_else(|| elements(body, "Likelihood").into_iter().next().map(str::trim))
+                .and_then(|l| l.parse().ok());
+            Some(DetectedObject { id, class, likelihood, bbox })
+        })
+        .collect()
+}
@@ -0,0 +1,52 @@
+//! Monitoring endpoints, behind an admin token:
+//!
+//! - `GET /metrics`: the process-wide gauges and each running session's
+//!   receive-side RTP metrics, in the Prometheus text format
+//! - `GET /sessions[?session=<id>]`: the RTP metrics as JSON
+
+use serde_json::json;
+use std::fmt::Write;
+use crate::config::Config;
+use crate::http::{Request, Response};
+
+/// Answer a monitoring request, which needs the admin token
+pub fn handle(request: &Request, config: &Config) -> Response {
+    if let Err(refused) = crate::http::require_admin(request, config) {
+        return refused;
+    }
+    let params = request.query();
+
+    let mut sessions = crate::receive::snapshot();
+    if request.path() == "/sessions" {
+        if let Some(id) = params.get("session") {
+            sessions.retain(|session, _| session == id);
+            if sessions.is_empty() {
+                return Response::json("404 Not Found", &json!({ "error": "No running session has this ID" }));
+            }
+        }
+        let sessions: Vec<_> = sessions.into_iter().map(|(id, channels)| json!({ "session": id, "channels": channels })).collect();
+        return Response::json("200 OK", &json!({ "sessions": sessions }));
+    }
+
+    let mut out = String::new();
+    for (key, value) in crate::stats::global().snapshot() {
+        let _ = writeln!(out, "{} {}", key, value);
+    }
+    type Series = (&'static str, fn(&crate::receive::ChannelReport) -> f64);
+    let series: [Series; 5] = [
+        ("rtp_packets_received", |r| r.packets as f64),
+        ("rtp_packets_lost", |r| r.lost as f64),
+        ("rtp_loss_fraction", |r| r.loss_fraction as f64),
+        ("rtp_jitter_ms", |r| r.jitter_ms),
+        ("rtp_bitrate_kbps", |r| r.bitrate_kbps as f64),
+    ];
+    for (name, value) in series {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (session, channels) in &sessions {
+            for report in channels {
+                let _ = writeln!(out, "{}{{session=\"{}\",channel=\"{}\"}} {}", name, session, report.channel, value(report));
+            }
+        }
+    }
+    Response::new("200 OK", "text/plain; version=0.0.4", out)
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use tracing::info;
+use crate::config::{HeaderRule, MiddlewareConfig};
+use crate::rtsp::{RtspRequest, RtspResponse};
+
+/// Hook into the RTSP messages a session exchanges with the camera.
+///
+/// Requests pass through the chain in order on their way to the camera,
+/// responses in reverse order on their way back to the browser. Messages the
+/// proxy generates itself (notifications, TEARDOWN on disconnect, capability
+/// discovery) do not go through the chain.
+pub trait Middleware: Send + Sync {
+    /// Inspect or modify a browser request. Returning a response answers the
+    /// browser directly: the request is not forwarded and later middleware
+    /// does not see it.
+    fn on_request(&self, _req: &mut RtspRequest) -> Option<RtspResponse> {
+        None
+    }
+
+    /// Inspect or modify a camera response before the proxy acts on it
+    fn on_response(&self, _resp: &mut RtspResponse) {}
+}
+
+/// Ordered middleware applied by one session
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    /// Built-in middleware enabled in the config: logging first, so it sees
+    /// requests as the browser sent them and responses as the browser gets them
+    pub fn from_config(config: &MiddlewareConfig) -> Self {
+        let mut chain = Self::default();
+        if config.log {
+            chain.push(Arc::new(LogMessages));
+        }
+        if !config.deny_methods.is_empty() {
+            chain.push(Arc::new(DenyMethods { methods: config.deny_methods.clone() }));
+        }
+        for rule in &config.header {
+            chain.push(Arc::new(HeaderRewrite { rule: rule.clone() }));
+        }
+        chain
+    }
+
+    pub fn push(&mut self, layer: Arc<dyn Middleware>) {
+        self.layers.push(layer);
+    }
+
+    pub fn on_request(&self, req: &mut RtspRequest) -> Option<RtspResponse> {
+        for layer in &self.layers {
+            if let Some(mut resp) = layer.on_request(req) {
+                if let Some(cseq) = req.headers.get("CSeq") {
+                    resp.headers.entry("CSeq".to_string()).or_insert_with(|| cseq.clone());
+                }
+                return Some(resp);
+            }
+        }
+        None
+    }
+
+    pub fn on_response(&self, resp: &mut RtspResponse) {
+        for layer in self.layers.iter().rev() {
+            layer.on_response(resp);
+        }
+    }
+}
+
+/// Log the first line of every request and response
+struct LogMessages;
+
+impl Middleware for LogMessages {
+    fn on_request(&self, req: &mut RtspRequest) -> Option<RtspResponse> {
+        info!("RTSP > {} {} (CSeq {})", req.method, req.path, req.headers.get("CSeq").map_or("-", String::as_str));
+        None
+    }
+
+    fn on_response(&self, resp: &mut RtspResponse) {
+        info!("RTSP < {} {} (CSeq {})", resp.status_code, resp.reason, resp.headers.get("CSeq").map_or("-", String::as_str));
+    }
+}
+
+/// Answer requests for methods the browser may not use with 405
+struct DenyMethods {
+    methods: Vec<String>,
+}
+
+impl Middleware for DenyMethods {
+    fn on_request(&self, req: &mut RtspRequest) -> Option<RtspResponse> {
+        if !self.methods.iter().any(|m| m.eq_ignore_ascii_case(&req.method)) {
+            return None;
+        }
+        info!("Rejecting {} request", req.method);
+        Some(RtspResponse::new(405, "Method Not Allowed"))
+    }
+}
+
+/// Set or remove headers as configured in a `[[middleware.header]]` rule
+struct HeaderRewrite {
+    rule: HeaderRule,
+}
+
+impl HeaderRewrite {
+    fn apply(&self, headers: &mut std::collections::HashMap<String, String>) {
+        // Header names are matched case-insensitively, whatever case the peer used
+        headers.retain(|name, _| {
+            !self.rule.remove.iter().chain(self.rule.set.keys()).any(|r| r.eq_ignore_ascii_case(name))
+        });
+        for (name, value) in &self.rule.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+impl Middleware for HeaderRewrite {
+    fn on_request(&self, req: &mut RtspRequest) -> Option<RtspResponse> {
+        let method_matches = self.rule.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(&req.method));
+        if self.rule.direction.requests() && method_matches {
+            self.apply(&mut req.headers);
+        }
+        None
+    }
+
+    fn on_response(&self, resp: &mut RtspResponse) {
+        if self.rule.direction.responses() {
+            self.apply(&mut resp.headers);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Cheap motion hints from the shape of a camera's video stream, for
+//! dashboards that only want to show cameras with something happening.
+//!
+//! Encoders spend more bits on inter frames when the picture changes, and
+//! many insert an extra keyframe on a scene change. Neither is proof of
+//! motion, so the events are hints, not analytics.
+
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::config::MotionConfig;
+use crate::control::Event;
+
+/// Span over which inter-frame bytes are compared with the baseline
+const WINDOW: Duration = Duration::from_secs(1);
+/// Windows measured before the baseline is trusted
+const WARMUP_WINDOWS: u32 = 10;
+/// Baseline smoothing for quiet windows, and for windows over the threshold
+/// so sustained motion is not learned as the new normal too quickly
+const BASELINE_ALPHA: f64 = 0.1;
+const BASELINE_ALPHA_OVER: f64 = 0.01;
+/// Windows carrying fewer inter-frame bytes never count, so a nearly static
+/// low-bitrate stream does not trigger on noise
+const MIN_WINDOW_BYTES: u64 = 4 * 1024;
+/// A keyframe this much earlier than the usual cadence counts as a scene change
+const EARLY_KEYFRAME: f64 = 0.5;
+
+/// What suggested motion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionCue {
+    /// Inter frames grew well over their usual size
+    Bitrate,
+    /// The encoder sent a keyframe ahead of its usual cadence
+    Keyframe,
+}
+
+impl MotionCue {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MotionCue::Bitrate => "bitrate",
+            MotionCue::Keyframe => "keyframe",
+        }
+    }
+}
+
+/// Watches one video track's RTP and reports when motion starts and ends
+#[derive(Debug)]
+pub struct MotionDetector {
+    channel_id: u8,
+    ratio: f64,
+    hold: Duration,
+    tx: mpsc::Sender<Event>,
+    /// Inter-frame payload bytes in the current window
+    window_bytes: u64,
+    window_start: Instant,
+    windows: u32,
+    baseline: Option<f64>,
+    /// Between a keyframe's first packet and its marker bit
+    in_keyframe: bool,
+    last_keyframe: Option<Instant>,
+    keyframe_interval: Option<Duration>,
+    /// Cue of the ongoing motion and when it ends without further cues
+    active: Option<(MotionCue, Instant)>,
+}
+
+impl MotionDetector {
+    pub fn new(channel_id: u8, config: &MotionConfig, tx: mpsc::Sender<Event>) -> Self {
+        Self {
+            channel_id,
+            ratio: config.bitrate_ratio,
+            hold: Duration::from_secs(config.hold_s),
+            tx,
+            window_bytes: 0,
+            window_start: Instant::now(),
+            windows: 0,
+            baseline: None,
+            in_keyframe: false,
+            last_keyframe: None,
+            keyframe_interval: None,
+            active: None,
+        }
+    }
+
+    /// Inspect one RTP packet as received from the camera
+    pub fn on_rtp(&mut self, packet: &[u8]) {
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return;
+        };
+        let now = Instant::now();
+        if !self.in_keyframe && crate::rtp::is_keyframe_start(packet) {
+            self.in_keyframe = true;
+            self.on_keyframe(now);
+        }
+        if !self.in_keyframe {
+            self.window_bytes += payload.len() as u64;
+        }
+        if packet[1] & 0x80 != 0 {
+            self.in_keyframe = false;
+        }
+
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.end_window(now);
+        }
+        if let Some((cue, until)) = self.active
+            && now >= until
+        {
+            self.active = None;
+            info!("Motion ended on channel {}", self.channel_id);
+            self.notify(cue, false);
+        }
+    }
+
+    fn on_keyframe(&mut self, now: Instant) {
+        let Some(last) = self.last_keyframe.replace(now) else {
+            return;
+        };
+        let gap = now.duration_since(last);
+        match self.keyframe_interval {
+            Some(usual) => {
+                if gap < usual.mul_f64(EARLY_KEYFRAME) {
+                    self.cue(MotionCue::Keyframe, now);
+                }
+                self.keyframe_interval = Some(usual.mul_f64(0.8) + gap.mul_f64(0.2));
+            }
+            None => self.keyframe_interval = Some(gap),
+        }
+    }
+
+    fn end_window(&mut self, now: Instant) {
+        let bytes = self.window_bytes as f64;
+        self.window_bytes = 0;
+        self.window_start = now;
+        self.windows += 1;
+        let Some(baseline) = self.baseline else {
+            self.baseline = Some(bytes);
+            return;
+        };
+        let over = bytes > baseline * self.ratio;
+        if over && self.windows > WARMUP_WINDOWS && bytes >= MIN_WINDOW_BYTES as f64 {
+            self.cue(MotionCue::Bitrate, now);
+        }
+        let alpha = if over { BASELINE_ALPHA_OVER } else { BASELINE_ALPHA };
+        self.baseline = Some(baseline + alpha * (bytes - baseline));
+    }
+
+    fn cue(&mut self, cue: MotionCue, now: Instant) {
+        let started = self.active.is_none();
+        let cue = self.active.map_or(cue, |(first, _)| first);
+        self.active = Some((cue, now + self.hold));
+        if started {
+            info!("Motion on channel {} ({})", self.channel_id, cue.as_str());
+            self.notify(cue, true);
+        }
+    }
+
+    fn notify(&self, cue: MotionCue, active: bool) {
+        let _ = self.tx.try_send(Event::Motion { channel_id: self.channel_id, active, cue });
+    }
+}
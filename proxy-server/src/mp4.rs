@@ -0,0 +1,234 @@
+//! Minimal MP4 (ISO/IEC 14496-12) writer for H.264 clips: one video track,
+//! all samples in a single chunk, `moov` ahead of `mdat` so players can start
+//! before the download ends.
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
+use crate::h264::AccessUnit;
+
+/// Media timescale: the RTP clock of H.264
+const TIMESCALE: u32 = 90_000;
+/// Movie timescale, for the movie and track headers
+const MOVIE_TIMESCALE: u32 = 1000;
+/// Sample duration when the timestamps give none (one frame at 30 fps)
+const DEFAULT_DURATION: u32 = 3000;
+/// Larger timestamp steps are treated as a discontinuity
+const MAX_DURATION: u32 = 10 * TIMESCALE;
+
+/// Identity transformation matrix of the movie and track headers
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// MP4 file of the access units from the first keyframe on. `parameter_sets`
+/// are used when no SPS and PPS precede that keyframe in the stream.
+pub fn h264_clip(units: &[AccessUnit], parameter_sets: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let Some(start) = units.iter().position(AccessUnit::is_keyframe) else {
+        bail!("No keyframe in the requested span");
+    };
+    let units = &units[start..];
+    let find = |nal_type: u8| {
+        units[0]
+            .nals
+            .iter()
+            .chain(parameter_sets)
+            .find(|nal| nal[0] & 0x1F == nal_type)
+            .cloned()
+    };
+    let (Some(sps), Some(pps)) = (find(7), find(8)) else {
+        bail!("No SPS and PPS for the first keyframe");
+    };
+    if sps.len() < 4 {
+        bail!("Truncated SPS");
+    }
+    let (width, height) = crate::h264::sps_resolution(&sps).unwrap_or((0, 0));
+
+    // Samples in AVCC format: each NAL unit prefixed with its 32-bit length,
+    // access unit delimiters dropped
+    let mut mdat = BytesMut::new();
+    let mut sizes = Vec::with_capacity(units.len());
+    for unit in units {
+        let before = mdat.len();
+        for nal in unit.nals.iter().filter(|nal| nal[0] & 0x1F != 9) {
+            mdat.put_u32(nal.len() as u32);
+            mdat.extend_from_slice(nal);
+        }
+        sizes.push((mdat.len() - before) as u32);
+    }
+    let mut durations: Vec<u32> = units
+        .windows(2)
+        .map(|pair| pair[1].timestamp.wrapping_sub(pair[0].timestamp))
+        .map(|d| if d == 0 || d > MAX_DURATION { DEFAULT_DURATION } else { d })
+        .collect();
+    durations.push(durations.last().copied().unwrap_or(DEFAULT_DURATION));
+    let duration: u64 = durations.iter().map(|&d| d as u64).sum();
+    let keyframes: Vec<u32> = units.iter().enumerate().filter(|(_, u)| u.is_keyframe()).map(|(i, _)| i as u32 + 1).collect();
+
+    let ftyp = mp4_box(b"ftyp", |b| {
+        b.put_slice(b"isom");
+        b.put_u32(0x200);
+        for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+            b.put_slice(brand);
+        }
+    });
+
+    // The chunk offset depends on the size of moov, which does not depend on
+    // the offset's value
+    let moov = |offset: u32| {
+        let stbl = mp4_box(b"stbl", |b| {
+            b.put_slice(&full_box(b"stsd", 0, 0, |b| {
+                b.put_u32(1);
+                b.put_slice(&avc1(&sps, &pps, width, height));
+            }));
+            b.put_slice(&full_box(b"stts", 0, 0, |b| {
+                let mut runs: Vec<(u32, u32)> = Vec::new();
+                for &d in &durations {
+                    match runs.last_mut() {
+                        Some((count, delta)) if *delta == d => *count += 1,
+                        _ => runs.push((1, d)),
+                    }
+                }
+                b.put_u32(runs.len() as u32);
+                for (count, delta) in runs {
+                    b.put_u32(count);
+                    b.put_u32(delta);
+                }
+            }));
+            b.put_slice(&full_box(b"stss", 0, 0, |b| {
+                b.put_u32(keyframes.len() as u32);
+                keyframes.iter().for_each(|&k| b.put_u32(k));
+            }));
+            b.put_slice(&full_box(b"stsc", 0, 0, |b| {
+                b.put_u32(1);
+                b.put_u32(1); // first chunk
+                b.put_u32(sizes.len() as u32);
+                b.put_u32(1); // sample description index
+            }));
+            b.put_slice(&full_box(b"stsz", 0, 0, |b| {
+                b.put_u32(0);
+                b.put_u32(sizes.len() as u32);
+                sizes.iter().for_each(|&s| b.put_u32(s));
+            }));
+            b.put_slice(&full_box(b"stco", 0, 0, |b| {
+                b.put_u32(1);
+                b.put_u32(offset);
+            }));
+        });
+        let minf = mp4_box(b"minf", |b| {
+            b.put_slice(&full_box(b"vmhd", 0, 1, |b| b.put_bytes(0, 8)));
+            b.put_slice(&mp4_box(b"dinf", |b| {
+                b.put_slice(&full_box(b"dref", 0, 0, |b| {
+                    b.put_u32(1);
+                    // Media data is in this file
+                    b.put_slice(&full_box(b"url ", 0, 1, |_| {}));
+                }));
+            }));
+            b.put_slice(&stbl);
+        });
+        let mdia = mp4_box(b"mdia", |b| {
+            b.put_slice(&full_box(b"mdhd", 0, 0, |b| {
+                b.put_u64(0); // creation and modification time
+                b.put_u32(TIMESCALE);
+                b.put_u32(duration as u32);
+                b.put_u16(0x55C4); // "und"
+                b.put_u16(0);
+            }));
+            b.put_slice(&full_box(b"hdlr", 0, 0, |b| {
+                b.put_u32(0);
+                b.put_slice(b"vide");
+                b.put_bytes(0, 12);
+                b.put_slice(b"VideoHandler\0");
+            }));
+            b.put_slice(&minf);
+        });
+        let movie_duration = (duration * MOVIE_TIMESCALE as u64 / TIMESCALE as u64) as u32;
+        mp4_box(b"moov", |b| {
+            b.put_slice(&full_box(b"mvhd", 0, 0, |b| {
+                b.put_u64(0);
+                b.put_u32(MOVIE_TIMESCALE);
+                b.put_u32(movie_duration);
+                b.put_u32(0x0001_0000); // rate 1.0
+                b.put_u16(0x0100); // volume 1.0
+                b.put_bytes(0, 10);
+                MATRIX.iter().for_each(|&m| b.put_u32(m));
+                b.put_bytes(0, 24);
+                b.put_u32(2); // next track ID
+            }));
+            b.put_slice(&mp4_box(b"trak", |b| {
+                // Enabled, in movie
+                b.put_slice(&full_box(b"tkhd", 0, 3, |b| {
+                    b.put_u64(0);
+                    b.put_u32(1); // track ID
+                    b.put_u32(0);
+                    b.put_u32(movie_duration);
+                    b.put_bytes(0, 8);
+                    b.put_u16(0); // layer
+                    b.put_u16(0); // alternate group
+                    b.put_u16(0); // volume
+                    b.put_u16(0);
+                    MATRIX.iter().for_each(|&m| b.put_u32(m));
+                    b.put_u32(width << 16);
+                    b.put_u32(height << 16);
+                }));
+                b.put_slice(&mdia);
+            }));
+        })
+    };
+    let offset = ftyp.len() + moov(0).len() + 8;
+    if offset + mdat.len() > u32::MAX as usize {
+        bail!("Clip too large for 32-bit chunk offsets");
+    }
+    let offset = offset as u32;
+
+    let mut out = Vec::with_capacity(offset as usize + mdat.len());
+    out.extend_from_slice(&ftyp);
+    out.extend_from_slice(&moov(offset));
+    out.extend_from_slice(&((mdat.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    out.extend_from_slice(&mdat);
+    Ok(out)
+}
+
+/// `avc1` sample entry with its `avcC` decoder configuration
+fn avc1(sps: &[u8], pps: &[u8], width: u32, height: u32) -> Vec<u8> {
+    mp4_box(b"avc1", |b| {
+        b.put_bytes(0, 6);
+        b.put_u16(1); // data reference index
+        b.put_bytes(0, 16);
+        b.put_u16(width as u16);
+        b.put_u16(height as u16);
+        b.put_u32(0x0048_0000); // 72 dpi
+        b.put_u32(0x0048_0000);
+        b.put_u32(0);
+        b.put_u16(1); // frame count
+        b.put_bytes(0, 32); // compressor name
+        b.put_u16(0x0018); // depth
+        b.put_i16(-1);
+        b.put_slice(&mp4_box(b"avcC", |b| {
+            b.put_u8(1);
+            b.put_slice(&sps[1..4]); // profile, compatibility, level
+            b.put_u8(0xFF); // 4-byte NAL unit lengths
+            b.put_u8(0xE1); // one SPS
+            b.put_u16(sps.len() as u16);
+            b.put_slice(sps);
+            b.put_u8(1); // one PPS
+            b.put_u16(pps.len() as u16);
+            b.put_slice(pps);
+        }));
+    })
+}
+
+fn mp4_box(kind: &[u8; 4], body: impl FnOnce(&mut BytesMut)) -> Vec<u8> {
+    let mut b = BytesMut::new();
+    b.put_u32(0);
+    b.put_slice(kind);
+    body(&mut b);
+    let len = b.len() as u32;
+    b[..4].copy_from_slice(&len.to_be_bytes());
+    b.to_vec()
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut BytesMut)) -> Vec<u8> {
+    mp4_box(kind, |b| {
+        b.put_u32((version as u32) << 24 | flags);
+        body(b);
+    })
+}
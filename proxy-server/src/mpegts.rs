@@ -0,0 +1,435 @@
+//! MPEG-TS over RTP (RFC 2250, payload type 33). Browsers cannot play a
+//! transport stream, so the proxy demuxes it and re-packetizes the H.264
+//! video as RFC 6184 RTP; other elementary streams are dropped.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use crate::sdp::{MediaDescription, SessionDescription};
+
+/// Static payload type of MP2T
+pub const TS_PAYLOAD_TYPE: u8 = 33;
+/// Payload type the re-packetized video is advertised with
+const OUTPUT_PAYLOAD_TYPE: u8 = 96;
+const TS_PACKET: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+const STREAM_TYPE_H264: u8 = 0x1B;
+/// AUD NAL unit allowing any picture type
+const ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xF0];
+/// RTP header plus the largest media framing header
+const OUTPUT_OVERHEAD: usize = 12 + 3;
+/// Bounds on the output RTP payload, as for transcoded output
+const DEFAULT_OUTPUT_PAYLOAD: usize = 1200;
+const MIN_OUTPUT_PAYLOAD: usize = 256;
+const MAX_OUTPUT_PAYLOAD: usize = 1400;
+
+/// Whether a track carries MPEG-TS rather than a single elementary stream
+pub fn is_transport_stream(media: &MediaDescription) -> bool {
+    match &media.encoding {
+        Some(encoding) => encoding.eq_ignore_ascii_case("MP2T"),
+        None => media.payload_type == Some(TS_PAYLOAD_TYPE),
+    }
+}
+
+/// Turns RTP packets of MPEG-TS into RTP packets of H.264
+#[derive(Debug)]
+pub struct TsRepacketizer {
+    demuxer: TsDemuxer,
+    packetizer: H264Packetizer,
+}
+
+impl TsRepacketizer {
+    /// Output packets are sized to fit the client's datagrams once framed
+    pub fn new(max_datagram_size: Option<usize>) -> Self {
+        let max_payload = match max_datagram_size {
+            Some(max) => max.saturating_sub(OUTPUT_OVERHEAD).clamp(MIN_OUTPUT_PAYLOAD, MAX_OUTPUT_PAYLOAD),
+            None => DEFAULT_OUTPUT_PAYLOAD,
+        };
+        Self {
+            demuxer: TsDemuxer::default(),
+            packetizer: H264Packetizer { ssrc: rand::random(), seq: rand::random(), max_payload },
+        }
+    }
+
+    /// H.264 RTP packets for the pictures completed by one MP2T RTP packet.
+    /// A picture is complete when the next one starts, so output lags the
+    /// input by one frame.
+    pub fn push(&mut self, packet: &[u8]) -> Vec<Bytes> {
+        let Some(payload) = crate::rtp_header::payload(packet) else {
+            return Vec::new();
+        };
+        let mut out = Vec::new();
+        for picture in self.demuxer.push(payload) {
+            self.packetizer.packetize(&picture, &mut out);
+        }
+        out
+    }
+}
+
+/// One PES packet of video: an access unit in Annex B format
+#[derive(Debug)]
+struct Picture {
+    /// 90 kHz presentation time, truncated to 32 bits
+    pts: u32,
+    data: Vec<u8>,
+}
+
+/// Follows PAT and PMT to the first H.264 stream and reassembles its PES
+/// packets. PSI sections are assumed to fit in one TS packet, as they do in
+/// practice for single-program streams.
+#[derive(Debug, Default)]
+struct TsDemuxer {
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    /// PES packet being assembled, once its start was seen
+    pes: Option<Vec<u8>>,
+    last_pts: u32,
+}
+
+impl TsDemuxer {
+    fn push(&mut self, mut data: &[u8]) -> Vec<Picture> {
+        let mut pictures = Vec::new();
+        while data.len() >= TS_PACKET {
+            if data[0] != SYNC_BYTE {
+                // Resynchronize on the next sync byte
+                let skip = data[1..].iter().position(|&b| b == SYNC_BYTE).map_or(data.len(), |p| p + 1);
+                data = &data[skip..];
+                continue;
+            }
+            if let Some(picture) = self.packet(&data[..TS_PACKET]) {
+                pictures.push(picture);
+            }
+            data = &data[TS_PACKET..];
+        }
+        pictures
+    }
+
+    /// Handle one 188-byte packet; Some when it completes a picture
+    fn packet(&mut self, ts: &[u8]) -> Option<Picture> {
+        let unit_start = ts[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([ts[1] & 0x1F, ts[2]]);
+        let adaptation = (ts[3] >> 4) & 0x03;
+        if adaptation & 0x01 == 0 {
+            return None; // No payload
+        }
+        let start = if adaptation & 0x02 != 0 { 5 + ts[4] as usize } else { 4 };
+        let payload = ts.get(start..)?;
+
+        if pid == PAT_PID && unit_start {
+            self.pmt_pid = section(payload, 0x00).and_then(pat_pmt_pid);
+        } else if Some(pid) == self.pmt_pid && unit_start {
+            self.video_pid = section(payload, 0x02).and_then(pmt_video_pid);
+        } else if Some(pid) == self.video_pid {
+            if unit_start {
+                let finished = self.pes.replace(payload.to_vec());
+                return finished.and_then(|pes| self.picture(&pes));
+            }
+            if let Some(pes) = &mut self.pes {
+                pes.extend_from_slice(payload);
+            }
+        }
+        None
+    }
+
+    fn picture(&mut self, pes: &[u8]) -> Option<Picture> {
+        if pes.len() < 9 || pes[..3] != [0, 0, 1] {
+            return None;
+        }
+        let header_len = pes[8] as usize;
+        if pes[7] & 0x80 != 0 && pes.len() >= 14 {
+            let p = &pes[9..14];
+            let pts = ((p[0] as u64 >> 1) & 0x07) << 30
+                | (p[1] as u64) << 22
+                | (p[2] as u64 >> 1) << 15
+                | (p[3] as u64) << 7
+                | p[4] as u64 >> 1;
+            self.last_pts = pts as u32;
+        }
+        let data = pes.get(9 + header_len..)?.to_vec();
+        Some(Picture { pts: self.last_pts, data })
+    }
+}
+
+/// Body of a PSI section with the given table id, after the pointer field
+fn section(payload: &[u8], table_id: u8) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    if *section.first()? != table_id || section.len() < 3 {
+        return None;
+    }
+    let length = u16::from_be_bytes([section[1] & 0x0F, section[2]]) as usize;
+    // Without the trailing CRC
+    section.get(..(3 + length).checked_sub(4)?)
+}
+
+/// PID of the first program's PMT
+fn pat_pmt_pid(pat: &[u8]) -> Option<u16> {
+    pat.get(8..)?
+        .chunks_exact(4)
+        .find(|entry| entry[0] != 0 || entry[1] != 0) // program 0 is the network PID
+        .map(|entry| u16::from_be_bytes([entry[2] & 0x1F, entry[3]]))
+}
+
+/// PID of the program's first H.264 stream
+fn pmt_video_pid(pmt: &[u8]) -> Option<u16> {
+    let info_len = u16::from_be_bytes([*pmt.get(10)? & 0x0F, *pmt.get(11)?]) as usize;
+    let mut streams = pmt.get(12 + info_len..)?;
+    while streams.len() >= 5 {
+        let pid = u16::from_be_bytes([streams[1] & 0x1F, streams[2]]);
+        if streams[0] == STREAM_TYPE_H264 {
+            return Some(pid);
+        }
+        let es_info_len = u16::from_be_bytes([streams[3] & 0x0F, streams[4]]) as usize;
+        streams = streams.get(5 + es_info_len..)?;
+    }
+    None
+}
+
+/// RFC 6184 packetization: single NAL unit packets, FU-A for larger NAL units
+#[derive(Debug)]
+struct H264Packetizer {
+    ssrc: u32,
+    seq: u16,
+    max_payload: usize,
+}
+
+impl H264Packetizer {
+    fn packetize(&mut self, picture: &Picture, out: &mut Vec<Bytes>) {
+        let mut nals: Vec<&[u8]> = nal_units(&picture.data).collect();
+        // The player starts a new frame at each access unit delimiter
+        if nals.first().is_none_or(|nal| nal[0] & 0x1F != 9) {
+            nals.insert(0, &ACCESS_UNIT_DELIMITER);
+        }
+        for (i, nal) in nals.iter().enumerate() {
+            let last_nal = i + 1 == nals.len();
+            if nal.len() <= self.max_payload {
+                out.push(self.packet(picture.pts, last_nal, &[], nal));
+                continue;
+            }
+            let indicator = (nal[0] & 0xE0) | 28;
+            let chunks: Vec<&[u8]> = nal[1..].chunks(self.max_payload - 2).collect();
+            for (j, chunk) in chunks.iter().enumerate() {
+                let mut header = nal[0] & 0x1F;
+                if j == 0 {
+                    header |= 0x80;
+                }
+                let last_chunk = j + 1 == chunks.len();
+                if last_chunk {
+                    header |= 0x40;
+                }
+                out.push(self.packet(picture.pts, last_nal && last_chunk, &[indicator, header], chunk));
+            }
+        }
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, prefix: &[u8], payload: &[u8]) -> Bytes {
+        let mut packet = BytesMut::with_capacity(12 + prefix.len() + payload.len());
+        packet.put_u8(0x80);
+        packet.put_u8(OUTPUT_PAYLOAD_TYPE | if marker { 0x80 } else { 0 });
+        packet.put_u16(self.seq);
+        packet.put_u32(timestamp);
+        packet.put_u32(self.ssrc);
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(payload);
+        self.seq = self.seq.wrapping_add(1);
+        packet.freeze()
+    }
+}
+
+/// NAL units of an Annex B byte stream, without start codes
+fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let ends: Vec<usize> = starts.iter().skip(1).map(|&s| s - 3).chain(std::iter::once(data.len())).collect();
+    starts.into_iter().zip(ends).filter_map(move |(start, end)| {
+        // A four-byte start code leaves a zero at the end of the previous unit
+        let mut end = end;
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        (end > start).then(|| &data[start..end])
+    })
+}
+
+/// Rewrite the SDP so MP2T tracks advertise the H.264 the proxy produces
+pub fn rewrite_sdp(sdp: &str) -> String {
+    let description = SessionDescription::parse(sdp);
+    let mut media = description.media.iter();
+    let mut out = String::with_capacity(sdp.len());
+    let mut in_ts = false;
+    for line in sdp.lines() {
+        if let Some(m_line) = line.strip_prefix("m=") {
+            in_ts = media.next().is_some_and(is_transport_stream);
+            if in_ts {
+                // Keep the port and protocol
+                let transport: Vec<&str> = m_line.split_whitespace().skip(1).take(2).collect();
+                out.push_str(&format!("m=video {} {}\r\n", transport.join(" "), OUTPUT_PAYLOAD_TYPE));
+                out.push_str(&format!("a=rtpmap:{} H264/90000\r\n", OUTPUT_PAYLOAD_TYPE));
+                out.push_str(&format!("a=fmtp:{} packetization-mode=1\r\n", OUTPUT_PAYLOAD_TYPE));
+                continue;
+            }
+        }
+        if in_ts && (line.starts_with("a=rtpmap:") || line.starts_with("a=fmtp:")) {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FFmpeg's PAT: program 1 on PMT PID 0x1000
+    const PAT: &[u8] = &[0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xF0, 0x00, 0x2A, 0xB1, 0x04, 0xB2];
+    /// PMT with AAC on PID 0x102 ahead of H.264 on PID 0x101
+    const PMT: &[u8] = &[
+        0x00, 0x02, 0xB0, 0x17, 0x00, 0x01, 0xC1, 0x00, 0x00, 0xE1, 0x01, 0xF0, 0x00, 0x0F, 0xE1, 0x02, 0xF0, 0x00, 0x1B, 0xE1,
+        0x01, 0xF0, 0x00, 0xF7, 0x47, 0x8A, 0xBF,
+    ];
+    const PMT_PID: u16 = 0x1000;
+    const VIDEO_PID: u16 = 0x101;
+    const AUDIO_PID: u16 = 0x102;
+
+    /// One TS packet, with adaptation field stuffing when `payload` is short
+    fn ts(pid: u16, unit_start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![SYNC_BYTE, (pid >> 8) as u8 | if unit_start { 0x40 } else { 0 }, pid as u8];
+        let stuffing = TS_PACKET - 4 - payload.len();
+        if stuffing == 0 {
+            packet.push(0x10);
+        } else {
+            packet.push(0x30);
+            packet.push(stuffing as u8 - 1);
+            if stuffing > 1 {
+                packet.push(0x00);
+                packet.resize(packet.len() + stuffing - 2, 0xFF);
+            }
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// A video PES header with a 33-bit PTS, `pts` already coded
+    fn pes(pts: [u8; 5], data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        pes.extend_from_slice(&pts);
+        pes.extend_from_slice(data);
+        pes
+    }
+
+    /// PTS 900000 (10 s)
+    const PTS_10S: [u8; 5] = [0x21, 0x00, 0x37, 0x77, 0x41];
+    /// PTS 0x1_0000_0005, past 32 bits
+    const PTS_WRAPPED: [u8; 5] = [0x29, 0x00, 0x01, 0x00, 0x0B];
+
+    fn psi() -> Vec<u8> {
+        [ts(PAT_PID, true, PAT), ts(PMT_PID, true, PMT)].concat()
+    }
+
+    #[test]
+    fn program_tables() {
+        assert_eq!(section(PAT, 0x00).and_then(pat_pmt_pid), Some(PMT_PID));
+        assert_eq!(section(PMT, 0x02).and_then(pmt_video_pid), Some(VIDEO_PID));
+        // A PAT is not a PMT
+        assert_eq!(section(PAT, 0x02), None);
+    }
+
+    #[test]
+    fn pictures_end_at_the_next_unit_start() {
+        let first = pes(PTS_10S, &[0, 0, 0, 1, 0x65, 0xAA]);
+        let mut demuxer = TsDemuxer::default();
+        // The picture spans two packets, split inside its data
+        let input = [
+            psi(),
+            ts(VIDEO_PID, true, &first[..16]),
+            ts(AUDIO_PID, true, &[0x00, 0x00, 0x01, 0xC0]),
+            ts(VIDEO_PID, false, &first[16..]),
+        ]
+        .concat();
+        assert!(demuxer.push(&input).is_empty());
+
+        let second = pes(PTS_WRAPPED, &[0, 0, 1, 0x41, 0xBB]);
+        let pictures = demuxer.push(&ts(VIDEO_PID, true, &second));
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].pts, 900000);
+        assert_eq!(pictures[0].data, [0, 0, 0, 1, 0x65, 0xAA]);
+
+        let pictures = demuxer.push(&ts(VIDEO_PID, true, &pes(PTS_10S, &[])));
+        assert_eq!((pictures[0].pts, pictures[0].data.as_slice()), (5, [0, 0, 1, 0x41, 0xBB].as_slice()));
+    }
+
+    #[test]
+    fn video_before_the_tables_is_ignored() {
+        let mut demuxer = TsDemuxer::default();
+        let early = ts(VIDEO_PID, true, &pes(PTS_10S, &[0, 0, 1, 0x65]));
+        let input = [early.clone(), early, psi()].concat();
+        assert!(demuxer.push(&input).is_empty());
+        assert_eq!(demuxer.video_pid, Some(VIDEO_PID));
+    }
+
+    #[test]
+    fn garbage_between_packets_is_skipped() {
+        let mut demuxer = TsDemuxer::default();
+        let input = [
+            vec![0x00, 0x12],
+            psi(),
+            vec![0xFF; 3],
+            ts(VIDEO_PID, true, &pes(PTS_10S, &[0, 0, 1, 0x65])),
+            ts(VIDEO_PID, true, &pes(PTS_10S, &[0, 0, 1, 0x41])),
+        ]
+        .concat();
+        let pictures = demuxer.push(&input);
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].data, [0, 0, 1, 0x65]);
+    }
+
+    #[test]
+    fn annex_b_nal_units() {
+        let stream = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 0, 1, 0x65, 0x88, 0x00];
+        let nals: Vec<&[u8]> = nal_units(&stream).collect();
+        assert_eq!(nals, [&[0x67, 0x42][..], &[0x68, 0xCE], &[0x65, 0x88]]);
+        assert_eq!(nal_units(&[0xAB, 0xCD]).count(), 0);
+    }
+
+    #[test]
+    fn packetizer_splits_large_nal_units() {
+        let mut packetizer = H264Packetizer { ssrc: 0x01020304, seq: 0xFFFF, max_payload: 8 };
+        let mut idr = vec![0x65];
+        idr.extend(1..=13u8);
+        let data = [&[0, 0, 1, 0x67, 0x42][..], &[0, 0, 1], &idr].concat();
+        let mut out = Vec::new();
+        packetizer.packetize(&Picture { pts: 3000, data }, &mut out);
+
+        let headers: Vec<(u8, u16, u32)> = out
+            .iter()
+            .map(|p| (p[1], u16::from_be_bytes([p[2], p[3]]), u32::from_be_bytes([p[4], p[5], p[6], p[7]])))
+            .collect();
+        assert_eq!(headers, [(96, 0xFFFF, 3000), (96, 0, 3000), (96, 1, 3000), (96, 2, 3000), (96 | 0x80, 3, 3000)]);
+        assert!(out.iter().all(|p| p[8..12] == [1, 2, 3, 4]));
+        // An AUD first, then the SPS whole and the IDR in FU-As of six bytes
+        assert_eq!(&out[0][12..], ACCESS_UNIT_DELIMITER);
+        assert_eq!(&out[1][12..], [0x67, 0x42]);
+        assert_eq!(&out[2][12..], [0x7C, 0x85, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(&out[3][12..], [0x7C, 0x05, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(&out[4][12..], [0x7C, 0x45, 13]);
+    }
+
+    #[test]
+    fn sdp_advertises_h264() {
+        let sdp = "v=0\r\nm=audio 0 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\nm=video 0 RTP/AVP 33\r\na=rtpmap:33 MP2T/90000\r\na=control:track2\r\n";
+        assert_eq!(
+            rewrite_sdp(sdp),
+            "v=0\r\nm=audio 0 RTP/AVP 0\r\na=rtpmap:0 PCMU/8000\r\n\
+             m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=fmtp:96 packetization-mode=1\r\na=control:track2\r\n"
+        );
+    }
+}
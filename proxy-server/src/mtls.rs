@@ -0,0 +1,68 @@
+//! Client certificate (mTLS) authentication for WebTransport, for kiosks and
+//! managed devices that carry a certificate rather than a token.
+//!
+//! Certificates must chain to the configured CA bundle. The identities one
+//! names — its subject CN and DNS, email and URI names — select a tenant
+//! the same way a token does.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::Arc;
+use wtransport::tls::rustls::pki_types::pem::PemObject;
+use wtransport::tls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use wtransport::tls::rustls::server::WebPkiClientVerifier;
+use wtransport::tls::rustls::{version, RootCertStore, ServerConfig as TlsServerConfig};
+use wtransport::tls::{CertificateChain, WEBTRANSPORT_ALPN};
+use wtransport::Identity;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use crate::config::MtlsConfig;
+
+/// TLS config for the WebTransport server, asking for client certificates
+/// when `config` is set
+pub fn tls_config(identity: Identity, config: Option<&MtlsConfig>) -> Result<TlsServerConfig> {
+    let Some(config) = config else {
+        return Ok(wtransport::tls::server::build_default_tls_config(identity));
+    };
+    // The default config is only built for its crypto provider
+    let provider = wtransport::tls::server::build_default_tls_config(identity.clone_identity()).crypto_provider().clone();
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(&config.ca_file)?), provider.clone());
+    let verifier = if config.required { verifier } else { verifier.allow_unauthenticated() };
+    let verifier = verifier.build().context("Invalid mtls.ca_file")?;
+
+    let chain = identity.certificate_chain().as_slice().iter().map(|cert| CertificateDer::from(cert.der().to_vec())).collect();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(identity.private_key().secret_der().to_vec()));
+    let mut tls = TlsServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&version::TLS13])?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(chain, key)?;
+    tls.alpn_protocols = vec![WEBTRANSPORT_ALPN.to_vec()];
+    Ok(tls)
+}
+
+pub fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).with_context(|| format!("Failed to read mtls.ca_file {}", path.display()))? {
+        roots.add(cert?)?;
+    }
+    anyhow::ensure!(!roots.is_empty(), "mtls.ca_file {} holds no certificates", path.display());
+    Ok(roots)
+}
+
+/// The identities the client's certificate names, empty without one
+pub fn client_identities(chain: Option<&CertificateChain>) -> Vec<String> {
+    let Some(leaf) = chain.and_then(|chain| chain.as_slice().first()) else {
+        return Vec::new();
+    };
+    let Ok((_, cert)) = X509Certificate::from_der(leaf.der()) else {
+        return Vec::new();
+    };
+    let mut identities: Vec<String> = cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).map(String::from).collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        identities.extend(san.value.general_names.iter().filter_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+            _ => None,
+        }));
+    }
+    identities
+}
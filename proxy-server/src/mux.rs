@@ -0,0 +1,172 @@
+//! UDP multiplexing: cameras send every track's RTP and RTCP to one fixed
+//! port pair instead of two ephemeral ports per track, for firewalls that
+//! open only a couple of inbound UDP ports.
+//!
+//! Packets are routed to their track by the camera's address and
+//! `server_port`, then by SSRC, then by address alone, and replayed to the
+//! track's own sockets over loopback so they take the usual path from there.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+use crate::config::UdpMuxConfig;
+use crate::filter::SourceValidation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    /// The camera's address and port for this track
+    Addr(SocketAddr),
+    /// The track's SSRC, from the SETUP response
+    Ssrc(u32),
+    /// The camera's address, when it is the only track from there
+    Host(IpAddr),
+}
+
+/// Tracks reachable by each key, by route id. A key shared by several
+/// tracks is ambiguous and routes nothing.
+type Table = Mutex<HashMap<Key, Vec<(u64, SocketAddr)>>>;
+
+struct Mux {
+    rtp: Arc<UdpSocket>,
+    rtcp: Arc<UdpSocket>,
+    rtp_routes: Arc<Table>,
+    rtcp_routes: Arc<Table>,
+}
+
+static MUX: OnceLock<Mux> = OnceLock::new();
+static NEXT_ROUTE: AtomicU64 = AtomicU64::new(0);
+
+/// Bind the shared ports and start routing; without this every track gets
+/// its own ports
+pub async fn init(config: &UdpMuxConfig) -> Result<()> {
+    let rtcp_port = config.port.checked_add(1).context("UDP mux port must leave room for RTCP")?;
+    let rtp = Arc::new(UdpSocket::bind((config.address, config.port)).await.with_context(|| format!("Failed to bind UDP mux port {}", config.port))?);
+    let rtcp = Arc::new(UdpSocket::bind((config.address, rtcp_port)).await.with_context(|| format!("Failed to bind UDP mux port {}", rtcp_port))?);
+    let mux = Mux { rtp, rtcp, rtp_routes: Default::default(), rtcp_routes: Default::default() };
+    tokio::spawn(dispatch(mux.rtp.clone(), mux.rtp_routes.clone(), false));
+    tokio::spawn(dispatch(mux.rtcp.clone(), mux.rtcp_routes.clone(), true));
+    info!("Receiving camera media on UDP ports {}-{}", config.port, rtcp_port);
+    let _ = MUX.set(mux);
+    Ok(())
+}
+
+/// The RTP and RTCP ports cameras should send to, None when not multiplexing
+pub fn client_ports() -> Option<(u16, u16)> {
+    let mux = MUX.get()?;
+    Some((mux.rtp.local_addr().ok()?.port(), mux.rtcp.local_addr().ok()?.port()))
+}
+
+/// The shared RTCP socket, so the proxy's own RTCP reaches cameras from the
+/// port they send theirs to
+pub fn rtcp_socket() -> Option<Arc<UdpSocket>> {
+    MUX.get().map(|mux| mux.rtcp.clone())
+}
+
+/// Send hole-punching packets from the shared ports, which the camera's
+/// media must come back to
+pub async fn punch(camera: IpAddr, ports: (u16, u16)) {
+    if let Some(mux) = MUX.get() {
+        crate::nat::punch(&mux.rtp, &mux.rtcp, camera, ports).await;
+    }
+}
+
+/// A track's routes, removed when dropped
+pub struct MuxRoute {
+    id: u64,
+}
+
+impl MuxRoute {
+    /// Route the media of a track whose SETUP response had `camera_transport`
+    /// to the sockets bound on `rtp_port` and `rtcp_port`. Strict source
+    /// validation routes by the camera's address and port only.
+    pub fn new(camera_transport: &str, source: IpAddr, validation: SourceValidation, rtp_port: u16, rtcp_port: u16) -> Option<Self> {
+        let mux = MUX.get()?;
+        let id = NEXT_ROUTE.fetch_add(1, Ordering::Relaxed);
+        let server_ports = crate::nat::server_ports(camera_transport);
+        let ssrc = camera_transport
+            .split(';')
+            .find_map(|p| p.trim().strip_prefix("ssrc="))
+            .and_then(|ssrc| u32::from_str_radix(ssrc.trim(), 16).ok());
+        if server_ports.is_none() && ssrc.is_none() {
+            warn!("Camera at {} sent neither server_port nor ssrc; its tracks are told apart by address only", source);
+        }
+
+        for (table, port, rtcp) in [(&mux.rtp_routes, rtp_port, false), (&mux.rtcp_routes, rtcp_port, true)] {
+            let target = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            let addr = server_ports.map(|(rtp, rtcp_port)| Key::Addr(SocketAddr::new(source, if rtcp { rtcp_port } else { rtp })));
+            let mut table = table.lock().unwrap();
+            let keys = match (validation, addr) {
+                (SourceValidation::Strict, Some(addr)) => vec![addr],
+                (SourceValidation::Strict, None) => vec![Key::Host(source)],
+                _ => [addr, ssrc.map(Key::Ssrc), Some(Key::Host(source))].into_iter().flatten().collect(),
+            };
+            for key in keys {
+                table.entry(key).or_default().push((id, target));
+            }
+        }
+        Some(Self { id })
+    }
+}
+
+impl Drop for MuxRoute {
+    fn drop(&mut self) {
+        let Some(mux) = MUX.get() else {
+            return;
+        };
+        for table in [&mux.rtp_routes, &mux.rtcp_routes] {
+            table.lock().unwrap().retain(|_, targets| {
+                targets.retain(|(id, _)| *id != self.id);
+                !targets.is_empty()
+            });
+        }
+    }
+}
+
+/// Receive on a shared port and replay each packet to its track
+async fn dispatch(socket: Arc<UdpSocket>, routes: Arc<Table>, rtcp: bool) {
+    let replay = match UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await {
+        Ok(replay) => replay,
+        Err(e) => {
+            warn!("Failed to bind the UDP mux replay socket: {}", e);
+            return;
+        }
+    };
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                if !crate::udp::is_send_error(&e) {
+                    warn!("UDP mux receive error: {}", e);
+                }
+                continue;
+            }
+        };
+        let packet = &buf[..len];
+        match route(&routes.lock().unwrap(), packet, from, rtcp) {
+            Some(target) => {
+                // Loopback rarely fills up; a packet lost to it is like one lost on UDP
+                let _ = replay.try_send_to(packet, target);
+            }
+            None => crate::stats::global().add("udp_mux_unrouted", 1),
+        }
+    }
+}
+
+/// The track `packet` from `from` belongs to, if exactly one matches
+fn route(table: &HashMap<Key, Vec<(u64, SocketAddr)>>, packet: &[u8], from: SocketAddr, rtcp: bool) -> Option<SocketAddr> {
+    // RTP carries the SSRC after the timestamp; RTCP names its sender first
+    let at = if rtcp { 4 } else { 8 };
+    let ssrc = packet.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let unique = |key: Key| match table.get(&key).map(Vec::as_slice) {
+        Some([(_, target)]) => Some(*target),
+        _ => None,
+    };
+    unique(Key::Addr(from))
+        .or_else(|| ssrc.and_then(|ssrc| unique(Key::Ssrc(ssrc))))
+        .or_else(|| unique(Key::Host(from.ip())))
+}
@@ -0,0 +1,128 @@
+//! Workarounds for cameras and proxies behind NAT
+
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+use crate::rtsp::RtspResponse;
+
+/// Packets sent to each camera port when punching
+const PUNCH_PACKETS: usize = 3;
+
+/// Point the addresses a camera advertises in a DESCRIBE response (SDP
+/// `o=`/`c=` lines, absolute control URLs, `Content-Base`/`Content-Location`)
+/// at the host and port the proxy actually connected to. Cameras behind NAT
+/// often advertise internal IPs that neither the proxy nor the browser can reach.
+pub fn rewrite_describe(resp: &mut RtspResponse, connected: &url::Url) {
+    let Some(host) = connected.host_str() else {
+        return;
+    };
+
+    for header in ["Content-Base", "Content-Location"] {
+        if let Some(value) = resp.headers.get_mut(header) {
+            *value = rewrite_url(value, connected);
+        }
+    }
+
+    // SDP carries IPv6 addresses without the URL brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let text = String::from_utf8_lossy(&resp.body);
+    let addrtype = if host.contains(':') { "IP6" } else { "IP4" };
+    let mut body = String::with_capacity(text.len());
+    let mut changed = false;
+    for line in text.lines() {
+        let rewritten = if let Some(origin) = line.strip_prefix("o=") {
+            // o=<username> <sess-id> <sess-version> IN <addrtype> <address>
+            let mut fields: Vec<&str> = origin.split_whitespace().collect();
+            if fields.len() == 6 {
+                fields[4] = addrtype;
+                fields[5] = host;
+            }
+            format!("o={}", fields.join(" "))
+        } else if let Some(connection) = line.strip_prefix("c=") {
+            rewrite_connection(connection, addrtype, host)
+        } else if let Some(control) = line.strip_prefix("a=control:") {
+            format!("a=control:{}", rewrite_url(control.trim(), connected))
+        } else {
+            line.to_string()
+        };
+        changed |= rewritten != line;
+        body.push_str(&rewritten);
+        body.push_str("\r\n");
+    }
+
+    if changed {
+        info!("Rewrote advertised camera addresses to {}", host);
+        resp.body = body.into_bytes();
+        resp.headers.insert("Content-Length".to_string(), resp.body.len().to_string());
+    }
+}
+
+/// `c=IN <addrtype> <address>[/ttl]`; wildcard and multicast addresses are kept
+fn rewrite_connection(connection: &str, addrtype: &str, host: &str) -> String {
+    let fields: Vec<&str> = connection.split_whitespace().collect();
+    let keep = match fields.get(2).map(|a| a.split('/').next().unwrap_or(a)) {
+        Some(address) => match address.parse::<std::net::IpAddr>() {
+            Ok(ip) => ip.is_unspecified() || ip.is_multicast(),
+            Err(_) => false,
+        },
+        None => true,
+    };
+    if keep {
+        return format!("c={}", connection);
+    }
+    format!("c={} {} {}", fields[0], addrtype, host)
+}
+
+/// Replace the host and port of an absolute RTSP URL; relative URLs are kept
+fn rewrite_url(value: &str, connected: &url::Url) -> String {
+    let Ok(mut url) = url::Url::parse(value) else {
+        return value.to_string();
+    };
+    if url.host_str().is_none() || url.host_str() == connected.host_str() && url.port() == connected.port() {
+        return value.to_string();
+    }
+    if url.set_host(connected.host_str()).is_err() || url.set_port(connected.port()).is_err() {
+        return value.to_string();
+    }
+    url.to_string()
+}
+
+/// `server_port=<rtp>-<rtcp>` from a SETUP response's Transport header
+pub fn server_ports(transport: &str) -> Option<(u16, u16)> {
+    let ports = transport.split(';').find_map(|p| p.trim().strip_prefix("server_port="))?;
+    match ports.split_once('-') {
+        Some((rtp, rtcp)) => Some((rtp.trim().parse().ok()?, rtcp.trim().parse().ok()?)),
+        None => {
+            let rtp: u16 = ports.trim().parse().ok()?;
+            Some((rtp, rtp.checked_add(1)?))
+        }
+    }
+}
+
+/// `source=<address>` from a SETUP response's Transport header: where the
+/// camera sends media from, when that differs from its RTSP address
+pub fn source_address(transport: &str) -> Option<IpAddr> {
+    transport.split(';').find_map(|p| p.trim().strip_prefix("source=")?.trim().parse().ok())
+}
+
+/// Open NAT/firewall mappings towards the camera's RTP and RTCP ports by
+/// sending a few empty packets from the proxy's sockets, like other RTSP
+/// clients do after SETUP: a bare RTP header and an empty RTCP receiver report.
+pub async fn punch(rtp_socket: &UdpSocket, rtcp_socket: &UdpSocket, camera: IpAddr, ports: (u16, u16)) {
+    const RTP: [u8; 12] = [0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    const RTCP: [u8; 8] = [0x80, 201, 0, 1, 0, 0, 0, 0];
+
+    let rtp_addr = SocketAddr::new(camera, ports.0);
+    let rtcp_addr = SocketAddr::new(camera, ports.1);
+    for _ in 0..PUNCH_PACKETS {
+        let sent = async {
+            rtp_socket.send_to(&RTP, rtp_addr).await?;
+            rtcp_socket.send_to(&RTCP, rtcp_addr).await
+        };
+        if let Err(e) = sent.await {
+            warn!("Hole punching towards {} failed: {}", rtp_addr, e);
+            return;
+        }
+    }
+    info!("Sent hole-punching packets to {} and {}", rtp_addr, rtcp_addr);
+}
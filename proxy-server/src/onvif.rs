@@ -0,0 +1,521 @@
+//! ONVIF event bridging: while a session runs, the proxy holds a PullPoint
+//! subscription on the session's camera and forwards motion, tamper and I/O
+//! events to the browser, so alarms can be matched with the video.
+//!
+//! The same client shortens a camera's GOP for `method = "onvif"` keyframe
+//! rules. Only the few SOAP calls involved are spoken, with WS-UsernameToken
+//! authentication and a deliberately small XML reader.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use crate::config::{Credentials, OnvifRule};
+use crate::control::Event;
+
+/// Longest a PullMessages call waits for events
+const PULL_TIMEOUT: Duration = Duration::from_secs(10);
+const MESSAGE_LIMIT: u32 = 16;
+/// Subscription lifetime requested; renewed at half of it
+const SUBSCRIPTION: Duration = Duration::from_secs(60);
+/// Wait before subscribing again after a failure
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Deadline for one SOAP exchange, beyond the pull timeout
+const HTTP_TIMEOUT: Duration = Duration::from_secs(20);
+/// Largest SOAP response read
+const MAX_RESPONSE: u64 = 256 * 1024;
+/// Frame rate assumed for an encoder configuration without a limit
+const DEFAULT_FRAMERATE: f64 = 25.0;
+
+const DEVICE_NS: &str = "http://www.onvif.org/ver10/device/wsdl";
+const EVENTS_NS: &str = "http://www.onvif.org/ver10/events/wsdl";
+const MEDIA_NS: &str = "http://www.onvif.org/ver10/media/wsdl";
+const WSN_NS: &str = "http://docs.oasis-open.org/wsn/b-2";
+
+/// Broad class of an ONVIF event, from its topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnvifKind {
+    Motion,
+    Tamper,
+    Io,
+    Other,
+}
+
+impl OnvifKind {
+    fn for_topic(topic: &str) -> Self {
+        if topic.contains("Motion") {
+            OnvifKind::Motion
+        } else if topic.contains("Tamper") || topic.contains("GlobalSceneChange") {
+            OnvifKind::Tamper
+        } else if topic.contains("DigitalInput") || topic.contains("Relay") || topic.contains("Trigger/") {
+            OnvifKind::Io
+        } else {
+            OnvifKind::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OnvifKind::Motion => "motion",
+            OnvifKind::Tamper => "tamper",
+            OnvifKind::Io => "io",
+            OnvifKind::Other => "other",
+        }
+    }
+}
+
+/// One notification from the camera
+#[derive(Debug, Clone)]
+pub struct OnvifEvent {
+    /// e.g. "tns1:RuleEngine/CellMotionDetector/Motion"
+    pub topic: String,
+    pub kind: OnvifKind,
+    /// Camera's UTC time for the event
+    pub time: Option<String>,
+    /// "Initialized", "Changed" or "Deleted"
+    pub operation: Option<String>,
+    /// What raised the event, e.g. VideoSourceConfigurationToken=1
+    pub source: Vec<(String, String)>,
+    /// Its state, e.g. IsMotion=true
+    pub data: Vec<(String, String)>,
+}
+
+/// Keep a subscription on the camera until `token` is cancelled, resubscribing
+/// after failures
+pub async fn bridge(rule: OnvifRule, rtsp_url: String, credentials: Option<Credentials>, notify: mpsc::Sender<Event>, token: CancellationToken) {
+    let device_url = match device_url(rule.device_url.as_deref(), &rtsp_url) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("ONVIF events disabled for {}: {:#}", rtsp_url, e);
+            return;
+        }
+    };
+    let credentials = credentials.or_else(|| url_credentials(&rtsp_url));
+    let mut client = Client { device_url, credentials, clock_offset: 0 };
+    loop {
+        match client.run(&rule, &notify, &token).await {
+            Ok(()) => return,
+            Err(e) => warn!("ONVIF events from {} failed, retrying in {:?}: {:#}", client.device_url, RETRY_DELAY, e),
+        }
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = tokio::time::sleep(RETRY_DELAY) => {}
+        }
+    }
+}
+
+/// Shorten the GOP of the camera's encoder configurations so keyframes come
+/// at least every `interval`. This changes the camera for all its clients,
+/// and is not undone when the session ends.
+pub async fn limit_gov_length(device_url: Option<String>, rtsp_url: String, credentials: Option<Credentials>, interval: Duration) {
+    let device_url = match self::device_url(device_url.as_deref(), &rtsp_url) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Cannot set the GOP length of {}: {:#}", rtsp_url, e);
+            return;
+        }
+    };
+    let credentials = credentials.or_else(|| url_credentials(&rtsp_url));
+    let mut client = Client { device_url, credentials, clock_offset: 0 };
+    client.clock_offset = client.camera_clock_offset().await.unwrap_or_else(|e| {
+        debug!("Could not read the camera's clock, assuming ours: {:#}", e);
+        0
+    });
+    match client.limit_gov_length(interval).await {
+        Ok(0) => debug!("Encoder configurations at {} already have short enough GOPs", client.device_url),
+        Ok(changed) => info!("Shortened the GOP of {} encoder configuration(s) at {}", changed, client.device_url),
+        Err(e) => warn!("Could not set the GOP length at {}: {:#}", client.device_url, e),
+    }
+}
+
+/// The configured device service URL, or the camera's default one
+fn device_url(configured: Option<&str>, rtsp_url: &str) -> Result<String> {
+    if let Some(url) = configured {
+        return Ok(url.to_string());
+    }
+    let url = url::Url::parse(rtsp_url)?;
+    let host = url.host_str().context("RTSP URL has no host")?;
+    Ok(format!("http://{}/onvif/device_service", host))
+}
+
+fn url_credentials(rtsp_url: &str) -> Option<Credentials> {
+    let url = url::Url::parse(rtsp_url).ok()?;
+    (!url.username().is_empty()).then(|| Credentials {
+        username: url.username().to_string(),
+        password: url.password().unwrap_or_default().to_string(),
+    })
+}
+
+struct Client {
+    device_url: String,
+    credentials: Option<Credentials>,
+    /// Camera clock minus ours, in seconds, so the token's timestamp is accepted
+    clock_offset: i64,
+}
+
+impl Client {
+    /// One subscription: Ok when cancelled, Err when the camera fails
+    async fn run(&mut self, rule: &OnvifRule, notify: &mpsc::Sender<Event>, token: &CancellationToken) -> Result<()> {
+        self.clock_offset = self.camera_clock_offset().await.unwrap_or_else(|e| {
+            debug!("Could not read the camera's clock, assuming ours: {:#}", e);
+            0
+        });
+        let events_url = self.events_url().await?;
+        let pull_point = self.create_pull_point(&events_url).await?;
+        info!("Subscribed to ONVIF events at {}", pull_point);
+
+        let mut renew_at = Instant::now() + SUBSCRIPTION / 2;
+        loop {
+            let pulled = tokio::select! {
+                _ = token.cancelled() => {
+                    let body = format!("<Unsubscribe xmlns=\"{}\"/>", WSN_NS);
+                    let _ = self.call(&pull_point, "http://docs.oasis-open.org/wsn/bw-2/SubscriptionManager/UnsubscribeRequest", &body).await;
+                    return Ok(());
+                }
+                res = self.pull(&pull_point) => res?,
+            };
+            for event in pulled {
+                if !rule.kinds.contains(&event.kind) {
+                    debug!("Ignoring ONVIF event {}", event.topic);
+                    continue;
+                }
+                if notify.send(Event::Onvif(event)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if Instant::now() >= renew_at {
+                let body = format!("<Renew xmlns=\"{}\"><TerminationTime>PT{}S</TerminationTime></Renew>", WSN_NS, SUBSCRIPTION.as_secs());
+                self.call(&pull_point, "http://docs.oasis-open.org/wsn/bw-2/SubscriptionManager/RenewRequest", &body).await?;
+                renew_at = Instant::now() + SUBSCRIPTION / 2;
+            }
+        }
+    }
+
+    async fn camera_clock_offset(&self) -> Result<i64> {
+        let body = format!("<GetSystemDateAndTime xmlns=\"{}\"/>", DEVICE_NS);
+        let xml = self.call(&self.device_url, &format!("{}/GetSystemDateAndTime", DEVICE_NS), &body).await?;
+        let utc = elements(&xml, "UTCDateTime").into_iter().next().context("No UTCDateTime")?;
+        let field = |name| -> Result<i64> {
+            let element = elements(utc, name).into_iter().next().with_context(|| format!("No {}", name))?;
+            Ok(element.trim().parse()?)
+        };
+        let days = days_from_civil(field("Year")?, field("Month")?, field("Day")?);
+        let camera = days * 86400 + field("Hour")? * 3600 + field("Minute")? * 60 + field("Second")?;
+        Ok(camera - unix_now())
+    }
+
+    async fn events_url(&self) -> Result<String> {
+        let body = format!("<GetCapabilities xmlns=\"{}\"><Category>Events</Category></GetCapabilities>", DEVICE_NS);
+        let xml = self.call(&self.device_url, &format!("{}/GetCapabilities", DEVICE_NS), &body).await?;
+        let events = elements(&xml, "Events").into_iter().next().context("Camera has no event service")?;
+        let address = elements(events, "XAddr").into_iter().next().context("Event service has no address")?;
+        Ok(unescape(address.trim()))
+    }
+
+    async fn media_url(&self) -> Result<String> {
+        let body = format!("<GetCapabilities xmlns=\"{}\"><Category>Media</Category></GetCapabilities>", DEVICE_NS);
+        let xml = self.call(&self.device_url, &format!("{}/GetCapabilities", DEVICE_NS), &body).await?;
+        let media = elements(&xml, "Media").into_iter().next().context("Camera has no media service")?;
+        let address = elements(media, "XAddr").into_iter().next().context("Media service has no address")?;
+        Ok(unescape(address.trim()))
+    }
+
+    /// Set `GovLength` to `interval` at the configured frame rate wherever it
+    /// is longer; the number of configurations changed
+    async fn limit_gov_length(&self, interval: Duration) -> Result<usize> {
+        let media_url = self.media_url().await?;
+        let body = format!("<GetVideoEncoderConfigurations xmlns=\"{}\"/>", MEDIA_NS);
+        let xml = self.call(&media_url, &format!("{}/GetVideoEncoderConfigurations", MEDIA_NS), &body).await?;
+        // The configurations are sent back as they came, with the prefixes they use
+        let namespaces: String = tags(&xml)
+            .find(|tag| tag.name == "Envelope")
+            .map(|tag| tag.text.split_whitespace().filter(|a| a.starts_with("xmlns:")).map(|a| format!(" {}", a.trim_end_matches('/'))).collect())
+            .unwrap_or_default();
+        let tokens: Vec<&str> = tags(&xml)
+            .filter(|tag| tag.name == "Configurations" && !tag.closing)
+            .map(|tag| attribute(tag.text, "token").unwrap_or_default())
+            .collect();
+
+        let mut changed = 0;
+        for (token, config) in tokens.into_iter().zip(elements(&xml, "Configurations")) {
+            let framerate = elements(config, "FrameRateLimit")
+                .into_iter()
+                .next()
+                .and_then(|limit| limit.trim().parse::<f64>().ok())
+                .filter(|&fps| fps > 0.0)
+                .unwrap_or(DEFAULT_FRAMERATE);
+            let target = ((framerate * interval.as_secs_f64()) as u32).max(1);
+            let Some(gov) = elements(config, "GovLength").into_iter().next() else {
+                continue;
+            };
+            if gov.trim().parse::<u32>().is_ok_and(|length| length <= target) {
+                continue;
+            }
+            let start = gov.as_ptr() as usize - config.as_ptr() as usize;
+            let updated = format!("{}{}{}", &config[..start], target, &config[start + gov.len()..]);
+            let body = format!(
+                "<SetVideoEncoderConfiguration xmlns=\"{}\"><Configuration token=\"{}\"{}>{}</Configuration>\
+                 <ForcePersistence>false</ForcePersistence></SetVideoEncoderConfiguration>",
+                MEDIA_NS, token, namespaces, updated
+            );
+            self.call(&media_url, &format!("{}/SetVideoEncoderConfiguration", MEDIA_NS), &body).await?;
+            info!("Encoder configuration {} at {}: GovLength {} -> {}", token, self.device_url, gov.trim(), target);
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    async fn create_pull_point(&self, events_url: &str) -> Result<String> {
+        let body = format!(
+            "<CreatePullPointSubscription xmlns=\"{}\"><InitialTerminationTime>PT{}S</InitialTerminationTime></CreatePullPointSubscription>",
+            EVENTS_NS,
+            SUBSCRIPTION.as_secs()
+        );
+        let xml = self.call(events_url, &format!("{}/EventPortType/CreatePullPointSubscriptionRequest", EVENTS_NS), &body).await?;
+        let reference = elements(&xml, "SubscriptionReference").into_iter().next().context("No subscription reference")?;
+        let address = elements(reference, "Address").into_iter().next().context("Subscription has no address")?;
+        Ok(unescape(address.trim()))
+    }
+
+    async fn pull(&self, pull_point: &str) -> Result<Vec<OnvifEvent>> {
+        let body = format!(
+            "<PullMessages xmlns=\"{}\"><Timeout>PT{}S</Timeout><MessageLimit>{}</MessageLimit></PullMessages>",
+            EVENTS_NS,
+            PULL_TIMEOUT.as_secs(),
+            MESSAGE_LIMIT
+        );
+        let xml = self.call(pull_point, &format!("{}/PullPointSubscription/PullMessagesRequest", EVENTS_NS), &body).await?;
+        Ok(elements(&xml, "NotificationMessage").iter().filter_map(|message| notification(message)).collect())
+    }
+
+    /// POST one SOAP request and return the response envelope
+    async fn call(&self, url: &str, action: &str, body: &str) -> Result<String> {
+        let envelope = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\" xmlns:a=\"http://www.w3.org/2005/08/addressing\">\
+             <s:Header>{}<a:Action s:mustUnderstand=\"1\">{}</a:Action><a:To s:mustUnderstand=\"1\">{}</a:To></s:Header>\
+             <s:Body>{}</s:Body></s:Envelope>",
+            self.security_header(),
+            action,
+            escape(url),
+            body
+        );
+        match tokio::time::timeout(HTTP_TIMEOUT, post(url, action, &envelope)).await {
+            Ok(response) => response,
+            Err(_) => Err(anyhow!("No answer from {} within {:?}", url, HTTP_TIMEOUT)),
+        }
+    }
+
+    /// WS-UsernameToken with a password digest, or nothing without credentials
+    fn security_header(&self) -> String {
+        let Some(credentials) = &self.credentials else {
+            return String::new();
+        };
+        let nonce: [u8; 16] = rand::random();
+        let created = format_utc(unix_now() + self.clock_offset);
+        let mut hasher = Sha1::new();
+        hasher.update(nonce);
+        hasher.update(created.as_bytes());
+        hasher.update(credentials.password.as_bytes());
+        let b64 = base64::engine::general_purpose::STANDARD;
+        format!(
+            "<Security s:mustUnderstand=\"1\" xmlns=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd\">\
+             <UsernameToken><Username>{}</Username>\
+             <Password Type=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest\">{}</Password>\
+             <Nonce EncodingType=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary\">{}</Nonce>\
+             <Created xmlns=\"http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd\">{}</Created>\
+             </UsernameToken></Security>",
+            escape(&credentials.username),
+            b64.encode(hasher.finalize()),
+            b64.encode(nonce),
+            created
+        )
+    }
+}
+
+/// Plain HTTP POST; SOAP faults come back as errors with their reason
+async fn post(url: &str, action: &str, envelope: &str) -> Result<String> {
+    let parsed = url::Url::parse(url).with_context(|| format!("Invalid ONVIF URL {}", url))?;
+    if parsed.scheme() != "http" {
+        bail!("ONVIF URL must be http://, not {}", parsed.scheme());
+    }
+    let host = parsed.host_str().context("ONVIF URL has no host")?;
+    let port = parsed.port().unwrap_or(80);
+
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rtsp2browser\r\nContent-Type: application/soap+xml; charset=utf-8; action=\"{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        &parsed[url::Position::BeforePath..],
+        host,
+        action,
+        envelope.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(envelope.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").context("Incomplete ONVIF response")?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) == Some("200") {
+        return Ok(body.to_string());
+    }
+    let reason = elements(body, "Text").into_iter().next().map(|text| unescape(text.trim()));
+    match reason {
+        Some(reason) => bail!("Camera answered {}: {}", status_line, reason),
+        None => bail!("Camera answered {}", status_line),
+    }
+}
+
+/// Event from one wsnt:NotificationMessage
+fn notification(xml: &str) -> Option<OnvifEvent> {
+    let topic = unescape(elements(xml, "Topic").into_iter().next()?.trim());
+    // The tt:Message inside wsnt:Message carries the time and operation
+    let message = tags(xml).find(|tag| tag.name == "Message" && attribute(tag.text, "UtcTime").is_some());
+    let items = |section| {
+        elements(xml, section)
+            .into_iter()
+            .next()
+            .map(|element| {
+                tags(element)
+                    .filter(|tag| tag.name == "SimpleItem" && !tag.closing)
+                    .filter_map(|tag| Some((unescape(attribute(tag.text, "Name")?), unescape(attribute(tag.text, "Value")?))))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    Some(OnvifEvent {
+        kind: OnvifKind::for_topic(&topic),
+        time: message.and_then(|tag| attribute(tag.text, "UtcTime")).map(unescape),
+        operation: message.and_then(|tag| attribute(tag.text, "PropertyOperation")).map(unescape),
+        source: items("Source"),
+        data: items("Data"),
+        topic,
+    })
+}
+
+/// One XML tag, with its namespace prefix dropped from the name
+#[derive(Debug, Clone, Copy)]
+pub struct Tag<'a> {
+    pub name: &'a str,
+    /// Everything between `<` and `>`
+    pub text: &'a str,
+    pub closing: bool,
+    pub self_closing: bool,
+    start: usize,
+    end: usize,
+}
+
+pub fn tags(xml: &str) -> impl Iterator<Item = Tag<'_>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        loop {
+            let open = pos + xml.get(pos..)?.find('<')?;
+            let close = open + xml[open..].find('>')?;
+            pos = close + 1;
+            let text = &xml[open + 1..close];
+            if text.starts_with('?') || text.starts_with('!') {
+                continue;
+            }
+            let qualified = text.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+            return Some(Tag {
+                name: qualified.rsplit(':').next().unwrap_or(qualified),
+                text,
+                closing: text.starts_with('/'),
+                self_closing: text.ends_with('/'),
+                start: open,
+                end: close + 1,
+            });
+        }
+    })
+}
+
+/// Content of the outermost elements with the given local name, in document order
+pub fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut open: Option<Tag> = None;
+    let mut depth = 0;
+    for tag in tags(xml).filter(|tag| tag.name == name) {
+        if tag.self_closing {
+            if depth == 0 {
+                found.push("");
+            }
+        } else if !tag.closing {
+            if depth == 0 {
+                open = Some(tag);
+            }
+            depth += 1;
+        } else if depth > 0 {
+            depth -= 1;
+            if depth == 0 && let Some(start) = open.take() {
+                found.push(&xml[start.end..tag.start]);
+            }
+        }
+    }
+    found
+}
+
+pub fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    for quote in ['"', '\''] {
+        let pattern = format!("{}={}", name, quote);
+        let mut from = 0;
+        while let Some(at) = tag[from..].find(&pattern).map(|i| from + i) {
+            from = at + pattern.len();
+            if tag[..at].ends_with(char::is_whitespace) {
+                let end = tag[from..].find(quote)?;
+                return Some(&tag[from..from + end]);
+            }
+        }
+    }
+    None
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+/// "2024-05-01T12:00:00Z" for a Unix time
+fn format_utc(unix: i64) -> String {
+    let (days, secs) = (unix.div_euclid(86400), unix.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
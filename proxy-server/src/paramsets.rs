@@ -0,0 +1,144 @@
+//! SPS/PPS injection for H.264 cameras that send their parameter sets only
+//! once at the start of the stream, or only in the SDP's
+//! `sprop-parameter-sets`. A browser decoder that joins mid-stream, or lost
+//! the packets that carried them, cannot start on the next IDR without
+//! them, so they are sent ahead of IDRs that arrive without them, as single
+//! NAL unit packets.
+//!
+//! Injected packets take sequence numbers of their own; every later packet
+//! of the track is renumbered to make room for them.
+
+use bytes::{Bytes, BytesMut};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use crate::config::ParameterSetsConfig;
+use crate::sdp::MediaDescription;
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const FU_A: u8 = 28;
+
+/// Keeps an H.264 track's latest parameter sets and sends them ahead of IDRs
+#[derive(Debug)]
+pub struct ParameterSetInjector {
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// None sends them ahead of the first IDR only
+    repeat: Option<Duration>,
+    /// When the browser last got parameter sets, in band or injected
+    last_sent: Option<Instant>,
+    /// RTP timestamp of the picture the camera last sent them with
+    in_band: Option<u32>,
+    /// Added to every sequence number
+    seq_offset: u16,
+}
+
+impl ParameterSetInjector {
+    /// None when injection is off or the track is not H.264. The SDP's
+    /// parameter sets are used until the camera sends its own.
+    pub fn new(media: &MediaDescription, config: &ParameterSetsConfig) -> Option<Self> {
+        if !config.inject || !media.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("H264")) {
+            return None;
+        }
+        let sets = media.parameter_sets();
+        let find = |kind| sets.iter().find(|nal| nal[0] & 0x1F == kind).cloned();
+        Some(Self {
+            sps: find(NAL_SPS),
+            pps: find(NAL_PPS),
+            repeat: (config.repeat_ms > 0).then(|| Duration::from_millis(config.repeat_ms)),
+            last_sent: None,
+            in_band: None,
+            seq_offset: 0,
+        })
+    }
+
+    /// Packets to send for one RTP packet, parameter sets first if it starts
+    /// an IDR that needs them. None sends the packet unchanged.
+    pub fn push(&mut self, packet: &[u8]) -> Option<Vec<Bytes>> {
+        let mut out = Vec::new();
+        if let Some(payload) = crate::rtp_header::payload(packet).filter(|p| !p.is_empty()) {
+            let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            self.learn(payload, timestamp);
+            if starts_idr(payload)
+                && self.needs_sets(timestamp)
+                && let (Some(sps), Some(pps)) = (&self.sps, &self.pps)
+            {
+                let seq = u16::from_be_bytes([packet[2], packet[3]]);
+                for nal in [sps, pps] {
+                    out.push(parameter_set_packet(packet, seq.wrapping_add(self.seq_offset), nal));
+                    self.seq_offset = self.seq_offset.wrapping_add(1);
+                }
+                debug!("Injected SPS and PPS ahead of an IDR");
+                crate::stats::global().add("parameter_sets_injected", 1);
+                self.last_sent = Some(Instant::now());
+            }
+        }
+        if out.is_empty() && self.seq_offset == 0 {
+            return None;
+        }
+        let mut renumbered = BytesMut::from(packet);
+        if renumbered.len() >= 12 {
+            let seq = u16::from_be_bytes([packet[2], packet[3]]).wrapping_add(self.seq_offset);
+            renumbered[2..4].copy_from_slice(&seq.to_be_bytes());
+        }
+        out.push(renumbered.freeze());
+        Some(out)
+    }
+
+    /// Switch to parameter sets the camera announced, sending them ahead of
+    /// the next IDR
+    pub fn replace(&mut self, parameter_sets: &[Vec<u8>]) {
+        let find = |kind| parameter_sets.iter().find(|nal| nal[0] & 0x1F == kind).cloned();
+        self.sps = find(NAL_SPS).or(self.sps.take());
+        self.pps = find(NAL_PPS).or(self.pps.take());
+        self.last_sent = None;
+        self.in_band = None;
+    }
+
+    /// Keep parameter sets the camera sends in band, which replace the SDP's
+    fn learn(&mut self, payload: &[u8], timestamp: u32) {
+        let mut found = false;
+        for nal in crate::h264::rtp_nal_units(payload) {
+            match nal[0] & 0x1F {
+                NAL_SPS => self.sps = Some(nal.to_vec()),
+                NAL_PPS => self.pps = Some(nal.to_vec()),
+                _ => continue,
+            }
+            found = true;
+        }
+        if found {
+            self.in_band = Some(timestamp);
+            self.last_sent = Some(Instant::now());
+        }
+    }
+
+    fn needs_sets(&self, timestamp: u32) -> bool {
+        if self.in_band == Some(timestamp) {
+            return false;
+        }
+        match self.last_sent {
+            None => true,
+            Some(sent) => self.repeat.is_some_and(|repeat| sent.elapsed() >= repeat),
+        }
+    }
+}
+
+/// Whether an RTP payload carries the start of an IDR picture
+fn starts_idr(payload: &[u8]) -> bool {
+    match payload[0] & 0x1F {
+        FU_A => payload.get(1).is_some_and(|fu| fu & 0x80 != 0 && fu & 0x1F == NAL_IDR),
+        _ => crate::h264::rtp_nal_units(payload).iter().any(|nal| nal[0] & 0x1F == NAL_IDR),
+    }
+}
+
+/// A single NAL unit packet for `nal`, on the timestamp and SSRC of `packet`
+fn parameter_set_packet(packet: &[u8], seq: u16, nal: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(12 + nal.len());
+    // Plain header: no padding, extension or CSRCs, marker clear
+    out.extend_from_slice(&[0x80, packet[1] & 0x7F]);
+    out.extend_from_slice(&seq.to_be_bytes());
+    out.extend_from_slice(&packet[4..12]);
+    out.extend_from_slice(nal);
+    out.freeze()
+}
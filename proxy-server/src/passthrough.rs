@@ -0,0 +1,57 @@
+//! Strict pass-through for servers that reject reconstructed requests: the
+//! browser's bytes are relayed as-is, except for the Transport header
+
+/// The client's Transport directives, asking for UDP to the proxy's ports
+/// instead of its own; everything else is kept in order
+pub fn transport(value: &str, rtp_port: u16, rtcp_port: u16) -> String {
+    value
+        .split(',')
+        .map(|spec| {
+            let mut params: Vec<String> = Vec::new();
+            for (i, param) in spec.split(';').enumerate() {
+                let name = param.trim().split('=').next().unwrap_or_default();
+                if i == 0 {
+                    // RTP/AVP/TCP becomes RTP/AVP (UDP)
+                    params.push(param.replace("/TCP", ""));
+                } else if name.eq_ignore_ascii_case("client_port") {
+                    params.push(format!("client_port={}-{}", rtp_port, rtcp_port));
+                } else if !name.eq_ignore_ascii_case("interleaved") {
+                    params.push(param.to_string());
+                }
+            }
+            if !params.iter().any(|p| p.trim().to_ascii_lowercase().starts_with("client_port=")) {
+                params.push(format!("client_port={}-{}", rtp_port, rtcp_port));
+            }
+            params.join(";")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// `raw` with the value of its Transport header replaced, every other byte
+/// (header order, casing, spacing, line endings, body) unchanged
+pub fn replace_transport(raw: &[u8], transport: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len() + transport.len());
+    let mut pos = 0;
+    while let Some(len) = raw[pos..].iter().position(|&b| b == b'\n') {
+        let line = &raw[pos..pos + len + 1];
+        pos += len + 1;
+        let content = line.strip_suffix(b"\r\n").or_else(|| line.strip_suffix(b"\n")).unwrap_or(line);
+        if content.is_empty() {
+            out.extend_from_slice(line);
+            break;
+        }
+        match content.iter().position(|&b| b == b':') {
+            Some(colon) if content[..colon].trim_ascii().eq_ignore_ascii_case(b"Transport") => {
+                // Keep the name and the whitespace after the colon as sent
+                let value_start = colon + 1 + content[colon + 1..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+                out.extend_from_slice(&content[..value_start]);
+                out.extend_from_slice(transport.as_bytes());
+                out.extend_from_slice(&line[content.len()..]);
+            }
+            _ => out.extend_from_slice(line),
+        }
+    }
+    out.extend_from_slice(&raw[pos..]);
+    out
+}
@@ -0,0 +1,322 @@
+//! `validate --config <file>`: everything that would stop the proxy from
+//! starting, or make it quietly do less than the file says, checked without
+//! serving, so a bad config fails in CI or before a deploy rather than on
+//! the host. Each problem is printed as one `error:` or `warning:` line.
+
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use crate::config::{Config, KeyframeMethod};
+
+/// How long a `[[warm]]` camera gets to answer DESCRIBE
+const CAMERA_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn error(&mut self, message: impl Display) {
+        println!("error: {}", message);
+        self.errors += 1;
+    }
+
+    fn warning(&mut self, message: impl Display) {
+        println!("warning: {}", message);
+        self.warnings += 1;
+    }
+
+    fn ok(&self, message: impl Display) {
+        println!("ok: {}", message);
+    }
+}
+
+/// Check the config at `path`; an error if anything in it would fail
+pub async fn run(path: &Path, reach_cameras: bool) -> Result<()> {
+    let mut report = Report::default();
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            report.error(format!("{:#}", e));
+            bail!("{} is not a valid config", path.display());
+        }
+    };
+    report.ok(format!("{} parses", path.display()));
+
+    for problem in config.unsupported() {
+        report.warning(problem);
+    }
+    policies(&config, &mut report);
+    certificates(&config, &mut report);
+    ports(&config, &mut report).await;
+    if reach_cameras {
+        cameras(&config, &mut report).await;
+    }
+
+    println!("{} error(s), {} warning(s)", report.errors, report.warnings);
+    if report.errors > 0 {
+        bail!("{} has {} error(s)", path.display(), report.errors);
+    }
+    Ok(())
+}
+
+/// Settings that contradict each other or can never take effect
+fn policies(config: &Config, report: &mut Report) {
+    if config.streams_only && config.stream.is_empty() {
+        report.error("streams_only is set without any [[stream]]; every session would be refused");
+    }
+    for name in duplicates(config.stream.iter().map(|stream| stream.name.as_str())) {
+        report.error(format!("[[stream]] '{}' is configured more than once", name));
+    }
+    for stream in &config.stream {
+        for url in std::iter::once(&stream.url).chain(&stream.sub) {
+            if let Err(e) = url::Url::parse(url) {
+                report.error(format!("[[stream]] '{}': invalid URL {}: {}", stream.name, url, e));
+            }
+        }
+    }
+    if let Some(rtsp) = &config.rtsp_server {
+        for name in duplicates(rtsp.stream.iter().map(|stream| stream.name.as_str())) {
+            report.error(format!("[[rtsp_server.stream]] '{}' is configured more than once", name));
+        }
+    }
+    for camera in &config.warm {
+        if let Err(e) = url::Url::parse(&camera.url) {
+            report.error(format!("[[warm]] invalid URL {}: {}", camera.url, e));
+        }
+    }
+
+    for name in duplicates(config.tenant.iter().map(|tenant| tenant.name.as_str())) {
+        report.warning(format!("[[tenant]] '{}' is configured more than once; metrics and logs mix them", name));
+    }
+    if !duplicates(config.tenant.iter().filter_map(|tenant| tenant.token.as_deref())).is_empty() {
+        report.error("Two [[tenant]]s share a token; clients sending it always get the first");
+    }
+    for tenant in &config.tenant {
+        if tenant.token.is_none() && tenant.client_cert.is_none() && tenant.path_prefix.is_none() {
+            report.warning(format!("[[tenant]] '{}' has no token, client_cert or path_prefix; no client can select it", tenant.name));
+        }
+    }
+
+    if let Some(cluster) = &config.cluster {
+        for peer in &cluster.peers {
+            match url::Url::parse(peer) {
+                Ok(url) if url.scheme() == "https" && url.host_str().is_some() => {}
+                Ok(url) => report.error(format!("cluster.peers must be https:// URLs with a host, not {}; the secret is only sent over TLS", url)),
+                Err(e) => report.error(format!("cluster.peers: {} is not a URL: {}", peer, e)),
+            }
+        }
+        if config.wss.is_none() {
+            report.error("[cluster] is set without [wss]; peers look up sessions and relay connections on the [wss] port");
+        }
+    }
+    if config.allowed_origins.is_empty() {
+        report.warning("allowed_origins is empty; any web page can open sessions");
+    }
+    if config.drain.is_some() && config.admin.is_none() {
+        report.error("[drain] is set without an [admin] token; draining can never be started");
+    }
+    if let Some(authorizer) = &config.authorizer {
+        http_url("authorizer.url", &authorizer.url, report);
+    }
+    if let Some(health) = &config.health {
+        if let Some(webhook) = &health.webhook {
+            http_url("health.webhook", webhook, report);
+        }
+        let thresholds = [
+            ("alert_below", health.alert_below),
+            ("transcode_below", health.transcode_below),
+            ("reconnect_below", health.reconnect_below),
+        ];
+        for (name, below) in thresholds {
+            if let Some(below) = below.filter(|below| *below > 100) {
+                report.error(format!("health.{} is {}; scores go from 0 to 100", name, below));
+            }
+        }
+        if thresholds.iter().all(|(_, below)| below.is_none()) {
+            report.warning("[health] has no thresholds; sessions are scored but nothing is done");
+        }
+        if health.transcode_below.is_some() && !config.transcode.iter().any(|rule| rule.fallback) {
+            report.warning("health.transcode_below is set but no [[transcode]] rule has fallback = true; it is never acted on");
+        }
+    }
+    for rule in &config.keyframes {
+        if rule.method == KeyframeMethod::Transcode && config.transcode_for(&rule.url_prefix).is_none() {
+            report.warning(format!(
+                "[[keyframes]] rule \"{}\" leaves keyframes to the transcoder but no [[transcode]] rule matches it",
+                rule.url_prefix
+            ));
+        }
+    }
+
+    shadowed("[[quirks]]", config.quirks.iter().map(|rule| rule.url_prefix.as_str()), report);
+    shadowed("[[onvif]]", config.onvif.iter().map(|rule| rule.url_prefix.as_str()), report);
+    shadowed("[[keyframes]]", config.keyframes.iter().map(|rule| rule.url_prefix.as_str()), report);
+    for fallback in [false, true] {
+        let prefixes = config.transcode.iter().filter(|rule| rule.fallback == fallback).map(|rule| rule.url_prefix.as_str());
+        shadowed("[[transcode]]", prefixes, report);
+    }
+    shadowed("[[upstream.relay]]", config.upstream.relay.iter().map(|rule| rule.url_prefix.as_str()), report);
+}
+
+/// Values that appear more than once, each reported once
+fn duplicates<'a>(values: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    let mut repeated = Vec::new();
+    for value in values {
+        if !seen.insert(value) && !repeated.contains(&value) {
+            repeated.push(value);
+        }
+    }
+    repeated
+}
+
+/// Prefix rules apply first match first, so a rule whose prefix extends an
+/// earlier one's never applies
+fn shadowed<'a>(section: &str, prefixes: impl Iterator<Item = &'a str>, report: &mut Report) {
+    let prefixes: Vec<&str> = prefixes.collect();
+    for (i, prefix) in prefixes.iter().enumerate() {
+        if let Some(earlier) = prefixes[..i].iter().find(|earlier| prefix.starts_with(**earlier)) {
+            report.warning(format!("{} rule \"{}\" never applies: the earlier rule \"{}\" matches first", section, prefix, earlier));
+        }
+    }
+}
+
+/// Endpoints posted to with the built-in HTTP/1.0 client
+fn http_url(setting: &str, value: &str, report: &mut Report) {
+    match url::Url::parse(value) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => {}
+        Ok(url) => report.error(format!("{} must be an http:// URL with a host, not {}", setting, url)),
+        Err(e) => report.error(format!("{} is not a URL: {}", setting, e)),
+    }
+}
+
+/// The files the TLS setup reads at startup
+fn certificates(config: &Config, report: &mut Report) {
+    let (cert_file, key_file) = (Path::new(crate::CERT_PEMFILE), Path::new(crate::KEY_PEMFILE));
+    let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+    match (cert_file.exists(), key_file.exists()) {
+        (false, _) if cfg!(feature = "webtransport") => {
+            report.ok(format!("{} not found; a self-signed identity is generated at startup", crate::CERT_PEMFILE))
+        }
+        (false, _) if config.wss.is_some() => report.error(format!("[wss] needs {} and {}", crate::CERT_PEMFILE, crate::KEY_PEMFILE)),
+        (false, _) => {}
+        (true, false) => report.error(format!("{} exists but its key {} does not", crate::CERT_PEMFILE, crate::KEY_PEMFILE)),
+        (true, true) => match crate::sni::load_pair(cert_file, key_file, "the default certificate", &provider) {
+            Ok(_) => report.ok(format!("{} and {} load", crate::CERT_PEMFILE, crate::KEY_PEMFILE)),
+            Err(e) => report.error(format!("{:#}", e)),
+        },
+    }
+    for certificate in &config.certificate {
+        match crate::sni::load_one(certificate, &provider) {
+            Ok(_) => report.ok(format!("certificate for {} loads", certificate.hostname)),
+            Err(e) => report.error(format!("{:#}", e)),
+        }
+    }
+    let hostnames: Vec<String> = config.certificate.iter().map(|certificate| certificate.hostname.to_ascii_lowercase()).collect();
+    for hostname in duplicates(hostnames.iter().map(String::as_str)) {
+        report.warning(format!("[[certificate]] {} is configured more than once; the last one is served", hostname));
+    }
+    #[cfg(feature = "webtransport")]
+    if let Some(mtls) = &config.mtls {
+        match crate::mtls::load_roots(&mtls.ca_file) {
+            Ok(roots) => report.ok(format!("mtls.ca_file {} holds {} CA(s)", mtls.ca_file.display(), roots.len())),
+            Err(e) => report.error(format!("{:#}", e)),
+        }
+    }
+    // A missing key file is created at startup, so only an existing one is read
+    #[cfg(feature = "webtransport")]
+    if let Some(path) = config.resumption.ticket_key_file.as_ref().filter(|path| path.exists()) {
+        match std::fs::read(path) {
+            Ok(key) if key.len() == crate::resumption::KEY_LEN => {}
+            Ok(_) => report.error(format!("Ticket key file {} must hold exactly {} bytes", path.display(), crate::resumption::KEY_LEN)),
+            Err(e) => report.error(format!("Failed to read ticket key file {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        })
+    }
+}
+
+/// Whether every port the proxy listens on is free, and used once
+async fn ports(config: &Config, report: &mut Report) {
+    let any = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let mut listeners = vec![("WebSocket", Protocol::Tcp, SocketAddr::new(any, crate::WEBSOCKET_PORT))];
+    if cfg!(feature = "webtransport") {
+        listeners.insert(0, ("WebTransport", Protocol::Udp, SocketAddr::new(any, crate::WEBTRANSPORT_PORT)));
+    }
+    if let Some(tcp) = &config.tcp {
+        listeners.push(("[tcp]", Protocol::Tcp, SocketAddr::new(tcp.address, tcp.port)));
+    }
+    if let Some(wss) = &config.wss {
+        listeners.push(("[wss]", Protocol::Tcp, SocketAddr::new(wss.address, wss.port)));
+    }
+    if let Some(rtsp) = &config.rtsp_server {
+        listeners.push(("[rtsp_server]", Protocol::Tcp, SocketAddr::new(rtsp.address, rtsp.port)));
+    }
+    if let Some(udp_mux) = &config.udp_mux {
+        listeners.push(("[udp_mux] RTP", Protocol::Udp, SocketAddr::new(udp_mux.address, udp_mux.port)));
+        match udp_mux.port.checked_add(1) {
+            Some(port) => listeners.push(("[udp_mux] RTCP", Protocol::Udp, SocketAddr::new(udp_mux.address, port))),
+            None => report.error("udp_mux.port is 65535; RTCP needs the next port"),
+        }
+    }
+
+    for (i, (name, protocol, addr)) in listeners.iter().enumerate() {
+        let clash = listeners[..i].iter().find(|(_, other_protocol, other)| {
+            other_protocol == protocol
+                && other.port() == addr.port()
+                && (other.ip() == addr.ip() || other.ip().is_unspecified() || addr.ip().is_unspecified())
+        });
+        if let Some((other, _, _)) = clash {
+            report.error(format!("{} and {} both listen on {} port {}", other, name, protocol, addr.port()));
+            continue;
+        }
+        let bound = match protocol {
+            Protocol::Tcp => TcpListener::bind(*addr).await.map(drop),
+            Protocol::Udp => UdpSocket::bind(*addr).await.map(drop),
+        };
+        match bound {
+            Ok(()) => report.ok(format!("{} can bind {} {}", name, protocol, addr)),
+            Err(e) => report.error(format!("{} cannot bind {} {}: {}", name, protocol, addr, e)),
+        }
+    }
+}
+
+/// Whether each `[[warm]]` camera answers DESCRIBE
+async fn cameras(config: &Config, report: &mut Report) {
+    if config.warm.is_empty() {
+        report.ok("no [[warm]] cameras to reach");
+    }
+    for camera in &config.warm {
+        if let Some(edge) = config.upstream.relay_for(&camera.url) {
+            report.warning(format!("{} is reached through edge '{}', which only connects to a running proxy; not checked", camera.url, edge));
+            continue;
+        }
+        match tokio::time::timeout(CAMERA_TIMEOUT, crate::warm::probe(camera, config)).await {
+            Ok(Ok(200)) => report.ok(format!("{} answers DESCRIBE", camera.url)),
+            // Typically 401: sessions send their own DESCRIBE with credentials
+            Ok(Ok(status)) => report.warning(format!("{} answers DESCRIBE with {}", camera.url, status)),
+            Ok(Err(e)) => report.error(format!("{} is unreachable: {:#}", camera.url, e)),
+            Err(_) => report.error(format!("{} did not answer within {:?}", camera.url, CAMERA_TIMEOUT)),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! `GET /probe?rtsp=<url>`: check a camera URL without starting a session
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use crate::config::{Config, Credentials, QuirkRule, UpstreamConfig};
+use crate::http::{Request, Response};
+use crate::rtsp_client::RtspClient;
+use crate::sdp::{SessionDescription, StreamInfo};
+
+/// Upper bound on the whole OPTIONS + DESCRIBE exchange
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a probe found out about a camera
+#[derive(Debug, Default, Serialize)]
+pub struct ProbeResult {
+    /// The proxy could connect and got an RTSP response
+    pub reachable: bool,
+    /// The camera asked for credentials
+    pub auth_required: bool,
+    /// DESCRIBE succeeded, with the tenant's credentials if it has any
+    pub authorized: bool,
+    /// Status of the DESCRIBE response
+    pub status: Option<u16>,
+    pub server: Option<String>,
+    pub methods: Vec<String>,
+    pub tracks: Vec<StreamInfo>,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Answer a probe request, applying the same tenant policy as sessions.
+/// Without tenants, probing needs the admin token.
+pub async fn handle(request: &Request, config: &Config) -> Response {
+    // Otherwise anyone reaching the port could make the proxy connect anywhere
+    if config.tenant.is_empty()
+        && let Err(refused) = crate::http::require_admin(request, config)
+    {
+        return refused;
+    }
+    let params = request.query();
+    let Some(url) = params.get("rtsp") else {
+        return Response::json("400 Bad Request", &json!({ "error": "Missing 'rtsp' query parameter" }));
+    };
+    let token = crate::http::bearer_token(request);
+    let tenant = match config.tenant_for(request.path(), token.as_deref(), &[]) {
+        Ok(tenant) => tenant,
+        Err(e) => return Response::json("401 Unauthorized", &json!({ "error": format!("{:#}", e) })),
+    };
+    if let Some(tenant) = tenant
+        && !tenant.allows(url)
+    {
+        warn!("Tenant '{}' may not probe {}", tenant.name, url);
+        return Response::json("403 Forbidden", &json!({ "error": format!("Tenant '{}' may not open {}", tenant.name, url) }));
+    }
+
+    let credentials = tenant.and_then(|t| t.credentials_for(url));
+    let quirks = config.quirks_for(url).cloned();
+    let result = probe(url, credentials, quirks, &config.upstream).await;
+    info!("Probed {}: reachable={} authorized={}", url, result.reachable, result.authorized);
+    match serde_json::to_value(&result) {
+        Ok(body) => Response::json("200 OK", &body),
+        Err(e) => Response::json("500 Internal Server Error", &json!({ "error": e.to_string() })),
+    }
+}
+
+/// OPTIONS + DESCRIBE against the camera, within [`PROBE_TIMEOUT`]
+pub async fn probe(url: &str, credentials: Option<Credentials>, quirks: Option<QuirkRule>, upstream: &UpstreamConfig) -> ProbeResult {
+    let started = Instant::now();
+    let mut result = ProbeResult::default();
+    let outcome = tokio::time::timeout(PROBE_TIMEOUT, run(url, credentials, quirks, upstream, &mut result)).await;
+    result.error = match outcome {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{:#}", e)),
+        Err(_) => Some(format!("Timed out after {:?}", PROBE_TIMEOUT)),
+    };
+    result.elapsed_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+async fn run(
+    url: &str,
+    credentials: Option<Credentials>,
+    quirks: Option<QuirkRule>,
+    upstream: &UpstreamConfig,
+    result: &mut ProbeResult,
+) -> Result<()> {
+    let parsed = url::Url::parse(url)?;
+    let mut client = RtspClient::connect(&parsed, upstream).await?.with_credentials(credentials).with_quirks(quirks);
+
+    let options = client.request("OPTIONS", url, &[]).await?;
+    result.reachable = true;
+    result.server = options.headers.get("Server").cloned();
+    if let Some(public) = options.headers.get("Public") {
+        result.methods = crate::capabilities::Capabilities::from_public(public).methods;
+    }
+
+    let describe = client.request("DESCRIBE", url, &[("Accept", "application/sdp".to_string())]).await?;
+    result.status = Some(describe.status_code);
+    result.auth_required = client.challenged();
+    result.authorized = (200..300).contains(&describe.status_code);
+    if result.server.is_none() {
+        result.server = describe.headers.get("Server").cloned();
+    }
+    if !result.authorized {
+        return Ok(());
+    }
+
+    let sdp = SessionDescription::parse(&String::from_utf8_lossy(&describe.body));
+    result.tracks = sdp.media.iter().map(|media| media.info()).collect();
+    Ok(())
+}
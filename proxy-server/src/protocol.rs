@@ -0,0 +1,1012 @@
+//! The wire protocol between proxy and browser as types: the query
+//! parameters of the connection handshake, the JSON control envelopes
+//! (`protocol=json`) and the framing of media datagrams.
+//!
+//! The envelopes are the serde types the proxy reads and writes, so they
+//! cannot drift from what it does; each comes with a [`Shape`] beside it,
+//! from which `--dump-protocol schema|typescript` generates definitions
+//! for third-party clients. Nothing here depends on the rest of the proxy,
+//! so a Rust client can include the file as it is.
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Browser request to the proxy itself, sent as a "command" envelope, e.g.
+/// `{"type":"command","command":"rewind","seconds":10}`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Reply with a "stats" envelope for this session
+    Stats,
+    /// Hold the picture while the time-shift buffer keeps recording
+    PauseLive,
+    /// Replay from this far behind the current position
+    Rewind { seconds: u64 },
+    /// Continue from where playback was paused
+    Resume,
+    /// Drop back to live
+    Live,
+    /// Managed mode: start playback, or continue it after a pause
+    Play,
+    /// Managed mode: pause the camera's stream
+    Pause,
+    /// Managed mode: play from this many seconds into the stream
+    Seek { seconds: f64 },
+    /// Managed mode: play at this multiple of normal speed, as the camera's
+    /// `Scale` header (negative plays backwards)
+    Scale { scale: f64 },
+    /// Managed mode: deliver at this multiple of the normal rate, as the
+    /// camera's `Speed` header
+    Speed { speed: f64 },
+    /// Managed mode: choose whether a track is set up, before the first play
+    SelectTrack { track: usize, selected: bool },
+}
+
+/// Messages the browser may send in JSON mode
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Rtsp { data: String },
+    Command {
+        #[serde(flatten)]
+        command: Command,
+    },
+}
+
+/// Messages the proxy sends in JSON mode, one per line
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// An RTSP message from the camera
+    Rtsp { data: String },
+    /// An asynchronous notification
+    Event(EventMessage),
+    /// Answer to a `stats` command
+    Stats {
+        uptime_ms: u64,
+        bytes_sent: u64,
+        profile: &'static str,
+        stalled: bool,
+        /// Milliseconds to reach each startup phase, null until reached
+        startup: Map<String, Value>,
+        channels: Value,
+        /// The client's estimated bandwidth, null until it is known
+        estimate_kbps: Option<u32>,
+    },
+    /// Simple and managed mode: the tracks being played or offered
+    Tracks { tracks: Vec<Value> },
+    /// Managed mode: playback was paused or continued
+    State { state: &'static str },
+    /// Managed mode: the playback rate the camera granted after a `scale`
+    /// or `speed` command, 1 where it ignored the request
+    Rate { scale: f64, speed: f64 },
+    /// A command, or in simple mode the whole session, failed
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        command: Option<&'static str>,
+        error: String,
+    },
+}
+
+/// Asynchronous notification from the proxy, inside an "event" message and
+/// told apart by its `event` name
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventMessage {
+    /// Wall-clock time, in Unix milliseconds, of an RTP timestamp of a track
+    Sync { channel: u8, wallclock: f64, rtp: u32, clock_rate: u32, measured_rate: Option<f64> },
+    /// Methods the camera supports and what its SDP says about the streams
+    Capabilities { pause: bool, get_parameter: bool, set_parameter: bool, methods: Vec<String>, streams: Vec<StreamInfo> },
+    Stall { idle_ms: u64 },
+    Resume,
+    Profile { profile: &'static str },
+    Rendition { name: String },
+    Expired { reason: &'static str },
+    Audio { channel: u8, level_dbfs: f32, voice: bool },
+    Motion { channel: u8, active: bool, cue: &'static str },
+    Onvif {
+        kind: &'static str,
+        topic: String,
+        time: Option<String>,
+        operation: Option<String>,
+        source: BTreeMap<String, String>,
+        data: BTreeMap<String, String>,
+    },
+    Timeshift { state: &'static str, behind_ms: u64 },
+    Objects { channel: u8, time: Option<String>, objects: Vec<DetectedObject> },
+    Draining { alternate: Option<String> },
+    UpstreamError { method: String, code: u16, class: String, reason: String },
+    Carousel { index: usize, count: usize },
+    Skip { channel: u8, behind_ms: u64 },
+    CodecConfigChanged { channel: u8, cause: &'static str, profile_level_id: Option<String>, stream: StreamInfo },
+    /// Channel IDs of the track whose SETUP response, with this CSeq, follows
+    Channels { cseq: String, rtp_channel: u8, rtcp_channel: u8 },
+    Qos { action: &'static str, reason: &'static str, priority: &'static str },
+    CameraBusy { limit: usize, wait_ms: u64 },
+    EndOfStream { cause: &'static str },
+    Discontinuity { npt: f64 },
+    Health { score: u8, action: &'static str },
+}
+
+/// What a track's SDP says about its stream, for players to show before
+/// the first frame arrives
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StreamInfo {
+    pub kind: String,
+    pub codec: Option<String>,
+    pub clock_rate: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+    pub bitrate_kbps: Option<u32>,
+    /// Codec profile ("high", "main-10", ...)
+    pub profile: Option<String>,
+    /// Codec level ("4.1")
+    pub level: Option<String>,
+}
+
+/// One detected object of an analytics frame
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedObject {
+    pub id: String,
+    /// Best class candidate, e.g. "Human" or "Vehicle"
+    pub class: Option<String>,
+    pub likelihood: Option<f32>,
+    #[serde(rename = "box")]
+    pub bbox: Option<BoundingBox>,
+}
+
+/// Bounding box in the camera's coordinates; ONVIF normalizes them to [-1, 1]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BoundingBox {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+/// Layout of the channel ID and RTP packet inside each media payload,
+/// requested by the client with the `framing` query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// `[channel][packet]`
+    #[default]
+    Prefix,
+    /// `[channel][u16 length][packet]`: an RFC 4571 length field after the channel
+    Rfc4571,
+    /// `[varint channel][packet]`: the channel as a QUIC variable-length
+    /// integer, like an RFC 9297 datagram context ID
+    Context,
+}
+
+impl Framing {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "prefix" => Some(Framing::Prefix),
+            "rfc4571" => Some(Framing::Rfc4571),
+            "context" => Some(Framing::Context),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Framing::Prefix => "prefix",
+            Framing::Rfc4571 => "rfc4571",
+            Framing::Context => "context",
+        }
+    }
+
+    /// Write the header for `channel_id` and return its length; the packet is
+    /// appended after it and [`Framing::finish`] completes the frame
+    pub fn begin(&self, channel_id: u8, out: &mut BytesMut) -> usize {
+        match self {
+            Framing::Prefix => out.put_u8(channel_id),
+            Framing::Rfc4571 => {
+                out.put_u8(channel_id);
+                out.put_u16(0);
+            }
+            Framing::Context if channel_id < 64 => out.put_u8(channel_id),
+            Framing::Context => out.put_u16(0x4000 | channel_id as u16),
+        }
+        self.header_len(channel_id)
+    }
+
+    pub fn finish(&self, out: &mut BytesMut) {
+        if *self == Framing::Rfc4571 {
+            let len = (out.len() - 3) as u16;
+            out[1..3].copy_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    /// Channel ID and packet of a payload framed by [`Framing::begin`]
+    pub fn split<'a>(&self, payload: &'a [u8]) -> Option<(u8, &'a [u8])> {
+        let first = *payload.first()?;
+        match self {
+            Framing::Prefix => Some((first, &payload[1..])),
+            Framing::Rfc4571 => Some((first, payload.get(3..)?)),
+            Framing::Context if first >> 6 == 0 => Some((first, &payload[1..])),
+            // Two-byte varint
+            Framing::Context => Some((*payload.get(1)?, payload.get(2..)?)),
+        }
+    }
+
+    fn header_len(&self, channel_id: u8) -> usize {
+        match self {
+            Framing::Prefix => 1,
+            Framing::Rfc4571 => 3,
+            Framing::Context if channel_id < 64 => 1,
+            Framing::Context => 2,
+        }
+    }
+}
+
+/// How a message or parameter looks on the wire, for the generators
+#[derive(Debug)]
+pub enum Shape {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// One of these strings
+    Enum(&'static [&'static str]),
+    Nullable(Box<Shape>),
+    Array(Box<Shape>),
+    /// An object with exactly these fields
+    Struct(Vec<Field>),
+    /// An object with these fields and possibly others
+    Open(Vec<Field>),
+    /// Objects told apart by the value of `tag`; a variant that is not a
+    /// struct is merged into the object, like `#[serde(flatten)]`
+    Tagged { tag: &'static str, variants: Vec<(&'static str, Shape)> },
+    /// Another definition
+    Ref(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Field {
+    name: &'static str,
+    shape: Shape,
+    required: bool,
+    doc: &'static str,
+}
+
+/// A required field
+fn field(name: &'static str, shape: Shape, doc: &'static str) -> Field {
+    Field { name, shape, required: true, doc }
+}
+
+/// A field that may be left out
+fn optional(name: &'static str, shape: Shape, doc: &'static str) -> Field {
+    Field { name, shape, required: false, doc }
+}
+
+/// A named type of the protocol
+#[derive(Debug)]
+pub struct Definition {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub shape: Shape,
+}
+
+impl Command {
+    pub fn shape() -> Shape {
+        let seconds = |shape| vec![field("seconds", shape, "")];
+        Shape::Tagged {
+            tag: "command",
+            variants: vec![
+                ("stats", Shape::Struct(Vec::new())),
+                ("pause_live", Shape::Struct(Vec::new())),
+                ("rewind", Shape::Struct(seconds(Shape::Integer))),
+                ("resume", Shape::Struct(Vec::new())),
+                ("live", Shape::Struct(Vec::new())),
+                ("play", Shape::Struct(Vec::new())),
+                ("pause", Shape::Struct(Vec::new())),
+                ("seek", Shape::Struct(seconds(Shape::Number))),
+                ("scale", Shape::Struct(vec![field("scale", Shape::Number, "Multiple of normal speed; negative plays backwards")])),
+                ("speed", Shape::Struct(vec![field("speed", Shape::Number, "Multiple of the normal delivery rate")])),
+                (
+                    "select_track",
+                    Shape::Struct(vec![field("track", Shape::Integer, ""), field("selected", Shape::Boolean, "")]),
+                ),
+            ],
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn shape() -> Shape {
+        Shape::Tagged {
+            tag: "type",
+            variants: vec![
+                ("rtsp", Shape::Struct(vec![field("data", Shape::String, "RTSP request text")])),
+                ("command", Shape::Ref("Command")),
+            ],
+        }
+    }
+}
+
+impl ServerMessage {
+    pub fn shape() -> Shape {
+        Shape::Tagged {
+            tag: "type",
+            variants: vec![
+                ("rtsp", Shape::Struct(vec![field("data", Shape::String, "RTSP response or request text")])),
+                ("event", Shape::Ref("Event")),
+                (
+                    "stats",
+                    Shape::Struct(vec![
+                        field("uptime_ms", Shape::Integer, ""),
+                        field("bytes_sent", Shape::Integer, ""),
+                        field("profile", Shape::Enum(&["main", "sub"]), ""),
+                        field("stalled", Shape::Boolean, ""),
+                        field("startup", Shape::Open(Vec::new()), "Milliseconds to reach each startup phase, null until reached"),
+                        field("channels", Shape::Array(Box::new(Shape::Ref("ChannelReport"))), ""),
+                        field("estimate_kbps", Shape::Nullable(Box::new(Shape::Integer)), "The client's estimated bandwidth, null until it is known"),
+                    ]),
+                ),
+                ("tracks", Shape::Struct(vec![field("tracks", Shape::Array(Box::new(Shape::Ref("Track"))), "")])),
+                ("state", Shape::Struct(vec![field("state", Shape::Enum(&["playing", "paused"]), "")])),
+                (
+                    "rate",
+                    Shape::Struct(vec![
+                        field("scale", Shape::Number, "Scale the camera granted"),
+                        field("speed", Shape::Number, "Speed the camera granted"),
+                    ]),
+                ),
+                (
+                    "error",
+                    Shape::Struct(vec![
+                        optional("command", Shape::String, "The command that failed; absent when the session did"),
+                        field("error", Shape::String, ""),
+                    ]),
+                ),
+            ],
+        }
+    }
+}
+
+impl EventMessage {
+    pub fn shape() -> Shape {
+        let nullable = |shape| Shape::Nullable(Box::new(shape));
+        let channel = || field("channel", Shape::Integer, "RTP channel ID of the track");
+        Shape::Tagged {
+            tag: "event",
+            variants: vec![
+                (
+                    "sync",
+                    Shape::Struct(vec![
+                        channel(),
+                        field("wallclock", Shape::Number, "Unix milliseconds at the `rtp` timestamp"),
+                        field("rtp", Shape::Integer, ""),
+                        field("clock_rate", Shape::Integer, "Nominal clock rate from the SDP"),
+                        field("measured_rate", nullable(Shape::Number), "Camera clock rate from its Sender Reports"),
+                    ]),
+                ),
+                (
+                    "capabilities",
+                    Shape::Struct(vec![
+                        field("pause", Shape::Boolean, ""),
+                        field("get_parameter", Shape::Boolean, ""),
+                        field("set_parameter", Shape::Boolean, ""),
+                        field("methods", Shape::Array(Box::new(Shape::String)), "From the camera's Public header"),
+                        field("streams", Shape::Array(Box::new(Shape::Ref("StreamInfo"))), ""),
+                    ]),
+                ),
+                ("stall", Shape::Struct(vec![field("idle_ms", Shape::Integer, "Time since the last packet")])),
+                ("resume", Shape::Struct(Vec::new())),
+                ("profile", Shape::Struct(vec![field("profile", Shape::Enum(&["main", "sub"]), "")])),
+                ("rendition", Shape::Struct(vec![field("name", Shape::String, "")])),
+                ("expired", Shape::Struct(vec![field("reason", Shape::Enum(&["max-duration", "viewing-hours"]), "")])),
+                (
+                    "audio",
+                    Shape::Struct(vec![
+                        channel(),
+                        field("level_dbfs", Shape::Number, ""),
+                        field("voice", Shape::Boolean, "Whether the level looks like speech"),
+                    ]),
+                ),
+                (
+                    "motion",
+                    Shape::Struct(vec![
+                        channel(),
+                        field("active", Shape::Boolean, "Whether motion started or ended"),
+                        field("cue", Shape::Enum(&["bitrate", "keyframe"]), "What started it"),
+                    ]),
+                ),
+                (
+                    "onvif",
+                    Shape::Struct(vec![
+                        field("kind", Shape::Enum(&["motion", "tamper", "io", "other"]), ""),
+                        field("topic", Shape::String, "e.g. tns1:RuleEngine/CellMotionDetector/Motion"),
+                        field("time", nullable(Shape::String), "Camera's UTC time for the event"),
+                        field("operation", nullable(Shape::String), "\"Initialized\", \"Changed\" or \"Deleted\""),
+                        field("source", Shape::Open(Vec::new()), "What raised the event, as strings"),
+                        field("data", Shape::Open(Vec::new()), "Its state, as strings"),
+                    ]),
+                ),
+                (
+                    "timeshift",
+                    Shape::Struct(vec![
+                        field("state", Shape::Enum(&["live", "paused", "playing"]), ""),
+                        field("behind_ms", Shape::Integer, "How far playback is behind live"),
+                    ]),
+                ),
+                (
+                    "objects",
+                    Shape::Struct(vec![
+                        channel(),
+                        field("time", nullable(Shape::String), "The frame's UtcTime"),
+                        field("objects", Shape::Array(Box::new(Shape::Ref("DetectedObject"))), ""),
+                    ]),
+                ),
+                ("draining", Shape::Struct(vec![field("alternate", nullable(Shape::String), "Where to reconnect instead")])),
+                (
+                    "upstream_error",
+                    Shape::Struct(vec![
+                        field("method", Shape::String, ""),
+                        field("code", Shape::Integer, ""),
+                        field("class", Shape::String, "e.g. \"4xx\""),
+                        field("reason", Shape::String, ""),
+                    ]),
+                ),
+                (
+                    "carousel",
+                    Shape::Struct(vec![
+                        field("index", Shape::Integer, "Position of the camera in the playlist"),
+                        field("count", Shape::Integer, ""),
+                    ]),
+                ),
+                ("skip", Shape::Struct(vec![channel(), field("behind_ms", Shape::Integer, "")])),
+                (
+                    "codec_config_changed",
+                    Shape::Struct(vec![
+                        channel(),
+                        field("cause", Shape::Enum(&["in-band", "announce"]), ""),
+                        field("profile_level_id", nullable(Shape::String), ""),
+                        field("stream", Shape::Ref("StreamInfo"), ""),
+                    ]),
+                ),
+                (
+                    "channels",
+                    Shape::Struct(vec![
+                        field("cseq", Shape::String, "CSeq of the SETUP response that follows"),
+                        field("rtp_channel", Shape::Integer, ""),
+                        field("rtcp_channel", Shape::Integer, ""),
+                    ]),
+                ),
+                (
+                    "qos",
+                    Shape::Struct(vec![
+                        field("action", Shape::Enum(&["degrade", "shed"]), ""),
+                        field("reason", Shape::Enum(&["bandwidth", "cpu", "memory"]), ""),
+                        field("priority", Shape::Enum(&["low", "normal", "high"]), ""),
+                    ]),
+                ),
+                (
+                    "camera_busy",
+                    Shape::Struct(vec![
+                        field("limit", Shape::Integer, "Sessions the camera allows"),
+                        field("wait_ms", Shape::Integer, "How long the session waits for one to end"),
+                    ]),
+                ),
+                ("end_of_stream", Shape::Struct(vec![field("cause", Shape::Enum(&["bye", "announce"]), "")])),
+                ("discontinuity", Shape::Struct(vec![field("npt", Shape::Number, "Seconds into the stream where media resumes")])),
+                (
+                    "health",
+                    Shape::Struct(vec![
+                        field("score", Shape::Integer, "0 to 100"),
+                        field("action", Shape::Enum(&["alert", "transcode", "reconnect"]), ""),
+                    ]),
+                ),
+            ],
+        }
+    }
+}
+
+/// What the SDP says about a track's stream
+fn stream_info() -> Shape {
+    let nullable = |shape| Shape::Nullable(Box::new(shape));
+    Shape::Struct(vec![
+        field("kind", Shape::String, "\"video\", \"audio\", ..."),
+        field("codec", nullable(Shape::String), ""),
+        field("clock_rate", nullable(Shape::Integer), ""),
+        field("width", nullable(Shape::Integer), ""),
+        field("height", nullable(Shape::Integer), ""),
+        field("fps", nullable(Shape::Number), ""),
+        field("bitrate_kbps", nullable(Shape::Integer), ""),
+        field("profile", nullable(Shape::String), "Codec profile (\"high\", \"main-10\", ...)"),
+        field("level", nullable(Shape::String), "Codec level (\"4.1\")"),
+    ])
+}
+
+/// An object of an "objects" event
+fn detected_object() -> Shape {
+    let nullable = |shape| Shape::Nullable(Box::new(shape));
+    let edge = |name| field(name, Shape::Number, "");
+    Shape::Struct(vec![
+        field("id", Shape::String, ""),
+        field("class", nullable(Shape::String), "Best class candidate, e.g. \"Human\" or \"Vehicle\""),
+        field("likelihood", nullable(Shape::Number), ""),
+        field(
+            "box",
+            nullable(Shape::Struct(vec![edge("left"), edge("top"), edge("right"), edge("bottom")])),
+            "In the camera's coordinates; ONVIF normalizes them to [-1, 1]",
+        ),
+    ])
+}
+
+/// An entry of a "tracks" message
+fn track() -> Shape {
+    let nullable = |shape| Shape::Nullable(Box::new(shape));
+    Shape::Struct(vec![
+        field("channel", nullable(Shape::Integer), "RTP channel ID, null until set up"),
+        field("rtcp_channel", nullable(Shape::Integer), ""),
+        field("kind", Shape::Enum(&["video", "audio", "metadata"]), ""),
+        field("format", nullable(Shape::String), "Metadata format, for metadata tracks"),
+        field("codec", Shape::String, ""),
+        field("payload_type", Shape::Integer, ""),
+        field("clock_rate", Shape::Integer, ""),
+        field("fmtp", nullable(Shape::String), ""),
+        field(
+            "resolution",
+            nullable(Shape::Struct(vec![field("width", Shape::Integer, ""), field("height", Shape::Integer, "")])),
+            "",
+        ),
+        optional("track", Shape::Integer, "Managed mode: index of the track in the camera's SDP"),
+        optional("selected", Shape::Boolean, "Managed mode: whether the track will be set up"),
+    ])
+}
+
+/// Query parameters of the WebTransport or WebSocket connection. With
+/// WebSockets the control and data sockets are paired by `session_id`.
+fn handshake() -> Shape {
+    Shape::Struct(vec![
+        optional("rtsp", Shape::String, "Camera URL; required unless `playlist` is given"),
+        optional("playlist", Shape::String, "Comma-separated camera URLs to cycle through"),
+        optional("dwell", Shape::Integer, "Seconds a playlist shows each camera"),
+        optional("sub", Shape::String, "Lower-bitrate stream of the same camera"),
+        optional("rendition", Shape::String, "Transcoded rendition to watch, or \"auto\""),
+        optional("framing", Shape::Ref("DatagramFraming"), ""),
+        optional("compress", Shape::Enum(&["deflate", "zstd"]), "Control message compression; permessage-deflate on WebSocket"),
+        optional("latency", Shape::Enum(&["ultra-low", "balanced", "reliable"]), ""),
+        optional("protocol", Shape::Enum(&["raw", "json"]), "Control stream format"),
+        optional("mode", Shape::Enum(&["rtsp", "simple", "managed"]), "Who runs the RTSP exchange"),
+        optional("output", Shape::Enum(&["rtp", "mpegts", "flv"]), "RTP, or a container over one WebSocket"),
+        optional("token", Shape::String, "Selects the tenant and is passed to the authorizer"),
+        optional("session_id", Shape::String, "WebSocket only: pairs the control and data sockets"),
+        optional("type", Shape::Enum(&["control", "data"]), "WebSocket only: which of the pair this socket is"),
+    ])
+}
+
+/// Receive-side metrics of one of the camera's RTP channels
+fn channel_report() -> Shape {
+    Shape::Struct(vec![
+        field("channel", Shape::Integer, ""),
+        field("packets", Shape::Integer, ""),
+        field("lost", Shape::Integer, ""),
+        field("loss_fraction", Shape::Number, "Over the last two seconds"),
+        field("jitter_ms", Shape::Number, ""),
+        field("bitrate_kbps", Shape::Integer, "Over the last two seconds"),
+    ])
+}
+
+/// Every definition, in the order they are printed
+pub fn definitions() -> Vec<Definition> {
+    vec![
+        Definition { name: "Handshake", doc: "Query parameters of the connection", shape: handshake() },
+        Definition {
+            name: "DatagramFraming",
+            doc: "Header before each media packet, which carries its channel ID: \
+                  `prefix` is [u8 channel][packet], \
+                  `rfc4571` is [u8 channel][u16 length][packet], \
+                  `context` is [QUIC varint channel][packet]",
+            shape: Shape::Enum(&["prefix", "rfc4571", "context"]),
+        },
+        Definition { name: "Command", doc: "Request to the proxy itself, inside a \"command\" message", shape: Command::shape() },
+        Definition { name: "ClientMessage", doc: "Browser to proxy, one JSON object per line", shape: ClientMessage::shape() },
+        Definition { name: "ServerMessage", doc: "Proxy to browser, one JSON object per line", shape: ServerMessage::shape() },
+        Definition { name: "Event", doc: "Asynchronous notification, inside an \"event\" message", shape: EventMessage::shape() },
+        Definition { name: "StreamInfo", doc: "What a track's SDP says about its stream", shape: stream_info() },
+        Definition { name: "DetectedObject", doc: "An object the camera's analytics detected", shape: detected_object() },
+        Definition { name: "Track", doc: "One track of a \"tracks\" message", shape: track() },
+        Definition { name: "ChannelReport", doc: "Receive-side metrics of one RTP channel", shape: channel_report() },
+    ]
+}
+
+/// Output of `--dump-protocol`
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    /// JSON Schema (2020-12), with each type under `$defs`
+    Schema,
+    /// TypeScript type declarations
+    Typescript,
+}
+
+pub fn dump(format: Format) -> String {
+    let definitions = definitions();
+    match format {
+        Format::Schema => {
+            let defs: Map<String, Value> = definitions
+                .iter()
+                .map(|d| {
+                    let mut schema = schema(&d.shape);
+                    schema["description"] = json!(d.doc);
+                    (d.name.to_string(), schema)
+                })
+                .collect();
+            let root = json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "title": "rtsp2browser wire protocol",
+                "$defs": defs,
+            });
+            serde_json::to_string_pretty(&root).expect("schemas serialize") + "\n"
+        }
+        Format::Typescript => {
+            let mut out = String::from("// Generated by `server --dump-protocol typescript`; do not edit\n");
+            for d in &definitions {
+                out += &format!("\n/** {} */\nexport type {} = {};\n", d.doc, d.name, typescript(&d.shape, 0));
+            }
+            out
+        }
+    }
+}
+
+fn schema(shape: &Shape) -> Value {
+    match shape {
+        Shape::String => json!({ "type": "string" }),
+        Shape::Integer => json!({ "type": "integer" }),
+        Shape::Number => json!({ "type": "number" }),
+        Shape::Boolean => json!({ "type": "boolean" }),
+        Shape::Enum(values) => json!({ "enum": values }),
+        Shape::Nullable(shape) => json!({ "anyOf": [schema(shape), { "type": "null" }] }),
+        Shape::Array(shape) => json!({ "type": "array", "items": schema(shape) }),
+        Shape::Struct(fields) => object_schema(&[], fields, false),
+        Shape::Open(fields) => object_schema(&[], fields, true),
+        Shape::Tagged { tag, variants } => {
+            let variants: Vec<Value> = variants.iter().flat_map(|(name, shape)| variant_schemas(&[(tag, name)], shape)).collect();
+            json!({ "oneOf": variants })
+        }
+        Shape::Ref(name) => json!({ "$ref": format!("#/$defs/{}", name) }),
+    }
+}
+
+/// Object schemas of a tagged variant. One that is tagged itself, directly
+/// or through a definition, is merged into the outer object: each of its
+/// variants becomes a schema with both tags, as the fields would otherwise
+/// be refused by one side's `additionalProperties`.
+fn variant_schemas(tags: &[(&str, &str)], shape: &Shape) -> Vec<Value> {
+    match shape {
+        Shape::Struct(fields) => vec![object_schema(tags, fields, false)],
+        Shape::Open(fields) => vec![object_schema(tags, fields, true)],
+        Shape::Tagged { tag, variants } => variants
+            .iter()
+            .flat_map(|(name, shape)| {
+                let mut tags = tags.to_vec();
+                tags.push((tag, name));
+                variant_schemas(&tags, shape)
+            })
+            .collect(),
+        Shape::Ref(name) => match definitions().into_iter().find(|d| d.name == *name) {
+            Some(definition) => variant_schemas(tags, &definition.shape),
+            None => vec![json!({ "allOf": [object_schema(tags, &[], true), schema(shape)] })],
+        },
+        other => vec![json!({ "allOf": [object_schema(tags, &[], true), schema(other)] })],
+    }
+}
+
+/// An object schema, with the tags of a variant as its first properties
+fn object_schema(tags: &[(&str, &str)], fields: &[Field], open: bool) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for (tag, name) in tags {
+        properties.insert(tag.to_string(), json!({ "const": name }));
+        required.push(tag.to_string());
+    }
+    for field in fields {
+        let mut schema = schema(&field.shape);
+        if !field.doc.is_empty() {
+            schema["description"] = json!(field.doc);
+        }
+        properties.insert(field.name.to_string(), schema);
+        if field.required {
+            required.push(field.name.to_string());
+        }
+    }
+    json!({ "type": "object", "properties": properties, "required": required, "additionalProperties": open })
+}
+
+fn typescript(shape: &Shape, indent: usize) -> String {
+    match shape {
+        Shape::String => "string".to_string(),
+        Shape::Integer | Shape::Number => "number".to_string(),
+        Shape::Boolean => "boolean".to_string(),
+        Shape::Enum(values) => values.iter().map(|v| format!("\"{}\"", v)).collect::<Vec<_>>().join(" | "),
+        Shape::Nullable(shape) => format!("{} | null", typescript(shape, indent)),
+        Shape::Array(shape) => match shape.as_ref() {
+            Shape::Ref(name) => format!("{}[]", name),
+            shape => format!("({})[]", typescript(shape, indent)),
+        },
+        Shape::Struct(fields) => object_typescript(None, fields, false, indent),
+        Shape::Open(fields) => object_typescript(None, fields, true, indent),
+        Shape::Tagged { tag, variants } => variants
+            .iter()
+            .map(|(name, shape)| {
+                let pad = "  ".repeat(indent + 1);
+                match shape {
+                    Shape::Struct(fields) => format!("\n{}| {}", pad, object_typescript(Some((*tag, *name)), fields, false, indent + 1)),
+                    Shape::Open(fields) => format!("\n{}| {}", pad, object_typescript(Some((*tag, *name)), fields, true, indent + 1)),
+                    other => format!("\n{}| ({{ {}: \"{}\" }} & {})", pad, tag, name, typescript(other, indent + 1)),
+                }
+            })
+            .collect(),
+        Shape::Ref(name) => name.to_string(),
+    }
+}
+
+fn object_typescript(tag: Option<(&str, &str)>, fields: &[Field], open: bool, indent: usize) -> String {
+    let pad = "  ".repeat(indent + 1);
+    let mut out = String::from("{\n");
+    if let Some((tag, name)) = tag {
+        out += &format!("{}{}: \"{}\";\n", pad, tag, name);
+    }
+    for field in fields {
+        if !field.doc.is_empty() {
+            out += &format!("{}/** {} */\n", pad, field.doc);
+        }
+        let mark = if field.required { "" } else { "?" };
+        out += &format!("{}{}{}: {};\n", pad, field.name, mark, typescript(&field.shape, indent + 1));
+    }
+    if open {
+        out += &format!("{}[field: string]: unknown;\n", pad);
+    }
+    out + &"  ".repeat(indent) + "}"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `value` matches `schema`, for the keywords [`dump`] emits
+    fn conforms(value: &Value, schema: &Value, root: &Value) -> bool {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/$defs/");
+            return conforms(value, &root["$defs"][name], root);
+        }
+        if let Some(expected) = schema.get("const") {
+            return value == expected;
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            return values.contains(value);
+        }
+        if let Some(schemas) = schema.get("anyOf").and_then(Value::as_array) {
+            return schemas.iter().any(|s| conforms(value, s, root));
+        }
+        if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+            return schemas.iter().filter(|s| conforms(value, s, root)).count() == 1;
+        }
+        if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+            return schemas.iter().all(|s| conforms(value, s, root));
+        }
+        match schema["type"].as_str() {
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            Some("null") => value.is_null(),
+            Some("array") => value.as_array().is_some_and(|items| items.iter().all(|item| conforms(item, &schema["items"], root))),
+            Some("object") => {
+                let Some(object) = value.as_object() else {
+                    return false;
+                };
+                let properties = schema["properties"].as_object().unwrap();
+                let required = schema["required"].as_array().unwrap();
+                let open = schema["additionalProperties"].as_bool().unwrap();
+                required.iter().all(|name| object.contains_key(name.as_str().unwrap()))
+                    && object.iter().all(|(name, value)| match properties.get(name) {
+                        Some(schema) => conforms(value, schema, root),
+                        None => open,
+                    })
+            }
+            other => panic!("unexpected schema type {:?}", other),
+        }
+    }
+
+    fn variant_names(shape: &Shape) -> Vec<&'static str> {
+        match shape {
+            Shape::Tagged { variants, .. } => variants.iter().map(|(name, _)| *name).collect(),
+            _ => panic!("not a tagged shape"),
+        }
+    }
+
+    fn stream() -> StreamInfo {
+        StreamInfo {
+            kind: "video".to_string(),
+            codec: Some("H264".to_string()),
+            clock_rate: Some(90000),
+            width: Some(1920),
+            height: Some(1080),
+            fps: Some(29.97),
+            bitrate_kbps: None,
+            profile: Some("high".to_string()),
+            level: Some("4.1".to_string()),
+        }
+    }
+
+    fn events() -> Vec<EventMessage> {
+        let pairs = BTreeMap::from([("IsMotion".to_string(), "true".to_string())]);
+        let object = |bbox| DetectedObject { id: "1".to_string(), class: Some("Human".to_string()), likelihood: Some(0.9), bbox };
+        vec![
+            EventMessage::Sync { channel: 0, wallclock: 1.7e12, rtp: 90000, clock_rate: 90000, measured_rate: Some(90000.5) },
+            EventMessage::Capabilities {
+                pause: true,
+                get_parameter: false,
+                set_parameter: false,
+                methods: vec!["PLAY".to_string()],
+                streams: vec![stream(), StreamInfo { kind: "audio".to_string(), ..Default::default() }],
+            },
+            EventMessage::Stall { idle_ms: 5000 },
+            EventMessage::Resume,
+            EventMessage::Profile { profile: "sub" },
+            EventMessage::Rendition { name: "480p".to_string() },
+            EventMessage::Expired { reason: "max-duration" },
+            EventMessage::Audio { channel: 2, level_dbfs: -20.5, voice: true },
+            EventMessage::Motion { channel: 0, active: true, cue: "keyframe" },
+            EventMessage::Onvif {
+                kind: "motion",
+                topic: "tns1:RuleEngine/CellMotionDetector/Motion".to_string(),
+                time: None,
+                operation: Some("Changed".to_string()),
+                source: pairs.clone(),
+                data: pairs,
+            },
+            EventMessage::Timeshift { state: "paused", behind_ms: 1500 },
+            EventMessage::Objects {
+                channel: 4,
+                time: Some("2024-01-01T00:00:00Z".to_string()),
+                objects: vec![object(Some(BoundingBox { left: -0.5, top: 0.5, right: 0.0, bottom: 0.0 })), object(None)],
+            },
+            EventMessage::Draining { alternate: None },
+            EventMessage::UpstreamError { method: "DESCRIBE".to_string(), code: 401, class: "4xx".to_string(), reason: "Unauthorized".to_string() },
+            EventMessage::Carousel { index: 1, count: 3 },
+            EventMessage::Skip { channel: 0, behind_ms: 2500 },
+            EventMessage::CodecConfigChanged { channel: 0, cause: "in-band", profile_level_id: Some("640028".to_string()), stream: stream() },
+            EventMessage::Channels { cseq: "3".to_string(), rtp_channel: 0, rtcp_channel: 1 },
+            EventMessage::Qos { action: "degrade", reason: "bandwidth", priority: "low" },
+            EventMessage::CameraBusy { limit: 2, wait_ms: 10000 },
+            EventMessage::EndOfStream { cause: "bye" },
+            EventMessage::Discontinuity { npt: 12.5 },
+            EventMessage::Health { score: 30, action: "alert" },
+        ]
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        let mut messages = vec![
+            ServerMessage::Rtsp { data: "RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n".to_string() },
+            ServerMessage::Stats {
+                uptime_ms: 1000,
+                bytes_sent: 4096,
+                profile: "main",
+                stalled: false,
+                startup: Map::from_iter([("total_ms".to_string(), json!(250)), ("play_ms".to_string(), Value::Null)]),
+                channels: json!([{
+                    "channel": 0, "packets": 10, "lost": 0, "loss_fraction": 0.0, "jitter_ms": 1.5, "bitrate_kbps": 800,
+                }]),
+                estimate_kbps: None,
+            },
+            ServerMessage::Tracks {
+                tracks: vec![json!({
+                    "channel": 0, "rtcp_channel": 1, "kind": "video", "format": null, "codec": "H264",
+                    "payload_type": 96, "clock_rate": 90000, "fmtp": null,
+                    "resolution": { "width": 1280, "height": 720 }, "track": 0, "selected": true,
+                })],
+            },
+            ServerMessage::State { state: "paused" },
+            ServerMessage::Rate { scale: 2.0, speed: 1.0 },
+            ServerMessage::Error { command: Some("seek"), error: "Not supported".to_string() },
+            ServerMessage::Error { command: None, error: "Camera unreachable".to_string() },
+        ];
+        messages.extend(events().into_iter().map(ServerMessage::Event));
+        messages
+    }
+
+    #[test]
+    fn server_messages_match_the_schema() {
+        let root: Value = serde_json::from_str(&dump(Format::Schema)).unwrap();
+        let mut types = Vec::new();
+        let mut events = Vec::new();
+        for message in server_messages() {
+            let value = serde_json::to_value(&message).unwrap();
+            assert!(conforms(&value, &root["$defs"]["ServerMessage"], &root), "{} does not match the schema", value);
+            types.push(value["type"].as_str().unwrap().to_string());
+            if let Some(event) = value["event"].as_str() {
+                events.push(event.to_string());
+            }
+        }
+        for name in variant_names(&ServerMessage::shape()) {
+            assert!(types.iter().any(|t| t == name), "no sample of the \"{}\" message", name);
+        }
+        for name in variant_names(&EventMessage::shape()) {
+            assert!(events.iter().any(|e| e == name), "no sample of the \"{}\" event", name);
+        }
+    }
+
+    #[test]
+    fn client_messages_match_the_schema() {
+        let root: Value = serde_json::from_str(&dump(Format::Schema)).unwrap();
+        let samples = [
+            json!({ "type": "rtsp", "data": "OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n" }),
+            json!({ "type": "command", "command": "stats" }),
+            json!({ "type": "command", "command": "pause_live" }),
+            json!({ "type": "command", "command": "rewind", "seconds": 10 }),
+            json!({ "type": "command", "command": "resume" }),
+            json!({ "type": "command", "command": "live" }),
+            json!({ "type": "command", "command": "play" }),
+            json!({ "type": "command", "command": "pause" }),
+            json!({ "type": "command", "command": "seek", "seconds": 12.5 }),
+            json!({ "type": "command", "command": "scale", "scale": -2.0 }),
+            json!({ "type": "command", "command": "speed", "speed": 4.0 }),
+            json!({ "type": "command", "command": "select_track", "track": 1, "selected": false }),
+        ];
+        for sample in &samples {
+            assert!(conforms(sample, &root["$defs"]["ClientMessage"], &root), "{} does not match the schema", sample);
+            serde_json::from_value::<ClientMessage>(sample.clone()).unwrap();
+        }
+        for name in variant_names(&Command::shape()) {
+            assert!(samples.iter().any(|s| s["command"] == name), "no sample of the \"{}\" command", name);
+        }
+        // What the schema rejects, the proxy does too
+        let wrong = json!({ "type": "command", "command": "rewind", "seconds": "ten" });
+        assert!(!conforms(&wrong, &root["$defs"]["ClientMessage"], &root));
+        assert!(serde_json::from_value::<ClientMessage>(wrong).is_err());
+    }
+
+    fn framed(framing: Framing, channel_id: u8, packet: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        let header_len = framing.begin(channel_id, &mut out);
+        assert_eq!(out.len(), header_len);
+        out.extend_from_slice(packet);
+        framing.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn framing_known_answers() {
+        let packet = [0x80, 0x60, 0x00];
+        assert_eq!(&framed(Framing::Prefix, 5, &packet)[..], [5, 0x80, 0x60, 0x00]);
+        assert_eq!(&framed(Framing::Rfc4571, 5, &packet)[..], [5, 0x00, 0x03, 0x80, 0x60, 0x00]);
+        assert_eq!(&framed(Framing::Context, 5, &packet)[..], [5, 0x80, 0x60, 0x00]);
+        // Channels from 64 need a two-byte varint
+        assert_eq!(&framed(Framing::Context, 64, &packet)[..], [0x40, 64, 0x80, 0x60, 0x00]);
+        assert_eq!(&framed(Framing::Context, 255, &packet)[..], [0x40, 255, 0x80, 0x60, 0x00]);
+    }
+
+    #[test]
+    fn framing_round_trips() {
+        let long = vec![0xab; 1400];
+        for framing in [Framing::Prefix, Framing::Rfc4571, Framing::Context] {
+            assert_eq!(Framing::parse(framing.as_str()), Some(framing));
+            for channel_id in [0, 1, 63, 64, 200, 255] {
+                for packet in [&[][..], &[0x80, 0x60], &long[..]] {
+                    let out = framed(framing, channel_id, packet);
+                    assert_eq!(framing.split(&out), Some((channel_id, packet)), "{} channel {}", framing.as_str(), channel_id);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_framing_is_refused() {
+        for framing in [Framing::Prefix, Framing::Rfc4571, Framing::Context] {
+            assert_eq!(framing.split(&[]), None, "{}", framing.as_str());
+        }
+        assert_eq!(Framing::Rfc4571.split(&[5]), None);
+        assert_eq!(Framing::Rfc4571.split(&[5, 0x00]), None);
+        assert_eq!(Framing::Context.split(&[0x40]), None);
+        // A header with nothing after it is an empty packet, not an error
+        assert_eq!(Framing::Rfc4571.split(&[5, 0x00, 0x00]), Some((5, &[][..])));
+        assert_eq!(Framing::Context.split(&[0x40, 64]), Some((64, &[][..])));
+    }
+}
@@ -1,237 +1,2098 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use crate::error::Error;
 use bytes::{BytesMut, Buf};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
-use tracing::{error, info, instrument};
+use tokio::net::UdpSocket;
+use tracing::{error, info, instrument, warn};
+use crate::adaptive::{self, CongestionMonitor, GateHandle, Profile, ProfileGate};
+use crate::bandwidth::BandwidthEstimator;
+use crate::feedback::{Ack, Feedback, Nack};
+use crate::filter::{PayloadFilter, SourceFilter};
+use crate::flow::FlowControl;
+use crate::health::{Alert, HealthAction, HealthMonitor};
+use crate::keyframes::{FirRoute, FirSender, KeyframeRequest, KeyframeWatch};
+use crate::middleware::MiddlewareChain;
 use crate::rtsp::{RtspRequest, RtspResponse};
-use std::collections::VecDeque;
+use crate::rtsp2::{RtspVersion, Translator};
+use crate::auth::UpstreamAuth;
+use crate::audio::AudioMeter;
+use crate::config::{AudioLevelConfig, ChannelIdConfig, ChannelIdFormat, Credentials, FlowControlConfig, HealthConfig, KeyframeMethod, KeyframeRule, MetadataConfig, MotionConfig, NamedStream, OnvifRule, ParameterSetsConfig, Priority, QuirkRule, RtpConfig, Tenant, TimeShiftConfig, TranscodeRule, UpstreamConfig, ValidationConfig};
+use crate::control::{Command, ControlCodec, ControlProtocol, EndCause, EndOfStream, Event, MediaWatch, SessionStats};
+use crate::quirks::QuirkSelector;
+use crate::rtp::{RtpRewriter, SessionTimeline, Timescale};
+use crate::sdp::{MediaDescription, SessionDescription};
+use crate::seek::SeekGate;
+use crate::simple::{ClientMode, SimpleDriver, Step};
+use crate::startup::{Phase, StartupTimer};
+use crate::memory::{SessionMemory, Usage};
+use crate::metadata::{MetadataFormat, ObjectParser};
+use crate::motion::MotionDetector;
+use crate::mpegts::TsRepacketizer;
+use crate::paramsets::ParameterSetInjector;
+use crate::protocol::ServerMessage;
+use crate::qos::{Congestion, QosAction};
+use crate::receive::{ChannelMeter, SessionReceive};
+use crate::reconfig::{CodecWatch, ConfigChange, TrackConfig};
+use crate::remux::{Output, RemuxTrack};
+use crate::latency::LatencyMode;
+use crate::limits::{BitrateCheck, Expiry, RequestLimiter, RequestLimits, SessionLimits};
+use crate::retransmit::Retransmitter;
+use crate::workers::Shard;
+use crate::transcript::Transcript;
+use crate::sync::ClockSync;
+use crate::timeshift::{ExportTrack, TimeShift};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
-use crate::transport::Transport;
+use crate::transport::{ControlReader, ControlWriter, DatagramReceiver, DatagramSender, Transport};
 
 pub struct RTSPProxy {
     rtsp_url: String,
+    /// ID of the session in the journal and export requests
+    session_id: String,
+    /// Lower-bitrate profile of the same camera, used under sustained congestion
+    substream_url: Option<String>,
+    /// Re-encode the video track (only honoured with the `transcode` feature)
+    #[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+    transcode: Option<TranscodeRule>,
+    /// Transcode rule a session switches to when its health calls for it
+    #[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+    transcode_fallback: Option<TranscodeRule>,
+    /// Transcoded rendition the client picked; chosen from its feedback when unset
+    #[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+    rendition: Option<String>,
+    rtp: RtpConfig,
+    /// Tenant the client belongs to, for upstream credentials
+    tenant: Option<Tenant>,
+    /// The `[[stream]]` this camera is configured as, for its credentials and
+    /// the name the browser may use in requests
+    stream: Option<NamedStream>,
+    middleware: MiddlewareChain,
+    /// Checks on the browser's requests before they are forwarded
+    validation: ValidationConfig,
+    /// Configured quirks for this camera; auto-detected when unset
+    quirks: Option<QuirkRule>,
+    upstream: UpstreamConfig,
+    /// Control stream format requested by the client
+    protocol: ControlProtocol,
+    /// Record of the RTSP exchange with the camera, in debug mode
+    transcript: Option<Transcript>,
+    /// Whether the proxy runs the RTSP exchange itself
+    mode: ClientMode,
+    /// How long a carousel shows this camera before moving on
+    dwell: Option<Duration>,
+    /// RTP, or a container the media is remuxed into
+    output: Output,
+    limits: SessionLimits,
+    /// Rate and size limits on the browser's RTSP requests
+    request_limits: RequestLimits,
+    /// Report audio levels to the browser
+    audio_levels: Option<AudioLevelConfig>,
+    /// Report motion hints to the browser
+    motion: Option<MotionConfig>,
+    /// Score the session's health and act on low scores
+    health: Option<HealthConfig>,
+    /// What to do with metadata tracks beyond forwarding them
+    metadata: MetadataConfig,
+    /// Forward the camera's ONVIF events to the browser
+    onvif: Option<OnvifRule>,
+    /// Longest wait for a keyframe a joining viewer should have
+    keyframes: Option<KeyframeRule>,
+    /// Keep recent media so the browser can pause live and rewind
+    timeshift: Option<TimeShiftConfig>,
+    /// When tracks on a reliable path skip ahead to catch up with live
+    flow_control: FlowControlConfig,
+    /// Latency mode the client picked, if any
+    latency: Option<LatencyMode>,
+    /// Sending H.264 parameter sets ahead of IDRs that lack them
+    parameter_sets: ParameterSetsConfig,
+    /// How SETUP responses carry a track's channel IDs
+    channel_ids: ChannelIdConfig,
 }
 
+/// Session timeout assumed when the camera does not send one (RFC 2326 default)
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Shortest keep-alive period, so `timeout=1` can't make the proxy spin
+const MIN_KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+/// CSeq numbers of proxy keep-alives start here, clear of the browser's own
+const KEEPALIVE_CSEQ_BASE: u32 = 100_000;
+
+/// Starting size of the RTSP and control stream buffers
+const INITIAL_BUFFER: usize = 4096;
+
+/// How often to check the camera's media for stalls
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the browser has to close after an end-of-stream event before
+/// the proxy does
+const END_OF_STREAM_GRACE: Duration = Duration::from_secs(2);
+
 struct PendingSetup {
     rtp_channel_id: u8,
     rtcp_channel_id: u8,
     rtp_socket: Arc<UdpSocket>,
     rtcp_socket: Arc<UdpSocket>,
+    /// Where the track's sockets are bound and its forwarders run
+    shard: Shard,
+    /// SDP media section this SETUP refers to, if it could be matched
+    media: Option<MediaDescription>,
 }
 
 impl RTSPProxy {
     pub fn new(rtsp_url: String) -> Self {
-        Self { rtsp_url }
+        Self {
+            rtsp_url,
+            session_id: String::new(),
+            substream_url: None,
+            transcode: None,
+            transcode_fallback: None,
+            rendition: None,
+            rtp: RtpConfig::default(),
+            tenant: None,
+            stream: None,
+            middleware: MiddlewareChain::default(),
+            validation: ValidationConfig::default(),
+            quirks: None,
+            upstream: UpstreamConfig::default(),
+            protocol: ControlProtocol::default(),
+            transcript: None,
+            mode: ClientMode::Rtsp,
+            dwell: None,
+            output: Output::Rtp,
+            limits: SessionLimits::default(),
+            request_limits: RequestLimits::default(),
+            audio_levels: None,
+            motion: None,
+            health: None,
+            metadata: MetadataConfig::default(),
+            onvif: None,
+            keyframes: None,
+            timeshift: None,
+            flow_control: FlowControlConfig::default(),
+            latency: None,
+            parameter_sets: ParameterSetsConfig::default(),
+            channel_ids: ChannelIdConfig::default(),
+        }
+    }
+
+    pub fn with_session_id(mut self, session_id: String) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    pub fn with_substream(mut self, substream_url: Option<String>) -> Self {
+        self.substream_url = substream_url;
+        self
+    }
+
+    pub fn with_transcode(mut self, transcode: Option<TranscodeRule>) -> Self {
+        self.transcode = transcode;
+        self
+    }
+
+    pub fn with_transcode_fallback(mut self, transcode_fallback: Option<TranscodeRule>) -> Self {
+        self.transcode_fallback = transcode_fallback;
+        self
+    }
+
+    pub fn with_rendition(mut self, rendition: Option<String>) -> Self {
+        self.rendition = rendition;
+        self
+    }
+
+    pub fn with_audio_levels(mut self, audio_levels: Option<AudioLevelConfig>) -> Self {
+        self.audio_levels = audio_levels;
+        self
+    }
+
+    pub fn with_motion(mut self, motion: Option<MotionConfig>) -> Self {
+        self.motion = motion;
+        self
+    }
+
+    pub fn with_health(mut self, health: Option<HealthConfig>) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: MetadataConfig) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_onvif(mut self, onvif: Option<OnvifRule>) -> Self {
+        self.onvif = onvif;
+        self
+    }
+
+    pub fn with_keyframes(mut self, keyframes: Option<KeyframeRule>) -> Self {
+        self.keyframes = keyframes;
+        self
+    }
+
+    pub fn with_timeshift(mut self, timeshift: Option<TimeShiftConfig>) -> Self {
+        self.timeshift = timeshift;
+        self
+    }
+
+    pub fn with_flow_control(mut self, flow_control: FlowControlConfig) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Option<LatencyMode>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_parameter_sets(mut self, parameter_sets: ParameterSetsConfig) -> Self {
+        self.parameter_sets = parameter_sets;
+        self
+    }
+
+    pub fn with_channel_ids(mut self, channel_ids: ChannelIdConfig) -> Self {
+        self.channel_ids = channel_ids;
+        self
+    }
+
+    pub fn with_rtp(mut self, rtp: RtpConfig) -> Self {
+        self.rtp = rtp;
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: Option<Tenant>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    pub fn with_stream(mut self, stream: Option<NamedStream>) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Upstream credentials for `url`: the named stream's, then the tenant's
+    fn credentials_for(&self, url: &str) -> Option<Credentials> {
+        self.stream
+            .as_ref()
+            .and_then(|s| s.credentials_for(url))
+            .or_else(|| self.tenant.as_ref().and_then(|t| t.credentials_for(url)))
+    }
+
+    /// A session slot at the camera at `addr`, waiting up to
+    /// `camera_queue_s` for one when it is at its limit
+    async fn camera_slot(&self, addr: &str, limit: usize, transport: &Transport) -> crate::error::Result<crate::cameras::CameraSlot> {
+        if let Some(slot) = crate::cameras::try_acquire(addr, limit) {
+            return Ok(slot);
+        }
+        let wait = Duration::from_secs(self.upstream.camera_queue_s);
+        let refused = || {
+            crate::stats::global().add("camera_sessions_refused", 1);
+            Error::PolicyDenied(format!("{} already has {} sessions", addr, limit))
+        };
+        if wait.is_zero() {
+            warn!("Refusing session: {} already has {} sessions", addr, limit);
+            return Err(refused());
+        }
+        info!("{} already has {} sessions; waiting up to {:?} for one to end", addr, limit, wait);
+        transport.write_control(&ControlCodec::new(self.protocol).event(&Event::CameraBusy { limit, wait })).await?;
+        tokio::select! {
+            slot = tokio::time::timeout(wait, crate::cameras::acquire(addr, limit)) => slot.map_err(|_| {
+                warn!("Refusing session: no slot at {} within {:?}", addr, wait);
+                refused()
+            }),
+            _ = transport.closed() => Err(Error::transport("closed while waiting for the camera")),
+        }
+    }
+
+    /// Priority class of the session, from its tenant
+    fn priority(&self) -> Priority {
+        self.tenant.as_ref().map_or_else(Priority::default, |t| t.priority)
     }
 
+    /// Feed the browser's video channel from the sub-stream until the gate
+    /// switches back to the main profile
+    fn spawn_substream(
+        &self,
+        url: &str,
+        target: &adaptive::SubstreamTarget,
+        sender: &DatagramSender,
+        gate: &Arc<ProfileGate>,
+        token: &CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        let url = url.to_string();
+        let credentials = self.credentials_for(&url);
+        let (target, sender, gate, token) = (target.clone(), sender.clone(), gate.clone(), token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = adaptive::run_substream(url, credentials, target, sender, gate, token).await {
+                error!("Substream error: {}", e);
+            }
+        })
+    }
+
+    pub fn with_middleware(mut self, middleware: MiddlewareChain) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    pub fn with_validation(mut self, validation: ValidationConfig) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Option<QuirkRule>) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn with_upstream(mut self, upstream: UpstreamConfig) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: ControlProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_transcript(mut self, transcript: Option<Transcript>) -> Self {
+        self.transcript = transcript;
+        self
+    }
 
+    pub fn with_mode(mut self, mode: ClientMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_dwell(mut self, dwell: Option<Duration>) -> Self {
+        self.dwell = dwell;
+        self
+    }
+
+    pub fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: SessionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_request_limits(mut self, request_limits: RequestLimits) -> Self {
+        self.request_limits = request_limits;
+        self
+    }
+
+
+    /// Run the session until either side ends it. A failed session closes
+    /// the browser's connection with the kind of failure.
     #[instrument(skip(self, transport))]
-    pub async fn handle_connection(&self, mut transport: Transport) -> Result<()> {
-        info!("Handling new connection via Transport abstraction");
+    pub async fn handle_connection(&mut self, mut transport: Transport) -> crate::error::Result<()> {
+        let closer = transport.closer();
+        let max_restarts = self.health.as_ref().map_or(0, |health| health.max_restarts);
+        let mut restarts = 0;
+        // Restarts carry on the browser's stream where the last session left it
+        let timeline = SessionTimeline::default();
+        let result = loop {
+            match self.run(&mut transport, &timeline).await {
+                // Only simple mode ends this way; it sets the camera up again by
+                // itself and the browser gets a new tracks message
+                Ok(Ended::Unhealthy(action)) if restarts < max_restarts => {
+                    restarts += 1;
+                    if action == HealthAction::Transcode {
+                        self.transcode = self.transcode_fallback.take();
+                    }
+                    info!("Restarting the session ({}, {} of {})", action.as_str(), restarts, max_restarts);
+                }
+                Ok(Ended::Unhealthy(action)) => {
+                    break Err(Error::UpstreamClosed(format!("stream health too low ({})", action.as_str())));
+                }
+                result => break result,
+            }
+        };
+        match &result {
+            Err(e) => closer.close(e).await,
+            // Closing at once could discard the event before it is delivered
+            Ok(Ended::EndOfStream) => {
+                let _ = tokio::time::timeout(END_OF_STREAM_GRACE, transport.closed()).await;
+            }
+            Ok(_) => {}
+        }
+        result.map(|_| ())
+    }
 
-        // 1. Reading/Writing control is now done via transport
-        // We don't accept_bi here anymore, we expect transport to be ready for control
+    /// Run the session on a connection that may outlive it, as a carousel's
+    /// does. The connection is left open whatever the outcome. Tracks keep
+    /// the SSRCs and timelines in `timeline` from earlier runs.
+    pub async fn run(&self, transport: &mut Transport, timeline: &SessionTimeline) -> crate::error::Result<Ended> {
+        info!("Handling new connection via Transport abstraction");
+        timeline.restart();
+        let startup = Arc::new(StartupTimer::new());
+        let memory = crate::memory::session(&self.rtsp_url, self.priority());
 
-        // 2. Connect to the RTSP server
-        let url = url::Url::parse(&self.rtsp_url).context("Invalid RTSP URL")?;
-        let host = url.host_str().context("Missing host in RTSP URL")?;
-        let port = url.port().unwrap_or(8554);
-        let addr = format!("{}:{}", host, port);
+        let url = url::Url::parse(&self.rtsp_url).map_err(|e| Error::Parse(format!("Invalid RTSP URL: {}", e)))?;
+        let addr = crate::rtsp_client::upstream_addr(&url)?;
+        // Held until the session ends
+        let _camera_slot = match self.upstream.camera_limit_for(&self.rtsp_url) {
+            Some(limit) => Some(self.camera_slot(&addr, limit, transport).await?),
+            None => None,
+        };
 
-        info!("Connecting to RTSP server at {}", addr);
-        let mut tcp_stream = TcpStream::connect(&addr)
-            .await
-            .context("Failed to connect to RTSP server")?;
-        
+        // Strict pass-through: the browser's requests reach the camera as sent,
+        // apart from the Transport header
+        let passthrough = self.upstream.passthrough_for(&self.rtsp_url);
+        let (upstream, translator, warm_describe) = self.connect(&url, &addr, passthrough).await?;
+        let crate::upstream::Upstream { reader: mut tcp_read, writer: tcp_write, camera_ip, interleaved } = upstream;
+        let demux = if interleaved { Some(crate::relay::InterleavedDemux::new().await?) } else { None };
         info!("Connected to RTSP server");
+        startup.mark(Phase::Connect);
 
-        let (mut tcp_read, mut tcp_write) = tcp_stream.split();
-
-        // For detecting connection loss
-        // let closed_fut = transport.closed(); // This borrows transport.
-        // tokio::pin!(closed_fut);
-        
-        // State management
-        let mut next_channel_id = 0;
-        let mut pending_setups: VecDeque<PendingSetup> = VecDeque::new();
-        let mut session_id: Option<String> = None;
-        
-        // Cancellation token for background tasks
         let cancel_token = CancellationToken::new();
+        let (notify_tx, mut notify_rx) = mpsc::channel::<Event>(16);
+
+        // Every RTP payload for the browser passes through the time-shift buffer
+        let timeshift = self.timeshift.as_ref().filter(|_| self.output == Output::Rtp).map(|config| TimeShift::new(config, transport.framing(), notify_tx.clone(), memory.clone()));
+        transport.set_timeshift(timeshift.clone());
+        let _export = timeshift.as_ref().map(|timeshift| crate::export::register(&self.session_id, timeshift));
+        let receive = Arc::new(SessionReceive::default());
+        let _receive = crate::receive::register(&self.session_id, &receive);
+        let started = Instant::now();
+        let bytes_sent = transport.bytes_sent();
+        let feedback = transport.datagram_receiver();
+        // The browser's messages are read while control messages and media go out
+        let (reader, writer, datagrams) = transport.split();
 
-        // Buffers
-        let mut wt_buf = BytesMut::with_capacity(4096);
-        let mut tcp_buf = BytesMut::with_capacity(4096);
+        // Health score, taken with the stall check. Only simple mode sets the
+        // camera up again by itself; elsewhere a low score is only reported.
+        // A fallback is only offered to a session that is not transcoding already.
+        let restart = self.mode == ClientMode::Simple;
+        let fallback = cfg!(feature = "transcode") && self.transcode.is_none() && self.transcode_fallback.is_some();
+
+        #[cfg(feature = "transcode")]
+        let rendition_gate = self
+            .transcode
+            .as_ref()
+            .filter(|rule| !rule.renditions.is_empty())
+            .map(|rule| Arc::new(crate::transcode::RenditionGate::new(rule, self.rendition.as_deref(), notify_tx.clone())));
+        #[cfg(feature = "transcode")]
+        let rendition_monitor = rendition_gate.as_deref().filter(|gate| !gate.pinned()).map(crate::transcode::RenditionMonitor::new);
+        #[cfg(feature = "transcode")]
+        let wants_feedback = self.substream_url.is_some() || rendition_monitor.is_some() || self.health.is_some();
+        #[cfg(not(feature = "transcode"))]
+        let wants_feedback = self.substream_url.is_some() || self.health.is_some();
+
+        let (keyframe_tx, mut keyframe_rx) = mpsc::channel::<KeyframeRequest>(4);
+        let (feedback_tx, mut feedback_rx) = mpsc::channel::<Feedback>(16);
+        // Unbounded, as the loop that sends on it is also the one that drains it
+        let (simple_tx, mut simple_rx) = mpsc::unbounded_channel::<RtspRequest>();
+        let camera_url = crate::upstream::camera_url(&self.rtsp_url);
+        let gate = Arc::new(ProfileGate::new(notify_tx.clone()));
+
+        let mut session = Session {
+            proxy: self,
+            timeline,
+            writer,
+            datagrams,
+            control: ControlCodec::new(self.protocol),
+            url,
+            passthrough,
+            tcp_write,
+            camera_ip,
+            interleaved,
+            demux,
+            mux_routes: Vec::new(),
+            translator,
+            warm_describe,
+            wt_buf: BytesMut::with_capacity(INITIAL_BUFFER),
+            tcp_buf: BytesMut::with_capacity(INITIAL_BUFFER),
+            control_buf: BytesMut::with_capacity(INITIAL_BUFFER),
+            shed_seen: 0,
+            next_channel_id: 0,
+            pending_setups: HashMap::new(),
+            session_id: None,
+            sdp: None,
+            sdp_base: camera_url.clone(),
+            aggregate_url: camera_url.clone(),
+            camera_url,
+            describe_urls: HashMap::new(),
+            play_cseqs: HashSet::new(),
+            reported: HashMap::new(),
+            track_configs: HashMap::new(),
+            track_urls: HashMap::new(),
+            auth: self.credentials_for(&self.rtsp_url).map(UpstreamAuth::new),
+            in_flight: HashMap::new(),
+            quirks: QuirkSelector::new(self.quirks.clone()),
+            keepalive: None,
+            keepalive_cseq: KEEPALIVE_CSEQ_BASE,
+            keepalive_pending: HashSet::new(),
+            cancel_token,
+            notify_tx,
+            startup,
+            memory,
+            timeshift,
+            receive,
+            started,
+            qos: crate::qos::session(&self.rtsp_url, self.priority(), bytes_sent.clone()),
+            bytes_sent,
+            remuxer: self.output.remuxer(),
+            media: Arc::new(MediaWatch::new()),
+            health: self.health.as_ref().map(|config| HealthMonitor::new(config, restart, fallback)),
+            end_of_stream: Arc::new(EndOfStream::default()),
+            torn_down: false,
+            gate,
+            congestion: CongestionMonitor::new(),
+            video_target: None,
+            substream_task: None,
+            degraded: false,
+            estimate_kbps: Arc::new(AtomicU32::new(0)),
+            #[cfg(feature = "transcode")]
+            rendition_gate,
+            #[cfg(feature = "transcode")]
+            rendition_monitor,
+            keyframe_tx,
+            firs: FirSender::default(),
+            // Lost datagrams are resent when the browser asks, in balanced mode
+            retransmitter: self.latency.filter(|mode| mode.retransmit() && !datagrams.reliable()).map(|_| Retransmitter::default()),
+            bitrate: BitrateCheck::new(&self.limits),
+            requests: RequestLimiter::new(self.request_limits),
+            simple: (self.mode != ClientMode::Rtsp).then(|| SimpleDriver::new(&self.rtsp_url, self.mode)),
+            simple_tx,
+            seek: (self.mode == ClientMode::Managed).then(|| Arc::new(SeekGate::default())),
+            timescale: (self.mode == ClientMode::Managed).then(|| Arc::new(Timescale::default())),
+        };
+        if let Some(driver) = session.simple.as_mut() {
+            let req = driver.start();
+            session.queue_request(req)?;
+        }
+
+        self.discover_capabilities(&session.notify_tx, &session.cancel_token);
+        // Camera-side alarms, alongside the video
+        if let Some(rule) = self.onvif.clone() {
+            let url = self.rtsp_url.clone();
+            let credentials = self.credentials_for(&url);
+            tokio::spawn(crate::onvif::bridge(rule, url, credentials, session.notify_tx.clone(), session.cancel_token.clone()));
+        }
+        self.limit_keyframe_interval();
+        if wants_feedback || session.retransmitter.is_some() {
+            let sender = datagrams.clone();
+            let retransmitter = session.retransmitter.clone();
+            let estimate_kbps = session.estimate_kbps.clone();
+            let token = session.cancel_token.clone();
+            tokio::spawn(read_feedback(feedback, sender, retransmitter, session.bytes_sent.clone(), estimate_kbps, feedback_tx, token));
+        }
 
-        loop {
-            tokio::select! {
+        #[cfg(feature = "chaos")]
+        let kill_upstream = crate::chaos::upstream_killed(started);
+        #[cfg(not(feature = "chaos"))]
+        let kill_upstream = std::future::pending::<()>();
+        tokio::pin!(kill_upstream);
+
+        // Time and bitrate limits from the config and the authorizer
+        let expired = self.limits.expired(started);
+        tokio::pin!(expired);
+
+        // Time on this camera, in a carousel
+        let dwell = async {
+            match self.dwell {
+                Some(dwell) => tokio::time::sleep(dwell).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(dwell);
+
+        // Stall detection on the camera's media
+        let mut stall_check = tokio::time::interval(STALL_CHECK_INTERVAL);
+
+        // How the session ended, for the caller
+        let outcome = loop {
+            let flow = tokio::select! {
                 // Read from Transport (Browser) -> Forward to TCP (RTSP Server)
-                res = transport.read_control(&mut wt_buf) => {
-                    let n = match res {
-                        Ok(n) => n,
-                        Err(e) => {
-                            error!("Transport read error: {}", e);
-                            break;
-                        }
-                    };
-                    
-                    if n == 0 {
-                        info!("Transport stream closed by client");
-                        break;
+                res = next_input(reader, &mut session.control_buf, &mut simple_rx) => match res {
+                    Ok(input) => session.on_input(input).await,
+                    Err(e) => {
+                        error!("Transport read error: {}", e);
+                        Err(e)
                     }
+                },
 
-                    // Process all complete requests in buffer
-                    while let Some((mut req, consumed)) = RtspRequest::parse(&wt_buf)? {
-                        wt_buf.advance(consumed);
-                        
-                        if req.method == "SETUP" {
-                            info!("Intercepted SETUP request");
-                            
-                            // 1. Allocate UDP ports
-                            let rtp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-                            let rtcp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-                            let rtp_port = rtp_socket.local_addr()?.port();
-                            let rtcp_port = rtcp_socket.local_addr()?.port();
-                            
-                            info!("Allocated UDP ports: RTP={}, RTCP={}", rtp_port, rtcp_port);
-
-                            // 2. Rewrite Transport header
-                            if let Some(transport) = req.headers.get_mut("Transport") {
-                                *transport = format!("RTP/AVP;unicast;client_port={}-{}", rtp_port, rtcp_port);
-                            }
+                // Read from TCP (RTSP Server) -> Forward to Transport (Browser)
+                res = tcp_read.read_buf(&mut session.tcp_buf) => session.on_camera_data(res).await,
 
-                            // 3. Store pending state
-                            let rtp_id = next_channel_id;
-                            let rtcp_id = next_channel_id + 1;
-                            next_channel_id += 2;
-
-                            pending_setups.push_back(PendingSetup {
-                                rtp_channel_id: rtp_id,
-                                rtcp_channel_id: rtcp_id,
-                                rtp_socket,
-                                rtcp_socket,
-                            });
-                        }
+                // Client feedback -> switch rendition or upstream profile under sustained congestion
+                Some(fb) = feedback_rx.recv() => {
+                    session.on_feedback(&fb);
+                    Ok(ControlFlow::Continue(()))
+                }
 
-                        // Forward to RTSP Server
-                        if let Err(e) = tcp_write.write_all(&req.to_bytes()).await {
-                            error!("Failed to write to RTSP server: {}", e);
-                            break;
-                        }
+                // Over a `[qos]` budget: the sub-stream for the rest of the session
+                _ = session.qos.degraded(), if !session.degraded => session.degrade().await.map(ControlFlow::Continue),
+
+                // Keep the upstream session alive
+                _ = keepalive_due(&mut session.keepalive) => session.send_keepalive().await.map(ControlFlow::Continue),
+
+                // A keyframe is overdue: FIR to the camera
+                Some(request) = keyframe_rx.recv() => session.request_keyframe(request).await.map(ControlFlow::Continue),
+
+                // Proxy-originated notification -> Browser
+                Some(event) = notify_rx.recv() => session.send_event(&event).await.map(ControlFlow::Continue),
+
+                // Camera media stopped or resumed
+                _ = stall_check.tick() => session.check().await,
+
+                expiry = &mut expired => {
+                    match expiry {
+                        Expiry::MaxDuration => warn!("Closing session: it reached its maximum duration"),
+                        Expiry::ViewingHours => warn!("Closing session: viewing hours are over"),
                     }
+                    // TEARDOWN follows below
+                    let _ = session.writer.write(&session.control.event(&Event::Expired(expiry))).await;
+                    Err(Error::PolicyDenied(expiry.as_str().to_string()))
                 }
-                
-                // Read from TCP (RTSP Server) -> Forward to Transport (Browser)
-                res = tcp_read.read_buf(&mut tcp_buf) => {
-                    let n = match res {
-                        Ok(n) => n,
-                        Err(e) => {
-                            error!("RTSP server read error: {}", e);
-                            break;
+
+                // The camera ended the stream; TEARDOWN follows below
+                _ = session.end_of_stream.ended(), if !session.torn_down => {
+                    let cause = session.end_of_stream.cause();
+                    info!("Camera ended the stream ({})", cause.as_str());
+                    crate::stats::global().add(&format!("sessions_end_of_stream{{cause=\"{}\"}}", cause.as_str()), 1);
+                    let _ = session.writer.write(&session.control.event(&Event::EndOfStream { cause })).await;
+                    Ok(ControlFlow::Break(Ended::EndOfStream))
+                }
+
+                // The carousel moves on; TEARDOWN follows below
+                _ = &mut dwell => {
+                    info!("Dwell time is up; moving on to the next camera");
+                    Ok(ControlFlow::Break(Ended::Dwell))
+                }
+
+                // Over a `[qos]` budget; TEARDOWN follows below
+                _ = session.qos.shed() => {
+                    let reason = session.qos.reason();
+                    warn!("Closing session to stay within the {} budget", reason.as_str());
+                    let event = Event::Qos { action: QosAction::Shed, reason, priority: session.qos.priority() };
+                    let _ = session.writer.write(&session.control.event(&event)).await;
+                    Err(Error::PolicyDenied(format!("over the {} budget", reason.as_str())))
+                }
+
+                // Over the memory budget
+                _ = session.memory.evicted() => {
+                    warn!("Closing session to stay within the memory budget");
+                    let event = Event::Qos { action: QosAction::Shed, reason: Congestion::Memory, priority: session.qos.priority() };
+                    let _ = session.writer.write(&session.control.event(&event)).await;
+                    Err(Error::PolicyDenied("over the memory budget".to_string()))
+                }
+
+                // Injected fault (`chaos` feature)
+                _ = &mut kill_upstream => {
+                    warn!("Chaos: dropping the camera connection");
+                    Err(Error::UpstreamClosed("dropped by fault injection".to_string()))
+                }
+            };
+            match flow {
+                Ok(ControlFlow::Continue(())) => {}
+                Ok(ControlFlow::Break(ended)) => break Ok(ended),
+                Err(e) => break Err(e),
+            }
+        };
+
+        session.close().await;
+        outcome
+    }
+
+    /// Connect to the camera, or take its warm standby connection. Returns
+    /// the connection, a translator when the camera speaks RTSP 2.0, and the
+    /// standby's answer to DESCRIBE.
+    async fn connect(
+        &self,
+        url: &url::Url,
+        addr: &str,
+        passthrough: bool,
+    ) -> crate::error::Result<(crate::upstream::Upstream, Option<Translator>, Option<crate::warm::CachedDescribe>)> {
+        // Version check on its own connection, before the relayed one opens
+        let version = if self.upstream.rtsp2 && !passthrough {
+            let credentials = self.credentials_for(&self.rtsp_url);
+            crate::rtsp2::negotiate(&self.rtsp_url, credentials, self.quirks.clone(), &self.upstream).await
+        } else {
+            RtspVersion::V1
+        };
+        let translator = (version == RtspVersion::V2).then(Translator::new);
+
+        // A warm standby connection has DESCRIBE answered already; it speaks 1.0
+        let warm = (version == RtspVersion::V1 && !passthrough).then(|| crate::warm::take(&self.rtsp_url)).flatten();
+        let (upstream, warm_describe) = match warm {
+            Some((upstream, describe)) => {
+                info!("Using the warm standby connection to {}", addr);
+                (upstream, describe)
+            }
+            None => {
+                info!("Connecting to RTSP server at {}", addr);
+                let upstream = crate::upstream::connect(url, &self.upstream)
+                    .await
+                    .map_err(|e| Error::UpstreamConnect(format!("{:#}", e)))?;
+                (upstream, None)
+            }
+        };
+        Ok((upstream, translator, warm_describe))
+    }
+
+    /// Discover what the camera supports so the player can offer the right controls
+    fn discover_capabilities(&self, notify_tx: &mpsc::Sender<Event>, token: &CancellationToken) {
+        let url = self.rtsp_url.clone();
+        let credentials = self.credentials_for(&url);
+        let quirks = self.quirks.clone();
+        let upstream = self.upstream.clone();
+        let (notify_tx, token) = (notify_tx.clone(), token.clone());
+        tokio::spawn(async move {
+            let discovered = tokio::select! {
+                _ = token.cancelled() => return,
+                res = crate::capabilities::discover(&url, credentials, quirks, &upstream) => res,
+            };
+            match discovered {
+                Ok(capabilities) => {
+                    info!("Camera supports: {}", capabilities.methods.join(", "));
+                    let _ = notify_tx.send(Event::Capabilities(capabilities)).await;
+                }
+                Err(e) => warn!("Capability discovery failed: {:#}", e),
+            }
+        });
+    }
+
+    /// Ask the camera for a shorter GOP, when keyframes are kept close
+    /// together by configuring it
+    fn limit_keyframe_interval(&self) {
+        let Some(rule) = &self.keyframes else {
+            return;
+        };
+        info!("Keeping keyframes at most {:?} apart ({})", rule.interval(), rule.method.as_str());
+        match rule.method {
+            KeyframeMethod::Onvif => {
+                let device_url = self.onvif.as_ref().and_then(|onvif| onvif.device_url.clone());
+                let credentials = self.credentials_for(&self.rtsp_url);
+                tokio::spawn(crate::onvif::limit_gov_length(device_url, self.rtsp_url.clone(), credentials, rule.interval()));
+            }
+            KeyframeMethod::Transcode if self.transcode.is_none() => {
+                warn!("Keyframe method 'transcode' needs a [[transcode]] rule for {}; the camera's GOP is kept", self.rtsp_url);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How a session that did not fail ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ended {
+    /// The browser closed the connection
+    Closed,
+    /// Its dwell time in a carousel ran out
+    Dwell,
+    /// The camera ended the stream, as at the end of a recording
+    EndOfStream,
+    /// Its health stayed low and `action` needs a new session; simple mode only
+    Unhealthy(HealthAction),
+}
+
+/// Whether a request from the camera says the stream has ended: RealNetworks'
+/// `x-Notice: 2101 End-of-Stream Reached` on ANNOUNCE, or an RTSP 2.0
+/// PLAY_NOTIFY with `Notify-Reason: end-of-stream`
+fn announces_end(req: &RtspRequest) -> bool {
+    let header = |name: &str| req.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.trim());
+    match req.method.as_str() {
+        "ANNOUNCE" => header("x-Notice").is_some_and(|notice| notice.starts_with("2101")),
+        "PLAY_NOTIFY" => header("Notify-Reason").is_some_and(|reason| reason.eq_ignore_ascii_case("end-of-stream")),
+        _ => false,
+    }
+}
+
+/// Control input for a session
+enum Input {
+    /// Bytes read from the browser into the control buffer; 0 when it closed
+    Browser(usize),
+    /// Request the proxy makes on the browser's behalf in simple or managed mode
+    Proxy(RtspRequest),
+}
+
+async fn next_input(reader: &mut ControlReader, control_buf: &mut BytesMut, proxy_rx: &mut mpsc::UnboundedReceiver<RtspRequest>) -> crate::error::Result<Input> {
+    tokio::select! {
+        Some(req) = proxy_rx.recv() => Ok(Input::Proxy(req)),
+        res = reader.read(control_buf) => Ok(Input::Browser(res?)),
+    }
+}
+
+/// Resolves at the next keep-alive; never before the camera has given the
+/// session an ID
+/// Keep-alive period for a `Session` header: half its timeout, at least a second
+fn keepalive_period(session: &str) -> Duration {
+    let timeout = session
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("timeout=")?.parse().ok())
+        .filter(|&secs: &u64| secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SESSION_TIMEOUT);
+    (timeout / 2).max(MIN_KEEPALIVE_PERIOD)
+}
+
+async fn keepalive_due(keepalive: &mut Option<tokio::time::Interval>) {
+    match keepalive {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Read the browser's feedback: NACKs are answered from the retransmit
+/// buffer, and reports and acks become bandwidth estimates on `feedback_tx`
+async fn read_feedback(
+    receiver: DatagramReceiver,
+    sender: DatagramSender,
+    retransmitter: Option<Retransmitter>,
+    sent: Arc<AtomicU64>,
+    estimate_kbps: Arc<AtomicU32>,
+    feedback_tx: mpsc::Sender<Feedback>,
+    token: CancellationToken,
+) {
+    let mut estimator = BandwidthEstimator::default();
+    // Clients that acknowledge media have their reports taken from the acks
+    let mut acked = false;
+    loop {
+        let msg = tokio::select! {
+            _ = token.cancelled() => break,
+            msg = receiver.recv() => msg,
+        };
+        match msg {
+            Ok(Some(data)) => {
+                if let (Some(retransmitter), Some(nack)) = (&retransmitter, Nack::parse(&data)) {
+                    for payload in retransmitter.lookup(&nack) {
+                        crate::stats::global().add("retransmits", 1);
+                        if let Err(e) = sender.resend(payload).await {
+                            warn!("Failed to resend media: {}", e);
                         }
+                    }
+                } else if let Some(ack) = Ack::parse(&data) {
+                    acked = true;
+                    let Some(mut fb) = estimator.on_ack(ack, sent.load(Ordering::Relaxed), Instant::now()) else {
+                        continue;
                     };
-                    
-                    if n == 0 {
-                        info!("RTSP server closed connection");
+                    // Never more than the QUIC connection could carry
+                    if let (Some(estimate), Some(quic)) = (fb.estimate_kbps, sender.quic_estimate_kbps()) {
+                        fb.estimate_kbps = Some(estimate.min(quic));
+                    }
+                    estimate_kbps.store(fb.estimate_kbps.unwrap_or(0), Ordering::Relaxed);
+                    if feedback_tx.send(fb).await.is_err() {
                         break;
                     }
-                    
-                    // Process all complete responses in buffer
-                    while let Some((mut resp, consumed)) = RtspResponse::parse(&tcp_buf)? {
-                        tcp_buf.advance(consumed);
-                        
-                        // Capture Session ID if present
-                        if let Some(sid) = resp.headers.get("Session") {
-                            // Session ID might have ;timeout=...
-                            let clean_sid = sid.split(';').next().unwrap_or(sid).to_string();
-                            if session_id.is_none() {
-                                info!("Captured Session ID: {}", clean_sid);
-                                session_id = Some(clean_sid);
-                            }
-                        }
-
-                        if resp.status_code == 200 {
-                            if resp.headers.contains_key("Transport") {
-                                if let Some(setup) = pending_setups.pop_front() {
-                                    info!("Intercepted SETUP response, injecting channel IDs {}-{}", setup.rtp_channel_id, setup.rtcp_channel_id);
-                                    
-                                    // Inject Channel IDs into Transport header
-                                    if let Some(transport) = resp.headers.get_mut("Transport") {
-                                        *transport = format!("{};x-wt-channel-id={}-{}", transport, setup.rtp_channel_id, setup.rtcp_channel_id);
-                                    }
-                                    
-                                    // Spawn UDP forwarders
-                                    // We need to clone the transport sender part
-                                    // Assuming transport.clone_sender() exists and returns a DatagramSender
-                                    let sender = transport.clone_sender(); 
-                                    let rtp_socket = setup.rtp_socket.clone();
-                                    let rtp_id = setup.rtp_channel_id;
-                                    let token = cancel_token.clone();
-                                    
-                                    tokio::spawn(async move {
-                                        if let Err(e) = forward_udp(rtp_socket, sender, rtp_id, token).await {
-                                            // Only log error if not cancelled
-                                            error!("RTP forwarder error: {}", e);
-                                        }
-                                    });
-                                    
-                                    let sender = transport.clone_sender(); 
-                                    let rtcp_socket = setup.rtcp_socket.clone();
-                                    let rtcp_id = setup.rtcp_channel_id;
-                                    let token = cancel_token.clone();
-                                    
-                                    tokio::spawn(async move {
-                                        if let Err(e) = forward_udp(rtcp_socket, sender, rtcp_id, token).await {
-                                            error!("RTCP forwarder error: {}", e);
-                                        }
-                                    });
-                                }
-                            }
-                        }
-                        
-                        // Forward to Browser
-                        if let Err(e) = transport.write_control(&resp.to_bytes()).await {
-                            error!("Failed to write to Transport: {}", e);
-                            break;
-                        }
+                } else if let Some(mut fb) = Feedback::parse(&data)
+                    && !acked
+                {
+                    fb.estimate_kbps = sender.quic_estimate_kbps();
+                    estimate_kbps.store(fb.estimate_kbps.unwrap_or(0), Ordering::Relaxed);
+                    if feedback_tx.send(fb).await.is_err() {
+                        break;
                     }
                 }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Feedback receive error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// What the session loop does after a handler: carry on, end the session,
+/// or fail it. TEARDOWN follows either way.
+type Flow = crate::error::Result<ControlFlow<Ended>>;
+
+/// Where the camera sends a track's media from
+struct MediaSource {
+    /// Transport header of the camera's SETUP response
+    transport: String,
+    address: IpAddr,
+    /// The camera's RTP and RTCP ports, when it gave them
+    ports: Option<(u16, u16)>,
+    /// Whether the shared UDP ports receive the track's media
+    muxed: bool,
+}
+
+/// One run of a session: the state its event loop's handlers share
+struct Session<'a> {
+    proxy: &'a RTSPProxy,
+    /// SSRCs and timelines kept from earlier runs
+    timeline: &'a SessionTimeline,
+    writer: &'a ControlWriter,
+    datagrams: &'a DatagramSender,
+    control: ControlCodec,
+    /// The camera's URL, for rewriting the addresses it advertises
+    url: url::Url,
+    /// Strict pass-through: the browser's requests reach the camera as sent,
+    /// apart from the Transport header
+    passthrough: bool,
+    tcp_write: crate::upstream::UpstreamWriter,
+    camera_ip: IpAddr,
+    /// Whether media is requested interleaved on the RTSP connection
+    interleaved: bool,
+    /// Media requested interleaved is split off the RTSP connection here
+    demux: Option<crate::relay::InterleavedDemux>,
+    /// Routes from the shared UDP ports to each track, while the session lasts
+    mux_routes: Vec<crate::mux::MuxRoute>,
+    /// Requests to and responses from an RTSP 2.0 camera
+    translator: Option<Translator>,
+    /// The warm standby's description, which stands in for the camera's
+    /// answer to DESCRIBE
+    warm_describe: Option<crate::warm::CachedDescribe>,
+
+    /// The browser's RTSP, the camera's RTSP and the browser's control stream
+    wt_buf: BytesMut,
+    tcp_buf: BytesMut,
+    control_buf: BytesMut,
+    /// Requests to shed memory handled so far
+    shed_seen: u64,
+
+    next_channel_id: u8,
+    /// SETUPs awaiting their response, by CSeq, so several can be in flight
+    pending_setups: HashMap<String, PendingSetup>,
+    session_id: Option<String>,
+    /// Presentation description, the base its control URLs resolve against,
+    /// and the aggregate URL the proxy uses for its own requests, as the
+    /// camera knows them (without the port of an `rtsp+http://` URL)
+    sdp: Option<SessionDescription>,
+    camera_url: String,
+    sdp_base: String,
+    aggregate_url: String,
+    describe_urls: HashMap<String, String>,
+    play_cseqs: HashSet<String>,
+    /// Methods whose failures are reported to the browser as events, by CSeq
+    reported: HashMap<String, String>,
+    /// H.264 video tracks' channel and parameter sets, by control URL, for ANNOUNCE
+    track_configs: HashMap<String, (u8, Arc<TrackConfig>)>,
+    /// RTP channels by control URL, to match a seek's RTP-Info to its tracks
+    track_urls: HashMap<String, u8>,
+
+    /// Tenant credentials: requests are authorized by the proxy, and kept by
+    /// CSeq so a Digest challenge can be answered without the browser
+    auth: Option<UpstreamAuth>,
+    in_flight: HashMap<String, RtspRequest>,
+    /// Camera quirks, and proxy keep-alives so the camera does not time the
+    /// session out (the browser never sends any)
+    quirks: QuirkSelector,
+    keepalive: Option<tokio::time::Interval>,
+    keepalive_cseq: u32,
+    keepalive_pending: HashSet<String>,
+
+    /// Stops the session's background tasks
+    cancel_token: CancellationToken,
+    /// Proxy-originated events for the browser (sync points, capabilities,
+    /// stalls, profile switches)
+    notify_tx: mpsc::Sender<Event>,
+    startup: Arc<StartupTimer>,
+    memory: Arc<SessionMemory>,
+    timeshift: Option<Arc<TimeShift>>,
+    receive: Arc<SessionReceive>,
+    started: Instant,
+    bytes_sent: Arc<AtomicU64>,
+    qos: Arc<crate::qos::SessionQos>,
+    /// Container output: every track feeds the one muxer
+    remuxer: Option<Arc<std::sync::Mutex<crate::remux::Remuxer>>>,
+    /// Stall detection on the camera's media
+    media: Arc<MediaWatch>,
+    health: Option<HealthMonitor>,
+    /// The camera ending the stream, as an NVR does at the end of a
+    /// recording; BYEs that answer the browser's TEARDOWN do not count
+    end_of_stream: Arc<EndOfStream>,
+    torn_down: bool,
+
+    /// Adaptive substream switching, driven by client feedback
+    gate: Arc<ProfileGate>,
+    congestion: CongestionMonitor,
+    video_target: Option<adaptive::SubstreamTarget>,
+    substream_task: Option<tokio::task::JoinHandle<()>>,
+    /// Whether a `[qos]` budget moved the session to the sub-stream
+    degraded: bool,
+    /// Latest bandwidth estimate for the client, in kbps; 0 until there is one
+    estimate_kbps: Arc<AtomicU32>,
+    /// Transcoded renditions: the one the client picked, or one chosen from its feedback
+    #[cfg(feature = "transcode")]
+    rendition_gate: Option<Arc<crate::transcode::RenditionGate>>,
+    #[cfg(feature = "transcode")]
+    rendition_monitor: Option<crate::transcode::RenditionMonitor>,
+
+    /// Keyframes the camera is asked for when its GOP runs long, and where
+    /// each track's requests go
+    keyframe_tx: mpsc::Sender<KeyframeRequest>,
+    firs: FirSender,
+    retransmitter: Option<Retransmitter>,
+    bitrate: BitrateCheck,
+    requests: RequestLimiter,
+
+    /// Simple and managed mode: requests the proxy makes on the browser's
+    /// behalf are handled like the browser's own
+    simple: Option<SimpleDriver>,
+    simple_tx: mpsc::UnboundedSender<RtspRequest>,
+    /// Managed mode: media from before a seek is dropped
+    seek: Option<Arc<SeekGate>>,
+    /// Managed mode: timestamps follow the Scale and Speed the camera granted
+    timescale: Option<Arc<Timescale>>,
+}
+
+impl Session<'_> {
+    /// Send a frame on the control stream
+    async fn send_control(&mut self, frame: &[u8]) -> crate::error::Result<()> {
+        self.writer.write(frame).await.inspect_err(|e| error!("Failed to write to Transport: {}", e))
+    }
+
+    async fn send_rtsp(&mut self, resp: &RtspResponse) -> crate::error::Result<()> {
+        let frame = self.control.rtsp(&resp.to_bytes());
+        self.send_control(&frame).await
+    }
+
+    async fn send_message(&mut self, message: &ServerMessage) -> crate::error::Result<()> {
+        let frame = self.control.message(message);
+        self.send_control(&frame).await
+    }
+
+    async fn send_event(&mut self, event: &Event) -> crate::error::Result<()> {
+        let frame = self.control.event(event);
+        self.send_control(&frame).await
+    }
+
+    /// Send an RTSP message to the camera
+    async fn send_camera(&mut self, message: &[u8]) -> crate::error::Result<()> {
+        if let Some(transcript) = &self.proxy.transcript {
+            transcript.sent(message);
+        }
+        self.write_camera(message).await
+    }
+
+    async fn write_camera(&mut self, data: &[u8]) -> crate::error::Result<()> {
+        self.tcp_write.write_all(data).await.map_err(|e| {
+            error!("Failed to write to RTSP server: {}", e);
+            Error::UpstreamClosed(e.to_string())
+        })
+    }
+
+    /// Handle a request simple mode makes like one from the browser, once
+    /// the current input is done with
+    fn queue_request(&self, req: RtspRequest) -> crate::error::Result<()> {
+        self.simple_tx.send(req).map_err(|_| Error::transport("simple mode's request queue is closed"))
+    }
+
+    /// The browser's control stream, or a request simple mode makes
+    async fn on_input(&mut self, input: Input) -> Flow {
+        match input {
+            Input::Browser(0) => {
+                info!("Transport stream closed by client");
+                return Ok(ControlFlow::Break(Ended::Closed));
+            }
+            Input::Browser(_) => {}
+            Input::Proxy(req) => self.wt_buf.extend_from_slice(&req.to_bytes()),
+        }
+
+        // In simple and managed mode the browser's own RTSP is not relayed
+        let mut ignored = BytesMut::new();
+        let rtsp_in = if self.simple.is_some() { &mut ignored } else { &mut self.wt_buf };
+        let commands = self.control.decode(&mut self.control_buf, rtsp_in);
+        if !ignored.is_empty() {
+            warn!("Ignoring RTSP from the browser in {} mode", self.proxy.mode.as_str());
+        }
+        for command in commands {
+            self.on_command(command).await?;
+        }
+
+        // Process all complete requests in buffer
+        while let Some((req, consumed)) = RtspRequest::parse(&self.wt_buf)? {
+            self.on_request(req, consumed).await?;
+        }
+
+        // The rest of an oversized request could not be told apart from the next one
+        let limits = self.proxy.request_limits;
+        if limits.overflowed(self.wt_buf.len()) {
+            warn!("Browser sent an RTSP request over {} bytes; closing the session", limits.max_bytes);
+            let resp = RtspResponse::new(413, "Request Entity Too Large");
+            let _ = self.writer.write(&self.control.rtsp(&resp.to_bytes())).await;
+            return Err(Error::PolicyDenied(format!("RTSP request over {} bytes", limits.max_bytes)));
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn on_command(&mut self, command: Command) -> crate::error::Result<()> {
+        match command {
+            Command::Stats => {
+                let stats = SessionStats {
+                    uptime: self.started.elapsed(),
+                    bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+                    profile: self.gate.active(),
+                    stalled: self.media.is_stalled(),
+                    startup: self.startup.report(),
+                    channels: self.receive.reports(),
+                    estimate_kbps: Some(self.estimate_kbps.load(Ordering::Relaxed)).filter(|&kbps| kbps > 0),
+                };
+                let frame = self.control.stats(&stats);
+                self.send_control(&frame).await?;
+            }
+            Command::PauseLive | Command::Rewind { .. } | Command::Resume | Command::Live if self.timeshift.is_none() => {
+                warn!("Ignoring {:?}: time-shift is not configured", command);
+            }
+            Command::PauseLive => self.timeshift.as_ref().expect("guarded").pause(),
+            Command::Rewind { seconds } => {
+                self.timeshift.as_ref().expect("guarded").rewind(Duration::from_secs(seconds), self.datagrams.clone(), &self.cancel_token);
+            }
+            Command::Resume => self.timeshift.as_ref().expect("guarded").resume(self.datagrams.clone(), &self.cancel_token),
+            Command::Live => self.timeshift.as_ref().expect("guarded").live(),
+            Command::Play | Command::Pause | Command::Seek { .. } | Command::Scale { .. } | Command::Speed { .. } | Command::SelectTrack { .. } => {
+                let Some(driver) = self.simple.as_mut() else {
+                    warn!("Ignoring {:?}: playback commands need mode=managed", command);
+                    return Ok(());
+                };
+                let step = driver.command(command);
+                if let Some(seek) = &self.seek {
+                    seek.set_flushing(driver.seeking());
+                }
+                match step {
+                    Step::Send(req) => self.queue_request(req)?,
+                    Step::Message(message) => self.send_message(&message).await?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A request from the browser, or simple mode, on its way to the camera
+    async fn on_request(&mut self, mut req: RtspRequest, consumed: usize) -> crate::error::Result<()> {
+        // A browser that knows the camera by name addresses it as stream://<name>
+        if let Some(stream) = &self.proxy.stream
+            && let Some(rest) = req.path.strip_prefix(&stream.request_url())
+            && (rest.is_empty() || rest.starts_with(['/', '?']))
+        {
+            req.path = format!("{}{}", self.camera_url.trim_end_matches('/'), rest);
+        }
+        // Floods and requests the camera should never see are answered
+        // here; simple mode's requests are the proxy's own
+        let rejected = self.simple.is_none().then(|| {
+            self.requests.check(&req, consumed).or_else(|| {
+                // The session's URL, the presentation base and the SDP's control URLs
+                let controls: Vec<String> = self
+                    .sdp
+                    .iter()
+                    .flat_map(|sdp| &sdp.media)
+                    .map(|m| crate::sdp::resolve_control(&self.sdp_base, m.control.as_deref()))
+                    .collect();
+                let targets: Vec<&str> = [self.camera_url.as_str(), &self.sdp_base, &self.aggregate_url]
+                    .into_iter()
+                    .chain(controls.iter().map(String::as_str))
+                    .collect();
+                crate::validate::check(&self.proxy.validation, &self.wt_buf[..consumed], &req, &targets)
+            })
+        });
+        let raw = self.passthrough.then(|| self.wt_buf[..consumed].to_vec());
+        self.wt_buf.advance(consumed);
+        if let Some(resp) = rejected.flatten() {
+            return self.send_rtsp(&resp).await;
+        }
+        if !self.passthrough {
+            req.path = crate::upstream::camera_url(&req.path);
+        }
+
+        if !self.passthrough && let Some(resp) = self.proxy.middleware.on_request(&mut req) {
+            return self.send_rtsp(&resp).await;
+        }
+
+        // Media is expected to stop while paused
+        match req.method.as_str() {
+            "PAUSE" => self.media.set_paused(true),
+            "PLAY" => self.media.set_paused(false),
+            "TEARDOWN" => self.torn_down = true,
+            _ => {}
+        }
+
+        if req.method == "SETUP" && !req.headers.contains_key("CSeq") {
+            warn!("SETUP without CSeq; its media cannot be relayed");
+        }
+        if req.method == "SETUP"
+            && let Some(cseq) = req.headers.get("CSeq").cloned()
+        {
+            self.prepare_setup(&mut req, cseq).await?;
+        }
+
+        if req.method == "DESCRIBE"
+            && let Some(cseq) = req.headers.get("CSeq")
+        {
+            self.describe_urls.insert(cseq.clone(), req.path.clone());
+        }
+        if req.method == "PLAY"
+            && let Some(cseq) = req.headers.get("CSeq")
+        {
+            self.play_cseqs.insert(cseq.clone());
+        }
+        if matches!(req.method.as_str(), "DESCRIBE" | "SETUP" | "PLAY")
+            && let Some(cseq) = req.headers.get("CSeq")
+        {
+            self.reported.insert(cseq.clone(), req.method.clone());
+        }
+
+        if let Some(translator) = self.translator.as_mut() {
+            translator.request(&mut req);
+        }
+        // In pass-through the browser authenticates itself
+        if !self.passthrough && let Some(auth) = self.auth.as_mut() {
+            auth.authorize(&mut req);
+            if let Some(cseq) = req.headers.get("CSeq") {
+                self.in_flight.insert(cseq.clone(), req.clone());
+            }
+        }
+
+        // The warm standby's description stands in for the camera's answer
+        if self.warm_describe.as_ref().is_some_and(|cached| cached.answers(&req)) {
+            info!("Answering DESCRIBE from the warm standby");
+            self.warm_describe.take().expect("checked").answer(&req);
+            return Ok(());
+        }
+
+        // Forward to RTSP Server
+        let message = match (raw, req.headers.get("Transport")) {
+            (Some(raw), Some(transport)) if req.method == "SETUP" => crate::passthrough::replace_transport(&raw, transport),
+            (Some(raw), _) => raw,
+            (None, _) => req.to_bytes(),
+        };
+        self.send_camera(&message).await
+    }
+
+    /// Ports and channels for a track the browser sets up, and the SETUP's
+    /// Transport header rewritten for them
+    async fn prepare_setup(&mut self, req: &mut RtspRequest, cseq: String) -> crate::error::Result<()> {
+        info!("Intercepted SETUP request (CSeq {})", cseq);
+
+        // 1. Allocate UDP ports
+        let shard = Shard::next();
+        let rtp_socket = Arc::new(shard.bind_udp().await?);
+        let rtcp_socket = Arc::new(shard.bind_udp().await?);
+        let rtp_port = rtp_socket.local_addr()?.port();
+        let rtcp_port = rtcp_socket.local_addr()?.port();
+
+        info!("Allocated UDP ports: RTP={}, RTCP={}", rtp_port, rtcp_port);
+
+        // 2. Allocate channel IDs
+        let rtp_id = self.next_channel_id;
+        let rtcp_id = self.next_channel_id + 1;
+        self.next_channel_id += 2;
+
+        // 3. Rewrite Transport header; with a UDP mux the camera sends to the shared ports
+        let (rtp_port, rtcp_port) = crate::mux::client_ports().unwrap_or((rtp_port, rtcp_port));
+        if let Some(transport) = req.headers.get_mut("Transport") {
+            *transport = if self.interleaved {
+                format!("RTP/AVP/TCP;unicast;interleaved={}-{}", rtp_id, rtcp_id)
+            } else if self.passthrough {
+                crate::passthrough::transport(transport, rtp_port, rtcp_port)
+            } else {
+                format!("RTP/AVP;unicast;client_port={}-{}", rtp_port, rtcp_port)
+            };
+        }
+
+        // 4. Store pending state
+        let media = self
+            .sdp
+            .as_ref()
+            .and_then(|sdp| sdp.media_for_url(&self.sdp_base, &req.path))
+            .cloned();
+        if let Some(media) = &media {
+            info!("SETUP is for {} track ({})", media.kind, media.encoding.as_deref().unwrap_or("unknown codec"));
+        }
+
+        self.pending_setups.insert(cseq, PendingSetup {
+            rtp_channel_id: rtp_id,
+            rtcp_channel_id: rtcp_id,
+            rtp_socket,
+            rtcp_socket,
+            shard,
+            media,
+        });
+        Ok(())
+    }
+
+    /// What the camera sent on the RTSP connection
+    async fn on_camera_data(&mut self, res: std::io::Result<usize>) -> Flow {
+        let n = res.map_err(|e| {
+            error!("RTSP server read error: {}", e);
+            Error::UpstreamClosed(e.to_string())
+        })?;
+        if n == 0 {
+            info!("RTSP server closed connection");
+            return Err(Error::UpstreamClosed("the camera closed the connection".to_string()));
+        }
+
+        // Process all complete responses in buffer, replaying interleaved media between them
+        while !self.demux.as_mut().is_some_and(|demux| demux.drain(&mut self.tcp_buf)) {
+            // Requests from the camera (ANNOUNCE) are answered here, not forwarded
+            if self.tcp_buf.len() >= 5 && !self.tcp_buf.starts_with(b"RTSP/") {
+                let Some((req, consumed)) = RtspRequest::parse(&self.tcp_buf)? else {
+                    break;
+                };
+                if let Some(transcript) = &self.proxy.transcript {
+                    transcript.received(&self.tcp_buf[..consumed]);
+                }
+                self.tcp_buf.advance(consumed);
+                self.on_camera_request(req).await?;
+                continue;
+            }
+            let Some((resp, consumed)) = RtspResponse::parse(&self.tcp_buf)? else {
+                break;
+            };
+            if let Some(transcript) = &self.proxy.transcript {
+                transcript.received(&self.tcp_buf[..consumed]);
+            }
+            self.tcp_buf.advance(consumed);
+            self.on_response(resp).await?;
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// A request from the camera, answered here rather than forwarded
+    async fn on_camera_request(&mut self, req: RtspRequest) -> crate::error::Result<()> {
+        let mut resp = if announces_end(&req) {
+            info!("Camera announced the end of the stream");
+            self.end_of_stream.finish(EndCause::Announce);
+            RtspResponse::new(200, "OK")
+        } else if req.method == "ANNOUNCE" {
+            self.on_announce(&req).await?;
+            RtspResponse::new(200, "OK")
+        } else {
+            warn!("Camera sent an unsupported {} request", req.method);
+            RtspResponse::new(501, "Not Implemented")
+        };
+        if let Some(cseq) = req.headers.get("CSeq") {
+            resp.headers.insert("CSeq".to_string(), cseq.clone());
+        }
+        self.send_camera(&resp.to_bytes()).await
+    }
+
+    /// New parameters the camera announced for its tracks
+    async fn on_announce(&mut self, req: &RtspRequest) -> crate::error::Result<()> {
+        let description = SessionDescription::parse(&String::from_utf8_lossy(&req.body));
+        for media in &description.media {
+            let url = crate::sdp::resolve_control(&self.sdp_base, media.control.as_deref());
+            let Some((channel_id, config)) = self.track_configs.get(&url) else {
+                continue;
+            };
+            let previous = self.sdp.as_ref().and_then(|sdp| sdp.media_for_url(&self.sdp_base, &url));
+            if previous.is_some_and(|p| p.fmtp == media.fmtp && p.dimensions == media.dimensions) {
+                continue;
+            }
+            let parameter_sets = media.parameter_sets();
+            let sps = parameter_sets.iter().find(|nal| nal[0] & 0x1F == 7);
+            let stream = match sps.and_then(|nal| crate::h264::parse_sps(nal)) {
+                Some(parsed) => crate::reconfig::stream_info(media, &parsed),
+                None => media.info(),
+            };
+            let profile_level_id = sps
+                .and_then(|nal| crate::reconfig::profile_level_id(nal))
+                .or_else(|| media.fmtp_param("profile-level-id").map(str::to_ascii_uppercase));
+            info!("Camera announced new parameters for channel {}", channel_id);
+            crate::stats::global().add("codec_config_changes", 1);
+            config.announce(parameter_sets);
+            let event = Event::CodecConfig { channel_id: *channel_id, stream, profile_level_id, cause: ConfigChange::Announce };
+            self.send_event(&event).await?;
+        }
+        self.sdp = Some(description);
+        Ok(())
+    }
+
+    /// A response from the camera: kept here, handed to simple mode's
+    /// driver, or forwarded to the browser
+    async fn on_response(&mut self, mut resp: RtspResponse) -> crate::error::Result<()> {
+        if let Some(translator) = self.translator.as_mut() {
+            translator.response(&mut resp);
+        }
+        self.quirks.observe(&resp.headers);
+
+        // Retry once with the camera's Digest challenge instead of passing the 401 on
+        let request = resp.headers.get("CSeq").and_then(|cseq| self.in_flight.remove(cseq));
+        if resp.status_code == 401
+            && let (Some(auth), Some(mut req)) = (self.auth.as_mut(), request)
+            && auth.on_unauthorized(&resp)
+        {
+            info!("Answering {} authentication challenge with configured credentials", req.method);
+            auth.authorize(&mut req);
+            return self.send_camera(&req.to_bytes()).await;
+        }
+
+        // Answers to the proxy's own keep-alives stay here
+        if resp.headers.get("CSeq").is_some_and(|cseq| self.keepalive_pending.remove(cseq)) {
+            if !(200..300).contains(&resp.status_code) {
+                warn!("Keep-alive failed: {} {}", resp.status_code, resp.reason);
+            }
+            return Ok(());
+        }
+
+        self.proxy.middleware.on_response(&mut resp);
+
+        if let Some(method) = resp.headers.get("CSeq").and_then(|cseq| self.reported.remove(cseq))
+            && resp.status_code >= 400
+        {
+            warn!("Camera refused {}: {} {}", method, resp.status_code, resp.reason);
+            let class = crate::control::status_class(resp.status_code);
+            crate::stats::global().add(&format!("rtsp_upstream_errors{{method=\"{}\",class=\"{}\"}}", method, class), 1);
+            let event = Event::UpstreamError { method, code: resp.status_code, reason: resp.reason.clone() };
+            self.send_event(&event).await?;
+        }
+
+        if self.session_id.is_none()
+            && let Some(sid) = resp.headers.get("Session")
+        {
+            self.start_keepalive(sid);
+        }
+
+        // Remember the presentation description to identify tracks at SETUP
+        if resp.headers.get("Content-Type").is_some_and(|t| t.starts_with("application/sdp")) {
+            self.on_description(&mut resp);
+        }
+
+        let setup = resp.headers.get("CSeq").and_then(|cseq| self.pending_setups.remove(cseq));
+        if let Some(setup) = &setup
+            && resp.status_code != 200
+        {
+            warn!("SETUP for channels {}-{} failed: {} {}", setup.rtp_channel_id, setup.rtcp_channel_id, resp.status_code, resp.reason);
+        }
+        if resp.headers.get("CSeq").is_some_and(|cseq| self.play_cseqs.remove(cseq)) && resp.status_code == 200 {
+            self.startup.mark(Phase::Play);
+        }
+        if resp.status_code == 200
+            && resp.headers.contains_key("Transport")
+            && let Some(setup) = setup
+        {
+            self.start_track(&mut resp, setup).await?;
+        }
+
+        // In simple and managed mode, answers go to the driver instead of the browser
+        if let Some(driver) = self.simple.as_mut() {
+            let step = driver.on_response(&resp);
+            if let Some(seek) = &self.seek {
+                seek.set_flushing(driver.seeking());
+            }
+            if let Some(timescale) = &self.timescale {
+                timescale.set(driver.rate());
+            }
+            return self.on_step(step, &resp).await;
+        }
+
+        // Forward to Browser
+        #[cfg(feature = "chaos")]
+        crate::chaos::delay_response().await;
+        self.send_rtsp(&resp).await
+    }
+
+    /// Capture the session ID the camera gave, and keep the session alive
+    /// within its timeout
+    fn start_keepalive(&mut self, sid: &str) {
+        // Session ID might have ;timeout=...
+        let clean_sid = sid.split(';').next().unwrap_or(sid).to_string();
+        info!("Captured Session ID: {}", clean_sid);
+        self.session_id = Some(clean_sid);
+
+        let period = keepalive_period(sid);
+        self.keepalive = Some(tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    }
+
+    /// The camera's presentation description: kept to identify tracks at
+    /// SETUP, and rewritten for what the browser will receive
+    fn on_description(&mut self, resp: &mut RtspResponse) {
+        if self.proxy.upstream.rewrite_advertised_addresses {
+            crate::nat::rewrite_describe(resp, &self.url);
+        }
+        let description = SessionDescription::parse(&String::from_utf8_lossy(&resp.body));
+        let request_url = resp.headers.get("CSeq").and_then(|cseq| self.describe_urls.remove(cseq));
+        self.sdp_base = crate::sdp::content_base(&resp.headers, request_url.as_deref().unwrap_or(&self.camera_url));
+        self.aggregate_url = description.aggregate_url(&self.sdp_base);
+        info!("Presentation base {}, aggregate control {}", self.sdp_base, self.aggregate_url);
+        self.sdp = Some(description);
+        self.startup.mark(Phase::Describe);
+
+        // MPEG-TS tracks reach the browser as the H.264 demuxed from them
+        if self.sdp.as_ref().is_some_and(|s| s.media.iter().any(crate::mpegts::is_transport_stream)) {
+            info!("Camera sends MPEG-TS; demuxing its H.264 video");
+            let rewritten = crate::mpegts::rewrite_sdp(&String::from_utf8_lossy(&resp.body));
+            resp.body = rewritten.into_bytes();
+            resp.headers.insert("Content-Length".to_string(), resp.body.len().to_string());
+        }
+
+        // Advertise the transcoder's output instead of the camera's codec
+        #[cfg(feature = "transcode")]
+        if self.proxy.transcode.is_some()
+            && let Some(video) = self.sdp.as_ref().and_then(|s| s.first_video())
+        {
+            let rewritten = crate::transcode::rewrite_sdp(&String::from_utf8_lossy(&resp.body), video);
+            resp.body = rewritten.into_bytes();
+            resp.headers.insert("Content-Length".to_string(), resp.body.len().to_string());
+        }
+    }
+
+    /// A track the camera set up: the routes its media takes, the Transport
+    /// header the browser gets, and the forwarders that relay it
+    async fn start_track(&mut self, resp: &mut RtspResponse, setup: PendingSetup) -> crate::error::Result<()> {
+        self.startup.mark(Phase::Setup);
+        info!("Intercepted SETUP response, injecting channel IDs {}-{}", setup.rtp_channel_id, setup.rtcp_channel_id);
+
+        // Where the camera sends this track's media from
+        let camera_transport = resp.headers.get("Transport").cloned().unwrap_or_default();
+        if let Some(demux) = self.demux.as_mut() {
+            demux.map(&camera_transport, setup.rtp_socket.local_addr()?.port(), setup.rtcp_socket.local_addr()?.port());
+        }
+        let server_ports = crate::nat::server_ports(&camera_transport);
+        let address = crate::nat::source_address(&camera_transport).unwrap_or(self.camera_ip);
+        let mux_route = if self.interleaved {
+            None
+        } else {
+            let ports = (setup.rtp_socket.local_addr()?.port(), setup.rtcp_socket.local_addr()?.port());
+            crate::mux::MuxRoute::new(&camera_transport, address, self.proxy.upstream.validate_source, ports.0, ports.1)
+        };
+        if self.proxy.upstream.hole_punch && let Some(ports) = server_ports {
+            if mux_route.is_some() {
+                crate::mux::punch(address, ports).await;
+            } else {
+                crate::nat::punch(&setup.rtp_socket, &setup.rtcp_socket, address, ports).await;
+            }
+        }
+        // The mux already routed by the camera's address; its replays come from loopback
+        let muxed = mux_route.is_some();
+        self.mux_routes.extend(mux_route);
+
+        // Inject Channel IDs and the RTP delivery mode into Transport header
+        if let Some(transport) = resp.headers.get_mut("Transport") {
+            *transport = self.client_transport(transport, &setup);
+        }
+        // Simple mode's driver already knows the channels
+        if self.proxy.channel_ids.format == ChannelIdFormat::Event && self.simple.is_none() {
+            let cseq = resp.headers.get("CSeq").cloned().unwrap_or_default();
+            let event = Event::Channels { cseq, rtp_channel_id: setup.rtp_channel_id, rtcp_channel_id: setup.rtcp_channel_id };
+            self.send_event(&event).await?;
+        }
+
+        let source = MediaSource { transport: camera_transport, address, ports: server_ports, muxed };
+        self.spawn_forwarders(&setup, &source);
+        Ok(())
+    }
+
+    /// The Transport header the browser gets for a track: the camera's,
+    /// with the track's channels and how its media is delivered
+    fn client_transport(&self, camera: &str, setup: &PendingSetup) -> String {
+        let delivery = self.datagrams.delivery_mode();
+        let framing = self.datagrams.framing();
+        let channels = self.proxy.channel_ids.transport_param(setup.rtp_channel_id, setup.rtcp_channel_id);
+        let mut transport = format!(
+            "{}{};x-wt-delivery={};x-wt-framing={}",
+            self.quirks.quirks().normalize_transport(camera),
+            channels.map(|param| format!(";{}", param)).unwrap_or_default(),
+            delivery.as_str(),
+            framing.as_str()
+        );
+        if let Some(max) = self.datagrams.max_datagram_size() {
+            transport.push_str(&format!(";x-wt-max-datagram={}", max));
+        }
+        // What the latency mode asks of the player: a reliable path needs no jitter buffer
+        if let Some(mode) = self.proxy.latency {
+            let jitter = if self.datagrams.reliable() { Duration::ZERO } else { mode.jitter_buffer() };
+            transport.push_str(&format!(
+                ";x-wt-latency={};x-wt-jitter-ms={};x-wt-nack={}",
+                mode.as_str(),
+                jitter.as_millis(),
+                if self.retransmitter.is_some() { "yes" } else { "no" }
+            ));
+        }
+        // Metadata tracks are marked so the browser routes them to overlays
+        if let Some(format) = setup.media.as_ref().and_then(MetadataFormat::for_media) {
+            transport.push_str(&format!(";x-wt-metadata={}", format.as_str()));
+        }
+        transport
+    }
+
+    /// Relay a track's RTP and RTCP to the browser, or its video through the transcoder
+    fn spawn_forwarders(&mut self, setup: &PendingSetup, source: &MediaSource) {
+        let is_video = setup.media.as_ref().is_some_and(|m| m.kind == "video");
+        #[cfg(feature = "transcode")]
+        let transcoded = is_video && self.spawn_transcoder(setup);
+        #[cfg(not(feature = "transcode"))]
+        let transcoded = false;
+        if transcoded {
+            return;
+        }
+
+        // Proxy-chosen SSRC shared by this track's RTP and RTCP, and the
+        // track's timeline, both kept when the session is set up again
+        let clock_rate = setup.media.as_ref().and_then(|m| m.clock_rate).unwrap_or(90000);
+        let (ssrc, continuity) = self.timeline.channel(setup.rtp_channel_id, clock_rate);
+        let proxy = self.proxy;
+
+        // The first video track follows the adaptive profile gate, on a
+        // timeline that stays continuous when the profile switches
+        let gated = is_video && proxy.substream_url.is_some() && self.video_target.is_none();
+        if gated {
+            self.video_target = Some(adaptive::SubstreamTarget {
+                rtp_channel_id: setup.rtp_channel_id,
+                rtcp_channel_id: setup.rtcp_channel_id,
+                ssrc,
+                clock_rate,
+                rtp: proxy.rtp,
+                quirks: proxy.quirks.clone(),
+                upstream: proxy.upstream.clone(),
+                continuity: continuity.clone(),
+                notify: self.notify_tx.clone(),
+                memory: self.memory.clone(),
+                retransmit: self.retransmitter.clone(),
+                parameter_sets: proxy.parameter_sets.clone(),
+            });
+            self.qos.set_degradable();
+        }
+        let formats = setup.media.as_ref().map(|m| m.formats.as_slice());
+        // MPEG-TS is demuxed; the camera's RTCP describes the TS timeline, not the output's
+        let transport_stream = setup.media.as_ref().is_some_and(crate::mpegts::is_transport_stream);
+        let h264 = transport_stream
+            || setup.media.as_ref().and_then(|m| m.encoding.as_deref()).is_some_and(|e| e.eq_ignore_ascii_case("H264"));
+        if is_video && h264 && let Some(timeshift) = &self.timeshift {
+            let parameter_sets = setup.media.as_ref().map(MediaDescription::parameter_sets).unwrap_or_default();
+            timeshift.set_video(ExportTrack { channel_id: setup.rtp_channel_id, parameter_sets });
+        }
+        let audio = match (&proxy.audio_levels, &setup.media) {
+            (Some(config), Some(media)) if media.kind == "audio" => {
+                AudioMeter::new(media, setup.rtp_channel_id, config, self.notify_tx.clone())
+            }
+            _ => None,
+        };
+        let motion = match &proxy.motion {
+            Some(config) if is_video => Some(MotionDetector::new(setup.rtp_channel_id, config, self.notify_tx.clone())),
+            _ => None,
+        };
+        let metadata = match &setup.media {
+            Some(media) if proxy.metadata.objects => ObjectParser::new(media, setup.rtp_channel_id, self.notify_tx.clone()),
+            _ => None,
+        };
+        let make_rewriter = |rtcp| {
+            let rewriter = if gated {
+                RtpRewriter::continuous(&proxy.rtp, ssrc, rtcp, continuity.clone(), Profile::Main as u8)
+            } else {
+                RtpRewriter::always(&proxy.rtp, ssrc, rtcp).with_continuity(continuity.clone(), Profile::Main as u8)
+            };
+            Some(match &self.timescale {
+                Some(timescale) => rewriter.with_timescale(timescale.clone()),
+                None => rewriter,
+            })
+        };
+
+        // Tracks the browser cannot lose packets of skip ahead when it falls behind
+        let keyframes = h264 || setup.media.as_ref().and_then(|m| m.encoding.as_deref()).is_some_and(|e| e.eq_ignore_ascii_case("H265"));
+        let flow = match self.datagrams.reliable() {
+            true => FlowControl::new(setup.rtp_channel_id, clock_rate, is_video && keyframes, &proxy.flow_control, self.notify_tx.clone()),
+            false => None,
+        };
+        // Overdue keyframes are asked for with a FIR on the camera's RTCP path
+        let fir_route = match &proxy.keyframes {
+            Some(rule) if rule.method == KeyframeMethod::Fir && is_video && keyframes && !transport_stream => self.fir_route(setup, source),
+            _ => None,
+        };
+        let cadence = proxy.keyframes.as_ref().zip(fir_route).map(|(rule, route)| {
+            self.firs.add(setup.rtp_channel_id, route);
+            KeyframeWatch::new(setup.rtp_channel_id, rule.interval(), self.keyframe_tx.clone())
+        });
+        // Containers carry the parameter sets themselves
+        let parameter_sets = match (&setup.media, &self.remuxer) {
+            (Some(media), None) if is_video => ParameterSetInjector::new(media, &proxy.parameter_sets),
+            _ => None,
+        };
+        // Kept up to date when the camera announces new parameter sets
+        let reconfig = setup.media.as_ref().and_then(|media| {
+            let config = Arc::new(TrackConfig::default());
+            let watch = CodecWatch::new(setup.rtp_channel_id, media, config.clone(), self.notify_tx.clone())?;
+            let url = crate::sdp::resolve_control(&self.sdp_base, media.control.as_deref());
+            self.track_configs.insert(url, (setup.rtp_channel_id, config));
+            Some(watch)
+        });
+        if let Some(media) = &setup.media {
+            self.track_urls.insert(crate::sdp::resolve_control(&self.sdp_base, media.control.as_deref()), setup.rtp_channel_id);
+        }
+        let source_filter = |rtcp: bool| {
+            let port = source.ports.map(|(rtp, rtcp_port)| if rtcp { rtcp_port } else { rtp });
+            SourceFilter::new(proxy.upstream.validate_source, source.address, port).filter(|_| !source.muxed)
+        };
+
+        let sender = self.datagrams.clone();
+        let rtp_socket = setup.rtp_socket.clone();
+        let rtp_id = setup.rtp_channel_id;
+        let token = self.cancel_token.clone();
+        let pipeline = PacketPipeline {
+            source: source_filter(false),
+            payload: PayloadFilter::new(&proxy.rtp, formats, false),
+            seek: self.seek.clone(),
+            receive: Some(self.receive.channel(rtp_id, clock_rate)),
+            motion,
+            cadence,
+            bye: None,
+            gate: gated.then(|| GateHandle::new(self.gate.clone(), Profile::Main, false)),
+            demux: transport_stream.then(|| TsRepacketizer::new(self.datagrams.max_datagram_size())),
+            audio,
+            metadata,
+            remux: self.remuxer.as_ref().map(|remuxer| RemuxTrack::new(remuxer, setup.media.as_ref())),
+            flow,
+            parameter_sets,
+            reconfig,
+            retransmit: self.retransmitter.clone(),
+            rewriter: make_rewriter(false),
+            sync: None,
+            media: Some(self.media.clone()),
+            startup: Some(self.startup.clone()),
+            memory: Some(self.memory.clone()),
+        };
+
+        setup.shard.spawn(async move {
+            if let Err(e) = forward_udp(rtp_socket, sender, rtp_id, pipeline, token).await {
+                // Only log error if not cancelled
+                error!("RTP forwarder error: {}", e);
+            }
+        });
+
+        let sender = self.datagrams.clone();
+        let rtcp_socket = setup.rtcp_socket.clone();
+        let rtcp_id = setup.rtcp_channel_id;
+        let token = self.cancel_token.clone();
+        self.end_of_stream.watch(rtcp_id);
+        let pipeline = PacketPipeline {
+            source: source_filter(true),
+            payload: PayloadFilter::new(&proxy.rtp, None, true),
+            seek: None,
+            receive: None,
+            motion: None,
+            cadence: None,
+            bye: Some(self.end_of_stream.clone()),
+            gate: gated.then(|| GateHandle::new(self.gate.clone(), Profile::Main, true)),
+            demux: None,
+            audio: None,
+            metadata: None,
+            remux: self.remuxer.as_ref().map(RemuxTrack::skip),
+            flow: None,
+            parameter_sets: None,
+            reconfig: None,
+            retransmit: None,
+            rewriter: make_rewriter(true),
+            sync: (!transport_stream).then(|| ClockSync::new(rtp_id, clock_rate, self.notify_tx.clone())),
+            media: None,
+            startup: None,
+            memory: Some(self.memory.clone()),
+        };
+
+        setup.shard.spawn(async move {
+            if let Err(e) = forward_udp(rtcp_socket, sender, rtcp_id, pipeline, token).await {
+                error!("RTCP forwarder error: {}", e);
+            }
+        });
+    }
+
+    /// Transcode a video track instead of relaying it; false without a
+    /// rule or an SDP section to transcode by
+    #[cfg(feature = "transcode")]
+    fn spawn_transcoder(&self, setup: &PendingSetup) -> bool {
+        let (Some(rule), Some(media)) = (&self.proxy.transcode, &setup.media) else {
+            return false;
+        };
+        // Transcoded video replaces both the RTP and RTCP forwarders;
+        // the camera's RTCP describes a stream the browser never sees
+        let sender = self.datagrams.clone();
+        let rtp_socket = setup.rtp_socket.clone();
+        let rtcp_socket = setup.rtcp_socket.clone();
+        let rtp_id = setup.rtp_channel_id;
+        let (media, rule) = (media.clone(), rule.clone());
+        let renditions = self.rendition_gate.clone();
+        let keyframe_interval = self.proxy.keyframes.as_ref().map(KeyframeRule::interval);
+        // The transcoder sends its parameter sets in band
+        if let Some(timeshift) = &self.timeshift {
+            timeshift.set_video(ExportTrack { channel_id: rtp_id, parameter_sets: Vec::new() });
+        }
+        let memory = self.memory.clone();
+        let token = self.cancel_token.clone();
+        setup.shard.spawn(async move {
+            // Keep the RTCP port bound for the camera while transcoding
+            let _rtcp_socket = rtcp_socket;
+            if let Err(e) = crate::transcode::forward_transcoded(rtp_socket, sender, rtp_id, media, rule, keyframe_interval, renditions, memory, token).await {
+                error!("Transcode forwarder error: {}", e);
+            }
+        });
+        true
+    }
+
+    /// Where a track's FIRs reach the camera's RTCP
+    fn fir_route(&self, setup: &PendingSetup, source: &MediaSource) -> Option<FirRoute> {
+        if self.interleaved {
+            crate::relay::interleaved_channels(&source.transport).and_then(|(_, rtcp)| rtcp).map(FirRoute::Interleaved)
+        } else {
+            let socket = crate::mux::rtcp_socket().filter(|_| source.muxed).unwrap_or_else(|| setup.rtcp_socket.clone());
+            source.ports.map(|(_, rtcp)| FirRoute::Udp(socket, SocketAddr::new(source.address, rtcp)))
+        }
+    }
+
+    /// What simple mode's driver does after a camera response
+    async fn on_step(&mut self, step: Step, resp: &RtspResponse) -> crate::error::Result<()> {
+        match step {
+            Step::Ignore => {}
+            Step::Send(req) => self.queue_request(req)?,
+            Step::Ready(tracks) => {
+                info!("Session playing in {} mode", self.proxy.mode.as_str());
+                self.send_message(&tracks).await?;
+            }
+            Step::Message(message) => self.send_message(&message).await?,
+            Step::Seeked(position) => {
+                // Packets from before the new position may still be in flight
+                if let Some(seek) = &self.seek {
+                    let resume_at = resp.headers.get("RTP-Info").map(|info| crate::seek::rtp_info(info)).unwrap_or_default();
+                    seek.resume(resume_at.into_iter()
+                        .filter_map(|(url, seq)| Some((*self.track_urls.get(&crate::sdp::resolve_control(&self.sdp_base, Some(&url)))?, seq)))
+                        .collect());
+                }
+                info!("Seeked to {:.3}s", position);
+                let frames = [self.control.event(&Event::Discontinuity { position }), self.control.message(&ServerMessage::State { state: "playing" })];
+                for frame in frames {
+                    self.send_control(&frame).await?;
+                }
+            }
+            Step::Failed(reason) => {
+                warn!("Session failed in {} mode: {}", self.proxy.mode.as_str(), reason);
+                let _ = self.writer.write(&self.control.message(&ServerMessage::Error { command: None, error: reason.clone() })).await;
+                return Err(Error::RtspProtocol(reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Client feedback: switch rendition or upstream profile under sustained congestion
+    fn on_feedback(&mut self, fb: &Feedback) {
+        if let Some(health) = self.health.as_mut() {
+            health.on_feedback(fb);
+        }
+        #[cfg(feature = "transcode")]
+        if let (Some(gate), Some(monitor)) = (&self.rendition_gate, &mut self.rendition_monitor) {
+            if let Some(index) = monitor.on_feedback(fb) {
+                info!("Client feedback (loss {:.1}%, {} kbps): switching to rendition {}", fb.loss_fraction * 100.0, fb.received_kbps, index);
+                gate.request(index);
+            }
+            return;
+        }
+        let (Some(substream_url), Some(target)) = (&self.proxy.substream_url, &self.video_target) else {
+            return;
+        };
+        let Some(profile) = self.congestion.on_feedback(fb) else {
+            return;
+        };
+
+        info!("Client feedback (loss {:.1}%, {} kbps): switching to {:?} profile", fb.loss_fraction * 100.0, fb.received_kbps, profile);
+        self.gate.request(profile);
+
+        let running = self.substream_task.as_ref().is_some_and(|t| !t.is_finished());
+        if profile == Profile::Sub && !running {
+            self.substream_task = Some(self.proxy.spawn_substream(substream_url, target, self.datagrams, &self.gate, &self.cancel_token));
+        }
+    }
+
+    /// Over a `[qos]` budget: the sub-stream for the rest of the session
+    async fn degrade(&mut self) -> crate::error::Result<()> {
+        self.degraded = true;
+        let (Some(substream_url), Some(target)) = (&self.proxy.substream_url, &self.video_target) else {
+            return Ok(());
+        };
+        self.congestion.hold(Profile::Sub);
+        self.gate.request(Profile::Sub);
+        let running = self.substream_task.as_ref().is_some_and(|t| !t.is_finished());
+        if !running {
+            self.substream_task = Some(self.proxy.spawn_substream(substream_url, target, self.datagrams, &self.gate, &self.cancel_token));
+        }
+        let event = Event::Qos { action: QosAction::Degrade, reason: self.qos.reason(), priority: self.qos.priority() };
+        self.send_event(&event).await
+    }
+
+    /// Keep the upstream session alive
+    async fn send_keepalive(&mut self) -> crate::error::Result<()> {
+        let mut req = RtspRequest::new(self.quirks.quirks().keepalive.method(), &self.aggregate_url);
+        req.headers.insert("CSeq".to_string(), self.keepalive_cseq.to_string());
+        if let Some(sid) = &self.session_id {
+            req.headers.insert("Session".to_string(), sid.clone());
+        }
+        if let Some(translator) = self.translator.as_mut() {
+            translator.request(&mut req);
+        }
+        if let Some(auth) = self.auth.as_mut() {
+            auth.authorize(&mut req);
+            self.in_flight.insert(self.keepalive_cseq.to_string(), req.clone());
+        }
+        self.keepalive_pending.insert(self.keepalive_cseq.to_string());
+        self.keepalive_cseq += 1;
+        self.send_camera(&req.to_bytes()).await
+    }
+
+    /// A keyframe is overdue: FIR to the camera
+    async fn request_keyframe(&mut self, request: KeyframeRequest) -> crate::error::Result<()> {
+        let Some((route, packet)) = self.firs.request(request) else {
+            return Ok(());
+        };
+        crate::stats::global().add("keyframe_requests", 1);
+        match route {
+            FirRoute::Udp(socket, camera) => {
+                if let Err(e) = socket.send_to(&packet, *camera).await {
+                    warn!("Failed to send FIR to {}: {}", camera, e);
+                }
+            }
+            FirRoute::Interleaved(_) => self.write_camera(&packet).await?,
+        }
+        Ok(())
+    }
+
+    /// The once-a-second check: buffers, the bitrate limit, health and stalls
+    async fn check(&mut self) -> Flow {
+        self.account_buffers();
+        if self.bitrate.exceeded(self.bytes_sent.load(Ordering::Relaxed)) {
+            warn!("Closing session: over its {} kbit/s limit", self.proxy.limits.max_bitrate_kbps.unwrap_or_default());
+            return Err(Error::PolicyDenied("over the bitrate limit".to_string()));
+        }
+        if let Some(action) = self.check_health().await? {
+            return Ok(ControlFlow::Break(Ended::Unhealthy(action)));
+        }
+        let Some(event) = self.media.check() else {
+            return Ok(ControlFlow::Continue(()));
+        };
+        match &event {
+            Event::Stall { idle } => warn!("No media from the camera for {:?}", idle),
+            _ => info!("Media from the camera resumed"),
+        }
+        self.send_event(&event).await?;
+        Ok(ControlFlow::Continue(()))
+    }
 
-                // _ = closed_fut => {
-                //      error!("Connection closed");
-                //      break;
-                // }
+    /// Account the session's buffers to its memory; those that grew for a
+    /// large message go back to their initial size when memory runs short
+    fn account_buffers(&mut self) {
+        if self.memory.shed_requested(&mut self.shed_seen) {
+            for buf in [&mut self.wt_buf, &mut self.tcp_buf, &mut self.control_buf] {
+                if buf.is_empty() && buf.capacity() > INITIAL_BUFFER {
+                    *buf = BytesMut::with_capacity(INITIAL_BUFFER);
+                }
             }
         }
-        
-        // Cleanup
+        self.memory.set(Usage::Control, self.wt_buf.capacity() + self.tcp_buf.capacity() + self.control_buf.capacity());
+    }
+
+    /// Score the session's health and act on a score that stayed low.
+    /// Returns the action when it needs a new session.
+    async fn check_health(&mut self) -> crate::error::Result<Option<HealthAction>> {
+        let Some(monitor) = self.health.as_mut() else {
+            return Ok(None);
+        };
+        let Some(action) = monitor.check(self.media.is_stalled(), &self.receive.reports(), Instant::now()) else {
+            return Ok(None);
+        };
+        let score = monitor.score();
+        warn!("Stream health {} stayed low: {}", score, action.as_str());
+        crate::stats::global().add(&format!("health_actions{{action=\"{}\"}}", action.as_str()), 1);
+        let config = self.proxy.health.as_ref().expect("health is configured");
+        if action == HealthAction::Transcode {
+            crate::health::fall_back(&self.proxy.rtsp_url, config.fallback());
+        }
+        let alert = Alert { session_id: self.proxy.session_id.clone(), rtsp_url: self.proxy.rtsp_url.clone(), score, action: action.as_str(), inputs: monitor.inputs() };
+        let config = config.clone();
+        tokio::spawn(async move { crate::health::notify(&config, &alert).await });
+        self.send_event(&Event::Health { score, action }).await?;
+        // A reconnect or fallback is a new session; TEARDOWN follows when the loop ends
+        Ok((action != HealthAction::Alert).then_some(action))
+    }
+
+    /// Stop the session's background tasks and end its session at the camera
+    async fn close(&mut self) {
         info!("Cleaning up connection...");
-        cancel_token.cancel(); // Stop UDP forwarders
-        
+        self.cancel_token.cancel(); // Stop UDP forwarders
+
         // Send TEARDOWN if we have a session ID
-        if let Some(sid) = session_id {
-            info!("Sending TEARDOWN for session {}", sid);
-            let teardown = format!(
-                "TEARDOWN {} RTSP/1.0\r\nCSeq: 99\r\nSession: {}\r\n\r\n",
-                self.rtsp_url, sid
-            );
-            
-            // We ignore errors here as the connection might be broken
-            let _ = tcp_write.write_all(teardown.as_bytes()).await;
+        let Some(sid) = self.session_id.take() else {
+            return;
+        };
+        info!("Sending TEARDOWN for session {}", sid);
+        // Numbered and authorized like the keep-alives
+        let mut req = RtspRequest::new("TEARDOWN", &self.aggregate_url);
+        req.headers.insert("CSeq".to_string(), self.keepalive_cseq.to_string());
+        req.headers.insert("Session".to_string(), sid);
+        if let Some(translator) = self.translator.as_mut() {
+            translator.request(&mut req);
         }
+        if let Some(auth) = self.auth.as_mut() {
+            auth.authorize(&mut req);
+        }
+        let teardown = req.to_bytes();
 
-        Ok(())
+        // We ignore errors here as the connection might be broken
+        if let Some(transcript) = &self.proxy.transcript {
+            transcript.sent(&teardown);
+        }
+        let _ = self.tcp_write.write_all(&teardown).await;
     }
 }
 
-async fn forward_udp(
+/// Receive buffer size for camera media, so large packets are not truncated
+pub(crate) const MAX_UDP_PACKET: usize = 65_535;
+
+/// Per-packet stages of a UDP forwarder, applied in field order
+#[derive(Default)]
+pub(crate) struct PacketPipeline {
+    /// Drop packets that did not come from the camera
+    pub source: Option<SourceFilter>,
+    /// Drop malformed packets and unannounced payload types
+    pub payload: Option<PayloadFilter>,
+    /// Drop media from before a seek
+    pub seek: Option<Arc<SeekGate>>,
+    /// Loss, jitter and bitrate of the camera's RTP
+    pub receive: Option<Arc<ChannelMeter>>,
+    /// Watch the camera's main stream for motion hints
+    pub motion: Option<MotionDetector>,
+    /// Ask the camera for a keyframe when one is overdue
+    pub cadence: Option<KeyframeWatch>,
+    /// Notice the camera ending the stream with RTCP BYE
+    pub bye: Option<Arc<EndOfStream>>,
+    /// Adaptive profile gate
+    pub gate: Option<GateHandle>,
+    /// Re-packetize MPEG-TS as H.264; one packet in may give none or several out
+    pub demux: Option<TsRepacketizer>,
+    /// Measure audio levels for level and voice activity events
+    pub audio: Option<AudioMeter>,
+    /// Turn ONVIF analytics into object events
+    pub metadata: Option<ObjectParser>,
+    /// Remux into the session's container output instead of sending RTP
+    pub remux: Option<RemuxTrack>,
+    /// Skip ahead when the browser falls behind on a reliable path
+    pub flow: Option<FlowControl>,
+    /// Send H.264 parameter sets ahead of IDRs that lack them
+    pub parameter_sets: Option<ParameterSetInjector>,
+    /// Notice the camera changing its H.264 parameter sets
+    pub reconfig: Option<CodecWatch>,
+    /// Keep sent packets for the browser's NACKs
+    pub retransmit: Option<Retransmitter>,
+    pub rewriter: Option<RtpRewriter>,
+    /// Turn RTCP sender reports into clock sync notifications
+    pub sync: Option<ClockSync>,
+    /// Record camera activity for stall events
+    pub media: Option<Arc<MediaWatch>>,
+    /// Record when the first packet reached the browser
+    pub startup: Option<Arc<StartupTimer>>,
+    /// Session the receive buffer is accounted to
+    pub memory: Option<Arc<SessionMemory>>,
+}
+
+pub(crate) async fn forward_udp(
     socket: Arc<UdpSocket>, 
-    sender: crate::transport::TransportSender, 
+    sender: crate::transport::DatagramSender, 
     channel_id: u8,
+    mut pipeline: PacketPipeline,
     token: CancellationToken
 ) -> Result<()> {
-    let mut buf = [0u8; 2048];
+    let mut buf = vec![0u8; MAX_UDP_PACKET];
+    let _hold = pipeline.memory.as_ref().map(|memory| memory.hold(Usage::Receive, buf.len()));
     loop {
         tokio::select! {
             _ = token.cancelled() => {
@@ -240,16 +2101,54 @@ async fn forward_udp(
             }
             res = socket.recv_from(&mut buf) => {
                 match res {
-                    Ok((n, _)) => {
-                        let mut payload = bytes::BytesMut::with_capacity(n + 1);
-                        payload.extend_from_slice(&[channel_id]);
-                        payload.extend_from_slice(&buf[..n]);
-                        
-                        if let Err(e) = sender.send_datagram(payload.freeze()).await {
-                            // If connection is closed, we should stop
-                            return Err(anyhow::anyhow!("Failed to send datagram: {}", e));
+                    Ok((n, from)) => {
+                        if pipeline.source.as_mut().is_some_and(|s| !s.admit(from)) {
+                            continue;
+                        }
+                        if let Some(media) = &pipeline.media {
+                            media.touch();
+                        }
+                        if pipeline.payload.as_ref().is_some_and(|p| !p.admit(&buf[..n])) {
+                            continue;
+                        }
+                        if pipeline.seek.as_ref().is_some_and(|s| !s.admit(channel_id, &buf[..n])) {
+                            continue;
+                        }
+                        if let Some(receive) = &pipeline.receive {
+                            receive.on_rtp(&buf[..n]);
+                        }
+                        if let Some(motion) = pipeline.motion.as_mut() {
+                            motion.on_rtp(&buf[..n]);
+                        }
+                        if let Some(cadence) = pipeline.cadence.as_mut() {
+                            cadence.on_rtp(&buf[..n]);
+                        }
+                        if let Some(bye) = &pipeline.bye
+                            && crate::rtp::has_bye(&buf[..n])
+                        {
+                            bye.on_bye(channel_id);
+                        }
+                        if pipeline.gate.as_ref().is_some_and(|g| !g.admit(&buf[..n])) {
+                            continue;
+                        }
+
+                        if let Some(audio) = pipeline.audio.as_mut() {
+                            audio.on_rtp(&buf[..n]);
+                        }
+                        if let Some(metadata) = pipeline.metadata.as_mut() {
+                            metadata.on_rtp(&buf[..n]);
+                        }
+
+                        match pipeline.demux.as_mut().map(|demux| demux.push(&buf[..n])) {
+                            Some(packets) => {
+                                for packet in packets {
+                                    send_packet(&sender, channel_id, &mut pipeline, &packet).await?;
+                                }
+                            }
+                            None => send_packet(&sender, channel_id, &mut pipeline, &buf[..n]).await?,
                         }
                     }
+                    Err(e) if crate::udp::is_send_error(&e) => continue,
                     Err(e) => {
                         return Err(anyhow::anyhow!("UDP recv error: {}", e));
                     }
@@ -258,3 +2157,94 @@ async fn forward_udp(
         }
     }
 }
+
+/// Send one packet to the browser, unless it is skipping ahead
+async fn send_packet(sender: &crate::transport::DatagramSender, channel_id: u8, pipeline: &mut PacketPipeline, packet: &[u8]) -> Result<()> {
+    if let Some(parameter_sets) = pipeline.reconfig.as_mut().and_then(|watch| watch.on_rtp(packet)) {
+        if let Some(remux) = pipeline.remux.as_mut() {
+            remux.restart(&parameter_sets);
+        }
+        if let Some(injector) = pipeline.parameter_sets.as_mut() {
+            injector.replace(&parameter_sets);
+        }
+    }
+    if pipeline.flow.as_mut().is_some_and(|flow| !flow.admit(packet)) {
+        return Ok(());
+    }
+    let result = match pipeline.parameter_sets.as_mut().and_then(|injector| injector.push(packet)) {
+        Some(packets) => {
+            let mut result = Ok(());
+            for packet in packets {
+                result = send_media(sender, channel_id, pipeline, &packet).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+            result
+        }
+        None => send_media(sender, channel_id, pipeline, packet).await,
+    };
+    if let Some(flow) = pipeline.flow.as_mut() {
+        flow.sent();
+    }
+    result
+}
+
+/// Rewrite or remux, frame and send one packet to the browser
+async fn send_media(sender: &crate::transport::DatagramSender, channel_id: u8, pipeline: &mut PacketPipeline, packet: &[u8]) -> Result<()> {
+    if let Some(remux) = pipeline.remux.as_mut() {
+        for message in remux.push(packet) {
+            sender.send_datagram(message).await.map_err(|e| anyhow::anyhow!("Failed to send media: {}", e))?;
+            if let Some(startup) = &pipeline.startup {
+                startup.first_packet();
+            }
+        }
+        return Ok(());
+    }
+    let framing = sender.framing();
+    let mut payload = bytes::BytesMut::with_capacity(packet.len() + 3);
+    let header = framing.begin(channel_id, &mut payload);
+    match pipeline.rewriter.as_mut() {
+        Some(rewriter) => rewriter.rewrite_into(packet, &mut payload),
+        None => payload.extend_from_slice(packet),
+    }
+    framing.finish(&mut payload);
+    if let Some(sync) = pipeline.sync.as_mut() {
+        sync.on_rtcp(&payload[header..]);
+    }
+    let payload = payload.freeze();
+    if let Some(retransmit) = &pipeline.retransmit
+        && let Some(sequence) = payload.get(header + 2..header + 4)
+    {
+        retransmit.record(channel_id, u16::from_be_bytes([sequence[0], sequence[1]]), payload.clone());
+    }
+
+    if let Err(e) = sender.send_datagram(payload).await {
+        // If connection is closed, we should stop
+        return Err(anyhow::anyhow!("Failed to send datagram: {}", e));
+    }
+    if let Some(startup) = &pipeline.startup {
+        startup.first_packet();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_period_halves_the_session_timeout() {
+        assert_eq!(keepalive_period("12345678;timeout=30"), Duration::from_secs(15));
+        assert_eq!(keepalive_period("12345678; timeout=90"), Duration::from_secs(45));
+        assert_eq!(keepalive_period("12345678"), DEFAULT_SESSION_TIMEOUT / 2);
+        assert_eq!(keepalive_period("12345678;timeout=soon"), DEFAULT_SESSION_TIMEOUT / 2);
+    }
+
+    #[test]
+    fn keepalive_period_is_never_zero() {
+        assert_eq!(keepalive_period("12345678;timeout=0"), DEFAULT_SESSION_TIMEOUT / 2);
+        assert_eq!(keepalive_period("12345678;timeout=1"), MIN_KEEPALIVE_PERIOD);
+        assert_eq!(keepalive_period("12345678;timeout=2"), MIN_KEEPALIVE_PERIOD);
+    }
+}
@@ -0,0 +1,266 @@
+//! Session priority classes: when the proxy's egress or CPU goes over its
+//! `[qos]` budget, lower classes give way first, by moving to their camera's
+//! sub-stream and then by being closed
+
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tracing::{info, warn};
+use crate::config::{Priority, QosConfig};
+
+/// How often egress and CPU are checked against the budget
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Checks skipped after an action, so a sub-stream switch (which waits for a
+/// keyframe) takes effect before the next session is touched
+const SETTLE_CHECKS: u32 = 5;
+/// `USER_HZ`, the unit of the CPU times in `/proc/self/stat`
+const CLOCK_TICKS: f64 = 100.0;
+
+/// The budget a session gave way to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Congestion {
+    Bandwidth,
+    Cpu,
+    Memory,
+}
+
+impl Congestion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Congestion::Bandwidth => "bandwidth",
+            Congestion::Cpu => "cpu",
+            Congestion::Memory => "memory",
+        }
+    }
+}
+
+/// What a session did to relieve congestion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QosAction {
+    /// Moved to the camera's sub-stream for the rest of the session
+    Degrade,
+    /// Closed
+    Shed,
+}
+
+impl QosAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QosAction::Degrade => "degrade",
+            QosAction::Shed => "shed",
+        }
+    }
+}
+
+/// One session as the budgets see it
+#[derive(Debug)]
+pub struct SessionQos {
+    url: String,
+    priority: Priority,
+    bytes_sent: Arc<AtomicU64>,
+    /// Egress counted at the previous check
+    last_bytes: AtomicU64,
+    /// Set once the session has a sub-stream to move to
+    degradable: AtomicBool,
+    degrade: CancellationToken,
+    shed: CancellationToken,
+    /// Budget behind the latest action
+    reason: Mutex<Congestion>,
+}
+
+impl SessionQos {
+    fn new(url: &str, priority: Priority, bytes_sent: Arc<AtomicU64>) -> Self {
+        Self {
+            url: url.to_string(),
+            priority,
+            last_bytes: AtomicU64::new(bytes_sent.load(Ordering::Relaxed)),
+            bytes_sent,
+            degradable: AtomicBool::new(false),
+            degrade: CancellationToken::new(),
+            shed: CancellationToken::new(),
+            reason: Mutex::new(Congestion::Bandwidth),
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// The session can now move to a sub-stream under congestion
+    pub fn set_degradable(&self) {
+        self.degradable.store(true, Ordering::Relaxed);
+    }
+
+    /// Resolves when the budget asks the session to move to its sub-stream
+    pub fn degraded(&self) -> WaitForCancellationFuture<'_> {
+        self.degrade.cancelled()
+    }
+
+    /// Resolves when the budget closes the session
+    pub fn shed(&self) -> WaitForCancellationFuture<'_> {
+        self.shed.cancelled()
+    }
+
+    /// Why the session was degraded or shed
+    pub fn reason(&self) -> Congestion {
+        *self.reason.lock().unwrap()
+    }
+
+    fn act(&self, action: QosAction, congestion: Congestion) {
+        *self.reason.lock().unwrap() = congestion;
+        match action {
+            QosAction::Degrade => self.degrade.cancel(),
+            QosAction::Shed => self.shed.cancel(),
+        }
+    }
+}
+
+/// Limits from `[qos]`, and the sessions that give way to them
+struct QosBudget {
+    bandwidth_bps: Option<u64>,
+    cpu_percent: Option<u64>,
+    sessions: Mutex<Vec<Weak<SessionQos>>>,
+}
+
+static BUDGET: OnceLock<QosBudget> = OnceLock::new();
+
+/// Enforce the configured budgets for the rest of the process
+pub fn init(config: &QosConfig) {
+    let budget = QosBudget {
+        bandwidth_bps: config.bandwidth_mbps.map(|mbps| mbps * 1_000_000),
+        cpu_percent: config.cpu_percent,
+        sessions: Mutex::new(Vec::new()),
+    };
+    if BUDGET.set(budget).is_ok() {
+        info!(
+            "QoS budget: {} Mbit/s, {}% CPU",
+            config.bandwidth_mbps.map_or("unlimited".to_string(), |mbps| mbps.to_string()),
+            config.cpu_percent.map_or("unlimited".to_string(), |percent| percent.to_string())
+        );
+        tokio::spawn(enforce());
+    }
+}
+
+/// Budget view of a new session; only acted on when `[qos]` is configured
+pub fn session(url: &str, priority: Priority, bytes_sent: Arc<AtomicU64>) -> Arc<SessionQos> {
+    let session = Arc::new(SessionQos::new(url, priority, bytes_sent));
+    if let Some(budget) = BUDGET.get() {
+        budget.sessions.lock().unwrap().push(Arc::downgrade(&session));
+    }
+    session
+}
+
+async fn enforce() {
+    let Some(budget) = BUDGET.get() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut cpu = CpuMeter::default();
+    let mut settle = 0;
+    loop {
+        interval.tick().await;
+        let sessions: Vec<Arc<SessionQos>> = {
+            let mut sessions = budget.sessions.lock().unwrap();
+            sessions.retain(|s| s.strong_count() > 0);
+            sessions.iter().filter_map(Weak::upgrade).filter(|s| !s.shed.is_cancelled()).collect()
+        };
+
+        // Bytes each session sent since the last check
+        let rates: Vec<(Arc<SessionQos>, u64)> = sessions
+            .into_iter()
+            .map(|s| {
+                let sent = s.bytes_sent.load(Ordering::Relaxed);
+                let rate = sent.saturating_sub(s.last_bytes.swap(sent, Ordering::Relaxed));
+                (s, rate)
+            })
+            .collect();
+        let egress_bps = rates.iter().map(|(_, rate)| rate * 8).sum::<u64>() / CHECK_INTERVAL.as_secs();
+        crate::stats::global().set("egress_bps", egress_bps as i64);
+        let cpu_percent = cpu.percent();
+        if let Some(percent) = cpu_percent {
+            crate::stats::global().set("cpu_percent", percent as i64);
+        }
+
+        if settle > 0 {
+            settle -= 1;
+            continue;
+        }
+        let congestion = if budget.bandwidth_bps.is_some_and(|limit| egress_bps > limit) {
+            Congestion::Bandwidth
+        } else if budget.cpu_percent.is_some_and(|limit| cpu_percent.is_some_and(|percent| percent > limit)) {
+            Congestion::Cpu
+        } else {
+            continue;
+        };
+        if relieve(&rates, congestion) {
+            settle = SETTLE_CHECKS;
+        }
+    }
+}
+
+/// Take one step against congestion: degrade or shed the busiest session of
+/// the lowest priority class that still has a step left. A class is degraded
+/// as far as it can be and then shed before the next class is touched; high
+/// priority sessions are only ever degraded.
+fn relieve(sessions: &[(Arc<SessionQos>, u64)], congestion: Congestion) -> bool {
+    let mut order: Vec<&(Arc<SessionQos>, u64)> = sessions.iter().collect();
+    order.sort_by_key(|(_, rate)| Reverse(*rate));
+    for priority in [Priority::Low, Priority::Normal, Priority::High] {
+        let class = || order.iter().map(|(s, _)| s).filter(|s| s.priority == priority);
+        let step = match class().find(|s| s.degradable.load(Ordering::Relaxed) && !s.degrade.is_cancelled()) {
+            Some(session) => Some((session, QosAction::Degrade)),
+            None if priority < Priority::High => class().next().map(|session| (session, QosAction::Shed)),
+            None => None,
+        };
+        let Some((session, action)) = step else {
+            continue;
+        };
+        match action {
+            QosAction::Degrade => {
+                info!("Over the {} budget; moving the {} priority session for {} to its sub-stream", congestion.as_str(), priority.as_str(), session.url);
+                crate::stats::global().add("sessions_degraded", 1);
+            }
+            QosAction::Shed => {
+                warn!("Over the {} budget; closing the {} priority session for {}", congestion.as_str(), priority.as_str(), session.url);
+                crate::stats::global().add("sessions_shed", 1);
+            }
+        }
+        session.act(action, congestion);
+        return true;
+    }
+    false
+}
+
+/// Process CPU time between calls, as a percentage of one core
+#[derive(Default)]
+struct CpuMeter {
+    last: Option<(u64, Instant)>,
+}
+
+impl CpuMeter {
+    /// None on the first call and where CPU time cannot be read
+    fn percent(&mut self) -> Option<u64> {
+        let ticks = cpu_ticks()?;
+        let now = Instant::now();
+        let (last_ticks, last_at) = self.last.replace((ticks, now))?;
+        let seconds = now.duration_since(last_at).as_secs_f64();
+        (seconds > 0.0).then(|| (ticks.saturating_sub(last_ticks) as f64 / CLOCK_TICKS / seconds * 100.0) as u64)
+    }
+}
+
+/// User and system CPU time of the process, in clock ticks
+#[cfg(target_os = "linux")]
+fn cpu_ticks() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the command name, which may contain spaces; utime and
+    // stime are the 14th and 15th
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split_whitespace().collect();
+    Some(fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_ticks() -> Option<u64> {
+    None
+}
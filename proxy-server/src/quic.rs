@@ -0,0 +1,50 @@
+//! QUIC transport settings for the WebTransport server, from `[quic]`
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use wtransport::config::QuicTransportConfig;
+use wtransport::quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use wtransport::quinn::{IdleTimeout, VarInt};
+use crate::config::{CongestionController, QuicConfig};
+
+/// The transport config `config` describes; unset values keep quinn's defaults
+pub fn transport_config(config: &QuicConfig) -> Result<QuicTransportConfig> {
+    let mut transport = QuicTransportConfig::default();
+    transport.keep_alive_interval((config.keep_alive_ms > 0).then(|| Duration::from_millis(config.keep_alive_ms)));
+    if let Some(ms) = config.max_idle_timeout_ms {
+        let timeout = match ms {
+            0 => None,
+            ms => Some(IdleTimeout::try_from(Duration::from_millis(ms)).context("quic.max_idle_timeout_ms is too large")?),
+        };
+        transport.max_idle_timeout(timeout);
+    }
+    if let Some(kb) = config.datagram_receive_buffer_kb {
+        transport.datagram_receive_buffer_size(Some(kb * 1024));
+    }
+    if let Some(kb) = config.datagram_send_buffer_kb {
+        transport.datagram_send_buffer_size(kb * 1024);
+    }
+    if let Some(kb) = config.stream_receive_window_kb {
+        transport.stream_receive_window(window(kb, "stream_receive_window_kb")?);
+    }
+    if let Some(kb) = config.receive_window_kb {
+        transport.receive_window(window(kb, "receive_window_kb")?);
+    }
+    if let Some(kb) = config.send_window_kb {
+        transport.send_window(kb * 1024);
+    }
+    if let Some(ms) = config.initial_rtt_ms {
+        transport.initial_rtt(Duration::from_millis(ms));
+    }
+    match config.congestion {
+        CongestionController::Cubic => transport.congestion_controller_factory(Arc::new(CubicConfig::default())),
+        CongestionController::NewReno => transport.congestion_controller_factory(Arc::new(NewRenoConfig::default())),
+        CongestionController::Bbr => transport.congestion_controller_factory(Arc::new(BbrConfig::default())),
+    };
+    Ok(transport)
+}
+
+fn window(kb: u64, name: &str) -> Result<VarInt> {
+    VarInt::from_u64(kb * 1024).with_context(|| format!("quic.{} is too large", name))
+}
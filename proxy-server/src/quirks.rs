@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::info;
+use crate::config::QuirkRule;
+
+/// Camera families with known RTSP deviations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vendor {
+    Hikvision,
+    Dahua,
+    Axis,
+    /// LIVE555-based servers from before 2012
+    Live555,
+}
+
+impl Vendor {
+    /// Guess the vendor from a `Server` (or `User-Agent`) header value
+    fn detect(server: &str) -> Option<Self> {
+        let server = server.to_ascii_lowercase();
+        if server.contains("hikvision") || server.starts_with("hwserver") {
+            Some(Vendor::Hikvision)
+        } else if server.contains("dahua") {
+            Some(Vendor::Dahua)
+        } else if server.contains("axis") {
+            Some(Vendor::Axis)
+        } else if let Some(version) = server.strip_prefix("live555 streaming media v") {
+            // Versions are dates, e.g. "v2011.05.25"
+            let year: u32 = version.get(..4)?.parse().ok()?;
+            (year < 2012).then_some(Vendor::Live555)
+        } else {
+            None
+        }
+    }
+
+    fn quirks(self) -> Quirks {
+        match self {
+            Vendor::Hikvision | Vendor::Dahua => Quirks { keepalive: KeepAlive::GetParameter, normalize_transport: true },
+            Vendor::Axis | Vendor::Live555 => Quirks::default(),
+        }
+    }
+}
+
+/// Request used to keep an upstream session from timing out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeepAlive {
+    #[default]
+    Options,
+    GetParameter,
+}
+
+impl KeepAlive {
+    pub fn method(&self) -> &'static str {
+        match self {
+            KeepAlive::Options => "OPTIONS",
+            KeepAlive::GetParameter => "GET_PARAMETER",
+        }
+    }
+}
+
+/// Behaviour adjustments for one camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quirks {
+    pub keepalive: KeepAlive,
+    /// Clean up non-standard `Transport` headers in SETUP responses
+    pub normalize_transport: bool,
+}
+
+impl Quirks {
+    /// Rewrite a camera `Transport` header to the syntax browsers and the
+    /// proxy expect: no whitespace around separators, `RTP/AVP` for UDP
+    pub fn normalize_transport(&self, value: &str) -> String {
+        if !self.normalize_transport {
+            return value.to_string();
+        }
+        value
+            .split(';')
+            .map(|param| {
+                let param = param.trim();
+                match param.split_once('=') {
+                    Some((key, value)) => format!("{}={}", key.trim(), value.trim()),
+                    None if param.eq_ignore_ascii_case("RTP/AVP/UDP") => "RTP/AVP".to_string(),
+                    None => param.to_string(),
+                }
+            })
+            .filter(|param| !param.is_empty())
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+/// Quirks in effect for an upstream connection: the configured vendor or
+/// overrides, otherwise whatever the camera's `Server` header identifies
+#[derive(Debug, Clone, Default)]
+pub struct QuirkSelector {
+    rule: Option<QuirkRule>,
+    vendor: Option<Vendor>,
+    quirks: Quirks,
+}
+
+impl QuirkSelector {
+    pub fn new(rule: Option<QuirkRule>) -> Self {
+        let mut selector = Self { vendor: rule.as_ref().and_then(|r| r.vendor), rule, quirks: Quirks::default() };
+        selector.update();
+        selector
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Auto-detect the vendor from response headers, unless one is configured
+    pub fn observe(&mut self, headers: &HashMap<String, String>) {
+        if self.vendor.is_some() {
+            return;
+        }
+        let detected = ["Server", "User-Agent"].iter().filter_map(|h| headers.get(*h)).find_map(|v| Vendor::detect(v));
+        if let Some(vendor) = detected {
+            info!("Detected {:?} camera, applying its quirks", vendor);
+            self.vendor = Some(vendor);
+            self.update();
+        }
+    }
+
+    fn update(&mut self) {
+        let mut quirks = self.vendor.map(Vendor::quirks).unwrap_or_default();
+        if let Some(rule) = &self.rule {
+            quirks.keepalive = rule.keepalive.unwrap_or(quirks.keepalive);
+            quirks.normalize_transport = rule.normalize_transport.unwrap_or(quirks.normalize_transport);
+        }
+        self.quirks = quirks;
+    }
+}
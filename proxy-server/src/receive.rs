@@ -0,0 +1,209 @@
+//! Receive-side metrics of the camera's RTP, per channel, as an RFC 3550
+//! receiver keeps them: interarrival jitter, loss estimated from sequence
+//! number gaps, and the bitrate over the last couple of seconds. The browser
+//! gets them in `stats` answers; `/sessions` and `/metrics` expose them too.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Span the bitrate and recent loss are measured over
+const WINDOW: Duration = Duration::from_secs(2);
+/// A jump in sequence numbers this large is a restarted sender, not loss
+const MAX_DROPOUT: u16 = 3000;
+
+/// One channel's metrics at a point in time
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelReport {
+    pub channel: u8,
+    pub packets: u64,
+    /// Packets missing since the first, never below zero
+    pub lost: u64,
+    /// Fraction of the packets expected over the last window that did not
+    /// arrive, like an RTCP receiver report's
+    pub loss_fraction: f32,
+    pub jitter_ms: f64,
+    /// Over the last window
+    pub bitrate_kbps: u32,
+}
+
+/// Measures one RTP channel; shared by its forwarder and whoever reports
+#[derive(Debug)]
+pub struct ChannelMeter {
+    channel_id: u8,
+    clock_rate: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Arrival times are counted in RTP ticks from here
+    origin: Instant,
+    packets: u64,
+    /// First and highest extended sequence numbers
+    base_seq: u32,
+    max_seq: Option<u32>,
+    /// Previous packet's arrival minus its timestamp, in ticks
+    transit: Option<i64>,
+    /// In ticks, scaled by 16 as RFC 3550 A.8 keeps it
+    jitter: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    /// Expected and received counts when the window started
+    window_expected: u64,
+    window_packets: u64,
+    bitrate_kbps: u32,
+    loss_fraction: f32,
+}
+
+impl ChannelMeter {
+    fn new(channel_id: u8, clock_rate: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            channel_id,
+            clock_rate: clock_rate.max(1),
+            state: Mutex::new(State {
+                origin: now,
+                packets: 0,
+                base_seq: 0,
+                max_seq: None,
+                transit: None,
+                jitter: 0,
+                window_start: now,
+                window_bytes: 0,
+                window_expected: 0,
+                window_packets: 0,
+                bitrate_kbps: 0,
+                loss_fraction: 0.0,
+            }),
+        }
+    }
+
+    pub fn on_rtp(&self, packet: &[u8]) {
+        if packet.len() < 12 {
+            return;
+        }
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.packets += 1;
+        state.window_bytes += packet.len() as u64;
+
+        match state.max_seq {
+            None => {
+                state.base_seq = u32::from(seq);
+                state.max_seq = Some(u32::from(seq));
+            }
+            Some(max) => {
+                let delta = seq.wrapping_sub(max as u16);
+                if delta != 0 && delta < MAX_DROPOUT {
+                    // In order, possibly after a gap or a wrap
+                    state.max_seq = Some(max.wrapping_add(u32::from(delta)));
+                } else if (MAX_DROPOUT..=u16::MAX - 100).contains(&delta) {
+                    // Too far from anything recent: the sender restarted
+                    state.base_seq = u32::from(seq);
+                    state.max_seq = Some(u32::from(seq));
+                    state.packets = 1;
+                    state.window_expected = 0;
+                    state.window_packets = 0;
+                }
+            }
+        }
+
+        // RFC 3550 A.8, on the camera's clock
+        let arrival = (now.duration_since(state.origin).as_secs_f64() * self.clock_rate as f64) as i64;
+        let transit = arrival - i64::from(timestamp);
+        if let Some(previous) = state.transit.replace(transit) {
+            let d = (transit - previous).unsigned_abs();
+            state.jitter = state.jitter + d - ((state.jitter + 8) >> 4);
+        }
+
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed >= WINDOW {
+            let expected = state.expected();
+            let window_expected = expected.saturating_sub(state.window_expected);
+            let window_packets = state.packets.saturating_sub(state.window_packets);
+            state.loss_fraction = match window_expected {
+                0 => 0.0,
+                n => n.saturating_sub(window_packets) as f32 / n as f32,
+            };
+            state.bitrate_kbps = (state.window_bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1000.0) as u32;
+            state.window_start = now;
+            state.window_bytes = 0;
+            state.window_expected = expected;
+            state.window_packets = state.packets;
+        }
+    }
+
+    pub fn report(&self) -> ChannelReport {
+        let state = self.state.lock().unwrap();
+        ChannelReport {
+            channel: self.channel_id,
+            packets: state.packets,
+            lost: state.expected().saturating_sub(state.packets),
+            loss_fraction: state.loss_fraction,
+            jitter_ms: ((state.jitter >> 4) as f64 * 1000.0 / self.clock_rate as f64 * 100.0).round() / 100.0,
+            bitrate_kbps: state.bitrate_kbps,
+        }
+    }
+}
+
+impl State {
+    fn expected(&self) -> u64 {
+        self.max_seq.map_or(0, |max| u64::from(max.wrapping_sub(self.base_seq)) + 1)
+    }
+}
+
+/// Every RTP channel of one session
+#[derive(Debug, Default)]
+pub struct SessionReceive {
+    channels: Mutex<BTreeMap<u8, Arc<ChannelMeter>>>,
+}
+
+impl SessionReceive {
+    /// The meter for a channel, replacing any earlier one after a new SETUP
+    pub fn channel(&self, channel_id: u8, clock_rate: u32) -> Arc<ChannelMeter> {
+        let meter = Arc::new(ChannelMeter::new(channel_id, clock_rate));
+        self.channels.lock().unwrap().insert(channel_id, meter.clone());
+        meter
+    }
+
+    /// In channel order
+    pub fn reports(&self) -> Vec<ChannelReport> {
+        self.channels.lock().unwrap().values().map(|meter| meter.report()).collect()
+    }
+}
+
+/// Metrics of running sessions, by session ID
+fn sessions() -> &'static Mutex<HashMap<String, Weak<SessionReceive>>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, Weak<SessionReceive>>>> = OnceLock::new();
+    SESSIONS.get_or_init(Default::default)
+}
+
+/// Keeps a session's metrics listed until dropped
+pub struct Registration {
+    session_id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        sessions().lock().unwrap().remove(&self.session_id);
+    }
+}
+
+pub fn register(session_id: &str, receive: &Arc<SessionReceive>) -> Registration {
+    sessions().lock().unwrap().insert(session_id.to_string(), Arc::downgrade(receive));
+    Registration { session_id: session_id.to_string() }
+}
+
+/// Reports of every running session, by session ID
+pub fn snapshot() -> BTreeMap<String, Vec<ChannelReport>> {
+    sessions()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(id, receive)| Some((id.clone(), receive.upgrade()?.reports())))
+        .collect()
+}
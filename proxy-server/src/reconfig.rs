@@ -0,0 +1,123 @@
+//! Mid-session codec changes. Some cameras switch encoding parameters while
+//! streaming, typically the resolution on a day/night switch, and signal it
+//! with new in-band SPS/PPS or an ANNOUNCE carrying a new SDP. Either way
+//! the browser gets a `codec-config-changed` event, and container outputs
+//! start again from the next keyframe with the new parameter sets.
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::info;
+use crate::control::Event;
+use crate::h264::Sps;
+use crate::sdp::{MediaDescription, StreamInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// New SPS in the media itself
+    InBand,
+    /// ANNOUNCE from the camera
+    Announce,
+}
+
+impl ConfigChange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigChange::InBand => "in-band",
+            ConfigChange::Announce => "announce",
+        }
+    }
+}
+
+/// A video track's parameter sets as last announced, shared by the session's
+/// control loop and the track's forwarder
+#[derive(Debug, Default)]
+pub struct TrackConfig {
+    announced: Mutex<(u64, Vec<Vec<u8>>)>,
+}
+
+impl TrackConfig {
+    /// The camera announced new parameter sets for the track
+    pub fn announce(&self, parameter_sets: Vec<Vec<u8>>) {
+        let mut announced = self.announced.lock().unwrap();
+        *announced = (announced.0 + 1, parameter_sets);
+    }
+
+    /// Parameter sets announced after `generation`, and the new generation
+    fn newer_than(&self, generation: u64) -> Option<(u64, Vec<Vec<u8>>)> {
+        let announced = self.announced.lock().unwrap();
+        (announced.0 != generation).then(|| announced.clone())
+    }
+}
+
+/// Watches one H.264 track for parameter sets that change the stream
+#[derive(Debug)]
+pub struct CodecWatch {
+    channel_id: u8,
+    media: MediaDescription,
+    config: Arc<TrackConfig>,
+    generation: u64,
+    /// Size, profile and level of the SPS in use
+    current: Option<(u32, u32, u8, u8)>,
+    tx: mpsc::Sender<Event>,
+}
+
+impl CodecWatch {
+    /// None for tracks other than H.264 video
+    pub fn new(channel_id: u8, media: &MediaDescription, config: Arc<TrackConfig>, tx: mpsc::Sender<Event>) -> Option<Self> {
+        if media.kind != "video" || !media.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("H264")) {
+            return None;
+        }
+        let current = media.parameter_sets().iter().find_map(|nal| crate::h264::parse_sps(nal)).map(summary);
+        Some(Self { channel_id, media: media.clone(), config, generation: 0, current, tx })
+    }
+
+    /// The new parameter sets when the stream changed with this packet, or
+    /// was announced to have changed since the last one
+    pub fn on_rtp(&mut self, packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+        if let Some((generation, parameter_sets)) = self.config.newer_than(self.generation) {
+            // The control loop told the browser already
+            self.generation = generation;
+            self.current = parameter_sets.iter().find_map(|nal| crate::h264::parse_sps(nal)).map(summary).or(self.current);
+            return Some(parameter_sets);
+        }
+
+        let payload = crate::rtp_header::payload(packet)?;
+        let nals = crate::h264::rtp_nal_units(payload);
+        let (nal, sps) = nals.iter().find_map(|nal| Some((*nal, crate::h264::parse_sps(nal)?)))?;
+        let previous = self.current.replace(summary(sps));
+        if previous.is_none_or(|previous| previous == summary(sps)) {
+            return None;
+        }
+        info!("Channel {} changed to {}x{} in band", self.channel_id, sps.width, sps.height);
+        crate::stats::global().add("codec_config_changes", 1);
+        let stream = stream_info(&self.media, &sps);
+        let profile_level_id = profile_level_id(nal);
+        let _ = self.tx.try_send(Event::CodecConfig { channel_id: self.channel_id, stream, profile_level_id, cause: ConfigChange::InBand });
+        let mut parameter_sets = vec![nal.to_vec()];
+        parameter_sets.extend(nals.iter().filter(|nal| nal[0] & 0x1F == 8).map(|nal| nal.to_vec()));
+        Some(parameter_sets)
+    }
+}
+
+/// What the SDP says about a track, with the size, frame rate, profile and
+/// level of a newer SPS
+pub fn stream_info(media: &MediaDescription, sps: &Sps) -> StreamInfo {
+    StreamInfo {
+        width: Some(sps.width),
+        height: Some(sps.height),
+        fps: sps.fps.map(|fps| (fps * 100.0).round() / 100.0),
+        profile: crate::h264::profile_name(sps.profile_idc, sps.constraints).map(str::to_string),
+        level: Some(crate::h264::level_name(sps.profile_idc, sps.constraints, sps.level_idc)),
+        ..media.info()
+    }
+}
+
+/// `profile-level-id` (as in the SDP) of an SPS NAL unit, which players
+/// configure their decoders with
+pub fn profile_level_id(sps: &[u8]) -> Option<String> {
+    sps.get(1..4).map(|id| format!("{:02X}{:02X}{:02X}", id[0], id[1], id[2]))
+}
+
+fn summary(sps: Sps) -> (u32, u32, u8, u8) {
+    (sps.width, sps.height, sps.profile_idc, sps.level_idc)
+}
@@ -0,0 +1,293 @@
+//! Relay chaining: an edge instance near the cameras registers with a public
+//! instance over one WebTransport connection, which then carries the RTSP of
+//! every session for the cameras behind it. The edge dials out, so the camera
+//! network needs no inbound firewall holes.
+//!
+//! The public instance opens a bidirectional stream per session and names
+//! the camera with `CONNECT <host> <port>`; the edge answers `OK` and pipes
+//! the stream to the camera's RTSP port, or answers `ERR <reason>`. Media is
+//! requested interleaved so it travels on the same stream.
+//!
+//! Edges register over WebTransport, so without the `webtransport` feature
+//! neither side is available and cameras behind an edge cannot be reached.
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BytesMut};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tracing::warn;
+use crate::upstream::Upstream;
+#[cfg(feature = "webtransport")]
+use anyhow::Context;
+#[cfg(feature = "webtransport")]
+use std::net::IpAddr;
+#[cfg(feature = "webtransport")]
+use std::sync::{Arc, Mutex, OnceLock};
+#[cfg(feature = "webtransport")]
+use std::time::Duration;
+#[cfg(feature = "webtransport")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "webtransport")]
+use tokio::net::TcpStream;
+#[cfg(feature = "webtransport")]
+use tracing::info;
+#[cfg(feature = "webtransport")]
+use wtransport::endpoint::{ConnectOptions, SessionRequest};
+#[cfg(feature = "webtransport")]
+use wtransport::{ClientConfig, Connection, Endpoint};
+#[cfg(feature = "webtransport")]
+use crate::config::{Config, EdgeConfig};
+
+/// Path edge instances register at
+#[cfg(feature = "webtransport")]
+pub const RELAY_PATH: &str = "/relay";
+/// Header carrying the edge's name
+#[cfg(feature = "webtransport")]
+const EDGE_HEADER: &str = "x-rtsp2browser-edge";
+/// Wait before an edge registers again after losing its connection
+#[cfg(feature = "webtransport")]
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(feature = "webtransport")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(feature = "webtransport")]
+const MAX_LINE: usize = 512;
+
+#[cfg(feature = "webtransport")]
+struct Edge {
+    /// The connection's id, so a stale connection closing does not remove its replacement
+    id: usize,
+    connection: Arc<Connection>,
+}
+
+/// Registered edges by name
+#[cfg(feature = "webtransport")]
+fn edges() -> &'static Mutex<HashMap<String, Edge>> {
+    static EDGES: OnceLock<Mutex<HashMap<String, Edge>>> = OnceLock::new();
+    EDGES.get_or_init(Default::default)
+}
+
+/// Register an edge knocking at [`RELAY_PATH`] and hold its connection until
+/// it closes
+#[cfg(feature = "webtransport")]
+pub async fn accept_edge(request: SessionRequest, config: &Config) -> Result<()> {
+    let Some(relay) = &config.relay else {
+        request.not_found().await;
+        return Ok(());
+    };
+    let secret = request.headers().get("authorization").and_then(|a| crate::http::bearer(a));
+    let name = request.headers().get(EDGE_HEADER).cloned().unwrap_or_default();
+    if !crate::http::token_matches(secret, &relay.secret) || name.is_empty() {
+        warn!("Refusing edge registration from {} without a name or the relay secret", request.remote_address());
+        request.forbidden().await;
+        return Ok(());
+    }
+    let remote = request.remote_address();
+    let connection = Arc::new(request.accept().await?);
+    let id = connection.stable_id();
+    info!("Edge {} registered from {}", name, remote);
+    if edges().lock().unwrap().insert(name.clone(), Edge { id, connection: connection.clone() }).is_some() {
+        info!("Edge {} replaced its previous connection", name);
+    }
+
+    let reason = connection.closed().await;
+    let mut edges = edges().lock().unwrap();
+    if edges.get(&name).is_some_and(|edge| edge.id == id) {
+        edges.remove(&name);
+    }
+    info!("Edge {} disconnected: {}", name, reason);
+    Ok(())
+}
+
+/// An RTSP connection to `host:port` through the edge registered as `edge`
+#[cfg(feature = "webtransport")]
+pub async fn open(edge: &str, host: &str, port: u16) -> Result<Upstream> {
+    let connection = edges()
+        .lock()
+        .unwrap()
+        .get(edge)
+        .map(|edge| edge.connection.clone())
+        .with_context(|| format!("Edge {} is not connected", edge))?;
+    info!("Connecting to {}:{} through edge {}", host, port, edge);
+    let (mut send, mut recv) = connection.open_bi().await?.await?;
+    send.write_all(format!("CONNECT {} {}\n", host, port).as_bytes()).await?;
+    let reply = tokio::time::timeout(CONNECT_TIMEOUT, read_line(&mut recv))
+        .await
+        .with_context(|| format!("Edge {} did not answer in time", edge))??;
+    if let Some(reason) = reply.strip_prefix("ERR") {
+        bail!("Edge {} could not reach {}:{}: {}", edge, host, port, reason.trim());
+    }
+    if reply != "OK" {
+        bail!("Edge {} answered {:?}", edge, reply);
+    }
+    // Media comes interleaved over the stream and is replayed from loopback
+    Ok(Upstream { reader: Box::new(recv), writer: Box::new(send), camera_ip: IpAddr::V4(Ipv4Addr::LOCALHOST), interleaved: true })
+}
+
+/// Without WebTransport no edge can have registered
+#[cfg(not(feature = "webtransport"))]
+pub async fn open(edge: &str, host: &str, port: u16) -> Result<Upstream> {
+    bail!("Cannot reach {}:{} through edge {}: built without the `webtransport` feature", host, port, edge)
+}
+
+/// Keep this instance registered with the public one, reconnecting when the
+/// connection drops. Runs for the life of the process.
+#[cfg(feature = "webtransport")]
+pub async fn run_edge(edge: EdgeConfig) {
+    loop {
+        match register(&edge).await {
+            Ok((_endpoint, connection)) => {
+                info!("Registered as edge {} with {}", edge.name, edge.url);
+                loop {
+                    match connection.accept_bi().await {
+                        Ok((send, recv)) => {
+                            let hosts = edge.hosts.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve(send, recv, &hosts).await {
+                                    warn!("Relayed camera connection failed: {:#}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            warn!("Relay connection to {} lost: {}", edge.url, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to register with {}: {:#}", edge.url, e),
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Connect to the public instance, keeping the endpoint alongside the connection
+#[cfg(feature = "webtransport")]
+async fn register(edge: &EdgeConfig) -> Result<(Endpoint<wtransport::endpoint::endpoint_side::Client>, Connection)> {
+    let builder = ClientConfig::builder().with_bind_default();
+    let builder = match &edge.cert_hash {
+        Some(hash) => {
+            let digest = hash.parse().map_err(|_| anyhow::anyhow!("Invalid edge cert_hash {}", hash))?;
+            builder.with_server_certificate_hashes([digest])
+        }
+        None => builder.with_native_certs(),
+    };
+    let config = builder.keep_alive_interval(Some(Duration::from_secs(3))).build();
+    let endpoint = Endpoint::client(config)?;
+    let options = ConnectOptions::builder(&edge.url)
+        .add_header("authorization", format!("Bearer {}", edge.secret))
+        .add_header(EDGE_HEADER, &edge.name)
+        .build();
+    let connection = endpoint.connect(options).await?;
+    Ok((endpoint, connection))
+}
+
+/// Answer one `CONNECT` from the public instance and pipe the stream to the camera
+#[cfg(feature = "webtransport")]
+async fn serve<W, R>(mut send: W, mut recv: R, hosts: &[String]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let line = read_line(&mut recv).await?;
+    let target = line.strip_prefix("CONNECT ").and_then(|rest| {
+        let (host, port) = rest.rsplit_once(' ')?;
+        Some((host.to_string(), port.parse::<u16>().ok()?))
+    });
+    let Some((host, port)) = target else {
+        send.write_all(b"ERR malformed request\n").await?;
+        bail!("Malformed relay request {:?}", line);
+    };
+    if !hosts.is_empty() && !hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(&host)) {
+        send.write_all(b"ERR host not allowed\n").await?;
+        bail!("Refusing relay request for {}: not in the edge's hosts", host);
+    }
+    let mut camera = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(camera) => camera,
+        Err(e) => {
+            send.write_all(format!("ERR {}\n", e).as_bytes()).await?;
+            bail!("Failed to connect to {}:{}: {}", host, port, e);
+        }
+    };
+    send.write_all(b"OK\n").await?;
+    info!("Relaying RTSP to {}:{}", host, port);
+    let mut stream = tokio::io::join(recv, send);
+    tokio::io::copy_bidirectional(&mut stream, &mut camera).await?;
+    Ok(())
+}
+
+/// One `\n`-terminated line, read a byte at a time so nothing after it is consumed
+#[cfg(feature = "webtransport")]
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    loop {
+        match reader.read_u8().await? {
+            b'\n' => break,
+            byte => line.push(byte),
+        }
+        if line.len() > MAX_LINE {
+            bail!("Relay line too long");
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string())
+}
+
+/// RTP and RTCP channels of a SETUP response's `interleaved=`, either one
+/// None when unreadable; RTCP follows RTP when only one is given
+pub fn interleaved_channels(camera_transport: &str) -> Option<(Option<u8>, Option<u8>)> {
+    let channels = camera_transport.split(';').find_map(|p| p.trim().strip_prefix("interleaved="))?;
+    Some(match channels.split_once('-') {
+        Some((rtp, rtcp)) => (rtp.trim().parse().ok(), rtcp.trim().parse().ok()),
+        None => (channels.trim().parse().ok(), channels.trim().parse::<u8>().ok().and_then(|c| c.checked_add(1))),
+    })
+}
+
+/// Splits the camera's interleaved media (`$`, channel, length, packet) off a
+/// relayed RTSP connection and replays it to the track's UDP sockets over
+/// loopback, so it takes the same path as media received directly
+pub struct InterleavedDemux {
+    socket: UdpSocket,
+    /// Camera channel to the socket it is replayed to
+    targets: HashMap<u8, SocketAddr>,
+}
+
+impl InterleavedDemux {
+    pub async fn new() -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        Ok(Self { socket, targets: HashMap::new() })
+    }
+
+    /// Replay the channels of a SETUP response's `interleaved=` to the
+    /// sockets bound on `rtp_port` and `rtcp_port`
+    pub fn map(&mut self, camera_transport: &str, rtp_port: u16, rtcp_port: u16) {
+        let Some((rtp, rtcp)) = interleaved_channels(camera_transport) else {
+            warn!("Camera did not confirm interleaved channels; its media is lost");
+            return;
+        };
+        for (channel, port) in [(rtp, rtp_port), (rtcp, rtcp_port)] {
+            if let Some(channel) = channel {
+                self.targets.insert(channel, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+            }
+        }
+    }
+
+    /// Replay the complete frames at the front of `buf`; true if an
+    /// incomplete one is left there, so no RTSP can be parsed yet
+    pub fn drain(&mut self, buf: &mut BytesMut) -> bool {
+        while buf.first() == Some(&b'$') {
+            if buf.len() < 4 {
+                return true;
+            }
+            let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if buf.len() < 4 + len {
+                return true;
+            }
+            if let Some(target) = self.targets.get(&buf[1]) {
+                // Loopback rarely fills up; a packet lost to it is like one lost on UDP
+                let _ = self.socket.try_send_to(&buf[4..4 + len], *target);
+            }
+            buf.advance(4 + len);
+        }
+        false
+    }
+}
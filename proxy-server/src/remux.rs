@@ -0,0 +1,266 @@
+//! Container outputs (`output`): instead of RTP, the browser gets the
+//! camera's H.264 and AAC remuxed into a stream that off-the-shelf players
+//! take over a single WebSocket: MPEG-TS for mpegts.js, or FLV for flv.js.
+//!
+//! Each track's forwarder depacketizes its RTP and hands whole frames to the
+//! session's muxer. Tracks are put on one timeline by when their first
+//! packet arrived, which keeps audio and video within network jitter of
+//! each other.
+
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::aac::{AacConfig, AacDepacketizer};
+use crate::flvmux::FlvMuxer;
+use crate::h264::Depacketizer;
+use crate::sdp::MediaDescription;
+use crate::tsmux::TsMuxer;
+
+/// Timestamps start this far ahead of the session clock, in 90 kHz units,
+/// so the first frames are not due before the player has them
+const START_DELAY: u64 = 45000;
+/// Annex B start code
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+/// AUD NAL unit allowing any picture type, which H.264 in MPEG-TS expects
+const ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xF0];
+
+/// What the browser receives on its media path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Output {
+    /// RTP packets with the session's framing
+    #[default]
+    Rtp,
+    /// MPEG-TS, each message holding whole TS packets of one frame
+    MpegTs,
+    /// FLV, the file header and then whole tags in each message
+    Flv,
+}
+
+impl Output {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rtp" => Some(Output::Rtp),
+            "mpegts" => Some(Output::MpegTs),
+            "flv" => Some(Output::Flv),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Output::Rtp => "rtp",
+            Output::MpegTs => "mpegts",
+            Output::Flv => "flv",
+        }
+    }
+
+    /// The session's muxer; None for RTP
+    pub fn remuxer(&self) -> Option<Arc<Mutex<Remuxer>>> {
+        let container = match self {
+            Output::Rtp => return None,
+            Output::MpegTs => Container::Ts(TsMuxer::default()),
+            Output::Flv => Container::Flv(FlvMuxer::default()),
+        };
+        Some(Arc::new(Mutex::new(Remuxer { container, started: Instant::now() })))
+    }
+}
+
+/// Muxer shared by a session's tracks
+#[derive(Debug)]
+pub struct Remuxer {
+    container: Container,
+    started: Instant,
+}
+
+#[derive(Debug)]
+enum Container {
+    Ts(TsMuxer),
+    Flv(FlvMuxer),
+}
+
+impl Remuxer {
+    fn add_video(&mut self) {
+        match &mut self.container {
+            Container::Ts(ts) => ts.add_video(),
+            Container::Flv(flv) => flv.add_video(),
+        }
+    }
+
+    fn add_audio(&mut self) {
+        match &mut self.container {
+            Container::Ts(ts) => ts.add_audio(),
+            Container::Flv(flv) => flv.add_audio(),
+        }
+    }
+
+    /// One picture's NAL units, without access unit delimiters
+    fn video(&mut self, pts: u64, keyframe: bool, nals: &[&[u8]]) -> Vec<u8> {
+        match &mut self.container {
+            Container::Ts(ts) => {
+                let mut annex_b = Vec::new();
+                for nal in std::iter::once(&ACCESS_UNIT_DELIMITER[..]).chain(nals.iter().copied()) {
+                    annex_b.extend_from_slice(&START_CODE);
+                    annex_b.extend_from_slice(nal);
+                }
+                ts.video(pts, keyframe, &annex_b)
+            }
+            Container::Flv(flv) => flv.video(pts, keyframe, nals),
+        }
+    }
+
+    /// Raw AAC frames, each with its output time
+    fn audio(&mut self, config: &AacConfig, frames: &[(u64, &[u8])]) -> Vec<u8> {
+        match &mut self.container {
+            Container::Ts(ts) => {
+                let Some(&(pts, _)) = frames.first() else {
+                    return Vec::new();
+                };
+                let mut adts = Vec::new();
+                for (_, frame) in frames {
+                    adts.extend_from_slice(&config.adts_header(frame.len()));
+                    adts.extend_from_slice(frame);
+                }
+                ts.audio(pts, &adts)
+            }
+            Container::Flv(flv) => flv.audio(&config.audio_specific_config(), frames),
+        }
+    }
+}
+
+/// One track's way into the session's muxer
+#[derive(Debug)]
+pub struct RemuxTrack {
+    remuxer: Arc<Mutex<Remuxer>>,
+    input: Input,
+    clock_rate: u32,
+    timeline: Option<Timeline>,
+}
+
+#[derive(Debug)]
+enum Input {
+    H264 {
+        depacketizer: Depacketizer,
+        /// From the SDP, for cameras that do not repeat them in band
+        parameter_sets: Vec<Vec<u8>>,
+        /// Pictures are dropped until the first keyframe
+        keyframe_seen: bool,
+    },
+    Aac { depacketizer: AacDepacketizer, config: AacConfig },
+    /// RTCP, and tracks the output cannot carry
+    Skip,
+}
+
+impl RemuxTrack {
+    /// Add the track `media` describes to the session's muxer. MPEG-TS
+    /// cameras count as H.264, since their video is re-packetized first.
+    pub fn new(remuxer: &Arc<Mutex<Remuxer>>, media: Option<&MediaDescription>) -> Self {
+        let h264 = media.is_some_and(|m| {
+            crate::mpegts::is_transport_stream(m) || m.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("H264"))
+        });
+        let aac = media.and_then(|m| Some((AacConfig::for_media(m)?, m)));
+        let input = match (media, aac) {
+            (Some(media), _) if h264 => {
+                remuxer.lock().unwrap().add_video();
+                Input::H264 { depacketizer: Depacketizer::default(), parameter_sets: media.parameter_sets(), keyframe_seen: false }
+            }
+            (_, Some((config, media))) => {
+                remuxer.lock().unwrap().add_audio();
+                Input::Aac { depacketizer: AacDepacketizer::new(media), config }
+            }
+            _ => Input::Skip,
+        };
+        let clock_rate = match &input {
+            Input::Aac { config, .. } => media.and_then(|m| m.clock_rate).unwrap_or(config.sample_rate()),
+            _ => 90000,
+        };
+        Self { remuxer: remuxer.clone(), input, clock_rate, timeline: None }
+    }
+
+    /// For a forwarder whose packets are not part of the output
+    pub fn skip(remuxer: &Arc<Mutex<Remuxer>>) -> Self {
+        Self { remuxer: remuxer.clone(), input: Input::Skip, clock_rate: 90000, timeline: None }
+    }
+
+    /// Start the video again from the next keyframe, after the camera changed
+    /// its parameter sets
+    pub fn restart(&mut self, new_sets: &[Vec<u8>]) {
+        if let Input::H264 { parameter_sets, keyframe_seen, .. } = &mut self.input {
+            replace_parameter_sets(parameter_sets, new_sets);
+            *keyframe_seen = false;
+        }
+    }
+
+    /// Output messages for the frames one RTP packet completes
+    pub fn push(&mut self, packet: &[u8]) -> Vec<Bytes> {
+        let mut out = Vec::new();
+        match &mut self.input {
+            Input::H264 { depacketizer, parameter_sets, keyframe_seen } => {
+                for unit in depacketizer.push(packet) {
+                    let keyframe = unit.is_keyframe();
+                    *keyframe_seen |= keyframe;
+                    if !*keyframe_seen {
+                        continue;
+                    }
+                    // In-band parameter sets replace the SDP's for later keyframes
+                    let in_band: Vec<Vec<u8>> = unit.nals.iter().filter(|n| matches!(n[0] & 0x1F, 7 | 8)).cloned().collect();
+                    replace_parameter_sets(parameter_sets, &in_band);
+                    let mut nals: Vec<&[u8]> = Vec::new();
+                    if keyframe && !unit.nals.iter().any(|n| n[0] & 0x1F == 7) {
+                        nals.extend(parameter_sets.iter().map(Vec::as_slice));
+                    }
+                    nals.extend(unit.nals.iter().filter(|n| n[0] & 0x1F != 9).map(Vec::as_slice));
+                    let pts = self.timeline.get_or_insert_with(|| Timeline::start(&self.remuxer, unit.timestamp)).pts(unit.timestamp, self.clock_rate);
+                    let message = self.remuxer.lock().unwrap().video(pts, keyframe, &nals);
+                    if !message.is_empty() {
+                        out.push(Bytes::from(message));
+                    }
+                }
+            }
+            Input::Aac { depacketizer, config } => {
+                let frames = depacketizer.push(packet);
+                let Some(&(first, _)) = frames.first() else {
+                    return out;
+                };
+                let timeline = self.timeline.get_or_insert_with(|| Timeline::start(&self.remuxer, first));
+                let frames: Vec<(u64, &[u8])> = frames.iter().map(|(timestamp, frame)| (timeline.pts(*timestamp, self.clock_rate), frame.as_slice())).collect();
+                out.push(Bytes::from(self.remuxer.lock().unwrap().audio(config, &frames)));
+            }
+            Input::Skip => {}
+        }
+        out
+    }
+}
+
+/// Replace the SPS and PPS in `parameter_sets` with those in `new_sets`
+fn replace_parameter_sets(parameter_sets: &mut Vec<Vec<u8>>, new_sets: &[Vec<u8>]) {
+    for nal in new_sets {
+        let kind = nal[0] & 0x1F;
+        parameter_sets.retain(|set| set[0] & 0x1F != kind);
+        parameter_sets.push(nal.clone());
+    }
+    // SPS first
+    parameter_sets.sort_by_key(|set| set[0] & 0x1F);
+}
+
+/// Maps a track's RTP timestamps onto the session's 90 kHz output clock
+#[derive(Debug)]
+struct Timeline {
+    /// Output time of the first frame
+    base: u64,
+    last: u32,
+    /// RTP ticks since the first frame, unwrapped
+    ticks: i64,
+}
+
+impl Timeline {
+    fn start(remuxer: &Mutex<Remuxer>, timestamp: u32) -> Self {
+        let elapsed = remuxer.lock().unwrap().started.elapsed();
+        Self { base: START_DELAY + (elapsed.as_micros() * 9 / 100) as u64, last: timestamp, ticks: 0 }
+    }
+
+    fn pts(&mut self, timestamp: u32, clock_rate: u32) -> u64 {
+        self.ticks += timestamp.wrapping_sub(self.last) as i32 as i64;
+        self.last = timestamp;
+        self.base + (self.ticks.max(0) as u64) * 90000 / clock_rate as u64
+    }
+}
@@ -0,0 +1,471 @@
+//! Fake camera that replays an RTSP transcript recorded by the proxy's
+//! `[transcript]` option, so a camera's behaviour can be reproduced without
+//! the camera. Media is synthesized from the recorded SDP.
+//!
+//! Without a transcript it serves a built-in H.264 test stream, a test
+//! camera that needs no GStreamer.
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use clap::Parser;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_util::sync::CancellationToken;
+
+/// How often an RTCP sender report is sent per track
+const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Video frames between keyframes (SPS, PPS and an IDR slice)
+const KEYFRAME_INTERVAL: u32 = 30;
+
+/// Transcript of a well-behaved H.264 camera at `/test`, served when none is given
+const TEST_STREAM: &str = r#"# rtsp://127.0.0.1:8554/test session test
+
+--- +0.000s proxy -> camera
+OPTIONS rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 1
+
+--- +0.001s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 1
+Public: OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER
+
+--- +0.002s proxy -> camera
+DESCRIBE rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 2
+Accept: application/sdp
+
+--- +0.003s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 2
+Content-Base: rtsp://127.0.0.1:8554/test/
+Content-Type: application/sdp
+
+v=0
+o=- 0 0 IN IP4 127.0.0.1
+s=test
+t=0 0
+m=video 0 RTP/AVP 96
+a=rtpmap:96 H264/90000
+a=fmtp:96 packetization-mode=1;sprop-parameter-sets=Z0IAH5WoFAFuQA==,aM48gA==
+a=control:stream=0
+
+--- +0.004s proxy -> camera
+SETUP rtsp://127.0.0.1:8554/test/stream=0 RTSP/1.0
+CSeq: 3
+Transport: RTP/AVP;unicast;client_port=5000-5001
+
+--- +0.005s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 3
+Session: test;timeout=60
+Transport: RTP/AVP;unicast;client_port=5000-5001;server_port=6000-6001
+
+--- +0.006s proxy -> camera
+PLAY rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 4
+Session: test
+
+--- +0.007s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 4
+Session: test
+Range: npt=0.000-
+
+--- +0.008s proxy -> camera
+PAUSE rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 5
+Session: test
+
+--- +0.009s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 5
+Session: test
+
+--- +0.010s proxy -> camera
+GET_PARAMETER rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 6
+Session: test
+
+--- +0.011s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 6
+Session: test
+
+--- +0.012s proxy -> camera
+TEARDOWN rtsp://127.0.0.1:8554/test RTSP/1.0
+CSeq: 7
+Session: test
+
+--- +0.013s camera -> proxy
+RTSP/1.0 200 OK
+CSeq: 7
+Session: test
+"#;
+
+#[derive(Parser)]
+struct Cli {
+    /// Transcript file written by the proxy; without one, a test stream is
+    /// served at `/test`
+    transcript: Option<PathBuf>,
+    /// Address to accept RTSP connections on
+    #[arg(long, default_value = "127.0.0.1:8554")]
+    listen: SocketAddr,
+}
+
+/// A camera response from the transcript
+#[derive(Debug, Clone)]
+struct Recorded {
+    status_line: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Recorded {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn set_header(&mut self, name: &str, value: String) {
+        match self.headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some((_, v)) => *v = value,
+            None => self.headers.push((name.to_string(), value)),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{}\r\n", self.status_line);
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                out.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if !self.body.is_empty() {
+            out.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        out.push_str("\r\n");
+        out.push_str(&self.body);
+        out.into_bytes()
+    }
+}
+
+/// Recorded responses by the method of the request they answered, replayed
+/// in order; the last one for a method is repeated once they run out
+#[derive(Debug, Clone, Default)]
+struct Script {
+    responses: HashMap<String, VecDeque<Recorded>>,
+    last: HashMap<String, Recorded>,
+}
+
+impl Script {
+    fn parse(text: &str) -> Result<Self> {
+        let mut script = Script::default();
+        let mut methods: HashMap<String, String> = HashMap::new();
+        for entry in text.split("\n--- ").skip(1) {
+            let (marker, message) = entry.split_once('\n').unwrap_or((entry, ""));
+            let (head, body) = message.split_once("\n\n").unwrap_or((message, ""));
+            let mut lines = head.lines();
+            let first = lines.next().unwrap_or_default().to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect();
+            let cseq = headers.iter().find(|(n, _)| n.eq_ignore_ascii_case("CSeq")).map(|(_, v)| v.clone());
+
+            if marker.ends_with("proxy -> camera") {
+                if let (Some(method), Some(cseq)) = (first.split_whitespace().next(), cseq) {
+                    methods.insert(cseq, method.to_string());
+                }
+            } else if marker.ends_with("camera -> proxy") {
+                let Some(method) = cseq.and_then(|cseq| methods.get(&cseq)) else {
+                    continue;
+                };
+                let body: String = body.lines().map(|line| format!("{}\r\n", line)).collect();
+                let recorded = Recorded { status_line: first, headers, body };
+                script.responses.entry(method.clone()).or_default().push_back(recorded);
+            }
+        }
+        if script.responses.is_empty() {
+            bail!("No camera responses found in the transcript");
+        }
+        Ok(script)
+    }
+
+    fn next(&mut self, method: &str) -> Option<Recorded> {
+        match self.responses.get_mut(method).and_then(VecDeque::pop_front) {
+            Some(recorded) => {
+                self.last.insert(method.to_string(), recorded.clone());
+                Some(recorded)
+            }
+            None => self.last.get(method).cloned(),
+        }
+    }
+}
+
+/// Media section of the recorded SDP, enough to synthesize packets
+#[derive(Debug, Clone)]
+struct Track {
+    video: bool,
+    payload_type: u8,
+    clock_rate: u32,
+    /// H.264 SPS and PPS from `sprop-parameter-sets`
+    parameter_sets: Vec<Vec<u8>>,
+}
+
+fn parse_tracks(sdp: &str) -> Vec<Track> {
+    let mut tracks: Vec<Track> = Vec::new();
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            let mut fields = media.split_whitespace();
+            let video = fields.next() == Some("video");
+            let payload_type = fields.nth(2).and_then(|pt| pt.parse().ok()).unwrap_or(96);
+            tracks.push(Track { video, payload_type, clock_rate: if video { 90000 } else { 8000 }, parameter_sets: Vec::new() });
+        } else if let Some(track) = tracks.last_mut() {
+            if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                if let Some(rate) = rtpmap.split('/').nth(1).and_then(|r| r.parse().ok()) {
+                    track.clock_rate = rate;
+                }
+            } else if let Some(sets) = line.split("sprop-parameter-sets=").nth(1) {
+                let sets = sets.split(';').next().unwrap_or_default();
+                track.parameter_sets = sets
+                    .split(',')
+                    .filter_map(|set| base64::engine::general_purpose::STANDARD.decode(set.trim()).ok())
+                    .collect();
+            }
+        }
+    }
+    tracks
+}
+
+/// A SETUP answered on this connection
+struct Setup {
+    track: Track,
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    rtp_dest: SocketAddr,
+    rtcp_dest: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let (text, source) = match &cli.transcript {
+        Some(path) => (
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?,
+            path.display().to_string(),
+        ),
+        None => (TEST_STREAM.to_string(), "the test stream".to_string()),
+    };
+    let script = Script::parse(&text)?;
+    let listener = TcpListener::bind(cli.listen).await?;
+    let path = text
+        .lines()
+        .next()
+        .and_then(|header| header.strip_prefix("# "))
+        .and_then(|header| header.split_whitespace().next())
+        .and_then(|url| url::Url::parse(url).ok())
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    println!("Replaying {} at rtsp://{}{}", source, listener.local_addr()?, path);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let script = script.clone();
+        tokio::spawn(async move {
+            println!("Connection from {}", peer);
+            if let Err(e) = serve(stream, script).await {
+                println!("Connection from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve(mut stream: TcpStream, mut script: Script) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let media = CancellationToken::new();
+    let _stop_media = media.clone().drop_guard();
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut setups: Vec<Setup> = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        let Some((method, headers)) = read_request(&mut stream, &mut buf).await? else {
+            return Ok(());
+        };
+        let header = |name: &str| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+        let Some(mut response) = script.next(&method) else {
+            println!("No recorded response to {}, answering 501", method);
+            let cseq = header("CSeq").unwrap_or_default();
+            stream.write_all(format!("RTSP/1.0 501 Not Implemented\r\nCSeq: {}\r\n\r\n", cseq).as_bytes()).await?;
+            continue;
+        };
+        if let Some(cseq) = header("CSeq") {
+            response.set_header("CSeq", cseq);
+        }
+        let ok = response.status_line.split_whitespace().nth(1).is_some_and(|s| s.starts_with('2'));
+
+        if method == "DESCRIBE" && ok {
+            tracks = parse_tracks(&response.body);
+        }
+        if method == "SETUP" && ok {
+            let transport = header("Transport").unwrap_or_default();
+            let Some((rtp_port, rtcp_port)) = client_ports(&transport) else {
+                bail!("SETUP without client_port: {}", transport);
+            };
+            let track = tracks.get(setups.len()).cloned().context("SETUP for a track the SDP does not have")?;
+            let rtp = UdpSocket::bind("0.0.0.0:0").await?;
+            let rtcp = UdpSocket::bind("0.0.0.0:0").await?;
+            let server_ports = (rtp.local_addr()?.port(), rtcp.local_addr()?.port());
+            if let Some(recorded) = response.header("Transport") {
+                let rewritten = rewrite_transport(recorded, (rtp_port, rtcp_port), server_ports);
+                response.set_header("Transport", rewritten);
+            }
+            setups.push(Setup {
+                track,
+                rtp,
+                rtcp,
+                rtp_dest: SocketAddr::new(peer.ip(), rtp_port),
+                rtcp_dest: SocketAddr::new(peer.ip(), rtcp_port),
+            });
+        }
+
+        stream.write_all(&response.to_bytes()).await?;
+        println!("{} -> {}", method, response.status_line);
+
+        if method == "PLAY" && ok {
+            for setup in setups.drain(..) {
+                tokio::spawn(send_media(setup, media.clone()));
+            }
+        }
+        if method == "TEARDOWN" {
+            return Ok(());
+        }
+    }
+}
+
+/// Read one request head (and skip its body); None once the client hangs up
+async fn read_request(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<Option<(String, Vec<(String, String)>)>> {
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            let mut lines = head.lines();
+            let method = lines.next().and_then(|l| l.split_whitespace().next()).unwrap_or_default().to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect();
+            let body_len = headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+                .and_then(|(_, v)| v.parse().ok())
+                .unwrap_or(0);
+            if buf.len() >= end + 4 + body_len {
+                buf.drain(..end + 4 + body_len);
+                return Ok(Some((method, headers)));
+            }
+        }
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn client_ports(transport: &str) -> Option<(u16, u16)> {
+    let ports = transport.split(';').find_map(|p| p.trim().strip_prefix("client_port="))?;
+    let (rtp, rtcp) = ports.split_once('-')?;
+    Some((rtp.parse().ok()?, rtcp.parse().ok()?))
+}
+
+/// Point the recorded Transport at this client and the replay's sockets,
+/// dropping any recorded `source` so media is accepted from here
+fn rewrite_transport(recorded: &str, client: (u16, u16), server: (u16, u16)) -> String {
+    recorded
+        .split(';')
+        .filter(|p| !p.trim().starts_with("source="))
+        .map(|p| match p.trim().split_once('=') {
+            Some(("client_port", _)) => format!("client_port={}-{}", client.0, client.1),
+            Some(("server_port", _)) => format!("server_port={}-{}", server.0, server.1),
+            _ => p.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Send synthetic RTP and RTCP sender reports until the connection ends:
+/// for video, 30 frames a second with SPS/PPS and an IDR slice every
+/// keyframe interval; for audio, silence every 20ms
+async fn send_media(setup: Setup, token: CancellationToken) {
+    let ssrc: u32 = rand::random();
+    let mut seq: u16 = rand::random();
+    let mut timestamp: u32 = rand::random();
+    let (period, ticks) = if setup.track.video {
+        (Duration::from_millis(33), setup.track.clock_rate / 30)
+    } else {
+        (Duration::from_millis(20), setup.track.clock_rate / 50)
+    };
+    let mut interval = tokio::time::interval(period);
+    let mut report = tokio::time::interval(SENDER_REPORT_INTERVAL);
+    let (mut frames, mut packets, mut octets) = (0u32, 0u32, 0u32);
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = report.tick() => {
+                let _ = setup.rtcp.send_to(&sender_report(ssrc, timestamp, packets, octets), setup.rtcp_dest).await;
+            }
+            _ = interval.tick() => {
+                let mut payloads: Vec<Vec<u8>> = Vec::new();
+                if setup.track.video {
+                    let keyframe = frames % KEYFRAME_INTERVAL == 0;
+                    if keyframe {
+                        payloads.extend(setup.track.parameter_sets.iter().cloned());
+                    }
+                    // NAL header (IDR or non-IDR slice) and filler
+                    payloads.push([vec![if keyframe { 0x65 } else { 0x41 }], vec![0u8; 200]].concat());
+                } else {
+                    payloads.push(vec![0u8; 160]);
+                }
+                let count = payloads.len();
+                for (i, payload) in payloads.into_iter().enumerate() {
+                    let marker = i + 1 == count;
+                    let mut packet = Vec::with_capacity(12 + payload.len());
+                    packet.push(0x80);
+                    packet.push(setup.track.payload_type | if marker { 0x80 } else { 0 });
+                    packet.extend_from_slice(&seq.to_be_bytes());
+                    packet.extend_from_slice(&timestamp.to_be_bytes());
+                    packet.extend_from_slice(&ssrc.to_be_bytes());
+                    packet.extend_from_slice(&payload);
+                    if setup.rtp.send_to(&packet, setup.rtp_dest).await.is_err() {
+                        return;
+                    }
+                    seq = seq.wrapping_add(1);
+                    packets = packets.wrapping_add(1);
+                    octets = octets.wrapping_add(payload.len() as u32);
+                }
+                timestamp = timestamp.wrapping_add(ticks);
+                frames += 1;
+            }
+        }
+    }
+}
+
+fn sender_report(ssrc: u32, timestamp: u32, packets: u32, octets: u32) -> Vec<u8> {
+    // NTP time: seconds since 1900 and a 32-bit fraction
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ntp_seconds = (now.as_secs() + 2_208_988_800) as u32;
+    let ntp_fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    let mut report = vec![0x80, 200, 0, 6];
+    report.extend_from_slice(&ssrc.to_be_bytes());
+    report.extend_from_slice(&ntp_seconds.to_be_bytes());
+    report.extend_from_slice(&(ntp_fraction as u32).to_be_bytes());
+    report.extend_from_slice(&timestamp.to_be_bytes());
+    report.extend_from_slice(&packets.to_be_bytes());
+    report.extend_from_slice(&octets.to_be_bytes());
+    report
+}
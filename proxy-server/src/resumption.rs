@@ -0,0 +1,103 @@
+//! TLS session resumption and 0-RTT for WebTransport, so returning browsers
+//! reconnect with fewer round trips.
+//!
+//! Session tickets are encrypted with a key of the proxy's own. Kept in a
+//! file, it survives restarts and tickets issued before one stay valid.
+
+use anyhow::{bail, Context, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+use wtransport::tls::rustls::server::ProducesTickets;
+use wtransport::tls::rustls::ServerConfig as TlsServerConfig;
+use crate::config::ResumptionConfig;
+
+pub const KEY_LEN: usize = 32;
+/// Longest ticket lifetime TLS 1.3 allows
+const MAX_LIFETIME_S: u32 = 7 * 24 * 3600;
+
+/// Set up tickets and early data on the WebTransport server's TLS config
+pub fn configure(tls: &mut TlsServerConfig, config: &ResumptionConfig) -> Result<()> {
+    let key = match &config.ticket_key_file {
+        Some(path) => load_or_create_key(path)?,
+        None => rand::random(),
+    };
+    tls.ticketer = Arc::new(TicketKey::new(&key, config.ticket_lifetime_s.min(MAX_LIFETIME_S))?);
+    if config.early_data {
+        // QUIC takes early data all or nothing
+        tls.max_early_data_size = u32::MAX;
+    }
+    Ok(())
+}
+
+/// The ticket key in `path`, generated and written there if the file is missing
+fn load_or_create_key(path: &Path) -> Result<[u8; KEY_LEN]> {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            let Ok(key) = <[u8; KEY_LEN]>::try_from(bytes.as_slice()) else {
+                bail!("Ticket key file {} must hold exactly {} bytes", path.display(), KEY_LEN);
+            };
+            Ok(key)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key: [u8; KEY_LEN] = rand::random();
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(path).with_context(|| format!("Failed to create ticket key file {}", path.display()))?;
+            file.write_all(&key)?;
+            info!("Generated a new session ticket key in {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read ticket key file {}", path.display())),
+    }
+}
+
+/// Seals tickets with AES-256-GCM as `nonce || ciphertext || tag`
+struct TicketKey {
+    key: LessSafeKey,
+    lifetime: u32,
+}
+
+impl std::fmt::Debug for TicketKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKey").field("lifetime", &self.lifetime).finish_non_exhaustive()
+    }
+}
+
+impl TicketKey {
+    fn new(key: &[u8; KEY_LEN], lifetime: u32) -> Result<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow::anyhow!("Invalid ticket key"))?;
+        Ok(Self { key: LessSafeKey::new(key), lifetime })
+    }
+}
+
+impl ProducesTickets for TicketKey {
+    fn enabled(&self) -> bool {
+        self.lifetime > 0
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = plain.to_vec();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed).ok()?;
+        let mut ticket = nonce.to_vec();
+        ticket.append(&mut sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, sealed) = cipher.split_at_checked(NONCE_LEN)?;
+        let mut buf = sealed.to_vec();
+        let plain = self.key.open_in_place(Nonce::try_assume_unique_for_key(nonce).ok()?, Aad::empty(), &mut buf).ok()?;
+        crate::stats::global().add("tls_resumptions", 1);
+        Some(plain.to_vec())
+    }
+}
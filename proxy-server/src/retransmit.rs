@@ -0,0 +1,44 @@
+//! Resending lost media for `latency=balanced` sessions. Each track's
+//! forwarder keeps the last packets it sent the browser, and a [`Nack`] on
+//! the datagram path sends the ones it names again.
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use crate::feedback::Nack;
+
+/// Packets kept per track; about a second of 4 Mbit/s video
+const HISTORY: usize = 512;
+
+/// A track's last packets, with their sequence numbers, oldest first
+type History = VecDeque<(u16, Bytes)>;
+
+/// Recently sent packets of a session's tracks, by channel
+#[derive(Debug, Clone, Default)]
+pub struct Retransmitter {
+    tracks: Arc<Mutex<HashMap<u8, History>>>,
+}
+
+impl Retransmitter {
+    /// Remember a packet as sent: `payload` is the framed packet and
+    /// `sequence` the RTP sequence number the browser saw
+    pub fn record(&self, channel_id: u8, sequence: u16, payload: Bytes) {
+        let mut tracks = self.tracks.lock().unwrap();
+        let history = tracks.entry(channel_id).or_default();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back((sequence, payload));
+    }
+
+    /// The packets a NACK asks for that are still kept
+    pub fn lookup(&self, nack: &Nack) -> Vec<Bytes> {
+        let tracks = self.tracks.lock().unwrap();
+        let Some(history) = tracks.get(&nack.channel_id) else {
+            return Vec::new();
+        };
+        nack.sequence_numbers()
+            .filter_map(|sequence| history.iter().rev().find(|(s, _)| *s == sequence).map(|(_, payload)| payload.clone()))
+            .collect()
+    }
+}
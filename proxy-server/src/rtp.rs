@@ -0,0 +1,448 @@
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::config::RtpConfig;
+use crate::rtp_header::payload_offset;
+
+/// Whether an RTP packet starts a decodable picture (parameter sets or an IDR),
+/// for H.264 (RFC 6184) or H.265 (RFC 7798) payloads.
+pub fn is_keyframe_start(packet: &[u8]) -> bool {
+    let Some(offset) = payload_offset(packet) else {
+        return false;
+    };
+    let payload = &packet[offset..];
+    is_h264_keyframe_start(payload) || is_h265_keyframe_start(payload)
+}
+
+fn is_h264_keyframe_start(payload: &[u8]) -> bool {
+    let Some(&nal_header) = payload.first() else {
+        return false;
+    };
+    if nal_header & 0x80 != 0 {
+        return false;
+    }
+    match nal_header & 0x1F {
+        // IDR slice, SPS
+        5 | 7 => true,
+        // STAP-A: first aggregated NAL unit
+        24 => payload.get(3).is_some_and(|n| matches!(n & 0x1F, 5 | 7)),
+        // FU-A: start fragment of an IDR slice
+        28 => payload.get(1).is_some_and(|fu| fu & 0x80 != 0 && fu & 0x1F == 5),
+        _ => false,
+    }
+}
+
+fn is_h265_keyframe_start(payload: &[u8]) -> bool {
+    if payload.len() < 2 || payload[0] & 0x80 != 0 {
+        return false;
+    }
+    let is_irap_or_vps = |t: u8| (16..=21).contains(&t) || t == 32;
+    match (payload[0] >> 1) & 0x3F {
+        // Aggregation packet: first NAL unit header after the 2-byte size
+        48 => payload.get(4).is_some_and(|n| is_irap_or_vps((n >> 1) & 0x3F)),
+        // Fragmentation unit: start fragment of an IRAP picture
+        49 => payload.get(2).is_some_and(|fu| fu & 0x80 != 0 && is_irap_or_vps(fu & 0x3F)),
+        t => is_irap_or_vps(t),
+    }
+}
+
+/// Per-channel rewriting applied to packets before they reach the browser
+#[derive(Debug)]
+pub struct RtpRewriter {
+    strip_extensions: bool,
+    ssrc: Option<u32>,
+    fix_timestamps: bool,
+    strip_padding: bool,
+    rtcp: bool,
+    /// Highest sequence number seen and the timestamp sent with it
+    last: Option<(u16, u32)>,
+    /// Shared output timeline and this forwarder's upstream source on it
+    continuity: Option<(Arc<Continuity>, u8)>,
+    /// Slow timestamps down to the session's playback rate
+    timescale: Option<ScaledClock>,
+}
+
+impl RtpRewriter {
+    /// Build a rewriter for an RTP (or, with `rtcp`, RTCP) channel, even if
+    /// the config leaves packets untouched
+    pub fn always(config: &RtpConfig, ssrc: u32, rtcp: bool) -> Self {
+        Self {
+            strip_extensions: config.strip_header_extensions && !rtcp,
+            ssrc: config.rewrite_ssrc.then_some(ssrc),
+            fix_timestamps: config.fix_timestamps && !rtcp,
+            strip_padding: config.strip_padding && !rtcp,
+            rtcp,
+            last: None,
+            continuity: None,
+            timescale: None,
+        }
+    }
+
+    /// Build a rewriter that maps `source`'s packets onto a timeline shared with
+    /// the channel's other sources. The SSRC is always rewritten so the browser
+    /// sees one stream whichever source is feeding it.
+    pub fn continuous(config: &RtpConfig, ssrc: u32, rtcp: bool, continuity: Arc<Continuity>, source: u8) -> Self {
+        let config = RtpConfig { rewrite_ssrc: true, ..*config };
+        Self::always(&config, ssrc, rtcp).with_continuity(continuity, source)
+    }
+
+    /// Also map `source`'s packets onto `continuity`'s timeline, keeping the
+    /// SSRC as the config says
+    pub fn with_continuity(mut self, continuity: Arc<Continuity>, source: u8) -> Self {
+        self.continuity = Some((continuity, source));
+        self
+    }
+
+    /// Also scale RTP timestamps down by the session's playback rate, so
+    /// media the camera sends faster than real time plays at its pace
+    pub fn with_timescale(mut self, timescale: Arc<Timescale>) -> Self {
+        if !self.rtcp {
+            self.timescale = Some(ScaledClock { rate: timescale, current: 1.0, anchor: None, last: None });
+        }
+        self
+    }
+
+    /// Append the rewritten packet to `out`; malformed packets are passed through untouched
+    pub fn rewrite_into(&mut self, packet: &[u8], out: &mut BytesMut) {
+        let start = out.len();
+        if self.rtcp {
+            out.extend_from_slice(packet);
+            if let Some(ssrc) = self.ssrc {
+                rewrite_rtcp_ssrc(&mut out[start..], ssrc);
+            }
+            if let Some((continuity, source)) = &self.continuity {
+                continuity.map_sender_report(*source, &mut out[start..]);
+            }
+            return;
+        }
+
+        let Some(offset) = payload_offset(packet) else {
+            out.extend_from_slice(packet);
+            return;
+        };
+
+        if self.strip_extensions && packet[0] & 0x10 != 0 {
+            // The proxy never negotiates extmap with the browser, so every
+            // header extension is unknown to it: drop the block and the X bit
+            let csrc_end = 12 + (packet[0] & 0x0F) as usize * 4;
+            out.extend_from_slice(&packet[..csrc_end]);
+            out[start] &= !0x10;
+            out.extend_from_slice(&packet[offset..]);
+        } else {
+            out.extend_from_slice(packet);
+        }
+
+        if self.strip_padding && out[start] & 0x20 != 0 {
+            // The last byte counts the padding bytes, itself included
+            let padding = out[out.len() - 1] as usize;
+            let header_len = payload_offset(&out[start..]).unwrap_or(out.len() - start);
+            if padding > 0 && out.len() - start >= header_len + padding {
+                out.truncate(out.len() - padding);
+                out[start] &= !0x20;
+            }
+        }
+
+        let header = &mut out[start..];
+        if let Some(ssrc) = self.ssrc {
+            header[8..12].copy_from_slice(&ssrc.to_be_bytes());
+        }
+        if let Some((continuity, source)) = &self.continuity {
+            let seq = u16::from_be_bytes([header[2], header[3]]);
+            let ts = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            let (seq, ts) = continuity.map(*source, seq, ts);
+            header[2..4].copy_from_slice(&seq.to_be_bytes());
+            header[4..8].copy_from_slice(&ts.to_be_bytes());
+        }
+        if let Some(clock) = self.timescale.as_mut() {
+            let ts = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            header[4..8].copy_from_slice(&clock.map(ts).to_be_bytes());
+        }
+        if self.fix_timestamps {
+            let seq = u16::from_be_bytes([header[2], header[3]]);
+            let ts = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            match self.last {
+                // Only newer packets move the reference; reordered ones keep their timestamp
+                Some((last_seq, last_ts)) if (seq.wrapping_sub(last_seq) as i16) > 0 => {
+                    let fixed = if (ts.wrapping_sub(last_ts) as i32) < 0 { last_ts } else { ts };
+                    header[4..8].copy_from_slice(&fixed.to_be_bytes());
+                    self.last = Some((seq, fixed));
+                }
+                Some(_) => {}
+                None => self.last = Some((seq, ts)),
+            }
+        }
+    }
+}
+
+/// Rewrite the sender SSRC of every packet in a compound RTCP packet
+fn rewrite_rtcp_ssrc(packet: &mut [u8], ssrc: u32) {
+    let mut offset = 0;
+    while packet.len() >= offset + 8 {
+        let words = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        packet[offset + 4..offset + 8].copy_from_slice(&ssrc.to_be_bytes());
+        offset += (words + 1) * 4;
+    }
+}
+
+/// Continuous sequence numbers and timestamps for one browser channel that is
+/// fed by several upstream sources in turn (main and sub-stream profiles, or a
+/// camera session that was re-established).
+///
+/// Each source keeps its own numbering upstream; when a different source
+/// starts feeding the channel, its offsets are re-anchored so the browser sees
+/// the next sequence number and a timestamp advanced by the wall-clock gap.
+#[derive(Debug)]
+pub struct Continuity {
+    clock_rate: u32,
+    state: Mutex<ContinuityState>,
+}
+
+#[derive(Debug, Default)]
+struct ContinuityState {
+    /// Source the offsets are currently anchored to
+    source: Option<u8>,
+    seq_offset: u16,
+    ts_offset: u32,
+    /// Newest packet sent to the browser: output sequence, output timestamp, when
+    last: Option<(u16, u32, Instant)>,
+}
+
+impl Continuity {
+    pub fn new(clock_rate: u32) -> Arc<Self> {
+        Arc::new(Self { clock_rate, state: Mutex::default() })
+    }
+
+    /// Map an upstream sequence number and timestamp from `source` onto the browser's timeline
+    pub fn map(&self, source: u8, seq: u16, ts: u32) -> (u16, u32) {
+        let mut state = self.state.lock().unwrap();
+        if state.source != Some(source) {
+            if let Some((last_seq, last_ts, at)) = state.last {
+                let gap = (at.elapsed().as_secs_f64() * self.clock_rate as f64) as u32;
+                state.seq_offset = last_seq.wrapping_add(1).wrapping_sub(seq);
+                state.ts_offset = last_ts.wrapping_add(gap.max(1)).wrapping_sub(ts);
+            }
+            state.source = Some(source);
+        }
+
+        let out = (seq.wrapping_add(state.seq_offset), ts.wrapping_add(state.ts_offset));
+        let newer = state.last.is_none_or(|(last_seq, ..)| (out.0.wrapping_sub(last_seq) as i16) > 0);
+        if newer {
+            state.last = Some((out.0, out.1, Instant::now()));
+        }
+        out
+    }
+
+    /// Re-anchor on the next packet whichever source it comes from, as the
+    /// camera session feeding the channel was set up again
+    pub fn restart(&self) {
+        self.state.lock().unwrap().source = None;
+    }
+
+    /// Shift the RTP timestamp of any Sender Report in a compound RTCP packet
+    /// from the anchored source, keeping its NTP mapping consistent with the RTP
+    pub fn map_sender_report(&self, source: u8, packet: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        if state.source != Some(source) {
+            return;
+        }
+        let mut offset = 0;
+        while packet.len() >= offset + 20 {
+            let words = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+            if packet[offset + 1] == 200 {
+                let at = offset + 16;
+                let ts = u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]);
+                packet[at..at + 4].copy_from_slice(&ts.wrapping_add(state.ts_offset).to_be_bytes());
+            }
+            offset += (words + 1) * 4;
+        }
+    }
+}
+
+/// The SSRC and timeline of each RTP channel of a browser session. They
+/// outlive the camera sessions feeding it, so a camera session that is set up
+/// again carries on the same stream for the browser.
+#[derive(Debug, Default)]
+pub struct SessionTimeline {
+    /// By RTP channel ID; a track's RTCP shares them
+    channels: Mutex<HashMap<u8, (u32, Arc<Continuity>)>>,
+}
+
+impl SessionTimeline {
+    /// A new camera session starts feeding the channels
+    pub fn restart(&self) {
+        for (_, continuity) in self.channels.lock().unwrap().values() {
+            continuity.restart();
+        }
+    }
+
+    /// SSRC and timeline of the track on `rtp_channel_id`, made on first use
+    pub fn channel(&self, rtp_channel_id: u8, clock_rate: u32) -> (u32, Arc<Continuity>) {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(rtp_channel_id)
+            .or_insert_with(|| (rand::random(), Continuity::new(clock_rate)))
+            .clone()
+    }
+}
+
+/// A session's playback rate: media time per second of real time, 1 at
+/// normal speed, shared by its channels' rewriters
+#[derive(Debug)]
+pub struct Timescale(AtomicU64);
+
+impl Default for Timescale {
+    fn default() -> Self {
+        Self(AtomicU64::new(1f64.to_bits()))
+    }
+}
+
+impl Timescale {
+    pub fn set(&self, rate: f64) {
+        self.0.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// One channel's timestamps at the session's playback rate. Each rate
+/// change anchors the mapping at the newest packet, so the output timeline
+/// carries on without a jump and only its slope changes.
+#[derive(Debug)]
+struct ScaledClock {
+    rate: Arc<Timescale>,
+    /// Rate of the current mapping
+    current: f64,
+    /// Input and output timestamp the mapping starts from; None maps
+    /// unchanged, until the first rate change
+    anchor: Option<(u32, u32)>,
+    /// Newest input and output timestamp
+    last: Option<(u32, u32)>,
+}
+
+impl ScaledClock {
+    fn map(&mut self, ts: u32) -> u32 {
+        let rate = self.rate.get();
+        if rate != self.current && rate > 0.0 {
+            self.current = rate;
+            self.anchor = self.last;
+        }
+        let out = match self.anchor {
+            Some((anchor_in, anchor_out)) => {
+                let elapsed = ts.wrapping_sub(anchor_in) as i32 as f64 / self.current;
+                anchor_out.wrapping_add(elapsed as i64 as u32)
+            }
+            None => ts,
+        };
+        self.last = Some((ts, out));
+        out
+    }
+}
+
+/// NTP/RTP timestamp pair from an RTCP Sender Report (RFC 3550 §6.4.1)
+#[derive(Debug, Clone, Copy)]
+pub struct SenderReport {
+    /// 64-bit NTP timestamp: seconds since 1900 in the high word, fraction in the low word
+    pub ntp: u64,
+    pub rtp_timestamp: u32,
+}
+
+/// Whether a compound RTCP packet holds a BYE (RFC 3550 §6.6)
+pub fn has_bye(packet: &[u8]) -> bool {
+    let mut offset = 0;
+    while packet.len() >= offset + 4 {
+        if packet[offset + 1] == 203 {
+            return true;
+        }
+        let words = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        offset += (words + 1) * 4;
+    }
+    false
+}
+
+/// First Sender Report in a compound RTCP packet
+pub fn sender_report(packet: &[u8]) -> Option<SenderReport> {
+    let mut offset = 0;
+    while packet.len() >= offset + 20 {
+        let words = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        if packet[offset + 1] == 200 {
+            let p = &packet[offset..];
+            return Some(SenderReport {
+                ntp: u64::from_be_bytes(p[8..16].try_into().ok()?),
+                rtp_timestamp: u32::from_be_bytes(p[16..20].try_into().ok()?),
+            });
+        }
+        offset += (words + 1) * 4;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 90000;
+
+    fn packet(seq: u16, ts: u32, ssrc: u32) -> Vec<u8> {
+        let mut packet = vec![0x80, 96];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&ts.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.push(0xAA);
+        packet
+    }
+
+    /// Sequence number, timestamp and SSRC
+    fn header(packet: &[u8]) -> (u16, u32, u32) {
+        (
+            u16::from_be_bytes([packet[2], packet[3]]),
+            u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        )
+    }
+
+    /// What one camera session's rewriter makes of `packets`
+    fn camera_session(timeline: &SessionTimeline, packets: &[(u16, u32, u32)]) -> Vec<(u16, u32, u32)> {
+        timeline.restart();
+        let (ssrc, continuity) = timeline.channel(0, CLOCK_RATE);
+        let config = RtpConfig { rewrite_ssrc: true, ..Default::default() };
+        let mut rewriter = RtpRewriter::always(&config, ssrc, false).with_continuity(continuity, 0);
+        packets
+            .iter()
+            .map(|&(seq, ts, ssrc)| {
+                let mut out = BytesMut::new();
+                rewriter.rewrite_into(&packet(seq, ts, ssrc), &mut out);
+                header(&out)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reconnect_continues_the_stream() {
+        let timeline = SessionTimeline::default();
+        let first = camera_session(&timeline, &[(1000, 5000, 0x1111), (1001, 8000, 0x1111), (1002, 11000, 0x1111)]);
+        // The camera numbers its new session from scratch
+        let second = camera_session(&timeline, &[(40000, 900, 0x2222), (40001, 3900, 0x2222)]);
+
+        let ssrc = first[0].2;
+        assert_eq!(first.iter().map(|p| (p.0, p.1)).collect::<Vec<_>>(), [(1000, 5000), (1001, 8000), (1002, 11000)]);
+        assert_eq!((second[0].0, second[1].0), (1003, 1004));
+        assert!(second[0].1 > 11000, "timestamp went back to {}", second[0].1);
+        assert_eq!(second[1].1 - second[0].1, 3000);
+        assert!(first.iter().chain(&second).all(|p| p.2 == ssrc));
+    }
+
+    #[test]
+    fn channels_keep_their_own_ssrc() {
+        let timeline = SessionTimeline::default();
+        let (video, _) = timeline.channel(0, CLOCK_RATE);
+        let (audio, _) = timeline.channel(2, 8000);
+        timeline.restart();
+        assert_eq!(timeline.channel(0, CLOCK_RATE).0, video);
+        assert_eq!(timeline.channel(2, 8000).0, audio);
+    }
+}
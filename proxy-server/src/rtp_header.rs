@@ -0,0 +1,32 @@
+//! RTP packet layout (RFC 3550), for everything that looks inside packets.
+//! Has no dependencies, so the benchmarks can build it on its own.
+
+/// Offset of the payload in an RTP packet (RFC 3550), past CSRCs and any
+/// header extension. None if the packet is truncated.
+pub fn payload_offset(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 12 {
+        return None;
+    }
+
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let mut offset = 12 + csrc_count * 4;
+    if packet[0] & 0x10 != 0 {
+        if packet.len() < offset + 4 {
+            return None;
+        }
+        let ext_words = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        offset += 4 + ext_words * 4;
+    }
+    (packet.len() >= offset).then_some(offset)
+}
+
+/// Payload of an RTP packet without any padding. None if the packet is truncated.
+pub fn payload(packet: &[u8]) -> Option<&[u8]> {
+    let offset = payload_offset(packet)?;
+    // Padding count is the last byte when the P bit is set
+    let end = match packet[0] & 0x20 != 0 {
+        true => packet.len().saturating_sub(*packet.last().unwrap_or(&0) as usize).max(offset),
+        false => packet.len(),
+    };
+    Some(&packet[offset..end])
+}
@@ -1,7 +1,8 @@
-use anyhow::{anyhow, Result};
 use std::collections::HashMap;
+use std::num::IntErrorKind;
+use crate::error::{Error, Result};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RtspRequest {
     pub method: String,
     pub path: String,
@@ -10,7 +11,7 @@ pub struct RtspRequest {
     pub body: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RtspResponse {
     pub version: String,
     pub status_code: u16,
@@ -20,58 +21,51 @@ pub struct RtspResponse {
 }
 
 impl RtspRequest {
+    pub fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            version: "RTSP/1.0".to_string(),
+            headers: HashMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Proxy-originated `SET_PARAMETER *` carrying `x-wt-*` parameters to the
+    /// browser. It is a notification only: the browser must not answer it, and
+    /// the CSeq is assigned when it is sent.
+    pub fn notification(params: &str) -> Self {
+        let mut req = Self::new("SET_PARAMETER", "*");
+        req.headers.insert("Content-Type".to_string(), "text/parameters".to_string());
+        req.headers.insert("Content-Length".to_string(), params.len().to_string());
+        req.body = params.as_bytes().to_vec();
+        req
+    }
+
     pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>> {
-        let text = String::from_utf8_lossy(data);
-        
-        // Find double CRLF which marks end of headers
-        let header_end = match text.find("\r\n\r\n") {
-            Some(idx) => idx,
-            None => return Ok(None), // Incomplete
+        let Some(head) = Head::split(data) else {
+            return Ok(None); // Incomplete
         };
+        let request_line = head.start_line.as_deref().ok_or_else(|| Error::Parse("Empty request".to_string()))?;
 
-        let header_bytes = header_end + 4;
-        let header_str = &text[..header_end];
-        
-        let mut lines = header_str.lines();
-        let request_line = lines.next().ok_or_else(|| anyhow!("Empty request"))?;
-        
         let parts: Vec<&str> = request_line.split_whitespace().collect();
         if parts.len() < 3 {
-            return Err(anyhow!("Invalid request line"));
-        }
-        
-        let method = parts[0].to_string();
-        let path = parts[1].to_string();
-        let version = parts[2].to_string();
-        
-        let mut headers = HashMap::new();
-        for line in lines {
-            if let Some((key, value)) = line.split_once(':') {
-                headers.insert(key.trim().to_string(), value.trim().to_string());
-            }
+            return Err(Error::Parse("Invalid request line".to_string()));
         }
-        
-        // Check Content-Length
-        let content_length: usize = headers
-            .get("Content-Length")
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0);
-            
-        if data.len() < header_bytes + content_length {
+
+        let Some((body, consumed)) = head.body(data)? else {
             return Ok(None); // Incomplete body
-        }
-        
-        let body = data[header_bytes..header_bytes + content_length].to_vec();
-        
+        };
+
         Ok(Some((
             RtspRequest {
-                method,
-                path,
-                version,
-                headers,
+                method: parts[0].to_string(),
+                path: parts[1].to_string(),
+                version: parts[2].to_string(),
+                headers: head.headers,
                 body,
             },
-            header_bytes + content_length,
+            consumed,
         )))
     }
 
@@ -90,62 +84,50 @@ impl RtspRequest {
 }
 
 impl RtspResponse {
+    pub fn new(status_code: u16, reason: &str) -> Self {
+        Self {
+            version: "RTSP/1.0".to_string(),
+            status_code,
+            reason: reason.to_string(),
+            headers: HashMap::new(),
+            body: Vec::new(),
+        }
+    }
+
     pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>> {
-        let text = String::from_utf8_lossy(data);
-        
-        let header_end = match text.find("\r\n\r\n") {
-            Some(idx) => idx,
-            None => return Ok(None),
+        let Some(head) = Head::split(data) else {
+            return Ok(None);
         };
+        let status_line = head.start_line.as_deref().ok_or_else(|| Error::Parse("Empty response".to_string()))?;
 
-        let header_bytes = header_end + 4;
-        let header_str = &text[..header_end];
-        
-        let mut lines = header_str.lines();
-        let status_line = lines.next().ok_or_else(|| anyhow!("Empty response"))?;
-        
-        let parts: Vec<&str> = status_line.split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(anyhow!("Invalid status line"));
-        }
-        
-        let version = parts[0].to_string();
-        let status_code: u16 = parts[1].parse().map_err(|_| anyhow!("Invalid status code"))?;
-        let reason = parts[2..].join(" ");
-        
-        let mut headers = HashMap::new();
-        for line in lines {
-            if let Some((key, value)) = line.split_once(':') {
-                headers.insert(key.trim().to_string(), value.trim().to_string());
-            }
-        }
-        
-        let content_length: usize = headers
-            .get("Content-Length")
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0);
-            
-        if data.len() < header_bytes + content_length {
+        // Some encoders leave out the reason phrase; it is filled in on output
+        let mut parts = status_line.split_whitespace();
+        let (Some(version), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(Error::Parse("Invalid status line".to_string()));
+        };
+        let status_code: u16 = code.parse().map_err(|_| Error::Parse("Invalid status code".to_string()))?;
+        let reason = parts.collect::<Vec<_>>().join(" ");
+
+        let Some((body, consumed)) = head.body(data)? else {
             return Ok(None);
-        }
-        
-        let body = data[header_bytes..header_bytes + content_length].to_vec();
-        
+        };
+
         Ok(Some((
             RtspResponse {
-                version,
+                version: version.to_string(),
                 status_code,
                 reason,
-                headers,
+                headers: head.headers,
                 body,
             },
-            header_bytes + content_length,
+            consumed,
         )))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut out = Vec::new();
-        out.extend_from_slice(format!("{} {} {}\r\n", self.version, self.status_code, self.reason).as_bytes());
+        let reason = if self.reason.is_empty() { default_reason(self.status_code) } else { &self.reason };
+        out.extend_from_slice(format!("{} {} {}\r\n", self.version, self.status_code, reason).as_bytes());
         
         for (k, v) in &self.headers {
             out.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
@@ -156,3 +138,245 @@ impl RtspResponse {
         out
     }
 }
+
+/// Largest body accepted; SDP and parameter bodies are a few kilobytes
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Start line and headers of a message, parsed leniently: lines may end in
+/// CRLF or a bare LF, and obsolete folded header lines (starting with a space
+/// or tab) continue the previous header. Output always uses CRLF.
+struct Head {
+    start_line: Option<String>,
+    headers: HashMap<String, String>,
+    /// Bytes up to and including the blank line after the headers
+    len: usize,
+}
+
+impl Head {
+    /// None until the blank line ending the headers has arrived
+    fn split(data: &[u8]) -> Option<Self> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut pos = 0;
+        loop {
+            let end = pos + data[pos..].iter().position(|&b| b == b'\n')?;
+            let line = String::from_utf8_lossy(&data[pos..end]);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            pos = end + 1;
+
+            if line.is_empty() {
+                // Stray blank lines before a message are skipped
+                if lines.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            let folded = lines.len() > 1 && line.starts_with([' ', '\t']);
+            match lines.last_mut() {
+                Some(last) if folded => {
+                    last.push(' ');
+                    last.push_str(line.trim());
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+
+        let mut lines = lines.into_iter();
+        let start_line = lines.next();
+        let mut headers = HashMap::new();
+        for line in lines {
+            if let Some((key, value)) = line.split_once(':') {
+                headers.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Some(Self { start_line, headers, len: pos })
+    }
+
+    /// The body announced by Content-Length and the total message length,
+    /// or None if the body is incomplete. A Content-Length that is not a
+    /// number or is over [`MAX_BODY`] is an error; `validate` is stricter.
+    fn body(&self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        let content_length = match self.headers.get("Content-Length") {
+            None => 0,
+            Some(v) => match v.parse::<usize>() {
+                Ok(len) if len <= MAX_BODY => len,
+                Err(e) if *e.kind() != IntErrorKind::PosOverflow => return Err(Error::Parse(format!("Invalid Content-Length: {:?}", v))),
+                _ => return Err(Error::Parse(format!("Content-Length over {} bytes", MAX_BODY))),
+            },
+        };
+        let end = self.len.checked_add(content_length).ok_or_else(|| Error::Parse("Message too large".to_string()))?;
+        Ok((data.len() >= end).then(|| (data[self.len..end].to_vec(), end)))
+    }
+}
+
+/// Standard reason phrase for responses that arrived without one
+fn default_reason(status_code: u16) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        250 => "Low on Storage Space",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Moved Temporarily",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        451 => "Parameter Not Understood",
+        454 => "Session Not Found",
+        455 => "Method Not Valid in This State",
+        459 => "Aggregate Operation Not Allowed",
+        461 => "Unsupported Transport",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "RTSP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+
+    /// Headers as a camera or browser might send them. Values have no
+    /// surrounding whitespace, which parsing trims; Content-Length is added
+    /// to match the body.
+    fn headers(body_len: usize) -> impl Strategy<Value = HashMap<String, String>> {
+        hash_map("X-[A-Za-z0-9-]{1,16}", "[!-~]([ -~]{0,30}[!-~])?", 0..8).prop_map(move |mut headers| {
+            headers.insert("Content-Length".to_string(), body_len.to_string());
+            headers
+        })
+    }
+
+    fn version() -> impl Strategy<Value = String> {
+        prop_oneof![Just("RTSP/1.0".to_string()), Just("RTSP/2.0".to_string())]
+    }
+
+    fn request() -> impl Strategy<Value = RtspRequest> {
+        (vec(any::<u8>(), 0..256), "[A-Z_]{1,16}", "[!-~]{1,64}", version())
+            .prop_flat_map(|(body, method, path, version)| {
+                headers(body.len()).prop_map(move |headers| RtspRequest {
+                    method: method.clone(),
+                    path: path.clone(),
+                    version: version.clone(),
+                    headers,
+                    body: body.clone(),
+                })
+            })
+    }
+
+    fn response() -> impl Strategy<Value = RtspResponse> {
+        // Parsing joins the reason's words with single spaces
+        let reason = vec("[A-Za-z]{1,8}", 1..4).prop_map(|words| words.join(" "));
+        (vec(any::<u8>(), 0..256), 100..=999u16, reason, version())
+            .prop_flat_map(|(body, status_code, reason, version)| {
+                headers(body.len()).prop_map(move |headers| RtspResponse {
+                    version: version.clone(),
+                    status_code,
+                    reason: reason.clone(),
+                    headers,
+                    body: body.clone(),
+                })
+            })
+    }
+
+    #[test]
+    fn bare_lf_line_endings() {
+        let data = b"RTSP/1.0 200 OK\nCSeq: 2\nContent-Length: 3\n\nv=0";
+        let (response, consumed) = RtspResponse::parse(data).unwrap().unwrap();
+        assert_eq!((response.status_code, response.reason.as_str(), consumed), (200, "OK", data.len()));
+        assert_eq!(response.headers["CSeq"], "2");
+        assert_eq!(response.body, b"v=0");
+    }
+
+    #[test]
+    fn folded_header_continues_the_previous_one() {
+        let data = b"RTSP/1.0 200 OK\r\nCSeq: 3\r\nPublic: OPTIONS, DESCRIBE,\r\n\tSETUP, PLAY\r\n  TEARDOWN\r\n\r\n";
+        let (response, consumed) = RtspResponse::parse(data).unwrap().unwrap();
+        assert_eq!(response.headers["Public"], "OPTIONS, DESCRIBE, SETUP, PLAY TEARDOWN");
+        assert_eq!(response.headers["CSeq"], "3");
+        assert_eq!(consumed, data.len());
+    }
+
+    #[test]
+    fn missing_reason_phrase_is_filled_in() {
+        let (response, _) = RtspResponse::parse(b"RTSP/1.0 454\r\nCSeq: 4\r\n\r\n").unwrap().unwrap();
+        assert_eq!((response.status_code, response.reason.as_str()), (454, ""));
+        assert!(response.to_bytes().starts_with(b"RTSP/1.0 454 Session Not Found\r\n"));
+    }
+
+    #[test]
+    fn bad_content_length_is_rejected() {
+        let parse = |content_length: &str| RtspRequest::parse(format!("ANNOUNCE rtsp://cam/ RTSP/1.0\r\nContent-Length: {}\r\n\r\n", content_length).as_bytes());
+        for content_length in ["", "abc", "-1", "3 3", "0x10", "1.5"] {
+            assert!(matches!(parse(content_length), Err(Error::Parse(_))), "{:?}", content_length);
+        }
+        for content_length in [(MAX_BODY + 1).to_string(), usize::MAX.to_string(), "99999999999999999999999".to_string()] {
+            assert!(matches!(parse(&content_length), Err(Error::Parse(_))), "{}", content_length);
+        }
+        assert_eq!(parse(&MAX_BODY.to_string()).unwrap(), None);
+        assert_eq!(parse("0").unwrap().unwrap().0.body, b"");
+    }
+
+    proptest! {
+        #[test]
+        fn request_round_trips(request in request()) {
+            let bytes = request.to_bytes();
+            prop_assert_eq!(RtspRequest::parse(&bytes).unwrap(), Some((request, bytes.len())));
+        }
+
+        #[test]
+        fn response_round_trips(response in response()) {
+            let bytes = response.to_bytes();
+            prop_assert_eq!(RtspResponse::parse(&bytes).unwrap(), Some((response, bytes.len())));
+        }
+
+        /// A message split anywhere is incomplete, never an error, until its last byte
+        #[test]
+        fn partial_request_waits(request in request()) {
+            let bytes = request.to_bytes();
+            for end in 0..bytes.len() {
+                prop_assert_eq!(RtspRequest::parse(&bytes[..end]).unwrap(), None, "split at {}", end);
+            }
+        }
+
+        #[test]
+        fn partial_response_waits(response in response()) {
+            let bytes = response.to_bytes();
+            for end in 0..bytes.len() {
+                prop_assert_eq!(RtspResponse::parse(&bytes[..end]).unwrap(), None, "split at {}", end);
+            }
+        }
+
+        /// Only the first of several buffered messages is consumed, blank lines before it included
+        #[test]
+        fn pipelined_responses_split(first in response(), second in response(), blank_lines in 0..3usize) {
+            let mut bytes = b"\r\n".repeat(blank_lines);
+            bytes.extend_from_slice(&first.to_bytes());
+            let consumed = bytes.len();
+            bytes.extend_from_slice(&second.to_bytes());
+
+            prop_assert_eq!(RtspResponse::parse(&bytes).unwrap(), Some((first, consumed)));
+            prop_assert_eq!(RtspResponse::parse(&bytes[consumed..]).unwrap(), Some((second, bytes.len() - consumed)));
+        }
+
+        /// Any Content-Length is a body length, an error or a wait; none overflows
+        #[test]
+        fn any_content_length_is_handled(content_length in prop_oneof!["[0-9]{1,30}", "[ -~]{0,20}", any::<usize>().prop_map(|n| n.to_string())]) {
+            let bytes = format!("RTSP/1.0 200 OK\r\nContent-Length: {}\r\n\r\nv=0\r\n", content_length);
+            let value = content_length.trim();
+            match RtspResponse::parse(bytes.as_bytes()) {
+                Ok(Some((response, _))) => prop_assert_eq!(Some(response.body.len()), value.parse().ok()),
+                Ok(None) => prop_assert!(value.parse::<usize>().is_ok_and(|len| len > 5 && len <= MAX_BODY)),
+                Err(_) => prop_assert!(!value.parse::<usize>().is_ok_and(|len| len <= MAX_BODY)),
+            }
+        }
+    }
+}
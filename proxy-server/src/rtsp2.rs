@@ -0,0 +1,169 @@
+//! RTSP 2.0 (RFC 7826) cameras: version negotiation, and translation between
+//! the browser's RTSP/1.0 and the camera's 2.0 on the relayed connection
+
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::config::{Credentials, QuirkRule, UpstreamConfig};
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::rtsp_client::RtspClient;
+
+/// Upper bound on the version check, so a silent camera cannot hold up the session
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Protocol version spoken with a camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RtspVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl RtspVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtspVersion::V1 => "RTSP/1.0",
+            RtspVersion::V2 => "RTSP/2.0",
+        }
+    }
+}
+
+/// Ask the camera for `OPTIONS` in RTSP/2.0 on a separate connection. A
+/// 1.0-only camera answers 505, answers in 1.0 or hangs up, and gets 1.0.
+pub async fn negotiate(
+    url: &str,
+    credentials: Option<Credentials>,
+    quirks: Option<QuirkRule>,
+    upstream: &UpstreamConfig,
+) -> RtspVersion {
+    let attempt = async {
+        let parsed = url::Url::parse(url)?;
+        let mut client = RtspClient::connect(&parsed, upstream)
+            .await?
+            .with_credentials(credentials)
+            .with_quirks(quirks)
+            .with_version(RtspVersion::V2);
+        client.request("OPTIONS", url, &[("Supported", "play.basic".to_string())]).await
+    };
+    match tokio::time::timeout(NEGOTIATE_TIMEOUT, attempt).await {
+        Ok(Ok(resp)) if resp.version == RtspVersion::V2.as_str() && (200..300).contains(&resp.status_code) => {
+            info!("Camera speaks RTSP/2.0");
+            RtspVersion::V2
+        }
+        Ok(Ok(resp)) => {
+            info!("Camera answered RTSP/2.0 OPTIONS with {} {}; using RTSP/1.0", resp.version, resp.status_code);
+            RtspVersion::V1
+        }
+        Ok(Err(e)) => {
+            warn!("RTSP/2.0 negotiation failed, using RTSP/1.0: {:#}", e);
+            RtspVersion::V1
+        }
+        Err(_) => {
+            warn!("RTSP/2.0 negotiation timed out, using RTSP/1.0");
+            RtspVersion::V1
+        }
+    }
+}
+
+/// Rewrites relayed messages for a 2.0 camera; the browser always sees 1.0
+#[derive(Debug)]
+pub struct Translator {
+    /// `Pipelined-Requests` identifier for requests sent before the camera
+    /// assigned a session, so SETUP and PLAY can be sent back to back
+    pipeline_id: String,
+    session_known: bool,
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Self { pipeline_id: rand::random::<u32>().to_string(), session_known: false }
+    }
+
+    /// Browser (or proxy) request on its way to the camera
+    pub fn request(&mut self, req: &mut RtspRequest) {
+        req.version = RtspVersion::V2.as_str().to_string();
+        if req.method == "SETUP" {
+            if let Some(transport) = req.headers.get_mut("Transport") {
+                *transport = ports_to_addresses(transport);
+            }
+            req.headers.entry("Accept-Ranges".to_string()).or_insert_with(|| "npt".to_string());
+        }
+        if !self.session_known && !req.headers.contains_key("Session") && matches!(req.method.as_str(), "SETUP" | "PLAY") {
+            req.headers.insert("Pipelined-Requests".to_string(), self.pipeline_id.clone());
+        }
+    }
+
+    /// Camera response on its way to the proxy's own handling and the browser
+    pub fn response(&mut self, resp: &mut RtspResponse) {
+        resp.version = RtspVersion::V1.as_str().to_string();
+        self.session_known |= resp.headers.contains_key("Session");
+        resp.headers.remove("Pipelined-Requests");
+        if let Some(transport) = resp.headers.get_mut("Transport") {
+            *transport = addresses_to_ports(transport);
+        }
+        if let Some(rtp_info) = resp.headers.get_mut("RTP-Info") {
+            *rtp_info = rtp_info_v1(rtp_info);
+        }
+    }
+}
+
+/// `client_port=a-b` (1.0) to `dest_addr=":a"/":b"` (2.0); without a host,
+/// the camera sends to the address of the RTSP connection
+fn ports_to_addresses(transport: &str) -> String {
+    transport
+        .split(';')
+        .map(|param| match param.trim().strip_prefix("client_port=").and_then(|p| p.split_once('-')) {
+            Some((rtp, rtcp)) => format!("dest_addr=\":{}\"/\":{}\"", rtp, rtcp),
+            None => param.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// `src_addr` and `dest_addr` (2.0) back to `source`, `server_port` and
+/// `client_port` (1.0), which the proxy and browser understand
+fn addresses_to_ports(transport: &str) -> String {
+    let mut params = Vec::new();
+    for param in transport.split(';') {
+        let Some((name, value)) = param.trim().split_once('=') else {
+            params.push(param.to_string());
+            continue;
+        };
+        let addresses: Vec<(&str, &str)> = value
+            .split('/')
+            .filter_map(|a| a.trim_matches('"').rsplit_once(':'))
+            .collect();
+        match (name, addresses.as_slice()) {
+            ("src_addr", [(host, rtp), (_, rtcp), ..]) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if !host.is_empty() {
+                    params.push(format!("source={}", host));
+                }
+                params.push(format!("server_port={}-{}", rtp, rtcp));
+            }
+            ("dest_addr", [(_, rtp), (_, rtcp), ..]) => params.push(format!("client_port={}-{}", rtp, rtcp)),
+            _ => params.push(param.to_string()),
+        }
+    }
+    params.join(";")
+}
+
+/// 2.0 `url="U" ssrc=X:seq=A;rtptime=B` entries to 1.0 `url=U;seq=A;rtptime=B`
+fn rtp_info_v1(rtp_info: &str) -> String {
+    rtp_info
+        .split(',')
+        .map(|entry| {
+            let mut fields = vec![];
+            for part in entry.split_whitespace() {
+                if let Some(url) = part.strip_prefix("url=") {
+                    fields.push(format!("url={}", url.trim_matches('"')));
+                } else if let Some((_, params)) = part.strip_prefix("ssrc=").and_then(|p| p.split_once(':')) {
+                    fields.push(params.to_string());
+                } else {
+                    fields.push(part.to_string());
+                }
+            }
+            fields.join(";")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
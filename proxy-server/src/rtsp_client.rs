@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::IpAddr;
+use crate::auth::UpstreamAuth;
+use crate::config::{Credentials, QuirkRule, UpstreamConfig};
+use crate::quirks::QuirkSelector;
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::rtsp2::RtspVersion;
+use crate::upstream::{Upstream, UpstreamReader, UpstreamWriter};
+
+const USER_AGENT: &str = "rtsp2browser";
+
+/// Resolve the `host:port` of an RTSP URL, defaulting to port 8554
+pub fn upstream_addr(url: &url::Url) -> Result<String> {
+    let host = url.host_str().context("Missing host in RTSP URL")?;
+    let port = url.port().unwrap_or(8554);
+    Ok(format!("{}:{}", host, port))
+}
+
+/// RTSP client used when the proxy itself drives an upstream session
+/// (as opposed to relaying the browser's requests)
+pub struct RtspClient {
+    reader: UpstreamReader,
+    writer: UpstreamWriter,
+    buf: BytesMut,
+    cseq: u32,
+    session_id: Option<String>,
+    auth: Option<UpstreamAuth>,
+    quirks: QuirkSelector,
+    camera_ip: IpAddr,
+    /// Whether media must be requested interleaved on this connection
+    interleaved: bool,
+    /// Whether the camera has answered any request with 401
+    challenged: bool,
+    version: RtspVersion,
+}
+
+impl RtspClient {
+    pub async fn connect(url: &url::Url, upstream: &UpstreamConfig) -> Result<Self> {
+        let Upstream { reader, writer, camera_ip, interleaved } = crate::upstream::connect(url, upstream).await?;
+
+        Ok(Self {
+            reader,
+            writer,
+            camera_ip,
+            interleaved,
+            challenged: false,
+            buf: BytesMut::with_capacity(4096),
+            cseq: 1,
+            session_id: None,
+            auth: None,
+            quirks: QuirkSelector::default(),
+            version: RtspVersion::default(),
+        })
+    }
+
+    /// Authorize every request with these credentials
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.auth = credentials.map(UpstreamAuth::new);
+        self
+    }
+
+    /// Apply a configured quirk rule; the vendor is auto-detected otherwise
+    pub fn with_quirks(mut self, rule: Option<QuirkRule>) -> Self {
+        self.quirks = QuirkSelector::new(rule);
+        self
+    }
+
+    /// Protocol version of the requests sent
+    pub fn with_version(mut self, version: RtspVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Address of the camera this client is connected to
+    pub fn camera_ip(&self) -> IpAddr {
+        self.camera_ip
+    }
+
+    /// The connection, for relaying a session on it; anything the camera
+    /// sent that was not read yet comes first
+    pub fn into_upstream(self) -> Upstream {
+        let reader: UpstreamReader = if self.buf.is_empty() {
+            self.reader
+        } else {
+            Box::new(tokio::io::AsyncReadExt::chain(std::io::Cursor::new(self.buf), self.reader))
+        };
+        Upstream { reader, writer: self.writer, camera_ip: self.camera_ip, interleaved: self.interleaved }
+    }
+
+    /// Whether the camera asked for credentials, even if they were then accepted
+    pub fn challenged(&self) -> bool {
+        self.challenged
+    }
+
+    /// Method to use for session keep-alives with this camera
+    pub fn keepalive_method(&self) -> &'static str {
+        self.quirks.quirks().keepalive.method()
+    }
+
+    /// Send a request and wait for its response, tracking CSeq and Session
+    pub async fn request(&mut self, method: &str, url: &str, headers: &[(&str, String)]) -> Result<RtspResponse> {
+        let mut req = RtspRequest::new(method, &crate::upstream::camera_url(url));
+        req.version = self.version.as_str().to_string();
+        req.headers.insert("CSeq".to_string(), self.cseq.to_string());
+        req.headers.insert("User-Agent".to_string(), USER_AGENT.to_string());
+        if let Some(sid) = &self.session_id {
+            req.headers.insert("Session".to_string(), sid.clone());
+        }
+        for (k, v) in headers {
+            req.headers.insert(k.to_string(), v.clone());
+        }
+        self.cseq += 1;
+
+        let mut resp = self.send(&mut req).await?;
+        self.challenged |= resp.status_code == 401;
+        if resp.status_code == 401 && self.auth.as_mut().is_some_and(|auth| auth.on_unauthorized(&resp)) {
+            resp = self.send(&mut req).await?;
+        }
+
+        if let Some(sid) = resp.headers.get("Session") {
+            // Session ID might have ;timeout=...
+            let clean_sid = sid.split(';').next().unwrap_or(sid).to_string();
+            self.session_id.get_or_insert(clean_sid);
+        }
+
+        Ok(resp)
+    }
+
+    async fn send(&mut self, req: &mut RtspRequest) -> Result<RtspResponse> {
+        if let Some(auth) = self.auth.as_mut() {
+            auth.authorize(req);
+        }
+        self.writer.write_all(&req.to_bytes()).await?;
+
+        loop {
+            if let Some((resp, consumed)) = RtspResponse::parse(&self.buf)? {
+                self.buf.advance(consumed);
+                self.quirks.observe(&resp.headers);
+                return Ok(resp);
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(anyhow!("RTSP server closed connection during {}", req.method));
+            }
+        }
+    }
+
+    /// Like [`request`](Self::request), but treats non-2xx responses as errors
+    pub async fn request_ok(&mut self, method: &str, url: &str, headers: &[(&str, String)]) -> Result<RtspResponse> {
+        let resp = self.request(method, url, headers).await?;
+        if !(200..300).contains(&resp.status_code) {
+            return Err(anyhow!("{} failed: {} {}", method, resp.status_code, resp.reason));
+        }
+        Ok(resp)
+    }
+}
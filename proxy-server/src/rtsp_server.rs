@@ -0,0 +1,198 @@
+//! RTSP listener: re-exports configured cameras at `rtsp://<proxy>/<name>`
+//! for consumers that only speak RTSP, such as a VMS or ffmpeg.
+//!
+//! Each connection runs an ordinary session over a [`TcpLink`], as if a TCP
+//! client had opened `/?rtsp=<camera>&framing=rfc4571`: requests for the
+//! proxy's URL are rewritten to the camera's, SETUP answers carry
+//! `interleaved` channels, and media goes back as RFC 2326 `$` frames.
+//! Only TCP interleaved delivery is offered.
+
+use anyhow::{bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use crate::config::{RtspServerConfig, RtspStream};
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::tcp::{Dialect, TcpLink, TcpWriter, FRAME_CONTROL, FRAME_OPEN};
+
+/// Largest RTSP request or interleaved frame accepted from a consumer
+const MAX_MESSAGE: usize = 64 * 1024;
+/// Feedback frames queued before further ones are dropped
+const MEDIA_QUEUE: usize = 64;
+
+/// What a consumer sent
+enum Incoming {
+    Request(RtspRequest),
+    /// An interleaved frame without its `$`: `[channel][u16 length][packet]`
+    Media(Bytes),
+}
+
+/// Read the consumer's first request and find the stream it names. The
+/// target is the path and query of the equivalent TCP session request; None
+/// when the consumer was answered and hung up on.
+pub async fn accept(stream: TcpStream, config: &RtspServerConfig) -> Result<Option<(String, TcpLink)>> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = BytesMut::new();
+    let first = loop {
+        match next_message(&mut reader, &mut buf).await? {
+            Some(Incoming::Request(req)) => break req,
+            Some(Incoming::Media(_)) => continue,
+            None => bail!("RTSP client closed the connection before its first request"),
+        }
+    };
+    let cseq = header(&first.headers, "CSeq").map(str::to_string);
+    let Some((exported, base)) = find_stream(&first.path, &config.stream) else {
+        warn!("RTSP client asked for unknown stream {}", first.path);
+        let mut response = RtspResponse::new(404, "");
+        if let Some(cseq) = &cseq {
+            response.headers.insert("CSeq".to_string(), cseq.clone());
+        }
+        writer.write_all(&response.to_bytes()).await?;
+        let _ = writer.shutdown().await;
+        return Ok(None);
+    };
+    info!("RTSP client asked for stream '{}'", exported.name);
+
+    let mut target = format!("/?rtsp={}&framing=rfc4571", encode(&exported.url));
+    if let Some(token) = &exported.token {
+        target.push_str(&format!("&token={}", encode(token)));
+    }
+
+    let (control_tx, control) = mpsc::channel(16);
+    let (media_tx, media) = mpsc::channel(MEDIA_QUEUE);
+    let closed = CancellationToken::new();
+    let token = closed.clone();
+    let camera = exported.url.clone();
+    tokio::spawn(async move {
+        // The first request is the session's too
+        let mut pending = Some(first);
+        loop {
+            let incoming = match pending.take() {
+                Some(req) => Some(Incoming::Request(req)),
+                None => match next_message(&mut reader, &mut buf).await {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        warn!("RTSP client read error: {:#}", e);
+                        break;
+                    }
+                },
+            };
+            match incoming {
+                Some(Incoming::Request(mut req)) => {
+                    if let Some(rest) = req.path.strip_prefix(&base) {
+                        req.path = format!("{}{}", camera.trim_end_matches('/'), rest);
+                    }
+                    if control_tx.send(Bytes::from(req.to_bytes())).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Incoming::Media(frame)) => {
+                    // Feedback is lossy anyway; a client flooding it loses some
+                    let _ = media_tx.try_send(frame);
+                }
+                None => break,
+            }
+        }
+        token.cancel();
+    });
+
+    let writer = TcpWriter::new(writer, Dialect::Rtsp(Arc::new(Mutex::new(cseq))));
+    let link = TcpLink { writer, control, media: Arc::new(tokio::sync::Mutex::new(media)), closed };
+    Ok(Some((target, link)))
+}
+
+/// What an RTSP consumer is sent for a frame of the session, if anything:
+/// responses with their Transport made plain interleaved, and a 503 for the
+/// first request when the session is refused. Proxy notifications have no
+/// meaning to an RTSP client and are dropped.
+pub fn outgoing(kind: u8, payload: &[u8], unanswered: &Mutex<Option<String>>) -> Option<Vec<u8>> {
+    match kind {
+        FRAME_CONTROL => {
+            if !payload.starts_with(b"RTSP/") {
+                return None;
+            }
+            *unanswered.lock().unwrap() = None;
+            let Ok(Some((mut response, _))) = RtspResponse::parse(payload) else {
+                return Some(payload.to_vec());
+            };
+            let key = response.headers.keys().find(|key| key.eq_ignore_ascii_case("Transport")).cloned();
+            if let Some(transport) = key.and_then(|key| response.headers.get_mut(&key)) {
+                *transport = interleaved_transport(transport);
+            }
+            Some(response.to_bytes())
+        }
+        FRAME_OPEN if payload.starts_with(b"ERR") => {
+            let cseq = unanswered.lock().unwrap().take()?;
+            let mut response = RtspResponse::new(503, "");
+            response.headers.insert("CSeq".to_string(), cseq);
+            Some(response.to_bytes())
+        }
+        _ => None,
+    }
+}
+
+/// The stream a request URL names, and the URL prefix that stands for the
+/// camera's
+fn find_stream<'a>(url: &str, streams: &'a [RtspStream]) -> Option<(&'a RtspStream, String)> {
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path_start = after_scheme.find('/')?;
+    let name = after_scheme[path_start + 1..].split(['/', '?']).next()?;
+    let stream = streams.iter().find(|stream| stream.name == name)?;
+    let base_len = url.len() - after_scheme.len() + path_start + 1 + name.len();
+    Some((stream, url[..base_len].to_string()))
+}
+
+/// A SETUP answer's Transport as an RTSP client expects it: TCP with the
+/// channels the proxy assigned, which come after any the camera sent
+fn interleaved_transport(value: &str) -> String {
+    let params: Vec<&str> = value.split(';').map(str::trim).collect();
+    let mut transport = "RTP/AVP/TCP;unicast".to_string();
+    if let Some(channels) = params.iter().rev().find(|param| param.starts_with("interleaved=")) {
+        transport.push(';');
+        transport.push_str(channels);
+    }
+    if let Some(ssrc) = params.iter().find(|param| param.starts_with("ssrc=")) {
+        transport.push(';');
+        transport.push_str(ssrc);
+    }
+    transport
+}
+
+fn header<'a>(headers: &'a std::collections::HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+fn encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Next request or interleaved frame, or None at a clean end of stream
+async fn next_message(reader: &mut OwnedReadHalf, buf: &mut BytesMut) -> Result<Option<Incoming>> {
+    loop {
+        if buf.first() == Some(&b'$') {
+            if buf.len() >= 4 {
+                let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+                if buf.len() >= 4 + len {
+                    buf.advance(1);
+                    return Ok(Some(Incoming::Media(buf.split_to(3 + len).freeze())));
+                }
+            }
+        } else if !buf.is_empty() {
+            if let Some((req, consumed)) = RtspRequest::parse(&buf[..])? {
+                buf.advance(consumed);
+                return Ok(Some(Incoming::Request(req)));
+            }
+            if buf.len() > MAX_MESSAGE {
+                bail!("RTSP request exceeds {} bytes", MAX_MESSAGE);
+            }
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
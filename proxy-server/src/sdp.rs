@@ -0,0 +1,257 @@
+use base64::Engine;
+use std::collections::HashMap;
+
+pub use crate::protocol::StreamInfo;
+
+/// Parsed Session Description (RFC 4566), limited to what the proxy needs
+#[derive(Debug, Clone, Default)]
+pub struct SessionDescription {
+    /// Session-level `a=control` attribute
+    pub control: Option<String>,
+    pub media: Vec<MediaDescription>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaDescription {
+    /// Media type from the `m=` line ("video", "audio", "application", ...)
+    pub kind: String,
+    /// First payload type listed on the `m=` line
+    pub payload_type: Option<u8>,
+    /// Every payload type listed on the `m=` line
+    pub formats: Vec<u8>,
+    /// Encoding name from the matching `a=rtpmap` ("H264", "H265", ...)
+    pub encoding: Option<String>,
+    pub clock_rate: Option<u32>,
+    /// Format parameters from the matching `a=fmtp`
+    pub fmtp: Option<String>,
+    /// Size from `a=framesize` or `a=x-dimensions`
+    pub dimensions: Option<(u32, u32)>,
+    /// Frames per second from `a=framerate`
+    pub framerate: Option<f64>,
+    /// Media-level bandwidth from `b=AS` (kbit/s) or `b=TIAS` (bit/s), in kbit/s
+    pub bandwidth_kbps: Option<u32>,
+    /// Media-level `a=control` attribute
+    pub control: Option<String>,
+}
+
+
+impl SessionDescription {
+    pub fn parse(text: &str) -> Self {
+        let mut sdp = SessionDescription::default();
+
+        for line in text.lines() {
+            let line = line.trim_end();
+            if let Some(media) = line.strip_prefix("m=") {
+                let mut parts = media.split_whitespace();
+                let kind = parts.next().unwrap_or_default().to_string();
+                // Skip port and protocol
+                let formats: Vec<u8> = parts.skip(2).filter_map(|pt| pt.parse().ok()).collect();
+                sdp.media.push(MediaDescription {
+                    kind,
+                    payload_type: formats.first().copied(),
+                    formats,
+                    ..Default::default()
+                });
+            } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                let Some(media) = sdp.media.last_mut() else {
+                    continue;
+                };
+                let Some((pt, format)) = rtpmap.split_once(' ') else {
+                    continue;
+                };
+                if pt.parse().ok() == media.payload_type {
+                    let mut format = format.trim().split('/');
+                    media.encoding = format.next().map(|e| e.to_string());
+                    media.clock_rate = format.next().and_then(|c| c.parse().ok());
+                }
+            } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+                let Some(media) = sdp.media.last_mut() else {
+                    continue;
+                };
+                if let Some((pt, params)) = fmtp.split_once(' ')
+                    && pt.parse().ok() == media.payload_type
+                {
+                    media.fmtp = Some(params.trim().to_string());
+                }
+            } else if let Some(framesize) = line.strip_prefix("a=framesize:") {
+                // a=framesize:<pt> <width>-<height>
+                if let Some(media) = sdp.media.last_mut() {
+                    let size = framesize.split_whitespace().nth(1).and_then(|s| s.split_once('-'));
+                    media.dimensions = size.and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+                }
+            } else if let Some(dimensions) = line.strip_prefix("a=x-dimensions:") {
+                // a=x-dimensions:<width>,<height>
+                if let Some(media) = sdp.media.last_mut() {
+                    let size = dimensions.split_once(',');
+                    media.dimensions = size.and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)));
+                }
+            } else if let Some(framerate) = line.strip_prefix("a=framerate:") {
+                if let Some(media) = sdp.media.last_mut() {
+                    media.framerate = framerate.trim().parse().ok().filter(|fps: &f64| *fps > 0.0);
+                }
+            } else if let Some(bandwidth) = line.strip_prefix("b=") {
+                // Per track only; AS is what cameras send, TIAS only counts when it is alone
+                let Some(media) = sdp.media.last_mut() else {
+                    continue;
+                };
+                let kbps = match bandwidth.split_once(':') {
+                    Some(("AS", kbps)) => kbps.trim().parse().ok(),
+                    Some(("TIAS", bps)) => bps.trim().parse::<u32>().ok().map(|bps| bps / 1000),
+                    _ => None,
+                };
+                if kbps.is_some() && (bandwidth.starts_with("AS:") || media.bandwidth_kbps.is_none()) {
+                    media.bandwidth_kbps = kbps;
+                }
+            } else if let Some(control) = line.strip_prefix("a=control:") {
+                let control = Some(control.trim().to_string());
+                match sdp.media.last_mut() {
+                    Some(media) => media.control = control,
+                    None => sdp.control = control,
+                }
+            }
+        }
+
+        sdp
+    }
+
+    pub fn first_video(&self) -> Option<&MediaDescription> {
+        self.media.iter().find(|m| m.kind == "video")
+    }
+
+    /// URL for aggregate control of the whole presentation (PLAY, keep-alives, TEARDOWN)
+    pub fn aggregate_url(&self, base: &str) -> String {
+        resolve_control(base, self.control.as_deref())
+    }
+
+    /// Find the media section a SETUP request URL refers to
+    pub fn media_for_url(&self, base: &str, url: &str) -> Option<&MediaDescription> {
+        self.media
+            .iter()
+            .find(|m| resolve_control(base, m.control.as_deref()) == url)
+    }
+}
+
+impl MediaDescription {
+    /// Value of one `a=fmtp` parameter
+    pub fn fmtp_param(&self, name: &str) -> Option<&str> {
+        self.fmtp.as_deref()?.split(';').find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// Video size, from the SDP attributes or the H.264 SPS in `sprop-parameter-sets`
+    pub fn resolution(&self) -> Option<(u32, u32)> {
+        if self.dimensions.is_some() {
+            return self.dimensions;
+        }
+        self.sps().map(|sps| (sps.width, sps.height))
+    }
+
+    /// The H.264 SPS from `sprop-parameter-sets`, decoded
+    fn sps(&self) -> Option<crate::h264::Sps> {
+        self.parameter_sets().iter().find_map(|nal| crate::h264::parse_sps(nal))
+    }
+
+    /// Stream properties from the SDP alone. The H.264 SPS in
+    /// `sprop-parameter-sets` gives the frame rate, profile and level when
+    /// it has them, `a=framerate` and the `a=fmtp` profile fields otherwise.
+    pub fn info(&self) -> StreamInfo {
+        let sps = self.sps();
+        let resolution = self.dimensions.or(sps.map(|sps| (sps.width, sps.height)));
+        let (profile, level) = match sps {
+            Some(sps) => (
+                crate::h264::profile_name(sps.profile_idc, sps.constraints).map(str::to_string),
+                Some(crate::h264::level_name(sps.profile_idc, sps.constraints, sps.level_idc)),
+            ),
+            None => self.fmtp_profile_level(),
+        };
+        StreamInfo {
+            kind: self.kind.clone(),
+            codec: self.encoding.clone(),
+            clock_rate: self.clock_rate,
+            width: resolution.map(|(w, _)| w),
+            height: resolution.map(|(_, h)| h),
+            // 29.97, not 29.97002997
+            fps: sps.and_then(|sps| sps.fps).or(self.framerate).map(|fps| (fps * 100.0).round() / 100.0),
+            bitrate_kbps: self.bandwidth_kbps,
+            profile,
+            level,
+        }
+    }
+
+    /// Profile and level from `profile-level-id` (H.264) or `profile-id` and
+    /// `level-id` (H.265)
+    fn fmtp_profile_level(&self) -> (Option<String>, Option<String>) {
+        let encoding = self.encoding.as_deref().unwrap_or_default();
+        if encoding.eq_ignore_ascii_case("H264") {
+            // Three hex bytes: profile_idc, constraint flags, level_idc
+            let Some(id) = self.fmtp_param("profile-level-id").and_then(|id| u32::from_str_radix(id, 16).ok()) else {
+                return (None, None);
+            };
+            let (profile_idc, constraints, level_idc) = ((id >> 16) as u8, (id >> 8) as u8, id as u8);
+            (
+                crate::h264::profile_name(profile_idc, constraints).map(str::to_string),
+                Some(crate::h264::level_name(profile_idc, constraints, level_idc)),
+            )
+        } else if encoding.eq_ignore_ascii_case("H265") {
+            let profile = match self.fmtp_param("profile-id") {
+                Some("1") => Some("main"),
+                Some("2") => Some("main-10"),
+                Some("3") => Some("main-still-picture"),
+                _ => None,
+            };
+            // level-id is 30 times the level
+            let level = self.fmtp_param("level-id").and_then(|id| id.parse::<u32>().ok()).map(|id| {
+                let tenths = id / 3;
+                format!("{}.{}", tenths / 10, tenths % 10)
+            });
+            (profile.map(str::to_string), level)
+        } else {
+            (None, None)
+        }
+    }
+
+    /// H.264 parameter set NAL units from `sprop-parameter-sets`, SPS first
+    pub fn parameter_sets(&self) -> Vec<Vec<u8>> {
+        if !self.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("H264")) {
+            return Vec::new();
+        }
+        let Some(sets) = self.fmtp_param("sprop-parameter-sets") else {
+            return Vec::new();
+        };
+        sets.split(',')
+            .filter_map(|set| base64::engine::general_purpose::STANDARD.decode(set).ok())
+            .filter(|nal| !nal.is_empty())
+            .collect()
+    }
+}
+
+/// Base URL for the control attributes of a DESCRIBE response (RFC 2326
+/// C.1.1): `Content-Base`, then `Content-Location`, then the request URL
+pub fn content_base(headers: &HashMap<String, String>, request_url: &str) -> String {
+    headers
+        .get("Content-Base")
+        .or_else(|| headers.get("Content-Location"))
+        .map(|base| base.trim().to_string())
+        .filter(|base| !base.is_empty())
+        .unwrap_or_else(|| request_url.to_string())
+}
+
+/// Resolve a control attribute against the presentation base URL.
+///
+/// Absolute URLs are returned as-is, `*` (or no attribute) means the base itself,
+/// `/path` replaces the base's path, and anything else is appended to the base
+/// as a path segment (`trackID=1` on `rtsp://cam/live` is `rtsp://cam/live/trackID=1`,
+/// which is what cameras expect even without a trailing slash).
+pub fn resolve_control(base: &str, control: Option<&str>) -> String {
+    match control.map(str::trim) {
+        None | Some("") | Some("*") => base.to_string(),
+        Some(c) if c.contains("://") => c.to_string(),
+        Some(c) if c.starts_with('/') => match url::Url::parse(base).and_then(|base| base.join(c)) {
+            Ok(url) => url.to_string(),
+            Err(_) => format!("{}{}", base.trim_end_matches('/'), c),
+        },
+        Some(c) => format!("{}/{}", base.trim_end_matches('/'), c),
+    }
+}
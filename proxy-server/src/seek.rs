@@ -0,0 +1,68 @@
+//! Seeking in managed mode. A seek while playing pauses the camera and
+//! plays again from the new position; the RTP in between belongs to the old
+//! position and is dropped here, so the browser's jitter buffer and decoder
+//! start clean at the new one.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Holds back a session's RTP while a seek is in progress
+#[derive(Debug, Default)]
+pub struct SeekGate {
+    flushing: AtomicBool,
+    /// First sequence number at the new position, by RTP channel, until a
+    /// packet at or after it arrives
+    resume_at: Mutex<HashMap<u8, u16>>,
+}
+
+impl SeekGate {
+    /// Drop every packet until [`SeekGate::resume`], or stop doing so
+    pub fn set_flushing(&self, flushing: bool) {
+        self.flushing.store(flushing, Ordering::Relaxed);
+    }
+
+    /// The camera plays from the new position; `resume_at` has the tracks'
+    /// first sequence numbers there, from the PLAY answer's RTP-Info
+    pub fn resume(&self, resume_at: HashMap<u8, u16>) {
+        *self.resume_at.lock().unwrap() = resume_at;
+        self.flushing.store(false, Ordering::Relaxed);
+    }
+
+    pub fn admit(&self, channel_id: u8, packet: &[u8]) -> bool {
+        if self.flushing.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut resume_at = self.resume_at.lock().unwrap();
+        let (Some(&first), Some(seq)) = (resume_at.get(&channel_id), packet.get(2..4)) else {
+            return true;
+        };
+        let seq = u16::from_be_bytes([seq[0], seq[1]]);
+        // Still the old position, delivered late
+        if (seq.wrapping_sub(first) as i16) < 0 {
+            return false;
+        }
+        resume_at.remove(&channel_id);
+        true
+    }
+}
+
+/// Each stream's URL and first sequence number from an RTP-Info header
+/// (RFC 2326 §12.33)
+pub fn rtp_info(value: &str) -> Vec<(String, u16)> {
+    value
+        .split(',')
+        .filter_map(|stream| {
+            let mut url = None;
+            let mut seq = None;
+            for param in stream.split(';').map(str::trim) {
+                if let Some(value) = param.strip_prefix("url=") {
+                    url = Some(value.trim_matches('"').to_string());
+                } else if let Some(value) = param.strip_prefix("seq=") {
+                    seq = value.parse().ok();
+                }
+            }
+            Some((url?, seq?))
+        })
+        .collect()
+}
@@ -0,0 +1,157 @@
+//! Self-signed certificates for deployments without a real one. Browsers
+//! accept them through WebTransport's `serverCertificateHashes` option, but
+//! only for certificates valid for at most 14 days, so the certificate is
+//! rotated on a schedule and its hash is served at `/cert-hash`.
+//!
+//! Ahead of each rotation the next certificate is made and its hash
+//! published alongside the current one, so pages that fetch the hashes
+//! shortly before the switch still connect after it.
+
+use anyhow::{ensure, Context, Result};
+use serde_json::json;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+use wtransport::tls::rustls::crypto::CryptoProvider;
+use wtransport::tls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use wtransport::tls::rustls::server::{ClientHello, ResolvesServerCert};
+use wtransport::tls::rustls::sign::CertifiedKey;
+use wtransport::tls::rustls::ServerConfig as TlsServerConfig;
+use wtransport::tls::{Sha256Digest, Sha256DigestFmt};
+use wtransport::Identity;
+use crate::config::SelfSignedConfig;
+use crate::http::Response;
+
+/// Longest validity browsers accept with `serverCertificateHashes`
+const VALIDITY_DAYS: u32 = 14;
+/// Wait before trying again when a certificate could not be made
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const SUBJECT_ALT_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// The certificate handshakes are answered with, swapped on rotation
+#[derive(Debug)]
+struct Current {
+    provider: Arc<CryptoProvider>,
+    key: RwLock<Arc<CertifiedKey>>,
+    /// The current certificate's hash, then the next one's during an overlap
+    hashes: RwLock<Vec<Sha256Digest>>,
+}
+
+static CURRENT: OnceLock<Arc<Current>> = OnceLock::new();
+
+impl ResolvesServerCert for Current {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// A new certificate for the loopback names, valid for 14 days
+pub fn generate() -> Result<Identity> {
+    let identity = Identity::self_signed_builder()
+        .subject_alt_names(SUBJECT_ALT_NAMES)
+        .from_now_utc()
+        .validity_days(VALIDITY_DAYS)
+        .build()?;
+    Ok(identity)
+}
+
+/// Answer handshakes with `identity` and its successors, rotating it in the
+/// background for the life of the process
+pub fn install(tls: &mut TlsServerConfig, identity: &Identity, config: &SelfSignedConfig) -> Result<()> {
+    let rotate_after = Duration::from_secs(config.rotate_after_days * 24 * 3600);
+    let overlap = Duration::from_secs(config.overlap_h * 3600);
+    ensure!(config.rotate_after_days > 0, "self_signed.rotate_after_days must be at least 1");
+    ensure!(overlap < rotate_after, "self_signed.overlap_h must be shorter than rotate_after_days");
+    // The next certificate is made at the start of the overlap and must last until it is rotated out
+    ensure!(
+        rotate_after + overlap <= Duration::from_secs(u64::from(VALIDITY_DAYS) * 24 * 3600),
+        "self_signed.rotate_after_days plus overlap_h must fit in the {} days browsers accept",
+        VALIDITY_DAYS
+    );
+
+    let provider = tls.crypto_provider().clone();
+    let current = Arc::new(Current {
+        key: RwLock::new(certified_key(identity, &provider)?),
+        hashes: RwLock::new(Vec::new()),
+        provider,
+    });
+    let hash = hash_of(identity);
+    log_hash("Self-signed certificate hash", &hash);
+    current.publish(vec![hash], config);
+    tls.cert_resolver = current.clone();
+    let _ = CURRENT.set(current.clone());
+    tokio::spawn(rotate(current, config.clone(), rotate_after - overlap, overlap));
+    Ok(())
+}
+
+/// Every `rotate_after`: publish the next certificate's hash `overlap`
+/// ahead, then switch to it
+async fn rotate(current: Arc<Current>, config: SelfSignedConfig, until_overlap: Duration, overlap: Duration) {
+    loop {
+        tokio::time::sleep(until_overlap).await;
+        let (key, hash) = loop {
+            match generate().and_then(|identity| Ok((certified_key(&identity, &current.provider)?, hash_of(&identity)))) {
+                Ok(next) => break next,
+                Err(e) => {
+                    warn!("Failed to make the next self-signed certificate: {:#}", e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        };
+        log_hash("Next self-signed certificate hash", &hash);
+        let mut hashes = current.hashes.read().unwrap().clone();
+        hashes.truncate(1);
+        hashes.push(hash.clone());
+        current.publish(hashes, &config);
+
+        tokio::time::sleep(overlap).await;
+        // Connections already up keep the certificate they were made with
+        *current.key.write().unwrap() = key;
+        current.publish(vec![hash], &config);
+        info!("Rotated the self-signed certificate");
+    }
+}
+
+impl Current {
+    fn publish(&self, hashes: Vec<Sha256Digest>, config: &SelfSignedConfig) {
+        if let Some(path) = &config.hash_file
+            && let Err(e) = std::fs::write(path, hashes_json(&hashes).to_string())
+        {
+            warn!("Failed to write certificate hashes to {}: {}", path.display(), e);
+        }
+        *self.hashes.write().unwrap() = hashes;
+    }
+}
+
+fn certified_key(identity: &Identity, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    let chain = identity.certificate_chain().as_slice().iter().map(|cert| CertificateDer::from(cert.der().to_vec())).collect();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(identity.private_key().secret_der().to_vec()));
+    Ok(Arc::new(CertifiedKey::from_der(chain, key, provider).context("Unusable self-signed certificate")?))
+}
+
+fn hash_of(identity: &Identity) -> Sha256Digest {
+    identity.certificate_chain().as_slice()[0].hash()
+}
+
+/// Log the hash ready to paste into the browser's `WebTransport` options
+fn log_hash(what: &str, hash: &Sha256Digest) {
+    info!(
+        "{}: serverCertificateHashes: [{{ algorithm: \"sha-256\", value: new Uint8Array({}) }}] ({})",
+        what,
+        hash.fmt(Sha256DigestFmt::BytesArray),
+        hash.fmt(Sha256DigestFmt::DottedHex)
+    );
+}
+
+/// The hashes as a `serverCertificateHashes` list, values as arrays of bytes
+fn hashes_json(hashes: &[Sha256Digest]) -> serde_json::Value {
+    hashes.iter().map(|hash| json!({ "algorithm": "sha-256", "value": hash.as_ref() })).collect()
+}
+
+/// `GET /cert-hash`: the published hashes
+pub fn handle() -> Response {
+    let Some(current) = CURRENT.get() else {
+        return Response::text("404 Not Found", "The proxy is not using a self-signed certificate");
+    };
+    Response::json("200 OK", &hashes_json(&current.hashes.read().unwrap()))
+}
@@ -1,192 +1,1239 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
 use std::time::Duration;
-use tracing::{error, info};
-use wtransport::Endpoint;
-use wtransport::Identity;
-use wtransport::ServerConfig;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{error, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+use tracing_subscriber::prelude::*;
+#[cfg(feature = "webtransport")]
+use wtransport::endpoint::endpoint_side::Server;
+#[cfg(feature = "webtransport")]
+use wtransport::{Endpoint, Identity, ServerConfig};
 use tokio::net::TcpListener;
+#[cfg(feature = "websocket")]
 use tokio_tungstenite::accept_hdr_async;
-use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "websocket")]
+use std::sync::Mutex;
 
+mod aac;
+mod adaptive;
+mod audio;
+mod auth;
+mod authorizer;
+mod bandwidth;
+mod cameras;
+mod capabilities;
+mod carousel;
+#[cfg(feature = "websocket")]
+mod cluster;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod config;
+mod control;
+mod demo;
+mod drain;
+mod drops;
+mod error;
+mod export;
+mod feedback;
+mod filter;
+mod flow;
+mod flvmux;
+mod h264;
+mod health;
+mod http;
+mod journal;
+mod keyframes;
+mod latency;
+mod limits;
+mod memory;
+mod metadata;
+mod metrics;
+mod middleware;
+mod motion;
+mod mp4;
+mod mpegts;
+#[cfg(feature = "webtransport")]
+mod mtls;
+mod mux;
+mod nat;
+mod onvif;
+mod paramsets;
+mod passthrough;
+mod preflight;
+mod probe;
+mod protocol;
 mod proxy;
+mod qos;
+#[cfg(feature = "webtransport")]
+mod quic;
+mod quirks;
+mod receive;
+mod reconfig;
+mod relay;
+mod remux;
+#[cfg(feature = "webtransport")]
+mod resumption;
+mod retransmit;
+mod rtp;
+mod rtp_header;
 mod transport; 
 mod rtsp; 
+mod rtsp_client;
+mod rtsp_server;
+mod rtsp2;
+mod sdp;
+mod seek;
+#[cfg(feature = "webtransport")]
+mod selfsigned;
+mod simple;
+mod sni;
+mod startup;
+mod stats;
+mod sync;
+mod systemd;
+mod tcp;
+mod timeshift;
+mod transcript;
+mod tsmux;
+mod udp;
+mod upstream;
+mod validate;
+mod warm;
+mod workers;
+#[cfg(feature = "websocket")]
+mod ws_deflate;
+#[cfg(feature = "transcode")]
+mod transcode;
+#[cfg(feature = "srt")]
+mod srt;
 
+#[cfg(not(any(feature = "webtransport", feature = "websocket")))]
+compile_error!("The proxy needs a browser transport: enable the `webtransport` feature, `websocket`, or both");
+
+use authorizer::AuthRequest;
+use config::{ChannelIdConfig, ChannelIdFormat, Config, RuntimeConfig, RuntimeFlavor, Tenant};
+use latency::LatencyMode;
+use limits::{RequestLimits, SessionLimits};
+use control::{ControlCodec, ControlProtocol, Event};
+use middleware::MiddlewareChain;
 use proxy::RTSPProxy;
-use transport::Transport;
+use remux::Output;
+use simple::ClientMode;
+use protocol::Framing;
+use transport::{Compression, Transport};
+#[cfg(feature = "websocket")]
+use transport::WsSocket;
+
+/// Certificate and key served on the WebTransport endpoint; a self-signed
+/// identity is generated when the certificate is missing
+const CERT_PEMFILE: &str = "./DO_NOT_USE_CERT.pem";
+const KEY_PEMFILE: &str = "./DO_NOT_USE_KEY.pem";
+/// UDP port of the WebTransport endpoint, unless socket-activated
+const WEBTRANSPORT_PORT: u16 = 4433;
+/// TCP port of the WebSocket listener, unless socket-activated
+const WEBSOCKET_PORT: u16 = 8080;
+
+#[derive(Parser, Debug)]
+#[command(about = "RTSP to browser proxy over WebTransport / WebSocket")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to a TOML configuration file
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Print the session journal configured in `--config` as JSON and exit
+    #[arg(long)]
+    dump_journal: bool,
+    /// Print the browser wire protocol as a JSON Schema or TypeScript
+    /// definitions and exit, for third-party clients
+    #[arg(long, value_name = "FORMAT")]
+    dump_protocol: Option<protocol::Format>,
+    /// Serve the browser player from this directory over plain HTTP on the
+    /// WebSocket port, e.g. `--serve-demo client`
+    #[arg(long, value_name = "DIR")]
+    serve_demo: Option<PathBuf>,
+    /// Runtime flavor, overriding `[runtime] flavor`
+    #[arg(long, value_name = "FLAVOR")]
+    runtime: Option<RuntimeFlavor>,
+    /// Worker threads of the multi-threaded runtime, overriding
+    /// `[runtime] worker_threads`
+    #[arg(long, value_name = "N")]
+    worker_threads: Option<usize>,
+}
 
-type WsStream = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Check `--config` without serving: syntax, contradicting settings,
+    /// certificates and ports. Exits non-zero if anything would fail.
+    Validate {
+        /// Also connect to each `[[warm]]` camera and DESCRIBE it
+        #[arg(long)]
+        reach_cameras: bool,
+    },
+}
+
+#[cfg(feature = "websocket")]
+type WsStream = tokio_tungstenite::WebSocketStream<WsSocket>;
 
+#[cfg(feature = "websocket")]
 enum SessionState {
-    WaitingForData(WsStream, String), // Control socket waiting, holds RTSP URL
-    WaitingForControl(WsStream),      // Data socket waiting
+    WaitingForData(WsStream, Box<ClientOptions>), // Control socket waiting, holds the client's options
+    WaitingForControl(WsStream),             // Data socket waiting
+}
+
+/// Per-connection options passed by the client as query parameters
+struct ClientOptions {
+    rtsp_url: String,
+    /// Lower-bitrate profile of the same camera (`sub`)
+    substream_url: Option<String>,
+    /// Transcoded rendition to watch (`rendition`); picked from feedback when unset or "auto"
+    rendition: Option<String>,
+    /// Media payload framing (`framing`); unknown values fall back to the default
+    framing: Framing,
+    /// Control message compression (`compress`); off unless requested
+    compression: Option<Compression>,
+    /// Delivery, retransmission and flow control as one choice (`latency`)
+    latency: Option<LatencyMode>,
+    /// Control stream format (`protocol`): raw RTSP unless "json" is requested
+    protocol: ControlProtocol,
+    /// Tenant selected by `token` or the connection path, if tenants are configured
+    tenant: Option<Tenant>,
+    /// Whether the proxy runs the RTSP exchange itself (`mode=simple` or `mode=managed`)
+    mode: ClientMode,
+    /// Cameras a carousel cycles through (`playlist`), the first being
+    /// `rtsp_url`; empty for a single camera
+    playlist: Vec<String>,
+    /// How long a carousel shows each camera (`dwell`, in seconds)
+    dwell: Duration,
+    /// RTP, or a container for players that take one over a single WebSocket (`output`)
+    output: Output,
+    /// `token` as sent, for the authorizer
+    token: Option<String>,
+    /// First identity of the client's certificate, for the authorizer
+    client_cert: Option<String>,
+    /// Time limits from the config, tightened by the authorizer
+    limits: SessionLimits,
+    /// How SETUP answers name a track's channels
+    channel_ids: ChannelIdConfig,
 }
 
+impl ClientOptions {
+    /// Parse the query and check the client against the tenant policies.
+    /// `client_identities` are those of its TLS client certificate, if any.
+    fn from_params(params: &HashMap<String, String>, path: &str, client_identities: &[String], config: &Config) -> Result<Self> {
+        if config.streams_only && ["rtsp", "sub"].iter().any(|name| params.contains_key(*name)) {
+            return Err(anyhow::anyhow!("Cameras are opened by name here; use the 'stream' query parameter"));
+        }
+        // Names of `[[stream]]`s stand for their URLs, in a playlist too
+        let resolve = |name: &str| match config.stream_named(name) {
+            Some(stream) => Ok(stream.url.clone()),
+            None if config.streams_only || !name.contains("://") => Err(anyhow::anyhow!("Unknown stream '{}'", name)),
+            None => Ok(name.to_string()),
+        };
+        let playlist: Vec<String> = match params.get("playlist") {
+            Some(list) => list.split(',').map(str::trim).filter(|url| !url.is_empty()).map(resolve).collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        let named = match params.get("stream") {
+            Some(_) if params.contains_key("rtsp") || params.contains_key("playlist") => {
+                return Err(anyhow::anyhow!("'stream' cannot be combined with 'rtsp' or 'playlist'"));
+            }
+            Some(name) => Some(config.stream_named(name).ok_or_else(|| anyhow::anyhow!("Unknown stream '{}'", name))?),
+            None => None,
+        };
+        let rtsp_url = match (playlist.first(), named) {
+            (Some(first), _) => first.clone(),
+            (None, Some(stream)) => stream.url.clone(),
+            (None, None) => params.get("rtsp").cloned().ok_or_else(|| anyhow::anyhow!("Missing 'rtsp' or 'stream' query parameter"))?,
+        };
+        if params.contains_key("playlist") {
+            if playlist.is_empty() || playlist.len() > carousel::MAX_CAMERAS {
+                return Err(anyhow::anyhow!("A playlist must name 1 to {} cameras", carousel::MAX_CAMERAS));
+            }
+            if playlist.iter().any(|url| url.starts_with("srt://")) {
+                return Err(anyhow::anyhow!("A playlist cannot include SRT sources"));
+            }
+            if params.contains_key("sub") {
+                return Err(anyhow::anyhow!("'sub' cannot be combined with 'playlist'"));
+            }
+        }
+        let output = match params.get("output") {
+            Some(name) => Output::parse(name).ok_or_else(|| anyhow::anyhow!("Unknown output '{}'", name))?,
+            None => Output::default(),
+        };
+        if output != Output::Rtp && params.contains_key("sub") {
+            return Err(anyhow::anyhow!("'sub' cannot be combined with output={}", output.as_str()));
+        }
+        if output != Output::Rtp && rtsp_url.starts_with("srt://") {
+            return Err(anyhow::anyhow!("SRT sources cannot be played with output={}", output.as_str()));
+        }
+        // A named stream's sub-stream serves RTP output only
+        let substream_url = match named {
+            Some(stream) if output == Output::Rtp => params.get("sub").or(stream.sub.as_ref()).cloned(),
+            _ => params.get("sub").cloned(),
+        };
+        if rtsp_url.starts_with("srt://") && !cfg!(feature = "srt") {
+            return Err(anyhow::anyhow!("SRT sources need the proxy built with the `srt` feature"));
+        }
+        let tenant = config.tenant_for(path, params.get("token").map(String::as_str), client_identities)?.cloned();
+        if let Some(tenant) = &tenant {
+            let mut urls = std::iter::once(&rtsp_url).chain(&playlist).chain(&substream_url);
+            if let Some(url) = urls.find(|url| !tenant.allows(url)) {
+                return Err(anyhow::anyhow!("Tenant '{}' may not open {}", tenant.name, url));
+            }
+        }
+        let limits = config.session_limits(tenant.as_ref())?;
+        let rendition = params.get("rendition").filter(|name| *name != "auto").cloned();
+        if let Some(name) = &rendition {
+            let configured = |url: &String| config.transcode_for(url).is_some_and(|rule| rule.renditions.iter().any(|r| &r.name == name));
+            if let Some(url) = std::iter::once(&rtsp_url).chain(&playlist).find(|url| !configured(url)) {
+                return Err(anyhow::anyhow!("No rendition '{}' is configured for {}", name, url));
+            }
+        }
+        let framing = match params.get("framing") {
+            Some(name) => Framing::parse(name).unwrap_or_else(|| {
+                warn!("Unknown framing '{}', using {}", name, Framing::default().as_str());
+                Framing::default()
+            }),
+            None => Framing::default(),
+        };
+        let compression = params.get("compress").and_then(|name| {
+            let compression = Compression::parse(name);
+            if compression.is_none() {
+                warn!("Unknown compression '{}', sending control messages uncompressed", name);
+            }
+            compression
+        });
+        let latency = params.get("latency").and_then(|name| {
+            let latency = LatencyMode::parse(name);
+            if latency.is_none() {
+                warn!("Unknown latency mode '{}', using the configured delivery", name);
+            }
+            latency
+        });
+        let mut protocol = match params.get("protocol") {
+            Some(name) => ControlProtocol::parse(name).unwrap_or_else(|| {
+                warn!("Unknown control protocol '{}', using {}", name, ControlProtocol::default().as_str());
+                ControlProtocol::default()
+            }),
+            None => ControlProtocol::default(),
+        };
+        let mut mode = match params.get("mode") {
+            Some(name) => ClientMode::parse(name).unwrap_or_else(|| {
+                warn!("Unknown client mode '{}', using {}", name, ClientMode::default().as_str());
+                ClientMode::default()
+            }),
+            None => ClientMode::default(),
+        };
+        // The proxy sets up each camera of a carousel itself, and the streams
+        // of players that do not speak RTSP
+        if (!playlist.is_empty() || output != Output::Rtp) && mode != ClientMode::Simple {
+            if params.contains_key("mode") {
+                warn!("Playlists and container outputs run in simple mode; ignoring mode={}", mode.as_str());
+            }
+            mode = ClientMode::Simple;
+        }
+        let dwell = match params.get("dwell") {
+            Some(seconds) => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                _ => {
+                    warn!("Invalid dwell '{}', using {} s", seconds, carousel::DEFAULT_DWELL.as_secs());
+                    carousel::DEFAULT_DWELL
+                }
+            },
+            None => carousel::DEFAULT_DWELL,
+        };
+        // Simple and managed mode's track list, commands and errors only exist as JSON messages
+        if mode != ClientMode::Rtsp {
+            protocol = ControlProtocol::Json;
+        }
+        Ok(Self {
+            rtsp_url,
+            substream_url,
+            rendition,
+            framing,
+            compression,
+            latency,
+            protocol,
+            tenant,
+            mode,
+            playlist,
+            dwell,
+            output,
+            token: params.get("token").cloned(),
+            client_cert: client_identities.first().cloned(),
+            limits,
+            channel_ids: config.channel_ids.clone(),
+        })
+    }
+
+    /// Ask the configured authorizer, if any, and keep the limits it sets
+    async fn authorize(&mut self, config: &Config, client_address: Option<SocketAddr>, origin: Option<&str>, transport: &'static str) -> Result<()> {
+        let Some(authorizer) = &config.authorizer else {
+            return Ok(());
+        };
+        // Each camera of a carousel is authorized on its own
+        let urls = if self.playlist.is_empty() { std::slice::from_ref(&self.rtsp_url) } else { &self.playlist };
+        let mut limits = self.limits;
+        for url in urls {
+            let request = AuthRequest {
+                client_address,
+                origin,
+                token: self.token.as_deref(),
+                client_cert: self.client_cert.as_deref(),
+                tenant: self.tenant.as_ref().map(|t| t.name.as_str()),
+                rtsp_url: url,
+                substream_url: self.substream_url.as_deref(),
+                transport,
+            };
+            limits = limits.min(authorizer::authorize(authorizer, &request).await?);
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// Count the session against its tenant's limit; None if the tenant is full
+    fn admit(&self) -> Option<stats::GaugeGuard> {
+        match &self.tenant {
+            Some(tenant) => stats::global().try_track(format!("sessions_active{{tenant=\"{}\"}}", tenant.name), tenant.max_sessions.map(i64::from)),
+            None => Some(stats::global().track("sessions_active".to_string())),
+        }
+    }
+
+    /// Proxy the session to completion, recording it in the journal
+    async fn run(self, transport: Transport, config: &Config) -> Result<()> {
+        if let Some(drain) = drain::current() {
+            return self.redirect(transport, drain).await;
+        }
+        let session_id = format!("{:016x}", rand::random::<u64>());
+        info!("Session {} for {}", session_id, self.rtsp_url);
+        let journal = journal::global();
+        if let Some(journal) = journal {
+            journal.session_start(&session_id, &self.rtsp_url, self.tenant.as_ref().map(|t| t.name.as_str()));
+        }
+
+        if let Some(compression) = self.compression {
+            info!("Compressing control messages with {}", compression.as_str());
+        }
+        if let Some(latency) = self.latency {
+            info!("Latency mode {}", latency.as_str());
+        }
+        let transport = transport
+            .with_framing(self.framing)
+            .with_compression(self.compression)
+            .with_stream_delivery(self.latency.is_some_and(|latency| latency.stream_delivery()));
+        let bytes_sent = transport.bytes_sent();
+
+        let result = match self.rtsp_url.starts_with("srt://") {
+            #[cfg(feature = "srt")]
+            true => {
+                let session = srt::SrtSession::new(self.rtsp_url)
+                    .with_protocol(self.protocol)
+                    .with_limits(self.limits)
+                    .with_channel_ids(self.channel_ids.clone());
+                session.handle_connection(transport).await
+            }
+            _ if self.playlist.len() > 1 => {
+                let control = ControlCodec::new(self.protocol);
+                let proxy = |url: &str, limits| self.proxy(url, limits, config, &session_id);
+                carousel::run(&self.playlist, self.dwell, transport, control, self.limits, proxy).await.map_err(anyhow::Error::from)
+            }
+            _ => self.proxy(&self.rtsp_url, self.limits, config, &session_id).handle_connection(transport).await.map_err(anyhow::Error::from),
+        };
+        if let Err(e) = &result {
+            let kind = error::Error::find(e).map_or("internal", error::Error::kind);
+            stats::global().add(&format!("sessions_failed{{kind=\"{}\"}}", kind), 1);
+        }
+        if let Some(journal) = journal {
+            let error = result.as_ref().err().map(|e| format!("{:#}", e));
+            journal.session_stop(&session_id, bytes_sent.load(std::sync::atomic::Ordering::Relaxed), error);
+        }
+        result
+    }
+
+    /// Tell the browser to retry elsewhere instead of running the session
+    async fn redirect(self, transport: Transport, drain: drain::Drain) -> Result<()> {
+        info!("Draining: turning away a session for {}", self.rtsp_url);
+        let transport = transport.with_compression(self.compression);
+        let event = ControlCodec::new(self.protocol).event(&Event::Draining { alternate: drain.alternate });
+        transport.write_control(&event).await?;
+        // Closing at once could discard the event before it is delivered
+        let _ = tokio::time::timeout(REDIRECT_GRACE, transport.closed()).await;
+        Ok(())
+    }
+
+    /// RTSP proxy for the session's camera at `url`, or one of a carousel's
+    fn proxy(&self, url: &str, limits: SessionLimits, config: &Config, session_id: &str) -> RTSPProxy {
+        // Transcoded video is sent as RTP, so container outputs remux the camera's own
+        let transcode = config.transcode_for(url).filter(|_| self.output == Output::Rtp).cloned();
+        let transcode_fallback = config.transcode_fallback_for(url).filter(|_| self.output == Output::Rtp).cloned();
+        // A camera whose health fell back recently is transcoded from the start
+        let transcode = transcode.or_else(|| transcode_fallback.clone().filter(|_| health::falling_back(url)));
+        let quirks = config.quirks_for(url).cloned();
+        let onvif = config.onvif_for(url).cloned();
+        let transcript = config.transcript.as_ref().and_then(|t| transcript::Transcript::open(t, session_id, url));
+        RTSPProxy::new(url.to_string())
+            .with_session_id(session_id.to_string())
+            .with_stream(config.stream_for(url).cloned())
+            .with_substream(self.substream_url.clone())
+            .with_transcode(transcode)
+            .with_transcode_fallback(transcode_fallback)
+            .with_rendition(self.rendition.clone())
+            .with_rtp(config.rtp)
+            .with_tenant(self.tenant.clone())
+            .with_middleware(MiddlewareChain::from_config(&config.middleware))
+            .with_validation(config.validation.clone())
+            .with_quirks(quirks)
+            .with_upstream(config.upstream.clone())
+            .with_protocol(self.protocol)
+            .with_transcript(transcript)
+            .with_audio_levels(config.audio_levels.clone())
+            .with_motion(config.motion.clone())
+            .with_health(config.health.clone())
+            .with_metadata(config.metadata.clone())
+            .with_onvif(onvif)
+            .with_keyframes(config.keyframes_for(url).cloned())
+            .with_timeshift(config.timeshift.clone())
+            .with_flow_control(self.latency.map_or_else(|| config.flow_control.clone(), |latency| latency.flow_control(&config.flow_control)))
+            .with_latency(self.latency)
+            .with_parameter_sets(config.parameter_sets.clone())
+            .with_channel_ids(self.channel_ids.clone())
+            .with_mode(self.mode)
+            .with_output(self.output)
+            .with_limits(limits)
+            .with_request_limits(RequestLimits::from_config(&config.session))
+    }
+}
+
+#[cfg(feature = "websocket")]
 type SessionRegistry = Arc<Mutex<HashMap<String, SessionState>>>;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+type LogFilter = reload::Handle<EnvFilter, Registry>;
 
-    let cert_pemfile = "./DO_NOT_USE_CERT.pem";
-    let private_key_pemfile = "./DO_NOT_USE_KEY.pem";
-    
-    // Check if certs exist, otherwise generate self-signed (for dev)
-    let identity = if std::path::Path::new(cert_pemfile).exists() {
-        Identity::load_pemfiles(cert_pemfile, private_key_pemfile)
-            .await
-            .unwrap()
-    } else {
-        info!("Certificates not found, using self-signed identity");
-        Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap()
+/// Asks for the config file to be re-read, answered with the outcome
+type ReloadRequest = oneshot::Sender<Result<()>>;
+
+/// Where [`request_reload`] sends, once there is a config file to reload
+static RELOADS: OnceLock<mpsc::UnboundedSender<ReloadRequest>> = OnceLock::new();
+
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a turned-away browser has to read the draining event
+const REDIRECT_GRACE: Duration = Duration::from_secs(2);
+/// How long a `[wss]` client has to finish its TLS handshake, and then to
+/// send its request
+#[cfg(feature = "websocket")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() -> Result<()> {
+    // Initialize logging; the filter can be swapped when the config is reloaded
+    let (filter, log_filter) = reload::Layer::new(env_log_filter());
+    tracing_subscriber::registry().with(filter).with(tracing_subscriber::fmt::layer()).init();
+
+    let cli = Cli::parse();
+    if let Some(format) = cli.dump_protocol {
+        print!("{}", protocol::dump(format));
+        return Ok(());
+    }
+    if let Some(Command::Validate { reach_cameras }) = cli.command {
+        let path = cli.config.as_deref().ok_or_else(|| anyhow::anyhow!("validate needs --config"))?;
+        return build_runtime(&RuntimeConfig { flavor: RuntimeFlavor::CurrentThread, ..Default::default() })?
+            .block_on(preflight::run(path, reach_cameras));
+    }
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if cli.dump_journal {
+        let journal = config.journal.as_ref().ok_or_else(|| anyhow::anyhow!("No [journal] configured"))?;
+        return journal::dump(&journal.path);
+    }
+    if let Some(flavor) = cli.runtime {
+        config.runtime.flavor = flavor;
+    }
+    if let Some(threads) = cli.worker_threads {
+        config.runtime.worker_threads = Some(threads);
+    }
+    build_runtime(&config.runtime)?.block_on(serve(cli, config, log_filter))
+}
+
+/// The main runtime, which the listeners, control loops and (without
+/// `[forwarders]`) media forwarders run on. Read at startup only.
+fn build_runtime(config: &RuntimeConfig) -> Result<tokio::runtime::Runtime> {
+    let mut builder = match config.flavor {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(threads) = config.worker_threads {
+                builder.worker_threads(threads.max(1));
+            }
+            builder
+        }
     };
+    if let Some(name) = &config.thread_name {
+        builder.thread_name(name);
+    }
+    builder.enable_all().build().context("Failed to start the runtime")
+}
+
+async fn serve(cli: Cli, config: Config, log_filter: LogFilter) -> Result<()> {
+    info!("Running on a {:?} runtime", config.runtime.flavor);
+    config.warn_unsupported();
+    config.warn_any_origin();
+    apply_log_level(&log_filter, &config);
+    if let Some(journal) = &config.journal {
+        journal::init(journal)?;
+    }
+    if let Some(drop_log) = &config.drop_log {
+        drops::init(drop_log)?;
+    }
+    if let Some(memory) = &config.memory {
+        memory::init(memory);
+    }
+    if let Some(qos) = &config.qos {
+        qos::init(qos);
+    }
+    if let Some(forwarders) = &config.forwarders {
+        workers::init(forwarders)?;
+    }
+    if let Some(udp_mux) = &config.udp_mux {
+        mux::init(udp_mux).await?;
+    }
+    #[cfg(feature = "webtransport")]
+    if let Some(edge) = config.edge.clone() {
+        tokio::spawn(relay::run_edge(edge));
+    }
+    warm::init(&config);
 
-    let config = ServerConfig::builder()
-        .with_bind_default(4433)
-        .with_identity(identity)
-        .keep_alive_interval(Some(Duration::from_secs(3)))
-        .build();
+    // New sessions pick up the latest config; running ones keep their snapshot
+    let (config_tx, config) = watch::channel(Arc::new(config));
+    if let Some(path) = cli.config.clone() {
+        let (reloads, requests) = mpsc::unbounded_channel();
+        let _ = RELOADS.set(reloads.clone());
+        tokio::spawn(serve_reloads(path, config_tx, log_filter, requests));
+        tokio::spawn(reload_on_sighup(reloads));
+    }
 
-    let wt_server = Endpoint::server(config)?;
-    info!("WebTransport Server ready on port 4433");
-    
-    // WebSocket Server
-    let ws_listener = TcpListener::bind("0.0.0.0:8080").await?;
-    info!("WebSocket Server ready on port 8080");
+    // Listeners passed by systemd socket activation take precedence over binding
+    let activated = systemd::take_listeners();
+
+    #[cfg(feature = "webtransport")]
+    {
+        let wt_server = webtransport_endpoint(activated.webtransport, &config.borrow()).await?;
+        info!("WebTransport Server ready on {}", wt_server.local_addr()?);
+        tokio::spawn(accept_webtransport(wt_server, config.clone()));
+    }
+    #[cfg(not(feature = "webtransport"))]
+    if activated.webtransport.is_some() {
+        warn!("Ignoring the socket-activated WebTransport socket: built without the `webtransport` feature");
+    }
+    // Without WebTransport, [wss] starts from the certificate files alone
+    #[cfg(all(feature = "websocket", not(feature = "webtransport")))]
+    if config.borrow().wss.is_some() {
+        let mut tls = sni::default_tls_config(std::path::Path::new(CERT_PEMFILE), std::path::Path::new(KEY_PEMFILE))?;
+        sni::install(&mut tls, &config.borrow().certificate)?;
+    }
+
+    // WebSocket Server, which plain HTTP shares
+    let ws_listener = match activated.websocket {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(("0.0.0.0", WEBSOCKET_PORT)).await?,
+    };
+    if cfg!(feature = "websocket") {
+        info!("WebSocket Server ready on {}", ws_listener.local_addr()?);
+    } else {
+        info!("HTTP Server ready on {}", ws_listener.local_addr()?);
+    }
+    if let Some(dir) = &cli.serve_demo {
+        info!("Serving the demo player from {} on http://{}/", dir.display(), ws_listener.local_addr()?);
+    }
+    let demo_dir = cli.serve_demo.map(Arc::new);
 
+    #[cfg(feature = "websocket")]
     let session_registry: SessionRegistry = Arc::new(Mutex::new(HashMap::new()));
 
+    // WebSocket over TLS, with the WebTransport certificates; bound at startup only
+    #[cfg(feature = "websocket")]
+    if let Some(wss) = &config.borrow().wss {
+        let listener = TcpListener::bind((wss.address, wss.port)).await?;
+        info!("Secure WebSocket Server ready on {}", listener.local_addr()?);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(sni::websocket_tls_config()?));
+        tokio::spawn(accept_wss(listener, acceptor, session_registry.clone(), config.clone()));
+    }
+    #[cfg(feature = "websocket")]
+    if config.borrow().cluster.is_some() && config.borrow().wss.is_none() {
+        warn!("[cluster] is set without [wss]; peers reach each other on the [wss] port, so none can reach this instance");
+    }
+    // Plain TCP for native clients; bound at startup only
+    let tcp_listener = match &config.borrow().tcp {
+        Some(tcp) => {
+            let listener = TcpListener::bind((tcp.address, tcp.port)).await?;
+            info!("TCP Server ready on {}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+    // RTSP for consumers such as a VMS or ffmpeg; bound at startup only
+    let rtsp_listener = match &config.borrow().rtsp_server {
+        Some(rtsp) => {
+            let listener = TcpListener::bind((rtsp.address, rtsp.port)).await?;
+            info!("RTSP Server ready on {}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+
+    tokio::spawn(log_stats());
+
     loop {
         tokio::select! {
-             // WebTransport
-            incoming_session = wt_server.accept() => {
-                tokio::spawn(async move {
-                    if let Err(e) = handle_wt_connection(incoming_session).await {
-                         error!("WebTransport connection error: {:?}", e);
-                    }
-                });
-            }
             // WebSocket
             Ok((stream, _addr)) = ws_listener.accept() => {
+                #[cfg(feature = "websocket")]
                 let registry = session_registry.clone();
+                let config = config.borrow().clone();
+                let demo_dir = demo_dir.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_ws_connection(stream, registry).await {
+                    // Without WebSocket, everything on the port is plain HTTP
+                    #[cfg(not(feature = "websocket"))]
+                    if let Err(e) = http::handle(stream, &config, demo_dir.as_deref().map(PathBuf::as_path)).await {
+                        warn!("HTTP request failed: {:#}", e);
+                    }
+                    // Plain HTTP (probe endpoint, demo files) shares the port
+                    #[cfg(feature = "websocket")]
+                    match http::is_plain_http(&stream).await {
+                        Ok(true) => {
+                            if let Err(e) = http::handle(stream, &config, demo_dir.as_deref().map(PathBuf::as_path)).await {
+                                warn!("HTTP request failed: {:#}", e);
+                            }
+                            return;
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            warn!("Dropping connection: {:#}", e);
+                            return;
+                        }
+                    }
+                    #[cfg(feature = "websocket")]
+                    if let Err(e) = handle_ws_connection(WsSocket::plain(stream), registry, config).await {
                          error!("WebSocket connection error: {:?}", e);
                     }
                 });
             }
+            // Plain TCP
+            Ok((stream, addr)) = async {
+                match &tcp_listener {
+                    Some(listener) => listener.accept().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let config = config.borrow().clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_tcp_connection(stream, addr, config).await {
+                         error!("TCP connection error: {:?}", e);
+                    }
+                });
+            }
+            // RTSP
+            Ok((stream, addr)) = async {
+                match &rtsp_listener {
+                    Some(listener) => listener.accept().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let config = config.borrow().clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_rtsp_connection(stream, addr, config).await {
+                         error!("RTSP connection error: {:?}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// The WebTransport endpoint, whose TLS config `[wss]` shares
+#[cfg(feature = "webtransport")]
+async fn webtransport_endpoint(socket: Option<std::net::UdpSocket>, config: &Config) -> Result<Endpoint<Server>> {
+    // Check if certs exist, otherwise generate self-signed (for dev)
+    let self_signed = !std::path::Path::new(CERT_PEMFILE).exists();
+    let identity = if self_signed {
+        info!("Certificates not found, using self-signed identity");
+        selfsigned::generate()?
+    } else {
+        Identity::load_pemfiles(CERT_PEMFILE, KEY_PEMFILE)
+            .await
+            .unwrap()
+    };
+
+    let wt_builder = match socket {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            ServerConfig::builder().with_bind_socket(socket)
         }
+        None => ServerConfig::builder().with_bind_default(WEBTRANSPORT_PORT),
+    };
+    let quic = quic::transport_config(&config.quic)?;
+    let mut tls = mtls::tls_config(identity.clone_identity(), config.mtls.as_ref())?;
+    resumption::configure(&mut tls, &config.resumption)?;
+    if self_signed {
+        selfsigned::install(&mut tls, &identity, &config.self_signed)?;
+    }
+    sni::install(&mut tls, &config.certificate)?;
+    let wt_config = wt_builder.with_custom_tls_and_transport(tls, quic).build();
+    Ok(Endpoint::server(wt_config)?)
+}
+
+#[cfg(feature = "webtransport")]
+async fn accept_webtransport(server: Endpoint<Server>, config: watch::Receiver<Arc<Config>>) {
+    loop {
+        let incoming_session = server.accept().await;
+        let config = config.borrow().clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_wt_connection(incoming_session, config).await {
+                 error!("WebTransport connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "websocket")]
+async fn accept_wss(listener: TcpListener, acceptor: tokio_rustls::TlsAcceptor, registry: SessionRegistry, config: watch::Receiver<Arc<Config>>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let acceptor = acceptor.clone();
+        let registry = registry.clone();
+        let config = config.borrow().clone();
+        tokio::spawn(async move {
+            let mut stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("WebSocket TLS handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("WebSocket TLS handshake timed out");
+                    return;
+                }
+            };
+            // Peers' cluster lookups share the port with the handshakes
+            let (request, read) = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, http::Request::read_raw(&mut stream)).await {
+                Ok(Ok(head)) => head,
+                Ok(Err(e)) => {
+                    warn!("Dropping WebSocket TLS connection: {:#}", e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("WebSocket TLS connection sent no request in time");
+                    return;
+                }
+            };
+            if !request.is_websocket_handshake() {
+                if let Err(e) = http::handle_tls(stream, &request, &config).await {
+                    warn!("HTTP request failed: {:#}", e);
+                }
+                return;
+            }
+            if let Err(e) = handle_ws_connection(WsSocket::tls(stream, read.into()), registry, config).await {
+                 error!("WebSocket connection error: {:?}", e);
+            }
+        });
     }
 }
 
-async fn handle_wt_connection(incoming_session: wtransport::endpoint::IncomingSession) -> Result<()> {
+#[cfg(feature = "webtransport")]
+async fn handle_wt_connection(incoming_session: wtransport::endpoint::IncomingSession, config: Arc<Config>) -> Result<()> {
     info!("Waiting for WebTransport session request...");
     let session_request = incoming_session.await?;
+    if session_request.path() == relay::RELAY_PATH {
+        return relay::accept_edge(session_request, &config).await;
+    }
 
-    let path = session_request.path();
+    if !config.session_origin_allowed(session_request.origin()) {
+        warn!("Rejecting WebTransport session from origin {:?}", session_request.origin());
+        session_request.forbidden().await;
+        return Ok(());
+    }
+
+    let path = session_request.path().to_string();
+    let origin = session_request.origin().map(str::to_string);
+    let remote_address = session_request.remote_address();
     let url = url::Url::parse(&format!("https://localhost{}", path)).unwrap_or_else(|_| url::Url::parse("https://localhost/").unwrap());
-    
-    let rtsp_url = extract_rtsp_url(&url)?;
-    info!("Client requested RTSP URL: {}", rtsp_url);
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    // The client's certificate is only exposed once the session is accepted
+    let session = match config.mtls {
+        Some(_) => WtSession::Accepted(session_request.accept().await?),
+        None => WtSession::Requested(Box::new(session_request)),
+    };
+    let client_identities = match &session {
+        WtSession::Accepted(connection) => mtls::client_identities(connection.peer_identity().as_ref()),
+        WtSession::Requested(_) => Vec::new(),
+    };
+
+    let mut options = match ClientOptions::from_params(&params, url.path(), &client_identities, &config) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Rejecting WebTransport session: {:#}", e);
+            session.forbidden().await;
+            return Ok(());
+        }
+    };
+    if options.output != Output::Rtp {
+        warn!("Rejecting WebTransport session: output={} is served over WebSocket only", options.output.as_str());
+        session.forbidden().await;
+        return Ok(());
+    }
+    let authorized = options.authorize(&config, Some(remote_address), origin.as_deref(), "webtransport").await;
+    if let Err(e) = authorized {
+        warn!("Rejecting WebTransport session: {:#}", e);
+        session.forbidden().await;
+        return Ok(());
+    }
+    info!("Client requested RTSP URL: {}", options.rtsp_url);
 
-    let connection = session_request.accept().await?;
+    let Some(_session) = options.admit() else {
+        warn!("Rejecting WebTransport session: tenant session limit reached");
+        session.too_many_requests().await;
+        return Ok(());
+    };
+    let connection = session.accept().await?;
     
     // Accept the bi-stream for control immediately to form the Transport
     let (send, recv) = connection.accept_bi().await?;
     
     let transport = Transport::new_wt(std::sync::Arc::new(connection), send, recv);
-    let proxy = RTSPProxy::new(rtsp_url);
-    
-    proxy.handle_connection(transport).await?;
-    
-    Ok(())
+    options.run(transport, &config).await
+}
+
+/// A WebTransport session that is accepted early when mTLS is on. Once
+/// accepted it can no longer be answered with an HTTP status, so it is
+/// closed with the status as the error code instead.
+#[cfg(feature = "webtransport")]
+enum WtSession {
+    Requested(Box<wtransport::endpoint::SessionRequest>),
+    Accepted(wtransport::Connection),
+}
+
+#[cfg(feature = "webtransport")]
+impl WtSession {
+    async fn forbidden(self) {
+        match self {
+            Self::Requested(request) => request.forbidden().await,
+            Self::Accepted(connection) => connection.close(wtransport::VarInt::from_u32(403), b"Forbidden"),
+        }
+    }
+
+    async fn too_many_requests(self) {
+        match self {
+            Self::Requested(request) => request.too_many_requests().await,
+            Self::Accepted(connection) => connection.close(wtransport::VarInt::from_u32(429), b"Too Many Requests"),
+        }
+    }
+
+    async fn accept(self) -> Result<wtransport::Connection> {
+        match self {
+            Self::Requested(request) => Ok(request.accept().await?),
+            Self::Accepted(connection) => Ok(connection),
+        }
+    }
+}
+
+async fn handle_tcp_connection(stream: tokio::net::TcpStream, client_address: SocketAddr, config: Arc<Config>) -> Result<()> {
+    let (target, link) = tcp::accept(stream).await?;
+    run_tcp_link(&target, link, client_address, config).await
+}
+
+async fn handle_rtsp_connection(stream: tokio::net::TcpStream, client_address: SocketAddr, config: Arc<Config>) -> Result<()> {
+    let Some(rtsp) = &config.rtsp_server else {
+        return Ok(());
+    };
+    match rtsp_server::accept(stream, rtsp).await? {
+        Some((target, link)) => run_tcp_link(&target, link, client_address, config).await,
+        None => Ok(()),
+    }
+}
+
+/// Run the session a TCP or RTSP client asked for with `target`
+async fn run_tcp_link(target: &str, link: tcp::TcpLink, client_address: SocketAddr, config: Arc<Config>) -> Result<()> {
+    let name = if link.is_rtsp() { "RTSP" } else { "TCP" };
+    let url = url::Url::parse(&format!("tcp://localhost{}", target)).context("Invalid OPEN target")?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let mut options = match ClientOptions::from_params(&params, url.path(), &[], &config) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Rejecting {} session: {:#}", name, e);
+            link.reject(&format!("{:#}", e)).await;
+            return Ok(());
+        }
+    };
+    if options.output != Output::Rtp {
+        warn!("Rejecting {} session: output={} is served over WebSocket only", name, options.output.as_str());
+        link.reject("Container outputs are served over WebSocket only").await;
+        return Ok(());
+    }
+    // RTSP clients find their channels where RFC 2326 puts them
+    if link.is_rtsp() {
+        options.channel_ids.format = ChannelIdFormat::Interleaved;
+    }
+    let transport_name = if link.is_rtsp() { "rtsp" } else { "tcp" };
+    if let Err(e) = options.authorize(&config, Some(client_address), None, transport_name).await {
+        warn!("Rejecting {} session: {:#}", name, e);
+        link.reject("Not authorized").await;
+        return Ok(());
+    }
+    info!("Client requested RTSP URL: {}", options.rtsp_url);
+
+    let Some(_session) = options.admit() else {
+        warn!("Rejecting {} session: tenant session limit reached", name);
+        link.reject("Tenant session limit reached").await;
+        return Ok(());
+    };
+    link.open().await?;
+    options.run(Transport::new_tcp(link), &config).await
 }
 
-async fn handle_ws_connection(stream: tokio::net::TcpStream, registry: SessionRegistry) -> Result<()> {
+// The handshake callback's error type is tungstenite's
+#[cfg(feature = "websocket")]
+#[allow(clippy::result_large_err)]
+async fn handle_ws_connection(stream: WsSocket, registry: SessionRegistry, config: Arc<Config>) -> Result<()> {
     // Shared state to extract query parameters from the handshake callback
     let query_params = Arc::new(Mutex::new(None));
     let query_params_clone = query_params.clone();
+    let origins = config.clone();
+    let client_address = stream.peer_addr().ok();
+    // Peers only relay over TLS, so the secret is never taken from a plain connection
+    let encrypted = stream.encrypted();
 
-    let ws_stream = accept_hdr_async(stream, move |req: &Request, response: Response| {
+    let mut ws_stream = accept_hdr_async(stream, move |req: &Request, mut response: Response| {
+        let origin = req.headers().get("Origin").and_then(|o| o.to_str().ok());
+        if !origins.session_origin_allowed(origin) {
+            warn!("Rejecting WebSocket handshake from origin {:?}", origin);
+            let mut forbidden = ErrorResponse::new(Some("Origin not allowed".to_string()));
+            *forbidden.status_mut() = StatusCode::FORBIDDEN;
+            return Err(forbidden);
+        }
         let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let relayed = encrypted && cluster::is_relayed(&origins, req.headers().get(cluster::RELAY_HEADER).and_then(|v| v.to_str().ok()));
         if let Ok(url) = url::Url::parse(&format!("http://localhost{}", path)) {
             let mut params = HashMap::new();
             for (key, value) in url.query_pairs() {
                 params.insert(key.into_owned(), value.into_owned());
             }
-            *query_params_clone.lock().unwrap() = Some(params);
+            // Only a control socket that asked for compression takes permessage-deflate
+            let control = params.get("type").is_none_or(|t| t == "control") && params.get("output").is_none_or(|o| o == "rtp");
+            let offers = req.headers().get(SEC_WEBSOCKET_EXTENSIONS).and_then(|v| v.to_str().ok());
+            let deflate = control && params.contains_key("compress") && offers.is_some_and(ws_deflate::accepts);
+            if deflate {
+                response.headers_mut().insert(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static(ws_deflate::RESPONSE));
+            } else if control && params.contains_key("compress") {
+                warn!("Client asked for compression without a usable permessage-deflate offer; control messages go uncompressed");
+            }
+            *query_params_clone.lock().unwrap() =
+                Some((url.path().to_string(), path.to_string(), params, origin.map(str::to_string), relayed, deflate));
         }
-        Ok::<_, tokio_tungstenite::tungstenite::handshake::server::ErrorResponse>(response)
+        Ok::<_, ErrorResponse>(response)
     }).await?;
     
-    let params = {
+    let (path, target, params, origin, relayed, deflate) = {
         let locked = query_params.lock().unwrap();
         locked.clone().ok_or_else(|| anyhow::anyhow!("Missing query parameters"))?
     };
+    if deflate {
+        ws_stream.get_mut().inflate_messages();
+    }
+
+    // Players of a container output open one socket, which only takes media
+    if params.get("output").is_some_and(|output| output != "rtp") {
+        let mut options = ClientOptions::from_params(&params, &path, &[], &config)?;
+        options.authorize(&config, client_address, origin.as_deref(), "websocket").await?;
+        info!("WebSocket {} player for {}", options.output.as_str(), options.rtsp_url);
+        let _session = options.admit().ok_or_else(|| anyhow::anyhow!("Tenant session limit reached"))?;
+        return options.run(Transport::new_ws_player(ws_stream), &config).await;
+    }
 
     let session_id = params.get("session_id").cloned().ok_or_else(|| anyhow::anyhow!("Missing 'session_id'"))?;
     let conn_type = params.get("type").map(|s| s.as_str()).unwrap_or("control"); // default to control for backward compat?
     
     info!("WebSocket connection: type={}, session_id={}", conn_type, session_id);
 
+    // Control connection MUST have 'rtsp' param
+    let options = if conn_type == "data" {
+        None
+    } else {
+        let mut options = ClientOptions::from_params(&params, &path, &[], &config)?;
+        options.authorize(&config, client_address, origin.as_deref(), "websocket").await?;
+        Some(options)
+    };
+
     let maybe_pair = {
         let mut reg = registry.lock().unwrap();
         
         if conn_type == "data" {
             // I am Data. Check if Control is waiting.
             match reg.remove(&session_id) {
-                Some(SessionState::WaitingForData(control_socket, rtsp_url)) => {
+                Some(SessionState::WaitingForData(control_socket, options)) => {
                     info!("Paired with waiting Control connection for session {}", session_id);
-                    Some((control_socket, ws_stream, rtsp_url))
+                    cluster::control_paired(&session_id);
+                    Some((control_socket, ws_stream, *options))
                 }
                 Some(SessionState::WaitingForControl(_)) => {
                     return Err(anyhow::anyhow!("Duplicate Data connection for session {}", session_id));
                 }
                 None => {
                     info!("Data connection waiting for Control for session {}", session_id);
-                    reg.insert(session_id, SessionState::WaitingForControl(ws_stream));
+                    reg.insert(session_id.clone(), SessionState::WaitingForControl(ws_stream));
                     None
                 }
             }
         } else {
             // I am Control. Check if Data is waiting.
-            // Control connection MUST have 'rtsp' param
-            let rtsp_url = params.get("rtsp").cloned().ok_or_else(|| anyhow::anyhow!("Missing 'rtsp' query parameter for control connection"))?;
-            
+            let options = options.expect("parsed for control connections");
+
             match reg.remove(&session_id) {
                 Some(SessionState::WaitingForControl(data_socket)) => {
                     info!("Paired with waiting Data connection for session {}", session_id);
-                    Some((ws_stream, data_socket, rtsp_url))
+                    Some((ws_stream, data_socket, options))
                 }
-                Some(SessionState::WaitingForData(_, _)) => {
+                Some(SessionState::WaitingForData(..)) => {
                     return Err(anyhow::anyhow!("Duplicate Control connection for session {}", session_id));
                 }
                 None => {
                     info!("Control connection waiting for Data for session {}", session_id);
-                    reg.insert(session_id, SessionState::WaitingForData(ws_stream, rtsp_url));
+                    cluster::control_waiting(&session_id);
+                    reg.insert(session_id.clone(), SessionState::WaitingForData(ws_stream, Box::new(options)));
                     None
                 }
             }
         }
     };
 
-    if let Some((control_sock, data_sock, rtsp_url)) = maybe_pair {
+    if let Some((control_sock, data_sock, options)) = maybe_pair {
+        let _session = options.admit().ok_or_else(|| anyhow::anyhow!("Tenant session limit reached"))?;
         let transport = Transport::new_ws(control_sock, data_sock);
-        let proxy = RTSPProxy::new(rtsp_url);
-        
-        proxy.handle_connection(transport).await?;
+        options.run(transport, &config).await?;
+        return Ok(());
+    }
+
+    // In a cluster, a waiting data connection's control connection may be on
+    // a peer; connections a peer relayed here never go looking again
+    if conn_type == "data" && !relayed && let Some(cluster) = &config.cluster {
+        let waiting_here = || matches!(registry.lock().unwrap().get(&session_id), Some(SessionState::WaitingForControl(_)));
+        if let Some(peer) = cluster::find_control(cluster, &session_id, waiting_here).await {
+            let data_sock = match registry.lock().unwrap().remove(&session_id) {
+                Some(SessionState::WaitingForControl(data_sock)) => data_sock,
+                // Paired here after all
+                _ => return Ok(()),
+            };
+            cluster::relay(data_sock, cluster, &peer, &target, origin.as_deref()).await?;
+        }
     }
     Ok(())
 }
 
-fn extract_rtsp_url(url: &url::Url) -> Result<String> {
-    for (key, value) in url.query_pairs() {
-        if key == "rtsp" {
-            return Ok(value.to_string());
+/// Re-read the config file whenever asked, keeping the old config if the new one is invalid
+async fn serve_reloads(path: PathBuf, config: watch::Sender<Arc<Config>>, log_filter: LogFilter, mut requests: mpsc::UnboundedReceiver<ReloadRequest>) {
+    while let Some(answer) = requests.recv().await {
+        systemd::notify("RELOADING=1");
+        let result = Config::load(&path).map(|new| {
+            new.warn_unsupported();
+            new.warn_any_origin();
+            apply_log_level(&log_filter, &new);
+            sni::reload(&new.certificate);
+            config.send_replace(Arc::new(new));
+            info!("Reloaded config from {}", path.display());
+        });
+        if let Err(e) = &result {
+            error!("Config reload failed, keeping the current config: {:#}", e);
+        }
+        systemd::notify("READY=1");
+        let _ = answer.send(result);
+    }
+}
+
+/// Reload the config as on SIGHUP and wait for the outcome; for `POST /reload`
+async fn request_reload() -> Result<()> {
+    let reloads = RELOADS.get().context("The proxy was started without a config file")?;
+    let (answer, outcome) = oneshot::channel();
+    reloads.send(answer).map_err(|_| anyhow::anyhow!("Config reloads have stopped"))?;
+    outcome.await?
+}
+
+/// Ask for a reload on every SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(reloads: mpsc::UnboundedSender<ReloadRequest>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Cannot listen for SIGHUP, reloading on SIGHUP disabled: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        // Nobody waits for the outcome, which is logged
+        let (answer, _) = oneshot::channel();
+        if reloads.send(answer).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_sighup(_reloads: mpsc::UnboundedSender<ReloadRequest>) {}
+
+/// Use the config's log filter, or `RUST_LOG` when it has none
+fn apply_log_level(log_filter: &LogFilter, config: &Config) {
+    let filter = match &config.log_level {
+        Some(level) => match EnvFilter::try_new(level) {
+            Ok(filter) => filter,
+            Err(e) => {
+                warn!("Invalid log_level '{}': {}", level, e);
+                return;
+            }
+        },
+        None => env_log_filter(),
+    };
+    if let Err(e) = log_filter.reload(filter) {
+        warn!("Failed to apply log level: {}", e);
+    }
+}
+
+/// `RUST_LOG`, defaulting to info level
+fn env_log_filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// Periodically log the process-wide gauges
+async fn log_stats() {
+    let mut interval = tokio::time::interval(STATS_LOG_INTERVAL);
+    loop {
+        interval.tick().await;
+        let snapshot = stats::global().snapshot();
+        if snapshot.values().any(|v| *v != 0) {
+            let line: Vec<String> = snapshot.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            info!("Stats: {}", line.join(" "));
         }
     }
-    Err(anyhow::anyhow!("Missing 'rtsp' query parameter"))
 }
@@ -0,0 +1,387 @@
+//! Simple client mode (`mode=simple`): the proxy runs DESCRIBE, SETUP and
+//! PLAY itself, and the browser only receives media and a JSON track list.
+//!
+//! Managed mode (`mode=managed`) goes further: the proxy stops after
+//! DESCRIBE and the browser drives playback with JSON commands (play,
+//! pause, seek, select_track), which the proxy turns into RTSP.
+
+use serde_json::{json, Value};
+use crate::control::Command;
+use crate::metadata::MetadataFormat;
+use crate::protocol::ServerMessage;
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::sdp::{self, MediaDescription, SessionDescription};
+
+/// Who runs the RTSP exchange with the camera
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientMode {
+    /// The browser's RTSP is relayed
+    #[default]
+    Rtsp,
+    /// The proxy sets up and plays every track by itself
+    Simple,
+    /// The proxy sets up and plays tracks when the browser's commands ask
+    Managed,
+}
+
+impl ClientMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rtsp" => Some(ClientMode::Rtsp),
+            "simple" => Some(ClientMode::Simple),
+            "managed" => Some(ClientMode::Managed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientMode::Rtsp => "rtsp",
+            ClientMode::Simple => "simple",
+            ClientMode::Managed => "managed",
+        }
+    }
+}
+
+/// What the session should do after a camera response or a command
+#[derive(Debug)]
+pub enum Step {
+    /// Not an answer to one of the driver's requests
+    Ignore,
+    /// Send the next request of the exchange
+    Send(RtspRequest),
+    /// Media is playing; tell the browser about the tracks
+    Ready(ServerMessage),
+    /// Tell the browser and carry on, e.g. a managed-mode state change
+    Message(ServerMessage),
+    /// Managed mode: the camera plays from the position a seek asked for
+    Seeked(f64),
+    /// The exchange failed; tell the browser and end the session
+    Failed(String),
+}
+
+/// Where the exchange is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// DESCRIBE, the SETUPs and the first PLAY
+    Starting,
+    /// Managed mode: described, waiting for the first play
+    Described,
+    Playing,
+    Paused,
+}
+
+/// Drives the RTSP exchange on the browser's behalf. Requests go through the
+/// session's usual request handling, so SETUP gets its ports and channels
+/// there; tracks are set up in SDP order and so get channels 0-1, 2-3, ...
+#[derive(Debug)]
+pub struct SimpleDriver {
+    url: String,
+    managed: bool,
+    state: State,
+    cseq: u32,
+    /// Method and CSeq of the request awaiting its response
+    pending: Option<(String, u32)>,
+    sdp: SessionDescription,
+    base: String,
+    /// Indexes into `sdp.media` of the tracks to set up
+    tracks: Vec<usize>,
+    /// SETUPs sent so far
+    set_up: usize,
+    /// Where the first PLAY starts, in seconds
+    start: f64,
+    /// Managed mode: the command the pending request is for
+    command: &'static str,
+    /// Managed mode: where a seek in progress goes, in seconds
+    seek_to: Option<f64>,
+    /// Managed mode: Scale and Speed the camera granted, which later PLAYs
+    /// ask for again
+    scale: f64,
+    speed: f64,
+    /// Managed mode: Scale and Speed the next PLAY asks for instead
+    rate_change: Option<(f64, f64)>,
+    session: Option<String>,
+}
+
+impl SimpleDriver {
+    pub fn new(url: &str, mode: ClientMode) -> Self {
+        Self {
+            url: crate::upstream::camera_url(url),
+            managed: mode == ClientMode::Managed,
+            state: State::Starting,
+            cseq: 0,
+            pending: None,
+            sdp: SessionDescription::default(),
+            base: String::new(),
+            tracks: Vec::new(),
+            set_up: 0,
+            start: 0.0,
+            command: "play",
+            seek_to: None,
+            scale: 1.0,
+            speed: 1.0,
+            rate_change: None,
+            session: None,
+        }
+    }
+
+    /// The first request: DESCRIBE
+    pub fn start(&mut self) -> RtspRequest {
+        let mut req = self.request("DESCRIBE", &self.url.clone());
+        req.headers.insert("Accept".to_string(), "application/sdp".to_string());
+        req
+    }
+
+    pub fn on_response(&mut self, resp: &RtspResponse) -> Step {
+        let cseq = resp.headers.get("CSeq").and_then(|c| c.trim().parse::<u32>().ok());
+        let Some((method, _)) = self.pending.take_if(|(_, pending)| Some(*pending) == cseq) else {
+            return Step::Ignore;
+        };
+        if !(200..300).contains(&resp.status_code) {
+            let reason = format!("{} failed: {} {}", method, resp.status_code, resp.reason);
+            if let Some(to) = self.seek_to {
+                // A camera that cannot pause may still seek with a plain PLAY
+                if method == "PAUSE" {
+                    return Step::Send(self.play(Some(to)));
+                }
+                self.seek_to = None;
+            }
+            // Once playing, a refused command leaves the session as it was
+            return match self.state {
+                State::Playing | State::Paused => Step::Message(error(self.command, &reason)),
+                _ => Step::Failed(reason),
+            };
+        }
+        if let Some(session) = resp.headers.get("Session") {
+            self.session.get_or_insert_with(|| session.split(';').next().unwrap_or(session).trim().to_string());
+        }
+
+        if method == "PLAY" {
+            // Without the header the camera ignored the request and plays at the normal rate
+            let granted = |name| resp.headers.get(name).and_then(|value| value.trim().parse::<f64>().ok()).unwrap_or(1.0);
+            self.scale = granted("Scale");
+            self.speed = granted("Speed");
+        }
+        if let Some(to) = self.seek_to {
+            if method == "PAUSE" {
+                self.state = State::Paused;
+                return Step::Send(self.play(Some(to)));
+            }
+            self.seek_to = None;
+            self.state = State::Playing;
+            return Step::Seeked(to);
+        }
+
+        match method.as_str() {
+            "DESCRIBE" => {
+                self.sdp = SessionDescription::parse(&String::from_utf8_lossy(&resp.body));
+                self.base = sdp::content_base(&resp.headers, &self.url);
+                if !self.sdp.media.iter().any(playable) {
+                    return Step::Failed("The camera offers no audio or video".to_string());
+                }
+                // Metadata tracks ride along for overlays
+                self.tracks = (0..self.sdp.media.len())
+                    .filter(|&i| playable(&self.sdp.media[i]) || MetadataFormat::for_media(&self.sdp.media[i]).is_some())
+                    .collect();
+                if self.managed {
+                    self.state = State::Described;
+                    return Step::Message(self.description());
+                }
+                Step::Send(self.setup())
+            }
+            "SETUP" => {
+                if self.set_up < self.tracks.len() {
+                    return Step::Send(self.setup());
+                }
+                Step::Send(self.play(Some(self.start)))
+            }
+            "PAUSE" => {
+                self.state = State::Paused;
+                Step::Message(ServerMessage::State { state: "paused" })
+            }
+            _ if self.state == State::Starting => {
+                self.state = State::Playing;
+                Step::Ready(self.description())
+            }
+            _ if matches!(self.command, "scale" | "speed") => Step::Message(ServerMessage::Rate { scale: self.scale, speed: self.speed }),
+            _ => {
+                self.state = State::Playing;
+                Step::Message(ServerMessage::State { state: "playing" })
+            }
+        }
+    }
+
+    /// Managed mode: the request a playback command needs, or the browser's answer
+    pub fn command(&mut self, command: Command) -> Step {
+        let name = match command {
+            Command::Play => "play",
+            Command::Pause => "pause",
+            Command::Seek { .. } => "seek",
+            Command::Scale { .. } => "scale",
+            Command::Speed { .. } => "speed",
+            Command::SelectTrack { .. } => "select_track",
+            _ => return Step::Ignore,
+        };
+        if !self.managed {
+            return Step::Message(error(name, "Playback commands need mode=managed"));
+        }
+        if self.pending.is_some() || self.state == State::Starting {
+            return Step::Message(error(name, "Another request to the camera is in progress"));
+        }
+        self.command = name;
+
+        match (command, self.state) {
+            (Command::SelectTrack { track, selected }, State::Described) => {
+                if track >= self.sdp.media.len() {
+                    return Step::Message(error(name, &format!("The camera has no track {}", track)));
+                }
+                self.tracks.retain(|&i| i != track);
+                if selected {
+                    self.tracks.push(track);
+                    self.tracks.sort_unstable();
+                }
+                Step::Message(self.description())
+            }
+            (Command::SelectTrack { .. }, _) => Step::Message(error(name, "Tracks can only be selected before the first play")),
+            (Command::Play | Command::Seek { .. }, State::Described) => {
+                if !self.tracks.iter().any(|&i| playable(&self.sdp.media[i])) {
+                    return Step::Message(error(name, "No audio or video track is selected"));
+                }
+                if let Command::Seek { seconds } = command {
+                    self.start = seconds.max(0.0);
+                }
+                self.state = State::Starting;
+                Step::Send(self.setup())
+            }
+            (Command::Pause, State::Described) => Step::Message(error(name, "Playback has not started")),
+            // A PLAY without a Range changes the rate from the current position
+            (Command::Scale { scale }, State::Playing) if scale != 0.0 => {
+                self.rate_change = Some((scale, self.speed));
+                Step::Send(self.play(None))
+            }
+            (Command::Speed { speed }, State::Playing) if speed > 0.0 => {
+                self.rate_change = Some((self.scale, speed));
+                Step::Send(self.play(None))
+            }
+            (Command::Scale { .. }, State::Playing) => Step::Message(error(name, "Scale cannot be 0")),
+            (Command::Speed { .. }, State::Playing) => Step::Message(error(name, "Speed must be above 0")),
+            (Command::Scale { .. } | Command::Speed { .. }, _) => Step::Message(error(name, "The rate can only be changed while playing")),
+            // Without a Range, PLAY continues from where the stream was paused
+            (Command::Play, _) => Step::Send(self.play(None)),
+            // Pausing first stops the old position's media before the new
+            // position's starts, so the two are not interleaved
+            (Command::Seek { seconds }, State::Playing) => {
+                self.seek_to = Some(seconds.max(0.0));
+                let url = self.sdp.aggregate_url(&self.base);
+                Step::Send(self.request("PAUSE", &url))
+            }
+            (Command::Seek { seconds }, _) => {
+                self.seek_to = Some(seconds.max(0.0));
+                Step::Send(self.play(Some(seconds.max(0.0))))
+            }
+            (Command::Pause, _) => {
+                let url = self.sdp.aggregate_url(&self.base);
+                Step::Send(self.request("PAUSE", &url))
+            }
+            _ => Step::Ignore,
+        }
+    }
+
+    /// Managed mode: whether a seek is waiting on the camera, during which
+    /// media from the old position should be dropped
+    pub fn seeking(&self) -> bool {
+        self.seek_to.is_some()
+    }
+
+    /// SETUP for the next track
+    fn setup(&mut self) -> RtspRequest {
+        let media = &self.sdp.media[self.tracks[self.set_up]];
+        let url = sdp::resolve_control(&self.base, media.control.as_deref());
+        self.set_up += 1;
+        let mut req = self.request("SETUP", &url);
+        // Ports are filled in by the session's SETUP handling
+        req.headers.insert("Transport".to_string(), "RTP/AVP;unicast;client_port=0-1".to_string());
+        req
+    }
+
+    /// Managed mode: media time per second of playback at the granted
+    /// Scale and Speed, by which the camera's timestamps are slowed down
+    pub fn rate(&self) -> f64 {
+        self.scale.abs() * self.speed
+    }
+
+    fn play(&mut self, from: Option<f64>) -> RtspRequest {
+        let url = self.sdp.aggregate_url(&self.base);
+        let mut req = self.request("PLAY", &url);
+        if let Some(seconds) = from {
+            req.headers.insert("Range".to_string(), format!("npt={:.3}-", seconds));
+        }
+        // A PLAY without them goes back to the normal rate
+        let (scale, speed) = self.rate_change.take().unwrap_or((self.scale, self.speed));
+        if scale != 1.0 {
+            req.headers.insert("Scale".to_string(), format!("{:.3}", scale));
+        }
+        if speed != 1.0 {
+            req.headers.insert("Speed".to_string(), format!("{:.3}", speed));
+        }
+        req
+    }
+
+    fn request(&mut self, method: &str, url: &str) -> RtspRequest {
+        self.cseq += 1;
+        let mut req = RtspRequest::new(method, url);
+        req.headers.insert("CSeq".to_string(), self.cseq.to_string());
+        if let Some(session) = &self.session {
+            req.headers.insert("Session".to_string(), session.clone());
+        }
+        self.pending = Some((method.to_string(), self.cseq));
+        req
+    }
+
+    /// "tracks" message listing each track's channels and
+    /// codec. Managed mode lists every track the camera offers, with whether
+    /// it is selected; channels are null until it is set up.
+    fn description(&self) -> ServerMessage {
+        let channel = |i: usize| {
+            let n = self.tracks.iter().position(|&t| t == i)?;
+            (n < self.set_up).then_some(n as u8 * 2)
+        };
+        let tracks: Vec<Value> = if self.managed {
+            (0..self.sdp.media.len())
+                .map(|i| {
+                    let mut track = track(&self.sdp.media[i], channel(i));
+                    track["track"] = json!(i);
+                    track["selected"] = json!(self.tracks.contains(&i));
+                    track
+                })
+                .collect()
+        } else {
+            self.tracks.iter().map(|&i| track(&self.sdp.media[i], channel(i))).collect()
+        };
+        ServerMessage::Tracks { tracks }
+    }
+}
+
+fn playable(media: &MediaDescription) -> bool {
+    matches!(media.kind.as_str(), "video" | "audio")
+}
+
+fn track(media: &MediaDescription, channel: Option<u8>) -> Value {
+    let metadata = MetadataFormat::for_media(media);
+    json!({
+        "channel": channel,
+        "rtcp_channel": channel.map(|c| c + 1),
+        "kind": if metadata.is_some() { "metadata" } else { media.kind.as_str() },
+        "format": metadata.map(MetadataFormat::as_str),
+        "codec": media.encoding,
+        "payload_type": media.payload_type,
+        "clock_rate": media.clock_rate,
+        "fmtp": media.fmtp,
+        "resolution": media.resolution().map(|(w, h)| json!({ "width": w, "height": h })),
+    })
+}
+
+/// "error" message for a command that did not go through
+fn error(command: &'static str, reason: &str) -> ServerMessage {
+    ServerMessage::Error { command: Some(command), error: reason.to_string() }
+}
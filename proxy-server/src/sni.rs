@@ -0,0 +1,139 @@
+//! Certificates chosen by the hostname a client asks for (SNI), for hosted
+//! deployments where each customer reaches the proxy under its own domain.
+//!
+//! `[[certificate]]` files are read at startup and again on every config
+//! reload, so a hostname can be added, renewed or removed without a restart;
+//! connections already up keep the certificate they were made with. Names
+//! without an entry, and clients that send none, get the default certificate.
+
+use anyhow::{ensure, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig as TlsServerConfig;
+use tracing::{error, info};
+use crate::config::CertificateConfig;
+
+/// ALPN of the WebSocket handshake
+#[cfg(feature = "websocket")]
+const HTTP_1_1_ALPN: &[u8] = b"http/1.1";
+
+/// Picks a handshake's certificate by its server name
+#[derive(Debug)]
+struct SniResolver {
+    provider: Arc<CryptoProvider>,
+    /// By lowercase hostname, wildcards as `*.example.com`
+    certificates: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// Resolves the default certificate
+    fallback: Arc<dyn ResolvesServerCert>,
+}
+
+static RESOLVER: OnceLock<Arc<SniResolver>> = OnceLock::new();
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let found = client_hello.server_name().and_then(|name| self.lookup(name));
+        found.or_else(|| self.fallback.resolve(client_hello))
+    }
+}
+
+impl SniResolver {
+    fn lookup(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        let certificates = self.certificates.read().unwrap();
+        if let Some(key) = certificates.get(&name) {
+            return Some(key.clone());
+        }
+        // A wildcard stands for exactly one label
+        let (_, parent) = name.split_once('.')?;
+        certificates.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+/// Answer handshakes for the configured hostnames with their certificates,
+/// and all others the way `tls` did before
+pub fn install(tls: &mut TlsServerConfig, certificates: &[CertificateConfig]) -> Result<()> {
+    let provider = tls.crypto_provider().clone();
+    let resolver = Arc::new(SniResolver {
+        certificates: RwLock::new(load(certificates, &provider)?),
+        fallback: tls.cert_resolver.clone(),
+        provider,
+    });
+    if !certificates.is_empty() {
+        info!("Serving certificates for {} hostname(s) by SNI", certificates.len());
+    }
+    tls.cert_resolver = resolver.clone();
+    let _ = RESOLVER.set(resolver);
+    Ok(())
+}
+
+/// Read the certificates again after a config reload; if any cannot be
+/// loaded, the current ones stay in use
+pub fn reload(certificates: &[CertificateConfig]) {
+    let Some(resolver) = RESOLVER.get() else {
+        return;
+    };
+    match load(certificates, &resolver.provider) {
+        Ok(loaded) => {
+            *resolver.certificates.write().unwrap() = loaded;
+            info!("Reloaded certificates for {} hostname(s)", certificates.len());
+        }
+        Err(e) => error!("Certificate reload failed, keeping the current certificates: {:#}", e),
+    }
+}
+
+/// TLS config that serves the certificate in `cert_file` to everyone,
+/// for [`install`] to extend in builds without a WebTransport config
+#[cfg(not(feature = "webtransport"))]
+pub fn default_tls_config(cert_file: &Path, key_file: &Path) -> Result<TlsServerConfig> {
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let key = load_pair(cert_file, key_file, "the default certificate", &provider)?;
+    let tls = TlsServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(tokio_rustls::rustls::sign::SingleCertAndKey::from(key)));
+    Ok(tls)
+}
+
+/// TLS config for the `[wss]` listener, serving the same certificates as
+/// WebTransport; [`install`] must have run
+#[cfg(feature = "websocket")]
+pub fn websocket_tls_config() -> Result<TlsServerConfig> {
+    let resolver = RESOLVER.get().context("No certificates installed")?.clone();
+    let mut tls = TlsServerConfig::builder_with_provider(resolver.provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    tls.alpn_protocols = vec![HTTP_1_1_ALPN.to_vec()];
+    Ok(tls)
+}
+
+fn load(certificates: &[CertificateConfig], provider: &CryptoProvider) -> Result<HashMap<String, Arc<CertifiedKey>>> {
+    certificates
+        .iter()
+        .map(|certificate| Ok((certificate.hostname.to_ascii_lowercase(), load_one(certificate, provider)?)))
+        .collect()
+}
+
+/// One hostname's certificate chain and key, checked to belong together
+pub fn load_one(certificate: &CertificateConfig, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    load_pair(&certificate.cert, &certificate.key, &certificate.hostname, provider)
+}
+
+/// A certificate chain and its key, checked to belong together; `name` says
+/// what they are for in errors
+pub fn load_pair(cert_file: &Path, key_file: &Path, name: &str, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificate {} for {}", cert_file.display(), name))?;
+    ensure!(!chain.is_empty(), "Certificate file {} for {} holds no certificates", cert_file.display(), name);
+    let key = PrivateKeyDer::from_pem_file(key_file).with_context(|| format!("Failed to read key {} for {}", key_file.display(), name))?;
+    let key = CertifiedKey::from_der(chain, key, provider)
+        .with_context(|| format!("Unusable certificate {} and key {} for {}", cert_file.display(), key_file.display(), name))?;
+    Ok(Arc::new(key))
+}
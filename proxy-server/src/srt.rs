@@ -0,0 +1,336 @@
+//! SRT ingest for `srt://` URLs (requires the `srt` feature). An embedded
+//! GStreamer pipeline receives MPEG-TS over SRT and re-packetizes its H.264
+//! video as RTP; the proxy answers the browser's RTSP requests itself, so the
+//! player sees an ordinary one-track camera.
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gst::prelude::*;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, warn};
+use crate::adaptive::Profile;
+use crate::config::{ChannelIdConfig, ChannelIdFormat};
+use crate::control::{Command, ControlCodec, ControlProtocol, Event, SessionStats};
+use crate::drops::DropReason;
+use crate::limits::{BitrateCheck, Expiry, SessionLimits};
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::stats::{self, GaugeGuard};
+use crate::transport::{DatagramSender, Transport};
+
+/// Payload type of the re-packetized video
+const PAYLOAD_TYPE: u8 = 96;
+/// Channels announced for the single video track
+const RTP_CHANNEL: u8 = 0;
+const RTCP_CHANNEL: u8 = 1;
+/// Output RTP packets buffered between the appsink and the session
+const OUTPUT_QUEUE: usize = 512;
+/// How long DESCRIBE waits for the stream's parameter sets
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Packet size bounds, as for transcoded output
+const DEFAULT_OUTPUT_MTU: u32 = 1200;
+const MIN_OUTPUT_MTU: u32 = 256;
+const MAX_OUTPUT_MTU: u32 = 1400;
+const MAX_FRAMING_HEADER: usize = 3;
+
+/// GStreamer pipeline pulling MPEG-TS from an SRT listener or caller and
+/// producing H.264 RTP through a channel
+struct SrtSource {
+    pipeline: gst::Pipeline,
+    /// Cancelled when the pipeline reports an error or end of stream
+    ended: CancellationToken,
+    _active: GaugeGuard,
+}
+
+impl SrtSource {
+    /// `fmtp` receives the SDP format parameters once the first packet is out
+    fn new(url: &str, mtu: u32, output: mpsc::Sender<Bytes>, fmtp: watch::Sender<Option<String>>) -> Result<Self> {
+        gst::init()?;
+        let description = format!(
+            "srtsrc name=src ! tsdemux ! h264parse ! video/x-h264,stream-format=byte-stream \
+             ! rtph264pay config-interval=-1 pt={PAYLOAD_TYPE} mtu={mtu} \
+             ! appsink name=sink sync=false"
+        );
+        let pipeline = gst::parse::launch(&description)
+            .context("Failed to build SRT pipeline (is the GStreamer srt plugin installed?)")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow!("SRT description is not a pipeline"))?;
+        // Set as a property so the URL needs no escaping in the description
+        pipeline
+            .by_name("src")
+            .ok_or_else(|| anyhow!("SRT pipeline has no srtsrc"))?
+            .set_property("uri", url);
+        let appsink = pipeline
+            .by_name("sink")
+            .and_then(|e| e.dynamic_cast::<gst_app::AppSink>().ok())
+            .ok_or_else(|| anyhow!("SRT pipeline has no appsink"))?;
+
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    if fmtp.borrow().is_none()
+                        && let Some(caps) = sample.caps().and_then(|caps| caps.structure(0))
+                    {
+                        let mut params = vec!["packetization-mode=1".to_string()];
+                        if let Ok(profile) = caps.get::<String>("profile-level-id") {
+                            params.push(format!("profile-level-id={}", profile));
+                        }
+                        if let Ok(sprop) = caps.get::<String>("sprop-parameter-sets") {
+                            params.push(format!("sprop-parameter-sets={}", sprop));
+                        }
+                        fmtp.send_replace(Some(params.join(";")));
+                    }
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    // Drop output rather than stall the pipeline if the browser can't keep up
+                    if output.try_send(Bytes::copy_from_slice(&map)).is_err() {
+                        warn!("SRT output queue full, dropping packet");
+                        crate::drops::record(DropReason::QueueFull, Some(RTP_CHANNEL), map.len());
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        let ended = CancellationToken::new();
+        let bus = pipeline.bus().ok_or_else(|| anyhow!("SRT pipeline has no bus"))?;
+        let watcher = ended.clone();
+        std::thread::spawn(move || {
+            while !watcher.is_cancelled() {
+                let Some(message) = bus.timed_pop_filtered(gst::ClockTime::from_mseconds(500), &[gst::MessageType::Error, gst::MessageType::Eos])
+                else {
+                    continue;
+                };
+                match message.view() {
+                    gst::MessageView::Error(e) => error!("SRT source failed: {}", e.error()),
+                    _ => info!("SRT stream ended"),
+                }
+                watcher.cancel();
+            }
+        });
+
+        if let Err(e) = pipeline.set_state(gst::State::Playing) {
+            let _ = pipeline.set_state(gst::State::Null);
+            ended.cancel();
+            return Err(anyhow!("SRT pipeline failed to start: {}", e));
+        }
+        let active = stats::global().track("srt_sources_active".to_string());
+        Ok(Self { pipeline, ended, _active: active })
+    }
+}
+
+impl Drop for SrtSource {
+    fn drop(&mut self) {
+        // Also stops the bus watcher thread
+        self.ended.cancel();
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// One browser session fed from an SRT source
+pub struct SrtSession {
+    url: String,
+    protocol: ControlProtocol,
+    limits: SessionLimits,
+    channel_ids: ChannelIdConfig,
+}
+
+impl SrtSession {
+    pub fn new(url: String) -> Self {
+        Self { url, protocol: ControlProtocol::default(), limits: SessionLimits::default(), channel_ids: ChannelIdConfig::default() }
+    }
+
+    pub fn with_protocol(mut self, protocol: ControlProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_channel_ids(mut self, channel_ids: ChannelIdConfig) -> Self {
+        self.channel_ids = channel_ids;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: SessionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[instrument(skip(self, transport), fields(url = %self.url))]
+    pub async fn handle_connection(self, mut transport: Transport) -> Result<()> {
+        let (reader, writer, sender) = transport.split();
+        let mtu = match sender.max_datagram_size() {
+            Some(max) => (max.saturating_sub(MAX_FRAMING_HEADER) as u32).clamp(MIN_OUTPUT_MTU, MAX_OUTPUT_MTU),
+            None => DEFAULT_OUTPUT_MTU,
+        };
+        let (packet_tx, mut packet_rx) = mpsc::channel::<Bytes>(OUTPUT_QUEUE);
+        let (fmtp_tx, mut fmtp_rx) = watch::channel(None);
+        let source = SrtSource::new(&self.url, mtu, packet_tx, fmtp_tx)?;
+        info!("Receiving SRT from {}", self.url);
+
+        let mut control = ControlCodec::new(self.protocol);
+        let mut control_buf = BytesMut::new();
+        let mut rtsp_buf = BytesMut::new();
+        let session_id = format!("{:08x}", rand::random::<u32>());
+        let started = std::time::Instant::now();
+        let bytes_sent = sender.bytes_sent();
+        let mut playing = false;
+
+        let expired = self.limits.expired(started);
+        tokio::pin!(expired);
+        let mut bitrate = BitrateCheck::new(&self.limits);
+        let mut limit_check = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                res = reader.read(&mut control_buf) => {
+                    if res? == 0 {
+                        info!("Transport stream closed by client");
+                        break;
+                    }
+                    for command in control.decode(&mut control_buf, &mut rtsp_buf) {
+                        match command {
+                            Command::Stats => {
+                                let stats = SessionStats {
+                                    uptime: started.elapsed(),
+                                    bytes_sent: bytes_sent.load(std::sync::atomic::Ordering::Relaxed),
+                                    profile: Profile::Main,
+                                    stalled: false,
+                                    startup: Default::default(),
+                                    channels: Vec::new(),
+                                    estimate_kbps: None,
+                                };
+                                writer.write(&control.stats(&stats)).await?;
+                            }
+                            // SRT sources are not time-shifted or managed
+                            command => warn!("Ignoring {:?} on an SRT session", command),
+                        }
+                    }
+                    while let Some((req, consumed)) = RtspRequest::parse(&rtsp_buf)? {
+                        rtsp_buf.advance(consumed);
+                        let resp = match req.method.as_str() {
+                            "DESCRIBE" => self.describe(&mut fmtp_rx).await,
+                            "PLAY" => {
+                                playing = true;
+                                let mut resp = RtspResponse::new(200, "OK");
+                                resp.headers.insert("Range".to_string(), "npt=now-".to_string());
+                                resp
+                            }
+                            "TEARDOWN" => {
+                                playing = false;
+                                RtspResponse::new(200, "OK")
+                            }
+                            _ => answer(&req, sender, &self.channel_ids),
+                        };
+                        if req.method == "SETUP" && self.channel_ids.format == ChannelIdFormat::Event {
+                            let cseq = req.headers.get("CSeq").cloned().unwrap_or_default();
+                            let event = Event::Channels { cseq, rtp_channel_id: RTP_CHANNEL, rtcp_channel_id: RTCP_CHANNEL };
+                            writer.write(&control.event(&event)).await?;
+                        }
+                        writer.write(&control.rtsp(&respond(resp, &req, &session_id).to_bytes())).await?;
+                        if req.method == "TEARDOWN" {
+                            return Ok(());
+                        }
+                    }
+                }
+
+                Some(packet) = packet_rx.recv() => {
+                    if !playing {
+                        continue;
+                    }
+                    let framing = sender.framing();
+                    let mut payload = BytesMut::with_capacity(packet.len() + MAX_FRAMING_HEADER);
+                    framing.begin(RTP_CHANNEL, &mut payload);
+                    payload.extend_from_slice(&packet);
+                    framing.finish(&mut payload);
+                    sender.send_datagram(payload.freeze()).await?;
+                }
+
+                _ = source.ended.cancelled() => {
+                    return Err(anyhow!("SRT source {} stopped", self.url));
+                }
+
+                _ = limit_check.tick() => {
+                    if bitrate.exceeded(bytes_sent.load(std::sync::atomic::Ordering::Relaxed)) {
+                        warn!("Closing session: over its {} kbit/s limit", self.limits.max_bitrate_kbps.unwrap_or_default());
+                        break;
+                    }
+                }
+
+                expiry = &mut expired => {
+                    match expiry {
+                        Expiry::MaxDuration => warn!("Closing session: it reached its maximum duration"),
+                        Expiry::ViewingHours => warn!("Closing session: viewing hours are over"),
+                    }
+                    let _ = writer.write(&control.event(&Event::Expired(expiry))).await;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// SDP for the single video track, once the stream's parameter sets are known
+    async fn describe(&self, fmtp_rx: &mut watch::Receiver<Option<String>>) -> RtspResponse {
+        let fmtp = match tokio::time::timeout(DESCRIBE_TIMEOUT, fmtp_rx.wait_for(Option::is_some)).await {
+            Ok(Ok(fmtp)) => fmtp.clone().unwrap_or_default(),
+            _ => {
+                warn!("No H.264 video from {} within {:?}", self.url, DESCRIBE_TIMEOUT);
+                return RtspResponse::new(503, "Service Unavailable");
+            }
+        };
+        let sdp = format!(
+            "v=0\r\no=- 0 0 IN IP4 0.0.0.0\r\ns=SRT\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\na=control:*\r\n\
+             m=video 0 RTP/AVP {pt}\r\na=rtpmap:{pt} H264/90000\r\na=fmtp:{pt} {fmtp}\r\na=control:stream=0\r\n",
+            pt = PAYLOAD_TYPE,
+        );
+        let mut resp = RtspResponse::new(200, "OK");
+        resp.headers.insert("Content-Type".to_string(), "application/sdp".to_string());
+        resp.headers.insert("Content-Base".to_string(), format!("{}/", self.url.trim_end_matches('/')));
+        resp.headers.insert("Content-Length".to_string(), sdp.len().to_string());
+        resp.body = sdp.into_bytes();
+        resp
+    }
+}
+
+/// Answers that need no session state
+fn answer(req: &RtspRequest, sender: &DatagramSender, channel_ids: &ChannelIdConfig) -> RtspResponse {
+    match req.method.as_str() {
+        "OPTIONS" => {
+            let mut resp = RtspResponse::new(200, "OK");
+            resp.headers.insert("Public".to_string(), "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string());
+            resp
+        }
+        "GET_PARAMETER" => RtspResponse::new(200, "OK"),
+        "SETUP" => {
+            let channels = channel_ids.transport_param(RTP_CHANNEL, RTCP_CHANNEL).map(|param| format!(";{}", param)).unwrap_or_default();
+            let mut transport_header = format!(
+                "RTP/AVP;unicast{};x-wt-delivery={};x-wt-framing={}",
+                channels,
+                sender.delivery_mode().as_str(),
+                sender.framing().as_str()
+            );
+            if let Some(max) = sender.max_datagram_size() {
+                transport_header.push_str(&format!(";x-wt-max-datagram={}", max));
+            }
+            let mut resp = RtspResponse::new(200, "OK");
+            resp.headers.insert("Transport".to_string(), transport_header);
+            resp
+        }
+        _ => RtspResponse::new(501, "Not Implemented"),
+    }
+}
+
+/// Add the headers every response carries
+fn respond(mut resp: RtspResponse, req: &RtspRequest, session_id: &str) -> RtspResponse {
+    if let Some(cseq) = req.headers.get("CSeq") {
+        resp.headers.insert("CSeq".to_string(), cseq.clone());
+    }
+    if matches!(req.method.as_str(), "SETUP" | "PLAY" | "GET_PARAMETER") {
+        resp.headers.insert("Session".to_string(), session_id.to_string());
+    }
+    resp
+}
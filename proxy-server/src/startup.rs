@@ -0,0 +1,116 @@
+//! Startup latency: time from client connect to the first media datagram,
+//! broken down by phase
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Startup phases, in the order they complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Connected to the camera
+    Connect,
+    /// First DESCRIBE answered
+    Describe,
+    /// Last SETUP before PLAY answered
+    Setup,
+    /// PLAY answered
+    Play,
+    /// First RTP datagram sent to the browser
+    FirstPacket,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::Connect, Phase::Describe, Phase::Setup, Phase::Play, Phase::FirstPacket];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Connect => "connect",
+            Phase::Describe => "describe",
+            Phase::Setup => "setup",
+            Phase::Play => "play",
+            Phase::FirstPacket => "first_packet",
+        }
+    }
+}
+
+/// When each phase of one session completed
+#[derive(Debug)]
+pub struct StartupTimer {
+    started: Instant,
+    /// Milliseconds after `started`, plus one so 0 means "not yet"
+    marks: [AtomicU64; 5],
+}
+
+/// Duration of each phase, measured from the end of the previous one, so
+/// browser round trips count towards the phase they delay
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StartupReport {
+    phases: [Option<Duration>; 5],
+    /// Connect to first packet, once it arrived
+    pub total: Option<Duration>,
+}
+
+impl StartupTimer {
+    pub fn new() -> Self {
+        Self { started: Instant::now(), marks: Default::default() }
+    }
+
+    /// Record that `phase` completed. Later SETUPs move the end of the SETUP
+    /// phase until PLAY; other phases keep their first completion.
+    pub fn mark(&self, phase: Phase) {
+        let now = self.started.elapsed().as_millis() as u64 + 1;
+        let slot = &self.marks[phase as usize];
+        let first = slot.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed).is_ok();
+        if !first && phase == Phase::Setup && self.marks[Phase::Play as usize].load(Ordering::Relaxed) == 0 {
+            slot.store(now, Ordering::Relaxed);
+        }
+        if first && phase == Phase::FirstPacket {
+            self.record();
+        }
+    }
+
+    /// Record the first packet; cheap once it has been seen
+    pub fn first_packet(&self) {
+        if self.marks[Phase::FirstPacket as usize].load(Ordering::Relaxed) == 0 {
+            self.mark(Phase::FirstPacket);
+        }
+    }
+
+    pub fn report(&self) -> StartupReport {
+        let mut report = StartupReport::default();
+        let mut previous = Duration::ZERO;
+        for phase in Phase::ALL {
+            let mark = self.marks[phase as usize].load(Ordering::Relaxed);
+            if mark == 0 {
+                continue;
+            }
+            let at = Duration::from_millis(mark - 1);
+            report.phases[phase as usize] = Some(at.saturating_sub(previous));
+            previous = at;
+        }
+        report.total = report.phases[Phase::FirstPacket as usize].map(|_| previous);
+        report
+    }
+
+    /// Log the breakdown and add it to the process-wide metrics
+    fn record(&self) {
+        let report = self.report();
+        let stats = crate::stats::global();
+        stats.add("startup_sessions", 1);
+        let mut line = Vec::new();
+        for phase in Phase::ALL {
+            if let Some(duration) = report.phases[phase as usize] {
+                stats.add(&format!("startup_ms_sum{{phase=\"{}\"}}", phase.as_str()), duration.as_millis() as i64);
+                line.push(format!("{}={}ms", phase.as_str(), duration.as_millis()));
+            }
+        }
+        info!("Startup took {}ms: {}", report.total.unwrap_or_default().as_millis(), line.join(" "));
+    }
+}
+
+impl StartupReport {
+    pub fn phase(&self, phase: Phase) -> Option<Duration> {
+        self.phases[phase as usize]
+    }
+}
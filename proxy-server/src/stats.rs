@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
+/// Process-wide gauges keyed by Prometheus-style name, e.g.
+/// `transcoders_active{encoder="vaapih264enc"}`
+#[derive(Debug, Default)]
+pub struct Stats {
+    gauges: Mutex<BTreeMap<String, i64>>,
+}
+
+static STATS: LazyLock<Stats> = LazyLock::new(Stats::default);
+
+pub fn global() -> &'static Stats {
+    &STATS
+}
+
+impl Stats {
+    pub fn add(&self, key: &str, delta: i64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        *gauges.entry(key.to_string()).or_default() += delta;
+    }
+
+    pub fn set(&self, key: &str, value: i64) {
+        self.gauges.lock().unwrap().insert(key.to_string(), value);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, i64> {
+        self.gauges.lock().unwrap().clone()
+    }
+
+    /// Increment a gauge for as long as the returned guard is alive
+    pub fn track(&'static self, key: String) -> GaugeGuard {
+        self.add(&key, 1);
+        GaugeGuard { stats: self, key }
+    }
+
+    /// Like [`track`](Self::track), unless the gauge is already at `max`
+    pub fn try_track(&'static self, key: String, max: Option<i64>) -> Option<GaugeGuard> {
+        let mut gauges = self.gauges.lock().unwrap();
+        let value = gauges.entry(key.clone()).or_default();
+        if max.is_some_and(|max| *value >= max) {
+            return None;
+        }
+        *value += 1;
+        Some(GaugeGuard { stats: self, key })
+    }
+}
+
+pub struct GaugeGuard {
+    stats: &'static Stats,
+    key: String,
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.stats.add(&self.key, -1);
+    }
+}
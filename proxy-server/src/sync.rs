@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use crate::rtp::{self, SenderReport};
+use crate::control::Event;
+use crate::rtsp::RtspRequest;
+
+/// Minimum spacing between sync notifications for one track
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+
+/// Wall-clock position of an RTP timestamp on one track, as seen by the browser
+#[derive(Debug, Clone, Copy)]
+pub struct SyncPoint {
+    /// Channel ID of the track's RTP
+    pub channel_id: u8,
+    pub unix_ms: f64,
+    pub rtp_timestamp: u32,
+    /// Nominal clock rate from the SDP
+    pub clock_rate: u32,
+    /// Camera clock rate recovered from consecutive Sender Reports
+    pub measured_rate: Option<f64>,
+}
+
+impl SyncPoint {
+    /// Notification carrying the mapping to the browser
+    pub fn notification(&self) -> RtspRequest {
+        let mut params = format!(
+            "x-wt-sync: channel={};wallclock={:.3};rtp={};clock-rate={}",
+            self.channel_id, self.unix_ms, self.rtp_timestamp, self.clock_rate
+        );
+        if let Some(rate) = self.measured_rate {
+            params.push_str(&format!(";measured-rate={:.2}", rate));
+        }
+        params.push_str("\r\n");
+        RtspRequest::notification(&params)
+    }
+}
+
+/// Watches one track's outgoing RTCP for Sender Reports and turns them into
+/// rate-limited [`SyncPoint`] notifications
+pub struct ClockSync {
+    channel_id: u8,
+    clock_rate: u32,
+    tx: mpsc::Sender<Event>,
+    last_report: Option<SenderReport>,
+    last_sent: Option<Instant>,
+}
+
+impl ClockSync {
+    pub fn new(channel_id: u8, clock_rate: u32, tx: mpsc::Sender<Event>) -> Self {
+        Self { channel_id, clock_rate, tx, last_report: None, last_sent: None }
+    }
+
+    /// Inspect an RTCP packet after any rewriting, so timestamps match what the browser receives
+    pub fn on_rtcp(&mut self, packet: &[u8]) {
+        let Some(report) = rtp::sender_report(packet) else {
+            return;
+        };
+
+        let measured_rate = self.last_report.and_then(|last| {
+            let elapsed = ntp_seconds(report.ntp) - ntp_seconds(last.ntp);
+            let ticks = report.rtp_timestamp.wrapping_sub(last.rtp_timestamp) as i32;
+            (elapsed > 0.0 && ticks > 0).then(|| ticks as f64 / elapsed)
+        });
+        self.last_report = Some(report);
+
+        if self.last_sent.is_some_and(|at| at.elapsed() < SYNC_INTERVAL) {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+
+        let point = SyncPoint {
+            channel_id: self.channel_id,
+            unix_ms: (ntp_seconds(report.ntp) - NTP_UNIX_OFFSET) * 1000.0,
+            rtp_timestamp: report.rtp_timestamp,
+            clock_rate: self.clock_rate,
+            measured_rate,
+        };
+        // Stale sync metadata is worthless, so drop it rather than wait
+        let _ = self.tx.try_send(Event::Sync(point));
+    }
+}
+
+fn ntp_seconds(ntp: u64) -> f64 {
+    (ntp >> 32) as f64 + (ntp & 0xFFFF_FFFF) as f64 / 4_294_967_296.0
+}
@@ -0,0 +1,111 @@
+//! systemd integration: socket activation (`LISTEN_FDS`) and `sd_notify`
+//! readiness/watchdog messages. Everything is a no-op outside systemd.
+
+use std::env;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Listeners handed over by systemd instead of being bound by the proxy.
+///
+/// Sockets are matched by `FileDescriptorName=` (`webtransport` for the UDP
+/// socket, `websocket` for the TCP listener) when they come from separate
+/// socket units, and otherwise by position: UDP first, then TCP.
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    pub webtransport: Option<std::net::UdpSocket>,
+    pub websocket: Option<std::net::TcpListener>,
+}
+
+/// Take the listeners passed by systemd, if this process was socket-activated
+#[cfg(target_os = "linux")]
+pub fn take_listeners() -> ActivatedSockets {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    /// First passed descriptor (SD_LISTEN_FDS_START)
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let mut sockets = ActivatedSockets::default();
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count: RawFd = env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()).unwrap_or(0);
+    if !for_us || count <= 0 {
+        return sockets;
+    }
+    let names: Vec<String> = env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+
+    for i in 0..count {
+        let fd = LISTEN_FDS_START + i;
+        let name = names.get(i as usize).map(String::as_str).unwrap_or("");
+        let slot = match (name, i) {
+            ("webtransport", _) => "webtransport",
+            ("websocket", _) => "websocket",
+            (_, 0) => "webtransport",
+            (_, 1) => "websocket",
+            _ => {
+                warn!("Ignoring socket-activated fd {} named '{}'", fd, name);
+                continue;
+            }
+        };
+        info!("Using socket-activated fd {} for {}", fd, slot);
+        // SAFETY: systemd passes ownership of fds LISTEN_FDS_START.. to this
+        // process, and each one is wrapped exactly once
+        if slot == "webtransport" {
+            sockets.webtransport = Some(unsafe { std::net::UdpSocket::from_raw_fd(fd) });
+        } else {
+            sockets.websocket = Some(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+        }
+    }
+    sockets
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn take_listeners() -> ActivatedSockets {
+    ActivatedSockets::default()
+}
+
+/// Send a state string (e.g. `READY=1`) to the service manager
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = sent {
+        warn!("sd_notify to {} failed: {}", path, e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) {}
+
+/// Ping the systemd watchdog at half the configured `WatchdogSec=`, if enabled
+pub fn spawn_watchdog() {
+    let for_us = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    let Some(timeout) = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) else {
+        return;
+    };
+    if !for_us || timeout == 0 {
+        return;
+    }
+
+    let period = Duration::from_micros(timeout / 2);
+    info!("systemd watchdog enabled, pinging every {:?}", period);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
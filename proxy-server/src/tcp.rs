@@ -0,0 +1,167 @@
+//! Plain TCP transport for native clients, test tools and embedded viewers
+//! that speak neither WebTransport nor WebSocket.
+//!
+//! Everything travels as frames of `[u8 type][u32 length][payload]`, big
+//! endian. The client opens with an `OPEN` frame holding the path and query
+//! it would put in a WebSocket URL, e.g. `/?rtsp=rtsp://cam/stream`; the
+//! proxy answers `OPEN` with `OK`, or with `ERR <reason>` and closes. A
+//! session that fails later ends with a second `OPEN` frame, `ERR <kind>`.
+//!
+//! The RTSP listener ([`crate::rtsp_server`]) runs its clients' sessions on
+//! the same links, written in RTSP's own dialect instead.
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// RTSP text, both ways
+pub const FRAME_CONTROL: u8 = 0;
+/// A media payload with the session's framing; feedback from the client
+pub const FRAME_MEDIA: u8 = 1;
+/// Compressed control, proxy to client, when `compress` was requested
+pub const FRAME_COMPRESSED: u8 = 2;
+/// Session request from the client and the proxy's answer
+pub const FRAME_OPEN: u8 = 3;
+/// Largest frame accepted from a client
+const MAX_FRAME: usize = 1024 * 1024;
+/// Feedback frames queued before further ones are dropped
+const MEDIA_QUEUE: usize = 64;
+
+/// How frames are put on the wire
+#[derive(Debug, Clone)]
+pub enum Dialect {
+    /// `[u8 type][u32 length][payload]`
+    Frames,
+    /// An RTSP client's connection: RTSP text, and media as RFC 2326
+    /// interleaved `$` frames. Holds the CSeq of the client's first request
+    /// until it is answered, so a refusal can answer it.
+    Rtsp(Arc<SyncMutex<Option<String>>>),
+}
+
+/// Write side of a client connection, shared by control and media
+#[derive(Debug, Clone)]
+pub struct TcpWriter(Arc<Mutex<OwnedWriteHalf>>, Dialect);
+
+impl TcpWriter {
+    pub fn new(writer: OwnedWriteHalf, dialect: Dialect) -> Self {
+        Self(Arc::new(Mutex::new(writer)), dialect)
+    }
+
+    pub async fn send(&self, kind: u8, payload: &[u8]) -> Result<()> {
+        if let Dialect::Rtsp(unanswered) = &self.1 {
+            // Media payloads are RFC 4571 framed already: `$` completes them
+            if kind == FRAME_MEDIA {
+                let mut writer = self.0.lock().await;
+                writer.write_all(b"$").await?;
+                writer.write_all(payload).await?;
+                return Ok(());
+            }
+            if let Some(data) = crate::rtsp_server::outgoing(kind, payload, unanswered) {
+                self.0.lock().await.write_all(&data).await?;
+            }
+            return Ok(());
+        }
+        let len = u32::try_from(payload.len()).map_err(|_| anyhow::anyhow!("Frame too large: {} bytes", payload.len()))?;
+        let mut header = [0u8; 5];
+        header[0] = kind;
+        header[1..].copy_from_slice(&len.to_be_bytes());
+        let mut writer = self.0.lock().await;
+        writer.write_all(&header).await?;
+        writer.write_all(payload).await?;
+        Ok(())
+    }
+}
+
+/// A client connection after its `OPEN`: frames it sends are sorted into
+/// control and media by a reader task
+#[derive(Debug)]
+pub struct TcpLink {
+    pub writer: TcpWriter,
+    pub control: mpsc::Receiver<Bytes>,
+    pub media: Arc<Mutex<mpsc::Receiver<Bytes>>>,
+    /// Cancelled once the client hangs up
+    pub closed: CancellationToken,
+}
+
+/// Read the client's `OPEN` frame: the path and query of its session request
+pub async fn accept(stream: TcpStream) -> Result<(String, TcpLink)> {
+    let (mut reader, writer) = stream.into_split();
+    let target = match read_frame(&mut reader).await? {
+        Some((FRAME_OPEN, payload)) => String::from_utf8(payload).map_err(|_| anyhow::anyhow!("OPEN frame is not UTF-8"))?,
+        Some((kind, _)) => bail!("Expected an OPEN frame, got type {}", kind),
+        None => bail!("Client closed the connection before OPEN"),
+    };
+
+    let (control_tx, control) = mpsc::channel(16);
+    let (media_tx, media) = mpsc::channel(MEDIA_QUEUE);
+    let closed = CancellationToken::new();
+    let token = closed.clone();
+    tokio::spawn(async move {
+        loop {
+            match read_frame(&mut reader).await {
+                // An empty read would look like the end of the control stream
+                Ok(Some((FRAME_CONTROL, payload))) if payload.is_empty() => {}
+                Ok(Some((FRAME_CONTROL, payload))) => {
+                    if control_tx.send(Bytes::from(payload)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(Some((FRAME_MEDIA, payload))) => {
+                    // Feedback is lossy anyway; a client flooding it loses some
+                    let _ = media_tx.try_send(Bytes::from(payload));
+                }
+                Ok(Some((kind, _))) => warn!("Ignoring TCP frame of type {}", kind),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("TCP client read error: {:#}", e);
+                    break;
+                }
+            }
+        }
+        token.cancel();
+    });
+
+    let link = TcpLink { writer: TcpWriter::new(writer, Dialect::Frames), control, media: Arc::new(Mutex::new(media)), closed };
+    Ok((target, link))
+}
+
+impl TcpLink {
+    /// Whether the client is an RTSP client of the RTSP listener
+    pub fn is_rtsp(&self) -> bool {
+        matches!(self.writer.1, Dialect::Rtsp(_))
+    }
+
+    /// Accept the session request
+    pub async fn open(&self) -> Result<()> {
+        self.writer.send(FRAME_OPEN, b"OK").await
+    }
+
+    /// Refuse the session request and hang up
+    pub async fn reject(self, reason: &str) {
+        let _ = self.writer.send(FRAME_OPEN, format!("ERR {}", reason).as_bytes()).await;
+        let _ = self.writer.0.lock().await.shutdown().await;
+    }
+}
+
+/// Next frame, or None at a clean end of stream
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME {
+        bail!("Frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME);
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some((header[0], payload)))
+}
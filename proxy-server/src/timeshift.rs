@@ -0,0 +1,324 @@
+//! Time-shift buffer: the last seconds of a session's media, as sent to the
+//! browser, so it can pause live, rewind and catch up again.
+//!
+//! Every media payload passes through [`TimeShift::record`] on its way out.
+//! While the browser is live it is sent as usual; otherwise it is only kept.
+//! Replays start at a keyframe and go out on a reliable stream, faster than
+//! real time, until they reach the newest payload and playback is live again.
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use crate::config::TimeShiftConfig;
+use crate::control::Event;
+use crate::memory::{SessionMemory, Usage};
+use crate::protocol::Framing;
+use crate::transport::DatagramSender;
+
+/// Replays must outpace live media or they would never catch up
+const MIN_CATCHUP_SPEED: f64 = 1.1;
+/// Rejoining live waits this long at most for a keyframe, for codecs whose
+/// keyframes the proxy cannot spot
+const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the browser's playback is, as reported in timeshift events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    Live,
+    Paused,
+    /// Replaying the buffer, catching up with live
+    Playing,
+}
+
+impl PlaybackState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlaybackState::Live => "live",
+            PlaybackState::Paused => "paused",
+            PlaybackState::Playing => "playing",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Buffered {
+    at: Instant,
+    seq: u64,
+    keyframe: bool,
+    payload: Bytes,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Live,
+    /// Live media is held back while the browser shows the picture from `at`
+    Paused { at: Instant },
+    /// A replay task is sending the buffer; `position` is its last payload
+    Replaying { position: Instant, cancel: CancellationToken },
+    /// Back to live from the next keyframe
+    Joining { until: Instant },
+}
+
+/// The video track of the session, when it can be exported
+#[derive(Debug, Clone)]
+pub struct ExportTrack {
+    pub channel_id: u8,
+    /// H.264 SPS and PPS from the SDP, for streams that only send them out of band
+    pub parameter_sets: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct State {
+    packets: VecDeque<Buffered>,
+    bytes: usize,
+    next_seq: u64,
+    mode: Mode,
+    shed_seen: u64,
+    video: Option<ExportTrack>,
+}
+
+impl State {
+    /// Where the browser's picture is now
+    fn position(&self, now: Instant) -> Instant {
+        match self.mode {
+            Mode::Live | Mode::Joining { .. } => now,
+            Mode::Paused { at } => at,
+            Mode::Replaying { position, .. } => position,
+        }
+    }
+
+    /// Stop any running replay
+    fn stop_replay(&mut self) {
+        if let Mode::Replaying { cancel, .. } = &self.mode {
+            cancel.cancel();
+        }
+    }
+
+    /// First payload of a replay from `from`: the keyframe at or before it,
+    /// else the oldest keyframe, else the oldest payload
+    fn start_seq(&self, from: Instant) -> Option<u64> {
+        self.packets
+            .iter()
+            .rev()
+            .find(|p| p.keyframe && p.at <= from)
+            .or_else(|| self.packets.iter().find(|p| p.keyframe))
+            .or(self.packets.front())
+            .map(|p| p.seq)
+    }
+
+    fn get(&self, seq: u64) -> Option<&Buffered> {
+        let front = self.packets.front()?.seq;
+        self.packets.get(seq.saturating_sub(front) as usize)
+    }
+}
+
+/// One session's time-shift buffer
+#[derive(Debug)]
+pub struct TimeShift {
+    framing: Framing,
+    window: Duration,
+    max_bytes: usize,
+    speed: f64,
+    notify: mpsc::Sender<Event>,
+    memory: Arc<SessionMemory>,
+    state: Mutex<State>,
+}
+
+impl TimeShift {
+    pub fn new(config: &TimeShiftConfig, framing: Framing, notify: mpsc::Sender<Event>, memory: Arc<SessionMemory>) -> Arc<Self> {
+        Arc::new(Self {
+            framing,
+            window: Duration::from_secs(config.seconds),
+            max_bytes: (config.max_mb * 1024 * 1024) as usize,
+            speed: config.catchup_speed.max(MIN_CATCHUP_SPEED),
+            notify,
+            memory,
+            state: Mutex::new(State { packets: VecDeque::new(), bytes: 0, next_seq: 0, mode: Mode::Live, shed_seen: 0, video: None }),
+        })
+    }
+
+    /// Keep one framed media payload; whether it should also go out live
+    pub fn record(&self, payload: &Bytes) -> bool {
+        let now = Instant::now();
+        let packet = self.framing.split(payload).map_or(&[][..], |(_, packet)| packet);
+        // RTCP (payload types 200-204) never starts a picture
+        let keyframe = packet.get(1).is_some_and(|pt| !(200..=204).contains(pt)) && crate::rtp::is_keyframe_start(packet);
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if self.memory.shed_requested(&mut state.shed_seen) && !state.packets.is_empty() {
+            warn!("Dropping the time-shift buffer to free memory");
+            state.packets.clear();
+            state.bytes = 0;
+            if !matches!(state.mode, Mode::Live) {
+                state.stop_replay();
+                state.mode = Mode::Joining { until: now + JOIN_TIMEOUT };
+                self.notify(PlaybackState::Live, Duration::ZERO);
+            }
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.bytes += payload.len();
+        state.packets.push_back(Buffered { at: now, seq, keyframe, payload: payload.clone() });
+        while let Some(oldest) = state.packets.front()
+            && (now.duration_since(oldest.at) > self.window || state.bytes > self.max_bytes)
+        {
+            state.bytes -= oldest.payload.len();
+            state.packets.pop_front();
+        }
+        self.memory.set(Usage::TimeShift, state.bytes);
+
+        match state.mode {
+            Mode::Live => true,
+            Mode::Joining { until } if keyframe || now >= until => {
+                state.mode = Mode::Live;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Mark the H.264 video track, whose media can be exported
+    pub fn set_video(&self, track: ExportTrack) {
+        self.state.lock().unwrap().video.get_or_insert(track);
+    }
+
+    /// The video track and every payload recorded in the last `span`
+    pub fn snapshot(&self, span: Duration) -> (Option<ExportTrack>, Vec<Bytes>) {
+        let state = self.state.lock().unwrap();
+        let since = Instant::now().checked_sub(span);
+        let payloads = state
+            .packets
+            .iter()
+            .filter(|p| since.is_none_or(|since| p.at >= since))
+            .map(|p| p.payload.clone())
+            .collect();
+        (state.video.clone(), payloads)
+    }
+
+    /// Hold the picture where it is
+    pub fn pause(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.mode, Mode::Paused { .. }) {
+            return;
+        }
+        let at = state.position(now);
+        state.stop_replay();
+        state.mode = Mode::Paused { at };
+        info!("Time-shift paused");
+        self.notify(PlaybackState::Paused, now.duration_since(at));
+    }
+
+    /// Replay from `by` behind the current position
+    pub fn rewind(self: &Arc<Self>, by: Duration, sender: DatagramSender, token: &CancellationToken) {
+        let now = Instant::now();
+        let position = self.state.lock().unwrap().position(now);
+        let from = position.checked_sub(by).unwrap_or(position);
+        self.replay(from, sender, token);
+    }
+
+    /// Continue from where playback was paused
+    pub fn resume(self: &Arc<Self>, sender: DatagramSender, token: &CancellationToken) {
+        let Mode::Paused { at } = self.state.lock().unwrap().mode else {
+            return;
+        };
+        self.replay(at, sender, token);
+    }
+
+    /// Go back to live from the next keyframe
+    pub fn live(&self) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.mode, Mode::Live | Mode::Joining { .. }) {
+            return;
+        }
+        state.stop_replay();
+        state.mode = Mode::Joining { until: Instant::now() + JOIN_TIMEOUT };
+        info!("Time-shift back to live");
+        self.notify(PlaybackState::Live, Duration::ZERO);
+    }
+
+    fn replay(self: &Arc<Self>, from: Instant, sender: DatagramSender, token: &CancellationToken) {
+        let now = Instant::now();
+        let cancel = token.child_token();
+        let start = {
+            let mut state = self.state.lock().unwrap();
+            state.stop_replay();
+            let Some(seq) = state.start_seq(from) else {
+                // Nothing buffered yet
+                state.mode = Mode::Joining { until: now + JOIN_TIMEOUT };
+                drop(state);
+                self.notify(PlaybackState::Live, Duration::ZERO);
+                return;
+            };
+            let position = state.get(seq).expect("start is buffered").at;
+            state.mode = Mode::Replaying { position, cancel: cancel.clone() };
+            info!("Time-shift replaying from {:?} behind live", now.duration_since(position));
+            self.notify(PlaybackState::Playing, now.duration_since(position));
+            seq
+        };
+        tokio::spawn(self.clone().run_replay(start, sender, cancel));
+    }
+
+    async fn run_replay(self: Arc<Self>, mut seq: u64, sender: DatagramSender, cancel: CancellationToken) {
+        let mut stream = match sender.open_stream().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to open a time-shift stream: {:#}", e);
+                self.live();
+                return;
+            }
+        };
+        let started = Instant::now();
+        let mut origin: Option<Instant> = None;
+        loop {
+            let packet = {
+                let mut state = self.state.lock().unwrap();
+                if cancel.is_cancelled() {
+                    return;
+                }
+                let packet = state.get(seq).cloned();
+                match (&packet, &mut state.mode) {
+                    (Some(packet), Mode::Replaying { position, .. }) => *position = packet.at,
+                    // Caught up: payloads recorded from now on go out live
+                    (None, mode) => *mode = Mode::Live,
+                    _ => {}
+                }
+                packet
+            };
+            let Some(packet) = packet else {
+                info!("Time-shift caught up with live");
+                self.notify(PlaybackState::Live, Duration::ZERO);
+                return;
+            };
+
+            // Keep the buffered pacing, sped up
+            let origin = *origin.get_or_insert(packet.at);
+            let due = started + packet.at.duration_since(origin).div_f64(self.speed);
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep_until(due.into()) => {}
+            }
+            if let Err(e) = stream.send(&packet.payload).await {
+                warn!("Time-shift stream failed: {:#}", e);
+                self.live();
+                return;
+            }
+            // Skips ahead if the buffer was trimmed under the replay
+            seq = packet.seq + 1;
+        }
+    }
+
+    fn notify(&self, state: PlaybackState, behind: Duration) {
+        let _ = self.notify.try_send(Event::TimeShift { state, behind });
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gst::prelude::*;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use crate::adaptive::{CLEAN_LOSS, CONGESTED_LOSS, DOWNGRADE_AFTER, UPGRADE_AFTER};
+use crate::config::{RtpConfig, TranscodeRule};
+use crate::control::Event;
+use crate::drops::DropReason;
+use crate::feedback::Feedback;
+use crate::memory::{SessionMemory, Usage};
+use crate::rtp::{Continuity, RtpRewriter};
+use crate::sdp::MediaDescription;
+use crate::stats::{self, GaugeGuard};
+use crate::transport::DatagramSender;
+
+/// MTU for re-packetized output when the client has no datagram size limit
+const DEFAULT_OUTPUT_MTU: u32 = 1200;
+/// Bounds on the MTU derived from the client's datagram size limit
+const MIN_OUTPUT_MTU: u32 = 256;
+const MAX_OUTPUT_MTU: u32 = 1400;
+/// Largest media framing header (RFC 4571 framing)
+const MAX_FRAMING_HEADER: usize = 3;
+/// Output RTP packets buffered between the appsink and the forwarder
+const OUTPUT_QUEUE: usize = 512;
+/// Keyframe interval without a keyframe rule: 2s at 30fps
+const DEFAULT_GOP_FRAMES: u32 = 60;
+/// Frame rate assumed when the SDP has no `a=framerate`
+const DEFAULT_FRAMERATE: f64 = 30.0;
+
+/// Depayloader chain for an input encoding, or None to let decodebin pick one
+fn depayloader(encoding: &str) -> Option<&'static str> {
+    match encoding.to_ascii_uppercase().as_str() {
+        "H264" => Some("rtph264depay ! h264parse"),
+        "H265" => Some("rtph265depay ! h265parse"),
+        "JPEG" => Some("rtpjpegdepay"),
+        "MP4V-ES" => Some("rtpmp4vdepay"),
+        _ => None,
+    }
+}
+
+/// H.264 encoders in order of preference; hardware first, x264 as the fallback
+const ENCODERS: &[Encoder] = &[
+    Encoder { element: Cow::Borrowed("nvh264enc"), kind: EncoderKind::Nvenc },
+    Encoder { element: Cow::Borrowed("vah264enc"), kind: EncoderKind::Vaapi },
+    Encoder { element: Cow::Borrowed("vaapih264enc"), kind: EncoderKind::Vaapi },
+    Encoder { element: Cow::Borrowed("v4l2h264enc"), kind: EncoderKind::V4l2 },
+    Encoder { element: Cow::Borrowed("x264enc"), kind: EncoderKind::Software },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EncoderKind {
+    Nvenc,
+    Vaapi,
+    V4l2,
+    Software,
+    /// An element named in the config, with the properties given there
+    Custom,
+}
+
+#[derive(Debug, Clone)]
+struct Encoder {
+    /// Element name; for [`EncoderKind::Custom`], followed by its properties
+    element: Cow<'static, str>,
+    kind: EncoderKind,
+}
+
+impl Encoder {
+    /// GStreamer factory name, without properties
+    fn name(&self) -> &str {
+        self.element.split_whitespace().next().unwrap_or_default()
+    }
+
+    /// Low-latency element settings for a target bitrate and keyframe interval
+    /// in frames; a custom element keeps the properties it was given
+    fn launch_fragment(&self, bitrate_kbps: u32, gop_frames: u32) -> String {
+        match (self.element.as_ref(), self.kind) {
+            (_, EncoderKind::Nvenc) => format!("{} preset=low-latency-hq zerolatency=true bitrate={} gop-size={}", self.element, bitrate_kbps, gop_frames),
+            ("vah264enc", _) => format!("vah264enc bitrate={} key-int-max={} b-frames=0", bitrate_kbps, gop_frames),
+            (_, EncoderKind::Vaapi) => format!("{} rate-control=cbr bitrate={} keyframe-period={} max-bframes=0", self.element, bitrate_kbps, gop_frames),
+            (_, EncoderKind::V4l2) => format!(
+                "{} extra-controls=\"controls,video_bitrate={},h264_i_frame_period={}\"",
+                self.element,
+                bitrate_kbps * 1000,
+                gop_frames
+            ),
+            (_, EncoderKind::Software) => format!(
+                "{} tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={} byte-stream=true",
+                self.element, bitrate_kbps, gop_frames
+            ),
+            (_, EncoderKind::Custom) => self.element.to_string(),
+        }
+    }
+}
+
+/// Encoders to try for a rule: "auto" walks the preference list, "software"
+/// forces x264, a listed element gets its low-latency settings, and any other
+/// value is used as given, as a GStreamer element with optional properties
+fn encoder_candidates(choice: &str) -> Vec<Encoder> {
+    let installed = |e: &&Encoder| gst::ElementFactory::find(e.name()).is_some();
+    match choice.trim() {
+        "auto" => ENCODERS.iter().filter(installed).cloned().collect(),
+        "software" => ENCODERS.iter().filter(|e| e.kind == EncoderKind::Software).cloned().collect(),
+        element => match ENCODERS.iter().find(|e| e.element == element) {
+            Some(encoder) => vec![encoder.clone()],
+            None => vec![Encoder { element: Cow::Owned(element.to_string()), kind: EncoderKind::Custom }],
+        },
+    }
+}
+
+/// Size and bitrate of one encoder branch
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    size: Option<(u32, u32)>,
+    bitrate_kbps: u32,
+}
+
+/// Encoder branches for a rule: one per rendition, or the rule's own size
+fn outputs(rule: &TranscodeRule) -> Vec<Output> {
+    if rule.renditions.is_empty() {
+        let size = rule.width.zip(rule.height);
+        return vec![Output { size, bitrate_kbps: rule.bitrate_kbps }];
+    }
+    rule.renditions
+        .iter()
+        .map(|r| Output { size: Some((r.width, r.height)), bitrate_kbps: r.bitrate_kbps })
+        .collect()
+}
+
+/// GStreamer pipeline re-encoding one RTP video track to H.264 constrained baseline.
+///
+/// RTP from the camera is pushed into an appsrc; re-packetized RTP is pulled
+/// from one appsink per output and handed back through a channel, tagged with
+/// the output's index.
+pub struct Transcoder {
+    pipeline: gst::Pipeline,
+    appsrc: gst_app::AppSrc,
+    _active: GaugeGuard,
+}
+
+impl Transcoder {
+    /// Build a pipeline with the best available encoder, falling back down the
+    /// preference list when an encoder fails to start. Keyframes come every
+    /// `keyframe_interval` at the input's frame rate, or every 60 frames.
+    pub fn new(input: &MediaDescription, rule: &TranscodeRule, keyframe_interval: Option<Duration>, mtu: u32, output: mpsc::Sender<(usize, Bytes)>) -> Result<Self> {
+        gst::init()?;
+
+        let gop_frames = keyframe_interval.map_or(DEFAULT_GOP_FRAMES, |interval| {
+            let framerate = input.framerate.filter(|&fps| fps > 0.0).unwrap_or(DEFAULT_FRAMERATE);
+            ((framerate * interval.as_secs_f64()) as u32).max(1)
+        });
+        let mut last_error = None;
+        for encoder in encoder_candidates(&rule.encoder) {
+            match Self::build(input, rule, &encoder, gop_frames, mtu, output.clone()) {
+                Ok(transcoder) => return Ok(transcoder),
+                Err(e) => {
+                    warn!("Encoder {} failed to start, trying next: {:#}", encoder.name(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No usable H.264 encoder for '{}'", rule.encoder)))
+    }
+
+    fn build(input: &MediaDescription, rule: &TranscodeRule, encoder: &Encoder, gop_frames: u32, mtu: u32, output: mpsc::Sender<(usize, Bytes)>) -> Result<Self> {
+        let encoding = input.encoding.as_deref().unwrap_or("H264");
+        let payload_type = input.payload_type.unwrap_or(96);
+        let clock_rate = input.clock_rate.unwrap_or(90000);
+
+        let depay = match depayloader(encoding) {
+            Some(depay) => format!("{} ! decodebin", depay),
+            None => "decodebin".to_string(),
+        };
+        let outputs = outputs(rule);
+        let branch = |index: usize, output: &Output| {
+            let scale = match output.size {
+                Some((w, h)) => format!("videoscale ! video/x-raw,width={},height={} ! ", w, h),
+                None => String::new(),
+            };
+            format!(
+                "{scale}{encoder} \
+                 ! video/x-h264,profile=constrained-baseline ! h264parse \
+                 ! rtph264pay config-interval=-1 pt={payload_type} mtu={mtu} \
+                 ! appsink name=sink{index} sync=false",
+                encoder = encoder.launch_fragment(output.bitrate_kbps, gop_frames),
+            )
+        };
+        // Several outputs share one decode through a tee; a slow branch drops
+        // frames rather than stalling the others
+        let encode = match outputs.as_slice() {
+            [output] => branch(0, output),
+            _ => outputs.iter().enumerate().fold("tee name=t".to_string(), |mut chain, (i, output)| {
+                chain.push_str(&format!(" t. ! queue leaky=downstream max-size-buffers=2 ! {}", branch(i, output)));
+                chain
+            }),
+        };
+        let description = format!(
+            "appsrc name=src is-live=true format=time do-timestamp=true \
+             caps=\"application/x-rtp,media=video,clock-rate={clock_rate},encoding-name={encoding},payload={payload_type}\" \
+             ! rtpjitterbuffer latency=50 ! {depay} ! videoconvert ! {encode}",
+            encoding = encoding.to_ascii_uppercase(),
+        );
+
+        let pipeline = gst::parse::launch(&description)
+            .context("Failed to build transcode pipeline")?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow!("Transcode description is not a pipeline"))?;
+
+        let appsrc = pipeline
+            .by_name("src")
+            .and_then(|e| e.dynamic_cast::<gst_app::AppSrc>().ok())
+            .ok_or_else(|| anyhow!("Transcode pipeline has no appsrc"))?;
+        for index in 0..outputs.len() {
+            let appsink = pipeline
+                .by_name(&format!("sink{}", index))
+                .and_then(|e| e.dynamic_cast::<gst_app::AppSink>().ok())
+                .ok_or_else(|| anyhow!("Transcode pipeline has no appsink for output {}", index))?;
+            let output = output.clone();
+            appsink.set_callbacks(
+                gst_app::AppSinkCallbacks::builder()
+                    .new_sample(move |sink| {
+                        let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                        let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                        // Drop output rather than stall the pipeline if the browser can't keep up
+                        if output.try_send((index, Bytes::copy_from_slice(&map))).is_err() {
+                            warn!("Transcode output queue full, dropping packet");
+                            crate::drops::record(DropReason::QueueFull, None, map.len());
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+        }
+
+        if let Err(e) = pipeline.set_state(gst::State::Playing) {
+            let _ = pipeline.set_state(gst::State::Null);
+            return Err(anyhow!("Pipeline failed to start: {}", e));
+        }
+        let rates: Vec<String> = outputs.iter().map(|o| o.bitrate_kbps.to_string()).collect();
+        info!(
+            "Transcoding {} video to H.264 at {} kbps with {} ({:?}), {}-byte packets",
+            encoding, rates.join("/"), encoder.name(), encoder.kind, mtu
+        );
+
+        let active = stats::global().track(format!("transcoders_active{{encoder=\"{}\"}}", encoder.name()));
+        Ok(Self { pipeline, appsrc, _active: active })
+    }
+
+    pub fn push(&self, packet: &[u8]) -> Result<()> {
+        self.appsrc
+            .push_buffer(gst::Buffer::from_slice(packet.to_vec()))
+            .map_err(|e| anyhow!("Transcode pipeline rejected input: {:?}", e))?;
+        Ok(())
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// Decides which rendition of a multi-rendition transcode reaches the browser.
+///
+/// As with the adaptive profile gate, the old rendition keeps flowing until
+/// the requested one delivers a keyframe.
+#[derive(Debug)]
+pub struct RenditionGate {
+    names: Vec<String>,
+    bitrates_kbps: Vec<u32>,
+    desired: AtomicUsize,
+    active: AtomicUsize,
+    /// The client picked a rendition, so feedback does not move it
+    pinned: bool,
+    notify: mpsc::Sender<Event>,
+}
+
+impl RenditionGate {
+    /// Start on the rendition the client picked, or the largest
+    pub fn new(rule: &TranscodeRule, picked: Option<&str>, notify: mpsc::Sender<Event>) -> Self {
+        let names: Vec<String> = rule.renditions.iter().map(|r| r.name.clone()).collect();
+        let bitrates_kbps = rule.renditions.iter().map(|r| r.bitrate_kbps).collect();
+        let picked = picked.and_then(|name| names.iter().position(|n| n == name));
+        let start = picked.unwrap_or(0);
+        Self { names, bitrates_kbps, desired: AtomicUsize::new(start), active: AtomicUsize::new(start), pinned: picked.is_some(), notify }
+    }
+
+    pub fn pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn request(&self, index: usize) {
+        self.desired.store(index, Ordering::Relaxed);
+    }
+
+    fn admit(&self, index: usize, packet: &[u8]) -> bool {
+        let active = self.active.load(Ordering::Relaxed);
+        if index == active {
+            return true;
+        }
+        if index != self.desired.load(Ordering::Relaxed) || !crate::rtp::is_keyframe_start(packet) {
+            return false;
+        }
+        info!("Switching video to the {} rendition at keyframe", self.names[index]);
+        self.active.store(index, Ordering::Relaxed);
+        let _ = self.notify.try_send(Event::Rendition(self.names[index].clone()));
+        true
+    }
+}
+
+/// Steps through renditions on client feedback: one step smaller under
+/// sustained loss, one step larger after a long clean run. With a bandwidth
+/// estimate, a rendition above it counts as congested, and only a clean run
+/// with room for the next larger one steps up.
+#[derive(Debug)]
+pub struct RenditionMonitor {
+    current: usize,
+    count: usize,
+    bitrates_kbps: Vec<u32>,
+    congested_streak: u32,
+    clean_streak: u32,
+}
+
+impl RenditionMonitor {
+    pub fn new(gate: &RenditionGate) -> Self {
+        Self {
+            current: gate.active.load(Ordering::Relaxed),
+            count: gate.names.len(),
+            bitrates_kbps: gate.bitrates_kbps.clone(),
+            congested_streak: 0,
+            clean_streak: 0,
+        }
+    }
+
+    /// Returns the rendition to switch to when conditions have been sustained long enough
+    pub fn on_feedback(&mut self, fb: &Feedback) -> Option<usize> {
+        let over_estimate = fb.estimate_kbps.is_some_and(|estimate| self.bitrates_kbps[self.current] > estimate);
+        let room_above = match (fb.estimate_kbps, self.current.checked_sub(1)) {
+            (Some(estimate), Some(larger)) => self.bitrates_kbps[larger] <= estimate,
+            _ => true,
+        };
+        if fb.loss_fraction >= CONGESTED_LOSS || over_estimate {
+            self.congested_streak += 1;
+            self.clean_streak = 0;
+        } else if fb.loss_fraction <= CLEAN_LOSS && room_above {
+            self.clean_streak += 1;
+            self.congested_streak = 0;
+        } else {
+            self.congested_streak = 0;
+            self.clean_streak = 0;
+        }
+
+        let next = if self.congested_streak >= DOWNGRADE_AFTER && self.current + 1 < self.count {
+            self.current + 1
+        } else if self.clean_streak >= UPGRADE_AFTER && self.current > 0 {
+            self.current - 1
+        } else {
+            return None;
+        };
+        self.current = next;
+        self.congested_streak = 0;
+        self.clean_streak = 0;
+        Some(next)
+    }
+}
+
+/// Like `forward_udp`, but re-encodes the RTP stream before sending it on
+/// `channel_id`; with renditions, only the one `renditions` admits is sent
+#[allow(clippy::too_many_arguments)]
+pub async fn forward_transcoded(
+    socket: Arc<UdpSocket>,
+    sender: DatagramSender,
+    channel_id: u8,
+    input: MediaDescription,
+    rule: TranscodeRule,
+    keyframe_interval: Option<Duration>,
+    renditions: Option<Arc<RenditionGate>>,
+    memory: Arc<SessionMemory>,
+    token: CancellationToken,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(OUTPUT_QUEUE);
+    let mtu = output_mtu(sender.max_datagram_size());
+    let transcoder = Transcoder::new(&input, &rule, keyframe_interval, mtu, tx)?;
+    // Each rendition has its own sequence and timestamps; the browser sees one stream
+    let mut rewriters: Vec<RtpRewriter> = match &renditions {
+        Some(gate) => {
+            let continuity = Continuity::new(90000);
+            let ssrc = rand::random();
+            (0..gate.names.len())
+                .map(|i| RtpRewriter::continuous(&RtpConfig::default(), ssrc, false, continuity.clone(), i as u8))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let mut buf = vec![0u8; crate::proxy::MAX_UDP_PACKET];
+    let _hold = memory.hold(Usage::Receive, buf.len());
+    let mut shed_seen = 0;
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            res = socket.recv_from(&mut buf) => {
+                let (n, _) = match res {
+                    Err(e) if crate::udp::is_send_error(&e) => continue,
+                    res => res.map_err(|e| anyhow!("UDP recv error: {}", e))?,
+                };
+                if let Err(e) = transcoder.push(&buf[..n]) {
+                    error!("{}", e);
+                }
+            }
+            Some((index, packet)) = rx.recv() => {
+                // Queued output, at most one MTU per packet
+                memory.set(Usage::SendQueue, rx.len() * mtu as usize);
+                if memory.shed_requested(&mut shed_seen) {
+                    let mut dropped = 0;
+                    while rx.try_recv().is_ok() {
+                        dropped += 1;
+                    }
+                    warn!("Dropped {} queued transcoder packets to free memory", dropped);
+                    memory.set(Usage::SendQueue, 0);
+                }
+                if let Some(gate) = &renditions
+                    && !gate.admit(index, &packet)
+                {
+                    continue;
+                }
+                let framing = sender.framing();
+                let mut payload = bytes::BytesMut::with_capacity(packet.len() + 3);
+                framing.begin(channel_id, &mut payload);
+                match rewriters.get_mut(index) {
+                    Some(rewriter) => rewriter.rewrite_into(&packet, &mut payload),
+                    None => payload.extend_from_slice(&packet),
+                }
+                framing.finish(&mut payload);
+
+                if let Err(e) = sender.send_datagram(payload.freeze()).await {
+                    return Err(anyhow!("Failed to send datagram: {}", e));
+                }
+            }
+        }
+    }
+}
+
+/// Packet size for the transcoder's output so every packet, framed, fits in
+/// one of the client's datagrams
+fn output_mtu(max_datagram_size: Option<usize>) -> u32 {
+    match max_datagram_size {
+        Some(max) => (max.saturating_sub(MAX_FRAMING_HEADER) as u32).clamp(MIN_OUTPUT_MTU, MAX_OUTPUT_MTU),
+        None => DEFAULT_OUTPUT_MTU,
+    }
+}
+
+/// Rewrite the SDP so the video track advertises the transcoder's H.264 output
+pub fn rewrite_sdp(sdp: &str, video: &MediaDescription) -> String {
+    let Some(pt) = video.payload_type else {
+        return sdp.to_string();
+    };
+    let rtpmap = format!("a=rtpmap:{} ", pt);
+    let fmtp = format!("a=fmtp:{} ", pt);
+
+    let mut out = String::with_capacity(sdp.len());
+    let mut in_video = false;
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            in_video = line.starts_with("m=video");
+        }
+        if in_video && line.starts_with(&rtpmap) {
+            out.push_str(&format!("{}H264/90000\r\n", rtpmap));
+            out.push_str(&format!("{}packetization-mode=1;profile-level-id=42e01f\r\n", fmtp));
+            continue;
+        }
+        if in_video && line.starts_with(&fmtp) {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    out
+}
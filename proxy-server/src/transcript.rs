@@ -0,0 +1,102 @@
+//! Debug transcripts of the RTSP exchange between proxy and camera, one file
+//! per session, with credentials removed
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use crate::config::TranscriptConfig;
+
+/// Headers whose values are replaced, keeping only the auth scheme
+const SECRET_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization"];
+
+pub struct Transcript {
+    started: Instant,
+    file: Mutex<File>,
+}
+
+impl Transcript {
+    /// Start a transcript for a session if the config asks for one for this URL
+    pub fn open(config: &TranscriptConfig, session_id: &str, rtsp_url: &str) -> Option<Self> {
+        if !config.url_prefixes.is_empty() && !config.url_prefixes.iter().any(|prefix| rtsp_url.starts_with(prefix)) {
+            return None;
+        }
+        let unix_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let path = config.dir.join(format!("{}-{}.rtsp.txt", unix_ms, session_id));
+        let file = std::fs::create_dir_all(&config.dir)
+            .and_then(|_| OpenOptions::new().create_new(true).write(true).open(&path));
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Cannot create RTSP transcript {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let _ = writeln!(file, "# {} session {}\n", redact_url(rtsp_url), session_id);
+        info!("Recording RTSP transcript to {}", path.display());
+        Some(Self { started: Instant::now(), file: Mutex::new(file) })
+    }
+
+    /// A message the proxy sent to the camera
+    pub fn sent(&self, message: &[u8]) {
+        self.append("proxy -> camera", message);
+    }
+
+    /// A message received from the camera, as the camera sent it
+    pub fn received(&self, message: &[u8]) {
+        self.append("camera -> proxy", message);
+    }
+
+    fn append(&self, direction: &str, message: &[u8]) {
+        let entry = format!(
+            "--- +{:.3}s {}\n{}\n",
+            self.started.elapsed().as_secs_f64(),
+            direction,
+            sanitize(&String::from_utf8_lossy(message))
+        );
+        if let Err(e) = self.file.lock().unwrap().write_all(entry.as_bytes()) {
+            warn!("Failed to write RTSP transcript: {}", e);
+        }
+    }
+}
+
+/// Remove credentials from an RTSP message: auth header values and URL
+/// passwords. The body is kept as-is.
+fn sanitize(message: &str) -> String {
+    let (head, body) = match message.find("\r\n\r\n") {
+        Some(end) => message.split_at(end + 4),
+        None => (message, ""),
+    };
+    let mut out = String::with_capacity(message.len());
+    for (i, line) in head.split("\r\n").enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        match line.split_once(':') {
+            Some((name, value)) if SECRET_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name.trim())) => {
+                let scheme = value.split_whitespace().next().unwrap_or_default();
+                out.push_str(&format!("{}: {} <redacted>", name, scheme));
+            }
+            _ if i == 0 => out.push_str(&redact_url(line)),
+            _ => out.push_str(line),
+        }
+    }
+    out.push_str(&body.replace("\r\n", "\n"));
+    out
+}
+
+/// Replace the password of any `scheme://user:password@` in `text`
+fn redact_url(text: &str) -> String {
+    let Some(start) = text.find("://").map(|i| i + 3) else {
+        return text.to_string();
+    };
+    let authority_end = text[start..].find(['/', ' ']).map_or(text.len(), |i| start + i);
+    let Some(at) = text[start..authority_end].rfind('@').map(|i| start + i) else {
+        return text.to_string();
+    };
+    match text[start..at].find(':') {
+        Some(colon) => format!("{}:<redacted>{}", &text[..start + colon], &text[at..]),
+        None => text.to_string(),
+    }
+}
@@ -1,140 +1,983 @@
-use anyhow::Result;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+#[cfg(feature = "websocket")]
+use futures_util::stream::{SplitSink, SplitStream};
+#[cfg(feature = "websocket")]
 use futures_util::{SinkExt, StreamExt};
+use std::io::Write;
+#[cfg(feature = "websocket")]
+use std::net::SocketAddr;
+#[cfg(feature = "websocket")]
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "webtransport")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "websocket")]
+use std::task::Poll;
+use std::time::Duration;
+#[cfg(feature = "webtransport")]
 use tokio::io::AsyncReadExt;
-use tokio::sync::Mutex;
+#[cfg(feature = "websocket")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "websocket")]
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+#[cfg(feature = "websocket")]
 use tokio_tungstenite::tungstenite::Message;
+#[cfg(feature = "webtransport")]
 use wtransport::Connection;
+#[cfg(feature = "webtransport")]
+use wtransport::error::SendDatagramError;
+#[cfg(any(feature = "webtransport", feature = "websocket"))]
+use crate::drops::DropReason;
+use crate::error::{Error, Result};
+use crate::protocol::Framing;
+use crate::tcp::{TcpLink, TcpWriter, FRAME_COMPRESSED, FRAME_CONTROL, FRAME_MEDIA, FRAME_OPEN};
+use crate::timeshift::TimeShift;
 
+/// How RTP packets are delivered to the browser
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// One packet per unreliable datagram (or WebSocket binary message)
+    Datagram,
+    /// Length-prefixed packets on a unidirectional WebTransport stream
+    #[cfg_attr(not(feature = "webtransport"), allow(dead_code))]
+    Stream,
+}
+
+impl DeliveryMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryMode::Datagram => "datagram",
+            DeliveryMode::Stream => "stream",
+        }
+    }
+}
+
+/// Compression of proxy → browser control messages, requested by the client
+/// with the `compress` query parameter.
+///
+/// Each message is compressed on its own and sent as `[u32 length][data]` on
+/// the WebTransport control stream, or as a `COMPRESSED` frame on TCP.
+/// WebSocket control sockets use `permessage-deflate` instead
+/// ([`crate::ws_deflate`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw DEFLATE (RFC 1951), decodable with `DecompressionStream('deflate-raw')`
+    Deflate,
+    /// One zstd frame per message; needs the `zstd` feature
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "deflate" => Some(Compression::Deflate),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        }
+    }
+}
+
+/// WebTransport media path shared by all forwarders of a session.
+///
+/// Packets go out as QUIC datagrams unless the peer does not support them,
+/// in which case they are framed as `[u16 length][packet]` on a single
+/// unidirectional stream that is opened on first use.
+#[cfg(feature = "webtransport")]
 #[derive(Debug)]
-pub enum TransportType {
-    WebTransport(
-        Arc<Connection>,
-        wtransport::SendStream,
-        wtransport::RecvStream,
-    ),
-    WebSocket {
-        control: Arc<Mutex<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>>>,
-        data: Arc<Mutex<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>>>,
-    },
-}
-
-/// Abstract transport for RTSP/RTP
+pub struct WtMediaPath {
+    conn: Arc<Connection>,
+    stream_mode: AtomicBool,
+    fallback: Mutex<Option<wtransport::SendStream>>,
+    /// Whether an oversized datagram has been reported yet
+    warned_too_large: AtomicBool,
+}
+
+#[cfg(feature = "webtransport")]
+impl WtMediaPath {
+    fn new(conn: Arc<Connection>) -> Self {
+        // max_datagram_size is None when datagrams are disabled or unsupported by the peer
+        let stream_mode = conn.max_datagram_size().is_none();
+        if stream_mode {
+            tracing::warn!("Peer does not support datagrams, delivering RTP over a unidirectional stream");
+        }
+        Self {
+            conn,
+            stream_mode: AtomicBool::new(stream_mode),
+            fallback: Mutex::new(None),
+            warned_too_large: AtomicBool::new(false),
+        }
+    }
+
+    /// Largest payload a datagram can carry right now; None in stream mode,
+    /// where packets up to 64 KiB fit
+    fn max_datagram_size(&self) -> Option<usize> {
+        if self.stream_mode.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.conn.max_datagram_size()
+    }
+
+    /// The rate QUIC's congestion controller allows: one window per round trip
+    fn quic_estimate_kbps(&self) -> u32 {
+        let path = self.conn.quic_connection().stats().path;
+        let rtt_us = (path.rtt.as_micros() as u64).max(1);
+        u32::try_from(path.cwnd.saturating_mul(8_000) / rtt_us).unwrap_or(u32::MAX)
+    }
+
+    fn mode(&self) -> DeliveryMode {
+        if self.stream_mode.load(Ordering::Relaxed) {
+            DeliveryMode::Stream
+        } else {
+            DeliveryMode::Datagram
+        }
+    }
+
+    async fn send(&self, payload: Bytes, framing: Framing) -> Result<()> {
+        if !self.stream_mode.load(Ordering::Relaxed) {
+            match self.conn.send_datagram(payload.clone()) {
+                Ok(()) => return Ok(()),
+                Err(SendDatagramError::UnsupportedByPeer) => {
+                    tracing::warn!("Datagram send unsupported by peer, falling back to stream delivery");
+                    self.stream_mode.store(true, Ordering::Relaxed);
+                }
+                // Losing one packet is better than ending the track
+                Err(SendDatagramError::TooLarge) => {
+                    crate::stats::global().add("datagrams_too_large", 1);
+                    crate::drops::record(DropReason::TooLarge, framing.split(&payload).map(|(channel, _)| channel), payload.len());
+                    if !self.warned_too_large.swap(true, Ordering::Relaxed) {
+                        tracing::warn!(
+                            "Dropping {}-byte packet larger than the {:?}-byte datagram limit (further drops are only counted)",
+                            payload.len(),
+                            self.conn.max_datagram_size()
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.send_framed(&payload).await
+    }
+
+    async fn send_framed(&self, payload: &[u8]) -> Result<()> {
+        let len = u16::try_from(payload.len())
+            .map_err(|_| Error::Other(anyhow::anyhow!("Packet too large for stream framing: {} bytes", payload.len())))?;
+
+        let mut fallback = self.fallback.lock().await;
+        if fallback.is_none() {
+            let stream = self.conn.open_uni().await?.await?;
+            *fallback = Some(stream);
+        }
+        let stream = fallback.as_mut().expect("fallback stream opened above");
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream.write_all(payload).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "websocket")]
+type WsStream = tokio_tungstenite::WebSocketStream<WsSocket>;
+
+/// A connection on the WebSocket listener, or a TLS one on `[wss]`
+#[cfg(feature = "websocket")]
+#[derive(Debug)]
+pub struct WsSocket {
+    conn: WsConn,
+    /// Set once `permessage-deflate` is negotiated, to inflate what the browser compresses
+    inflater: Option<crate::ws_deflate::Inflater>,
+}
+
+#[cfg(feature = "websocket")]
+#[derive(Debug)]
+enum WsConn {
+    Plain(TcpStream),
+    /// With the bytes already read to tell a handshake from plain HTTP,
+    /// which reads return first
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>, Bytes),
+}
+
+#[cfg(feature = "websocket")]
+impl WsSocket {
+    pub fn plain(stream: TcpStream) -> Self {
+        Self { conn: WsConn::Plain(stream), inflater: None }
+    }
+
+    pub fn tls(stream: tokio_rustls::server::TlsStream<TcpStream>, read: Bytes) -> Self {
+        Self { conn: WsConn::Tls(Box::new(stream), read), inflater: None }
+    }
+
+    pub fn encrypted(&self) -> bool {
+        matches!(self.conn, WsConn::Tls(..))
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match &self.conn {
+            WsConn::Plain(stream) => stream.peer_addr(),
+            WsConn::Tls(stream, _) => stream.get_ref().0.peer_addr(),
+        }
+    }
+
+    /// Inflate the browser's messages from now on; call right after a
+    /// handshake that negotiated `permessage-deflate`
+    pub fn inflate_messages(&mut self) {
+        self.inflater.get_or_insert_default();
+    }
+
+    /// Whether `permessage-deflate` was negotiated
+    pub fn deflate(&self) -> bool {
+        self.inflater.is_some()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl WsConn {
+    fn poll_read(&mut self, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self {
+            WsConn::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            WsConn::Tls(_, unread) if !unread.is_empty() => {
+                let n = unread.len().min(buf.remaining());
+                buf.put_slice(&unread.split_to(n));
+                Poll::Ready(Ok(()))
+            }
+            WsConn::Tls(stream, _) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl AsyncRead for WsSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let Some(inflater) = &mut this.inflater else {
+            return this.conn.poll_read(cx, buf);
+        };
+        loop {
+            let ready = inflater.output();
+            if !ready.is_empty() {
+                let n = ready.len().min(buf.remaining());
+                buf.put_slice(&ready.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            std::task::ready!(this.conn.poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Ok(())); // EOF
+            }
+            inflater.push(read.filled())?;
+        }
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl AsyncWrite for WsSocket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().conn {
+            WsConn::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            WsConn::Tls(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().conn {
+            WsConn::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            WsConn::Tls(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().conn {
+            WsConn::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            WsConn::Tls(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Abstract transport for RTSP/RTP, made of handles that work independently
+/// of each other, so the session's loop can wait for the browser's next
+/// message while control messages and media go out. [`Transport::split`]
+/// hands them out.
 pub struct Transport {
-    inner: TransportType,
+    reader: ControlReader,
+    writer: ControlWriter,
+    sender: DatagramSender,
+    receiver: DatagramReceiver,
+    /// Cancelled once a TCP client hangs up
+    hangup: Option<CancellationToken>,
+}
+
+/// Control messages queued for the browser before writing more has to wait
+const CONTROL_QUEUE: usize = 64;
+/// A browser that takes longer than this to accept one control message, or
+/// to make room in the queue for another, is given up on
+const CONTROL_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long closing waits for queued control messages to go out
+const CONTROL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Browser → proxy half of the control stream
+#[derive(Debug)]
+pub enum ControlReader {
+    #[cfg(feature = "webtransport")]
+    WebTransport(wtransport::RecvStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(SplitStream<WsStream>),
+    /// Nothing the player sends is control; it is read only to notice it leave
+    #[cfg(feature = "websocket")]
+    Player(DatagramReceiver),
+    Tcp(mpsc::Receiver<Bytes>),
+}
+
+impl ControlReader {
+    /// Read next control message (RTSP text); 0 once the browser is gone
+    pub async fn read(&mut self, buf: &mut BytesMut) -> Result<usize> {
+        match self {
+            #[cfg(feature = "webtransport")]
+            ControlReader::WebTransport(recv) => {
+                let n = recv.read_buf(buf).await.map_err(Error::transport)?;
+                Ok(n) // 0 means EOF
+            }
+            #[cfg(feature = "websocket")]
+            ControlReader::WebSocket(ws) => match ws.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    buf.extend_from_slice(text.as_bytes());
+                    Ok(text.len())
+                }
+                Some(Ok(_)) => Ok(0), // Close, and other types are ignored for control
+                Some(Err(e)) => Err(Error::transport(format!("WebSocket error: {}", e))),
+                None => Ok(0), // EOF
+            },
+            #[cfg(feature = "websocket")]
+            ControlReader::Player(receiver) => {
+                while receiver.recv().await?.is_some() {}
+                Ok(0)
+            }
+            ControlReader::Tcp(control) => match control.recv().await {
+                Some(data) => {
+                    buf.extend_from_slice(&data);
+                    Ok(data.len())
+                }
+                None => Ok(0),
+            },
+        }
+    }
+}
+
+/// Proxy → browser half of the control stream. Clones share one queue to a
+/// writer task, so a browser that stops reading its control stream cannot
+/// hold up whoever writes to it.
+#[derive(Clone, Debug)]
+pub struct ControlWriter {
+    /// None when control messages are dropped
+    queue: Option<mpsc::Sender<ControlFrame>>,
+    compression: Option<Compression>,
+}
+
+impl ControlWriter {
+    fn spawn(sink: ControlSink) -> Self {
+        let (tx, rx) = mpsc::channel(CONTROL_QUEUE);
+        tokio::spawn(sink.run(rx));
+        Self { queue: Some(tx), compression: None }
+    }
+
+    /// Queue a control message (RTSP text). Waits only while the queue is
+    /// full; fails once the writer has given up on the browser, or the
+    /// browser has not made room for too long.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let frame = match self.compression {
+            Some(compression) => ControlFrame::Compressed(compression.compress(data)?),
+            None => ControlFrame::Plain(data.to_vec()),
+        };
+        self.send(frame).await
+    }
+
+    async fn send(&self, frame: ControlFrame) -> Result<()> {
+        let Some(queue) = &self.queue else {
+            return Ok(());
+        };
+        match tokio::time::timeout(CONTROL_WRITE_TIMEOUT, queue.send(frame)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(Error::transport("Control stream writer stopped")),
+            Err(_) => {
+                crate::stats::global().add("control_write_timeouts", 1);
+                Err(Error::transport("Control stream stalled"))
+            }
+        }
+    }
+
+    /// Wait, briefly, for everything queued so far to be written
+    async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        let _ = tokio::time::timeout(CONTROL_FLUSH_TIMEOUT, async {
+            if self.send(ControlFrame::Flush(done)).await.is_ok() {
+                let _ = flushed.await;
+            }
+        })
+        .await;
+    }
+}
+
+#[derive(Debug)]
+enum ControlFrame {
+    Plain(Vec<u8>),
+    Compressed(Vec<u8>),
+    /// Closes a WebSocket control socket
+    #[cfg(feature = "websocket")]
+    Close(CloseFrame<'static>),
+    /// Answered once everything queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Send half of the control stream, owned by the writer task
+enum ControlSink {
+    #[cfg(feature = "webtransport")]
+    WebTransport(wtransport::SendStream),
+    /// Compressing each text message when `permessage-deflate` was negotiated
+    #[cfg(feature = "websocket")]
+    WebSocket(SplitSink<WsStream, Message>, bool),
+    Tcp(TcpWriter),
+}
+
+impl ControlSink {
+    /// Ends when every [`ControlWriter`] is dropped, or when a write fails
+    /// or times out, which fails the session's next write
+    async fn run(mut self, mut rx: mpsc::Receiver<ControlFrame>) {
+        while let Some(frame) = rx.recv().await {
+            match tokio::time::timeout(CONTROL_WRITE_TIMEOUT, self.write(frame)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::warn!("Control stream write failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!("Browser accepted no control message for {:?}; giving up on it", CONTROL_WRITE_TIMEOUT);
+                    crate::stats::global().add("control_write_timeouts", 1);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, frame: ControlFrame) -> Result<()> {
+        match (self, frame) {
+            (_, ControlFrame::Flush(done)) => {
+                let _ = done.send(());
+            }
+            #[cfg(feature = "webtransport")]
+            (ControlSink::WebTransport(send), ControlFrame::Plain(data)) => send.write_all(&data).await?,
+            #[cfg(feature = "webtransport")]
+            (ControlSink::WebTransport(send), ControlFrame::Compressed(data)) => {
+                send.write_all(&(data.len() as u32).to_be_bytes()).await?;
+                send.write_all(&data).await?;
+            }
+            #[cfg(feature = "websocket")]
+            (ControlSink::WebSocket(ws, true), ControlFrame::Plain(data)) => {
+                let text = String::from_utf8_lossy(&data);
+                ws.send(Message::Frame(crate::ws_deflate::text_frame(text.as_bytes())?)).await?;
+            }
+            #[cfg(feature = "websocket")]
+            (ControlSink::WebSocket(ws, false), ControlFrame::Plain(data)) => {
+                // Ideally we should check if data is valid UTF-8, but RTSP is generally ASCII/UTF-8
+                let text = String::from_utf8_lossy(&data).to_string();
+                ws.send(Message::Text(text)).await?;
+            }
+            #[cfg(feature = "websocket")]
+            (ControlSink::WebSocket(ws, _), ControlFrame::Compressed(data)) => ws.send(Message::Binary(data)).await?,
+            #[cfg(feature = "websocket")]
+            (ControlSink::WebSocket(ws, _), ControlFrame::Close(frame)) => ws.send(Message::Close(Some(frame))).await?,
+            #[cfg(feature = "websocket")]
+            (_, ControlFrame::Close(_)) => {}
+            (ControlSink::Tcp(writer), ControlFrame::Plain(data)) => writer.send(FRAME_CONTROL, &data).await.map_err(Error::transport)?,
+            (ControlSink::Tcp(writer), ControlFrame::Compressed(data)) => writer.send(FRAME_COMPRESSED, &data).await.map_err(Error::transport)?,
+        }
+        Ok(())
+    }
 }
 
 /// Clone-able sender for datagrams
 #[derive(Clone, Debug)]
-pub enum TransportSender {
-    WebTransport(Arc<Connection>),
-    WebSocket(Arc<Mutex<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>>>),
+pub struct DatagramSender {
+    path: SenderPath,
+    framing: Framing,
+    bytes_sent: Arc<AtomicU64>,
+    /// Records outgoing media, and holds it back while the browser is not live
+    timeshift: Option<Arc<TimeShift>>,
 }
 
-impl TransportSender {
-    pub async fn send_datagram(&self, payload: Bytes) -> Result<()> {
+#[derive(Clone, Debug)]
+enum SenderPath {
+    #[cfg(feature = "webtransport")]
+    WebTransport(Arc<WtMediaPath>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Arc<Mutex<SplitSink<WsStream, Message>>>),
+    Tcp(TcpWriter),
+}
+
+/// Receiver for client → proxy messages on the datagram path (feedback)
+#[derive(Clone, Debug)]
+pub enum DatagramReceiver {
+    #[cfg(feature = "webtransport")]
+    WebTransport(Arc<WtMediaPath>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Arc<Mutex<SplitStream<WsStream>>>),
+    Tcp(Arc<Mutex<tokio::sync::mpsc::Receiver<Bytes>>>),
+}
+
+impl DatagramReceiver {
+    /// Next binary message from the client, or None once the path is closed
+    pub async fn recv(&self) -> Result<Option<Bytes>> {
         match self {
-            TransportSender::WebTransport(conn) => {
-                conn.send_datagram(payload)?;
-                Ok(())
+            #[cfg(feature = "webtransport")]
+            DatagramReceiver::WebTransport(path) => match path.conn.receive_datagram().await {
+                Ok(datagram) => Ok(Some(datagram.payload())),
+                Err(_) => Ok(None),
+            },
+            #[cfg(feature = "websocket")]
+            DatagramReceiver::WebSocket(ws) => {
+                let mut ws = ws.lock().await;
+                loop {
+                    match ws.next().await {
+                        Some(Ok(Message::Binary(data))) => return Ok(Some(data.into())),
+                        Some(Ok(Message::Close(_))) | None => return Ok(None),
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(Error::transport(format!("WebSocket error: {}", e))),
+                    }
+                }
             }
-            TransportSender::WebSocket(ws) => {
+            DatagramReceiver::Tcp(rx) => Ok(rx.lock().await.recv().await),
+        }
+    }
+}
+
+impl DatagramSender {
+    fn new(path: SenderPath) -> Self {
+        Self { path, framing: Framing::default(), bytes_sent: Arc::default(), timeshift: None }
+    }
+
+    /// Payload framing negotiated with the client
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Largest media payload, framing included, the client can receive in
+    /// one datagram; None when there is no limit below 64 KiB. Advertised to
+    /// the client in SETUP responses.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => path.max_datagram_size(),
+            _ => None,
+        }
+    }
+
+    /// Current RTP delivery mode, advertised to the client in SETUP responses
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => path.mode(),
+            _ => DeliveryMode::Datagram,
+        }
+    }
+
+    /// Whether media waits for a slow client instead of being dropped:
+    /// everything but WebTransport datagrams
+    pub fn reliable(&self) -> bool {
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => path.mode() == DeliveryMode::Stream,
+            _ => true,
+        }
+    }
+
+    /// Media bytes sent to the client so far
+    pub fn bytes_sent(&self) -> Arc<AtomicU64> {
+        self.bytes_sent.clone()
+    }
+
+    /// What the QUIC connection could carry right now; None off WebTransport
+    pub fn quic_estimate_kbps(&self) -> Option<u32> {
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => Some(path.quic_estimate_kbps()),
+            _ => None,
+        }
+    }
+
+    pub async fn send_datagram(&self, payload: Bytes) -> Result<()> {
+        if let Some(timeshift) = &self.timeshift
+            && !timeshift.record(&payload)
+        {
+            return Ok(());
+        }
+        #[cfg(feature = "chaos")]
+        if crate::chaos::drop_datagram() {
+            return Ok(());
+        }
+        self.send_path(payload).await
+    }
+
+    /// Send a packet again; the time-shift buffer already holds it
+    pub async fn resend(&self, payload: Bytes) -> Result<()> {
+        self.send_path(payload).await
+    }
+
+    async fn send_path(&self, payload: Bytes) -> Result<()> {
+        self.bytes_sent.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => path.send(payload, self.framing).await,
+            #[cfg(feature = "websocket")]
+            SenderPath::WebSocket(ws) => {
+                let (channel, size) = (self.framing.split(&payload).map(|(channel, _)| channel), payload.len());
                 let mut ws = ws.lock().await;
                 if let Err(e) = ws.send(Message::Binary(payload.into())).await {
                     tracing::error!("Failed to send WS datagram: {}", e);
+                    crate::drops::record(DropReason::SendFailed, channel, size);
                 }
                 Ok(())
             }
+            SenderPath::Tcp(writer) => writer.send(FRAME_MEDIA, &payload).await.map_err(Error::transport),
         }
     }
+
+    /// Reliable path for media the browser must not lose: a new
+    /// unidirectional stream on WebTransport, the data socket on WebSocket,
+    /// the connection itself on TCP.
+    /// Media sent on it bypasses the time-shift buffer.
+    pub async fn open_stream(&self) -> Result<MediaStream> {
+        let path = match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => StreamPath::WebTransport(path.conn.open_uni().await?.await?),
+            #[cfg(feature = "websocket")]
+            SenderPath::WebSocket(ws) => StreamPath::WebSocket(ws.clone()),
+            SenderPath::Tcp(writer) => StreamPath::Tcp(writer.clone()),
+        };
+        Ok(MediaStream { path, bytes_sent: self.bytes_sent.clone() })
+    }
+}
+
+/// Closes the client's connection with the kind of error that ended the session
+pub struct Closer {
+    path: SenderPath,
+    /// Queued control messages go out before the connection closes
+    control: ControlWriter,
+}
+
+impl Closer {
+    /// WebTransport and WebSocket close with [`Error::close_code`] and the
+    /// kind as the reason; TCP gets an `ERR <kind>` OPEN frame. Control
+    /// messages already queued go out first, unless the browser is too slow
+    /// to take them.
+    pub async fn close(&self, error: &Error) {
+        #[cfg_attr(not(any(feature = "webtransport", feature = "websocket")), allow(unused_variables))]
+        let code = error.close_code();
+        #[cfg(feature = "websocket")]
+        let frame = || CloseFrame { code: CloseCode::from(code as u16), reason: error.kind().into() };
+        #[cfg(feature = "websocket")]
+        if matches!(self.path, SenderPath::WebSocket(_)) {
+            let _ = self.control.send(ControlFrame::Close(frame())).await;
+        }
+        self.control.flush().await;
+        match &self.path {
+            #[cfg(feature = "webtransport")]
+            SenderPath::WebTransport(path) => path.conn.close(wtransport::VarInt::from_u32(code), error.kind().as_bytes()),
+            #[cfg(feature = "websocket")]
+            SenderPath::WebSocket(data) => {
+                let _ = data.lock().await.send(Message::Close(Some(frame()))).await;
+            }
+            SenderPath::Tcp(writer) => {
+                let _ = writer.send(FRAME_OPEN, format!("ERR {}", error.kind()).as_bytes()).await;
+            }
+        }
+    }
+}
+
+/// Media payloads delivered in order and without loss; on WebTransport each
+/// is framed as `[u16 length][payload]`, like the datagram fallback
+pub struct MediaStream {
+    path: StreamPath,
+    bytes_sent: Arc<AtomicU64>,
+}
+
+enum StreamPath {
+    #[cfg(feature = "webtransport")]
+    WebTransport(wtransport::SendStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(Arc<Mutex<SplitSink<WsStream, Message>>>),
+    Tcp(TcpWriter),
+}
+
+impl MediaStream {
+    pub async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        self.bytes_sent.fetch_add(payload.len() as u64, Ordering::Relaxed);
+        match &mut self.path {
+            #[cfg(feature = "webtransport")]
+            StreamPath::WebTransport(stream) => {
+                let len = u16::try_from(payload.len())
+                    .map_err(|_| Error::Other(anyhow::anyhow!("Packet too large for stream framing: {} bytes", payload.len())))?;
+                stream.write_all(&len.to_be_bytes()).await?;
+                stream.write_all(payload).await?;
+            }
+            #[cfg(feature = "websocket")]
+            StreamPath::WebSocket(ws) => {
+                ws.lock().await.send(Message::Binary(payload.to_vec())).await?;
+            }
+            StreamPath::Tcp(writer) => writer.send(FRAME_MEDIA, payload).await.map_err(Error::transport)?,
+        }
+        Ok(())
+    }
 }
 
 impl Transport {
+    #[cfg(feature = "webtransport")]
     pub fn new_wt(
         conn: Arc<Connection>,
         send: wtransport::SendStream,
         recv: wtransport::RecvStream,
     ) -> Self {
+        let path = Arc::new(WtMediaPath::new(conn));
         Self {
-            inner: TransportType::WebTransport(conn, send, recv),
+            reader: ControlReader::WebTransport(recv),
+            writer: ControlWriter::spawn(ControlSink::WebTransport(send)),
+            sender: DatagramSender::new(SenderPath::WebTransport(path.clone())),
+            receiver: DatagramReceiver::WebTransport(path),
+            hangup: None,
         }
     }
 
-    pub fn new_ws(
-        control: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-        data: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-    ) -> Self {
+    /// Control messages on `control` are compressed when it negotiated
+    /// `permessage-deflate`, and never by [`Transport::with_compression`]
+    #[cfg(feature = "websocket")]
+    pub fn new_ws(control: WsStream, data: WsStream) -> Self {
+        let deflate = control.get_ref().deflate();
+        let (control, control_rx) = control.split();
+        let (data, data_rx) = data.split();
         Self {
-            inner: TransportType::WebSocket {
-                control: Arc::new(Mutex::new(control)),
-                data: Arc::new(Mutex::new(data)),
-            },
+            reader: ControlReader::WebSocket(control_rx),
+            writer: ControlWriter::spawn(ControlSink::WebSocket(control, deflate)),
+            sender: DatagramSender::new(SenderPath::WebSocket(Arc::new(Mutex::new(data)))),
+            receiver: DatagramReceiver::WebSocket(Arc::new(Mutex::new(data_rx))),
+            hangup: None,
         }
     }
 
-    pub fn clone_sender(&self) -> TransportSender {
-        match &self.inner {
-            TransportType::WebTransport(conn, _, _) => TransportSender::WebTransport(conn.clone()),
-            TransportType::WebSocket { data, .. } => TransportSender::WebSocket(data.clone()),
+    /// One WebSocket that only carries media, for players such as
+    /// mpegts.js. Control messages for it are dropped.
+    #[cfg(feature = "websocket")]
+    pub fn new_ws_player(ws: WsStream) -> Self {
+        let (data, data_rx) = ws.split();
+        let receiver = DatagramReceiver::WebSocket(Arc::new(Mutex::new(data_rx)));
+        Self {
+            reader: ControlReader::Player(receiver.clone()),
+            writer: ControlWriter { queue: None, compression: None },
+            sender: DatagramSender::new(SenderPath::WebSocket(Arc::new(Mutex::new(data)))),
+            receiver,
+            hangup: None,
         }
     }
 
-    /// Read next control message (RTSP text)
-    pub async fn read_control(&mut self, buf: &mut bytes::BytesMut) -> Result<usize> {
-        match &mut self.inner {
-            TransportType::WebTransport(_, _, recv) => {
-                // Read from WT stream
-                let n = recv.read_buf(buf).await?;
-                Ok(n) // 0 means EOF
-            }
-            TransportType::WebSocket { control, .. } => {
-                let mut ws = control.lock().await;
-                match ws.next().await {
-                    Some(Ok(msg)) => {
-                        match msg {
-                            Message::Text(text) => {
-                                buf.extend_from_slice(text.as_bytes());
-                                Ok(text.len())
-                            }
-                            Message::Close(_) => Ok(0),
-                            _ => Ok(0), // Ignore other types for control
-                        }
-                    }
-                    Some(Err(e)) => Err(anyhow::anyhow!("WebSocket error: {}", e)),
-                    None => Ok(0), // EOF
-                }
-            }
+    pub fn new_tcp(link: TcpLink) -> Self {
+        Self {
+            reader: ControlReader::Tcp(link.control),
+            writer: ControlWriter::spawn(ControlSink::Tcp(link.writer.clone())),
+            sender: DatagramSender::new(SenderPath::Tcp(link.writer)),
+            receiver: DatagramReceiver::Tcp(link.media),
+            hangup: Some(link.closed),
         }
     }
 
-    /// Write control message (RTSP text)
-    pub async fn write_control(&mut self, data: &[u8]) -> Result<()> {
-        match &mut self.inner {
-            TransportType::WebTransport(_, send, _) => {
-                send.write_all(data).await?;
-                Ok(())
-            }
-            TransportType::WebSocket { control, .. } => {
-                // Ideally we should check if data is valid UTF-8, but RTSP is generally ASCII/UTF-8
-                let text = String::from_utf8_lossy(data).to_string();
-                let mut ws = control.lock().await;
-                ws.send(Message::Text(text.into())).await?;
-                Ok(())
-            }
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.sender.framing = framing;
+        self
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.sender.framing
+    }
+
+    pub fn set_timeshift(&mut self, timeshift: Option<Arc<TimeShift>>) {
+        self.sender.timeshift = timeshift;
+    }
+
+    /// Compress control messages on the WebTransport control stream or TCP;
+    /// WebSocket uses `permessage-deflate` instead
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        #[cfg(feature = "websocket")]
+        if matches!(self.sender.path, SenderPath::WebSocket(_)) {
+            return self;
         }
+        self.writer.compression = compression;
+        self
+    }
+
+    /// Deliver WebTransport media on a stream even when datagrams work
+    #[cfg_attr(not(feature = "webtransport"), allow(unused_variables))]
+    pub fn with_stream_delivery(self, stream: bool) -> Self {
+        #[cfg(feature = "webtransport")]
+        if let SenderPath::WebTransport(path) = &self.sender.path
+            && stream
+        {
+            path.stream_mode.store(true, Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// The control stream's two halves and the media path, usable at the
+    /// same time
+    pub fn split(&mut self) -> (&mut ControlReader, &ControlWriter, &DatagramSender) {
+        (&mut self.reader, &self.writer, &self.sender)
+    }
+
+    /// Handle that closes the client's connection, usable after the transport is moved
+    pub fn closer(&self) -> Closer {
+        Closer { path: self.sender.path.clone(), control: self.writer.clone() }
+    }
+
+    /// Media bytes sent to the client so far, readable after the transport is moved
+    pub fn bytes_sent(&self) -> Arc<AtomicU64> {
+        self.sender.bytes_sent()
+    }
+
+    pub fn datagram_receiver(&self) -> DatagramReceiver {
+        self.receiver.clone()
+    }
+
+    /// Write control message (RTSP text); see [`ControlWriter::write`]
+    pub async fn write_control(&self, data: &[u8]) -> Result<()> {
+        self.writer.write(data).await
     }
 
     pub async fn closed(&self) {
-        match &self.inner {
-            TransportType::WebTransport(conn, _, _) => {
-                conn.closed().await;
-            }
-            TransportType::WebSocket { .. } => {
-                // Monitor WS close?
-                // Currently just wait forever or until read returns 0
-                futures_util::future::pending::<()>().await;
+        match (&self.sender.path, &self.hangup) {
+            #[cfg(feature = "webtransport")]
+            (SenderPath::WebTransport(path), _) => {
+                path.conn.closed().await;
             }
+            (_, Some(hangup)) => hangup.cancelled().await,
+            // WebSocket clients are only noticed leaving by reading
+            (_, None) => futures_util::future::pending::<()>().await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const SDP: &[u8] = b"v=0\r\no=- 0 0 IN IP4 10.0.1.20\r\ns=cam\r\nt=0 0\r\nm=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=control:trackID=1\r\nm=video 0 RTP/AVP 97\r\na=rtpmap:97 H264/90000\r\na=control:trackID=2\r\n";
+
+    fn inflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        flate2::read::DeflateDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn compressions() -> Vec<Compression> {
+        vec![
+            Compression::Deflate,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for compression in compressions() {
+            assert_eq!(Compression::parse(compression.as_str()), Some(compression));
+        }
+        assert_eq!(Compression::parse("gzip"), None);
+    }
+
+    #[test]
+    fn deflate_round_trips() {
+        for data in [&b""[..], b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n", SDP, &SDP.repeat(100)] {
+            let compressed = Compression::Deflate.compress(data).unwrap();
+            assert_eq!(inflate(&compressed).unwrap(), data);
+        }
+        assert!(Compression::Deflate.compress(&SDP.repeat(100)).unwrap().len() < SDP.len() * 2);
+    }
+
+    #[test]
+    fn truncated_deflate_is_an_error() {
+        let compressed = Compression::Deflate.compress(&SDP.repeat(4)).unwrap();
+        for end in [0, 1, compressed.len() / 2, compressed.len() - 1] {
+            assert!(inflate(&compressed[..end]).is_err(), "truncated to {}", end);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trips() {
+        for data in [&b""[..], b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n", SDP, &SDP.repeat(100)] {
+            let compressed = Compression::Zstd.compress(data).unwrap();
+            assert_eq!(zstd::stream::decode_all(&compressed[..]).unwrap(), data);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn truncated_zstd_is_an_error() {
+        let compressed = Compression::Zstd.compress(&SDP.repeat(4)).unwrap();
+        for end in [0, 1, compressed.len() / 2, compressed.len() - 1] {
+            assert!(zstd::stream::decode_all(&compressed[..end]).is_err(), "truncated to {}", end);
+        }
+    }
+
+    /// Next `[u8 type][u32 length][payload]` frame from the proxy
+    async fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let kind = client.read_u8().await.unwrap();
+        let mut payload = vec![0; client.read_u32().await.unwrap() as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (kind, payload)
+    }
+
+    #[tokio::test]
+    async fn control_messages_are_framed_by_compression() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (proxy, _) = listener.accept().await.unwrap();
+        let (_read, write) = proxy.into_split();
+        let sink = ControlSink::Tcp(TcpWriter::new(write, crate::tcp::Dialect::Frames));
+        let plain = ControlWriter::spawn(sink);
+        let mut compressed = plain.clone();
+        compressed.compression = Some(Compression::Deflate);
+
+        plain.write(SDP).await.unwrap();
+        compressed.write(SDP).await.unwrap();
+        assert_eq!(read_frame(&mut client).await, (FRAME_CONTROL, SDP.to_vec()));
+        let (kind, payload) = read_frame(&mut client).await;
+        assert_eq!(kind, FRAME_COMPRESSED);
+        assert_eq!(inflate(&payload).unwrap(), SDP);
+    }
+}
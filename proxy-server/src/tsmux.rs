@@ -0,0 +1,176 @@
+//! MPEG-TS writer for `output=mpegts`: one program with an H.264 video and
+//! an ADTS AAC audio stream, as mpegts.js plays them. PAT and PMT precede
+//! every keyframe so a player can start from any of them.
+
+use std::iter::repeat_n;
+
+const TS_PACKET: usize = 188;
+const TS_PAYLOAD: usize = TS_PACKET - 4;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_ADTS: u8 = 0x0F;
+const STREAM_ID_VIDEO: u8 = 0xE0;
+const STREAM_ID_AUDIO: u8 = 0xC0;
+/// PCR runs this far behind the timestamps, in 90 kHz units, so players
+/// buffer a little before presenting
+const PCR_LEAD: u64 = 9000;
+/// How often audio-only streams repeat PAT and PMT, in 90 kHz units
+const PSI_INTERVAL: u64 = 90000;
+
+#[derive(Debug, Default)]
+pub struct TsMuxer {
+    video: bool,
+    audio: bool,
+    /// Continuity counters of the PAT, PMT, video and audio PIDs
+    counters: [u8; 4],
+    /// When PAT and PMT were last written, in 90 kHz units
+    last_psi: Option<u64>,
+}
+
+impl TsMuxer {
+    /// Streams must be added before the first frame is written
+    pub fn add_video(&mut self) {
+        self.video = true;
+    }
+
+    pub fn add_audio(&mut self) {
+        self.audio = true;
+    }
+
+    /// TS packets for one picture in Annex B format, stamped `pts` (90 kHz)
+    pub fn video(&mut self, pts: u64, keyframe: bool, annex_b: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        if keyframe || self.last_psi.is_none() {
+            self.psi(pts, &mut out);
+        }
+        let pes = pes(STREAM_ID_VIDEO, pts, annex_b);
+        self.write_pes(VIDEO_PID, 2, &pes, Some(pts.saturating_sub(PCR_LEAD)), keyframe, &mut out);
+        out
+    }
+
+    /// TS packets for ADTS frames, the first stamped `pts` (90 kHz)
+    pub fn audio(&mut self, pts: u64, adts: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        // With video, PSI goes out with keyframes instead
+        if !self.video && self.last_psi.is_none_or(|last| pts.saturating_sub(last) >= PSI_INTERVAL) {
+            self.psi(pts, &mut out);
+        }
+        let pes = pes(STREAM_ID_AUDIO, pts, adts);
+        // The PCR rides on audio when there is no video
+        let pcr = (!self.video).then(|| pts.saturating_sub(PCR_LEAD));
+        self.write_pes(AUDIO_PID, 3, &pes, pcr, false, &mut out);
+        out
+    }
+
+    fn psi(&mut self, pts: u64, out: &mut Vec<u8>) {
+        self.last_psi = Some(pts);
+        let pat = section(0x00, 1, &[0x00, 0x01, 0xE0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+        self.write_section(PAT_PID, 0, &pat, out);
+
+        let pcr_pid = if self.video { VIDEO_PID } else { AUDIO_PID };
+        let mut body = vec![0xE0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xF0, 0x00];
+        let streams = [(self.video, STREAM_TYPE_H264, VIDEO_PID), (self.audio, STREAM_TYPE_ADTS, AUDIO_PID)];
+        for (_, stream_type, pid) in streams.into_iter().filter(|(present, _, _)| *present) {
+            body.extend_from_slice(&[stream_type, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0x00]);
+        }
+        let pmt = section(0x02, 1, &body);
+        self.write_section(PMT_PID, 1, &pmt, out);
+    }
+
+    /// A PSI section in one packet, after a zero pointer field
+    fn write_section(&mut self, pid: u16, counter: usize, section: &[u8], out: &mut Vec<u8>) {
+        let mut payload = vec![0x00];
+        payload.extend_from_slice(section);
+        payload.resize(TS_PAYLOAD, 0xFF);
+        self.write_packet(pid, counter, true, &[], &payload, out);
+    }
+
+    fn write_pes(&mut self, pid: u16, counter: usize, pes: &[u8], pcr: Option<u64>, keyframe: bool, out: &mut Vec<u8>) {
+        // Adaptation field of the first packet: the random access flag and the PCR
+        let mut fields = Vec::new();
+        if pcr.is_some() || keyframe {
+            fields.push(if keyframe { 0x40 } else { 0x00 } | if pcr.is_some() { 0x10 } else { 0x00 });
+        }
+        if let Some(pcr) = pcr {
+            let base = pcr & 0x1_FFFF_FFFF;
+            fields.extend_from_slice(&[(base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8, ((base & 1) << 7) as u8 | 0x7E, 0x00]);
+        }
+        let mut rest = pes;
+        let mut first = true;
+        while !rest.is_empty() {
+            let taken = self.write_packet(pid, counter, first, if first { &fields } else { &[] }, rest, out);
+            rest = &rest[taken..];
+            first = false;
+        }
+    }
+
+    /// Write one TS packet with as much of `payload` as fits, stuffing the
+    /// adaptation field when it runs out; returns the payload bytes taken.
+    /// `fields` is the adaptation field after its length byte, if any.
+    fn write_packet(&mut self, pid: u16, counter: usize, start: bool, fields: &[u8], payload: &[u8], out: &mut Vec<u8>) -> usize {
+        let fields_len = if fields.is_empty() { 0 } else { 1 + fields.len() };
+        let taken = payload.len().min(TS_PAYLOAD - fields_len);
+        let adaptation_len = fields_len + (TS_PAYLOAD - fields_len - taken);
+
+        let cc = &mut self.counters[counter];
+        out.extend_from_slice(&[SYNC_BYTE, (start as u8) << 6 | (pid >> 8) as u8, pid as u8]);
+        out.push(if adaptation_len > 0 { 0x30 } else { 0x10 } | *cc);
+        *cc = (*cc + 1) & 0x0F;
+        if adaptation_len > 0 {
+            out.push((adaptation_len - 1) as u8);
+            if adaptation_len > 1 {
+                match fields.is_empty() {
+                    true => {
+                        out.push(0x00);
+                        out.extend(repeat_n(0xFF, adaptation_len - 2));
+                    }
+                    false => {
+                        out.extend_from_slice(fields);
+                        out.extend(repeat_n(0xFF, adaptation_len - 1 - fields.len()));
+                    }
+                }
+            }
+        }
+        out.extend_from_slice(&payload[..taken]);
+        taken
+    }
+}
+
+/// PES packet with a PTS. Video may leave the length unbounded; audio
+/// packets stay well under the 64 KiB a length can express.
+fn pes(stream_id: u8, pts: u64, data: &[u8]) -> Vec<u8> {
+    let len = data.len() + 8;
+    let len = if stream_id == STREAM_ID_VIDEO || len > u16::MAX as usize { 0 } else { len as u16 };
+    let pts = pts & 0x1_FFFF_FFFF;
+    let mut pes = vec![0x00, 0x00, 0x01, stream_id, (len >> 8) as u8, len as u8, 0x80, 0x80, 0x05];
+    pes.extend_from_slice(&[
+        0x21 | ((pts >> 29) & 0x0E) as u8,
+        (pts >> 22) as u8,
+        ((pts >> 14) & 0xFE) as u8 | 1,
+        (pts >> 7) as u8,
+        ((pts << 1) & 0xFE) as u8 | 1,
+    ]);
+    pes.extend_from_slice(data);
+    pes
+}
+
+/// Long-form PSI section, version 0, with its CRC
+fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    let len = 5 + body.len() + 4;
+    let mut section = vec![table_id, 0xB0 | (len >> 8) as u8, len as u8, (id >> 8) as u8, id as u8, 0xC1, 0x00, 0x00];
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// CRC-32/MPEG-2
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32) << 24, |crc, _| if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04C1_1DB7 } else { crc << 1 })
+    })
+}
@@ -0,0 +1,12 @@
+//! UDP behaviour that differs between platforms. An ICMP "port unreachable"
+//! for an earlier send, e.g. a NAT punch at a closed camera port, fails the
+//! next receive on Windows (`WSAECONNRESET`) and on connected sockets
+//! elsewhere. It is about a packet already gone, so receive loops skip it
+//! instead of ending the session.
+
+use std::io;
+
+/// A receive error left by an earlier send, after which the socket still works
+pub fn is_send_error(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused)
+}
@@ -0,0 +1,276 @@
+//! Connections to cameras: plain TCP, through a SOCKS5 / HTTP CONNECT proxy,
+//! RTSP tunnelled over HTTP, and through a registered edge instance
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use crate::config::UpstreamConfig;
+
+/// URL scheme for cameras that only speak RTSP tunnelled over HTTP
+const TUNNEL_SCHEME: &str = "rtsp+http";
+
+pub type UpstreamReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type UpstreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// An RTSP connection to a camera
+pub struct Upstream {
+    pub reader: UpstreamReader,
+    pub writer: UpstreamWriter,
+    /// Where the camera's UDP media comes from. Through a proxy this is the
+    /// camera's resolved address, not the peer of the TCP connection.
+    pub camera_ip: IpAddr,
+    /// Whether media must be requested interleaved on this connection,
+    /// because UDP from the camera cannot reach the proxy
+    pub interleaved: bool,
+}
+
+/// Connect to the camera of an RTSP URL, through the proxy configured for it,
+/// tunnelling over HTTP for `rtsp+http://` URLs and configured cameras, or
+/// through the edge instance configured for it
+pub async fn connect(url: &url::Url, config: &UpstreamConfig) -> Result<Upstream> {
+    let host = url.host_str().context("Missing host in RTSP URL")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Some(edge) = config.relay_for(url.as_str()) {
+        return crate::relay::open(edge, host, url.port().unwrap_or(8554)).await;
+    }
+
+    if let Some(http_port) = config.tunnel_port(url) {
+        return open_tunnel(url, host, http_port, config).await;
+    }
+
+    let (stream, camera_ip) = dial(url, host, url.port().unwrap_or(8554), config).await?;
+    let (reader, writer) = stream.into_split();
+    Ok(Upstream { reader: Box::new(reader), writer: Box::new(writer), camera_ip, interleaved: false })
+}
+
+/// The URL to put in RTSP requests to the camera: `rtsp+http://` URLs name
+/// the HTTP port, which the camera does not expect to see
+pub fn camera_url(url: &str) -> String {
+    let Some(rest) = url.strip_prefix(TUNNEL_SCHEME).and_then(|rest| rest.strip_prefix("://")) else {
+        return url.to_string();
+    };
+    let mut camera = match url::Url::parse(&format!("rtsp://{}", rest)) {
+        Ok(camera) => camera,
+        Err(_) => return url.to_string(),
+    };
+    let _ = camera.set_port(None);
+    camera.to_string()
+}
+
+/// A TCP connection to `host:port`, through the proxy configured for `url`
+async fn dial(url: &url::Url, host: &str, port: u16, config: &UpstreamConfig) -> Result<(TcpStream, IpAddr)> {
+    let addr = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+
+    let Some(proxy) = config.proxy_for(url.as_str()) else {
+        let stream = TcpStream::connect(&addr)
+            .await
+            .with_context(|| format!("Failed to connect to RTSP server at {}", addr))?;
+        let camera_ip = stream.peer_addr()?.ip();
+        return Ok((stream, camera_ip));
+    };
+
+    let proxy = url::Url::parse(proxy).with_context(|| format!("Invalid upstream proxy URL {}", proxy))?;
+    let proxy_addr = format!(
+        "{}:{}",
+        proxy.host_str().context("Missing host in upstream proxy URL")?,
+        proxy.port_or_known_default().unwrap_or(1080)
+    );
+    info!("Connecting to {} through {} proxy {}", addr, proxy.scheme(), proxy_addr);
+    let mut stream = TcpStream::connect(&proxy_addr)
+        .await
+        .with_context(|| format!("Failed to connect to upstream proxy {}", proxy_addr))?;
+    let credentials = (!proxy.username().is_empty()).then(|| (proxy.username(), proxy.password().unwrap_or("")));
+    match proxy.scheme() {
+        "socks5" | "socks5h" => socks5_connect(&mut stream, host, port, credentials).await?,
+        "http" => http_connect(&mut stream, &addr, credentials).await?,
+        scheme => bail!("Unsupported upstream proxy scheme '{}'", scheme),
+    }
+
+    // Media is still received over UDP straight from the camera
+    let camera_ip = match tokio::net::lookup_host(&addr).await.ok().and_then(|mut addrs| addrs.next()) {
+        Some(camera) => camera.ip(),
+        None => {
+            warn!("Cannot resolve {} locally; RTP source checks and hole punching will target the proxy", host);
+            stream.peer_addr()?.ip()
+        }
+    };
+    Ok((stream, camera_ip))
+}
+
+/// RFC 1928 CONNECT, with RFC 1929 username/password auth if credentials are set.
+/// The camera host is sent as a name so the proxy resolves it.
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<()> {
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        bail!("SOCKS5 proxy refused authentication method {}", method);
+    }
+
+    if let Some((username, password)) = credentials {
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            bail!("SOCKS5 proxy rejected the credentials");
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.extend_from_slice(&[0x03, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // VER REP RSV ATYP, then the bound address and port
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        bail!("SOCKS5 proxy could not connect to {}:{} (reply {})", host, port, head[1]);
+    }
+    let addr_len = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        atyp => bail!("SOCKS5 proxy sent unknown address type {}", atyp),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// HTTP CONNECT tunnel, with Basic proxy auth if credentials are set
+async fn http_connect(stream: &mut TcpStream, addr: &str, credentials: Option<(&str, &str)>) -> Result<()> {
+    let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+    if let Some((username, password)) = credentials {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let status_line = read_http_head(stream).await.context("HTTP proxy")?;
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        bail!("HTTP proxy refused CONNECT to {}: {}", addr, status_line);
+    }
+    Ok(())
+}
+
+/// Read an HTTP response head byte by byte, so nothing after it is consumed,
+/// and return its status line
+async fn read_http_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 {
+            bail!("Response head too long");
+        }
+        head.push(stream.read_u8().await.context("Connection closed before the response")?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    head.lines().next().map(str::to_string).ok_or_else(|| anyhow!("Empty HTTP response"))
+}
+
+/// QuickTime-style RTSP over HTTP: responses arrive on a long-lived GET,
+/// requests go base64-encoded on a POST, paired by `x-sessioncookie`
+async fn open_tunnel(url: &url::Url, host: &str, http_port: u16, config: &UpstreamConfig) -> Result<Upstream> {
+    let cookie = format!("{:016x}", rand::random::<u64>());
+    let path = &url[url::Position::BeforePath..];
+    info!("Tunnelling RTSP over HTTP to {}:{}", host, http_port);
+
+    let (mut get, camera_ip) = dial(url, host, http_port, config).await?;
+    let request = format!(
+        "GET {path} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: rtsp2browser\r\nx-sessioncookie: {cookie}\r\n\
+         Accept: application/x-rtsp-tunnelled\r\nPragma: no-cache\r\nCache-Control: no-cache\r\n\r\n"
+    );
+    get.write_all(request.as_bytes()).await?;
+    let status_line = read_http_head(&mut get).await.context("RTSP-over-HTTP GET")?;
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        bail!("Camera refused the RTSP-over-HTTP tunnel: {}", status_line);
+    }
+
+    // The POST body never ends; the camera reads requests as they arrive
+    let (mut post, _) = dial(url, host, http_port, config).await?;
+    let request = format!(
+        "POST {path} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: rtsp2browser\r\nx-sessioncookie: {cookie}\r\n\
+         Content-Type: application/x-rtsp-tunnelled\r\nPragma: no-cache\r\nCache-Control: no-cache\r\n\
+         Content-Length: 32767\r\nExpires: Sun, 9 Jan 1972 00:00:00 GMT\r\n\r\n"
+    );
+    post.write_all(request.as_bytes()).await?;
+
+    Ok(Upstream { reader: Box::new(get), writer: Box::new(Base64Writer::new(post)), camera_ip, interleaved: false })
+}
+
+/// Base64-encodes each write on its own, so every RTSP request can be
+/// decoded independently by the camera
+struct Base64Writer<W> {
+    inner: W,
+    /// Encoded bytes of the write in progress not yet accepted by `inner`
+    pending: Vec<u8>,
+    written: usize,
+    /// Length of the caller's buffer the pending bytes encode
+    accepted: usize,
+}
+
+impl<W: AsyncWrite + Unpin> Base64Writer<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, pending: Vec::new(), written: 0, accepted: 0 }
+    }
+
+    fn poll_drain(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Base64Writer<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // A write left pending is retried with the same buffer
+        if self.pending.is_empty() {
+            self.pending = base64::engine::general_purpose::STANDARD.encode(buf).into_bytes();
+            self.accepted = buf.len();
+        }
+        ready!(self.poll_drain(cx))?;
+        Poll::Ready(Ok(self.accepted))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
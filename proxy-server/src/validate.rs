@@ -0,0 +1,210 @@
+//! Strict checks on the browser's RTSP before it reaches the camera. Without
+//! them a page could make the proxy send any request to any URL on the
+//! camera, or smuggle a second request past the proxy's parser.
+
+use tracing::warn;
+use crate::config::ValidationConfig;
+use crate::rtsp::{RtspRequest, RtspResponse};
+
+/// Transport parameters a browser has no business sending: they would point
+/// the camera's media elsewhere
+const FORBIDDEN_TRANSPORT: [&str; 2] = ["destination", "source"];
+
+/// The local answer to a request that must not be forwarded, or None to let
+/// it through. `raw` is the request as received; `targets` are the URLs the
+/// session negotiated, whose sub-URLs may also be requested.
+pub fn check(config: &ValidationConfig, raw: &[u8], req: &RtspRequest, targets: &[&str]) -> Option<RtspResponse> {
+    if !config.enabled {
+        return None;
+    }
+    let mut resp = if !config.allow_methods.iter().any(|m| m == &req.method) {
+        warn!("Rejecting {} request: method not allowed", req.method);
+        let mut resp = RtspResponse::new(405, "Method Not Allowed");
+        resp.headers.insert("Allow".to_string(), config.allow_methods.join(", "));
+        resp
+    } else if let Some(problem) = header_problem(raw, req) {
+        warn!("Rejecting {} request: {}", req.method, problem);
+        RtspResponse::new(400, "Bad Request")
+    } else if !targets_session(req, targets) {
+        warn!("Rejecting {} request for {}: outside the session's URL", req.method, req.path);
+        RtspResponse::new(403, "Forbidden")
+    } else {
+        return None;
+    };
+    if let Some(cseq) = req.headers.get("CSeq") {
+        resp.headers.insert("CSeq".to_string(), cseq.clone());
+    }
+    Some(resp)
+}
+
+/// Whether the request line's URL is one of `targets` or under one of them;
+/// OPTIONS may also ask about the server as a whole
+fn targets_session(req: &RtspRequest, targets: &[&str]) -> bool {
+    if req.path == "*" {
+        return req.method == "OPTIONS";
+    }
+    let Some(url) = normalize(&req.path) else {
+        return false;
+    };
+    targets.iter().filter_map(|target| normalize(target)).any(|target| {
+        let base = target.trim_end_matches('/');
+        url == target || url == base || url.strip_prefix(base).is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// `url` without credentials and the default port, with the scheme and host
+/// lowercased, so equal URLs compare equal
+fn normalize(url: &str) -> Option<String> {
+    let url = url::Url::parse(&crate::upstream::camera_url(url)).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let port = url.port().filter(|&port| port != 554).map(|port| format!(":{}", port)).unwrap_or_default();
+    Some(format!("{}://{}{}{}", url.scheme(), host, port, &url[url::Position::BeforePath..]))
+}
+
+/// What makes the headers ambiguous or unsafe to forward, if anything.
+/// The proxy's parser is lenient; cameras' parsers may read the same bytes
+/// differently, so anything they could disagree on is refused.
+fn header_problem(raw: &[u8], req: &RtspRequest) -> Option<String> {
+    if !req.version.starts_with("RTSP/") {
+        return Some(format!("unsupported version {}", req.version));
+    }
+    let head_len = raw.len() - req.body.len();
+    let Ok(head) = std::str::from_utf8(&raw[..head_len]) else {
+        return Some("headers are not UTF-8".to_string());
+    };
+    let mut names: Vec<String> = Vec::new();
+    // Stray blank lines before the request line are skipped, as by the parser
+    let mut lines = head.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)).skip_while(|line| line.is_empty());
+    if lines.next().is_some_and(|line| line.contains('\r')) {
+        return Some("carriage return in the request line".to_string());
+    }
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            return Some("folded header line".to_string());
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Some(format!("header line without a colon: {:?}", line));
+        };
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Some(format!("invalid header name {:?}", name));
+        }
+        if value.chars().any(|c| c.is_control() && c != '\t') {
+            return Some(format!("control character in the {} header", name));
+        }
+        if names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            return Some(format!("duplicate {} header", name));
+        }
+        names.push(name.to_string());
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let value = value.trim();
+            // The parser only honours the canonical spelling; a camera may honour any
+            if name != "Content-Length" || !value.bytes().all(|b| b.is_ascii_digit()) || value.parse() != Ok(req.body.len()) {
+                return Some(format!("ambiguous Content-Length {:?}", value));
+            }
+        }
+        if name.eq_ignore_ascii_case("Transport") {
+            if req.method != "SETUP" {
+                return Some(format!("Transport header on {}", req.method));
+            }
+            for param in value.split([',', ';']) {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                let name = name.trim();
+                // Recording would turn the camera into a sink for the browser's media
+                let recording = name.eq_ignore_ascii_case("mode") && !value.trim().trim_matches('"').eq_ignore_ascii_case("PLAY");
+                if recording || FORBIDDEN_TRANSPORT.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+                    return Some(format!("Transport parameter {}", param.trim()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether `b` may appear in a header name (RFC 2326's token)
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "rtsp://cam.local/stream";
+
+    /// Status of the local answer to `raw`, or None if it is forwarded
+    fn status(raw: &str) -> Option<u16> {
+        let (req, _) = RtspRequest::parse(raw.as_bytes()).unwrap().unwrap();
+        check(&ValidationConfig::default(), raw.as_bytes(), &req, &[TARGET]).map(|resp| resp.status_code)
+    }
+
+    #[test]
+    fn session_urls_pass() {
+        assert_eq!(status("DESCRIBE rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n"), None);
+        assert_eq!(status("SETUP rtsp://cam.local/stream/trackID=1 RTSP/1.0\r\nCSeq: 2\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n"), None);
+        // Same URL: credentials, case and the default port don't matter
+        assert_eq!(status("PLAY rtsp://admin:pw@CAM.local:554/stream/ RTSP/1.0\r\nCSeq: 3\r\n\r\n"), None);
+        assert_eq!(status("OPTIONS * RTSP/1.0\r\nCSeq: 4\r\n\r\n"), None);
+        let body = "GET_PARAMETER rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 5\r\nContent-Length: 8\r\n\r\nposition";
+        assert_eq!(status(body), None);
+    }
+
+    #[test]
+    fn other_urls_are_forbidden() {
+        assert_eq!(status("DESCRIBE rtsp://cam.local/stream2 RTSP/1.0\r\nCSeq: 1\r\n\r\n"), Some(403));
+        assert_eq!(status("DESCRIBE rtsp://cam.local:8554/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n"), Some(403));
+        assert_eq!(status("DESCRIBE rtsp://other.local/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n"), Some(403));
+        assert_eq!(status("DESCRIBE * RTSP/1.0\r\nCSeq: 1\r\n\r\n"), Some(403));
+        assert_eq!(status("DESCRIBE not-a-url RTSP/1.0\r\nCSeq: 1\r\n\r\n"), Some(403));
+    }
+
+    #[test]
+    fn unlisted_methods_are_refused_with_allow() {
+        let raw = "RECORD rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 7\r\n\r\n";
+        let (req, _) = RtspRequest::parse(raw.as_bytes()).unwrap().unwrap();
+        let resp = check(&ValidationConfig::default(), raw.as_bytes(), &req, &[TARGET]).unwrap();
+        assert_eq!(resp.status_code, 405);
+        assert_eq!(resp.headers["CSeq"], "7");
+        assert_eq!(resp.headers["Allow"], "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER, SET_PARAMETER");
+    }
+
+    #[test]
+    fn ambiguous_headers_are_bad_requests() {
+        let refused = [
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\ncseq: 2\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\nRange: npt=0-\r\n  more\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\nBad Name: x\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\nRange: npt=0-\x0b\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\ncontent-length: 0\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\nContent-Length: +0\r\n\r\n",
+            "PLAY rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 1\r\nTransport: RTP/AVP/TCP\r\n\r\n",
+            "PLAY rtsp://cam.local/stream HTTP/1.1\r\nCSeq: 1\r\n\r\n",
+        ];
+        for raw in refused {
+            assert_eq!(status(raw), Some(400), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn media_cannot_be_redirected() {
+        for transport in [
+            "RTP/AVP;unicast;destination=10.0.0.9;client_port=5000-5001",
+            "RTP/AVP;unicast;client_port=5000-5001;SOURCE=10.0.0.9",
+            "RTP/AVP/TCP;interleaved=0-1;mode=record",
+            "RTP/AVP/TCP;interleaved=0-1,RTP/AVP;unicast;destination=10.0.0.9",
+        ] {
+            let raw = format!("SETUP rtsp://cam.local/stream/trackID=1 RTSP/1.0\r\nCSeq: 2\r\nTransport: {}\r\n\r\n", transport);
+            assert_eq!(status(&raw), Some(400), "{}", transport);
+        }
+        let play = "SETUP rtsp://cam.local/stream/trackID=1 RTSP/1.0\r\nCSeq: 2\r\nTransport: RTP/AVP/TCP;interleaved=0-1;mode=\"PLAY\"\r\n\r\n";
+        assert_eq!(status(play), None);
+    }
+
+    #[test]
+    fn disabled_lets_everything_through() {
+        let raw = "RECORD rtsp://other.local/ RTSP/1.0\r\nCSeq: 1\r\ncseq: 1\r\n\r\n";
+        let (req, _) = RtspRequest::parse(raw.as_bytes()).unwrap().unwrap();
+        let config = ValidationConfig { enabled: false, ..Default::default() };
+        assert!(check(&config, raw.as_bytes(), &req, &[TARGET]).is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! Warm standby connections to operator-critical cameras. The proxy keeps
+//! one connection open per configured URL with DESCRIBE already answered, so
+//! a viewer's session skips the connect and DESCRIBE round trips.
+
+use bytes::{Buf, Bytes};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{mpsc, Notify};
+use tracing::{info, warn};
+use crate::config::{Config, QuirkRule, UpstreamConfig, WarmCamera};
+use crate::rtsp::{RtspRequest, RtspResponse};
+use crate::rtsp_client::RtspClient;
+use crate::upstream::{Upstream, UpstreamReader};
+
+/// Wait before reconnecting a standby that failed
+const RETRY: Duration = Duration::from_secs(5);
+
+/// A connection waiting for its viewer
+struct Standby {
+    client: RtspClient,
+    /// The camera's latest answer to DESCRIBE, if it was a 200
+    describe: Option<RtspResponse>,
+}
+
+/// A camera's standby connection, and the signal that it was taken
+type Slot = (Option<Standby>, Arc<Notify>);
+
+/// Standby connections by RTSP URL
+static STANDBY: LazyLock<Mutex<HashMap<String, Slot>>> = LazyLock::new(Default::default);
+
+/// Keep the configured cameras connected for the rest of the process
+pub fn init(config: &Config) {
+    for camera in &config.warm {
+        let taken = Arc::new(Notify::new());
+        STANDBY.lock().unwrap().insert(camera.url.clone(), (None, taken.clone()));
+        tokio::spawn(maintain(camera.clone(), config.upstream.clone(), config.quirks_for(&camera.url).cloned(), taken));
+    }
+    if !config.warm.is_empty() {
+        info!("Keeping {} camera(s) connected on warm standby", config.warm.len());
+    }
+}
+
+/// The standby connection for `rtsp_url`, if one is ready, and the answer to
+/// the session's first DESCRIBE
+pub fn take(rtsp_url: &str) -> Option<(Upstream, Option<CachedDescribe>)> {
+    let mut standby = STANDBY.lock().unwrap();
+    let (slot, taken) = standby.get_mut(rtsp_url)?;
+    let Standby { client, describe } = slot.take()?;
+    taken.notify_one();
+    crate::stats::global().add("warm_standby_used", 1);
+
+    let mut upstream = client.into_upstream();
+    let describe = match describe {
+        Some(response) => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            upstream.reader = Box::new(WarmReader { answers: rx, pending: Bytes::new(), inner: upstream.reader });
+            Some(CachedDescribe { url: rtsp_url.to_string(), response, tx })
+        }
+        None => None,
+    };
+    Some((upstream, describe))
+}
+
+async fn maintain(camera: WarmCamera, upstream: UpstreamConfig, quirks: Option<QuirkRule>, taken: Arc<Notify>) {
+    let refresh = Duration::from_secs(camera.refresh_s.max(1));
+    loop {
+        let standby = match connect(&camera.url, &upstream, quirks.clone()).await {
+            Ok(standby) => standby,
+            Err(e) => {
+                warn!("Warm standby for {} failed: {:#}", camera.url, e);
+                tokio::time::sleep(RETRY).await;
+                continue;
+            }
+        };
+        put(&camera.url, standby);
+
+        // Refresh the description until a viewer takes the connection
+        loop {
+            tokio::select! {
+                _ = taken.notified() => break,
+                _ = tokio::time::sleep(refresh) => {}
+            }
+            let Some(mut standby) = STANDBY.lock().unwrap().get_mut(&camera.url).and_then(|(slot, _)| slot.take()) else {
+                // Taken while the timer fired; its notification ends the loop
+                continue;
+            };
+            match describe(&mut standby.client, &camera.url).await {
+                Ok(describe) => {
+                    standby.describe = describe;
+                    put(&camera.url, standby);
+                }
+                Err(e) => {
+                    warn!("Warm standby for {} dropped: {:#}", camera.url, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn connect(url: &str, upstream: &UpstreamConfig, quirks: Option<QuirkRule>) -> anyhow::Result<Standby> {
+    let parsed = url::Url::parse(url)?;
+    let mut client = RtspClient::connect(&parsed, upstream).await?.with_quirks(quirks);
+    let describe = describe(&mut client, url).await?;
+    info!("Warm standby connected to {}", url);
+    Ok(Standby { client, describe })
+}
+
+/// Connect to a camera and DESCRIBE it once, without keeping the
+/// connection; the status it answers with, for `validate --reach-cameras`
+pub async fn probe(camera: &WarmCamera, config: &Config) -> anyhow::Result<u16> {
+    let parsed = url::Url::parse(&camera.url)?;
+    let mut client = RtspClient::connect(&parsed, &config.upstream).await?.with_quirks(config.quirks_for(&camera.url).cloned());
+    let response = client.request("DESCRIBE", &camera.url, &[("Accept", "application/sdp".to_string())]).await?;
+    Ok(response.status_code)
+}
+
+fn put(url: &str, standby: Standby) {
+    STANDBY.lock().unwrap().get_mut(url).expect("registered at init").0 = Some(standby);
+}
+
+/// DESCRIBE on the standby connection; a refusal such as 401 leaves the
+/// session to send its own
+async fn describe(client: &mut RtspClient, url: &str) -> anyhow::Result<Option<RtspResponse>> {
+    let response = client.request("DESCRIBE", url, &[("Accept", "application/sdp".to_string())]).await?;
+    Ok((response.status_code == 200).then_some(response))
+}
+
+/// The standby's DESCRIBE response, given to the session in place of the
+/// camera's answer to its own DESCRIBE
+pub struct CachedDescribe {
+    url: String,
+    response: RtspResponse,
+    tx: mpsc::UnboundedSender<Bytes>,
+}
+
+impl CachedDescribe {
+    /// Whether `req` asks for the description that is cached
+    pub fn answers(&self, req: &RtspRequest) -> bool {
+        let path = req.path.trim_end_matches('/');
+        req.method == "DESCRIBE"
+            && (path == self.url.trim_end_matches('/') || path == crate::upstream::camera_url(&self.url).trim_end_matches('/'))
+    }
+
+    /// Queue the response, with the request's CSeq, as if the camera sent it
+    pub fn answer(mut self, req: &RtspRequest) {
+        if let Some(cseq) = req.headers.get("CSeq") {
+            self.response.headers.insert("CSeq".to_string(), cseq.clone());
+        }
+        let _ = self.tx.send(Bytes::from(self.response.to_bytes()));
+    }
+}
+
+/// The camera's side of a standby connection, with cached answers read first
+struct WarmReader {
+    answers: mpsc::UnboundedReceiver<Bytes>,
+    pending: Bytes,
+    inner: UpstreamReader,
+}
+
+impl AsyncRead for WarmReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.pending.is_empty()
+            && let Poll::Ready(Some(answer)) = self.answers.poll_recv(cx)
+        {
+            self.pending = answer;
+        }
+        if !self.pending.is_empty() {
+            let n = self.pending.len().min(buf.remaining());
+            buf.put_slice(&self.pending[..n]);
+            self.pending.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
@@ -0,0 +1,90 @@
+//! Runtimes for the UDP media forwarders, kept apart from the control loops
+//! when `[forwarders]` is configured
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tokio::net::UdpSocket;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task::JoinHandle;
+use tracing::info;
+use crate::config::{ForwarderConfig, ForwarderMode};
+
+enum Workers {
+    /// One multi-threaded runtime for all forwarders
+    Pool(Runtime),
+    /// One single-threaded runtime per thread; each track stays on one
+    Sharded(Vec<Handle>),
+}
+
+static WORKERS: OnceLock<Workers> = OnceLock::new();
+/// Round-robin position for the next track in sharded mode
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+/// Start the forwarder threads; without this, forwarders share the main runtime
+pub fn init(config: &ForwarderConfig) -> Result<()> {
+    let threads = config.threads.max(1);
+    let workers = match config.mode {
+        ForwarderMode::Pool => Workers::Pool(
+            Builder::new_multi_thread()
+                .worker_threads(threads)
+                .thread_name("forwarder")
+                .enable_all()
+                .build()
+                .context("Failed to start the forwarder runtime")?,
+        ),
+        ForwarderMode::Sharded => {
+            let mut handles = Vec::with_capacity(threads);
+            for i in 0..threads {
+                let runtime = Builder::new_current_thread().enable_all().build().context("Failed to start a forwarder shard")?;
+                handles.push(runtime.handle().clone());
+                std::thread::Builder::new()
+                    .name(format!("forwarder-{}", i))
+                    .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+            }
+            Workers::Sharded(handles)
+        }
+    };
+    info!("Running media forwarders on {} {:?} thread(s)", threads, config.mode);
+    let _ = WORKERS.set(workers);
+    Ok(())
+}
+
+/// Where one track's forwarders run. Its sockets are bound there too, so
+/// their I/O is driven by the same thread.
+#[derive(Debug, Clone)]
+pub struct Shard(Option<Handle>);
+
+impl Shard {
+    /// Shard for a new track: the pool, the next shard in turn, or the main runtime
+    pub fn next() -> Self {
+        match WORKERS.get() {
+            Some(Workers::Pool(runtime)) => Shard(Some(runtime.handle().clone())),
+            Some(Workers::Sharded(handles)) => {
+                let i = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % handles.len();
+                Shard(Some(handles[i].clone()))
+            }
+            None => Shard(None),
+        }
+    }
+
+    /// Bind an ephemeral UDP socket registered with this shard's runtime
+    pub async fn bind_udp(&self) -> Result<UdpSocket> {
+        match &self.0 {
+            Some(handle) => Ok(handle.spawn(UdpSocket::bind("0.0.0.0:0")).await??),
+            None => Ok(UdpSocket::bind("0.0.0.0:0").await?),
+        }
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.0 {
+            Some(handle) => handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+}
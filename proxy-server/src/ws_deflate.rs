@@ -0,0 +1,263 @@
+//! RFC 7692 `permessage-deflate` for WebSocket control sockets, which the
+//! WebSocket library does not implement.
+//!
+//! Only the no-context-takeover form is negotiated, so each message is
+//! compressed and inflated on its own. Outgoing messages are deflated here
+//! and sent as frames with RSV1 set. Incoming compressed messages are
+//! inflated on the socket, before the library reads them, and handed on as
+//! one plain frame each.
+
+use bytes::{BufMut, BytesMut};
+use flate2::write::DeflateEncoder;
+use std::io::{self, Cursor, Write};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+
+/// `Sec-WebSocket-Extensions` answer to an offer [`accepts`] takes
+pub const RESPONSE: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Largest message accepted from the browser, compressed or inflated
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+/// What a deflated message ends with before RSV1 framing strips it
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Whether one of the `Sec-WebSocket-Extensions` offers is a
+/// `permessage-deflate` that [`RESPONSE`] can answer: any whose parameters
+/// leave the proxy's window at the full 15 bits
+pub fn accepts(offers: &str) -> bool {
+    offers.split(',').any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        params.next() == Some("permessage-deflate")
+            && params.all(|param| match param.split_once('=').map(|(name, value)| (name.trim(), value.trim().trim_matches('"'))) {
+                None => matches!(param, "server_no_context_takeover" | "client_no_context_takeover" | "client_max_window_bits"),
+                Some(("server_max_window_bits", "15")) => true,
+                Some(("client_max_window_bits", bits)) => bits.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits)),
+                Some(_) => false,
+            })
+    })
+}
+
+/// `data` as a compressed text frame
+pub fn text_frame(data: &[u8]) -> io::Result<Frame> {
+    let mut frame = Frame::message(deflate(data)?, OpCode::Data(Data::Text), true);
+    frame.header_mut().rsv1 = true;
+    Ok(frame)
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    // A sync flush ends the message on a byte boundary with TAIL
+    encoder.flush()?;
+    let mut out = std::mem::take(encoder.get_mut());
+    out.truncate(out.len() - TAIL.len());
+    Ok(out)
+}
+
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let input = [data, &TAIL[..]].concat();
+    let mut decompress = flate2::Decompress::new(false);
+    let mut out = Vec::with_capacity(input.len() * 4);
+    // The message has no final block, so inflating stops when the input runs out
+    while (decompress.total_in() as usize) < input.len() {
+        if out.len() == out.capacity() {
+            if out.len() > MAX_MESSAGE {
+                return Err(invalid("compressed message inflates past the limit"));
+            }
+            out.reserve(out.len());
+        }
+        let before = (decompress.total_in(), decompress.total_out());
+        let status = decompress
+            .decompress_vec(&input[decompress.total_in() as usize..], &mut out, flate2::FlushDecompress::Sync)
+            .map_err(|e| invalid(&e.to_string()))?;
+        if status == flate2::Status::StreamEnd || (before == (decompress.total_in(), decompress.total_out()) && out.len() < out.capacity()) {
+            break;
+        }
+    }
+    if out.len() > MAX_MESSAGE {
+        return Err(invalid("compressed message inflates past the limit"));
+    }
+    Ok(out)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Rewrites the frames a browser sends: a compressed message becomes one
+/// uncompressed frame, and everything else passes through unchanged
+#[derive(Debug, Default)]
+pub struct Inflater {
+    /// Received bytes not yet making up a whole frame
+    input: BytesMut,
+    /// Frames ready for the WebSocket library
+    output: BytesMut,
+    /// Opcode and compressed payload so far of a fragmented compressed message
+    message: Option<(OpCode, Vec<u8>)>,
+}
+
+impl Inflater {
+    /// Take bytes read from the socket
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.input.extend_from_slice(data);
+        while let Some((header, header_len, len)) = self.next_header()? {
+            let frame = self.input.split_to(header_len + len);
+            self.frame(header, &frame, header_len)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes ready to be read, in frames
+    pub fn output(&mut self) -> &mut BytesMut {
+        &mut self.output
+    }
+
+    /// Header, header length and payload length of the next frame, once
+    /// all of it has arrived
+    fn next_header(&self) -> io::Result<Option<(FrameHeader, usize, usize)>> {
+        let mut cursor = Cursor::new(&self.input[..]);
+        let Some((header, len)) = FrameHeader::parse(&mut cursor).map_err(|e| invalid(&e.to_string()))? else {
+            return Ok(None);
+        };
+        let len = usize::try_from(len).ok().filter(|&len| len <= MAX_MESSAGE).ok_or_else(|| invalid("frame over the size limit"))?;
+        let header_len = cursor.position() as usize;
+        Ok((self.input.len() - header_len >= len).then_some((header, header_len, len)))
+    }
+
+    fn frame(&mut self, header: FrameHeader, frame: &[u8], header_len: usize) -> io::Result<()> {
+        let compressed = match (header.opcode, &self.message) {
+            (OpCode::Data(Data::Continue), Some(_)) => true,
+            (OpCode::Data(Data::Text | Data::Binary), None) => header.rsv1,
+            (OpCode::Data(_), Some(_)) => return Err(invalid("new message inside a compressed one")),
+            _ => false,
+        };
+        if !compressed {
+            self.output.extend_from_slice(frame);
+            return Ok(());
+        }
+        let mut payload = frame[header_len..].to_vec();
+        if let Some(mask) = header.mask {
+            payload.iter_mut().zip(mask.iter().cycle()).for_each(|(byte, mask)| *byte ^= mask);
+        }
+        let (opcode, message) = self.message.get_or_insert_with(|| (header.opcode, Vec::new()));
+        if message.len() + payload.len() > MAX_MESSAGE {
+            return Err(invalid("compressed message over the size limit"));
+        }
+        message.extend_from_slice(&payload);
+        if header.is_final {
+            let opcode = *opcode;
+            let (_, message) = self.message.take().expect("set above");
+            // Masked like every client frame, with a key that leaves it as is
+            let mut frame = Frame::message(inflate(&message)?, opcode, true);
+            frame.header_mut().mask = Some([0; 4]);
+            frame.format(&mut (&mut self.output).writer()).map_err(|e| invalid(&e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::Control;
+
+    /// A client frame as a browser would send it
+    fn client_frame(payload: &[u8], opcode: OpCode, is_final: bool, rsv1: bool) -> Vec<u8> {
+        let header = FrameHeader { is_final, rsv1, opcode, mask: Some([0x12, 0x34, 0x56, 0x78]), ..FrameHeader::default() };
+        let mut out = Vec::new();
+        Frame::from_payload(header, payload.to_vec()).format(&mut out).unwrap();
+        out
+    }
+
+    /// Payloads of the frames in `data`, with their RSV1 bits, unmasked
+    fn frames(mut data: &[u8]) -> Vec<(bool, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let mut cursor = Cursor::new(data);
+            let (header, len) = FrameHeader::parse(&mut cursor).unwrap().unwrap();
+            let start = cursor.position() as usize;
+            let mut payload = data[start..start + len as usize].to_vec();
+            if let Some(mask) = header.mask {
+                payload.iter_mut().zip(mask.iter().cycle()).for_each(|(byte, mask)| *byte ^= mask);
+            }
+            frames.push((header.rsv1, payload));
+            data = &data[start + len as usize..];
+        }
+        frames
+    }
+
+    const DESCRIBE: &[u8] = b"DESCRIBE rtsp://cam.local/stream RTSP/1.0\r\nCSeq: 2\r\nAccept: application/sdp\r\n\r\n";
+
+    #[test]
+    fn offers() {
+        assert!(accepts("permessage-deflate; client_max_window_bits"));
+        assert!(accepts("permessage-deflate"));
+        assert!(accepts("x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover; client_max_window_bits=10"));
+        assert!(accepts("permessage-deflate; server_max_window_bits=\"15\""));
+        assert!(!accepts("permessage-deflate; server_max_window_bits=10"));
+        assert!(!accepts("permessage-deflate; client_max_window_bits=16"));
+        assert!(!accepts("permessage-deflate; unknown"));
+        assert!(!accepts("x-webkit-deflate-frame"));
+        assert!(!accepts(""));
+    }
+
+    /// RFC 7692 section 7.2.3.1: "Hello" with no context takeover
+    #[test]
+    fn known_answer() {
+        assert_eq!(inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]).unwrap(), b"Hello");
+        let frame = text_frame(b"Hello").unwrap();
+        assert!(frame.header().rsv1);
+        assert_eq!(inflate(frame.payload()).unwrap(), b"Hello");
+        assert!(!frame.payload().ends_with(&TAIL));
+    }
+
+    #[test]
+    fn compressed_message_is_inflated() {
+        let compressed = text_frame(DESCRIBE).unwrap().into_data();
+        let mut inflater = Inflater::default();
+        inflater.push(&client_frame(&compressed, OpCode::Data(Data::Text), true, true)).unwrap();
+        assert_eq!(frames(inflater.output()), [(false, DESCRIBE.to_vec())]);
+    }
+
+    #[test]
+    fn fragments_and_split_reads() {
+        let compressed = text_frame(DESCRIBE).unwrap().into_data();
+        let (first, rest) = compressed.split_at(compressed.len() / 2);
+        let mut data = client_frame(first, OpCode::Data(Data::Text), false, true);
+        data.extend(client_frame(b"ping", OpCode::Control(Control::Ping), true, false));
+        data.extend(client_frame(rest, OpCode::Data(Data::Continue), true, false));
+        data.extend(client_frame(b"plain", OpCode::Data(Data::Text), true, false));
+
+        let mut inflater = Inflater::default();
+        for byte in data.chunks(1) {
+            inflater.push(byte).unwrap();
+        }
+        assert_eq!(frames(inflater.output()), [(false, b"ping".to_vec()), (false, DESCRIBE.to_vec()), (false, b"plain".to_vec())]);
+    }
+
+    #[test]
+    fn bad_input_is_refused() {
+        let mut inflater = Inflater::default();
+        assert!(inflater.push(&client_frame(b"\xff\xff not deflate", OpCode::Data(Data::Text), true, true)).is_err());
+
+        // Inflating past the limit
+        let bomb = text_frame(&vec![b'a'; MAX_MESSAGE + 1]).unwrap().into_data();
+        assert!(Inflater::default().push(&client_frame(&bomb, OpCode::Data(Data::Text), true, true)).is_err());
+
+        // A new message before the compressed one ended
+        let mut data = client_frame(b"\xf2\x48", OpCode::Data(Data::Text), false, true);
+        data.extend(client_frame(b"x", OpCode::Data(Data::Text), true, false));
+        assert!(Inflater::default().push(&data).is_err());
+    }
+
+    #[test]
+    fn truncated_frame_waits() {
+        let data = client_frame(&text_frame(DESCRIBE).unwrap().into_data(), OpCode::Data(Data::Text), true, true);
+        let mut inflater = Inflater::default();
+        inflater.push(&data[..data.len() - 1]).unwrap();
+        assert!(inflater.output().is_empty());
+        inflater.push(&data[data.len() - 1..]).unwrap();
+        assert_eq!(frames(inflater.output()), [(false, DESCRIBE.to_vec())]);
+    }
+}
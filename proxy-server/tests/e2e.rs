@@ -0,0 +1,181 @@
+//! End to end: a native WebTransport client plays the browser's part against
+//! the proxy and the test camera (`replay-server` without a transcript),
+//! going through DESCRIBE, SETUP and PLAY on the control stream and checking
+//! that RTP datagrams arrive with the channel prefix the SETUP answer assigned.
+//!
+//! Needs the default ports (4433, 8080 and 8554) free, so it only runs when
+//! asked: `cargo test --test e2e -- --ignored`.
+
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use wtransport::{ClientConfig, Endpoint, RecvStream, SendStream};
+
+const CAMERA: &str = "rtsp://127.0.0.1:8554/test";
+/// How long the proxy and camera get to start, and media to arrive
+const DEADLINE: Duration = Duration::from_secs(20);
+const RTP_PACKETS: usize = 50;
+
+/// A child process that is killed with the test
+struct Process(Child);
+
+impl Process {
+    fn spawn(program: &str) -> Self {
+        let child = Command::new(program)
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to start {}: {}", program, e));
+        Self(child)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Wait until something listens on a TCP port
+fn wait_for_port(port: u16) {
+    let start = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(start.elapsed() < DEADLINE, "Nothing listening on port {} after {:?}", port, DEADLINE);
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+struct Message {
+    start_line: String,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+/// The browser's side of the control stream, speaking raw RTSP
+struct Control {
+    send: SendStream,
+    recv: RecvStream,
+    buf: Vec<u8>,
+    cseq: u32,
+}
+
+impl Control {
+    async fn request(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> Message {
+        self.cseq += 1;
+        let mut text = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: e2e\r\n", method, url, self.cseq);
+        for (name, value) in headers {
+            text += &format!("{}: {}\r\n", name, value);
+        }
+        text += "\r\n";
+        self.send.write_all(text.as_bytes()).await.expect("control stream write");
+
+        // Proxy notifications may come first
+        loop {
+            let message = self.next().await;
+            if message.start_line.starts_with("RTSP/") && message.headers.get("cseq") == Some(&self.cseq.to_string()) {
+                assert!(message.start_line.contains(" 200 "), "{} failed: {}", method, message.start_line);
+                return message;
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Message {
+        loop {
+            if let Some(message) = self.parse() {
+                return message;
+            }
+            let mut chunk = [0u8; 4096];
+            let n = self.recv.read(&mut chunk).await.expect("control stream read").expect("control stream closed");
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn parse(&mut self) -> Option<Message> {
+        let head_end = self.buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let head = String::from_utf8_lossy(&self.buf[..head_end]).into_owned();
+        let mut lines = head.lines();
+        let start_line = lines.next()?.to_string();
+        let headers: HashMap<String, String> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        let length: usize = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        if self.buf.len() < head_end + length {
+            return None;
+        }
+        let body = String::from_utf8_lossy(&self.buf[head_end..head_end + length]).into_owned();
+        self.buf.drain(..head_end + length);
+        Some(Message { start_line, headers, body })
+    }
+}
+
+/// URL to SETUP the first video track of an SDP
+fn video_control(sdp: &str) -> String {
+    let video = &sdp[sdp.find("m=video").expect("SDP has a video track")..];
+    let control = video.lines().find_map(|line| line.strip_prefix("a=control:")).expect("video track has a control URL");
+    if control.contains("://") { control.to_string() } else { format!("{}/{}", CAMERA, control) }
+}
+
+/// RTP and RTCP channel IDs of a SETUP answer
+fn channels(transport: &str) -> (u8, u8) {
+    let range = transport
+        .split(';')
+        .find_map(|param| param.strip_prefix("x-wt-channel-id=").or_else(|| param.strip_prefix("interleaved=")))
+        .expect("SETUP answer assigns channels");
+    let (rtp, rtcp) = range.split_once('-').expect("channel range");
+    (rtp.parse().unwrap(), rtcp.parse().unwrap())
+}
+
+#[tokio::test]
+#[ignore = "needs free ports 4433, 8080 and 8554"]
+async fn plays_test_camera_over_webtransport() {
+    let _camera = Process::spawn(env!("CARGO_BIN_EXE_replay-server"));
+    let _proxy = Process::spawn(env!("CARGO_BIN_EXE_server"));
+    wait_for_port(8554);
+    // The proxy binds WebSocket after WebTransport, so this means both are up
+    wait_for_port(8080);
+
+    let config = ClientConfig::builder().with_bind_default().with_no_cert_validation().build();
+    let endpoint = Endpoint::client(config).unwrap();
+    let query: String = url::form_urlencoded::byte_serialize(CAMERA.as_bytes()).collect();
+    let connection = timeout(DEADLINE, endpoint.connect(format!("https://127.0.0.1:4433/?rtsp={}", query)))
+        .await
+        .expect("WebTransport connect timed out")
+        .expect("WebTransport connect");
+    let (send, recv) = connection.open_bi().await.unwrap().await.unwrap();
+    let mut control = Control { send, recv, buf: Vec::new(), cseq: 0 };
+
+    let describe = timeout(DEADLINE, control.request("DESCRIBE", CAMERA, &[("Accept", "application/sdp")]))
+        .await
+        .expect("DESCRIBE timed out");
+    let track = video_control(&describe.body);
+    let setup = timeout(DEADLINE, control.request("SETUP", &track, &[("Transport", "RTP/AVP;unicast;client_port=0-0")]))
+        .await
+        .expect("SETUP timed out");
+    let (rtp_channel, rtcp_channel) = channels(&setup.headers["transport"]);
+    let session = setup.headers["session"].split(';').next().unwrap().to_string();
+    timeout(DEADLINE, control.request("PLAY", CAMERA, &[("Session", &session)]))
+        .await
+        .expect("PLAY timed out");
+
+    let mut rtp_packets = 0;
+    let receive = async {
+        while rtp_packets < RTP_PACKETS {
+            let datagram = connection.receive_datagram().await.expect("datagram");
+            let payload = datagram.payload();
+            let (&channel, packet) = payload.split_first().expect("non-empty datagram");
+            assert!(channel == rtp_channel || channel == rtcp_channel, "datagram on unknown channel {}", channel);
+            if channel == rtp_channel {
+                assert!(packet.len() > 12, "RTP packet of {} bytes", packet.len());
+                assert_eq!(packet[0] >> 6, 2, "RTP version");
+                assert_eq!(packet[1] & 0x7F, 96, "payload type");
+                rtp_packets += 1;
+            }
+        }
+    };
+    let finished = timeout(DEADLINE, receive).await.is_ok();
+    assert!(finished, "Only {} of {} RTP packets arrived within {:?}", rtp_packets, RTP_PACKETS, DEADLINE);
+}